@@ -59,6 +59,14 @@ class RustCore {
       Int32 Function(),
       int Function()>('init_identity');
   
+  static final _exportIdentityEncrypted = dylib.lookupFunction<
+      Pointer<Utf8> Function(Pointer<Utf8>),
+      Pointer<Utf8> Function(Pointer<Utf8>)>('export_identity_encrypted');
+  
+  static final _importIdentityEncrypted = dylib.lookupFunction<
+      Int32 Function(Pointer<Utf8>, Pointer<Utf8>),
+      int Function(Pointer<Utf8>, Pointer<Utf8>)>('import_identity_encrypted');
+  
   static final _getUserId = dylib.lookupFunction<
       Pointer<Utf8> Function(),
       Pointer<Utf8> Function()>('get_user_id');
@@ -79,6 +87,60 @@ class RustCore {
       Int32 Function(),
       int Function()>('init_storage');
   
+  static final _initInMemory = dylib.lookupFunction<
+      Int32 Function(),
+      int Function()>('init_in_memory');
+  
+  static final _setDataDirectory = dylib.lookupFunction<
+      Int32 Function(Pointer<Utf8>),
+      int Function(Pointer<Utf8>)>('set_data_directory');
+  
+  static final _attachReadOnly = dylib.lookupFunction<
+      Int32 Function(),
+      int Function()>('attach_read_only');
+  
+  static final _suspend = dylib.lookupFunction<
+      Int32 Function(),
+      int Function()>('suspend');
+  
+  static final _resume = dylib.lookupFunction<
+      Pointer<Utf8> Function(),
+      Pointer<Utf8> Function()>('resume');
+  
+  static final _listIdentities = dylib.lookupFunction<
+      Pointer<Utf8> Function(),
+      Pointer<Utf8> Function()>('list_identities');
+  
+  static final _createIdentity = dylib.lookupFunction<
+      Pointer<Utf8> Function(Pointer<Utf8>),
+      Pointer<Utf8> Function(Pointer<Utf8>)>('create_identity');
+  
+  static final _switchIdentity = dylib.lookupFunction<
+      Int32 Function(Pointer<Utf8>),
+      int Function(Pointer<Utf8>)>('switch_identity');
+  
+  static final _createBackup = dylib.lookupFunction<
+      Pointer<Utf8> Function(Int32),
+      Pointer<Utf8> Function(int)>('create_backup');
+  
+  static final _restoreBackup = dylib.lookupFunction<
+      Pointer<Utf8> Function(Pointer<Utf8>),
+      Pointer<Utf8> Function(Pointer<Utf8>)>('restore_backup');
+  
+  static final _lastErrorMessage = dylib.lookupFunction<
+      Pointer<Utf8> Function(),
+      Pointer<Utf8> Function()>('last_error_message');
+  
+  static final _createDiagnosticsBundle = dylib.lookupFunction<
+      Pointer<Utf8> Function(),
+      Pointer<Utf8> Function()>('create_diagnostics_bundle');
+  
+  static final _registerEventCallback = dylib.lookupFunction<
+      Int32 Function(Pointer<NativeFunction<Void Function(Pointer<Utf8>)>>),
+      int Function(Pointer<NativeFunction<Void Function(Pointer<Utf8>)>>)>('register_event_callback');
+  
+  static NativeCallable<Void Function(Pointer<Utf8>)>? _eventCallable;
+  
   /// Helper to safely get a string from FFI
   static String? _getString(Pointer<Utf8> Function() getter) {
     try {
@@ -109,6 +171,25 @@ class RustCore {
     }
   }
   
+  /// Keep all core files under [path] (e.g. the app documents directory on
+  /// iOS/Android). Call before initIdentity.
+  static bool setDataDirectory(String path) {
+    final pathPtr = path.toNativeUtf8();
+    final result = _setDataDirectory(pathPtr);
+    malloc.free(pathPtr);
+    return result == 0;
+  }
+  
+  /// Open the data directory read-only, for a second process (e.g. a
+  /// background service) while the app holds it. Call before initIdentity.
+  static bool attachReadOnly() => _attachReadOnly() == 0;
+  
+  /// Flush and close files before the OS may kill the app (on pause)
+  static bool suspend() => _suspend() == 0;
+  
+  /// Reopen what [suspend] closed (on resume); JSON says what is loaded
+  static String? resume() => _getString(() => _resume());
+  
   /// Initialize identity (loads from storage or generates new)
   static bool initIdentity() {
     try {
@@ -119,6 +200,59 @@ class RustCore {
     }
   }
   
+  /// Seal the identity under a backup passphrase (JSON, for another device)
+  static String? exportIdentityEncrypted(String passphrase) {
+    final passphrasePtr = passphrase.toNativeUtf8();
+    final result = _getString(() => _exportIdentityEncrypted(passphrasePtr));
+    malloc.free(passphrasePtr);
+    return result;
+  }
+  
+  /// Restore an identity from a backup instead of initIdentity
+  static bool importIdentityEncrypted(String backupJson, String passphrase) {
+    final backupPtr = backupJson.toNativeUtf8();
+    final passphrasePtr = passphrase.toNativeUtf8();
+    final result = _importIdentityEncrypted(backupPtr, passphrasePtr);
+    malloc.free(backupPtr);
+    malloc.free(passphrasePtr);
+    return result == 0;
+  }
+  
+  /// Identities on this device (e.g. work, personal) as JSON
+  /// { active, identities: [{ name, user_id?, created_at }] }
+  static String? listIdentities() => _getString(_listIdentities);
+  
+  /// Create a new identity with its own friends and messages; returns JSON
+  /// { name, user_id, created_at }
+  static String? createIdentity(String name) {
+    final namePtr = name.toNativeUtf8();
+    final result = _getString(() => _createIdentity(namePtr));
+    malloc.free(namePtr);
+    return result;
+  }
+  
+  /// Make another identity the active one. Call initIdentity, initStorage
+  /// and initFriends again afterwards.
+  static bool switchIdentity(String name) {
+    final namePtr = name.toNativeUtf8();
+    final result = _switchIdentity(namePtr);
+    malloc.free(namePtr);
+    return result == 0;
+  }
+  
+  /// Back up the database: only what changed since the last backup unless
+  /// [full]. Keep every backup of the chain (JSON).
+  static String? createBackup({bool full = false}) => _getString(() => _createBackup(full ? 1 : 0));
+  
+  /// Restore from a JSON array of backups: the full one, then every delta in
+  /// order. Returns JSON { chain_id, backups, rows }.
+  static String? restoreBackup(String chainJson) {
+    final chainPtr = chainJson.toNativeUtf8();
+    final result = _getString(() => _restoreBackup(chainPtr));
+    malloc.free(chainPtr);
+    return result;
+  }
+  
   /// Get user ID (SHA256 of Ed25519 public key)
   static String? getUserId() => _getString(_getUserId);
   
@@ -131,6 +265,40 @@ class RustCore {
   /// Get X25519 public key
   static String? getX25519PublicKey() => _getString(_getX25519PublicKey);
   
+  /// Last Rust core failure on this thread as JSON {code, name, message}
+  static String? lastErrorMessage() => _getString(_lastErrorMessage);
+  
+  /// Write a sanitized diagnostics bundle (.tar.gz) to attach to bug
+  /// reports. Returns its path.
+  static String? createDiagnosticsBundle() => _getString(_createDiagnosticsBundle);
+  
+  /// Receive core events (new messages, receipts, friend changes, router
+  /// activity) as JSON { kind, timestamp, payload } instead of polling.
+  /// Replaces any earlier callback.
+  static bool registerEventCallback(void Function(String eventJson) onEvent) {
+    try {
+      final callable = NativeCallable<Void Function(Pointer<Utf8>)>.listener((Pointer<Utf8> eventPtr) {
+        final eventJson = eventPtr.toDartString();
+        _freeString(eventPtr);
+        onEvent(eventJson);
+      });
+      
+      _registerEventCallback(callable.nativeFunction);
+      _eventCallable?.close();
+      _eventCallable = callable;
+      return true;
+    } catch (e) {
+      return false;
+    }
+  }
+  
+  /// Stop pushing events; they are queued for polling again.
+  static void unregisterEventCallback() {
+    _registerEventCallback(nullptr);
+    _eventCallable?.close();
+    _eventCallable = null;
+  }
+  
   /// Initialize storage
   static bool initStorage() {
     try {
@@ -140,6 +308,17 @@ class RustCore {
     }
   }
   
+  /// Start a guest session kept in memory only: a new identity, no friends
+  /// and no messages on disk. Call instead of the identity, friends and
+  /// storage init functions.
+  static bool initInMemory() {
+    try {
+      return _initInMemory() == 0;
+    } catch (e) {
+      return false;
+    }
+  }
+  
   // Friends management FFI functions
   static final _initFriends = dylib.lookupFunction<
       Int32 Function(),
@@ -165,7 +344,7 @@ class RustCore {
       Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>),
       Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>)>('import_friend_from_json');
   
-  /// Initialize friends manager
+  /// Initialize friends manager (after initStorage)
   static bool initFriends() {
     try {
       final result = _initFriends();
@@ -307,27 +486,46 @@ class RustCore {
   
   // Messaging FFI functions
   static final _sendDmMessage = dylib.lookupFunction<
-      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>),
-      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>)>('send_dm_message');
+      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>, Pointer<Utf8>),
+      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>, Pointer<Utf8>)>('send_dm_message');
   
   static final _getDmMessages = dylib.lookupFunction<
       Pointer<Utf8> Function(Pointer<Utf8>, Uint32, Uint32),
       Pointer<Utf8> Function(Pointer<Utf8>, int, int)>('get_dm_messages');
   
+  static final _getDmMessagesSince = dylib.lookupFunction<
+      Pointer<Utf8> Function(Pointer<Utf8>, Int64, Uint32),
+      Pointer<Utf8> Function(Pointer<Utf8>, int, int)>('get_dm_messages_since');
+  
+  static final _getConversations = dylib.lookupFunction<
+      Pointer<Utf8> Function(),
+      Pointer<Utf8> Function()>('get_conversations');
+  
   static final _clearDmMessages = dylib.lookupFunction<
       Int32 Function(Pointer<Utf8>),
       int Function(Pointer<Utf8>)>('clear_dm_messages');
   
-  /// Send a DM message to a friend
-  static String? sendDmMessage(String friendUserIdHex, String plaintext) {
+  static final _markMessageDelivered = dylib.lookupFunction<
+      Int32 Function(Pointer<Utf8>),
+      int Function(Pointer<Utf8>)>('mark_message_delivered');
+  
+  static final _markMessageRead = dylib.lookupFunction<
+      Int32 Function(Pointer<Utf8>),
+      int Function(Pointer<Utf8>)>('mark_message_read');
+  
+  /// Send a DM message to a friend. Retries with the same [clientToken]
+  /// return the first send's message id instead of sending twice.
+  static String? sendDmMessage(String friendUserIdHex, String plaintext, {String? clientToken}) {
     try {
       final friendIdPtr = friendUserIdHex.toNativeUtf8();
       final textPtr = plaintext.toNativeUtf8();
+      final tokenPtr = clientToken?.toNativeUtf8() ?? nullptr;
       
-      final result = _sendDmMessage(friendIdPtr, textPtr);
+      final result = _sendDmMessage(friendIdPtr, textPtr, tokenPtr);
       
       malloc.free(friendIdPtr);
       malloc.free(textPtr);
+      if (tokenPtr != nullptr) malloc.free(tokenPtr);
       
       if (result == nullptr) {
         return null;
@@ -362,6 +560,32 @@ class RustCore {
     }
   }
   
+  /// Get DM messages stored since [afterSeq] (0 for all). Returns JSON
+  /// { messages, cursor }; pass the cursor as [afterSeq] next time.
+  static String? getDmMessagesSince(String friendUserIdHex, int afterSeq, {int limit = 100}) {
+    try {
+      final friendIdPtr = friendUserIdHex.toNativeUtf8();
+      
+      final result = _getDmMessagesSince(friendIdPtr, afterSeq, limit);
+      
+      malloc.free(friendIdPtr);
+      
+      if (result == nullptr) {
+        return null;
+      }
+      
+      final changesJson = result.toDartString();
+      _freeString(result);
+      return changesJson;
+    } catch (e) {
+      return null;
+    }
+  }
+  
+  /// Get the conversation list as JSON, with each conversation's last
+  /// message preview and unread count.
+  static String? getConversations() => _getString(_getConversations);
+  
   /// Clear all messages for a DM channel
   static bool clearDmMessages(String friendUserIdHex) {
     try {
@@ -376,6 +600,358 @@ class RustCore {
       return false;
     }
   }
+  
+  /// Mark a received message as delivered (sends a receipt to its sender)
+  static bool markMessageDelivered(String messageIdHex) => _markMessage(_markMessageDelivered, messageIdHex);
+  
+  /// Mark a received message as read (the receipt honours privacy.read_receipts)
+  static bool markMessageRead(String messageIdHex) => _markMessage(_markMessageRead, messageIdHex);
+  
+  static bool _markMessage(int Function(Pointer<Utf8>) mark, String messageIdHex) {
+    try {
+      final messageIdPtr = messageIdHex.toNativeUtf8();
+      final result = mark(messageIdPtr);
+      malloc.free(messageIdPtr);
+      return result == 0;
+    } catch (e) {
+      return false;
+    }
+  }
+  
+  // Blocklist FFI functions
+  static final _blockUser = dylib.lookupFunction<
+      Int32 Function(Pointer<Utf8>),
+      int Function(Pointer<Utf8>)>('block_user');
+  
+  static final _unblockUser = dylib.lookupFunction<
+      Int32 Function(Pointer<Utf8>),
+      int Function(Pointer<Utf8>)>('unblock_user');
+  
+  static final _getBlockedUsers = dylib.lookupFunction<
+      Pointer<Utf8> Function(),
+      Pointer<Utf8> Function()>('get_blocked_users');
+  
+  /// Block a user: their DMs and group messages are dropped on arrival
+  static bool blockUser(String userIdHex) {
+    final userIdPtr = userIdHex.toNativeUtf8();
+    final result = _blockUser(userIdPtr);
+    malloc.free(userIdPtr);
+    return result == 0;
+  }
+  
+  /// Unblock a user
+  static bool unblockUser(String userIdHex) {
+    final userIdPtr = userIdHex.toNativeUtf8();
+    final result = _unblockUser(userIdPtr);
+    malloc.free(userIdPtr);
+    return result == 0;
+  }
+  
+  /// Get blocked users (JSON)
+  static String? getBlockedUsers() => _getString(_getBlockedUsers);
+  
+  // Group FFI functions
+  static final _createGroup = dylib.lookupFunction<
+      Pointer<Utf8> Function(Pointer<Utf8>),
+      Pointer<Utf8> Function(Pointer<Utf8>)>('create_group');
+  
+  static final _addGroupMember = dylib.lookupFunction<
+      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>),
+      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>)>('add_group_member');
+  
+  static final _acceptGroupInvite = dylib.lookupFunction<
+      Pointer<Utf8> Function(Pointer<Utf8>),
+      Pointer<Utf8> Function(Pointer<Utf8>)>('accept_group_invite');
+  
+  static final _sendGroupMessage = dylib.lookupFunction<
+      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>, Pointer<Utf8>),
+      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>, Pointer<Utf8>)>('send_group_message');
+  
+  static final _getGroupMessages = dylib.lookupFunction<
+      Pointer<Utf8> Function(Pointer<Utf8>, Uint32, Uint32),
+      Pointer<Utf8> Function(Pointer<Utf8>, int, int)>('get_group_messages');
+  
+  static final _setGroupMetadata = dylib.lookupFunction<
+      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>, Pointer<Utf8>),
+      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>, Pointer<Utf8>)>('set_group_metadata');
+  
+  static final _moderateGroup = dylib.lookupFunction<
+      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>, Pointer<Utf8>),
+      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>, Pointer<Utf8>)>('moderate_group');
+  
+  /// Create a group with us as its only member; returns its channel id
+  static String? createGroup({String? name}) {
+    final namePtr = name?.toNativeUtf8() ?? nullptr;
+    final result = _getString(() => _createGroup(namePtr));
+    if (namePtr != nullptr) malloc.free(namePtr);
+    return result;
+  }
+  
+  /// Add a friend to a group; the key goes to them as a DM (returns its message id)
+  static String? addGroupMember(String channelIdHex, String userIdHex) {
+    final channelIdPtr = channelIdHex.toNativeUtf8();
+    final userIdPtr = userIdHex.toNativeUtf8();
+    final result = _getString(() => _addGroupMember(channelIdPtr, userIdPtr));
+    malloc.free(channelIdPtr);
+    malloc.free(userIdPtr);
+    return result;
+  }
+  
+  /// Join the group a received DM (with `group_invite`) invites us to
+  static String? acceptGroupInvite(String messageIdHex) {
+    final messageIdPtr = messageIdHex.toNativeUtf8();
+    final result = _getString(() => _acceptGroupInvite(messageIdPtr));
+    malloc.free(messageIdPtr);
+    return result;
+  }
+  
+  /// Send a message to a group; returns its message id
+  static String? sendGroupMessage(String channelIdHex, String text, {String? clientToken}) {
+    final channelIdPtr = channelIdHex.toNativeUtf8();
+    final textPtr = text.toNativeUtf8();
+    final tokenPtr = clientToken?.toNativeUtf8() ?? nullptr;
+    final result = _getString(() => _sendGroupMessage(channelIdPtr, textPtr, tokenPtr));
+    malloc.free(channelIdPtr);
+    malloc.free(textPtr);
+    if (tokenPtr != nullptr) malloc.free(tokenPtr);
+    return result;
+  }
+  
+  /// Change a group's name and topic for every member; returns the message id
+  static String? setGroupMetadata(String channelIdHex, {String? name, String? topic}) {
+    final channelIdPtr = channelIdHex.toNativeUtf8();
+    final namePtr = name?.toNativeUtf8() ?? nullptr;
+    final topicPtr = topic?.toNativeUtf8() ?? nullptr;
+    final result = _getString(() => _setGroupMetadata(channelIdPtr, namePtr, topicPtr));
+    malloc.free(channelIdPtr);
+    if (namePtr != nullptr) malloc.free(namePtr);
+    if (topicPtr != nullptr) malloc.free(topicPtr);
+    return result;
+  }
+  
+  /// As a group admin, remove/mute/unmute a member or delete a message (JSON result)
+  static String? moderateGroup(String channelIdHex, String action, String targetHex) {
+    final channelIdPtr = channelIdHex.toNativeUtf8();
+    final actionPtr = action.toNativeUtf8();
+    final targetPtr = targetHex.toNativeUtf8();
+    final result = _getString(() => _moderateGroup(channelIdPtr, actionPtr, targetPtr));
+    malloc.free(channelIdPtr);
+    malloc.free(actionPtr);
+    malloc.free(targetPtr);
+    return result;
+  }
+  
+  /// Get a group's decrypted messages (JSON)
+  static String? getGroupMessages(String channelIdHex, {int limit = 100, int offset = 0}) {
+    final channelIdPtr = channelIdHex.toNativeUtf8();
+    final result = _getString(() => _getGroupMessages(channelIdPtr, limit, offset));
+    malloc.free(channelIdPtr);
+    return result;
+  }
+  
+  // Disappearing messages FFI functions
+  static final _setChannelRetention = dylib.lookupFunction<
+      Int32 Function(Pointer<Utf8>, Uint64),
+      int Function(Pointer<Utf8>, int)>('set_channel_retention');
+  
+  static final _getChannelRetention = dylib.lookupFunction<
+      Int64 Function(Pointer<Utf8>),
+      int Function(Pointer<Utf8>)>('get_channel_retention');
+  
+  /// Make a conversation's messages disappear [seconds] after they are sent
+  /// (0 turns disappearing messages off)
+  static bool setChannelRetention(String channelIdHex, int seconds) {
+    final channelIdPtr = channelIdHex.toNativeUtf8();
+    final result = _setChannelRetention(channelIdPtr, seconds);
+    malloc.free(channelIdPtr);
+    return result == 0;
+  }
+  
+  /// A conversation's disappearing-message time in seconds (0 if off, null on error)
+  static int? getChannelRetention(String channelIdHex) {
+    final channelIdPtr = channelIdHex.toNativeUtf8();
+    final result = _getChannelRetention(channelIdPtr);
+    malloc.free(channelIdPtr);
+    return result < 0 ? null : result;
+  }
+  
+  // Pseudonyms FFI functions
+  static final _getChannelPseudonym = dylib.lookupFunction<
+      Pointer<Utf8> Function(Pointer<Utf8>),
+      Pointer<Utf8> Function(Pointer<Utf8>)>('get_channel_pseudonym');
+  
+  static final _regeneratePseudonym = dylib.lookupFunction<
+      Pointer<Utf8> Function(Pointer<Utf8>),
+      Pointer<Utf8> Function(Pointer<Utf8>)>('regenerate_pseudonym');
+  
+  static final _listPseudonyms = dylib.lookupFunction<
+      Pointer<Utf8> Function(),
+      Pointer<Utf8> Function()>('list_pseudonyms');
+  
+  /// Our pseudonym in a public channel (JSON)
+  static String? getChannelPseudonym(String channelIdHex) {
+    final channelIdPtr = channelIdHex.toNativeUtf8();
+    final result = _getString(() => _getChannelPseudonym(channelIdPtr));
+    malloc.free(channelIdPtr);
+    return result;
+  }
+  
+  /// Switch to a new pseudonym in a public channel (JSON)
+  static String? regeneratePseudonym(String channelIdHex) {
+    final channelIdPtr = channelIdHex.toNativeUtf8();
+    final result = _getString(() => _regeneratePseudonym(channelIdPtr));
+    malloc.free(channelIdPtr);
+    return result;
+  }
+  
+  /// All our pseudonyms, current and retired (JSON)
+  static String? listPseudonyms() => _getString(_listPseudonyms);
+  
+  // Geohash channels FFI functions
+  static final _getNearbyGeoChannels = dylib.lookupFunction<
+      Pointer<Utf8> Function(Double, Double, Uint32, Pointer<Utf8>),
+      Pointer<Utf8> Function(double, double, int, Pointer<Utf8>)>('get_nearby_geo_channels');
+  
+  /// Geo channels of [topic] in the cell of a GPS position and the cells around it (JSON)
+  static String? getNearbyGeoChannels(double lat, double lon, String topic, {int precision = 6}) {
+    final topicPtr = topic.toNativeUtf8();
+    final result = _getString(() => _getNearbyGeoChannels(lat, lon, precision, topicPtr));
+    malloc.free(topicPtr);
+    return result;
+  }
+  
+  static final _sendGeoMessage = dylib.lookupFunction<
+      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>, Pointer<Utf8>, Pointer<Utf8>),
+      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>, Pointer<Utf8>, Pointer<Utf8>)>('send_geo_message');
+  
+  /// Send a message on a geo channel, signed by our pseudonym there (JSON)
+  static String? sendGeoMessage(String channelIdHex, String text, {String? inReplyToHex, String? clientToken}) {
+    final channelPtr = channelIdHex.toNativeUtf8();
+    final textPtr = text.toNativeUtf8();
+    final replyPtr = inReplyToHex?.toNativeUtf8() ?? nullptr;
+    final tokenPtr = clientToken?.toNativeUtf8() ?? nullptr;
+    final result = _getString(() => _sendGeoMessage(channelPtr, textPtr, replyPtr, tokenPtr));
+    malloc.free(channelPtr);
+    malloc.free(textPtr);
+    if (replyPtr != nullptr) malloc.free(replyPtr);
+    if (tokenPtr != nullptr) malloc.free(tokenPtr);
+    return result;
+  }
+  
+  static final _sendMessage = dylib.lookupFunction<
+      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>, Pointer<Utf8>, Pointer<Utf8>),
+      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>, Pointer<Utf8>, Pointer<Utf8>)>('send_message');
+  
+  /// Send a message to a DM, notes, group or geo channel: [channelRef] is
+  /// `user:<user id hex>` or a channel id hex (JSON result)
+  static String? sendMessage(String channelRef, String plaintext, {String? optionsJson, String? clientToken}) {
+    final refPtr = channelRef.toNativeUtf8();
+    final textPtr = plaintext.toNativeUtf8();
+    final optionsPtr = optionsJson?.toNativeUtf8() ?? nullptr;
+    final tokenPtr = clientToken?.toNativeUtf8() ?? nullptr;
+    final result = _getString(() => _sendMessage(refPtr, textPtr, optionsPtr, tokenPtr));
+    malloc.free(refPtr);
+    malloc.free(textPtr);
+    if (optionsPtr != nullptr) malloc.free(optionsPtr);
+    if (tokenPtr != nullptr) malloc.free(tokenPtr);
+    return result;
+  }
+  
+  // Channel directory FFI functions
+  static final _announceGeoChannel = dylib.lookupFunction<
+      Int32 Function(Pointer<Utf8>, Pointer<Utf8>, Pointer<Utf8>, Int32),
+      int Function(Pointer<Utf8>, Pointer<Utf8>, Pointer<Utf8>, int)>('announce_geo_channel');
+  
+  static final _sendChannelAnnouncements = dylib.lookupFunction<
+      Int32 Function(),
+      int Function()>('send_channel_announcements');
+  
+  static final _getChannelsNear = dylib.lookupFunction<
+      Pointer<Utf8> Function(Pointer<Utf8>),
+      Pointer<Utf8> Function(Pointer<Utf8>)>('get_channels_near');
+  
+  /// Announce a geohash channel to nodes nearby, or stop announcing it
+  static bool announceGeoChannel(String geohash, String topic, {String? name, bool enabled = true}) {
+    final geohashPtr = geohash.toNativeUtf8();
+    final topicPtr = topic.toNativeUtf8();
+    final namePtr = name?.toNativeUtf8() ?? nullptr;
+    final result = _announceGeoChannel(geohashPtr, topicPtr, namePtr, enabled ? 1 : 0);
+    malloc.free(geohashPtr);
+    malloc.free(topicPtr);
+    if (namePtr != nullptr) malloc.free(namePtr);
+    return result == 0;
+  }
+  
+  /// Send the channel announcements that are due (call periodically); returns how many went out
+  static int sendChannelAnnouncements() => _sendChannelAnnouncements();
+  
+  /// Public channels announced around a geohash, "channels near you" (JSON)
+  static String? getChannelsNear(String geohash) {
+    final geohashPtr = geohash.toNativeUtf8();
+    final result = _getString(() => _getChannelsNear(geohashPtr));
+    malloc.free(geohashPtr);
+    return result;
+  }
+  
+  // Broadcasts FFI functions
+  static final _postBroadcast = dylib.lookupFunction<
+      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>),
+      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>)>('post_broadcast');
+  
+  static final _watchContact = dylib.lookupFunction<
+      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>),
+      Pointer<Utf8> Function(Pointer<Utf8>, Pointer<Utf8>)>('watch_contact');
+  
+  static final _unwatchContact = dylib.lookupFunction<
+      Int32 Function(Pointer<Utf8>),
+      int Function(Pointer<Utf8>)>('unwatch_contact');
+  
+  static final _listWatchedContacts = dylib.lookupFunction<
+      Pointer<Utf8> Function(),
+      Pointer<Utf8> Function()>('list_watched_contacts');
+  
+  static final _getBroadcastPosts = dylib.lookupFunction<
+      Pointer<Utf8> Function(Pointer<Utf8>, Uint32, Uint32),
+      Pointer<Utf8> Function(Pointer<Utf8>, int, int)>('get_broadcast_posts');
+  
+  /// Post on our broadcast channel; returns the message id
+  static String? postBroadcast(String text, {String? clientToken}) {
+    final textPtr = text.toNativeUtf8();
+    final tokenPtr = clientToken?.toNativeUtf8() ?? nullptr;
+    final result = _getString(() => _postBroadcast(textPtr, tokenPtr));
+    malloc.free(textPtr);
+    if (tokenPtr != nullptr) malloc.free(tokenPtr);
+    return result;
+  }
+  
+  /// Follow a key's broadcast posts without adding them as a friend (JSON)
+  static String? watchContact(String ed25519PublicHex, {String? label}) {
+    final keyPtr = ed25519PublicHex.toNativeUtf8();
+    final labelPtr = label?.toNativeUtf8() ?? nullptr;
+    final result = _getString(() => _watchContact(keyPtr, labelPtr));
+    malloc.free(keyPtr);
+    if (labelPtr != nullptr) malloc.free(labelPtr);
+    return result;
+  }
+  
+  /// Stop following a key and delete its posts
+  static bool unwatchContact(String ed25519PublicHex) {
+    final keyPtr = ed25519PublicHex.toNativeUtf8();
+    final result = _unwatchContact(keyPtr);
+    malloc.free(keyPtr);
+    return result == 0;
+  }
+  
+  /// Keys we follow (JSON)
+  static String? listWatchedContacts() => _getString(_listWatchedContacts);
+  
+  /// Verified broadcast posts of a key (JSON)
+  static String? getBroadcastPosts(String ed25519PublicHex, {int limit = 100, int offset = 0}) {
+    final keyPtr = ed25519PublicHex.toNativeUtf8();
+    final result = _getString(() => _getBroadcastPosts(keyPtr, limit, offset));
+    malloc.free(keyPtr);
+    return result;
+  }
 }
 
 void main() {
@@ -494,6 +1070,9 @@ class _HomePageState extends State<HomePage> with SingleTickerProviderStateMixin
         });
       }
 
+      // Initialize storage for messaging (friends are kept in it too)
+      RustCore.initStorage();
+
       final friendsInitResult = RustCore.initFriends();
       if (friendsInitResult) {
         setState(() {
@@ -504,9 +1083,6 @@ class _HomePageState extends State<HomePage> with SingleTickerProviderStateMixin
         print('Failed to initialize friends - check console for details');
       }
 
-      // Initialize storage for messaging
-      RustCore.initStorage();
-
       setState(() {
         _isLoading = false;
       });
@@ -1,6 +1,8 @@
 fn main() {
     // Tell Cargo to link against the standard C library
     println!("cargo:rustc-link-lib=dylib=c");
+    // Set for aarch64 in .cargo/config.toml (see crypto_backends)
+    println!("cargo::rustc-check-cfg=cfg(chacha20_force_neon)");
 }
 
 
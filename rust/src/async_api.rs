@@ -0,0 +1,219 @@
+//! Async core API (feature `async`)
+//!
+//! For the daemon/CLI and network transports, which run on Tokio. The
+//! synchronous FFI stays the facade for mobile; both go through the same
+//! modules underneath.
+//! - `AsyncStorage`: storage calls on Tokio's blocking pool (`spawn_blocking`),
+//!   so SQLite never blocks a runtime thread
+//! - `AsyncTransport`: transports that send and receive packets
+//!   asynchronously; `ChannelTransport` is an in-process pair for tests and
+//!   local daemons
+//! - `sync_channel` / `serve_history`: the history request/response protocol
+//!   (see `history`) over an async transport
+
+use crate::history;
+use crate::identity::Identity;
+use crate::storage::Storage;
+use crate::transport::{Packet, PacketKind};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// How long `sync_channel` waits for each history page
+pub const DEFAULT_PAGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shared storage whose calls run on the blocking pool.
+#[derive(Clone)]
+pub struct AsyncStorage {
+    inner: Arc<Mutex<Storage>>,
+}
+
+impl AsyncStorage {
+    pub fn new(storage: Storage) -> Self {
+        Self { inner: Arc::new(Mutex::new(storage)) }
+    }
+
+    /// Open (or create) the database at `path`.
+    pub async fn open(path: PathBuf) -> Result<Self, String> {
+        let storage = tokio::task::spawn_blocking(move || Storage::init(&path))
+            .await
+            .map_err(|e| format!("Storage task failed: {}", e))??;
+        Ok(Self::new(storage))
+    }
+
+    /// A database in memory only, for stateless runs and tests.
+    pub fn in_memory() -> Result<Self, String> {
+        Storage::in_memory().map(Self::new)
+    }
+
+    /// Run `f` with the storage on the blocking pool.
+    pub async fn call<T, F>(&self, f: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Storage) -> Result<T, String> + Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        tokio::task::spawn_blocking(move || f(&inner.lock().unwrap()))
+            .await
+            .map_err(|e| format!("Storage task failed: {}", e))?
+    }
+}
+
+/// A transport that sends and receives packets asynchronously.
+pub trait AsyncTransport: Send + Sync {
+    fn send(&self, packet: Packet) -> impl Future<Output = Result<(), String>> + Send;
+    /// The next received packet; None once the transport is closed.
+    fn recv(&self) -> impl Future<Output = Option<Packet>> + Send;
+    fn name(&self) -> &'static str {
+        "async transport"
+    }
+}
+
+/// One end of an in-process transport pair.
+pub struct ChannelTransport {
+    tx: mpsc::UnboundedSender<Packet>,
+    rx: tokio::sync::Mutex<mpsc::UnboundedReceiver<Packet>>,
+}
+
+impl ChannelTransport {
+    /// Two connected ends: what one sends, the other receives.
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, b_rx) = mpsc::unbounded_channel();
+        let (b_tx, a_rx) = mpsc::unbounded_channel();
+        (
+            Self { tx: a_tx, rx: tokio::sync::Mutex::new(a_rx) },
+            Self { tx: b_tx, rx: tokio::sync::Mutex::new(b_rx) },
+        )
+    }
+}
+
+impl AsyncTransport for ChannelTransport {
+    async fn send(&self, packet: Packet) -> Result<(), String> {
+        self.tx.send(packet).map_err(|_| "Channel transport is closed".to_string())
+    }
+
+    async fn recv(&self) -> Option<Packet> {
+        self.rx.lock().await.recv().await
+    }
+
+    fn name(&self) -> &'static str {
+        "channel"
+    }
+}
+
+/// Pull a channel's history after `since` from peers on `transport`, page by
+/// page, until the last page arrives or a page does not come within
+/// `page_timeout`. Pass `identity` to sign the requests (needed for DMs).
+/// Returns how many messages were stored.
+pub async fn sync_channel<T: AsyncTransport>(
+    storage: &AsyncStorage,
+    transport: &T,
+    identity: Option<&Identity>,
+    channel_id: [u8; 32],
+    since: i64,
+    page_timeout: Duration,
+) -> Result<usize, String> {
+    let (mut since, mut after) = (since, None);
+    let mut stored = 0;
+    loop {
+        let request = history::build_request(identity, channel_id, since, after, 0, 0, crate::now_ts())?;
+        transport.send(request).await?;
+
+        let page = tokio::time::timeout(page_timeout, async {
+            while let Some(packet) = transport.recv().await {
+                if packet.kind != PacketKind::HistoryResponse || packet.channel_id != channel_id {
+                    continue;
+                }
+                let page = storage.call(move |s| history::handle_response(s, &packet, crate::now_ts())).await?;
+                if page.is_some() {
+                    return Ok(page);
+                }
+            }
+            Ok::<_, String>(None)
+        })
+        .await;
+
+        let page = match page {
+            Ok(result) => result?,
+            // Timed out: keep what arrived so far
+            Err(_) => None,
+        };
+        let Some(page) = page else {
+            return Ok(stored);
+        };
+        stored += page.stored;
+        match (page.next_since, page.next_after) {
+            (Some(next_since), Some(next_after)) if page.has_more => {
+                since = next_since;
+                after = Some(crate::codec::parse_id_hex(&next_after, "history cursor")?);
+            }
+            _ => return Ok(stored),
+        }
+    }
+}
+
+/// Answer history requests arriving on `transport` until it closes.
+/// `own_public` is our Ed25519 key, needed to serve DM history.
+pub async fn serve_history<T: AsyncTransport>(
+    storage: &AsyncStorage,
+    transport: &T,
+    own_public: Option<[u8; 32]>,
+) -> Result<(), String> {
+    while let Some(packet) = transport.recv().await {
+        if packet.kind != PacketKind::HistoryRequest {
+            continue;
+        }
+        let response = storage.call(move |s| history::handle_request(s, own_public, &packet, crate::now_ts())).await;
+        match response {
+            Ok(Some(response)) => transport.send(response).await?,
+            Ok(None) => {}
+            Err(e) => eprintln!("serve_history: rejected request: {}", e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_channel_over_channel_transport() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        let path_a = std::env::temp_dir().join(format!("meshapp-async-a-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path_a);
+        let channel = crate::geo::derive_geo_channel_id("u4pru", "general");
+
+        runtime.block_on(async {
+            // The client keeps nothing on disk
+            let (server, client) = (AsyncStorage::open(path_a.clone()).await.unwrap(), AsyncStorage::in_memory().unwrap());
+            server
+                .call(move |s| {
+                    s.upsert_channel(channel, "geo")?;
+                    for i in 0..3u8 {
+                        s.store_message([i + 1; 32], channel, vec![i], 100 + i as i64, 1)?;
+                    }
+                    Ok(())
+                })
+                .await
+                .unwrap();
+
+            let (server_end, client_end) = ChannelTransport::pair();
+            let serving = tokio::spawn({
+                let server = server.clone();
+                async move { serve_history(&server, &server_end, None).await }
+            });
+            let stored = sync_channel(&client, &client_end, None, channel, 0, Duration::from_secs(5)).await.unwrap();
+            assert_eq!(stored, 3);
+            let fetched = client.call(move |s| s.fetch_messages(channel, 10, 0)).await.unwrap();
+            assert_eq!(fetched.len(), 3);
+
+            drop(client_end);
+            serving.await.unwrap().unwrap();
+        });
+
+        let _ = std::fs::remove_file(&path_a);
+    }
+}
@@ -0,0 +1,830 @@
+//! Attachments
+//!
+//! Files attached to messages are content-addressed
+//! (`attachment_id = SHA256(bytes)`) and stored as blobs under
+//! `<data_dir>/attachments/`, with metadata in the `attachments` table.
+//! Over the mesh an attachment travels as:
+//! - an `AttachmentManifest` packet announcing id, size, mime and chunk count
+//! - `AttachmentChunk` packets of `CHUNK_SIZE` bytes
+//!   (`attachment_id || chunk_index (u32 BE) || data`)
+//!
+//! Images can carry a thumbnail: a small separate attachment (generated by
+//! the platform's image codecs, or by `derive_thumbnail` with the
+//! `thumbnails` feature) whose chunks are pushed right away, while the full
+//! image is only sent when a peer asks for it with an `AttachmentRequest`
+//! packet (`attachment_id || chunk bitmap`).
+//!
+//! Received chunks persist in `attachment_chunks` until the blob is
+//! assembled, so an interrupted download resumes after reconnection: the
+//! request carries a bitmap of the chunks we already hold and peers only
+//! send the missing ones.
+//!
+//! Blobs are reference counted through `attachment_refs` (one row per
+//! referencing message, thumbnails included). `gc` reclaims blobs once no
+//! message references them; references to messages that never arrived are
+//! dropped after `REF_GRACE_SECS`.
+//!
+//! Attachments of private channels (protected channels with a key, and DMs
+//! with a known peer key) travel encrypted. Each gets a random chunk key; a
+//! chunk's data is ChaCha20-Poly1305 under it, with the chunk index as nonce
+//! and the attachment id as associated data. The manifest carries the chunk
+//! key sealed (as in `forward::seal_for_channel`) under the channel key, or
+//! for DMs under a key derived from the Noise session seeding the ratchet
+//! we send on, named by `key_session`; the message the attachment belongs
+//! to is sent first, so the receiver holds that session. Nodes that cannot open the chunk key
+//! relay the packets but do not store the attachment. Blobs and chunks held
+//! in `attachment_chunks` are stored decrypted, like other local data.
+//!
+//! Blobs are never loaded whole to be sent: chunks are read from disk one
+//! at a time (`BlobReader`), and received chunks are streamed from the
+//! database into the blob file while its hash is checked, so forwarding or
+//! receiving a large file keeps memory bounded on low-RAM phones.
+
+use crate::codec;
+use crate::error::MeshError;
+use crate::forward;
+use crate::priority::Priority;
+use crate::storage::{AttachmentRow, Storage};
+use crate::transport::{Packet, PacketKind, Router};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// Directory (under the data dir) holding attachment blobs
+pub const ATTACHMENTS_DIR: &str = "attachments";
+
+/// Bytes of attachment data per chunk packet
+pub const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Bytes an encrypted chunk adds (the Poly1305 tag)
+pub const CHUNK_TAG_SIZE: usize = 16;
+
+/// Thumbnails must stay small so they can be pushed eagerly
+pub const MAX_THUMBNAIL_BYTES: usize = 32 * 1024;
+
+/// Longest side of a derived thumbnail, in pixels
+#[cfg(feature = "thumbnails")]
+pub const THUMBNAIL_MAX_DIMENSION: u32 = 160;
+
+/// JPEG quality of a derived thumbnail
+#[cfg(feature = "thumbnails")]
+const THUMBNAIL_QUALITY: u8 = 70;
+
+/// How long a reference may point at a message we have not stored yet
+/// (manifests can arrive before their message)
+pub const REF_GRACE_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// Result of an attachment garbage collection pass
+#[derive(Serialize, Default, Debug)]
+pub struct GcStats {
+    pub attachments_removed: u32,
+    pub bytes_reclaimed: u64,
+}
+
+/// Attachment announcement carried in `AttachmentManifest` packets
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AttachmentManifest {
+    pub attachment_id: String, // hex
+    pub message_id: String,    // hex
+    pub parent_id: Option<String>, // hex, set for thumbnails
+    pub mime: String,
+    pub size: u64,
+    pub chunk_count: u32,
+    /// Sender pushes the data right away (no request needed)
+    #[serde(default)]
+    pub pushed: bool,
+    /// Chunk key sealed for the channel (hex); absent when chunks are plain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed_key: Option<String>,
+    /// DM session whose key sealed the chunk key (hex)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_session: Option<String>,
+}
+
+/// Download progress of an attachment
+#[derive(Serialize, Debug)]
+pub struct AttachmentProgress {
+    pub attachment_id: String, // hex
+    pub received_chunks: u32,
+    pub chunk_count: u32,
+    pub received_bytes: u64,
+    pub size: u64,
+    pub complete: bool,
+    pub requested: bool,
+}
+
+/// Bitmap of received chunks (bit `i % 8` of byte `i / 8` is chunk `i`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChunkBitmap {
+    bits: Vec<u8>,
+    chunk_count: u32,
+}
+
+impl ChunkBitmap {
+    /// Create an empty bitmap for `chunk_count` chunks.
+    pub fn new(chunk_count: u32) -> Self {
+        Self {
+            bits: vec![0u8; (chunk_count as usize).div_ceil(8)],
+            chunk_count,
+        }
+    }
+
+    /// Parse a bitmap received over the wire (extra trailing bytes are rejected).
+    pub fn from_bytes(bytes: &[u8], chunk_count: u32) -> Option<Self> {
+        let mut bitmap = Self::new(chunk_count);
+        if bytes.len() != bitmap.bits.len() {
+            return None;
+        }
+        bitmap.bits.copy_from_slice(bytes);
+        Some(bitmap)
+    }
+
+    pub fn set(&mut self, index: u32) {
+        if index < self.chunk_count {
+            self.bits[(index / 8) as usize] |= 1 << (index % 8);
+        }
+    }
+
+    pub fn contains(&self, index: u32) -> bool {
+        index < self.chunk_count && self.bits[(index / 8) as usize] & (1 << (index % 8)) != 0
+    }
+
+    /// Number of chunks marked as received.
+    pub fn count(&self) -> u32 {
+        self.bits.iter().map(|b| b.count_ones()).sum()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+}
+
+/// Compute the content address of attachment bytes.
+pub fn attachment_id_for(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Store a local attachment (e.g., picked by the user) for a message.
+pub fn store_local(
+    storage: &Storage,
+    message_id: [u8; 32],
+    channel_id: [u8; 32],
+    mime: &str,
+    data: &[u8],
+) -> Result<[u8; 32], String> {
+    store_blob(storage, message_id, channel_id, None, mime, data)
+}
+
+/// Attach a thumbnail to an existing attachment.
+/// Returns the thumbnail's attachment_id.
+pub fn set_thumbnail(
+    storage: &Storage,
+    attachment_id: [u8; 32],
+    mime: &str,
+    data: &[u8],
+) -> Result<[u8; 32], String> {
+    if data.len() > MAX_THUMBNAIL_BYTES {
+        return Err(format!(
+            "Thumbnail is {} bytes, limit is {}",
+            data.len(),
+            MAX_THUMBNAIL_BYTES
+        ));
+    }
+
+    let parent = storage
+        .get_attachment(attachment_id)?
+        .ok_or_else(|| MeshError::NotFound.raise("Attachment not found"))?;
+
+    store_blob(
+        storage,
+        parent.message_id,
+        parent.channel_id,
+        Some(attachment_id),
+        mime,
+        data,
+    )
+}
+
+/// Decode an image attachment, scale it down to at most
+/// `THUMBNAIL_MAX_DIMENSION` pixels a side and store it as the attachment's
+/// JPEG thumbnail (see `set_thumbnail`). `data` and `mime` are the image's.
+/// Returns the thumbnail's attachment_id.
+#[cfg(feature = "thumbnails")]
+pub fn derive_thumbnail(storage: &Storage, attachment_id: [u8; 32], data: &[u8], mime: &str) -> Result<[u8; 32], String> {
+    use image::codecs::jpeg::JpegEncoder;
+
+    let format = image::ImageFormat::from_mime_type(mime)
+        .ok_or_else(|| MeshError::InvalidArgument.raise(format!("Cannot make a thumbnail of {}", mime)))?;
+    let image = image::load_from_memory_with_format(data, format)
+        .map_err(|e| MeshError::InvalidArgument.raise(format!("Failed to decode image: {}", e)))?;
+
+    // Busy pictures can still be too large: halve the size until it fits
+    let mut dimension = THUMBNAIL_MAX_DIMENSION;
+    loop {
+        let thumbnail = image.thumbnail(dimension, dimension).into_rgb8();
+        let mut encoded = Vec::new();
+        JpegEncoder::new_with_quality(&mut encoded, THUMBNAIL_QUALITY)
+            .encode_image(&thumbnail)
+            .map_err(|e| format!("Failed to encode thumbnail: {}", e))?;
+        if encoded.len() <= MAX_THUMBNAIL_BYTES || dimension <= 1 {
+            return set_thumbnail(storage, attachment_id, "image/jpeg", &encoded);
+        }
+        dimension /= 2;
+    }
+}
+
+/// Read a complete attachment blob from disk.
+pub fn read_blob(attachment_id: &[u8; 32]) -> Result<Vec<u8>, String> {
+    fs::read(blob_path(attachment_id)?)
+        .map_err(|e| format!("Failed to read attachment blob: {}", e))
+}
+
+/// Chunk-at-a-time reader over a stored blob
+pub struct BlobReader {
+    file: BufReader<fs::File>,
+    size: u64,
+    /// Offset the reader is at (sequential reads skip the seek)
+    position: u64,
+}
+
+impl BlobReader {
+    pub fn open(attachment_id: &[u8; 32]) -> Result<Self, String> {
+        let file = fs::File::open(blob_path(attachment_id)?)
+            .map_err(|e| format!("Failed to open attachment blob: {}", e))?;
+        let size = file
+            .metadata()
+            .map_err(|e| format!("Failed to stat attachment blob: {}", e))?
+            .len();
+        Ok(Self {
+            file: BufReader::with_capacity(CHUNK_SIZE, file),
+            size,
+            position: 0,
+        })
+    }
+
+    pub fn chunk_count(&self) -> u32 {
+        self.size.div_ceil(CHUNK_SIZE as u64) as u32
+    }
+
+    /// Read chunk `index` (the last chunk may be short).
+    pub fn read_chunk(&mut self, index: u32) -> Result<Vec<u8>, String> {
+        let offset = index as u64 * CHUNK_SIZE as u64;
+        if offset >= self.size {
+            return Err(MeshError::InvalidArgument.raise(format!("Chunk {} out of range", index)));
+        }
+        if offset != self.position {
+            self.file
+                .seek(SeekFrom::Start(offset))
+                .map_err(|e| format!("Failed to seek attachment blob: {}", e))?;
+        }
+        let mut data = vec![0u8; (self.size - offset).min(CHUNK_SIZE as u64) as usize];
+        self.file
+            .read_exact(&mut data)
+            .map_err(|e| format!("Failed to read attachment blob: {}", e))?;
+        self.position = offset + data.len() as u64;
+        Ok(data)
+    }
+}
+
+/// Read one chunk of a complete attachment blob.
+pub fn read_chunk(attachment_id: &[u8; 32], index: u32) -> Result<Vec<u8>, String> {
+    BlobReader::open(attachment_id)?.read_chunk(index)
+}
+
+/// Build the packets that announce an attachment to the channel.
+///
+/// If the attachment has a thumbnail, only the thumbnail's data is pushed;
+/// the full blob waits for an `AttachmentRequest`. Attachments without a
+/// thumbnail are pushed in full.
+pub fn announce_packets(storage: &Storage, attachment_id: [u8; 32], ttl: u8) -> Result<Vec<Packet>, String> {
+    let row = storage
+        .get_attachment(attachment_id)?
+        .ok_or_else(|| MeshError::NotFound.raise("Attachment not found"))?;
+
+    let packets = match storage.get_thumbnail(attachment_id)? {
+        Some(thumb) => {
+            let mut packets = vec![manifest_packet(storage, &row, ttl, false)?, manifest_packet(storage, &thumb, ttl, true)?];
+            packets.extend(chunk_packets(&thumb, ttl, None)?);
+            packets
+        }
+        None => {
+            let mut packets = vec![manifest_packet(storage, &row, ttl, true)?];
+            packets.extend(chunk_packets(&row, ttl, None)?);
+            packets
+        }
+    };
+    Ok(packets)
+}
+
+/// Build a request packet asking peers for the chunks of an attachment we
+/// are still missing, and remember that we want it (for `resume_packets`).
+pub fn request_packet(storage: &Storage, attachment_id: [u8; 32], ttl: u8) -> Result<Packet, String> {
+    let row = storage
+        .get_attachment(attachment_id)?
+        .ok_or_else(|| MeshError::NotFound.raise("Attachment not found"))?;
+    if row.complete {
+        return Err(MeshError::Rejected.raise("Attachment already complete"));
+    }
+
+    storage.set_attachment_requested(attachment_id, true)?;
+    let bitmap = received_bitmap(storage, &row)?;
+
+    let mut payload = attachment_id.to_vec();
+    payload.extend_from_slice(bitmap.as_bytes());
+    Ok(Packet {
+        packet_id: Router::generate_packet_id(),
+        channel_id: row.channel_id,
+        kind: PacketKind::AttachmentRequest,
+        ttl,
+        payload,
+        priority: Priority::Normal,
+    })
+}
+
+/// Build request packets for every wanted attachment that is still incomplete
+/// (call after reconnecting to resume interrupted downloads).
+pub fn resume_packets(storage: &Storage, ttl: u8) -> Result<Vec<Packet>, String> {
+    storage
+        .list_pending_attachments()?
+        .into_iter()
+        .map(|row| request_packet(storage, row.attachment_id, ttl))
+        .collect()
+}
+
+/// Report how much of an attachment has arrived.
+pub fn progress(storage: &Storage, attachment_id: [u8; 32]) -> Result<AttachmentProgress, String> {
+    let row = storage
+        .get_attachment(attachment_id)?
+        .ok_or_else(|| MeshError::NotFound.raise("Attachment not found"))?;
+
+    let (received_chunks, received_bytes) = if row.complete {
+        (row.chunk_count, row.size)
+    } else {
+        let received = received_bitmap(storage, &row)?.count();
+        // All chunks but the last one are full-sized
+        let bytes = (received as u64 * CHUNK_SIZE as u64).min(row.size);
+        (received, bytes)
+    };
+
+    Ok(AttachmentProgress {
+        attachment_id: hex::encode(attachment_id),
+        received_chunks,
+        chunk_count: row.chunk_count,
+        received_bytes,
+        size: row.size,
+        complete: row.complete,
+        requested: row.requested,
+    })
+}
+
+/// Remove attachments no message references any more, with their blobs.
+pub fn gc(storage: &Storage) -> Result<GcStats, String> {
+    crate::storage::ensure_writable()?;
+    storage.prune_dangling_attachment_refs(crate::now_ts() - REF_GRACE_SECS)?;
+
+    let mut stats = GcStats::default();
+    for row in storage.list_unreferenced_attachments()? {
+        let path = blob_path(&row.attachment_id)?;
+        if let Ok(meta) = fs::metadata(&path) {
+            fs::remove_file(&path)
+                .map_err(|e| format!("Failed to remove attachment blob: {}", e))?;
+            stats.bytes_reclaimed += meta.len();
+        }
+        storage.delete_attachment(row.attachment_id)?;
+        stats.attachments_removed += 1;
+    }
+    Ok(stats)
+}
+
+fn received_bitmap(storage: &Storage, row: &AttachmentRow) -> Result<ChunkBitmap, String> {
+    let mut bitmap = ChunkBitmap::new(row.chunk_count);
+    for index in storage.list_attachment_chunk_indices(row.attachment_id)? {
+        bitmap.set(index);
+    }
+    Ok(bitmap)
+}
+
+/// Handle an incoming manifest: register the attachment as pending.
+pub fn ingest_manifest(storage: &Storage, packet: &Packet) -> Result<(), String> {
+    let manifest: AttachmentManifest = serde_json::from_slice(&packet.payload)
+        .map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid attachment manifest: {}", e)))?;
+
+    let expected_chunks = manifest.size.div_ceil(CHUNK_SIZE as u64);
+    if manifest.chunk_count as u64 != expected_chunks {
+        return Err("Attachment manifest chunk count does not match size".to_string());
+    }
+
+    let attachment_id = codec::parse_id_hex(&manifest.attachment_id, "attachment id")?;
+    let chunk_key = match manifest.sealed_key {
+        Some(ref sealed) => Some(open_chunk_key(storage, packet.channel_id, attachment_id, sealed, manifest.key_session.as_deref())?),
+        None => None,
+    };
+
+    let row = AttachmentRow {
+        attachment_id,
+        message_id: codec::parse_id_hex(&manifest.message_id, "message id")?,
+        channel_id: packet.channel_id,
+        parent_id: match manifest.parent_id {
+            Some(ref p) => Some(codec::parse_id_hex(p, "parent id")?),
+            None => None,
+        },
+        mime: manifest.mime,
+        size: manifest.size,
+        chunk_count: manifest.chunk_count,
+        complete: false,
+        created_at: crate::now_ts(),
+        requested: manifest.pushed,
+        chunk_key,
+    };
+    storage.insert_attachment(&row)
+}
+
+/// Handle an incoming chunk.
+/// Returns the attachment row once the last chunk arrives and the blob
+/// has been assembled and verified.
+pub fn ingest_chunk(storage: &Storage, packet: &Packet) -> Result<Option<AttachmentRow>, String> {
+    if packet.payload.len() < 36 {
+        return Err(MeshError::InvalidArgument.raise("Attachment chunk too short"));
+    }
+    let attachment_id = codec::read_array(&packet.payload, 0, "attachment id")?;
+    let index = codec::read_u32_be(&packet.payload, 32, "chunk index")?;
+    let data = &packet.payload[36..];
+
+    // Chunks are only accepted for announced attachments
+    let row = match storage.get_attachment(attachment_id)? {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    if row.complete || index >= row.chunk_count || data.len() > CHUNK_SIZE + CHUNK_TAG_SIZE {
+        return Ok(None);
+    }
+    let data = match row.chunk_key {
+        Some(ref key) => open_chunk(key, &attachment_id, index, data)?,
+        None => data.to_vec(),
+    };
+    if data.len() > CHUNK_SIZE {
+        return Ok(None);
+    }
+
+    storage.store_attachment_chunk(attachment_id, index, &data)?;
+    if storage.count_attachment_chunks(attachment_id)? < row.chunk_count {
+        return Ok(None);
+    }
+
+    let assembled = assemble_blob(storage, &row);
+    storage.delete_attachment_chunks(attachment_id)?;
+    assembled?;
+    storage.set_attachment_complete(attachment_id, true)?;
+    Ok(Some(AttachmentRow { complete: true, ..row }))
+}
+
+/// Handle an incoming request: return the requester's missing chunks if we
+/// hold the blob.
+pub fn handle_request(storage: &Storage, packet: &Packet) -> Result<Vec<Packet>, String> {
+    if packet.payload.len() < 32 {
+        return Err(MeshError::InvalidArgument.raise("Invalid attachment request"));
+    }
+    let attachment_id = codec::read_array(&packet.payload, 0, "attachment id")?;
+
+    let row = match storage.get_attachment(attachment_id)? {
+        Some(row) if row.complete => row,
+        _ => return Ok(Vec::new()),
+    };
+
+    // A request without a bitmap asks for everything
+    let have = &packet.payload[32..];
+    let skip = if have.is_empty() {
+        None
+    } else {
+        Some(ChunkBitmap::from_bytes(have, row.chunk_count).ok_or_else(|| MeshError::InvalidArgument.raise("Invalid chunk bitmap in request"))?)
+    };
+    chunk_packets(&row, packet.ttl, skip.as_ref())
+}
+
+fn store_blob(
+    storage: &Storage,
+    message_id: [u8; 32],
+    channel_id: [u8; 32],
+    parent_id: Option<[u8; 32]>,
+    mime: &str,
+    data: &[u8],
+) -> Result<[u8; 32], String> {
+    let attachment_id = attachment_id_for(data);
+    write_blob(&attachment_id, data)?;
+    let private = storage.get_channel_key(channel_id)?.is_some() || storage.get_dm_peer_key(channel_id)?.is_some();
+    let chunk_key = private.then(|| {
+        let mut key = [0u8; 32];
+        crate::rng::rng().fill_bytes(&mut key);
+        key
+    });
+
+    let row = AttachmentRow {
+        attachment_id,
+        message_id,
+        channel_id,
+        parent_id,
+        mime: mime.to_string(),
+        size: data.len() as u64,
+        chunk_count: data.len().div_ceil(CHUNK_SIZE) as u32,
+        complete: true,
+        created_at: crate::now_ts(),
+        requested: false,
+        chunk_key,
+    };
+    storage.insert_attachment(&row)?;
+    Ok(attachment_id)
+}
+
+fn manifest_packet(storage: &Storage, row: &AttachmentRow, ttl: u8, pushed: bool) -> Result<Packet, String> {
+    let (sealed_key, key_session) = match row.chunk_key {
+        Some(ref chunk_key) => {
+            let (key, session) = sealing_key(storage, row.channel_id)?;
+            let sealed = forward::seal_for_channel(&key, &row.attachment_id, chunk_key)?;
+            (Some(hex::encode(sealed)), session.map(hex::encode))
+        }
+        None => (None, None),
+    };
+    let manifest = AttachmentManifest {
+        attachment_id: hex::encode(row.attachment_id),
+        message_id: hex::encode(row.message_id),
+        parent_id: row.parent_id.map(hex::encode),
+        mime: row.mime.clone(),
+        size: row.size,
+        chunk_count: row.chunk_count,
+        pushed,
+        sealed_key,
+        key_session,
+    };
+    let payload = serde_json::to_vec(&manifest)
+        .map_err(|e| format!("Failed to serialize manifest: {}", e))?;
+
+    Ok(Packet {
+        packet_id: Router::generate_packet_id(),
+        channel_id: row.channel_id,
+        kind: PacketKind::AttachmentManifest,
+        ttl,
+        payload,
+        priority: Priority::Normal,
+    })
+}
+
+fn chunk_packets(row: &AttachmentRow, ttl: u8, skip: Option<&ChunkBitmap>) -> Result<Vec<Packet>, String> {
+    let mut reader = BlobReader::open(&row.attachment_id)?;
+    let mut packets = Vec::new();
+    for index in (0..reader.chunk_count()).filter(|i| !skip.is_some_and(|s| s.contains(*i))) {
+        let mut data = reader.read_chunk(index)?;
+        if let Some(ref key) = row.chunk_key {
+            data = seal_chunk(key, &row.attachment_id, index, &data)?;
+        }
+        let mut payload = Vec::with_capacity(36 + data.len());
+        payload.extend_from_slice(&row.attachment_id);
+        payload.extend_from_slice(&index.to_be_bytes());
+        payload.extend_from_slice(&data);
+        // Fresh ids so re-requested chunks are not dropped as duplicates
+        packets.push(Packet {
+            packet_id: Router::generate_packet_id(),
+            channel_id: row.channel_id,
+            kind: PacketKind::AttachmentChunk,
+            ttl,
+            payload,
+            priority: Priority::Normal,
+        });
+    }
+    Ok(packets)
+}
+
+/// The key that seals chunk keys for a private channel we send on: the
+/// channel key, or one derived from the DM session seeding the ratchet we
+/// send on (with its id).
+fn sealing_key(storage: &Storage, channel_id: [u8; 32]) -> Result<([u8; 32], Option<[u8; 32]>), String> {
+    if let Some(key) = storage.get_channel_key(channel_id)? {
+        return Ok((key, None));
+    }
+    let ratchet = match storage.get_dm_peer_key(channel_id)? {
+        Some(peer) => storage.sending_dm_ratchet(channel_id, peer)?,
+        None => None,
+    };
+    let session = match ratchet {
+        Some(ratchet) => storage.get_dm_session(ratchet.ratchet_id)?,
+        None => None,
+    }
+    .ok_or("No key to seal the attachment for its channel")?;
+    Ok((session_sealing_key(&session.key), Some(session.session_id)))
+}
+
+fn session_sealing_key(session_key: &[u8; 32]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(b"meshapp-attachment-key")
+        .chain_update(session_key)
+        .finalize()
+        .into()
+}
+
+/// Open the chunk key of a manifest, with a DM session or any of the
+/// channel's key epochs.
+fn open_chunk_key(
+    storage: &Storage,
+    channel_id: [u8; 32],
+    attachment_id: [u8; 32],
+    sealed_hex: &str,
+    session_hex: Option<&str>,
+) -> Result<[u8; 32], String> {
+    let sealed = hex::decode(sealed_hex).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid sealed attachment key: {}", e)))?;
+    let keys: Vec<[u8; 32]> = match session_hex {
+        Some(session) => storage
+            .get_dm_session(codec::parse_id_hex(session, "key session")?)?
+            .filter(|s| s.channel_id == channel_id)
+            .map(|s| session_sealing_key(&s.key))
+            .into_iter()
+            .collect(),
+        None => storage.list_channel_key_epochs(channel_id)?.iter().map(|e| e.key).collect(),
+    };
+    keys.iter()
+        .find_map(|key| forward::open_for_channel(key, &attachment_id, &sealed).ok())
+        .and_then(|key| <[u8; 32]>::try_from(key).ok())
+        .ok_or_else(|| MeshError::Crypto.raise("Attachment key does not decrypt"))
+}
+
+fn chunk_nonce(index: u32) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[8..].copy_from_slice(&index.to_be_bytes());
+    nonce
+}
+
+fn seal_chunk(key: &[u8; 32], attachment_id: &[u8; 32], index: u32, data: &[u8]) -> Result<Vec<u8>, String> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(Nonce::from_slice(&chunk_nonce(index)), Payload { msg: data, aad: attachment_id })
+        .map_err(|_| MeshError::Crypto.raise("Failed to encrypt attachment chunk"))
+}
+
+fn open_chunk(key: &[u8; 32], attachment_id: &[u8; 32], index: u32, data: &[u8]) -> Result<Vec<u8>, String> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(Nonce::from_slice(&chunk_nonce(index)), Payload { msg: data, aad: attachment_id })
+        .map_err(|_| MeshError::Crypto.raise("Failed to decrypt attachment chunk"))
+}
+
+/// Stream the received chunks of `row` into its blob file, checking size
+/// and hash on the way. The `.part` file is removed if verification fails.
+fn assemble_blob(storage: &Storage, row: &AttachmentRow) -> Result<(), String> {
+    crate::storage::ensure_writable()?;
+    let path = blob_path(&row.attachment_id)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create attachments directory: {}", e))?;
+    }
+
+    let part_path = path.with_extension("part");
+    let file = fs::File::create(&part_path)
+        .map_err(|e| format!("Failed to create attachment blob: {}", e))?;
+    let mut writer = BufWriter::new(file);
+    let mut hasher = Sha256::new();
+    let mut written = 0u64;
+    let streamed = storage.for_each_attachment_chunk(row.attachment_id, |data| {
+        hasher.update(data);
+        written += data.len() as u64;
+        writer
+            .write_all(data)
+            .map_err(|e| format!("Failed to write attachment blob: {}", e))
+    });
+
+    let verified = streamed.and_then(|()| {
+        if written != row.size || <[u8; 32]>::from(hasher.finalize()) != row.attachment_id {
+            return Err(format!(
+                "Attachment {} failed verification, discarded",
+                hex::encode(row.attachment_id)
+            ));
+        }
+        writer
+            .into_inner()
+            .map_err(|e| format!("Failed to write attachment blob: {}", e))?
+            .sync_all()
+            .map_err(|e| format!("Failed to sync attachment blob: {}", e))
+    });
+    if let Err(e) = verified {
+        let _ = fs::remove_file(&part_path);
+        return Err(e);
+    }
+
+    fs::rename(&part_path, &path)
+        .map_err(|e| format!("Failed to rename attachment blob: {}", e))
+}
+
+/// Write a blob atomically (`.part`, then rename to `.blob`).
+fn write_blob(attachment_id: &[u8; 32], data: &[u8]) -> Result<(), String> {
+    crate::storage::ensure_writable()?;
+    let path = blob_path(attachment_id)?;
+    if path.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create attachments directory: {}", e))?;
+    }
+
+    let part_path = path.with_extension("part");
+    let mut file = fs::File::create(&part_path)
+        .map_err(|e| format!("Failed to create attachment blob: {}", e))?;
+    file.write_all(data)
+        .map_err(|e| format!("Failed to write attachment blob: {}", e))?;
+    file.sync_all()
+        .map_err(|e| format!("Failed to sync attachment blob: {}", e))?;
+    drop(file);
+
+    fs::rename(&part_path, &path)
+        .map_err(|e| format!("Failed to rename attachment blob: {}", e))
+}
+
+fn blob_path(attachment_id: &[u8; 32]) -> Result<PathBuf, String> {
+    Ok(crate::storage::data_dir()?
+        .join(ATTACHMENTS_DIR)
+        .join(format!("{}.blob", hex::encode(attachment_id))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_bitmap_roundtrip() {
+        let mut bitmap = ChunkBitmap::new(10);
+        bitmap.set(0);
+        bitmap.set(9);
+        bitmap.set(42); // out of range, ignored
+
+        assert_eq!(bitmap.count(), 2);
+        assert!(bitmap.contains(9) && !bitmap.contains(1));
+
+        let parsed = ChunkBitmap::from_bytes(bitmap.as_bytes(), 10).unwrap();
+        assert_eq!(parsed, bitmap);
+        assert!(ChunkBitmap::from_bytes(&[0u8; 3], 10).is_none());
+    }
+
+    #[test]
+    fn test_dm_chunks_travel_encrypted() {
+        use crate::dm_crypto::{self, DmSessionManager};
+        use crate::identity::Identity;
+
+        let [alice_storage, bob_storage, relay_storage] = [(); 3].map(|_| Storage::in_memory().unwrap());
+        let (alice, bob) = (Identity::generate(), Identity::generate());
+        let channel = dm_crypto::derive_dm_channel_id(
+            alice.public().ed25519_public.as_bytes(),
+            bob.public().ed25519_public.as_bytes(),
+        );
+        let bob_x25519 = *bob.public().x25519_public.as_bytes();
+        alice_storage.set_dm_peer_key(channel, bob_x25519, 100).unwrap();
+
+        // The message goes first and sets up the session the key is sealed with
+        let sent = DmSessionManager::new(&alice_storage, alice.x25519_secret())
+            .encrypt(channel, bob_x25519, [8u8; 32], b"photo", 100)
+            .unwrap();
+        DmSessionManager::new(&bob_storage, bob.x25519_secret())
+            .decrypt(channel, Some(*alice.public().x25519_public.as_bytes()), [8u8; 32], &sent, 101)
+            .unwrap();
+
+        let row = AttachmentRow {
+            attachment_id: [9u8; 32],
+            message_id: [8u8; 32],
+            channel_id: channel,
+            parent_id: None,
+            mime: "image/png".to_string(),
+            size: CHUNK_SIZE as u64 + 1,
+            chunk_count: 2,
+            complete: true,
+            created_at: 100,
+            requested: false,
+            chunk_key: Some([7u8; 32]),
+        };
+        let manifest = manifest_packet(&alice_storage, &row, 3, true).unwrap();
+        ingest_manifest(&bob_storage, &manifest).unwrap();
+        assert_eq!(bob_storage.get_attachment(row.attachment_id).unwrap().unwrap().chunk_key, row.chunk_key);
+        assert!(ingest_manifest(&relay_storage, &manifest).is_err());
+
+        let data = vec![5u8; CHUNK_SIZE];
+        let mut payload = row.attachment_id.to_vec();
+        payload.extend_from_slice(&0u32.to_be_bytes());
+        payload.extend_from_slice(&seal_chunk(&[7u8; 32], &row.attachment_id, 0, &data).unwrap());
+        assert!(!payload.windows(64).any(|w| w == &data[..64]));
+        let chunk = Packet { packet_id: [1u8; 32], channel_id: channel, kind: PacketKind::AttachmentChunk, ttl: 3, payload, priority: Priority::Normal };
+        assert!(ingest_chunk(&bob_storage, &chunk).unwrap().is_none());
+        let mut stored = Vec::new();
+        bob_storage
+            .for_each_attachment_chunk(row.attachment_id, |d| {
+                stored.extend_from_slice(d);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(stored, data);
+
+        // A chunk under another key is refused
+        let mut forged = chunk.clone();
+        forged.payload.truncate(36);
+        forged.payload.extend_from_slice(&seal_chunk(&[6u8; 32], &row.attachment_id, 1, b"x").unwrap());
+        forged.payload[35] = 1;
+        assert!(ingest_chunk(&bob_storage, &forged).is_err());
+    }
+}
@@ -0,0 +1,298 @@
+//! Auditor exports (`audit` feature)
+//!
+//! Security reviewers check the implementation against the Noise and AEAD
+//! specs with real transcripts, not instrumented builds. Built with the
+//! `audit` feature, the core can export one channel, chosen beforehand with
+//! `select_channel`, as JSON:
+//!
+//! - DM channels: our static X25519 key pair, every Noise IK session (the
+//!   handshake message and the initiator-to-responder key from the split),
+//!   the current state of every Double Ratchet they seeded, and each message
+//!   split into handshake, counter (sessions) or message key (ratchets),
+//!   nonce, associated data, AEAD ciphertext and the plaintext it opens to
+//!   (long bodies still compressed, see `compression`)
+//! - group and other protected channels: every key epoch, and each message
+//!   with its nonce, associated data (the message id) and the epoch opening it
+//!
+//! Only the selected channel is exported, so a test channel can be audited
+//! on a device that also holds real ones. Builds without the feature have
+//! none of this.
+
+use crate::dm_crypto::{self, DmSessionManager};
+use crate::error::MeshError;
+use crate::forward;
+use crate::identity::Identity;
+use crate::storage::{Storage, MESSAGE_KIND_USER};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Setting holding the channel id hex exports are allowed for
+pub const AUDIT_CHANNEL_SETTING: &str = "audit.channel";
+
+/// A Noise session and the keys it derived
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SessionSchedule {
+    /// SHA-256 of the handshake message
+    pub session_id: String,
+    pub outgoing: bool,
+    pub remote_static: String,
+    /// Noise IK first message (e, es, s, ss)
+    pub handshake: String,
+    /// Initiator-to-responder key from the split
+    pub key: String,
+    pub next_counter: u64,
+    pub created_at: i64,
+}
+
+/// The current state of a DM Double Ratchet
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RatchetSchedule {
+    /// Id of the session that seeded it
+    pub ratchet_id: String,
+    pub initiator: bool,
+    pub root_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dh_remote: Option<String>,
+    pub send_count: u32,
+    pub recv_count: u32,
+    pub prev_send_count: u32,
+    pub created_at: i64,
+}
+
+/// A key a protected channel has had
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyEpoch {
+    pub key: String,
+    pub added_at: i64,
+}
+
+/// One stored message, taken apart
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct TranscriptEntry {
+    pub message_id: String,
+    pub timestamp: i64,
+    pub kind: u8,
+    /// The stored payload, as sent
+    pub ciphertext: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counter: Option<u64>,
+    /// Ratchet messages: the message key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub associated_data: Option<String>,
+    /// added_at of the key epoch that opens it (protected channels)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_epoch: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plaintext: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Our static X25519 key pair
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct StaticKeyPair {
+    pub public: String,
+    pub private: String,
+}
+
+/// Everything exported for one channel
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChannelAudit {
+    pub channel_id: String,
+    pub channel_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub noise_pattern: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_static: Option<StaticKeyPair>,
+    pub sessions: Vec<SessionSchedule>,
+    pub ratchets: Vec<RatchetSchedule>,
+    pub key_epochs: Vec<KeyEpoch>,
+    pub transcript: Vec<TranscriptEntry>,
+}
+
+/// Choose the channel exports are allowed for (None: no channel).
+pub fn select_channel(storage: &Storage, channel_id: Option<[u8; 32]>) -> Result<(), String> {
+    storage.set_setting(AUDIT_CHANNEL_SETTING, &channel_id.map(hex::encode).unwrap_or_default())
+}
+
+/// Export the selected channel's key schedule and transcript.
+pub fn export_channel(identity: &Identity, storage: &Storage, channel_id: [u8; 32], now: i64) -> Result<ChannelAudit, String> {
+    if storage.get_setting(AUDIT_CHANNEL_SETTING)?.as_deref() != Some(hex::encode(channel_id).as_str()) {
+        return Err(MeshError::Rejected.raise("Channel is not selected for audit"));
+    }
+    let channel_type = storage.get_channel_type(channel_id)?.ok_or_else(|| MeshError::NotFound.raise("Unknown channel"))?;
+    let messages = storage.fetch_messages(channel_id, u32::MAX, 0)?;
+
+    if channel_type == "dm" {
+        let secret = identity.x25519_secret();
+        let sessions = DmSessionManager::new(storage, secret);
+        let transcript = messages
+            .iter()
+            .map(|row| {
+                let mut entry = TranscriptEntry {
+                    message_id: hex::encode(row.message_id),
+                    timestamp: row.timestamp,
+                    kind: row.kind,
+                    ciphertext: hex::encode(&row.ciphertext),
+                    ..Default::default()
+                };
+                let opens = if let Some((handshake, counter, _)) = dm_crypto::split_session_message(&row.ciphertext) {
+                    entry.session_id = Some(hex::encode(Sha256::digest(handshake)));
+                    entry.counter = Some(counter);
+                    entry.nonce = Some(hex::encode(dm_crypto::counter_nonce(counter)));
+                    entry.associated_data = Some(hex::encode(channel_id));
+                    true
+                } else if let Some((handshake, header, _)) = dm_crypto::split_ratchet_message(&row.ciphertext) {
+                    entry.session_id = Some(hex::encode(Sha256::digest(handshake)));
+                    entry.nonce = Some(hex::encode([0u8; 12]));
+                    entry.associated_data = Some(hex::encode([&channel_id[..], header].concat()));
+                    true
+                } else {
+                    false
+                };
+                if opens {
+                    match sessions.decrypt_sealed(channel_id, None, row.message_id, &row.ciphertext, now) {
+                        Ok(opened) => entry.plaintext = Some(hex::encode(opened.plaintext)),
+                        Err(e) => entry.error = Some(e),
+                    }
+                    if let Ok(Some((key, _))) = storage.get_dm_message_key(row.message_id) {
+                        entry.message_key = Some(hex::encode(key));
+                    }
+                } else if row.kind == MESSAGE_KIND_USER {
+                    entry.error = Some("Not a session-encrypted DM".to_string());
+                }
+                entry
+            })
+            .collect();
+        return Ok(ChannelAudit {
+            channel_id: hex::encode(channel_id),
+            channel_type,
+            noise_pattern: Some("Noise_IK_25519_ChaChaPoly_SHA256".to_string()),
+            local_static: Some(StaticKeyPair {
+                public: hex::encode(identity.public().x25519_public.as_bytes()),
+                private: hex::encode(secret.to_bytes()),
+            }),
+            sessions: storage
+                .list_dm_sessions(channel_id)?
+                .into_iter()
+                .map(|s| SessionSchedule {
+                    session_id: hex::encode(s.session_id),
+                    outgoing: s.outgoing,
+                    remote_static: hex::encode(s.remote_static),
+                    handshake: hex::encode(&s.handshake),
+                    key: hex::encode(s.key),
+                    next_counter: s.next_counter,
+                    created_at: s.created_at,
+                })
+                .collect(),
+            ratchets: storage
+                .list_dm_ratchets(channel_id)?
+                .into_iter()
+                .map(|r| RatchetSchedule {
+                    ratchet_id: hex::encode(r.ratchet_id),
+                    initiator: r.initiator,
+                    root_key: hex::encode(r.root_key),
+                    dh_remote: r.dh_remote.map(hex::encode),
+                    send_count: r.send_count,
+                    recv_count: r.recv_count,
+                    prev_send_count: r.prev_send_count,
+                    created_at: r.created_at,
+                })
+                .collect(),
+            key_epochs: Vec::new(),
+            transcript,
+        });
+    }
+
+    let epochs = storage.list_channel_key_epochs(channel_id)?;
+    if epochs.is_empty() {
+        return Err("Channel has no keys to audit".to_string());
+    }
+    let transcript = messages
+        .iter()
+        .map(|row| {
+            let mut entry = TranscriptEntry {
+                message_id: hex::encode(row.message_id),
+                timestamp: row.timestamp,
+                kind: row.kind,
+                ciphertext: hex::encode(&row.ciphertext),
+                ..Default::default()
+            };
+            if let Some(nonce) = forward::sealed_nonce(&row.ciphertext) {
+                entry.nonce = Some(hex::encode(nonce));
+                entry.associated_data = Some(hex::encode(row.message_id));
+                let opened = epochs.iter().find_map(|epoch| {
+                    let plaintext = forward::open_for_channel(&epoch.key, &row.message_id, &row.ciphertext).ok()?;
+                    Some((epoch.added_at, plaintext))
+                });
+                match opened {
+                    Some((added_at, plaintext)) => {
+                        entry.key_epoch = Some(added_at);
+                        entry.plaintext = Some(hex::encode(plaintext));
+                    }
+                    None => entry.error = Some("No key epoch opens this message".to_string()),
+                }
+            } else if row.kind == MESSAGE_KIND_USER {
+                entry.error = Some("Not a sealed channel message".to_string());
+            }
+            entry
+        })
+        .collect();
+    Ok(ChannelAudit {
+        channel_id: hex::encode(channel_id),
+        channel_type,
+        noise_pattern: None,
+        local_static: None,
+        sessions: Vec::new(),
+        ratchets: Vec::new(),
+        key_epochs: epochs
+            .iter()
+            .map(|epoch| KeyEpoch { key: hex::encode(epoch.key), added_at: epoch.added_at })
+            .collect(),
+        transcript,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selected_dm_channel_exports_its_schedule() {
+        let (alice_storage, bob_storage) = (Storage::in_memory().unwrap(), Storage::in_memory().unwrap());
+        let (alice, bob) = (Identity::generate(), Identity::generate());
+        let channel = dm_crypto::derive_dm_channel_id(
+            alice.public().ed25519_public.as_bytes(),
+            bob.public().ed25519_public.as_bytes(),
+        );
+        bob_storage.upsert_channel(channel, "dm").unwrap();
+
+        let sent = DmSessionManager::new(&alice_storage, alice.x25519_secret())
+            .encrypt(channel, *bob.public().x25519_public.as_bytes(), [7u8; 32], b"audit me", 100)
+            .unwrap();
+        DmSessionManager::new(&bob_storage, bob.x25519_secret())
+            .decrypt(channel, Some(*alice.public().x25519_public.as_bytes()), [7u8; 32], &sent, 101)
+            .unwrap();
+        bob_storage.store_message([7u8; 32], channel, sent, 100, 3).unwrap();
+
+        assert!(export_channel(&bob, &bob_storage, channel, 102).is_err());
+        select_channel(&bob_storage, Some(channel)).unwrap();
+        let audit = export_channel(&bob, &bob_storage, channel, 102).unwrap();
+        let session = &audit.sessions[0];
+        assert!(!session.outgoing);
+        let entry = &audit.transcript[0];
+        assert_eq!(entry.session_id.as_ref(), Some(&session.session_id));
+        assert_eq!(entry.session_id.as_ref(), Some(&audit.ratchets[0].ratchet_id));
+        assert!(entry.message_key.is_some() && !audit.ratchets[0].initiator);
+        assert_eq!(entry.plaintext, Some(hex::encode(b"audit me")));
+
+        select_channel(&bob_storage, None).unwrap();
+        assert!(export_channel(&bob, &bob_storage, channel, 103).is_err());
+    }
+}
@@ -0,0 +1,256 @@
+//! Incremental backups of the database
+//!
+//! Backups form chains: a full backup (index 0) holds every row of the
+//! database, and each incremental one after it only the rows inserted,
+//! updated or deleted since the previous backup of its chain, so old phones
+//! do not rewrite everything each time. Restoring applies the base, then the
+//! deltas in order.
+//!
+//! Changes are logged by rowid (see `Storage::track_changes`) from the first
+//! backup on. The manifest of the last backup made is kept in the database;
+//! once a backup is made its changes are forgotten, so a backup the app
+//! loses breaks the chain: make a full one to start a new chain. A restored
+//! database starts a new chain too.
+//!
+//! Backups are JSON { version, manifest, nonce, ciphertext }. The rows are
+//! compressed and sealed with ChaCha20-Poly1305 under a key derived from the
+//! identity, the manifest bound as associated data, so only the same identity
+//! restores them (see `identity_backup` for the identity itself). Files
+//! outside the database (attachment blobs) are not part of them.
+
+use crate::error::MeshError;
+use crate::identity::Identity;
+use crate::storage::{Storage, TableRows};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+use rand::RngCore;
+use rusqlite::types::Value as SqlValue;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+pub const BACKUP_VERSION: u8 = 1;
+
+/// Largest decompressed backup accepted
+const MAX_BACKUP_BYTES: usize = 512 * 1024 * 1024;
+
+/// Where a backup sits in its chain
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackupManifest {
+    /// Random id shared by a full backup and its deltas
+    pub chain_id: String,
+    /// 0 for the full backup, then 1, 2, ...
+    pub index: u32,
+    /// Last change the backup covers
+    pub change_seq: i64,
+    pub created_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Backup {
+    pub version: u8,
+    pub manifest: BackupManifest,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+/// What a restore applied
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RestoreSummary {
+    pub chain_id: String,
+    pub backups: u32,
+    pub rows: u64,
+}
+
+fn backup_cipher(identity: &Identity) -> ChaCha20Poly1305 {
+    let mut hasher = Sha256::new();
+    hasher.update(b"meshapp-backup-key");
+    hasher.update(identity.x25519_secret().as_bytes());
+    let key: [u8; 32] = hasher.finalize().into();
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+fn manifest_aad(manifest: &BackupManifest) -> Result<Vec<u8>, String> {
+    serde_json::to_vec(manifest).map_err(|e| format!("Failed to serialize backup manifest: {}", e))
+}
+
+/// Make a backup: incremental on top of the last one, or full if `full` is
+/// set or there is no chain yet.
+pub fn create(identity: &Identity, storage: &Storage, full: bool, now: i64) -> Result<Backup, String> {
+    storage.with_transaction(|s| {
+        s.track_changes()?;
+        let last: Option<BackupManifest> = match s.get_backup_manifest()? {
+            Some(m) => Some(serde_json::from_str(&m).map_err(|e| format!("Failed to parse backup manifest: {}", e))?),
+            None => None,
+        };
+        let change_seq = s.change_seq()?;
+        let (manifest, tables) = match last.filter(|_| !full) {
+            Some(last) => {
+                let manifest = BackupManifest { chain_id: last.chain_id, index: last.index + 1, change_seq, created_at: now };
+                (manifest, s.dump_rows(Some(last.change_seq))?)
+            }
+            None => {
+                let mut chain_id = [0u8; 16];
+                crate::rng::rng().fill_bytes(&mut chain_id);
+                let manifest = BackupManifest { chain_id: hex::encode(chain_id), index: 0, change_seq, created_at: now };
+                (manifest, s.dump_rows(None)?)
+            }
+        };
+
+        let payload = serde_json::to_vec(&tables.iter().map(tables_to_json).collect::<Vec<_>>())
+            .map_err(|e| format!("Failed to serialize backup: {}", e))?;
+        let mut nonce = [0u8; 12];
+        crate::rng::rng().fill_bytes(&mut nonce);
+        let aad = manifest_aad(&manifest)?;
+        let ciphertext = backup_cipher(identity)
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: &compress_to_vec(&payload, 6), aad: &aad })
+            .map_err(|e| MeshError::Crypto.raise(format!("Failed to encrypt backup: {}", e)))?;
+
+        let stored = String::from_utf8(aad).map_err(|e| e.to_string())?;
+        s.set_backup_manifest(Some(&stored))?;
+        s.prune_changes(change_seq)?;
+        Ok(Backup { version: BACKUP_VERSION, manifest, nonce: hex::encode(nonce), ciphertext: hex::encode(ciphertext) })
+    })
+}
+
+/// Restore a chain: its full backup, then its deltas in order.
+pub fn restore(identity: &Identity, storage: &Storage, chain: &[Backup]) -> Result<RestoreSummary, String> {
+    let Some(base) = chain.first() else {
+        return Err(MeshError::InvalidArgument.raise("Backup chain is empty"));
+    };
+    for (i, backup) in chain.iter().enumerate() {
+        if backup.version != BACKUP_VERSION {
+            return Err(MeshError::InvalidArgument.raise(format!("Unsupported backup version {}", backup.version)));
+        }
+        if backup.manifest.chain_id != base.manifest.chain_id || backup.manifest.index as usize != i {
+            return Err(MeshError::InvalidArgument.raise(format!("Backup chain is broken at backup {} (must start with the full backup, then every delta in order)", i)));
+        }
+    }
+    let tables = chain.iter().map(|b| open(identity, b)).collect::<Result<Vec<_>, _>>()?;
+
+    let rows = storage.with_transaction(|s| {
+        s.track_changes()?;
+        let mut rows = 0;
+        for (i, t) in tables.iter().enumerate() {
+            rows += s.apply_rows(t, i == 0)?;
+        }
+        // The next backup starts a chain of this device's own
+        s.set_backup_manifest(None)?;
+        s.prune_changes(s.change_seq()?)?;
+        Ok(rows)
+    })?;
+    Ok(RestoreSummary { chain_id: base.manifest.chain_id.clone(), backups: chain.len() as u32, rows })
+}
+
+/// Decrypt a backup's rows.
+fn open(identity: &Identity, backup: &Backup) -> Result<Vec<TableRows>, String> {
+    let nonce = hex::decode(&backup.nonce).ok().filter(|n| n.len() == 12).ok_or_else(|| MeshError::InvalidArgument.raise("Invalid backup nonce"))?;
+    let ciphertext = hex::decode(&backup.ciphertext).map_err(|_| MeshError::InvalidArgument.raise("Invalid backup ciphertext"))?;
+    let compressed = backup_cipher(identity)
+        .decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: &manifest_aad(&backup.manifest)? })
+        .map_err(|_| MeshError::Crypto.raise("Failed to decrypt backup (made by another identity, or damaged)"))?;
+    let payload = decompress_to_vec_with_limit(&compressed, MAX_BACKUP_BYTES).map_err(|_| MeshError::InvalidArgument.raise("Invalid backup payload"))?;
+    let tables: Vec<Value> = serde_json::from_slice(&payload).map_err(|e| format!("Failed to parse backup: {}", e))?;
+    tables.iter().map(tables_from_json).collect()
+}
+
+fn tables_to_json(t: &TableRows) -> Value {
+    let rows: Vec<Value> = t
+        .rows
+        .iter()
+        .map(|(rowid, values)| {
+            let mut row = vec![json!(rowid)];
+            row.extend(values.iter().map(value_to_json));
+            Value::Array(row)
+        })
+        .collect();
+    json!({ "table": t.table, "columns": t.columns, "rows": rows, "deleted": t.deleted })
+}
+
+fn tables_from_json(value: &Value) -> Result<TableRows, String> {
+    let invalid = || MeshError::InvalidArgument.raise("Invalid backup rows");
+    let table = value["table"].as_str().ok_or_else(invalid)?.to_string();
+    let columns = serde_json::from_value(value["columns"].clone()).map_err(|_| invalid())?;
+    let deleted = serde_json::from_value(value["deleted"].clone()).map_err(|_| invalid())?;
+    let rows = value["rows"]
+        .as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|row| {
+            let cells = row.as_array().filter(|c| !c.is_empty()).ok_or_else(invalid)?;
+            let rowid = cells[0].as_i64().ok_or_else(invalid)?;
+            let values = cells[1..].iter().map(value_from_json).collect::<Result<_, _>>()?;
+            Ok((rowid, values))
+        })
+        .collect::<Result<_, String>>()?;
+    Ok(TableRows { table, columns, rows, deleted })
+}
+
+/// Integers and text as themselves, reals as { "r": n }, blobs as { "b": hex }
+fn value_to_json(value: &SqlValue) -> Value {
+    match value {
+        SqlValue::Null => Value::Null,
+        SqlValue::Integer(i) => json!(i),
+        SqlValue::Real(r) => json!({ "r": r }),
+        SqlValue::Text(t) => json!(t),
+        SqlValue::Blob(b) => json!({ "b": hex::encode(b) }),
+    }
+}
+
+fn value_from_json(value: &Value) -> Result<SqlValue, String> {
+    let invalid = || MeshError::InvalidArgument.raise("Invalid backup value");
+    Ok(match value {
+        Value::Null => SqlValue::Null,
+        Value::Number(n) => SqlValue::Integer(n.as_i64().ok_or_else(invalid)?),
+        Value::String(s) => SqlValue::Text(s.clone()),
+        Value::Object(o) => match (o.get("r"), o.get("b")) {
+            (Some(r), None) => SqlValue::Real(r.as_f64().ok_or_else(invalid)?),
+            (None, Some(b)) => SqlValue::Blob(b.as_str().and_then(|b| hex::decode(b).ok()).ok_or_else(invalid)?),
+            _ => return Err(invalid()),
+        },
+        _ => return Err(invalid()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base_and_deltas_restore() {
+        let identity = Identity::generate();
+        let from = Storage::in_memory().unwrap();
+        from.store_message([1u8; 32], [9u8; 32], vec![1, 2, 3], 100, 3).unwrap();
+        from.set_setting("app.theme", "\"dark\"").unwrap();
+
+        let base = create(&identity, &from, false, 1000).unwrap();
+        assert_eq!(base.manifest.index, 0);
+
+        // The delta holds only what changed: one new message, one updated setting
+        from.store_message([2u8; 32], [9u8; 32], vec![4, 5], 200, 3).unwrap();
+        from.set_setting("app.theme", "\"light\"").unwrap();
+        let delta = create(&identity, &from, false, 2000).unwrap();
+        assert_eq!((delta.manifest.index, &delta.manifest.chain_id), (1, &base.manifest.chain_id));
+        assert!(delta.ciphertext.len() < base.ciphertext.len());
+        let delta_tables = open(&identity, &delta).unwrap();
+        assert_eq!(delta_tables.iter().map(|t| t.table.as_str()).collect::<Vec<_>>(), vec!["messages", "settings"]);
+        assert_eq!(delta_tables[0].rows.len(), 1);
+
+        assert!(from.delete_message([1u8; 32]).unwrap());
+        let delta2 = create(&identity, &from, false, 3000).unwrap();
+        let messages = open(&identity, &delta2).unwrap().into_iter().find(|t| t.table == "messages").unwrap();
+        assert_eq!((messages.rows.len(), messages.deleted.len()), (0, 1));
+
+        // Restoring the chain gives the same rows; a chain missing a delta is refused
+        let to = Storage::in_memory().unwrap();
+        assert!(restore(&identity, &to, &[base.clone(), delta2.clone()]).is_err());
+        assert!(restore(&Identity::generate(), &to, std::slice::from_ref(&base)).is_err());
+        let summary = restore(&identity, &to, &[base, delta, delta2]).unwrap();
+        assert_eq!(summary.backups, 3);
+        assert_eq!(to.dump_rows(None).unwrap(), from.dump_rows(None).unwrap());
+        assert_eq!(to.get_setting("app.theme").unwrap().as_deref(), Some("\"light\""));
+        assert_eq!(create(&identity, &to, false, 4000).unwrap().manifest.index, 0);
+    }
+}
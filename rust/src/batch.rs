@@ -0,0 +1,93 @@
+//! Atomic batches of setup operations
+//!
+//! Compound flows (add a friend, register their channels, store a greeting,
+//! join a geohash channel) run through `run_batch` as one list of
+//! operations. Storage changes commit in one transaction
+//! (`Storage::with_transaction`); friends added by a batch that fails are
+//! removed again, so the app never sees a half-applied batch.
+//!
+//! Operations (JSON objects tagged by "op"):
+//! - "add_friend" { ed25519_public, nickname } -> { user_id }
+//! - "set_friend_x25519_key" { user_id, x25519_public }
+//! - "register_channel" { channel_id, channel_type }
+//! - "join_geo_channel" { geohash, topic } -> { channel_id }
+//! - "send_dm" { user_id, text } -> { message_id } (also notes, to our own user_id)
+//!
+//! Keys and ids are hex. A friend added earlier in the same batch can be
+//! referenced by user_id (SHA256 of the Ed25519 key).
+
+use crate::error::MeshError;
+use serde::Deserialize;
+
+/// Most operations in one batch
+pub const MAX_BATCH_OPS: usize = 64;
+
+/// One operation of a batch
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum BatchOp {
+    AddFriend { ed25519_public: String, nickname: String },
+    SetFriendX25519Key { user_id: String, x25519_public: String },
+    RegisterChannel { channel_id: String, channel_type: String },
+    JoinGeoChannel { geohash: String, topic: String },
+    SendDm { user_id: String, text: String },
+}
+
+impl BatchOp {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BatchOp::AddFriend { .. } => "add_friend",
+            BatchOp::SetFriendX25519Key { .. } => "set_friend_x25519_key",
+            BatchOp::RegisterChannel { .. } => "register_channel",
+            BatchOp::JoinGeoChannel { .. } => "join_geo_channel",
+            BatchOp::SendDm { .. } => "send_dm",
+        }
+    }
+}
+
+/// Channel types a batch may register (others are created by their own flows)
+const REGISTRABLE_CHANNEL_TYPES: &[&str] = &["dm", "geo", "group"];
+
+/// Parse and check a batch.
+pub fn parse(json: &str) -> Result<Vec<BatchOp>, String> {
+    let ops: Vec<BatchOp> = serde_json::from_str(json).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid batch: {}", e)))?;
+    if ops.is_empty() || ops.len() > MAX_BATCH_OPS {
+        return Err(format!("A batch holds 1 to {} operations", MAX_BATCH_OPS));
+    }
+    for op in &ops {
+        if let BatchOp::RegisterChannel { channel_type, .. } = op {
+            if !REGISTRABLE_CHANNEL_TYPES.contains(&channel_type.as_str()) {
+                return Err(MeshError::Rejected.raise(format!("Cannot register a channel of type {}", channel_type)));
+            }
+        }
+    }
+    Ok(ops)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+
+    #[test]
+    fn test_parse_and_rollback() {
+        let ops = parse(r#"[{"op":"join_geo_channel","geohash":"u4pru","topic":"general"},
+                            {"op":"send_dm","user_id":"00","text":"hi"}]"#)
+            .unwrap();
+        assert_eq!(ops[1].name(), "send_dm");
+        assert!(parse("[]").is_err());
+        assert!(parse(r#"[{"op":"register_channel","channel_id":"00","channel_type":"sos"}]"#).is_err());
+        assert!(parse(r#"[{"op":"drop_tables"}]"#).is_err());
+
+        let storage = Storage::in_memory().unwrap();
+        let failed: Result<(), String> = storage.with_transaction(|s| {
+            s.upsert_channel([1u8; 32], "geo")?;
+            s.store_outgoing_batch(&[])?;
+            Err("later op failed".to_string())
+        });
+        assert!(failed.is_err());
+        assert_eq!(storage.get_channel_type([1u8; 32]).unwrap(), None);
+        storage.with_transaction(|s| s.upsert_channel([1u8; 32], "geo")).unwrap();
+        assert_eq!(storage.get_channel_type([1u8; 32]).unwrap().as_deref(), Some("geo"));
+    }
+}
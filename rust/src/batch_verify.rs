@@ -0,0 +1,139 @@
+//! Batch Ed25519 verification
+//!
+//! Relay nodes check many signatures at once (key history epochs, signed
+//! beacons forwarded by the platform layer). `ed25519_dalek::verify_batch`
+//! checks n signatures as one random linear combination, a single
+//! multiscalar multiplication instead of n separate verifications.
+//!
+//! The batch equation alone accepts signatures that strict verification
+//! rejects, so items whose public key or R is a small-order point fail
+//! before the batch, and when a batch fails every item is checked with
+//! `verify_strict` to report which ones are bad. An item passes the batch
+//! only if it would pass `verify_strict` on its own.
+
+use crate::codec;
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::Deserialize;
+
+/// One signature to check
+pub struct SignedItem<'a> {
+    pub public_key: [u8; 32],
+    pub message: &'a [u8],
+    pub signature: [u8; 64],
+}
+
+/// A signature to check as received over FFI (hex fields)
+#[derive(Deserialize, Debug)]
+pub struct HexSignedItem {
+    pub public_key: String,
+    pub message: String,
+    pub signature: String,
+}
+
+/// The item's key, unless the key or the signature's R is a small-order
+/// point (or not a point at all).
+fn checked_key(item: &SignedItem) -> Option<VerifyingKey> {
+    let key = VerifyingKey::from_bytes(&item.public_key).ok().filter(|key| !key.is_weak())?;
+    let r: [u8; 32] = item.signature[..32].try_into().ok()?;
+    let r = CompressedEdwardsY(r).decompress()?;
+    (!r.is_small_order()).then_some(key)
+}
+
+fn verify_one(item: &SignedItem) -> bool {
+    checked_key(item).is_some_and(|key| key.verify_strict(item.message, &Signature::from_bytes(&item.signature)).is_ok())
+}
+
+/// Verify many signatures. Returns the indices of the items that fail
+/// (empty when all are valid).
+pub fn verify_batch(items: &[SignedItem]) -> Vec<usize> {
+    let keys: Option<Vec<VerifyingKey>> = items.iter().map(checked_key).collect();
+    if let Some(keys) = keys {
+        let messages: Vec<&[u8]> = items.iter().map(|item| item.message).collect();
+        let signatures: Vec<Signature> = items.iter().map(|item| Signature::from_bytes(&item.signature)).collect();
+        if items.is_empty() || ed25519_dalek::verify_batch(&messages, &signatures, &keys).is_ok() {
+            return Vec::new();
+        }
+    }
+    // Fallback: find the failing items
+    items
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| !verify_one(item))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Verify hex-encoded items. Malformed entries count as failing.
+pub fn verify_hex_batch(items: &[HexSignedItem]) -> Vec<usize> {
+    let mut failed = Vec::new();
+    let mut decoded = Vec::with_capacity(items.len());
+    for (i, item) in items.iter().enumerate() {
+        let parsed = codec::parse_id_hex(&item.public_key, "public key").and_then(|public_key| {
+            Ok((
+                public_key,
+                codec::parse_hex_payload(&item.message)?,
+                codec::parse_hex_array::<64>(&item.signature, "signature")?,
+            ))
+        });
+        match parsed {
+            Ok(d) => decoded.push((i, d)),
+            Err(_) => failed.push(i),
+        }
+    }
+
+    let signed: Vec<SignedItem> = decoded
+        .iter()
+        .map(|(_, (public_key, message, signature))| SignedItem {
+            public_key: *public_key,
+            message,
+            signature: *signature,
+        })
+        .collect();
+    failed.extend(verify_batch(&signed).into_iter().map(|j| decoded[j].0));
+    failed.sort_unstable();
+    failed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    #[test]
+    fn test_batch_accepts_valid_and_finds_bad_items() {
+        let keys: Vec<SigningKey> = (0..4).map(|_| SigningKey::generate(&mut crate::rng::rng())).collect();
+        let messages: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 20]).collect();
+        let mut items: Vec<SignedItem> = keys
+            .iter()
+            .zip(&messages)
+            .map(|(key, msg)| SignedItem {
+                public_key: key.verifying_key().to_bytes(),
+                message: msg,
+                signature: key.sign(msg).to_bytes(),
+            })
+            .collect();
+        assert!(verify_batch(&items).is_empty());
+
+        items[2].message = b"tampered";
+        items[3].signature[40] ^= 1;
+        assert_eq!(verify_batch(&items), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_small_order_public_key_fails() {
+        // The identity point: every signature with R = identity and s = 0
+        // satisfies the unstrict equation, whatever the message
+        let mut identity = [0u8; 32];
+        identity[0] = 1;
+        let mut signature = [0u8; 64];
+        signature[..32].copy_from_slice(&identity);
+        let key = SigningKey::generate(&mut crate::rng::rng());
+        let items = [
+            SignedItem { public_key: key.verifying_key().to_bytes(), message: b"fine", signature: key.sign(b"fine").to_bytes() },
+            SignedItem { public_key: identity, message: b"forged", signature },
+        ];
+        assert_eq!(verify_batch(&items), vec![1]);
+        assert_eq!(verify_batch(&items[1..]), vec![0]);
+    }
+}
@@ -0,0 +1,115 @@
+//! Blocklist
+//!
+//! A blocked user's packets are dropped before anything is stored. Their DM
+//! channel (known when we have their Ed25519 key, as for friends) is dropped
+//! by the router itself: packets on it are marked seen but neither handled
+//! nor relayed. In groups, where they share a channel with everyone else,
+//! each message is opened on arrival and dropped if its sender is blocked;
+//! messages stored before the block are hidden when the group is read.
+//!
+//! The list lives in the `blocked_users` table; every change is announced
+//! with a `blocklist_changed` event { user_id, blocked }.
+
+use crate::codec;
+use crate::dm_crypto;
+use crate::events;
+use crate::groups;
+use crate::storage::Storage;
+use serde::Serialize;
+
+/// A blocked user, as listed to the app
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockedUser {
+    pub user_id: String,
+    pub blocked_at: i64,
+}
+
+/// Block a user (with their Ed25519 key if known, so their DM channel is
+/// dropped too). Returns whether they were not blocked before.
+pub fn block(storage: &Storage, user_id: [u8; 32], ed25519_public: Option<[u8; 32]>, now: i64) -> Result<bool, String> {
+    let added = storage.block_user(user_id, ed25519_public, now)?;
+    if added {
+        events::emit("blocklist_changed", serde_json::json!({ "user_id": hex::encode(user_id), "blocked": true }));
+    }
+    Ok(added)
+}
+
+/// Unblock a user. Returns whether they were blocked.
+pub fn unblock(storage: &Storage, user_id: [u8; 32]) -> Result<bool, String> {
+    let removed = storage.unblock_user(user_id)?;
+    if removed {
+        events::emit("blocklist_changed", serde_json::json!({ "user_id": hex::encode(user_id), "blocked": false }));
+    }
+    Ok(removed)
+}
+
+/// Blocked users, in the order they were blocked.
+pub fn list(storage: &Storage) -> Result<Vec<BlockedUser>, String> {
+    Ok(storage
+        .list_blocked_users()?
+        .into_iter()
+        .map(|row| BlockedUser { user_id: hex::encode(row.user_id), blocked_at: row.blocked_at })
+        .collect())
+}
+
+/// DM channels of blocked users whose key we know, for the router to drop.
+pub fn blocked_channels(storage: &Storage, own_ed25519: &[u8; 32]) -> Result<Vec<[u8; 32]>, String> {
+    Ok(storage
+        .list_blocked_users()?
+        .iter()
+        .filter_map(|row| row.ed25519_public.as_ref())
+        .map(|key| dm_crypto::derive_dm_channel_id(own_ed25519, key))
+        .collect())
+}
+
+/// Whether an incoming group message comes from a blocked user. Messages we
+/// cannot open, or that name no sender, are not judged here.
+pub fn is_blocked_sender(storage: &Storage, channel_id: [u8; 32], message_id: [u8; 32], ciphertext: &[u8]) -> Result<bool, String> {
+    if storage.get_channel_type(channel_id)?.as_deref() != Some("group") {
+        return Ok(false);
+    }
+    let Ok(plaintext) = groups::open_message(storage, channel_id, &message_id, ciphertext) else {
+        return Ok(false);
+    };
+    let (Some(sender), _) = groups::parse_message(&String::from_utf8_lossy(&plaintext)) else {
+        return Ok(false);
+    };
+    match codec::parse_id_hex(&sender, "sender") {
+        Ok(user_id) => storage.is_user_blocked(user_id),
+        Err(_) => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Identity;
+
+    #[test]
+    fn test_blocked_senders_are_recognised() {
+        let (owner_storage, alice_storage) = (Storage::in_memory().unwrap(), Storage::in_memory().unwrap());
+        let (owner, alice) = (Identity::generate(), Identity::generate());
+        let (owner_key, alice_key) = (owner.public().ed25519_public.to_bytes(), alice.public().ed25519_public.to_bytes());
+
+        let channel = groups::create(&owner, &owner_storage, None, 100).unwrap();
+        let invite = groups::add_member(&owner, &owner_storage, channel, alice.public().user_id, 100).unwrap();
+        groups::accept_invite(&alice, &alice_storage, &invite, owner_key, 110).unwrap();
+        let message = groups::seal_message(&alice, &alice_storage, channel, "hi", 120).unwrap();
+        assert!(!is_blocked_sender(&owner_storage, channel, message.message_id, &message.ciphertext).unwrap());
+
+        // Blocking by id alone drops group messages; a later block with the key adds the DM channel
+        assert!(block(&owner_storage, alice.public().user_id, None, 130).unwrap());
+        assert!(blocked_channels(&owner_storage, &owner_key).unwrap().is_empty());
+        assert!(is_blocked_sender(&owner_storage, channel, message.message_id, &message.ciphertext).unwrap());
+        assert!(!block(&owner_storage, alice.public().user_id, Some(alice_key), 140).unwrap());
+        assert_eq!(
+            blocked_channels(&owner_storage, &owner_key).unwrap(),
+            vec![dm_crypto::derive_dm_channel_id(&owner_key, &alice_key)]
+        );
+        assert_eq!(list(&owner_storage).unwrap()[0].blocked_at, 130);
+
+        assert!(unblock(&owner_storage, alice.public().user_id).unwrap());
+        assert!(!unblock(&owner_storage, alice.public().user_id).unwrap());
+        assert!(!is_blocked_sender(&owner_storage, channel, message.message_id, &message.ciphertext).unwrap());
+    }
+}
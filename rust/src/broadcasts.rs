@@ -0,0 +1,260 @@
+//! Broadcast channels and watch-only contacts
+//!
+//! Every identity has a broadcast channel, SHA256("meshapp-broadcast-v1" ||
+//! Ed25519 key), where it posts to whoever follows it (a community
+//! broadcaster, a shelter, a radio net). Posts are plain `Message` packets:
+//! JSON { text, sent_at } || author Ed25519 key (32) || Ed25519 signature
+//! (64) over "meshapp-broadcast-post" || channel_id || everything before it.
+//!
+//! Watching a key (`watch`) follows its channel without friending it: the
+//! channel is registered (type "broadcast") so posts are stored and shown,
+//! but no friend entry, DM channel, session or anything the other side could
+//! see is created. Posts on registered broadcast channels that do not verify
+//! against the channel's key are dropped; verified posts by watched keys
+//! raise a `broadcast_post` event.
+
+use crate::error::MeshError;
+use crate::events;
+use crate::identity::Identity;
+use crate::priority::Priority;
+use crate::storage::{Storage, WatchedContactRow, MESSAGE_KIND_USER};
+use crate::transport::{Packet, PacketKind, Router};
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// channels.type of broadcast channels
+pub const BROADCAST_CHANNEL_TYPE: &str = "broadcast";
+
+/// TTL of posts we send
+pub const BROADCAST_TTL: u8 = 6;
+
+/// Longest post text, in bytes
+pub const MAX_POST_TEXT_LEN: usize = 2048;
+
+/// Longest label of a watched contact, in bytes
+const MAX_LABEL_LEN: usize = 64;
+
+const POST_CONTEXT: &[u8] = b"meshapp-broadcast-post";
+const SIGNATURE_LEN: usize = 64;
+
+/// What a post carries
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct PostBody {
+    text: String,
+    sent_at: i64,
+}
+
+/// A verified post, as shown to the app
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct BroadcastPost {
+    pub message_id: String,
+    pub author: String,
+    pub text: String,
+    /// When the author says they posted it
+    pub sent_at: i64,
+    /// When it reached us
+    pub timestamp: i64,
+}
+
+/// A watched contact, as listed to the app
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct WatchedContact {
+    pub ed25519_public: String,
+    pub user_id: String,
+    pub channel_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub added_at: i64,
+}
+
+impl From<&WatchedContactRow> for WatchedContact {
+    fn from(row: &WatchedContactRow) -> Self {
+        Self {
+            ed25519_public: hex::encode(row.ed25519_public),
+            user_id: hex::encode(Sha256::digest(row.ed25519_public)),
+            channel_id: hex::encode(channel_id(&row.ed25519_public)),
+            label: row.label.clone(),
+            added_at: row.added_at,
+        }
+    }
+}
+
+/// The broadcast channel of a key.
+pub fn channel_id(ed25519_public: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"meshapp-broadcast-v1");
+    hasher.update(ed25519_public);
+    hasher.finalize().into()
+}
+
+/// Sign a post on our broadcast channel (registering it).
+pub fn post(identity: &Identity, storage: &Storage, text: &str, now: i64) -> Result<Packet, String> {
+    if text.trim().is_empty() {
+        return Err(MeshError::InvalidArgument.raise("Post text must not be empty"));
+    }
+    if text.len() > MAX_POST_TEXT_LEN {
+        return Err(MeshError::InvalidArgument.raise(format!("Post text longer than {} bytes", MAX_POST_TEXT_LEN)));
+    }
+    let own_public = identity.public().ed25519_public.to_bytes();
+    let channel_id = channel_id(&own_public);
+    storage.upsert_channel(channel_id, BROADCAST_CHANNEL_TYPE)?;
+
+    let body = PostBody { text: text.to_string(), sent_at: now };
+    let mut payload = serde_json::to_vec(&body).map_err(|e| format!("Failed to serialize post: {}", e))?;
+    payload.extend_from_slice(&own_public);
+    let signature = identity.ed25519_signing_key().sign(&[POST_CONTEXT, &channel_id, &payload].concat());
+    payload.extend_from_slice(&signature.to_bytes());
+    Ok(Packet {
+        packet_id: Router::generate_packet_id(),
+        channel_id,
+        kind: PacketKind::Message,
+        ttl: BROADCAST_TTL,
+        payload,
+        priority: Priority::Normal,
+    })
+}
+
+/// Verify a post stored on a broadcast channel. Returns its author and body.
+fn open(channel_id: [u8; 32], payload: &[u8]) -> Result<([u8; 32], PostBody), String> {
+    if payload.len() < 32 + SIGNATURE_LEN {
+        return Err(MeshError::InvalidArgument.raise("Malformed broadcast post"));
+    }
+    let body_len = payload.len() - 32 - SIGNATURE_LEN;
+    let author: [u8; 32] = crate::codec::read_array(payload, body_len, "author key")?;
+    if self::channel_id(&author) != channel_id {
+        return Err(MeshError::Rejected.raise("Post is not by the channel's owner"));
+    }
+    let signature: [u8; 64] = crate::codec::read_array(payload, body_len + 32, "signature")?;
+    VerifyingKey::from_bytes(&author)
+        .map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid author key: {}", e)))?
+        .verify(&[POST_CONTEXT, &channel_id, &payload[..body_len + 32]].concat(), &Signature::from_bytes(&signature))
+        .map_err(|_| MeshError::Crypto.raise("Broadcast post signature does not verify"))?;
+    let body: PostBody =
+        serde_json::from_slice(&payload[..body_len]).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid broadcast post: {}", e)))?;
+    Ok((author, body))
+}
+
+/// Follow a key's broadcast channel without friending it.
+pub fn watch(storage: &Storage, own_public: [u8; 32], ed25519_public: [u8; 32], label: Option<String>, now: i64) -> Result<WatchedContact, String> {
+    VerifyingKey::from_bytes(&ed25519_public).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid Ed25519 key: {}", e)))?;
+    if ed25519_public == own_public {
+        return Err(MeshError::Rejected.raise("Cannot watch our own key"));
+    }
+    if label.as_ref().is_some_and(|l| l.len() > MAX_LABEL_LEN) {
+        return Err(MeshError::InvalidArgument.raise(format!("Label longer than {} bytes", MAX_LABEL_LEN)));
+    }
+    let channel_id = channel_id(&ed25519_public);
+    match storage.get_channel_type(channel_id)? {
+        Some(t) if t != BROADCAST_CHANNEL_TYPE => return Err(MeshError::Rejected.raise(format!("Channel is already registered as {}", t))),
+        Some(_) => {}
+        None => storage.upsert_channel(channel_id, BROADCAST_CHANNEL_TYPE)?,
+    }
+    let added_at = storage.get_watched_contact(ed25519_public)?.map_or(now, |row| row.added_at);
+    let row = WatchedContactRow { ed25519_public, label, added_at };
+    storage.put_watched_contact(&row)?;
+    Ok(WatchedContact::from(&row))
+}
+
+/// Stop following a key and forget its posts. Returns false if it was not watched.
+pub fn unwatch(storage: &Storage, ed25519_public: [u8; 32]) -> Result<bool, String> {
+    if !storage.delete_watched_contact(ed25519_public)? {
+        return Ok(false);
+    }
+    storage.delete_channel_messages(channel_id(&ed25519_public))?;
+    Ok(true)
+}
+
+/// Check an incoming message before it is stored. Posts on registered
+/// broadcast channels must verify (Err drops them); other messages pass.
+/// Returns the post if it is by a watched key, for `on_stored`.
+pub fn check(storage: &Storage, packet: &Packet, now: i64) -> Result<Option<BroadcastPost>, String> {
+    if storage.get_channel_type(packet.channel_id)?.as_deref() != Some(BROADCAST_CHANNEL_TYPE) {
+        return Ok(None);
+    }
+    let (author, body) = open(packet.channel_id, &packet.payload)?;
+    if storage.get_watched_contact(author)?.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(BroadcastPost {
+        message_id: hex::encode(packet.packet_id),
+        author: hex::encode(author),
+        text: body.text,
+        sent_at: body.sent_at,
+        timestamp: now,
+    }))
+}
+
+/// Emit `broadcast_post` { message_id, author, text, sent_at, timestamp } for
+/// a post `check` returned, once it is stored.
+pub fn on_stored(post: &BroadcastPost) {
+    events::emit("broadcast_post", serde_json::to_value(post).unwrap_or_default());
+}
+
+/// Verified posts of a key's broadcast channel (ours included), oldest first.
+pub fn posts(storage: &Storage, ed25519_public: [u8; 32], limit: u32, offset: u32, now: i64) -> Result<Vec<BroadcastPost>, String> {
+    let channel_id = channel_id(&ed25519_public);
+    let mut posts = Vec::new();
+    for row in storage.fetch_live_messages(channel_id, limit, offset, now)? {
+        if row.kind != MESSAGE_KIND_USER {
+            continue;
+        }
+        match open(channel_id, &row.ciphertext) {
+            Ok((author, body)) => posts.push(BroadcastPost {
+                message_id: hex::encode(row.message_id),
+                author: hex::encode(author),
+                text: body.text,
+                sent_at: body.sent_at,
+                timestamp: row.timestamp,
+            }),
+            Err(e) => eprintln!("Skipping broadcast post {}: {}", hex::encode(row.message_id), e),
+        }
+    }
+    Ok(posts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watched_posts_verify_without_friending() {
+        let storage = Storage::in_memory().unwrap();
+        let (own, broadcaster) = (Identity::generate(), Identity::generate());
+        let own_public = own.public().ed25519_public.to_bytes();
+        let key = broadcaster.public().ed25519_public.to_bytes();
+
+        assert!(watch(&storage, own_public, own_public, None, 1).is_err());
+        let watched = watch(&storage, own_public, key, Some("Shelter news".to_string()), 1).unwrap();
+        assert_eq!(watched.channel_id, hex::encode(channel_id(&key)));
+        assert_eq!(storage.list_watched_contacts().unwrap().len(), 1);
+        // Following creates no friend and no DM channel
+        assert!(storage.list_friends().unwrap().is_empty());
+        assert!(storage.list_channels_by_type("dm").unwrap().is_empty());
+
+        let packet = post(&broadcaster, &storage, "Water at the school gym", 100).unwrap();
+        let seen = check(&storage, &packet, 110).unwrap().unwrap();
+        assert_eq!((seen.text.as_str(), seen.sent_at), ("Water at the school gym", 100));
+        storage.store_message(packet.packet_id, packet.channel_id, packet.payload.clone(), 110, packet.ttl).unwrap();
+        assert_eq!(posts(&storage, key, 10, 0, 120).unwrap(), vec![seen]);
+
+        // Forged posts and posts by someone else on the channel are dropped
+        let mut forged = packet.clone();
+        let at = forged.payload.len() - 70;
+        forged.payload[at] ^= 1;
+        assert!(check(&storage, &forged, 110).is_err());
+        let mut moved = post(&own, &storage, "Not me", 100).unwrap();
+        moved.channel_id = channel_id(&key);
+        assert!(check(&storage, &moved, 110).is_err());
+        assert!(post(&own, &storage, " ", 100).is_err());
+
+        // Unrelated channels pass unchecked
+        let mut other = packet.clone();
+        other.channel_id = [5u8; 32];
+        assert_eq!(check(&storage, &other, 110).unwrap(), None);
+
+        assert!(unwatch(&storage, key).unwrap());
+        assert!(!unwatch(&storage, key).unwrap());
+        assert!(posts(&storage, key, 10, 0, 120).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,402 @@
+//! Channel announcements and the channel directory
+//!
+//! A node can announce public (geo) channels it takes part in, so nodes
+//! nearby can find them ("channels near you"). The app picks the channels
+//! (`set_announced`, with the geohash and topic the channel id derives
+//! from) and calls `announcement_packets` periodically; each channel goes
+//! out at most once every `ANNOUNCE_INTERVAL_SECS` as a
+//! `ChannelAnnouncement` packet on the channel itself.
+//!
+//! Payload: JSON { geohash, topic, name?, activity, announced_at } ||
+//! signer Ed25519 key (32) || Ed25519 signature (64) over
+//! "meshapp-channel-announcement" || channel_id || everything before it.
+//! The signer is our pseudonym in the channel (see `pseudonyms`), so
+//! announcements do not tie channels to our identity or to each other.
+//! `activity` is a coarse hint of the channel's traffic over the last day
+//! (0 quiet, 1 some, 2 busy, 3 very busy), not a message count.
+//!
+//! Received announcements fill the `channel_directory` table, one row per
+//! channel and signer, once they verify and the channel id matches the
+//! geohash and topic. Ingest is rate limited: a signer's re-announcement of
+//! a channel within `MIN_REANNOUNCE_SECS` is dropped, as is everything past
+//! `MAX_INGEST_PER_MINUTE`. Each row gets a spam score (keys announcing many
+//! channels, links, shouting); rows scoring `SPAM_THRESHOLD` or more are
+//! kept but not listed. Rows expire after `DIRECTORY_TTL_SECS`.
+
+use crate::error::MeshError;
+use crate::geo;
+use crate::identity::Identity;
+use crate::priority::Priority;
+use crate::pseudonyms;
+use crate::storage::{AnnouncedChannelRow, DirectoryRow, Storage};
+use crate::transport::{Packet, PacketKind};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// How often each channel is announced at most
+pub const ANNOUNCE_INTERVAL_SECS: i64 = 30 * 60;
+
+/// TTL of announcement packets
+pub const ANNOUNCE_TTL: u8 = 3;
+
+/// A signer's re-announcement of a channel sooner than this is dropped
+pub const MIN_REANNOUNCE_SECS: i64 = 10 * 60;
+
+/// Announcements ingested per minute at most, across all signers
+pub const MAX_INGEST_PER_MINUTE: u32 = 120;
+
+/// How long an announcement stays in the directory
+pub const DIRECTORY_TTL_SECS: i64 = 24 * 60 * 60;
+
+/// Directory rows kept at most (the oldest go first)
+pub const MAX_DIRECTORY_ENTRIES: u32 = 2000;
+
+/// Rows scoring this much or more are not listed
+pub const SPAM_THRESHOLD: u8 = 50;
+
+/// Channels a signer may announce before it counts as spamming
+const MAX_CHANNELS_PER_SIGNER: u32 = 5;
+
+/// Announcements dated further ahead than this are rejected
+const MAX_CLOCK_SKEW_SECS: i64 = 5 * 60;
+
+const MAX_TOPIC_LEN: usize = 64;
+const MAX_NAME_LEN: usize = 64;
+const ANNOUNCEMENT_CONTEXT: &[u8] = b"meshapp-channel-announcement";
+const MAX_PAYLOAD_LEN: usize = 512;
+const SIGNATURE_LEN: usize = 64;
+
+/// (minute, announcements ingested in it)
+static INGESTED: Lazy<Mutex<(i64, u32)>> = Lazy::new(|| Mutex::new((0, 0)));
+
+/// What an announcement says about a channel
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Announcement {
+    pub geohash: String,
+    pub topic: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub activity: u8,
+    pub announced_at: i64,
+}
+
+/// A channel of the directory, as listed to the app
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct NearbyChannel {
+    pub channel_id: String,
+    pub geohash: String,
+    pub topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Highest activity hint any announcer gave
+    pub activity: u8,
+    /// Distinct keys that announced it
+    pub announcers: u32,
+    pub last_announced_at: i64,
+    /// Whether we are registered in it
+    pub subscribed: bool,
+}
+
+fn validate(announcement: &Announcement) -> Result<(), String> {
+    if !geo::valid_geohash(&announcement.geohash) {
+        return Err(MeshError::InvalidArgument.raise("Invalid geohash"));
+    }
+    if announcement.topic.is_empty() || announcement.topic.len() > MAX_TOPIC_LEN {
+        return Err(MeshError::InvalidArgument.raise(format!("Topic must be 1-{} bytes", MAX_TOPIC_LEN)));
+    }
+    if announcement.name.as_ref().is_some_and(|n| n.is_empty() || n.len() > MAX_NAME_LEN) {
+        return Err(MeshError::InvalidArgument.raise(format!("Name must be 1-{} bytes", MAX_NAME_LEN)));
+    }
+    if announcement.activity > 3 {
+        return Err(MeshError::InvalidArgument.raise("Activity hint out of range"));
+    }
+    Ok(())
+}
+
+/// Start announcing a geo channel (registering it if needed). Returns its id.
+pub fn set_announced(storage: &Storage, geohash: &str, topic: &str, name: Option<String>) -> Result<[u8; 32], String> {
+    let announcement =
+        Announcement { geohash: geohash.to_string(), topic: topic.to_string(), name, activity: 0, announced_at: 0 };
+    validate(&announcement)?;
+    let channel_id = geo::derive_geo_channel_id(geohash, topic);
+    match storage.get_channel_type(channel_id)? {
+        Some(t) if !pseudonyms::PUBLIC_CHANNEL_TYPES.contains(&t.as_str()) => {
+            return Err(MeshError::Rejected.raise(format!("Only public channels are announced, not {} channels", t)));
+        }
+        Some(_) => {}
+        None => storage.upsert_channel(channel_id, "geo")?,
+    }
+    storage.set_announced_channel(&AnnouncedChannelRow {
+        channel_id,
+        geohash: announcement.geohash,
+        topic: announcement.topic,
+        name: announcement.name,
+        last_sent_at: None,
+    })?;
+    Ok(channel_id)
+}
+
+/// Coarse traffic of a channel over the last day.
+fn activity_hint(storage: &Storage, channel_id: [u8; 32], now: i64) -> Result<u8, String> {
+    let count = storage.fetch_messages_since(channel_id, now - 24 * 60 * 60, 100)?.len();
+    Ok(match count {
+        0 => 0,
+        1..=9 => 1,
+        10..=99 => 2,
+        _ => 3,
+    })
+}
+
+/// An announcement packet of a channel, signed with `key`.
+fn sign(key: &SigningKey, channel_id: [u8; 32], announcement: &Announcement) -> Result<Packet, String> {
+    let mut payload = serde_json::to_vec(announcement).map_err(|e| format!("Failed to serialize announcement: {}", e))?;
+    payload.extend_from_slice(key.verifying_key().as_bytes());
+    let signature = key.sign(&[ANNOUNCEMENT_CONTEXT, &channel_id, &payload].concat());
+    payload.extend_from_slice(&signature.to_bytes());
+    Ok(Packet {
+        packet_id: Sha256::digest(&payload).into(),
+        channel_id,
+        kind: PacketKind::ChannelAnnouncement,
+        ttl: ANNOUNCE_TTL,
+        payload,
+        priority: Priority::Background,
+    })
+}
+
+/// Announcement packets for the channels due, marking them sent.
+pub fn announcement_packets(identity: &Identity, storage: &Storage, now: i64) -> Result<Vec<Packet>, String> {
+    let mut packets = Vec::new();
+    for row in storage.list_announced_channels()? {
+        if row.last_sent_at.is_some_and(|at| now.saturating_sub(at) < ANNOUNCE_INTERVAL_SECS) {
+            continue;
+        }
+        let announcement = Announcement {
+            geohash: row.geohash,
+            topic: row.topic,
+            name: row.name,
+            activity: activity_hint(storage, row.channel_id, now)?,
+            announced_at: now,
+        };
+        let pseudonym = pseudonyms::current(identity, storage, row.channel_id, now)?;
+        let key = pseudonyms::derive(identity, &row.channel_id, pseudonym.generation);
+        packets.push(sign(&key, row.channel_id, &announcement)?);
+        storage.mark_channel_announced(row.channel_id, now)?;
+    }
+    Ok(packets)
+}
+
+/// Parse and verify an announcement packet. Returns it with its signer.
+fn verify(packet: &Packet, now: i64) -> Result<(Announcement, [u8; 32]), String> {
+    let len = packet.payload.len();
+    if !(32 + SIGNATURE_LEN..=MAX_PAYLOAD_LEN).contains(&len) {
+        return Err(MeshError::InvalidArgument.raise("Malformed channel announcement"));
+    }
+    let body_len = len - 32 - SIGNATURE_LEN;
+    let signer: [u8; 32] = crate::codec::read_array(&packet.payload, body_len, "signer key")?;
+    let signature: [u8; 64] = crate::codec::read_array(&packet.payload, body_len + 32, "signature")?;
+    VerifyingKey::from_bytes(&signer)
+        .map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid signer key: {}", e)))?
+        .verify(
+            &[ANNOUNCEMENT_CONTEXT, &packet.channel_id, &packet.payload[..body_len + 32]].concat(),
+            &Signature::from_bytes(&signature),
+        )
+        .map_err(|_| MeshError::Crypto.raise("Channel announcement signature does not verify"))?;
+
+    let announcement: Announcement = serde_json::from_slice(&packet.payload[..body_len])
+        .map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid channel announcement: {}", e)))?;
+    validate(&announcement)?;
+    if geo::derive_geo_channel_id(&announcement.geohash, &announcement.topic) != packet.channel_id {
+        return Err("Announcement does not match its channel".to_string());
+    }
+    if announcement.announced_at > now + MAX_CLOCK_SKEW_SECS || announcement.announced_at < now - DIRECTORY_TTL_SECS {
+        return Err("Channel announcement is out of date".to_string());
+    }
+    Ok((announcement, signer))
+}
+
+/// Take one announcement from this minute's ingest allowance.
+fn take_ingest_slot(now: i64) -> bool {
+    let mut ingested = INGESTED.lock().unwrap();
+    let minute = now.div_euclid(60);
+    if ingested.0 != minute {
+        *ingested = (minute, 0);
+    }
+    if ingested.1 >= MAX_INGEST_PER_MINUTE {
+        return false;
+    }
+    ingested.1 += 1;
+    true
+}
+
+fn looks_like_link(text: &str) -> bool {
+    let text = text.to_ascii_lowercase();
+    ["http:", "https:", "www.", "://", ".com", ".net", ".org"].iter().any(|s| text.contains(s))
+}
+
+fn shouting(text: &str) -> bool {
+    let letters: Vec<char> = text.chars().filter(|c| c.is_alphabetic()).collect();
+    let upper = letters.iter().filter(|c| c.is_uppercase()).count();
+    let repeated = text.chars().collect::<Vec<_>>().windows(4).any(|w| w.iter().all(|&c| c == w[0]));
+    (letters.len() >= 6 && upper * 4 >= letters.len() * 3) || repeated
+}
+
+/// Spam score of an announcement (0-100), given how many other channels its
+/// signer announced lately.
+fn spam_score(announcement: &Announcement, signer_channels: u32) -> u8 {
+    let name = announcement.name.as_deref().unwrap_or("");
+    let mut score = 0u32;
+    if signer_channels >= MAX_CHANNELS_PER_SIGNER {
+        score += 60;
+    }
+    if looks_like_link(name) || looks_like_link(&announcement.topic) {
+        score += 40;
+    }
+    if shouting(name) || shouting(&announcement.topic) {
+        score += 20;
+    }
+    score.min(100) as u8
+}
+
+/// Add a received announcement to the directory. Returns false if it was
+/// dropped by the rate limits.
+pub fn handle_announcement(storage: &Storage, packet: &Packet, now: i64) -> Result<bool, String> {
+    let (announcement, signer) = verify(packet, now)?;
+    if storage.find_pseudonym(signer)?.is_some() {
+        return Ok(false);
+    }
+    if storage
+        .directory_received_at(packet.channel_id, signer)?
+        .is_some_and(|at| now.saturating_sub(at) < MIN_REANNOUNCE_SECS)
+    {
+        return Ok(false);
+    }
+    if !take_ingest_slot(now) {
+        return Ok(false);
+    }
+
+    let signer_channels = storage.count_signer_channels(signer, packet.channel_id, now - DIRECTORY_TTL_SECS)?;
+    storage.put_directory_entry(&DirectoryRow {
+        channel_id: packet.channel_id,
+        signer,
+        spam_score: spam_score(&announcement, signer_channels),
+        geohash: announcement.geohash,
+        topic: announcement.topic,
+        name: announcement.name,
+        activity: announcement.activity,
+        announced_at: announcement.announced_at,
+        received_at: now,
+    })?;
+    storage.prune_directory(now - DIRECTORY_TTL_SECS, MAX_DIRECTORY_ENTRIES)?;
+    Ok(true)
+}
+
+/// Channels announced in the area of `geohash` (every channel whose geohash
+/// starts with it), most announced and busiest first. Spam is left out.
+pub fn near(storage: &Storage, geohash: &str, now: i64) -> Result<Vec<NearbyChannel>, String> {
+    if !geo::valid_geohash(geohash) {
+        return Err(MeshError::InvalidArgument.raise("Invalid geohash"));
+    }
+    // Rows come newest first per channel, so the first row of each names it
+    let mut channels: BTreeMap<[u8; 32], NearbyChannel> = BTreeMap::new();
+    for row in storage.list_directory(geohash, now - DIRECTORY_TTL_SECS)? {
+        if row.spam_score >= SPAM_THRESHOLD {
+            continue;
+        }
+        match channels.get_mut(&row.channel_id) {
+            Some(channel) => {
+                channel.activity = channel.activity.max(row.activity);
+                channel.announcers += 1;
+            }
+            None => {
+                let subscribed = storage.get_channel_type(row.channel_id)?.is_some();
+                channels.insert(
+                    row.channel_id,
+                    NearbyChannel {
+                        channel_id: hex::encode(row.channel_id),
+                        geohash: row.geohash,
+                        topic: row.topic,
+                        name: row.name,
+                        activity: row.activity,
+                        announcers: 1,
+                        last_announced_at: row.announced_at,
+                        subscribed,
+                    },
+                );
+            }
+        }
+    }
+    let mut channels: Vec<NearbyChannel> = channels.into_values().collect();
+    channels.sort_by(|a, b| {
+        (b.announcers, b.activity, b.last_announced_at).cmp(&(a.announcers, a.activity, a.last_announced_at))
+    });
+    Ok(channels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_announcements_fill_the_directory() {
+        let (a, b) = (Storage::in_memory().unwrap(), Storage::in_memory().unwrap());
+        let identity = Identity::generate();
+
+        assert!(set_announced(&a, "u33d", "", None).is_err());
+        assert!(set_announced(&a, "u33a", "chat", None).is_err()); // 'a' is not a geohash character
+        let channel_id = set_announced(&a, "u33dc", "market", Some("Market square".to_string())).unwrap();
+        assert_eq!(a.get_channel_type(channel_id).unwrap().as_deref(), Some("geo"));
+
+        // Announced once per interval
+        let packets = announcement_packets(&identity, &a, 10_000).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert!(announcement_packets(&identity, &a, 10_100).unwrap().is_empty());
+        assert_eq!(announcement_packets(&identity, &a, 10_000 + ANNOUNCE_INTERVAL_SECS).unwrap().len(), 1);
+
+        assert!(handle_announcement(&b, &packets[0], 10_005).unwrap());
+        let near_b = near(&b, "u33", 10_010).unwrap();
+        assert_eq!(near_b.len(), 1);
+        assert_eq!(near_b[0].channel_id, hex::encode(channel_id));
+        assert_eq!(near_b[0].name.as_deref(), Some("Market square"));
+        assert!(!near_b[0].subscribed);
+        assert!(near(&b, "u34", 10_010).unwrap().is_empty());
+        // Our own announcements coming back are not listed
+        assert!(!handle_announcement(&a, &packets[0], 10_005).unwrap());
+
+        // Re-announcing too soon is dropped; forgeries and mismatched channels are rejected
+        let key = SigningKey::from_bytes(&[9u8; 32]);
+        let announce = |topic: &str, name: Option<&str>, at: i64| {
+            let announcement = Announcement {
+                geohash: "u33dc".to_string(),
+                topic: topic.to_string(),
+                name: name.map(str::to_string),
+                activity: 1,
+                announced_at: at,
+            };
+            sign(&key, geo::derive_geo_channel_id("u33dc", topic), &announcement).unwrap()
+        };
+        assert!(handle_announcement(&b, &announce("market", None, 10_000), 10_000).unwrap());
+        assert!(!handle_announcement(&b, &announce("market", None, 10_060), 10_060).unwrap());
+        let listed = near(&b, "u33d", 10_060).unwrap();
+        assert_eq!((listed[0].announcers, listed[0].activity), (2, 1));
+        let mut forged = announce("market", None, 10_700);
+        forged.payload[2] ^= 1;
+        assert!(handle_announcement(&b, &forged, 10_700).is_err());
+        let mut moved = announce("market", None, 10_700);
+        moved.channel_id = geo::derive_geo_channel_id("u33dc", "other");
+        assert!(handle_announcement(&b, &moved, 10_700).is_err());
+        assert!(handle_announcement(&b, &announce("market", None, 10_700), 10_700 + 2 * DIRECTORY_TTL_SECS).is_err());
+
+        // Links, shouting and keys announcing many channels count as spam
+        assert!(handle_announcement(&b, &announce("news", Some("FREE STUFF AT WWW.X.IO"), 10_700), 10_700).unwrap());
+        for i in 0..MAX_CHANNELS_PER_SIGNER {
+            assert!(handle_announcement(&b, &announce(&format!("deals{}", i), None, 10_700), 10_700).unwrap());
+        }
+        let topics: Vec<String> = near(&b, "u33dc", 10_700).unwrap().into_iter().map(|c| c.topic).collect();
+        assert!(topics.contains(&"deals0".to_string()) && !topics.contains(&"news".to_string()));
+        assert!(!topics.contains(&format!("deals{}", MAX_CHANNELS_PER_SIGNER - 1)));
+    }
+}
@@ -0,0 +1,68 @@
+//! Sender-side duplicate suppression
+//!
+//! Send APIs take an optional `client_token` chosen by the app for each
+//! message it composes. A send repeated with the same token within
+//! `CLIENT_TOKEN_WINDOW_SECS` (a double tap, a UI retry) returns the first
+//! send's result instead of creating a second message. The token is bound
+//! to a fingerprint of the call (API and arguments): reusing it for a
+//! different message is an error rather than a silent no-op.
+
+use crate::error::MeshError;
+use crate::storage::Storage;
+use sha2::{Digest, Sha256};
+
+/// How long a token suppresses repeats
+pub const CLIENT_TOKEN_WINDOW_SECS: i64 = 10 * 60;
+
+/// Longest accepted token, in bytes
+pub const MAX_TOKEN_LEN: usize = 128;
+
+/// Fingerprint of a send: SHA256 of the API name and each argument, length-prefixed.
+pub fn fingerprint(api: &str, args: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(api.as_bytes());
+    for arg in args {
+        hasher.update((arg.len() as u64).to_be_bytes());
+        hasher.update(arg);
+    }
+    hasher.finalize().into()
+}
+
+/// The result of an earlier send with this token, if it is still in the window.
+pub fn lookup(storage: &Storage, token: &str, fingerprint: &[u8; 32], now: i64) -> Result<Option<String>, String> {
+    if token.is_empty() || token.len() > MAX_TOKEN_LEN {
+        return Err(MeshError::InvalidArgument.raise(format!("client_token must be 1 to {} bytes", MAX_TOKEN_LEN)));
+    }
+    match storage.get_client_token(token)? {
+        Some(entry) if entry.created_at + CLIENT_TOKEN_WINDOW_SECS > now => {
+            if entry.fingerprint != *fingerprint {
+                return Err(MeshError::Rejected.raise("client_token was already used for a different message"));
+            }
+            Ok(Some(entry.result))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Remember a send's result under its token (and forget expired tokens).
+pub fn remember(storage: &Storage, token: &str, fingerprint: [u8; 32], result: &str, now: i64) -> Result<(), String> {
+    storage.prune_client_tokens(now - CLIENT_TOKEN_WINDOW_SECS)?;
+    storage.put_client_token(token, fingerprint, result, now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeat_within_window_returns_first_result() {
+        let storage = Storage::in_memory().unwrap();
+        let sent = fingerprint("send_note", &[b"hello"]);
+
+        assert_eq!(lookup(&storage, "t1", &sent, 100).unwrap(), None);
+        remember(&storage, "t1", sent, "abcd", 100).unwrap();
+        assert_eq!(lookup(&storage, "t1", &sent, 160).unwrap(), Some("abcd".to_string()));
+        assert!(lookup(&storage, "t1", &fingerprint("send_note", &[b"other"]), 160).is_err());
+        assert_eq!(lookup(&storage, "t1", &sent, 100 + CLIENT_TOKEN_WINDOW_SECS).unwrap(), None);
+    }
+}
@@ -0,0 +1,201 @@
+//! Clock discrepancies
+//!
+//! Every timestamp the core writes (message times, retention and outbox
+//! deadlines, dedup ages) comes from `now`. A device back after months
+//! offline can have a clock that is far off, so `now` is not the system
+//! clock as-is:
+//!
+//! - with network time (`report_network_time`, from GPS, NTP or a trusted
+//!   peer), it is that time plus the monotonic time elapsed since
+//! - without it, it never goes behind the high-water mark: the latest time
+//!   the core has used, persisted in the `clock.high_water` setting and
+//!   in the newest message we sent (never in a time another node claimed)
+//!
+//! `status` compares the system clock with that time; more than
+//! `MAX_SKEW_SECS` apart, the clock is "behind" or "ahead" and a
+//! `clock_skew` event tells the app. Network time is trusted over the
+//! high-water mark, so a mark pushed forward by a clock that ran ahead is
+//! reset once network time is known.
+//!
+//! Timestamps other nodes hand us (history pages, `store_message`) that lie more than
+//! `MAX_SKEW_SECS` in our future are quarantined: stored at our current
+//! time instead, with the claimed time kept in `quarantined_timestamps`,
+//! so they cannot jump ahead of everything else or outlive retention.
+
+use crate::events;
+use crate::storage::{QuarantinedTimestampRow, Storage};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Largest accepted difference between two clocks (seconds)
+pub const MAX_SKEW_SECS: i64 = 300;
+
+/// Setting holding the high-water mark
+pub const HIGH_WATER_SETTING: &str = "clock.high_water";
+
+/// How the system clock compares with the time the core uses
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClockStatus {
+    pub local_time: i64,
+    pub trusted_time: i64,
+    /// local_time - trusted_time
+    pub skew_secs: i64,
+    /// "ok", "behind" or "ahead"
+    pub state: &'static str,
+    /// Whether trusted_time comes from network time (else the high-water mark)
+    pub network_time: bool,
+}
+
+/// The core's notion of time
+#[derive(Debug, Default)]
+pub struct Clock {
+    high_water: i64,
+    /// Network time and when it was reported
+    network: Option<(i64, Instant)>,
+}
+
+impl Clock {
+    /// The time to use, given the system clock; moves the high-water mark.
+    pub fn now(&mut self, local: i64) -> i64 {
+        let trusted = match self.network {
+            Some((at, reported)) => at + reported.elapsed().as_secs() as i64,
+            None => local.max(self.high_water),
+        };
+        self.high_water = self.high_water.max(trusted);
+        trusted
+    }
+
+    /// Raise the high-water mark (e.g. from storage).
+    pub fn raise_high_water(&mut self, mark: i64) {
+        self.high_water = self.high_water.max(mark);
+    }
+
+    /// Trust a network time from now on; it replaces the high-water mark.
+    pub fn set_network_time(&mut self, network_now: i64) {
+        self.network = Some((network_now, Instant::now()));
+        self.high_water = network_now;
+    }
+
+    pub fn high_water(&self) -> i64 {
+        self.high_water
+    }
+
+    pub fn status(&mut self, local: i64) -> ClockStatus {
+        let trusted = self.now(local);
+        let skew = local - trusted;
+        ClockStatus {
+            local_time: local,
+            trusted_time: trusted,
+            skew_secs: skew,
+            state: match skew {
+                s if s < -MAX_SKEW_SECS => "behind",
+                s if s > MAX_SKEW_SECS => "ahead",
+                _ => "ok",
+            },
+            network_time: self.network.is_some(),
+        }
+    }
+}
+
+static CLOCK: Lazy<Mutex<Clock>> = Lazy::new(|| Mutex::new(Clock::default()));
+
+/// The system clock (seconds since UNIX_EPOCH).
+pub fn local_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// The time the core uses.
+pub fn now() -> i64 {
+    CLOCK.lock().unwrap().now(local_now())
+}
+
+/// Raise the high-water mark from a freshly opened store.
+pub fn load(storage: &Storage) -> Result<(), String> {
+    let saved = storage
+        .get_setting(HIGH_WATER_SETTING)?
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(0);
+    let newest = storage.newest_local_timestamp()?.unwrap_or(0);
+    CLOCK.lock().unwrap().raise_high_water(saved.max(newest));
+    Ok(())
+}
+
+/// Persist the high-water mark.
+pub fn persist(storage: &Storage) -> Result<(), String> {
+    let mark = CLOCK.lock().unwrap().high_water();
+    storage.set_setting(HIGH_WATER_SETTING, &mark.to_string())
+}
+
+/// How the system clock compares with the time the core uses.
+pub fn status() -> ClockStatus {
+    CLOCK.lock().unwrap().status(local_now())
+}
+
+/// Trust a network time; emits `clock_skew` if the system clock is off.
+pub fn report_network_time(storage: &Storage, network_now: i64) -> Result<ClockStatus, String> {
+    let status = {
+        let mut clock = CLOCK.lock().unwrap();
+        clock.set_network_time(network_now);
+        clock.status(local_now())
+    };
+    persist(storage)?;
+    if status.state != "ok" {
+        events::emit("clock_skew", serde_json::to_value(&status).unwrap_or_default());
+    }
+    Ok(status)
+}
+
+/// The timestamp to store for a message another node dated `claimed`:
+/// `claimed`, or `now` if it is too far ahead (the claim is quarantined).
+pub fn admit_timestamp(storage: &Storage, message_id: [u8; 32], channel_id: [u8; 32], claimed: i64, now: i64) -> Result<i64, String> {
+    if claimed <= now.saturating_add(MAX_SKEW_SECS) {
+        return Ok(claimed);
+    }
+    storage.quarantine_timestamp(&QuarantinedTimestampRow {
+        message_id,
+        channel_id,
+        claimed,
+        stored: now,
+        quarantined_at: now,
+    })?;
+    Ok(now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_holds_against_a_reset_clock() {
+        let mut clock = Clock::default();
+        assert_eq!(clock.now(5_000), 5_000);
+        // The clock jumps back: time holds at the high-water mark
+        assert_eq!(clock.now(100), 5_000);
+        assert_eq!(clock.status(100).state, "behind");
+
+        // A clock that ran ahead pushed the mark; network time resets it
+        clock.now(90_000);
+        clock.set_network_time(6_000);
+        let status = clock.status(90_000);
+        assert_eq!((status.trusted_time, status.state, status.network_time), (6_000, "ahead", true));
+        assert_eq!(clock.status(6_100).state, "ok");
+
+        let storage = Storage::in_memory().unwrap();
+        assert_eq!(admit_timestamp(&storage, [1u8; 32], [2u8; 32], 6_100, 6_000).unwrap(), 6_100);
+        assert_eq!(admit_timestamp(&storage, [3u8; 32], [2u8; 32], 900_000, 6_000).unwrap(), 6_000);
+        let quarantined = storage.list_quarantined_timestamps(10).unwrap();
+        assert_eq!((quarantined.len(), quarantined[0].claimed), (1, 900_000));
+
+        // A far-future peer message, however it got stored, does not move the mark
+        let far_future = 4_102_444_800;
+        storage.store_message([4u8; 32], [2u8; 32], vec![1], far_future, 3).unwrap();
+        assert_eq!(storage.newest_local_timestamp().unwrap(), Some(6_000));
+        load(&storage).unwrap();
+        assert!(CLOCK.lock().unwrap().high_water() < far_future);
+    }
+}
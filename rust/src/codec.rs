@@ -0,0 +1,80 @@
+//! Codec helpers
+//!
+//! Fallible, length-checked parsing of ids, keys and payloads shared by the
+//! FFI layer, storage and packet handlers. These return errors instead of
+//! panicking on bad lengths, so malformed input from the app, the database
+//! or the network can never bring the core down.
+
+use crate::error::MeshError;
+
+/// Largest hex payload accepted from the FFI (decoded bytes)
+pub const MAX_PAYLOAD_BYTES: usize = 4 * 1024 * 1024;
+
+/// Convert a slice to a fixed-size array.
+pub fn array_from_slice<const N: usize>(bytes: &[u8], what: &str) -> Result<[u8; N], String> {
+    bytes
+        .try_into()
+        .map_err(|_| MeshError::InvalidArgument.raise(format!("{} must be {} bytes, got {}", what, N, bytes.len())))
+}
+
+/// Convert a slice to a 32-byte id.
+pub fn id_from_slice(bytes: &[u8]) -> Result<[u8; 32], String> {
+    array_from_slice(bytes, "Id")
+}
+
+/// Parse hex into a fixed-size array (keys, signatures).
+pub fn parse_hex_array<const N: usize>(value: &str, what: &str) -> Result<[u8; N], String> {
+    let bytes = hex::decode(value).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid {} encoding: {}", what, e)))?;
+    array_from_slice(&bytes, what)
+}
+
+/// Parse a hex 32-byte id or key.
+pub fn parse_id_hex(value: &str, what: &str) -> Result<[u8; 32], String> {
+    parse_hex_array(value, what)
+}
+
+/// Parse a hex payload of at most `MAX_PAYLOAD_BYTES`.
+pub fn parse_hex_payload(value: &str) -> Result<Vec<u8>, String> {
+    if value.len() / 2 > MAX_PAYLOAD_BYTES {
+        return Err(MeshError::InvalidArgument.raise(format!("Payload larger than {} bytes", MAX_PAYLOAD_BYTES)));
+    }
+    hex::decode(value).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid payload encoding: {}", e)))
+}
+
+/// Read `N` bytes at `at`, failing if the input is too short.
+pub fn read_array<const N: usize>(bytes: &[u8], at: usize, what: &str) -> Result<[u8; N], String> {
+    let slice = at
+        .checked_add(N)
+        .and_then(|end| bytes.get(at..end))
+        .ok_or_else(|| MeshError::InvalidArgument.raise(format!("Truncated {}", what)))?;
+    array_from_slice(slice, what)
+}
+
+/// Read a big-endian u32 at `at`.
+pub fn read_u32_be(bytes: &[u8], at: usize, what: &str) -> Result<u32, String> {
+    read_array(bytes, at, what).map(u32::from_be_bytes)
+}
+
+/// Read a big-endian i64 at `at`.
+pub fn read_i64_be(bytes: &[u8], at: usize, what: &str) -> Result<i64, String> {
+    read_array(bytes, at, what).map(i64::from_be_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parsers_reject_bad_lengths_without_panicking() {
+        assert_eq!(parse_id_hex(&"ab".repeat(32), "id").unwrap(), [0xab; 32]);
+        assert!(parse_id_hex(&"ab".repeat(31), "id").is_err());
+        assert!(parse_id_hex("zz", "id").is_err());
+        assert!(parse_hex_array::<64>(&"00".repeat(32), "signature").is_err());
+
+        let bytes = [0u8, 0, 0, 7, 1];
+        assert_eq!(read_u32_be(&bytes, 0, "index").unwrap(), 7);
+        assert!(read_u32_be(&bytes, 2, "index").is_err());
+        assert!(read_array::<4>(&bytes, usize::MAX, "id").is_err());
+        assert!(id_from_slice(&[1u8; 16]).is_err());
+    }
+}
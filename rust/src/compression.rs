@@ -0,0 +1,114 @@
+//! Envelope compression
+//!
+//! Transports can compress packets, but not once they are ciphertext, so
+//! long text bodies are compressed before they are encrypted (DMs, and
+//! group messages under the channel key) and fit the peer's MTU in fewer
+//! frames. A compressed body is flagged by its first byte, which never
+//! starts UTF-8 text:
+//!
+//!   0xFF || method (1 = raw DEFLATE) || original length (u32 BE) || data
+//!
+//! Bodies shorter than `MIN_COMPRESS_LEN`, or that do not shrink, are sent
+//! as they are, and unflagged bodies open unchanged, so older messages and
+//! older senders keep working.
+//!
+//! Decompression is bounded against zip bombs: the declared length may not
+//! exceed `MAX_DECOMPRESSED_LEN`, inflating stops at it, and the result must
+//! have exactly that length.
+
+use crate::error::MeshError;
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+
+/// First byte of a compressed body (never the first byte of UTF-8 text)
+pub const COMPRESSED_MARKER: u8 = 0xFF;
+
+/// Raw DEFLATE
+const METHOD_DEFLATE: u8 = 1;
+
+const HEADER_LEN: usize = 1 + 1 + 4;
+
+/// Shortest body worth compressing
+pub const MIN_COMPRESS_LEN: usize = 128;
+
+/// Largest body a compressed one may expand to
+pub const MAX_DECOMPRESSED_LEN: usize = 256 * 1024;
+
+/// DEFLATE level (0-10): good ratio, still cheap on phones
+const LEVEL: u8 = 6;
+
+/// Compress a body if that makes it smaller.
+pub fn pack(body: &[u8]) -> Vec<u8> {
+    if body.len() < MIN_COMPRESS_LEN || body.len() > MAX_DECOMPRESSED_LEN {
+        return body.to_vec();
+    }
+    let data = compress_to_vec(body, LEVEL);
+    if HEADER_LEN + data.len() >= body.len() {
+        return body.to_vec();
+    }
+    let mut out = Vec::with_capacity(HEADER_LEN + data.len());
+    out.push(COMPRESSED_MARKER);
+    out.push(METHOD_DEFLATE);
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    out.extend_from_slice(&data);
+    out
+}
+
+/// Whether a decrypted body is compressed.
+pub fn is_compressed(body: &[u8]) -> bool {
+    body.first() == Some(&COMPRESSED_MARKER)
+}
+
+/// The original body of a decrypted one (unchanged if not compressed).
+pub fn unpack(body: Vec<u8>) -> Result<Vec<u8>, String> {
+    if !is_compressed(&body) {
+        return Ok(body);
+    }
+    if body.len() < HEADER_LEN {
+        return Err(MeshError::InvalidArgument.raise("Compressed body is truncated"));
+    }
+    if body[1] != METHOD_DEFLATE {
+        return Err(MeshError::InvalidArgument.raise(format!("Unknown compression method {}", body[1])));
+    }
+    let declared = crate::codec::read_array(&body, 2, "original length").map(u32::from_be_bytes)? as usize;
+    if declared > MAX_DECOMPRESSED_LEN {
+        return Err(format!("Compressed body claims {} bytes, more than {}", declared, MAX_DECOMPRESSED_LEN));
+    }
+    let data = decompress_to_vec_with_limit(&body[HEADER_LEN..], declared)
+        .map_err(|e| format!("Compressed body does not inflate: {}", e))?;
+    if data.len() != declared {
+        return Err("Compressed body does not match its length".to_string());
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_long_texts_shrink_and_bombs_are_refused() {
+        let short = b"hello".to_vec();
+        assert_eq!(pack(&short), short);
+        assert_eq!(unpack(short.clone()).unwrap(), short);
+
+        let long = "the mesh carries this sentence again and again. ".repeat(20).into_bytes();
+        let packed = pack(&long);
+        assert!(is_compressed(&packed) && packed.len() < long.len() / 4);
+        assert_eq!(unpack(packed.clone()).unwrap(), long);
+
+        // Random bytes do not shrink and are left alone
+        let noise: Vec<u8> = (0..512u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+        assert!(!is_compressed(&pack(&noise)) || unpack(pack(&noise)).unwrap() == noise);
+
+        // A body inflating past its declared length, or claiming too much, is refused
+        let mut lying = packed.clone();
+        lying[2..6].copy_from_slice(&10u32.to_be_bytes());
+        assert!(unpack(lying).is_err());
+        let mut bomb = vec![COMPRESSED_MARKER, METHOD_DEFLATE];
+        bomb.extend_from_slice(&(MAX_DECOMPRESSED_LEN as u32 + 1).to_be_bytes());
+        bomb.extend_from_slice(&compress_to_vec(&vec![0u8; MAX_DECOMPRESSED_LEN + 1], LEVEL));
+        assert!(unpack(bomb).unwrap_err().contains("claims"));
+        assert!(unpack(vec![COMPRESSED_MARKER, 9, 0, 0, 0, 1]).is_err());
+    }
+}
@@ -0,0 +1,306 @@
+//! Contact import from other mesh apps
+//!
+//! Importers map another app's contact export (public keys and names) to
+//! friends. Each format is a `ContactImporter`; `IMPORTERS` lists the
+//! supported ones:
+//! - "bitchat": `{ "favorites": [{ "nickname", "signingPublicKey" (hex) }] }`
+//!   or a bare array of those entries (the Noise key is not needed)
+//! - "meshtastic": `{ "nodes": [{ "user": { "id", "longName", "shortName",
+//!   "publicKey" (base64) } }] }`, as exported by node-list tools
+//!
+//! Keys must be 32-byte Ed25519 keys; entries with other keys are skipped.
+//! (Stock Meshtastic node keys are X25519, so a bridge exporting nodes has to
+//! put the node's meshapp Ed25519 key in `publicKey`.)
+//! `plan` works out what an import would do without changing anything
+//! (dry run): contacts with a new key are created (a taken nickname gets a
+//! numeric suffix), contacts already known are merged by tagging them with
+//! the source format. `apply` carries the plan out.
+
+use crate::error::MeshError;
+use crate::friends::FriendManager;
+use crate::storage::Storage;
+use crate::uri::base64url_decode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+/// A contact read from another app's export
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportedContact {
+    pub name: String,
+    pub ed25519_public: [u8; 32],
+}
+
+/// One format of contact export
+pub trait ContactImporter: Sync {
+    fn name(&self) -> &'static str;
+    /// Parse an export. Entries that cannot be used are returned as skip reasons.
+    fn parse(&self, data: &str) -> Result<(Vec<ImportedContact>, Vec<String>), String>;
+}
+
+pub struct BitchatImporter;
+pub struct MeshtasticImporter;
+
+/// Supported formats
+pub static IMPORTERS: &[&dyn ContactImporter] = &[&BitchatImporter, &MeshtasticImporter];
+
+pub fn importer(format: &str) -> Option<&'static dyn ContactImporter> {
+    IMPORTERS.iter().copied().find(|i| i.name() == format)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BitchatEntry {
+    nickname: String,
+    signing_public_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BitchatExport {
+    favorites: Vec<BitchatEntry>,
+}
+
+impl ContactImporter for BitchatImporter {
+    fn name(&self) -> &'static str {
+        "bitchat"
+    }
+
+    fn parse(&self, data: &str) -> Result<(Vec<ImportedContact>, Vec<String>), String> {
+        let value: Value = serde_json::from_str(data).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid bitchat export: {}", e)))?;
+        let entries: Vec<BitchatEntry> = if value.is_array() {
+            serde_json::from_value(value)
+        } else {
+            serde_json::from_value::<BitchatExport>(value).map(|e| e.favorites)
+        }
+        .map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid bitchat export: {}", e)))?;
+
+        let mut contacts = Vec::new();
+        let mut skipped = Vec::new();
+        for entry in entries {
+            match entry.signing_public_key.as_deref().map(|k| crate::codec::parse_id_hex(k, "signing key")) {
+                Some(Ok(key)) => contacts.push(ImportedContact { name: entry.nickname, ed25519_public: key }),
+                Some(Err(e)) => skipped.push(format!("{}: {}", entry.nickname, e)),
+                None => skipped.push(format!("{}: no signing key", entry.nickname)),
+            }
+        }
+        Ok((contacts, skipped))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MeshtasticUser {
+    id: Option<String>,
+    long_name: Option<String>,
+    short_name: Option<String>,
+    public_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MeshtasticNode {
+    user: MeshtasticUser,
+}
+
+#[derive(Deserialize)]
+struct MeshtasticExport {
+    nodes: Vec<MeshtasticNode>,
+}
+
+impl ContactImporter for MeshtasticImporter {
+    fn name(&self) -> &'static str {
+        "meshtastic"
+    }
+
+    fn parse(&self, data: &str) -> Result<(Vec<ImportedContact>, Vec<String>), String> {
+        let export: MeshtasticExport =
+            serde_json::from_str(data).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid meshtastic export: {}", e)))?;
+
+        let mut contacts = Vec::new();
+        let mut skipped = Vec::new();
+        for node in export.nodes {
+            let user = node.user;
+            let Some(name) = user.long_name.or(user.short_name).or(user.id) else {
+                skipped.push("unnamed node: no name".to_string());
+                continue;
+            };
+            // Standard base64 -> base64url
+            let key = user
+                .public_key
+                .map(|k| base64url_decode(&k.replace('+', "-").replace('/', "_")))
+                .transpose()
+                .map(|k| k.and_then(|bytes| <[u8; 32]>::try_from(bytes).ok()));
+            match key {
+                Ok(Some(key)) => contacts.push(ImportedContact { name, ed25519_public: key }),
+                Ok(None) => skipped.push(format!("{}: no 32-byte public key", name)),
+                Err(e) => skipped.push(format!("{}: {}", name, e)),
+            }
+        }
+        Ok((contacts, skipped))
+    }
+}
+
+/// What an import does (or would do) with one contact
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImportEntry {
+    /// "create" | "merge" | "unchanged"
+    pub action: &'static str,
+    pub user_id: String,
+    pub nickname: String,
+}
+
+/// Outcome of an import (or dry run)
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+    pub format: &'static str,
+    pub dry_run: bool,
+    pub entries: Vec<ImportEntry>,
+    /// Export entries that could not be imported (name: reason)
+    pub skipped: Vec<String>,
+    pub created: usize,
+    pub merged: usize,
+}
+
+#[derive(Debug, Clone)]
+struct PlannedImport {
+    entry: ImportEntry,
+    user_id: [u8; 32],
+    ed25519_public: [u8; 32],
+}
+
+fn source_tag(format: &str) -> String {
+    format!("imported:{}", format)
+}
+
+/// Nickname not yet used by a friend or an earlier contact of the import.
+fn free_nickname(friends: &FriendManager, planned: &[PlannedImport], name: &str) -> String {
+    let taken = |n: &str| {
+        friends.is_nickname_taken(n) || planned.iter().any(|p| p.entry.nickname.eq_ignore_ascii_case(n))
+    };
+    let base = if name.trim().is_empty() { "Imported contact" } else { name.trim() };
+    if !taken(base) {
+        return base.to_string();
+    }
+    (2..).map(|i| format!("{} ({})", base, i)).find(|n| !taken(n)).unwrap_or_default()
+}
+
+fn plan_contacts(friends: &FriendManager, format: &str, contacts: &[ImportedContact]) -> Vec<PlannedImport> {
+    let tag = source_tag(format);
+    let mut planned: Vec<PlannedImport> = Vec::new();
+    for contact in contacts {
+        let user_id: [u8; 32] = Sha256::digest(contact.ed25519_public).into();
+        if planned.iter().any(|p| p.user_id == user_id) {
+            continue;
+        }
+        let entry = match friends.get_friend(&user_id) {
+            Some(friend) => ImportEntry {
+                action: if friend.tags.contains(&tag) { "unchanged" } else { "merge" },
+                user_id: hex::encode(user_id),
+                nickname: friend.nickname.clone(),
+            },
+            None => ImportEntry {
+                action: "create",
+                user_id: hex::encode(user_id),
+                nickname: free_nickname(friends, &planned, &contact.name),
+            },
+        };
+        planned.push(PlannedImport { entry, user_id, ed25519_public: contact.ed25519_public });
+    }
+    planned
+}
+
+fn parse_and_plan(
+    friends: &FriendManager,
+    format: &str,
+    data: &str,
+) -> Result<(&'static str, Vec<PlannedImport>, Vec<String>), String> {
+    let importer = importer(format).ok_or_else(|| MeshError::InvalidArgument.raise(format!("Unknown contact format: {}", format)))?;
+    let (contacts, skipped) = importer.parse(data)?;
+    Ok((importer.name(), plan_contacts(friends, importer.name(), &contacts), skipped))
+}
+
+/// Parse an export and work out what importing it would do (dry run).
+pub fn plan(friends: &FriendManager, format: &str, data: &str) -> Result<ImportReport, String> {
+    let (format, planned, skipped) = parse_and_plan(friends, format, data)?;
+    Ok(report(format, true, planned, skipped))
+}
+
+/// (ed25519_public, user_id) of a friend created by an import
+pub type CreatedFriend = ([u8; 32], [u8; 32]);
+
+/// Import an export into `friends`, all of it or (on error) none of it.
+/// Returns the report and every created friend.
+pub fn apply(
+    friends: &mut FriendManager,
+    storage: &Storage,
+    format: &str,
+    data: &str,
+) -> Result<(ImportReport, Vec<CreatedFriend>), String> {
+    let (format, planned, skipped) = parse_and_plan(friends, format, data)?;
+    let tag = source_tag(format);
+
+    let imported = storage.with_transaction(|s| {
+        let mut created = Vec::new();
+        for p in &planned {
+            match p.entry.action {
+                "create" => {
+                    friends.add_friend(s, p.ed25519_public, p.entry.nickname.clone())?;
+                    friends.update_profile(s, &p.user_id, None, None, Some(vec![tag.clone()]), None)?;
+                    created.push((p.ed25519_public, p.user_id));
+                }
+                "merge" => {
+                    let mut tags = friends.get_friend(&p.user_id).map(|f| f.tags.clone()).unwrap_or_default();
+                    tags.push(tag.clone());
+                    friends.update_profile(s, &p.user_id, None, None, Some(tags), None)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(created)
+    });
+    match imported {
+        Ok(created) => Ok((report(format, false, planned, skipped), created)),
+        Err(e) => {
+            // Rolled back: drop what the cache took of it
+            *friends = FriendManager::load(storage)?;
+            Err(e)
+        }
+    }
+}
+
+fn report(format: &'static str, dry_run: bool, planned: Vec<PlannedImport>, skipped: Vec<String>) -> ImportReport {
+    let count = |action: &str| planned.iter().filter(|p| p.entry.action == action).count();
+    ImportReport {
+        format,
+        dry_run,
+        created: count("create"),
+        merged: count("merge"),
+        entries: planned.into_iter().map(|p| p.entry).collect(),
+        skipped,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_importers_parse_keys_and_skip_bad_entries() {
+        let key = [7u8; 32];
+        let bitchat = serde_json::json!({ "favorites": [
+            { "nickname": "alice", "signingPublicKey": hex::encode(key), "noisePublicKey": "00" },
+            { "nickname": "bob" },
+        ]});
+        let (contacts, skipped) = BitchatImporter.parse(&bitchat.to_string()).unwrap();
+        assert_eq!(contacts, vec![ImportedContact { name: "alice".to_string(), ed25519_public: key }]);
+        assert_eq!(skipped, vec!["bob: no signing key".to_string()]);
+
+        let meshtastic = serde_json::json!({ "nodes": [
+            { "user": { "id": "!a1", "longName": "Base", "publicKey": "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=" } },
+            { "user": { "id": "!a2", "shortName": "X", "publicKey": "AAAA" } },
+        ]});
+        let (contacts, skipped) = importer("meshtastic").unwrap().parse(&meshtastic.to_string()).unwrap();
+        assert_eq!(contacts, vec![ImportedContact { name: "Base".to_string(), ed25519_public: key }]);
+        assert_eq!(skipped.len(), 1);
+        assert!(importer("signal").is_none());
+    }
+}
@@ -0,0 +1,114 @@
+//! Crypto backend detection
+//!
+//! The RustCrypto crates pick their accelerated code paths themselves:
+//! - SHA-256: SHA-NI on x86, the ARMv8 SHA2 extension on aarch64 (detected at runtime)
+//! - ChaCha20: AVX2 or SSE2 on x86 (detected at runtime); NEON on aarch64, which
+//!   `.cargo/config.toml` enables for every aarch64 target (NEON is mandatory there)
+//! - Poly1305: AVX2 on x86 (detected at runtime)
+//!
+//! This module runs the same detection so `get_core_info` can report which
+//! backends a device actually uses; relay nodes hash and decrypt thousands
+//! of packets, so a soft fallback is worth knowing about.
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+/// Backends in use on this device
+#[derive(Serialize, Debug, Clone)]
+pub struct CryptoBackends {
+    pub sha256: &'static str,
+    pub chacha20: &'static str,
+    pub poly1305: &'static str,
+    /// Relevant CPU features detected at runtime
+    pub cpu_features: Vec<&'static str>,
+}
+
+static BACKENDS: Lazy<CryptoBackends> = Lazy::new(detect);
+
+/// The detected backends (detection runs once).
+pub fn backends() -> &'static CryptoBackends {
+    &BACKENDS
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn detect() -> CryptoBackends {
+    let mut features = Vec::new();
+    for (name, present) in [
+        ("sse2", is_x86_feature_detected!("sse2")),
+        ("ssse3", is_x86_feature_detected!("ssse3")),
+        ("sse4.1", is_x86_feature_detected!("sse4.1")),
+        ("avx2", is_x86_feature_detected!("avx2")),
+        ("sha", is_x86_feature_detected!("sha")),
+        ("aes", is_x86_feature_detected!("aes")),
+    ] {
+        if present {
+            features.push(name);
+        }
+    }
+    let has = |f: &str| features.contains(&f);
+
+    CryptoBackends {
+        sha256: if ["sha", "sse2", "ssse3", "sse4.1"].iter().all(|f| has(f)) { "sha-ni" } else { "soft" },
+        chacha20: if has("avx2") {
+            "avx2"
+        } else if has("sse2") {
+            "sse2"
+        } else {
+            "soft"
+        },
+        poly1305: if has("avx2") { "avx2" } else { "soft" },
+        cpu_features: features,
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn detect() -> CryptoBackends {
+    use std::arch::is_aarch64_feature_detected;
+
+    let mut features = Vec::new();
+    for (name, present) in [
+        ("neon", is_aarch64_feature_detected!("neon")),
+        ("sha2", is_aarch64_feature_detected!("sha2")),
+        ("aes", is_aarch64_feature_detected!("aes")),
+    ] {
+        if present {
+            features.push(name);
+        }
+    }
+
+    CryptoBackends {
+        sha256: if features.contains(&"sha2") { "armv8-sha2" } else { "soft" },
+        chacha20: if cfg!(all(chacha20_force_neon, target_feature = "neon")) { "neon" } else { "soft" },
+        poly1305: "soft",
+        cpu_features: features,
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn detect() -> CryptoBackends {
+    CryptoBackends {
+        sha256: "soft",
+        chacha20: "soft",
+        poly1305: "soft",
+        cpu_features: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backends_follow_detected_features() {
+        let b = backends();
+        #[cfg(target_arch = "x86_64")]
+        {
+            // SSE2 is part of the x86_64 baseline
+            assert!(b.cpu_features.contains(&"sse2"));
+            assert_ne!(b.chacha20, "soft");
+        }
+        if b.sha256 != "soft" {
+            assert!(b.cpu_features.iter().any(|f| *f == "sha" || *f == "sha2"));
+        }
+    }
+}
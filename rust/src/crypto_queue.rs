@@ -0,0 +1,166 @@
+//! Deferred crypto work
+//!
+//! Decrypting what a long catch-up sync brings in can mean thousands of
+//! messages. Instead of doing it on the FFI call that ingested them, their
+//! ids are queued here and a background worker works through the queue in
+//! batches of `BATCH_SIZE`, taking the core locks once per batch so other
+//! calls get in between. After each batch it emits `crypto_progress`
+//! { done, total, pending }; when the queue runs dry it emits `crypto_done`
+//! { done, total } and the counters start over.
+//!
+//! `cancel` drops everything still queued and stops the worker after the
+//! batch in hand (`crypto_cancelled` { done, dropped }). Dropped work is not
+//! lost: whatever the worker does is also done lazily on the read path.
+
+use crate::events;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Message ids processed per batch (and per lock hold)
+pub const BATCH_SIZE: usize = 64;
+
+/// Ids queued at most; further work is left to the read path
+pub const CAPACITY: usize = 16 * 1024;
+
+/// Queue state as reported by `get_crypto_queue_status`
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStatus {
+    pub pending: usize,
+    /// Ids processed since the queue was last empty
+    pub done: u64,
+    /// Ids queued since the queue was last empty
+    pub total: u64,
+    pub running: bool,
+}
+
+#[derive(Default)]
+struct QueueState {
+    ids: VecDeque<[u8; 32]>,
+    done: u64,
+    total: u64,
+    running: bool,
+    cancelled: bool,
+}
+
+static QUEUE: Lazy<Mutex<QueueState>> = Lazy::new(|| Mutex::new(QueueState::default()));
+
+/// Queue a message id. Returns false if the queue is full.
+pub fn push(id: [u8; 32]) -> bool {
+    let mut state = QUEUE.lock().unwrap();
+    if state.ids.len() >= CAPACITY {
+        return false;
+    }
+    state.ids.push_back(id);
+    state.total += 1;
+    true
+}
+
+/// Current queue state.
+pub fn status() -> QueueStatus {
+    let state = QUEUE.lock().unwrap();
+    QueueStatus {
+        pending: state.ids.len(),
+        done: state.done,
+        total: state.total,
+        running: state.running,
+    }
+}
+
+/// Drop the queued work and stop the worker after its current batch.
+/// Returns how many ids were dropped.
+pub fn cancel() -> usize {
+    let mut state = QUEUE.lock().unwrap();
+    let dropped = state.ids.len();
+    state.ids.clear();
+    state.cancelled = state.running;
+    if !state.running {
+        state.done = 0;
+        state.total = 0;
+    }
+    events::emit("crypto_cancelled", json!({ "done": state.done, "dropped": dropped }));
+    dropped
+}
+
+/// The next batch, or None once the queue is empty or cancelled (the worker
+/// then stops, and the counters are reset).
+fn next_batch(state: &mut QueueState) -> Option<Vec<[u8; 32]>> {
+    if state.ids.is_empty() || state.cancelled {
+        if !state.cancelled {
+            events::emit("crypto_done", json!({ "done": state.done, "total": state.total }));
+        }
+        *state = QueueState::default();
+        return None;
+    }
+    let n = state.ids.len().min(BATCH_SIZE);
+    Some(state.ids.drain(..n).collect())
+}
+
+/// Work through the queue on this thread, calling `process` per batch.
+fn run<F>(mut process: F)
+where
+    F: FnMut(&[[u8; 32]]),
+{
+    loop {
+        let Some(batch) = next_batch(&mut QUEUE.lock().unwrap()) else {
+            return;
+        };
+        process(&batch);
+        let mut state = QUEUE.lock().unwrap();
+        state.done += batch.len() as u64;
+        events::emit(
+            "crypto_progress",
+            json!({ "done": state.done, "total": state.total, "pending": state.ids.len() }),
+        );
+    }
+}
+
+/// Start a background worker for the queued ids unless one is running or
+/// there is nothing to do. `process` must take the locks it needs itself.
+pub fn start<F>(process: F)
+where
+    F: FnMut(&[[u8; 32]]) + Send + 'static,
+{
+    {
+        let mut state = QUEUE.lock().unwrap();
+        if state.running || state.ids.is_empty() {
+            return;
+        }
+        state.running = true;
+    }
+    std::thread::spawn(move || run(process));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker_runs_in_batches_until_cancelled() {
+        for i in 0..(BATCH_SIZE + 1) as u32 {
+            let mut id = [0u8; 32];
+            id[..4].copy_from_slice(&i.to_be_bytes());
+            assert!(push(id));
+        }
+        assert_eq!(status().total, BATCH_SIZE as u64 + 1);
+
+        // Cancelled while the first batch is in hand: the rest is dropped
+        QUEUE.lock().unwrap().running = true;
+        let mut batches = Vec::new();
+        run(|batch| {
+            batches.push(batch.len());
+            cancel();
+        });
+        assert_eq!(batches, vec![BATCH_SIZE]);
+        assert_eq!(status(), QueueStatus::default());
+
+        push([1u8; 32]);
+        QUEUE.lock().unwrap().running = true;
+        let mut seen = Vec::new();
+        run(|batch| seen.extend_from_slice(batch));
+        assert_eq!(seen, vec![[1u8; 32]]);
+        assert!(!status().running);
+    }
+}
@@ -0,0 +1,276 @@
+//! Diagnostics bundles
+//!
+//! `create` writes what a bug report needs into a .tar.gz the user can attach
+//! to an issue:
+//!
+//! - manifest.json: core version, FFI and database schema versions, platform,
+//!   creation time
+//! - logs.json: recent failures (see `error::recent`)
+//! - router.json: transports, router caches, outbox, relay rule and budget stats
+//! - metrics.json: ingest, memory, crypto queue and clock status
+//! - schema.json: the database's tables, columns and row counts
+//! - config.json: the core settings (app-defined keys are left out)
+//!
+//! Sanitizing happens here rather than in the app, so no caller can forget
+//! it. Nothing is read from message bodies, keys, friends or profiles, and
+//! every string (object keys included) goes through `redact_text` on the way
+//! out: long hex or base64 runs (keys, ids), quoted text (names in error
+//! messages) and file paths are replaced, so a field added to a source later
+//! cannot leak either.
+
+use crate::error::{self, MeshError};
+use crate::ffi_types;
+use crate::settings;
+use crate::storage::Storage;
+use crate::transport::Router;
+use crate::{clock, crypto_queue, ingest, memory_budget, outbox, relay_budget, relay_policy};
+use miniz_oxide::deflate::compress_to_vec;
+use serde_json::{json, Map, Value};
+use std::path::{Path, PathBuf};
+
+/// What replaces sanitized text
+const REDACTED: &str = "[redacted]";
+
+/// Shortest all-hex run taken for an id or key (fingerprints are 16)
+const MIN_HEX_RUN: usize = 16;
+
+/// Shortest base64-like run taken for a key or token
+const MIN_TOKEN_RUN: usize = 24;
+
+/// Collect the bundle's files, sanitized. The router and storage are left out
+/// when not initialized.
+pub fn collect(router: Option<&Router>, storage: Option<&Storage>, now: i64) -> Result<Vec<(&'static str, Value)>, String> {
+    let value = |v: Result<Value, serde_json::Error>| v.map_err(|e| format!("Failed to serialize diagnostics: {}", e));
+
+    let manifest = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "schema_version": ffi_types::SCHEMA_VERSION,
+        "target_arch": std::env::consts::ARCH,
+        "target_os": std::env::consts::OS,
+        "created_at": now,
+        "router_initialized": router.is_some(),
+        "storage_initialized": storage.is_some(),
+        "db_schema_version": storage.map(Storage::schema_version).transpose()?,
+    });
+    let logs = value(serde_json::to_value(error::recent()))?;
+    let metrics = json!({
+        "ingest": value(serde_json::to_value(ingest::INGEST.metrics()))?,
+        "memory": value(serde_json::to_value(memory_budget::BUDGET.metrics()))?,
+        "crypto_queue": value(serde_json::to_value(crypto_queue::status()))?,
+        "clock": value(serde_json::to_value(clock::status()))?,
+    });
+    let mut router_stats = json!({
+        "router": value(serde_json::to_value(router.map(Router::stats)))?,
+        "relay_rules": value(serde_json::to_value(relay_policy::stats()))?,
+    });
+    let (mut schema, mut config) = (Value::Null, Value::Null);
+    if let Some(storage) = storage {
+        router_stats["outbox"] = value(serde_json::to_value(outbox::status(storage)?))?;
+        router_stats["relay_budget"] = value(serde_json::to_value(relay_budget::status(storage, now)?))?;
+        schema = value(serde_json::to_value(storage.table_summaries()?))?;
+        let mut settings_map = Map::new();
+        for key in settings::KNOWN_KEYS {
+            settings_map.insert(key.to_string(), settings::get_value(storage, key)?);
+        }
+        config = Value::Object(settings_map);
+    }
+
+    Ok([
+        ("manifest.json", manifest),
+        ("logs.json", logs),
+        ("router.json", router_stats),
+        ("metrics.json", metrics),
+        ("schema.json", schema),
+        ("config.json", config),
+    ]
+    .into_iter()
+    .map(|(name, v)| (name, redact(v)))
+    .collect())
+}
+
+/// Write a bundle into `dir`. Returns the path of the archive.
+pub fn create(router: Option<&Router>, storage: Option<&Storage>, dir: &Path, now: i64) -> Result<PathBuf, String> {
+    let files = collect(router, storage, now)?;
+    let archive = archive(&files, now)?;
+    std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create diagnostics directory: {}", e))?;
+    let path = dir.join(format!("meshapp-diagnostics-{}.tar.gz", now));
+    std::fs::write(&path, archive).map_err(|e| format!("Failed to write diagnostics bundle: {}", e))?;
+    Ok(path)
+}
+
+/// Sanitize every string of a JSON value, object keys included.
+pub fn redact(value: Value) -> Value {
+    match value {
+        Value::String(s) => Value::String(redact_text(&s)),
+        Value::Array(items) => Value::Array(items.into_iter().map(redact).collect()),
+        Value::Object(map) => Value::Object(map.into_iter().map(|(k, v)| (redact_text(&k), redact(v))).collect()),
+        other => other,
+    }
+}
+
+/// Replace quoted text, file paths and key- or id-like runs in free text.
+pub fn redact_text(text: &str) -> String {
+    redact_quoted(text)
+        .split_inclusive(char::is_whitespace)
+        .map(|word| {
+            let bare = word.trim_end();
+            let is_path = bare.starts_with('/') || bare.starts_with("~/") || bare.contains(":\\");
+            if is_path {
+                format!("{}{}", REDACTED, &word[bare.len()..])
+            } else {
+                redact_runs(word)
+            }
+        })
+        .collect()
+}
+
+/// Replace text between quotes. A quote right after a letter or digit is an
+/// apostrophe, not an opening quote.
+fn redact_quoted(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let opens = (c == '\'' || c == '"') && (i == 0 || !chars[i - 1].is_alphanumeric());
+        if opens {
+            if let Some(len) = chars[i + 1..].iter().position(|&d| d == c) {
+                out.push(c);
+                out.push_str(REDACTED);
+                out.push(c);
+                i += len + 2;
+                continue;
+            }
+        }
+        out.push(c);
+        i += 1;
+    }
+    out
+}
+
+/// Replace runs of hex (ids, keys) and of base64 with digits (keys, tokens).
+fn redact_runs(word: &str) -> String {
+    let is_run_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '=' | '_' | '-');
+    let flush = |run: &mut String, out: &mut String| {
+        let hex = run.len() >= MIN_HEX_RUN && run.chars().all(|c| c.is_ascii_hexdigit());
+        let token = run.len() >= MIN_TOKEN_RUN
+            && run.chars().any(|c| c.is_ascii_digit())
+            && run.chars().any(|c| c.is_ascii_alphabetic());
+        out.push_str(if hex || token { REDACTED } else { run.as_str() });
+        run.clear();
+    };
+    let mut out = String::with_capacity(word.len());
+    let mut run = String::new();
+    for c in word.chars() {
+        if is_run_char(c) {
+            run.push(c);
+        } else {
+            flush(&mut run, &mut out);
+            out.push(c);
+        }
+    }
+    flush(&mut run, &mut out);
+    out
+}
+
+/// The files as a gzip-compressed tar archive.
+pub fn archive(files: &[(&str, Value)], now: i64) -> Result<Vec<u8>, String> {
+    let mut tar = Vec::new();
+    for (name, value) in files {
+        let data = serde_json::to_vec_pretty(value).map_err(|e| format!("Failed to serialize {}: {}", name, e))?;
+        tar_entry(&mut tar, name, &data, now)?;
+    }
+    // End of archive: two empty blocks
+    tar.extend_from_slice(&[0u8; 1024]);
+
+    let mut gz = vec![0x1f, 0x8b, 8, 0];
+    gz.extend_from_slice(&(now.clamp(0, u32::MAX as i64) as u32).to_le_bytes());
+    gz.extend_from_slice(&[0, 255]);
+    gz.extend_from_slice(&compress_to_vec(&tar, 6));
+    gz.extend_from_slice(&crc32(&tar).to_le_bytes());
+    gz.extend_from_slice(&(tar.len() as u32).to_le_bytes());
+    Ok(gz)
+}
+
+/// Append one regular file (ustar header, data padded to 512-byte blocks).
+fn tar_entry(out: &mut Vec<u8>, name: &str, data: &[u8], mtime: i64) -> Result<(), String> {
+    if name.len() >= 100 {
+        return Err(MeshError::InvalidArgument.raise(format!("Archive name too long: {}", name)));
+    }
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], 0o644);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], data.len() as u64);
+    write_octal(&mut header[136..148], mtime.max(0) as u64);
+    header[148..156].fill(b' ');
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+    out.extend_from_slice(&header);
+    out.extend_from_slice(data);
+    out.resize(out.len().div_ceil(512) * 512, 0);
+    Ok(())
+}
+
+/// A zero-padded octal field ending in NUL.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(&digits.as_bytes()[digits.len() - field.len()..]);
+}
+
+/// CRC-32 (IEEE), as gzip trailers use.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::MeshError;
+    use miniz_oxide::inflate::decompress_to_vec;
+
+    #[test]
+    fn test_bundle_is_a_sanitized_tar_gz() {
+        let storage = Storage::in_memory().unwrap();
+        let channel = hex::encode([7u8; 32]);
+        settings::set_value(&storage, "app.display_name", json!("Ana")).unwrap();
+        error::set(MeshError::NotFound, &format!("Unknown channel {}", channel));
+        error::set(MeshError::Rejected, "Nickname 'ana' is already taken");
+        error::set(MeshError::Storage, "Failed to open /home/ana/.local/share/meshapp/mesh.db: locked");
+
+        assert_eq!(redact_text("key c0ffee00c0ffee00c0ffee00 isn't valid"), "key [redacted] isn't valid");
+        assert_eq!(redact_text("token dGhpcyBpcyBhIHNlY3JldCB0b2tlbg9= used"), "token [redacted] used");
+        assert_eq!(redact_text("relay.daily_budget_bytes"), "relay.daily_budget_bytes");
+
+        let files = collect(None, Some(&storage), 1_700_000_000).unwrap();
+        let text = serde_json::to_string(&files.iter().map(|(_, v)| v).collect::<Vec<_>>()).unwrap();
+        for secret in [channel.as_str(), "'ana'", "/home/ana", "Ana"] {
+            assert!(!text.contains(secret), "bundle leaks {}", secret);
+        }
+        assert!(text.contains("already taken") && text.contains("send_states"));
+
+        // A gzip of a ustar archive holding every file
+        let gz = archive(&files, 1_700_000_000).unwrap();
+        assert_eq!(&gz[..3], &[0x1f, 0x8b, 8]);
+        let tar = decompress_to_vec(&gz[10..gz.len() - 8]).unwrap();
+        assert_eq!(gz[gz.len() - 8..gz.len() - 4], crc32(&tar).to_le_bytes());
+        assert_eq!(&tar[257..262], b"ustar");
+        assert!(tar.len().is_multiple_of(512));
+        for (name, _) in &files {
+            assert!(tar.windows(name.len()).any(|w| w == name.as_bytes()));
+        }
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
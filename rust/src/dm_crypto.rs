@@ -3,11 +3,17 @@
 //! Implements Noise Protocol IK pattern for encrypted direct messages between friends.
 //! - DM Channel ID: SHA256(min(pubA, pubB) || max(pubA, pubB))
 //! - Noise Pattern: Noise_IK_25519_ChaChaPoly_SHA256
+//! - Sessions: `DmSessionManager` (one-sided IK handshakes seeding a Double Ratchet, see `ratchet`)
 
 use sha2::{Sha256, Digest};
-use snow::Builder;
 use std::cmp::Ordering;
-use chacha20poly1305::{ChaCha20Poly1305, KeyInit, aead::Aead};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, aead::{Aead, Payload}};
+use crate::compression;
+use crate::error::MeshError;
+use crate::ratchet;
+use crate::storage::{DmSessionRow, Storage};
+use x25519_dalek::StaticSecret;
+use zeroize::Zeroizing;
 
 /// Derive DM channel ID from two Ed25519 public keys
 /// 
@@ -49,11 +55,11 @@ impl DmCryptoState {
     /// Encrypt a message
     pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, String> {
         let transport = self.transport_state.as_mut()
-            .ok_or("Transport state not initialized")?;
+            .ok_or_else(|| MeshError::NotInitialized.raise("Transport state not initialized"))?;
 
         let mut ciphertext = vec![0u8; plaintext.len() + 16]; // +16 for MAC
         let len = transport.write_message(plaintext, &mut ciphertext)
-            .map_err(|e| format!("Encryption failed: {}", e))?;
+            .map_err(|e| MeshError::Crypto.raise(format!("Encryption failed: {}", e)))?;
 
         ciphertext.truncate(len);
         Ok(ciphertext)
@@ -62,11 +68,11 @@ impl DmCryptoState {
     /// Decrypt a message
     pub fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, String> {
         let transport = self.transport_state.as_mut()
-            .ok_or("Transport state not initialized")?;
+            .ok_or_else(|| MeshError::NotInitialized.raise("Transport state not initialized"))?;
 
         let mut plaintext = vec![0u8; ciphertext.len()];
         let len = transport.read_message(ciphertext, &mut plaintext)
-            .map_err(|e| format!("Decryption failed: {}", e))?;
+            .map_err(|e| MeshError::Crypto.raise(format!("Decryption failed: {}", e)))?;
 
         plaintext.truncate(len);
         Ok(plaintext)
@@ -89,7 +95,7 @@ pub fn perform_ik_handshake_initiator(
     local_x25519_secret: &[u8; 32],
     remote_x25519_public: &[u8; 32],
 ) -> Result<(Vec<u8>, snow::TransportState), String> {
-    let builder = Builder::new("Noise_IK_25519_ChaChaPoly_SHA256".parse()
+    let builder = crate::rng::noise_builder("Noise_IK_25519_ChaChaPoly_SHA256".parse()
         .map_err(|e| format!("Invalid noise pattern: {}", e))?);
 
     let mut handshake = builder
@@ -103,7 +109,7 @@ pub fn perform_ik_handshake_initiator(
     // Write message 1 (initiator -> responder)
     let mut msg1 = vec![0u8; 1024];
     let msg1_len = handshake.write_message(&[], &mut msg1)
-        .map_err(|e| format!("Handshake message 1 write failed: {}", e))?;
+        .map_err(|e| MeshError::Crypto.raise(format!("Handshake message 1 write failed: {}", e)))?;
     msg1.truncate(msg1_len);
 
     // Read message 2 (responder -> initiator)
@@ -125,11 +131,11 @@ pub fn perform_full_ik_handshake(
     initiator_x25519_public: &[u8; 32],
     responder_x25519_public: &[u8; 32],
 ) -> Result<(snow::TransportState, snow::TransportState), String> {
-    let _builder = Builder::new("Noise_IK_25519_ChaChaPoly_SHA256".parse()
+    let _builder = crate::rng::noise_builder("Noise_IK_25519_ChaChaPoly_SHA256".parse()
         .map_err(|e| format!("Invalid noise pattern: {}", e))?);
 
     // Initiator side
-    let init_builder = Builder::new("Noise_IK_25519_ChaChaPoly_SHA256".parse()
+    let init_builder = crate::rng::noise_builder("Noise_IK_25519_ChaChaPoly_SHA256".parse()
         .map_err(|e| format!("Invalid noise pattern: {}", e))?);
     let mut init_handshake = init_builder
         .local_private_key(initiator_x25519_secret)
@@ -140,7 +146,7 @@ pub fn perform_full_ik_handshake(
         .map_err(|e| format!("Failed to build initiator: {}", e))?;
 
     // Responder side
-    let resp_builder = Builder::new("Noise_IK_25519_ChaChaPoly_SHA256".parse()
+    let resp_builder = crate::rng::noise_builder("Noise_IK_25519_ChaChaPoly_SHA256".parse()
         .map_err(|e| format!("Invalid noise pattern: {}", e))?);
     let mut resp_handshake = resp_builder
         .local_private_key(responder_x25519_secret)
@@ -153,23 +159,23 @@ pub fn perform_full_ik_handshake(
     // Initiator sends message 1
     let mut msg1 = vec![0u8; 1024];
     let msg1_len = init_handshake.write_message(&[], &mut msg1)
-        .map_err(|e| format!("Handshake message 1 write failed: {}", e))?;
+        .map_err(|e| MeshError::Crypto.raise(format!("Handshake message 1 write failed: {}", e)))?;
     msg1.truncate(msg1_len);
 
     // Responder reads message 1 and sends message 2
     let mut msg2_buf = vec![0u8; 1024];
     resp_handshake.read_message(&msg1, &mut msg2_buf)
-        .map_err(|e| format!("Handshake message 1 read failed: {}", e))?;
+        .map_err(|e| MeshError::Crypto.raise(format!("Handshake message 1 read failed: {}", e)))?;
 
     let mut msg2 = vec![0u8; 1024];
     let msg2_len = resp_handshake.write_message(&[], &mut msg2)
-        .map_err(|e| format!("Handshake message 2 write failed: {}", e))?;
+        .map_err(|e| MeshError::Crypto.raise(format!("Handshake message 2 write failed: {}", e)))?;
     msg2.truncate(msg2_len);
 
     // Initiator reads message 2
     let mut dummy = vec![0u8; 1024];
     init_handshake.read_message(&msg2, &mut dummy)
-        .map_err(|e| format!("Handshake message 2 read failed: {}", e))?;
+        .map_err(|e| MeshError::Crypto.raise(format!("Handshake message 2 read failed: {}", e)))?;
 
     // Both sides enter transport mode
     let init_transport = init_handshake.into_transport_mode()
@@ -205,7 +211,7 @@ impl DmSession {
     pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, String> {
         let mut ciphertext = vec![0u8; plaintext.len() + 16]; // +16 for MAC
         let len = self.transport.write_message(plaintext, &mut ciphertext)
-            .map_err(|e| format!("Encryption failed: {}", e))?;
+            .map_err(|e| MeshError::Crypto.raise(format!("Encryption failed: {}", e)))?;
         ciphertext.truncate(len);
         Ok(ciphertext)
     }
@@ -214,7 +220,7 @@ impl DmSession {
     pub fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, String> {
         let mut plaintext = vec![0u8; ciphertext.len()];
         let len = self.transport.read_message(ciphertext, &mut plaintext)
-            .map_err(|e| format!("Decryption failed: {}", e))?;
+            .map_err(|e| MeshError::Crypto.raise(format!("Decryption failed: {}", e)))?;
         plaintext.truncate(len);
         Ok(plaintext)
     }
@@ -243,6 +249,288 @@ pub fn decrypt_dm_message(session: &mut DmSession, ciphertext: &[u8]) -> Result<
     session.decrypt(ciphertext)
 }
 
+/// Wire version of DMs on a bare Noise session (no longer sent; still read)
+pub const DM_WIRE_VERSION: u8 = 2;
+
+/// Wire version of DMs on a Double Ratchet (see `DmSessionManager`)
+pub const DM_RATCHET_WIRE_VERSION: u8 = 3;
+
+/// Both keys of a Noise split, initiator-to-responder first
+pub type NoiseSplit = ([u8; 32], [u8; 32]);
+
+/// Noise IK first message with an empty payload: e (32) || encrypted s (48) || tag (16)
+pub const IK_HANDSHAKE_LEN: usize = 32 + 48 + 16;
+
+const DM_HEADER_LEN: usize = 1 + IK_HANDSHAKE_LEN + 8;
+
+/// A decrypted session DM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenedDm {
+    pub plaintext: Vec<u8>,
+    /// True when we sent it (it is on one of our outgoing sessions)
+    pub outgoing: bool,
+}
+
+/// DM sessions backed by storage.
+///
+/// Mesh peers are rarely online at the same time, so the handshake is
+/// one-sided: the sender runs the first message of Noise IK against the
+/// friend's static X25519 key (registered with `Storage::set_dm_peer_key`)
+/// and both sides take keys from the Noise split right after it. The split
+/// seeds a Double Ratchet (see `ratchet`) that gives every message its own
+/// key. We send on the ratchet a message last arrived on (so replies step it
+/// forward), else on one we seed ourselves.
+///
+/// Wire format: version (3) || handshake message || ratchet message.
+/// Every message carries the handshake so it can be read in any order; the
+/// receiver authenticates the sender's static key in it, derives the same
+/// seed with only its own secret and identifies the ratchet by its hash.
+/// The seeding session is kept too (attachment keys are sealed under it).
+///
+/// Version 2 messages (handshake || counter (u64 BE) || ChaCha20-Poly1305
+/// under the initiator-to-responder key, nonce = 0u32 || counter, AD =
+/// channel id) are no longer sent but still decrypt.
+pub struct DmSessionManager<'a> {
+    storage: &'a Storage,
+    /// Our static secret, wiped when the manager is dropped
+    local_x25519_secret: Zeroizing<[u8; 32]>,
+}
+
+impl<'a> DmSessionManager<'a> {
+    pub fn new(storage: &'a Storage, local_x25519_secret: &StaticSecret) -> Self {
+        Self { storage, local_x25519_secret: Zeroizing::new(local_x25519_secret.to_bytes()) }
+    }
+
+    /// Encrypt a DM to `remote_static`, seeding a ratchet first if needed.
+    /// Its key is kept for `message_id`, so it can be read again.
+    pub fn encrypt(
+        &self,
+        channel_id: [u8; 32],
+        remote_static: [u8; 32],
+        message_id: [u8; 32],
+        plaintext: &[u8],
+        now: i64,
+    ) -> Result<Vec<u8>, String> {
+        let ratchet = match self.storage.sending_dm_ratchet(channel_id, remote_static)? {
+            Some(ratchet) => ratchet,
+            None => {
+                let (handshake, split) = self.handshake_initiator(remote_static)?;
+                let session = session_row(channel_id, true, remote_static, &handshake, split.0, now);
+                let ratchet = ratchet::initiate(session.session_id, channel_id, remote_static, handshake, &split, now);
+                self.storage.with_transaction(|s| {
+                    s.put_dm_session(&session)?;
+                    s.put_dm_ratchet(&ratchet)
+                })?;
+                ratchet
+            }
+        };
+        let body = ratchet::encrypt(self.storage, &ratchet, message_id, &compression::pack(plaintext))?;
+        Ok([&[DM_RATCHET_WIRE_VERSION][..], &ratchet.handshake, &body].concat())
+    }
+
+    /// Decrypt DM `message_id` of `channel_id`. Sessions started by the
+    /// friend are accepted only if their static key is `remote_static`.
+    pub fn decrypt(
+        &self,
+        channel_id: [u8; 32],
+        remote_static: Option<[u8; 32]>,
+        message_id: [u8; 32],
+        data: &[u8],
+        now: i64,
+    ) -> Result<OpenedDm, String> {
+        let opened = self.decrypt_sealed(channel_id, remote_static, message_id, data, now)?;
+        Ok(OpenedDm { plaintext: compression::unpack(opened.plaintext)?, outgoing: opened.outgoing })
+    }
+
+    /// `decrypt`, leaving a compressed body as the AEAD opened it.
+    pub fn decrypt_sealed(
+        &self,
+        channel_id: [u8; 32],
+        remote_static: Option<[u8; 32]>,
+        message_id: [u8; 32],
+        data: &[u8],
+        now: i64,
+    ) -> Result<OpenedDm, String> {
+        if is_ratchet_message(data) {
+            return self.decrypt_ratchet(channel_id, remote_static, message_id, data, now);
+        }
+        if !is_session_message(data) {
+            return Err(MeshError::Crypto.raise("Not a session-encrypted DM"));
+        }
+        let handshake = &data[1..1 + IK_HANDSHAKE_LEN];
+        let counter = crate::codec::read_array::<8>(data, 1 + IK_HANDSHAKE_LEN, "counter").map(u64::from_be_bytes)?;
+        let session_id: [u8; 32] = Sha256::digest(handshake).into();
+
+        let session = match self.storage.get_dm_session(session_id)? {
+            Some(session) => session,
+            None => {
+                let session = self.respond(channel_id, handshake, now)?;
+                if Some(session.remote_static) != remote_static {
+                    return Err(MeshError::Crypto.raise("DM session is not from this friend's key"));
+                }
+                self.storage.put_dm_session(&session)?;
+                session
+            }
+        };
+        if session.channel_id != channel_id {
+            return Err("DM session belongs to another channel".to_string());
+        }
+        Ok(OpenedDm {
+            plaintext: open(&session.key, counter, &channel_id, &data[DM_HEADER_LEN..])?,
+            outgoing: session.outgoing,
+        })
+    }
+
+    fn decrypt_ratchet(
+        &self,
+        channel_id: [u8; 32],
+        remote_static: Option<[u8; 32]>,
+        message_id: [u8; 32],
+        data: &[u8],
+        now: i64,
+    ) -> Result<OpenedDm, String> {
+        let (handshake, body) = data[1..].split_at(IK_HANDSHAKE_LEN);
+        // Read before: its key was kept
+        if let Some((key, outgoing)) = self.storage.get_dm_message_key(message_id)? {
+            return Ok(OpenedDm { plaintext: ratchet::open_with_key(&key, &channel_id, body)?, outgoing });
+        }
+
+        let ratchet_id: [u8; 32] = Sha256::digest(handshake).into();
+        let (ratchet, new_session) = match self.storage.get_dm_ratchet(ratchet_id)? {
+            // The friend's key may have changed since the ratchet started
+            Some(ratchet) if Some(ratchet.remote_static) != remote_static => {
+                return Err(MeshError::Crypto.raise("DM session is not from this friend's key"));
+            }
+            Some(ratchet) => (ratchet, None),
+            None => {
+                let (remote, split) = self.handshake_responder(handshake)?;
+                if Some(remote) != remote_static {
+                    return Err(MeshError::Crypto.raise("DM session is not from this friend's key"));
+                }
+                let session = session_row(channel_id, false, remote, handshake, split.0, now);
+                let ratchet = ratchet::respond(ratchet_id, channel_id, remote, handshake.to_vec(), &split, *self.local_x25519_secret, now);
+                (ratchet, Some(session))
+            }
+        };
+        if ratchet.channel_id != channel_id {
+            return Err("DM session belongs to another channel".to_string());
+        }
+        // A new ratchet is stored once a message opens on it
+        let opened = self.storage.with_transaction(|s| {
+            let opened = ratchet::decrypt(s, &ratchet, message_id, body, now)?;
+            if let Some(session) = &new_session {
+                s.put_dm_session(session)?;
+            }
+            Ok(opened)
+        })?;
+        Ok(OpenedDm { plaintext: opened.plaintext, outgoing: false })
+    }
+
+    /// First Noise IK message to `remote_static`, and the split after it.
+    fn handshake_initiator(&self, remote_static: [u8; 32]) -> Result<(Vec<u8>, Zeroizing<NoiseSplit>), String> {
+        let mut handshake = crate::rng::noise_builder(noise_params()?)
+            .local_private_key(&*self.local_x25519_secret)
+            .map_err(|e| format!("Failed to set local private key: {}", e))?
+            .remote_public_key(&remote_static)
+            .map_err(|e| format!("Failed to set remote public key: {}", e))?
+            .build_initiator()
+            .map_err(|e| format!("Failed to build initiator: {}", e))?;
+
+        let mut message = vec![0u8; IK_HANDSHAKE_LEN];
+        let len = handshake
+            .write_message(&[], &mut message)
+            .map_err(|e| MeshError::Crypto.raise(format!("Handshake message write failed: {}", e)))?;
+        message.truncate(len);
+        Ok((message, Zeroizing::new(handshake.dangerously_get_raw_split())))
+    }
+
+    /// The sender's static key in a first Noise IK message, and the split after it.
+    fn handshake_responder(&self, message: &[u8]) -> Result<([u8; 32], Zeroizing<NoiseSplit>), String> {
+        let mut handshake = crate::rng::noise_builder(noise_params()?)
+            .local_private_key(&*self.local_x25519_secret)
+            .map_err(|e| format!("Failed to set local private key: {}", e))?
+            .build_responder()
+            .map_err(|e| format!("Failed to build responder: {}", e))?;
+
+        let mut payload = vec![0u8; IK_HANDSHAKE_LEN];
+        handshake
+            .read_message(message, &mut payload)
+            .map_err(|e| MeshError::Crypto.raise(format!("Handshake message read failed: {}", e)))?;
+        let remote_static: [u8; 32] = handshake
+            .get_remote_static()
+            .and_then(|k| k.try_into().ok())
+            .ok_or_else(|| MeshError::Crypto.raise("Handshake did not carry a static key"))?;
+        Ok((remote_static, Zeroizing::new(handshake.dangerously_get_raw_split())))
+    }
+
+    fn respond(&self, channel_id: [u8; 32], message: &[u8], now: i64) -> Result<DmSessionRow, String> {
+        let (remote_static, split) = self.handshake_responder(message)?;
+        Ok(session_row(channel_id, false, remote_static, message, split.0, now))
+    }
+}
+
+/// A Noise session, with the initiator-to-responder key of its split.
+fn session_row(channel_id: [u8; 32], outgoing: bool, remote_static: [u8; 32], handshake: &[u8], key: [u8; 32], now: i64) -> DmSessionRow {
+    DmSessionRow {
+        session_id: Sha256::digest(handshake).into(),
+        channel_id,
+        outgoing,
+        remote_static,
+        handshake: handshake.to_vec(),
+        key,
+        next_counter: 0,
+        created_at: now,
+    }
+}
+
+/// Whether a stored DM ciphertext is on a Double Ratchet.
+pub fn is_ratchet_message(data: &[u8]) -> bool {
+    data.first() == Some(&DM_RATCHET_WIRE_VERSION) && data.len() >= 1 + IK_HANDSHAKE_LEN + ratchet::ENCRYPTED_HEADER_LEN + 16
+}
+
+/// Split a ratchet DM into its handshake message, encrypted header and AEAD ciphertext.
+#[cfg(feature = "audit")]
+pub fn split_ratchet_message(data: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    if !is_ratchet_message(data) {
+        return None;
+    }
+    let (handshake, body) = data[1..].split_at(IK_HANDSHAKE_LEN);
+    let (header, ciphertext) = body.split_at(ratchet::ENCRYPTED_HEADER_LEN);
+    Some((handshake, header, ciphertext))
+}
+
+/// Whether a stored DM ciphertext uses the session format.
+pub fn is_session_message(data: &[u8]) -> bool {
+    data.first() == Some(&DM_WIRE_VERSION) && data.len() >= DM_HEADER_LEN + 16
+}
+
+/// Split a session DM into its handshake message, counter and AEAD ciphertext.
+#[cfg(feature = "audit")]
+pub fn split_session_message(data: &[u8]) -> Option<(&[u8], u64, &[u8])> {
+    if !is_session_message(data) {
+        return None;
+    }
+    let counter = crate::codec::read_array::<8>(data, 1 + IK_HANDSHAKE_LEN, "counter").ok()?;
+    Some((&data[1..1 + IK_HANDSHAKE_LEN], u64::from_be_bytes(counter), &data[DM_HEADER_LEN..]))
+}
+
+fn noise_params() -> Result<snow::params::NoiseParams, String> {
+    "Noise_IK_25519_ChaChaPoly_SHA256".parse().map_err(|e| format!("Invalid noise pattern: {}", e))
+}
+
+/// AEAD nonce of a session message: 0u32 || counter (u64 BE).
+pub fn counter_nonce(counter: u64) -> chacha20poly1305::Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce.into()
+}
+
+fn open(key: &[u8; 32], counter: u64, channel_id: &[u8; 32], ciphertext: &[u8]) -> Result<Vec<u8>, String> {
+    ChaCha20Poly1305::new(key.into())
+        .decrypt(&counter_nonce(counter), Payload { msg: ciphertext, aad: channel_id })
+        .map_err(|e| MeshError::Crypto.raise(format!("Decryption failed: {}", e)))
+}
+
 /// Test helper: Create a session by simulating both sides of handshake
 /// 
 /// This is for Phase 3 testing only. In production, handshake happens over network.
@@ -278,29 +566,8 @@ pub fn dm_channel_id_to_hex(channel_id: &[u8; 32]) -> String {
     hex::encode(channel_id)
 }
 
-/// Deterministic encryption for self-messaging
-/// Uses ChaCha20Poly1305 with a key derived from channel_id and a nonce from message_id
-pub fn encrypt_self_message(channel_id: &[u8; 32], message_id: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, String> {
-    // Derive encryption key from channel_id
-    let mut hasher = Sha256::new();
-    hasher.update(b"self_msg_key");
-    hasher.update(channel_id);
-    let key_bytes: [u8; 32] = hasher.finalize().into();
-    let key = chacha20poly1305::Key::from_slice(&key_bytes);
-    
-    // Use message_id as nonce (first 12 bytes)
-    // If message_id is less than 12 bytes, pad with zeros
-    let mut nonce_bytes = [0u8; 12];
-    let copy_len = std::cmp::min(12, message_id.len());
-    nonce_bytes[0..copy_len].copy_from_slice(&message_id[0..copy_len]);
-    let nonce = chacha20poly1305::Nonce::from_slice(&nonce_bytes);
-    
-    let cipher = ChaCha20Poly1305::new(key);
-    cipher.encrypt(nonce, plaintext)
-        .map_err(|e| format!("Encryption failed: {}", e))
-}
-
-/// Deterministic decryption for self-messaging
+/// Deterministic decryption for self-messages written by older versions
+/// (key derived from channel_id, nonce from message_id). New notes use `notes::encrypt_note`.
 pub fn decrypt_self_message(channel_id: &[u8; 32], message_id: &[u8; 32], ciphertext: &[u8]) -> Result<Vec<u8>, String> {
     // Derive encryption key from channel_id (same as encryption)
     let mut hasher = Sha256::new();
@@ -318,6 +585,72 @@ pub fn decrypt_self_message(channel_id: &[u8; 32], message_id: &[u8; 32], cipher
     
     let cipher = ChaCha20Poly1305::new(key);
     cipher.decrypt(nonce, ciphertext)
-        .map_err(|e| format!("Decryption failed: {}", e))
+        .map_err(|e| MeshError::Crypto.raise(format!("Decryption failed: {}", e)))
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    #[test]
+    fn test_session_manager_ratchets_without_peer_secret() {
+        let (storage_a, storage_b) = (Storage::in_memory().unwrap(), Storage::in_memory().unwrap());
+        let (secret_a, secret_b) = (StaticSecret::from([1u8; 32]), StaticSecret::from([2u8; 32]));
+        let (public_a, public_b) = (PublicKey::from(&secret_a).to_bytes(), PublicKey::from(&secret_b).to_bytes());
+        let channel = [9u8; 32];
+
+        let alice = DmSessionManager::new(&storage_a, &secret_a);
+        let bob = DmSessionManager::new(&storage_b, &secret_b);
+        let first = alice.encrypt(channel, public_b, [1u8; 32], b"hello", 100).unwrap();
+        let second = alice.encrypt(channel, public_b, [2u8; 32], b"again", 101).unwrap();
+        // The ratchet is reused: same handshake, next message key
+        assert_eq!(first[..1 + IK_HANDSHAKE_LEN], second[..1 + IK_HANDSHAKE_LEN]);
+        assert!(is_ratchet_message(&first));
+
+        // Out of order, with only Bob's own secret
+        let opened = bob.decrypt(channel, Some(public_a), [2u8; 32], &second, 102).unwrap();
+        assert_eq!(opened, OpenedDm { plaintext: b"again".to_vec(), outgoing: false });
+        assert_eq!(bob.decrypt(channel, Some(public_a), [1u8; 32], &first, 102).unwrap().plaintext, b"hello");
+        assert!(alice.decrypt(channel, Some(public_b), [1u8; 32], &first, 103).unwrap().outgoing);
+
+        // Bob answers on Alice's ratchet
+        let reply = bob.encrypt(channel, public_a, [3u8; 32], b"reply", 104).unwrap();
+        assert_eq!(reply[..1 + IK_HANDSHAKE_LEN], first[..1 + IK_HANDSHAKE_LEN]);
+        let opened = alice.decrypt(channel, Some(public_b), [3u8; 32], &reply, 105).unwrap();
+        assert_eq!(opened, OpenedDm { plaintext: b"reply".to_vec(), outgoing: false });
+
+        // Not accepted from another key or on another channel
+        assert!(alice.decrypt([8u8; 32], Some(public_b), [4u8; 32], &first, 103).is_err());
+        let fresh = Storage::in_memory().unwrap();
+        assert!(DmSessionManager::new(&fresh, &secret_b).decrypt(channel, Some(public_b), [1u8; 32], &first, 103).is_err());
+    }
+
+    #[test]
+    fn test_ratchet_of_a_replaced_key_is_rejected() {
+        let (storage_a, storage_b) = (Storage::in_memory().unwrap(), Storage::in_memory().unwrap());
+        let (secret_a, secret_b) = (StaticSecret::from([1u8; 32]), StaticSecret::from([2u8; 32]));
+        let (public_a, public_b) = (PublicKey::from(&secret_a).to_bytes(), PublicKey::from(&secret_b).to_bytes());
+        let channel = [9u8; 32];
+
+        let alice = DmSessionManager::new(&storage_a, &secret_a);
+        let bob = DmSessionManager::new(&storage_b, &secret_b);
+        let first = alice.encrypt(channel, public_b, [1u8; 32], b"hello", 100).unwrap();
+        let second = alice.encrypt(channel, public_b, [2u8; 32], b"again", 101).unwrap();
+        bob.decrypt(channel, Some(public_a), [1u8; 32], &first, 102).unwrap();
+
+        // Alice's X25519 key changes: the ratchet of her old key is refused,
+        // while what was already read stays readable
+        let new_secret_a = StaticSecret::from([3u8; 32]);
+        let new_public_a = PublicKey::from(&new_secret_a).to_bytes();
+        let refused = bob.decrypt(channel, Some(new_public_a), [2u8; 32], &second, 103).unwrap_err();
+        assert_eq!(crate::error::code_of(&refused), MeshError::Crypto);
+        assert_eq!(bob.decrypt(channel, Some(new_public_a), [1u8; 32], &first, 103).unwrap().plaintext, b"hello");
+
+        // A ratchet started with her new key is accepted
+        let fresh = Storage::in_memory().unwrap();
+        let third = DmSessionManager::new(&fresh, &new_secret_a).encrypt(channel, public_b, [4u8; 32], b"new key", 104).unwrap();
+        assert_eq!(bob.decrypt(channel, Some(new_public_a), [4u8; 32], &third, 105).unwrap().plaintext, b"new key");
+    }
+}
@@ -0,0 +1,68 @@
+//! Extra entropy for identity creation
+//!
+//! Users who do not trust a cheap device's RNG can have the app contribute
+//! extra entropy (sensor noise, tap timings) before the identity is created.
+//! Contributions are hashed into a pool; `Identity::generate` draws its keys
+//! from a ChaCha RNG seeded with SHA256(OS randomness || pool), the OS
+//! randomness coming from `rng` (seeded in simulations). OS randomness
+//! is always part of the seed, so contributed bytes can only add entropy,
+//! never replace it. The pool is reset once it has been used.
+
+use once_cell::sync::Lazy;
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+
+struct Pool {
+    hasher: Sha256,
+    /// Bytes contributed since the pool was last used
+    bytes: u64,
+}
+
+static POOL: Lazy<Mutex<Pool>> = Lazy::new(|| {
+    Mutex::new(Pool {
+        hasher: Sha256::new(),
+        bytes: 0,
+    })
+});
+
+/// Mix `bytes` into the pool. Returns the bytes contributed so far.
+pub fn add(bytes: &[u8]) -> u64 {
+    let mut pool = POOL.lock().unwrap();
+    pool.hasher.update((bytes.len() as u64).to_be_bytes());
+    pool.hasher.update(bytes);
+    pool.bytes += bytes.len() as u64;
+    pool.bytes
+}
+
+/// An RNG seeded from OS randomness and the pool, which is then reset.
+pub fn take_rng() -> StdRng {
+    let mut os = [0u8; 32];
+    crate::rng::rng().fill_bytes(&mut os);
+
+    let mut pool = POOL.lock().unwrap();
+    let contributed = std::mem::replace(&mut pool.hasher, Sha256::new()).finalize();
+    pool.bytes = 0;
+
+    let mut seed = Sha256::new();
+    seed.update(b"meshapp-identity-seed");
+    seed.update(os);
+    seed.update(contributed);
+    StdRng::from_seed(seed.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contributions_are_counted_and_seeds_stay_fresh() {
+        // Other tests may take the pool concurrently, so only a lower bound holds
+        assert!(add(&[7u8; 16]) >= 16);
+        let mut a = take_rng();
+        let mut b = take_rng();
+        // Fresh OS randomness in every seed
+        assert_ne!(a.next_u64(), b.next_u64());
+    }
+}
@@ -0,0 +1,152 @@
+//! FFI error codes
+//!
+//! A failing FFI call returns a negative `MeshError` code (functions
+//! returning i32/i64) or null (functions returning strings), and records the
+//! error for `last_error_code` / `last_error_message`. The record is per
+//! thread and stays until the next failure on that thread, like errno, so
+//! read it right after the call that failed.
+//!
+//! Codes are stable: new ones may be added, existing ones never change
+//! meaning. -1 stays the unclassified failure, so callers that only check for
+//! -1 keep working for it.
+//!
+//! The last `MAX_RECENT_ERRORS` failures of all threads are also kept for
+//! diagnostics bundles (see `diagnostics`).
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum MeshError {
+    /// Unclassified failure
+    Internal = -1,
+    /// init_identity / init_friends / init_storage / init_router not called yet
+    NotInitialized = -2,
+    /// A null pointer, non-UTF-8 string, bad hex or malformed JSON argument
+    InvalidArgument = -3,
+    /// The friend, message, channel or other record does not exist
+    NotFound = -4,
+    /// The database failed
+    Storage = -5,
+    /// The database is locked by another connection; retry later
+    StorageBusy = -6,
+    /// Encryption, decryption or a signature failed
+    Crypto = -7,
+    /// The request is valid but not allowed in the current state
+    Rejected = -8,
+    /// The identity or friends file failed its integrity check (see `integrity`)
+    Corrupted = -9,
+    /// Another process holds the data directory, or this one is attached read-only (see `instance_lock`)
+    InUse = -10,
+}
+
+impl MeshError {
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MeshError::Internal => "internal",
+            MeshError::NotInitialized => "not_initialized",
+            MeshError::InvalidArgument => "invalid_argument",
+            MeshError::NotFound => "not_found",
+            MeshError::Storage => "storage",
+            MeshError::StorageBusy => "storage_busy",
+            MeshError::Crypto => "crypto",
+            MeshError::Rejected => "rejected",
+            MeshError::Corrupted => "corrupted",
+            MeshError::InUse => "in_use",
+        }
+    }
+
+    /// Raise an error with this code where it happens. The core modules
+    /// report errors as strings, so the code is kept beside the message on
+    /// this thread for the FFI call that fails with it (see `code_of`).
+    pub fn raise(self, message: impl Into<String>) -> String {
+        let message = message.into();
+        RAISED.with(|raised| *raised.borrow_mut() = Some((self, message.clone())));
+        message
+    }
+}
+
+/// Failures kept for diagnostics; the oldest are dropped first.
+const MAX_RECENT_ERRORS: usize = 200;
+
+/// A failure as kept for diagnostics
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RecordedError {
+    pub timestamp: i64,
+    pub code: i32,
+    pub error: &'static str,
+    pub message: String,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<(MeshError, String)>> = const { RefCell::new(None) };
+    /// The last error raised on this thread (see `MeshError::raise`)
+    static RAISED: RefCell<Option<(MeshError, String)>> = const { RefCell::new(None) };
+}
+
+static RECENT: Lazy<Mutex<VecDeque<RecordedError>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+
+/// Record a failure for this thread. Returns the error for chaining.
+pub fn set(error: MeshError, message: &str) -> MeshError {
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some((error, message.to_string())));
+    let mut recent = RECENT.lock().unwrap();
+    if recent.len() >= MAX_RECENT_ERRORS {
+        recent.pop_front();
+    }
+    recent.push_back(RecordedError {
+        timestamp: crate::now_ts(),
+        code: error.code(),
+        error: error.name(),
+        message: message.to_string(),
+    });
+    error
+}
+
+/// Code of a failure message: the code its error was raised with on this
+/// thread, if the message carries that error (as is or with context added
+/// by the callers), else Internal.
+pub fn code_of(message: &str) -> MeshError {
+    RAISED.with(|raised| match raised.borrow_mut().take() {
+        Some((error, raised)) if message.contains(&raised) => error,
+        _ => MeshError::Internal,
+    })
+}
+
+/// The last failure recorded on this thread.
+pub fn last() -> Option<(MeshError, String)> {
+    LAST_ERROR.with(|last| last.borrow().clone())
+}
+
+/// Recent failures of all threads, oldest first.
+pub fn recent() -> Vec<RecordedError> {
+    RECENT.lock().unwrap().iter().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_raise_and_record() {
+        let message = MeshError::NotFound.raise("Unknown message");
+        assert_eq!(code_of(&format!("Get message failed: {}", message)), MeshError::NotFound);
+        // Taken by the failure that carried it
+        assert_eq!(code_of(&message), MeshError::Internal);
+        MeshError::StorageBusy.raise("Failed to store message: database is locked");
+        assert_eq!(code_of("Unknown message"), MeshError::Internal);
+
+        let error = set(MeshError::NotFound, "Unknown message");
+        assert_eq!(error.code(), -4);
+        assert_eq!(last(), Some((MeshError::NotFound, "Unknown message".to_string())));
+        std::thread::spawn(|| assert_eq!(last(), None)).join().unwrap();
+        assert!(recent().iter().any(|e| e.code == -4 && e.message == "Unknown message"));
+    }
+}
@@ -0,0 +1,120 @@
+//! Event queue
+//!
+//! Events generated inside the core (startup recovery reports, new messages,
+//! receipts, friend changes, router activity...) are queued here until the
+//! app drains them with `poll_events`.
+//!
+//! Instead of polling, the app can register a callback (`set_callback`):
+//! each event is then handed to it as it is emitted, as a JSON
+//! { kind, timestamp, payload } C string the callback owns and frees with
+//! `free_string`. Events queued before it was registered are handed over
+//! first. The callback runs on the emitting thread, often while the core
+//! holds its locks, so it must return quickly and never call back into the
+//! core; Dart's `NativeCallable.listener` does both by posting to the isolate.
+//!
+//! Daemons can also have a few events posted to local webhooks (see `webhooks`).
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::sync::Mutex;
+
+/// Maximum number of undrained events; the oldest are dropped first.
+const MAX_QUEUED_EVENTS: usize = 1024;
+
+/// A single core event as delivered to the app.
+#[derive(Serialize, Clone, Debug)]
+pub struct Event {
+    pub kind: String,
+    pub timestamp: i64,
+    pub payload: serde_json::Value,
+}
+
+/// Receives each event as a JSON C string it must free with `free_string`
+pub type EventCallback = extern "C" fn(*mut c_char);
+
+static QUEUE: Lazy<Mutex<VecDeque<Event>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+
+static CALLBACK: Lazy<Mutex<Option<EventCallback>>> = Lazy::new(|| Mutex::new(None));
+
+/// Hand an event to the app: to the callback if one is registered, else to the queue.
+pub fn emit(kind: &str, payload: serde_json::Value) {
+    let event = Event {
+        kind: kind.to_string(),
+        timestamp: crate::now_ts(),
+        payload,
+    };
+    #[cfg(feature = "async")]
+    crate::webhooks::offer(&event);
+    // Held until the event is delivered or queued, so a callback registered
+    // meanwhile cannot miss it (see `set_callback`)
+    let callback = CALLBACK.lock().unwrap();
+    match *callback {
+        Some(callback) => deliver(callback, &event),
+        None => {
+            let mut queue = QUEUE.lock().unwrap();
+            if queue.len() >= MAX_QUEUED_EVENTS {
+                queue.pop_front();
+            }
+            queue.push_back(event);
+        }
+    }
+}
+
+fn deliver(callback: EventCallback, event: &Event) {
+    match serde_json::to_string(event).map_err(|e| e.to_string()).and_then(|s| CString::new(s).map_err(|e| e.to_string())) {
+        Ok(json) => callback(json.into_raw()),
+        Err(e) => eprintln!("Failed to deliver {} event: {}", event.kind, e),
+    }
+}
+
+/// Register the callback events are pushed to (None: queue them for
+/// `drain` again). Queued events are handed to a new callback first.
+pub fn set_callback(callback: Option<EventCallback>) {
+    let mut registered = CALLBACK.lock().unwrap();
+    *registered = callback;
+    if let Some(callback) = callback {
+        // Holding CALLBACK keeps newer events behind the queued ones
+        for event in drain() {
+            deliver(callback, &event);
+        }
+    }
+}
+
+/// Take all queued events in the order they were emitted.
+pub fn drain() -> Vec<Event> {
+    QUEUE.lock().unwrap().drain(..).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static RECEIVED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    extern "C" fn collect(json: *mut c_char) {
+        let json = unsafe { CString::from_raw(json) };
+        RECEIVED.lock().unwrap().push(json.into_string().unwrap());
+    }
+
+    #[test]
+    fn test_callback_gets_queued_then_new_events() {
+        emit("test_queued", serde_json::json!({ "n": 1 }));
+        set_callback(Some(collect));
+        emit("test_pushed", serde_json::json!({ "n": 2 }));
+        set_callback(None);
+        emit("test_after", serde_json::json!({ "n": 3 }));
+
+        let kinds: Vec<String> = RECEIVED
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|json| serde_json::from_str::<serde_json::Value>(json).unwrap()["kind"].as_str().unwrap().to_string())
+            .filter(|kind| kind.starts_with("test_"))
+            .collect();
+        assert_eq!(kinds, ["test_queued", "test_pushed"]);
+        assert!(drain().iter().any(|e| e.kind == "test_after"));
+    }
+}
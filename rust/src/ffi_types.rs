@@ -0,0 +1,1026 @@
+//! FFI payload types
+//!
+//! Typed versions of the JSON objects the FFI returns to the app, so each
+//! shape is defined once instead of in ad-hoc `json!` maps, plus the JSON
+//! Schema of those shapes (`schema`, exposed as `get_schema`) for the Dart
+//! side to validate against.
+//!
+//! `SCHEMA_VERSION` is bumped whenever a shape changes incompatibly
+//! (a field removed, renamed or retyped); adding optional fields does not
+//! bump it. Ids, keys and binary data are lowercase hex strings.
+
+use crate::crypto_backends::CryptoBackends;
+use crate::error::MeshError;
+use crate::forward::{self, ForwardedFrom};
+use crate::friends::Friend;
+use crate::group_delivery::{DeliveryStatus, Retransmit};
+use crate::groups;
+use crate::link_preview::{self, LinkPreview};
+use crate::peer_capabilities::CachedCapabilities;
+use crate::priority::Priority;
+use crate::pseudonyms::Pseudonym;
+use crate::receipts::ReceiptStatus;
+use crate::storage::{ChannelStatsRow, MessageReceiptRow, MessageRow, PeerRow, QuarantinedTimestampRow, StarredRow, MESSAGE_KIND_PENDING, MESSAGE_KIND_SYSTEM};
+use crate::system_messages::{self, SystemEvent};
+use crate::transport::Packet;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// Version of the payload shapes described by `schema`
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// `kind` of a message: written by a user, a core status event, or a
+/// placeholder for a message still on its way
+fn message_kind(kind: u8) -> &'static str {
+    match kind {
+        MESSAGE_KIND_SYSTEM => "system",
+        MESSAGE_KIND_PENDING => "pending",
+        _ => "user",
+    }
+}
+
+/// A friend (`get_all_friends`)
+#[derive(Serialize, Debug)]
+pub struct FriendInfo {
+    pub user_id: String,
+    pub ed25519_public: String,
+    pub nickname: String,
+    /// Custom display name if set, else the nickname
+    pub display_name: String,
+    pub notes: String,
+    pub tags: Vec<String>,
+    /// Noise static key; null until exchanged (see `set_friend_x25519_key`)
+    pub x25519_public: Option<String>,
+    /// Whether the user confirmed the key with a safety number
+    pub verified: bool,
+    pub verified_at: Option<i64>,
+}
+
+impl From<&Friend> for FriendInfo {
+    fn from(f: &Friend) -> Self {
+        Self {
+            user_id: hex::encode(f.user_id),
+            ed25519_public: hex::encode(f.ed25519_public),
+            nickname: f.nickname.clone(),
+            display_name: f.custom_display_name.clone().unwrap_or_else(|| f.nickname.clone()),
+            notes: f.notes.clone(),
+            tags: f.tags.clone(),
+            x25519_public: f.x25519_public.map(hex::encode),
+            verified: f.verified_at.is_some(),
+            verified_at: f.verified_at,
+        }
+    }
+}
+
+/// A friend's safety number to compare in person (`get_safety_number`)
+#[derive(Serialize, Debug)]
+pub struct SafetyNumber {
+    pub user_id: String,
+    /// Digits in groups, as shown to the user
+    pub safety_number: String,
+    pub verified: bool,
+    pub verified_at: Option<i64>,
+}
+
+/// Our public identity for QR export (`export_own_identity`)
+#[derive(Serialize, Debug)]
+pub struct IdentityExport {
+    pub user_id: String,
+    pub ed25519_public: String,
+    pub x25519_public: String,
+}
+
+/// A stored message as kept on disk (`get_messages`)
+#[derive(Serialize, Debug)]
+pub struct StoredMessage {
+    pub message_id: String,
+    pub channel_id: String,
+    pub kind: &'static str,
+    /// User messages only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ciphertext: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u8>,
+    /// System messages only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<SystemEvent>,
+    pub timestamp: i64,
+    /// Seconds until retention deletes the message (absent if kept forever)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_in: Option<i64>,
+    /// "urgent", "normal" or "background", as the sender marked it
+    pub priority: &'static str,
+    /// Signed geo messages only: SHA-256 of the sender's channel pseudonym key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender_pseudonym: Option<String>,
+    /// Signed geo messages only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+impl From<MessageRow> for StoredMessage {
+    fn from(r: MessageRow) -> Self {
+        let is_system = r.kind == MESSAGE_KIND_SYSTEM;
+        let is_user = !is_system && r.kind != MESSAGE_KIND_PENDING;
+        Self {
+            message_id: hex::encode(r.message_id),
+            channel_id: hex::encode(r.channel_id),
+            kind: message_kind(r.kind),
+            system: if is_system { system_messages::parse(&r.ciphertext).ok() } else { None },
+            ciphertext: if is_user { Some(hex::encode(&r.ciphertext)) } else { None },
+            ttl: if is_user { Some(r.ttl) } else { None },
+            timestamp: r.timestamp,
+            expires_in: None,
+            priority: Priority::from_u8(r.priority).name(),
+            sender_pseudonym: None,
+            text: None,
+        }
+    }
+}
+
+/// What was sent (`send_message`, `send_geo_message`)
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SendResult {
+    pub message_id: String,
+    pub channel_id: String,
+    pub channel_type: String,
+    /// The pseudonym that signed a geo message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pseudonym: Option<Pseudonym>,
+    /// Whether the message is routed (notes are not)
+    pub routed: bool,
+}
+
+/// Result of one operation of `run_batch`: the id it created, if any
+/// (`user_id` for add_friend, `channel_id` for join_geo_channel,
+/// `message_id` for send_dm; empty for the others)
+#[derive(Serialize, Debug, Default)]
+pub struct BatchOpResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+}
+
+/// A public message sent under our pseudonym (`omp_send_pseudonymous_message`)
+#[cfg(feature = "open-profile")]
+#[derive(Serialize, Debug)]
+pub struct PseudonymFrame {
+    pub packet_id: String,
+    /// The open mesh profile frame, for bridges and third-party transports
+    pub frame: String,
+    pub pseudonym: Pseudonym,
+}
+
+/// Data signed under our pseudonym on a channel (`sign_as_pseudonym`)
+#[derive(Serialize, Debug)]
+pub struct PseudonymSignature {
+    pub ed25519_public: String,
+    pub signature: String,
+}
+
+/// A message whose claimed time was too far in the future (`get_quarantined_timestamps`)
+#[derive(Serialize, Debug)]
+pub struct QuarantinedMessage {
+    pub message_id: String,
+    pub channel_id: String,
+    /// Time the sender claimed
+    pub claimed: i64,
+    /// Time the message was stored with instead
+    pub stored: i64,
+    pub quarantined_at: i64,
+}
+
+impl From<&QuarantinedTimestampRow> for QuarantinedMessage {
+    fn from(row: &QuarantinedTimestampRow) -> Self {
+        Self {
+            message_id: hex::encode(row.message_id),
+            channel_id: hex::encode(row.channel_id),
+            claimed: row.claimed,
+            stored: row.stored,
+            quarantined_at: row.quarantined_at,
+        }
+    }
+}
+
+/// Outcome for one recipient of `send_dm_to_many`
+#[derive(Serialize, Debug)]
+pub struct BulkSendResult {
+    pub user_id: String,
+    pub message_id: Option<String>,
+    pub error: Option<String>,
+}
+
+/// A moderation action sent to a group (`moderate_group`)
+#[derive(Serialize, Debug)]
+pub struct ModerationResult {
+    pub message_id: String,
+    /// Invites carrying the new key, when a removal rotated it
+    pub rekey: Vec<BulkSendResult>,
+}
+
+/// Custody receipts of one of our group messages (`get_group_delivery_status`)
+#[derive(Serialize, Debug)]
+pub struct GroupDeliveryStatus {
+    pub message_id: String,
+    /// Members who confirmed holding the message
+    pub acked: Vec<String>,
+    pub missing: Vec<String>,
+}
+
+impl GroupDeliveryStatus {
+    pub fn new(message_id: [u8; 32], status: &DeliveryStatus) -> Self {
+        Self {
+            message_id: hex::encode(message_id),
+            acked: status.acked.iter().map(hex::encode).collect(),
+            missing: status.missing.iter().map(hex::encode).collect(),
+        }
+    }
+}
+
+/// A group message to hand to the members still missing it (`get_due_group_retransmits`)
+#[derive(Serialize, Debug)]
+pub struct GroupRetransmit {
+    pub message_id: String,
+    pub channel_id: String,
+    pub missing: Vec<String>,
+    pub packet: WirePacket,
+}
+
+impl From<&Retransmit> for GroupRetransmit {
+    fn from(r: &Retransmit) -> Self {
+        Self {
+            message_id: hex::encode(r.message_id),
+            channel_id: hex::encode(r.channel_id),
+            missing: r.missing.iter().map(hex::encode).collect(),
+            packet: WirePacket::from(&r.packet),
+        }
+    }
+}
+
+/// A decrypted DM or note in conversation history (`get_dm_messages`)
+#[derive(Serialize, Debug)]
+pub struct DmMessage {
+    pub message_id: String,
+    pub kind: &'static str,
+    /// User messages only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plaintext: Option<String>,
+    /// System messages only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<SystemEvent>,
+    /// Original author of a forwarded message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forwarded: Option<ForwardedFrom>,
+    /// Attachment ids carried by a forwarded message
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<String>,
+    pub timestamp: i64,
+    pub is_sent: bool,
+    /// Seconds until retention deletes the message (absent if kept forever)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_in: Option<i64>,
+    /// Sent messages: "delivered" | "read" once the other side reported it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<&'static str>,
+    /// Sent messages: how far it got (see `send_status`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub send_state: Option<&'static str>,
+    /// Group messages: the member who says they wrote it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender_user_id: Option<String>,
+    /// A DM inviting us to a group, instead of `plaintext` (join with `accept_group_invite`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_invite: Option<GroupInviteInfo>,
+    /// Link previews the sender made (see `link_preview`)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub link_previews: Vec<LinkPreview>,
+    /// "urgent" or "background" if the sender marked it so (see `priority`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<&'static str>,
+}
+
+/// DM messages stored since a cursor (`get_dm_messages_since`)
+#[derive(Serialize, Debug)]
+pub struct DmMessagesSince {
+    pub messages: Vec<DmMessage>,
+    /// Pass as after_seq next time
+    pub cursor: i64,
+}
+
+/// A message priority worth showing (anything but normal).
+pub fn shown_priority(priority: u8) -> Option<&'static str> {
+    match Priority::from_u8(priority) {
+        Priority::Normal => None,
+        other => Some(other.name()),
+    }
+}
+
+/// The group a DM invites us to
+#[derive(Serialize, Debug)]
+pub struct GroupInviteInfo {
+    pub channel_id: String,
+    pub name: Option<String>,
+    pub members: Vec<String>,
+}
+
+impl DmMessage {
+    /// A user message; forward envelopes are unwrapped into `forwarded` and
+    /// group invites into `group_invite`.
+    pub fn user(message_id: &[u8; 32], plaintext: String, timestamp: i64, is_sent: bool) -> Self {
+        if let Some(invite) = groups::parse_invite(&plaintext) {
+            return Self {
+                plaintext: None,
+                group_invite: Some(GroupInviteInfo {
+                    channel_id: invite.invite.channel_id,
+                    name: invite.invite.name,
+                    members: invite.members,
+                }),
+                ..Self::user(message_id, String::new(), timestamp, is_sent)
+            };
+        }
+        let (plaintext, forwarded, attachments) = match forward::parse(&plaintext) {
+            Some(env) => (env.text, Some(env.forwarded_from), env.attachments),
+            None => (plaintext, None, Vec::new()),
+        };
+        let (plaintext, link_previews) = link_preview::parse(&plaintext).unwrap_or((plaintext, Vec::new()));
+        Self {
+            message_id: hex::encode(message_id),
+            kind: message_kind(0),
+            plaintext: Some(plaintext),
+            system: None,
+            forwarded,
+            attachments,
+            timestamp,
+            is_sent,
+            expires_in: None,
+            receipt: None,
+            send_state: None,
+            sender_user_id: None,
+            group_invite: None,
+            link_previews,
+            priority: None,
+        }
+    }
+
+    /// A placeholder for a message known to be on its way (see `message_futures`).
+    pub fn pending(message_id: &[u8; 32], timestamp: i64) -> Self {
+        Self {
+            message_id: hex::encode(message_id),
+            kind: message_kind(MESSAGE_KIND_PENDING),
+            plaintext: None,
+            system: None,
+            forwarded: None,
+            attachments: Vec::new(),
+            timestamp,
+            is_sent: false,
+            expires_in: None,
+            receipt: None,
+            send_state: None,
+            sender_user_id: None,
+            group_invite: None,
+            link_previews: Vec::new(),
+            priority: None,
+        }
+    }
+
+    pub fn system(message_id: &[u8; 32], event: SystemEvent, timestamp: i64) -> Self {
+        Self {
+            message_id: hex::encode(message_id),
+            kind: message_kind(MESSAGE_KIND_SYSTEM),
+            plaintext: None,
+            system: Some(event),
+            forwarded: None,
+            attachments: Vec::new(),
+            timestamp,
+            is_sent: false,
+            expires_in: None,
+            receipt: None,
+            send_state: None,
+            sender_user_id: None,
+            group_invite: None,
+            link_previews: Vec::new(),
+            priority: None,
+        }
+    }
+}
+
+/// A starred message (`list_starred`)
+#[derive(Serialize, Debug)]
+pub struct StarredMessage {
+    pub message_id: String,
+    pub channel_id: String,
+    pub author_user_id: Option<String>,
+    pub timestamp: i64,
+    pub starred_at: i64,
+    /// "encrypted" or "snapshot"
+    pub mode: String,
+    /// None if an encrypted star could not be decrypted
+    pub plaintext: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forwarded: Option<ForwardedFrom>,
+}
+
+impl StarredMessage {
+    pub fn new(star: &StarredRow, plaintext: Option<String>) -> Self {
+        let envelope = plaintext.as_deref().and_then(forward::parse);
+        let (plaintext, forwarded) = match envelope {
+            Some(env) => (Some(env.text), Some(env.forwarded_from)),
+            None => (plaintext, None),
+        };
+        Self {
+            message_id: hex::encode(star.message_id),
+            channel_id: hex::encode(star.channel_id),
+            author_user_id: star.author.map(hex::encode),
+            timestamp: star.timestamp,
+            starred_at: star.starred_at,
+            mode: star.mode.clone(),
+            plaintext,
+            forwarded,
+        }
+    }
+}
+
+/// Result of checking a friend's key history (`verify_key_history`)
+#[derive(Serialize, Debug)]
+pub struct KeyHistoryCheck {
+    pub owner_user_id: String,
+    pub epochs: usize,
+    pub current_ed25519_public: String,
+    /// The chain's current key differs from the key we have for the friend
+    pub rotated: bool,
+}
+
+/// An entry of the conversation list (`get_conversations`)
+#[derive(Serialize, Debug)]
+pub struct ConversationInfo {
+    pub channel_id: String,
+    #[serde(rename = "type")]
+    pub channel_type: String,
+    pub name: Option<String>,
+    pub pinned: bool,
+    pub sort_order: Option<i64>,
+    /// App-defined UI state (`set_channel_ui_metadata`)
+    pub ui_metadata: Option<String>,
+    /// Observer mode: stored and shown, never relayed (`set_channel_observe_only`)
+    pub observe_only: bool,
+    /// Friend the DM is with (DM channels only)
+    pub peer_user_id: Option<String>,
+    pub message_count: u64,
+    pub last_message_at: Option<i64>,
+    /// Decrypted start of the newest message
+    pub last_message_preview: Option<String>,
+    /// Messages after the read marker (`mark_channel_read`)
+    pub unread_count: u64,
+}
+
+/// A known peer (`get_peers`)
+#[derive(Serialize, Debug)]
+pub struct PeerInfo {
+    pub peer_id: String,
+    pub last_seen: i64,
+    /// Cached negotiated capabilities (None if never negotiated or expired)
+    pub capabilities: Option<CachedCapabilities>,
+}
+
+impl PeerInfo {
+    pub fn new(peer: &PeerRow, capabilities: Option<CachedCapabilities>) -> Self {
+        Self {
+            peer_id: hex::encode(peer.peer_id),
+            last_seen: peer.last_seen,
+            capabilities,
+        }
+    }
+}
+
+/// One reader's receipt for a message
+#[derive(Serialize, Debug)]
+pub struct ReceiptInfo {
+    pub user_id: String,
+    pub status: &'static str,
+    pub updated_at: i64,
+}
+
+/// Receipts for a message (`get_message_receipts`)
+#[derive(Serialize, Debug)]
+pub struct MessageReceipts {
+    pub message_id: String,
+    /// Furthest status any reader reported, null if none did
+    pub status: Option<&'static str>,
+    pub receipts: Vec<ReceiptInfo>,
+}
+
+impl MessageReceipts {
+    pub fn new(message_id: &[u8; 32], rows: &[MessageReceiptRow]) -> Self {
+        let statuses = || rows.iter().filter_map(|r| ReceiptStatus::from_u8(r.status));
+        Self {
+            message_id: hex::encode(message_id),
+            status: statuses().max().map(ReceiptStatus::name),
+            receipts: rows
+                .iter()
+                .filter_map(|r| {
+                    ReceiptStatus::from_u8(r.status).map(|status| ReceiptInfo {
+                        user_id: hex::encode(r.user_id),
+                        status: status.name(),
+                        updated_at: r.updated_at,
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Activity of a channel (`get_channel_stats`)
+#[derive(Serialize, Debug)]
+pub struct ChannelStats {
+    pub channel_id: String,
+    pub message_count: u64,
+    /// Message counts per UTC day, oldest first (days without messages are left out)
+    pub days: Vec<DayCount>,
+    /// Distinct nodes heard on the channel. Authors of channel messages are
+    /// only inside the ciphertext, so this counts the peers that delivered them.
+    pub distinct_senders: u64,
+    /// Ciphertext plus complete attachment blobs
+    pub bytes_stored: u64,
+    pub message_bytes: u64,
+    pub attachment_bytes: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DayCount {
+    /// UNIX seconds at 00:00 UTC
+    pub day_start: i64,
+    pub messages: u64,
+}
+
+impl ChannelStats {
+    pub fn new(channel_id: [u8; 32], row: ChannelStatsRow) -> Self {
+        Self {
+            channel_id: hex::encode(channel_id),
+            message_count: row.days.iter().map(|d| d.messages).sum(),
+            days: row.days.iter().map(|d| DayCount { day_start: d.day_start, messages: d.messages }).collect(),
+            distinct_senders: row.peers_heard,
+            bytes_stored: row.message_bytes + row.attachment_bytes,
+            message_bytes: row.message_bytes,
+            attachment_bytes: row.attachment_bytes,
+        }
+    }
+}
+
+/// A registered channel (`get_geo_channels`)
+#[derive(Serialize, Debug)]
+pub struct ChannelInfo {
+    pub channel_id: String,
+    #[serde(rename = "type")]
+    pub channel_type: String,
+}
+
+/// A packet as handed to or taken from a transport (`drain_loopback_packets`)
+#[derive(Serialize, Debug)]
+pub struct WirePacket {
+    pub packet_id: String,
+    pub channel_id: String,
+    /// `transport::PacketKind` as a number
+    pub kind: u8,
+    pub ttl: u8,
+    pub payload: String,
+    /// `priority::Priority` as a number
+    pub priority: u8,
+}
+
+impl From<&Packet> for WirePacket {
+    fn from(p: &Packet) -> Self {
+        Self {
+            packet_id: hex::encode(p.packet_id),
+            channel_id: hex::encode(p.channel_id),
+            kind: p.kind as u8,
+            ttl: p.ttl,
+            payload: hex::encode(&p.payload),
+            priority: p.priority as u8,
+        }
+    }
+}
+
+/// An attachment of a message (`get_message_attachments`)
+#[derive(Serialize, Debug)]
+pub struct AttachmentInfo {
+    pub attachment_id: String,
+    pub mime: String,
+    pub size: u64,
+    pub complete: bool,
+    pub thumbnail_id: Option<String>,
+    pub thumbnail_complete: bool,
+}
+
+/// Transport tuning for a battery mode (`get_optimization_config`)
+#[derive(Serialize, Debug)]
+pub struct OptimizationConfigInfo {
+    pub battery_mode: &'static str,
+    pub scan_interval_ms: u64,
+    pub scan_window_ms: u64,
+    pub batch_size: usize,
+    pub batch_age_secs: u64,
+}
+
+/// Build and platform information (`get_core_info`)
+#[derive(Serialize, Debug)]
+pub struct CoreInfo {
+    pub version: &'static str,
+    pub schema_version: u32,
+    pub target_arch: &'static str,
+    pub target_os: &'static str,
+    pub crypto_backends: CryptoBackends,
+}
+
+/// A parsed mesh:// link (`parse_mesh_uri`)
+#[derive(Serialize, Debug)]
+pub struct MeshUriInfo {
+    pub kind: &'static str,
+    pub payload: Value,
+}
+
+/// The last failure on the calling thread (`last_error_message`)
+#[derive(Serialize, Debug)]
+pub struct ErrorInfo {
+    /// `MeshError` code, as returned by failing i32 functions
+    pub code: i32,
+    pub name: &'static str,
+    pub message: String,
+}
+
+impl ErrorInfo {
+    pub fn new(error: MeshError, message: String) -> Self {
+        Self { code: error.code(), name: error.name(), message }
+    }
+}
+
+fn string() -> Value {
+    json!({ "type": "string" })
+}
+
+fn hex_string() -> Value {
+    json!({ "type": "string", "pattern": "^[0-9a-f]*$" })
+}
+
+fn nullable(schema: Value) -> Value {
+    json!({ "anyOf": [schema, { "type": "null" }] })
+}
+
+fn integer() -> Value {
+    json!({ "type": "integer" })
+}
+
+fn boolean() -> Value {
+    json!({ "type": "boolean" })
+}
+
+fn object(properties: Value, required: &[&str]) -> Value {
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    })
+}
+
+/// JSON Schema (draft 2020-12) of every FFI payload shape, under `$defs`.
+pub fn schema() -> Value {
+    let system_event = json!({
+        "type": "object",
+        "properties": {
+            "event": { "enum": ["friend_added", "key_changed", "member_joined", "expiry_changed", "group_metadata_changed", "moderation"] },
+            "user_id": hex_string(),
+            "expiry_secs": nullable(integer()),
+            "name": nullable(string()),
+            "topic": nullable(string()),
+            "action": string(),
+            "target": hex_string(),
+        },
+        "required": ["event"],
+    });
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "$id": format!("meshapp-ffi/v{}", SCHEMA_VERSION),
+        "version": SCHEMA_VERSION,
+        "$defs": {
+            "Friend": object(json!({
+                "user_id": hex_string(),
+                "ed25519_public": hex_string(),
+                "nickname": string(),
+                "display_name": string(),
+                "notes": string(),
+                "tags": { "type": "array", "items": string() },
+                "x25519_public": nullable(hex_string()),
+                "verified": boolean(),
+                "verified_at": nullable(integer()),
+            }), &["user_id", "ed25519_public", "nickname", "display_name", "notes", "tags", "x25519_public", "verified", "verified_at"]),
+            "SafetyNumber": object(json!({
+                "user_id": hex_string(),
+                "safety_number": string(),
+                "verified": boolean(),
+                "verified_at": nullable(integer()),
+            }), &["user_id", "safety_number", "verified", "verified_at"]),
+            "IdentityExport": object(json!({
+                "user_id": hex_string(),
+                "ed25519_public": hex_string(),
+                "x25519_public": hex_string(),
+            }), &["user_id", "ed25519_public", "x25519_public"]),
+            "SystemEvent": system_event,
+            "ForwardedFrom": object(json!({
+                "author_user_id": nullable(hex_string()),
+                "message_id": hex_string(),
+                "channel_id": hex_string(),
+                "timestamp": integer(),
+            }), &["author_user_id", "message_id", "channel_id", "timestamp"]),
+            "StoredMessage": object(json!({
+                "message_id": hex_string(),
+                "channel_id": hex_string(),
+                "kind": { "enum": ["user", "system", "pending"] },
+                "ciphertext": hex_string(),
+                "ttl": integer(),
+                "system": { "$ref": "#/$defs/SystemEvent" },
+                "timestamp": integer(),
+                "expires_in": integer(),
+                "priority": { "enum": ["urgent", "normal", "background"] },
+                "sender_pseudonym": hex_string(),
+                "text": string(),
+            }), &["message_id", "channel_id", "kind", "timestamp", "priority"]),
+            "DmMessage": object(json!({
+                "message_id": hex_string(),
+                "kind": { "enum": ["user", "system", "pending"] },
+                "plaintext": string(),
+                "system": { "$ref": "#/$defs/SystemEvent" },
+                "forwarded": { "$ref": "#/$defs/ForwardedFrom" },
+                "attachments": { "type": "array", "items": hex_string() },
+                "timestamp": integer(),
+                "is_sent": boolean(),
+                "expires_in": integer(),
+                "receipt": { "enum": ["delivered", "read"] },
+                "send_state": { "enum": ["composing", "queued", "routed", "relayed", "delivered", "read", "failed"] },
+                "sender_user_id": hex_string(),
+                "group_invite": { "$ref": "#/$defs/GroupInviteInfo" },
+                "link_previews": { "type": "array", "items": { "$ref": "#/$defs/LinkPreview" } },
+                "priority": { "enum": ["urgent", "background"] },
+            }), &["message_id", "kind", "timestamp", "is_sent"]),
+            "DmMessagesSince": object(json!({
+                "messages": { "type": "array", "items": { "$ref": "#/$defs/DmMessage" } },
+                "cursor": integer(),
+            }), &["messages", "cursor"]),
+            "LinkPreview": object(json!({
+                "url": string(),
+                "title": nullable(string()),
+                "description": nullable(string()),
+                "thumbnail_id": nullable(hex_string()),
+            }), &["url", "title", "description", "thumbnail_id"]),
+            "GroupInviteInfo": object(json!({
+                "channel_id": hex_string(),
+                "name": nullable(string()),
+                "members": { "type": "array", "items": hex_string() },
+            }), &["channel_id", "name", "members"]),
+            "StarredMessage": object(json!({
+                "message_id": hex_string(),
+                "channel_id": hex_string(),
+                "author_user_id": nullable(hex_string()),
+                "timestamp": integer(),
+                "starred_at": integer(),
+                "mode": { "enum": ["encrypted", "snapshot"] },
+                "plaintext": nullable(string()),
+                "forwarded": { "$ref": "#/$defs/ForwardedFrom" },
+            }), &["message_id", "channel_id", "author_user_id", "timestamp", "starred_at", "mode", "plaintext"]),
+            "KeyHistoryCheck": object(json!({
+                "owner_user_id": hex_string(),
+                "epochs": integer(),
+                "current_ed25519_public": hex_string(),
+                "rotated": boolean(),
+            }), &["owner_user_id", "epochs", "current_ed25519_public", "rotated"]),
+            "CoreInfo": object(json!({
+                "version": string(),
+                "schema_version": integer(),
+                "target_arch": string(),
+                "target_os": string(),
+                "crypto_backends": object(json!({
+                    "sha256": string(),
+                    "chacha20": string(),
+                    "poly1305": string(),
+                    "cpu_features": { "type": "array", "items": string() },
+                }), &["sha256", "chacha20", "poly1305", "cpu_features"]),
+            }), &["version", "schema_version", "target_arch", "target_os", "crypto_backends"]),
+            "Pseudonym": object(json!({
+                "channel_id": hex_string(),
+                "generation": integer(),
+                "ed25519_public": hex_string(),
+                "user_id": hex_string(),
+                "created_at": integer(),
+                "retired_at": integer(),
+            }), &["channel_id", "generation", "ed25519_public", "user_id", "created_at"]),
+            "SendResult": object(json!({
+                "message_id": hex_string(),
+                "channel_id": hex_string(),
+                "channel_type": string(),
+                "pseudonym": { "$ref": "#/$defs/Pseudonym" },
+                "routed": boolean(),
+            }), &["message_id", "channel_id", "channel_type", "routed"]),
+            "PseudonymFrame": object(json!({
+                "packet_id": hex_string(),
+                "frame": hex_string(),
+                "pseudonym": { "$ref": "#/$defs/Pseudonym" },
+            }), &["packet_id", "frame", "pseudonym"]),
+            "PseudonymSignature": object(json!({
+                "ed25519_public": hex_string(),
+                "signature": hex_string(),
+            }), &["ed25519_public", "signature"]),
+            "QuarantinedMessage": object(json!({
+                "message_id": hex_string(),
+                "channel_id": hex_string(),
+                "claimed": integer(),
+                "stored": integer(),
+                "quarantined_at": integer(),
+            }), &["message_id", "channel_id", "claimed", "stored", "quarantined_at"]),
+            "BatchOpResult": object(json!({
+                "user_id": hex_string(),
+                "channel_id": hex_string(),
+                "message_id": hex_string(),
+            }), &[]),
+            "BulkSendResult": object(json!({
+                "user_id": string(),
+                "message_id": nullable(hex_string()),
+                "error": nullable(string()),
+            }), &["user_id", "message_id", "error"]),
+            "ModerationResult": object(json!({
+                "message_id": hex_string(),
+                "rekey": { "type": "array", "items": { "$ref": "#/$defs/BulkSendResult" } },
+            }), &["message_id", "rekey"]),
+            "GroupDeliveryStatus": object(json!({
+                "message_id": hex_string(),
+                "acked": { "type": "array", "items": hex_string() },
+                "missing": { "type": "array", "items": hex_string() },
+            }), &["message_id", "acked", "missing"]),
+            "GroupRetransmit": object(json!({
+                "message_id": hex_string(),
+                "channel_id": hex_string(),
+                "missing": { "type": "array", "items": hex_string() },
+                "packet": { "$ref": "#/$defs/Packet" },
+            }), &["message_id", "channel_id", "missing", "packet"]),
+            "Conversation": object(json!({
+                "channel_id": hex_string(),
+                "type": string(),
+                "name": nullable(string()),
+                "pinned": boolean(),
+                "sort_order": nullable(integer()),
+                "ui_metadata": nullable(string()),
+                "observe_only": boolean(),
+                "peer_user_id": nullable(hex_string()),
+                "message_count": integer(),
+                "last_message_at": nullable(integer()),
+            }), &["channel_id", "type", "pinned", "observe_only", "message_count"]),
+            "Channel": object(json!({
+                "channel_id": hex_string(),
+                "type": string(),
+            }), &["channel_id", "type"]),
+            "Packet": object(json!({
+                "packet_id": hex_string(),
+                "channel_id": hex_string(),
+                "kind": integer(),
+                "ttl": integer(),
+                "payload": hex_string(),
+                "priority": integer(),
+            }), &["packet_id", "channel_id", "kind", "ttl", "payload", "priority"]),
+            "Event": object(json!({
+                "kind": string(),
+                "timestamp": integer(),
+                "payload": {},
+            }), &["kind", "timestamp", "payload"]),
+            "Attachment": object(json!({
+                "attachment_id": hex_string(),
+                "mime": string(),
+                "size": integer(),
+                "complete": boolean(),
+                "thumbnail_id": nullable(hex_string()),
+                "thumbnail_complete": boolean(),
+            }), &["attachment_id", "mime", "size", "complete", "thumbnail_complete"]),
+            "OptimizationConfig": object(json!({
+                "battery_mode": { "enum": ["Performance", "Balanced", "PowerSaving"] },
+                "scan_interval_ms": integer(),
+                "scan_window_ms": integer(),
+                "batch_size": integer(),
+                "batch_age_secs": integer(),
+            }), &["battery_mode", "scan_interval_ms", "scan_window_ms", "batch_size", "batch_age_secs"]),
+            "MeshUri": object(json!({
+                "kind": { "enum": ["add-friend", "join"] },
+                "payload": { "type": "object" },
+            }), &["kind", "payload"]),
+            "ErrorInfo": object(json!({
+                "code": integer(),
+                "name": { "enum": ["internal", "not_initialized", "invalid_argument", "not_found", "storage", "storage_busy", "crypto", "rejected", "corrupted", "in_use"] },
+                "message": string(),
+            }), &["code", "name", "message"]),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::PacketKind;
+
+    /// Every field a type serializes must be declared in its schema definition.
+    fn assert_matches(def: &str, value: impl Serialize) {
+        let schema = schema();
+        let properties = &schema["$defs"][def]["properties"];
+        let value = serde_json::to_value(value).unwrap();
+        for key in value.as_object().unwrap().keys() {
+            assert!(properties.get(key).is_some(), "{} is missing property {}", def, key);
+        }
+        for key in schema["$defs"][def]["required"].as_array().unwrap() {
+            assert!(value.get(key.as_str().unwrap()).is_some(), "{} does not serialize {}", def, key);
+        }
+    }
+
+    #[test]
+    fn test_schema_covers_serialized_fields() {
+        let friend = Friend {
+            user_id: [1u8; 32],
+            ed25519_public: [2u8; 32],
+            nickname: "alice".to_string(),
+            notes: String::new(),
+            tags: vec!["hiking".to_string()],
+            custom_display_name: None,
+            x25519_public: Some([7u8; 32]),
+            verified_at: Some(20),
+        };
+        assert_matches("Friend", FriendInfo::from(&friend));
+        let safety = SafetyNumber { user_id: hex::encode([1u8; 32]), safety_number: "12345 67890".to_string(), verified: true, verified_at: Some(20) };
+        assert_matches("SafetyNumber", safety);
+        assert_matches("DmMessage", DmMessage { priority: shown_priority(2), ..DmMessage::user(&[3u8; 32], "hi".to_string(), 10, true) });
+        let preview = LinkPreview { url: "https://a.example/".to_string(), title: None, description: None, thumbnail_id: None };
+        let with_preview = link_preview::wrap("https://a.example/", vec![preview]).unwrap();
+        assert_matches("DmMessage", DmMessage::user(&[3u8; 32], with_preview, 10, false));
+        assert_matches(
+            "DmMessage",
+            DmMessage::system(&[3u8; 32], SystemEvent::MemberJoined { user_id: "ab".to_string() }, 10),
+        );
+        let packet = Packet {
+            packet_id: [4u8; 32],
+            channel_id: [5u8; 32],
+            kind: PacketKind::Message,
+            ttl: 2,
+            payload: vec![1],
+            priority: Priority::Urgent,
+        };
+        assert_matches("Packet", WirePacket::from(&packet));
+        let status = DeliveryStatus { acked: vec![[1u8; 32]], missing: vec![[2u8; 32]] };
+        assert_matches("GroupDeliveryStatus", GroupDeliveryStatus::new([6u8; 32], &status));
+        let retransmit = Retransmit { message_id: [6u8; 32], channel_id: [5u8; 32], missing: vec![[2u8; 32]], packet: packet.clone() };
+        assert_matches("GroupRetransmit", GroupRetransmit::from(&retransmit));
+        assert_matches(
+            "StoredMessage",
+            StoredMessage::from(MessageRow {
+                message_id: [6u8; 32],
+                channel_id: [5u8; 32],
+                ciphertext: vec![1, 2],
+                timestamp: 10,
+                ttl: 3,
+                kind: 0,
+                priority: 0,
+            }),
+        );
+        let geo = MessageRow { message_id: [6u8; 32], channel_id: [5u8; 32], ciphertext: vec![1], timestamp: 10, ttl: 3, kind: 0, priority: 0 };
+        assert_matches(
+            "StoredMessage",
+            StoredMessage { sender_pseudonym: Some(hex::encode([7u8; 32])), text: Some("Road closed".to_string()), ..StoredMessage::from(geo) },
+        );
+        let pseudonym = Pseudonym {
+            channel_id: hex::encode([5u8; 32]),
+            generation: 1,
+            ed25519_public: hex::encode([8u8; 32]),
+            user_id: hex::encode([9u8; 32]),
+            created_at: 10,
+            retired_at: Some(20),
+        };
+        assert_matches("Pseudonym", pseudonym.clone());
+        #[cfg(feature = "open-profile")]
+        assert_matches("PseudonymFrame", PseudonymFrame { packet_id: hex::encode([6u8; 32]), frame: "00".to_string(), pseudonym: pseudonym.clone() });
+        assert_matches("PseudonymSignature", PseudonymSignature { ed25519_public: hex::encode([8u8; 32]), signature: hex::encode([3u8; 64]) });
+        let sent = SendResult {
+            message_id: hex::encode([6u8; 32]),
+            channel_id: hex::encode([5u8; 32]),
+            channel_type: "geo".to_string(),
+            pseudonym: Some(pseudonym),
+            routed: true,
+        };
+        assert_matches("SendResult", sent);
+        assert_matches("BatchOpResult", BatchOpResult { message_id: Some(hex::encode([6u8; 32])), ..Default::default() });
+        let rekey = vec![BulkSendResult { user_id: hex::encode([1u8; 32]), message_id: None, error: Some("Not a friend".to_string()) }];
+        assert_matches("ModerationResult", ModerationResult { message_id: hex::encode([6u8; 32]), rekey });
+        let quarantined = QuarantinedTimestampRow { message_id: [6u8; 32], channel_id: [5u8; 32], claimed: 4_000, stored: 100, quarantined_at: 100 };
+        assert_matches("QuarantinedMessage", QuarantinedMessage::from(&quarantined));
+        assert_matches("ErrorInfo", ErrorInfo::new(MeshError::NotFound, "Not a friend".to_string()));
+        assert_matches("Event", crate::events::Event {
+            kind: "k".to_string(),
+            timestamp: 1,
+            payload: json!({}),
+        });
+    }
+}
@@ -0,0 +1,138 @@
+//! Message forwarding
+//!
+//! A forwarded message is a normal message of the target channel whose
+//! plaintext is a forward envelope: a record-separator marker (0x1E)
+//! followed by JSON holding the text, the attachment ids it references and
+//! who originally wrote it. Forwarding a forward keeps the first author, so
+//! attribution survives any number of hops.
+//!
+//! Group channels with a key store forwards sealed with ChaCha20-Poly1305
+//! under the channel key: version byte, 12-byte nonce, ciphertext, with the
+//! message id as associated data.
+
+use crate::error::MeshError;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+/// Current envelope format version
+pub const FORWARD_VERSION: u8 = 1;
+
+/// First character of a forward envelope plaintext
+const FORWARD_MARKER: char = '\u{1e}';
+
+/// Version byte of channel-key sealed messages
+const SEALED_VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+
+/// Where a forwarded message was first written (hex-encoded ids)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ForwardedFrom {
+    /// None when the author is unknown (e.g. an unsigned group message)
+    pub author_user_id: Option<String>,
+    pub message_id: String,
+    pub channel_id: String,
+    pub timestamp: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ForwardEnvelope {
+    pub version: u8,
+    pub forwarded_from: ForwardedFrom,
+    pub text: String,
+    /// Attachment ids referenced by the message
+    #[serde(default)]
+    pub attachments: Vec<String>,
+}
+
+impl ForwardEnvelope {
+    /// Wrap the plaintext of an existing message for forwarding. If it is
+    /// already a forward, the original attribution is kept.
+    pub fn wrap(plaintext: &str, origin: ForwardedFrom, attachments: Vec<String>) -> Self {
+        match parse(plaintext) {
+            Some(inner) => Self {
+                attachments: if attachments.is_empty() { inner.attachments } else { attachments },
+                ..inner
+            },
+            None => Self {
+                version: FORWARD_VERSION,
+                forwarded_from: origin,
+                text: plaintext.to_string(),
+                attachments,
+            },
+        }
+    }
+
+    /// Plaintext to encrypt for the target channel.
+    pub fn encode(&self) -> Result<String, String> {
+        let json = serde_json::to_string(self).map_err(|e| format!("Failed to serialize forward: {}", e))?;
+        Ok(format!("{}{}", FORWARD_MARKER, json))
+    }
+}
+
+/// Parse a decrypted plaintext as a forward envelope (None for ordinary text).
+pub fn parse(plaintext: &str) -> Option<ForwardEnvelope> {
+    serde_json::from_str(plaintext.strip_prefix(FORWARD_MARKER)?).ok()
+}
+
+/// Seal a message under a protected channel's key.
+pub fn seal_for_channel(key: &[u8; 32], message_id: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    let mut nonce = [0u8; NONCE_LEN];
+    crate::rng::rng().fill_bytes(&mut nonce);
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: message_id })
+        .map_err(|_| MeshError::Crypto.raise("Failed to encrypt channel message"))?;
+
+    let mut out = Vec::with_capacity(1 + NONCE_LEN + ciphertext.len());
+    out.push(SEALED_VERSION);
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Open a message sealed with `seal_for_channel`.
+pub fn open_for_channel(key: &[u8; 32], message_id: &[u8; 32], sealed: &[u8]) -> Result<Vec<u8>, String> {
+    if sealed.len() < 1 + NONCE_LEN || sealed[0] != SEALED_VERSION {
+        return Err(MeshError::Rejected.raise("Not a sealed channel message"));
+    }
+    let (nonce, ciphertext) = sealed[1..].split_at(NONCE_LEN);
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: message_id })
+        .map_err(|_| MeshError::Crypto.raise("Failed to decrypt channel message"))
+}
+
+/// The nonce of a sealed channel message (for `audit` exports).
+#[cfg(feature = "audit")]
+pub fn sealed_nonce(sealed: &[u8]) -> Option<&[u8]> {
+    sealed.get(1..1 + NONCE_LEN).filter(|_| sealed[0] == SEALED_VERSION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forward_of_forward_keeps_original_author() {
+        let origin = ForwardedFrom {
+            author_user_id: Some("aa".repeat(32)),
+            message_id: "01".repeat(32),
+            channel_id: "02".repeat(32),
+            timestamp: 100,
+        };
+        let first = ForwardEnvelope::wrap("hello", origin.clone(), vec!["03".repeat(32)]);
+        let encoded = first.encode().unwrap();
+        assert!(parse("hello").is_none());
+
+        let hop = ForwardedFrom { author_user_id: Some("bb".repeat(32)), timestamp: 200, ..origin.clone() };
+        let second = ForwardEnvelope::wrap(&encoded, hop, Vec::new());
+        assert_eq!(second.forwarded_from, origin);
+        assert_eq!(second.text, "hello");
+        assert_eq!(second.attachments.len(), 1);
+
+        let key = [7u8; 32];
+        let sealed = seal_for_channel(&key, &[1u8; 32], encoded.as_bytes()).unwrap();
+        assert_eq!(open_for_channel(&key, &[1u8; 32], &sealed).unwrap(), encoded.as_bytes());
+        assert!(open_for_channel(&key, &[2u8; 32], &sealed).is_err());
+    }
+}
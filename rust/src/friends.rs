@@ -4,13 +4,31 @@
 //! - user_id: SHA256 of Ed25519 public key
 //! - ed25519_public: Public key for verification
 //! - nickname: Local-only display name
+//! - x25519_public: Noise static key for DM sessions, exchanged during pairing
+//!   (None for friends added before it was, until they share it)
+//! - verified_at: when the user confirmed the friend's key by comparing
+//!   safety numbers (see `safety_number`); None while unverified
+//!
+//! Friends are kept in the database's friends table, so changes to them can
+//! commit together with messages and keys. Versions before it kept them in
+//! friends.json, imported once by `import_legacy_file`.
+//!
+//! Every change emits `friend_changed` { user_id, change }, change being
+//! "added", "removed", "updated" or "verified".
 
+use crate::error::MeshError;
+use crate::events;
+use crate::integrity;
+use crate::storage::Storage;
 use serde::{Serialize, Deserialize};
 use sha2::{Sha256, Digest};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
-use std::io::Write;
+use std::path::{Path, PathBuf};
+
+fn changed(user_id: &[u8; 32], change: &str) {
+    events::emit("friend_changed", serde_json::json!({ "user_id": hex::encode(user_id), "change": change }));
+}
 
 /// Friend data structure
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -24,214 +42,113 @@ pub struct Friend {
     pub tags: Vec<String>, // User-defined tags for organization
     #[serde(default)]
     pub custom_display_name: Option<String>, // Optional custom display name (overrides nickname)
+    #[serde(default)]
+    pub x25519_public: Option<[u8; 32]>, // Noise static key for DM sessions
+    #[serde(default)]
+    pub verified_at: Option<i64>, // When the safety number was confirmed
 }
 
-/// Friend storage (in-memory representation)
+/// Contents of friends.json, where versions before the friends table kept
+/// the list (read once, by `import_legacy_file`)
 #[derive(Serialize, Deserialize, Default)]
 struct FriendsStorage {
     friends: HashMap<String, Friend>, // Keyed by user_id (hex string)
 }
 
 impl FriendsStorage {
-    /// Load friends from storage
+    /// Load friends from the file
     fn load(path: &PathBuf) -> Result<Self, String> {
-        if !path.exists() {
-            return Ok(Self::default());
-        }
-
         let data = fs::read(path)
             .map_err(|e| format!("Failed to read friends file: {}", e))?;
+        integrity::verify(path, &data)?;
 
         serde_json::from_slice(&data)
             .map_err(|e| format!("Failed to parse friends file: {}", e))
     }
 
-    /// Save friends to storage
-    fn save(&self, path: &PathBuf) -> Result<(), String> {
-        // Create parent directory if it doesn't exist
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent)
-                .map_err(|e| format!("Failed to create storage directory: {}", e))?;
-        }
-
-        let data = serde_json::to_vec_pretty(&self)
-            .map_err(|e| format!("Failed to serialize friends: {}", e))?;
-
-        // Write to temporary file first, then rename (atomic operation)
-        let temp_path = path.with_extension("tmp");
-        let mut file = fs::File::create(&temp_path)
-            .map_err(|e| format!("Failed to create friends file: {}", e))?;
-        
-        file.write_all(&data)
-            .map_err(|e| format!("Failed to write friends file: {}", e))?;
-        
-        file.sync_all()
-            .map_err(|e| format!("Failed to sync friends file: {}", e))?;
-        drop(file);
-
-        // Rename temp file to final location (atomic)
-        fs::rename(&temp_path, path)
-            .map_err(|e| format!("Failed to rename friends file: {}", e))?;
-
-        // Set restrictive permissions (Unix-like systems)
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            let mut perms = fs::metadata(path)
-                .map_err(|e| format!("Failed to get file metadata: {}", e))?
-                .permissions();
-            perms.set_mode(0o600); // rw------- only
-            fs::set_permissions(path, perms)
-                .map_err(|e| format!("Failed to set file permissions: {}", e))?;
-        }
-
-        Ok(())
-    }
-
-    /// Check if nickname is already taken (by a different friend)
-    fn is_nickname_taken(&self, nickname: &str, exclude_user_id: Option<&[u8; 32]>) -> bool {
-        for (user_id_hex, friend) in &self.friends {
-            // Skip the friend we're updating (if provided)
-            if let Some(exclude_id) = exclude_user_id {
-                if hex::encode(exclude_id) == *user_id_hex {
-                    continue;
-                }
-            }
-            
-            if friend.nickname.eq_ignore_ascii_case(nickname) {
-                return true;
-            }
-        }
-        false
-    }
-
-    /// Add a friend
-    fn add_friend(&mut self, friend: Friend) -> Result<(), String> {
-        let user_id_hex = hex::encode(friend.user_id);
-        
-        // Verify user_id matches public key
-        let mut hasher = Sha256::new();
-        hasher.update(&friend.ed25519_public);
-        let computed_user_id: [u8; 32] = hasher.finalize().into();
-        
-        if computed_user_id != friend.user_id {
-            return Err("user_id does not match Ed25519 public key".to_string());
-        }
-
-        // Check nickname uniqueness
-        if self.is_nickname_taken(&friend.nickname, None) {
-            return Err(format!("Nickname '{}' is already taken", friend.nickname));
-        }
-
-        self.friends.insert(user_id_hex, friend);
-        Ok(())
-    }
-
-    /// Remove a friend by user_id
-    fn remove_friend(&mut self, user_id: &[u8; 32]) -> bool {
-        let user_id_hex = hex::encode(user_id);
-        self.friends.remove(&user_id_hex).is_some()
-    }
-
     /// Get a friend by user_id
-    #[allow(dead_code)] // Will be used in future phases
+    #[cfg(test)]
     fn get_friend(&self, user_id: &[u8; 32]) -> Option<&Friend> {
-        let user_id_hex = hex::encode(user_id);
-        self.friends.get(&user_id_hex)
+        self.friends.get(&hex::encode(user_id))
     }
+}
 
-    /// Get all friends
-    fn get_all_friends(&self) -> Vec<&Friend> {
-        self.friends.values().collect()
+/// Move the friends of an older version's friends.json in `dir` into the
+/// database, in one transaction, and rename the file to
+/// friends.json.imported (dropping its checksum file, see `integrity`).
+/// Friends already in the database are kept as they are. Friends of files
+/// before version 2 have no X25519 key until they pair again. Returns how
+/// many were imported (0 without a file).
+pub fn import_legacy_file(storage: &Storage, dir: &Path) -> Result<usize, String> {
+    let path = dir.join("friends.json");
+    if !path.exists() {
+        return Ok(0);
     }
-
-    /// Update friend nickname
-    fn update_nickname(&mut self, user_id: &[u8; 32], nickname: String) -> Result<(), String> {
-        // Check nickname uniqueness (excluding current friend)
-        if self.is_nickname_taken(&nickname, Some(user_id)) {
-            return Err(format!("Nickname '{}' is already taken", nickname));
-        }
-        
-        let user_id_hex = hex::encode(user_id);
-        if let Some(friend) = self.friends.get_mut(&user_id_hex) {
-            friend.nickname = nickname;
-            Ok(())
-        } else {
-            Err("Friend not found".to_string())
+    let file = FriendsStorage::load(&path)?;
+    let imported = storage.with_transaction(|s| {
+        let existing: HashSet<[u8; 32]> = s.list_friends()?.into_iter().map(|f| f.user_id).collect();
+        let mut imported = 0;
+        for friend in file.friends.values().filter(|f| !existing.contains(&f.user_id)) {
+            s.put_friend(friend)?;
+            imported += 1;
         }
-    }
-
-    /// Update friend profile (nickname, notes, tags, custom_display_name)
-    fn update_profile(
-        &mut self,
-        user_id: &[u8; 32],
-        nickname: Option<String>,
-        notes: Option<String>,
-        tags: Option<Vec<String>>,
-        custom_display_name: Option<Option<String>>,
-    ) -> Result<(), String> {
-        let user_id_hex = hex::encode(user_id);
-        
-        // Check nickname uniqueness before getting mutable reference
-        if let Some(ref n) = nickname {
-            if self.is_nickname_taken(n, Some(user_id)) {
-                return Err(format!("Nickname '{}' is already taken", n));
-            }
-        }
-        
-        if let Some(friend) = self.friends.get_mut(&user_id_hex) {
-            if let Some(n) = nickname {
-                friend.nickname = n;
-            }
-            if let Some(n) = notes {
-                friend.notes = n;
-            }
-            if let Some(t) = tags {
-                friend.tags = t;
-            }
-            if let Some(cdn) = custom_display_name {
-                friend.custom_display_name = cdn;
-            }
-            Ok(())
-        } else {
-            Err("Friend not found".to_string())
-        }
-    }
+        Ok(imported)
+    })?;
+    integrity::remove_checksums(&path)?;
+    fs::rename(&path, dir.join("friends.json.imported"))
+        .map_err(|e| format!("Failed to rename imported friends file: {}", e))?;
+    Ok(imported)
 }
 
-/// Get the storage path for friends file
-fn get_storage_path() -> Result<PathBuf, String> {
-    let data_dir = dirs::data_local_dir()
-        .ok_or("Failed to get data directory")?;
-    
-    Ok(data_dir.join("meshapp").join("friends.json"))
-}
-
-/// Friend manager (handles loading/saving)
+/// Friend manager: the friends table, cached in memory. Changes go to the
+/// database first, so they can join a caller's transaction.
 pub struct FriendManager {
-    storage: FriendsStorage,
-    storage_path: PathBuf,
+    friends: HashMap<String, Friend>, // Keyed by user_id (hex string)
 }
 
 impl FriendManager {
-    /// Create a new friend manager
-    pub fn new() -> Result<Self, String> {
-        let storage_path = get_storage_path()?;
-        let storage = FriendsStorage::load(&storage_path)?;
-        
-        Ok(Self {
-            storage,
-            storage_path,
-        })
+    /// Load the friend list from the database
+    pub fn load(storage: &Storage) -> Result<Self, String> {
+        let friends = storage
+            .list_friends()?
+            .into_iter()
+            .map(|f| (hex::encode(f.user_id), f))
+            .collect();
+        Ok(Self { friends })
+    }
+
+    /// Check if nickname is already taken (by a different friend)
+    fn nickname_taken(&self, nickname: &str, exclude_user_id: Option<&[u8; 32]>) -> bool {
+        self.friends
+            .values()
+            .filter(|f| Some(&f.user_id) != exclude_user_id)
+            .any(|f| f.nickname.eq_ignore_ascii_case(nickname))
+    }
+
+    /// Store a changed friend and update the cache
+    fn put(&mut self, storage: &Storage, friend: Friend) -> Result<(), String> {
+        storage.put_friend(&friend)?;
+        self.friends.insert(hex::encode(friend.user_id), friend);
+        Ok(())
+    }
+
+    /// A copy of a friend to change
+    fn friend_to_change(&self, user_id: &[u8; 32]) -> Result<Friend, String> {
+        self.get_friend(user_id).cloned().ok_or_else(|| MeshError::NotFound.raise("Friend not found"))
     }
 
     /// Add a friend from public key and nickname
-    pub fn add_friend(&mut self, ed25519_public: [u8; 32], nickname: String) -> Result<[u8; 32], String> {
+    pub fn add_friend(&mut self, storage: &Storage, ed25519_public: [u8; 32], nickname: String) -> Result<[u8; 32], String> {
         // Compute user_id
         let mut hasher = Sha256::new();
-        hasher.update(&ed25519_public);
+        hasher.update(ed25519_public);
         let user_id: [u8; 32] = hasher.finalize().into();
 
+        // Check nickname uniqueness
+        if self.nickname_taken(&nickname, None) {
+            return Err(MeshError::Rejected.raise(format!("Nickname '{}' is already taken", nickname)));
+        }
+
         let friend = Friend {
             user_id,
             ed25519_public,
@@ -239,59 +156,106 @@ impl FriendManager {
             notes: String::new(),
             tags: Vec::new(),
             custom_display_name: None,
+            x25519_public: None,
+            verified_at: None,
         };
 
-        self.storage.add_friend(friend)?;
-        self.storage.save(&self.storage_path)?;
+        self.put(storage, friend)?;
+        changed(&user_id, "added");
         
         Ok(user_id)
     }
 
     /// Remove a friend
-    pub fn remove_friend(&mut self, user_id: &[u8; 32]) -> Result<bool, String> {
-        let removed = self.storage.remove_friend(user_id);
+    pub fn remove_friend(&mut self, storage: &Storage, user_id: &[u8; 32]) -> Result<bool, String> {
+        let removed = storage.delete_friend(*user_id)?;
+        self.friends.remove(&hex::encode(user_id));
         if removed {
-            self.storage.save(&self.storage_path)?;
+            changed(user_id, "removed");
         }
         Ok(removed)
     }
 
     /// Get a friend by user_id
-    #[allow(dead_code)] // Will be used in future phases
     pub fn get_friend(&self, user_id: &[u8; 32]) -> Option<&Friend> {
-        self.storage.get_friend(user_id)
+        self.friends.get(&hex::encode(user_id))
+    }
+
+    /// Whether a friend already uses this nickname (case-insensitive)
+    pub fn is_nickname_taken(&self, nickname: &str) -> bool {
+        self.nickname_taken(nickname, None)
     }
 
     /// Get all friends
     pub fn get_all_friends(&self) -> Vec<&Friend> {
-        self.storage.get_all_friends()
+        self.friends.values().collect()
     }
 
     /// Update friend nickname
-    pub fn update_nickname(&mut self, user_id: &[u8; 32], nickname: String) -> Result<(), String> {
-        self.storage.update_nickname(user_id, nickname)?;
-        self.storage.save(&self.storage_path)?;
-        Ok(())
+    pub fn update_nickname(&mut self, storage: &Storage, user_id: &[u8; 32], nickname: String) -> Result<(), String> {
+        self.update_profile(storage, user_id, Some(nickname), None, None, None)
     }
 
     /// Update friend profile (all customizable fields)
     pub fn update_profile(
         &mut self,
+        storage: &Storage,
         user_id: &[u8; 32],
         nickname: Option<String>,
         notes: Option<String>,
         tags: Option<Vec<String>>,
         custom_display_name: Option<Option<String>>,
     ) -> Result<(), String> {
-        self.storage.update_profile(user_id, nickname, notes, tags, custom_display_name)?;
-        self.storage.save(&self.storage_path)?;
+        // Check nickname uniqueness (excluding current friend)
+        if let Some(ref n) = nickname {
+            if self.nickname_taken(n, Some(user_id)) {
+                return Err(MeshError::Rejected.raise(format!("Nickname '{}' is already taken", n)));
+            }
+        }
+
+        let mut friend = self.friend_to_change(user_id)?;
+        if let Some(n) = nickname {
+            friend.nickname = n;
+        }
+        if let Some(n) = notes {
+            friend.notes = n;
+        }
+        if let Some(t) = tags {
+            friend.tags = t;
+        }
+        if let Some(cdn) = custom_display_name {
+            friend.custom_display_name = cdn;
+        }
+        self.put(storage, friend)?;
+        changed(user_id, "updated");
+        Ok(())
+    }
+
+    /// Set a friend's X25519 public key
+    pub fn set_x25519_public(&mut self, storage: &Storage, user_id: &[u8; 32], x25519_public: [u8; 32]) -> Result<(), String> {
+        let mut friend = self.friend_to_change(user_id)?;
+        if friend.x25519_public == Some(x25519_public) {
+            return Ok(());
+        }
+        friend.x25519_public = Some(x25519_public);
+        self.put(storage, friend)?;
+        changed(user_id, "updated");
+        Ok(())
+    }
+
+    /// Mark a friend's key verified (at `verified_at`) or unverified (None)
+    pub fn set_verified(&mut self, storage: &Storage, user_id: &[u8; 32], verified_at: Option<i64>) -> Result<(), String> {
+        let mut friend = self.friend_to_change(user_id)?;
+        friend.verified_at = verified_at;
+        self.put(storage, friend)?;
+        changed(user_id, "verified");
         Ok(())
     }
 
     /// Get display name for a friend (custom_display_name or nickname)
     #[allow(dead_code)] // Utility function for future FFI use
     pub fn get_display_name(&self, user_id: &[u8; 32]) -> Option<String> {
-        self.storage.get_friend(user_id).map(|f| {
+        self.get_friend(user_id).map(|f| {
             f.custom_display_name.clone()
                 .unwrap_or_else(|| f.nickname.clone())
         })
@@ -303,6 +267,8 @@ impl FriendManager {
 pub struct FriendExport {
     pub user_id: String, // hex
     pub ed25519_public: String, // hex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x25519_public: Option<String>, // hex; absent in exports from older versions
 }
 
 impl From<&Friend> for FriendExport {
@@ -310,25 +276,97 @@ impl From<&Friend> for FriendExport {
         Self {
             user_id: hex::encode(friend.user_id),
             ed25519_public: hex::encode(friend.ed25519_public),
+            x25519_public: friend.x25519_public.map(hex::encode),
         }
     }
 }
 
+/// A friend parsed from exported JSON
+pub struct ParsedFriend {
+    pub user_id_hex: String,
+    pub ed25519_public: [u8; 32],
+    pub x25519_public: Option<[u8; 32]>,
+}
+
 /// Parse friend from JSON string (for QR import)
-pub fn parse_friend_from_json(json: &str) -> Result<(String, [u8; 32]), String> {
+pub fn parse_friend_from_json(json: &str) -> Result<ParsedFriend, String> {
     let export: FriendExport = serde_json::from_str(json)
-        .map_err(|e| format!("Invalid friend data: {}", e))?;
+        .map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid friend data: {}", e)))?;
+
+    let key_bytes = crate::codec::parse_id_hex(&export.ed25519_public, "Ed25519 public key")?;
+    let x25519_public = export
+        .x25519_public
+        .map(|k| crate::codec::parse_id_hex(&k, "X25519 public key"))
+        .transpose()?;
+
+    Ok(ParsedFriend {
+        user_id_hex: export.user_id,
+        ed25519_public: key_bytes,
+        x25519_public,
+    })
+}
 
-    let ed25519_public = hex::decode(&export.ed25519_public)
-        .map_err(|e| format!("Invalid hex encoding: {}", e))?;
 
-    if ed25519_public.len() != 32 {
-        return Err("Ed25519 public key must be 32 bytes".to_string());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_friends_live_in_the_database() {
+        let storage = Storage::in_memory().unwrap();
+        let mut manager = FriendManager::load(&storage).unwrap();
+        let user_id = manager.add_friend(&storage, [5u8; 32], "bo".to_string()).unwrap();
+        manager.set_verified(&storage, &user_id, Some(100)).unwrap();
+        manager.update_profile(&storage, &user_id, None, None, Some(vec!["work".to_string()]), None).unwrap();
+        assert!(manager.is_nickname_taken("BO"));
+        assert!(manager.add_friend(&storage, [6u8; 32], "Bo".to_string()).is_err());
+
+        // A fresh manager reads back what was stored
+        let reloaded = FriendManager::load(&storage).unwrap();
+        let friend = reloaded.get_friend(&user_id).unwrap();
+        assert_eq!((friend.verified_at, friend.tags.clone()), (Some(100), vec!["work".to_string()]));
+        assert!(manager.remove_friend(&storage, &user_id).unwrap());
+        assert!(FriendManager::load(&storage).unwrap().get_all_friends().is_empty());
+
+        // friends.json of an older version is imported once, keeping friends already stored
+        let dir = std::env::temp_dir().join(format!("meshapp-friends-import-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let kept = manager.add_friend(&storage, [7u8; 32], "cy".to_string()).unwrap();
+        let imported: [u8; 32] = Sha256::digest([8u8; 32]).into();
+        let (kept_public, imported_public) = ([7u8; 32], [8u8; 32]);
+        let file = serde_json::json!({ "version": 2, "friends": {
+            hex::encode(kept): { "user_id": kept, "ed25519_public": kept_public, "nickname": "renamed" },
+            hex::encode(imported): { "user_id": imported, "ed25519_public": imported_public, "nickname": "di" },
+        }});
+        fs::write(dir.join("friends.json"), file.to_string()).unwrap();
+        assert_eq!(import_legacy_file(&storage, &dir).unwrap(), 1);
+        assert!(!dir.join("friends.json").exists() && dir.join("friends.json.imported").exists());
+        assert!(!dir.join("friends.sum").exists());
+        assert_eq!(import_legacy_file(&storage, &dir).unwrap(), 0);
+        let manager = FriendManager::load(&storage).unwrap();
+        assert_eq!(manager.get_friend(&kept).unwrap().nickname, "cy");
+        assert_eq!(manager.get_friend(&imported).unwrap().nickname, "di");
+        let _ = fs::remove_dir_all(&dir);
     }
 
-    let mut key_bytes = [0u8; 32];
-    key_bytes.copy_from_slice(&ed25519_public);
-
-    Ok((export.user_id, key_bytes))
+    #[test]
+    fn test_unversioned_file_loads_without_x25519_keys() {
+        let ed25519_public = [3u8; 32];
+        let user_id: [u8; 32] = Sha256::digest(ed25519_public).into();
+        let legacy = serde_json::json!({
+            "friends": {
+                hex::encode(user_id): { "user_id": user_id, "ed25519_public": ed25519_public, "nickname": "ana" }
+            }
+        });
+        let storage: FriendsStorage = serde_json::from_value(legacy).unwrap();
+        let friend = storage.get_friend(&user_id).unwrap();
+        assert_eq!(friend.x25519_public, None);
+
+        let mut export = FriendExport::from(friend);
+        assert!(serde_json::to_value(&export).unwrap().get("x25519_public").is_none());
+        export.x25519_public = Some(hex::encode([4u8; 32]));
+        let parsed = parse_friend_from_json(&serde_json::to_string(&export).unwrap()).unwrap();
+        assert_eq!((parsed.ed25519_public, parsed.x25519_public), (ed25519_public, Some([4u8; 32])));
+    }
 }
-
@@ -1,9 +1,23 @@
 //! Geohash-based group channels (Phase 7)
 //!
 //! geo_channel_id = SHA256(geohash + topic)
+//!
+//! Geohashes are computed here too, so the app can pass raw GPS coordinates:
+//! `encode` turns a position into a cell of 1 to `MAX_PRECISION` characters,
+//! `decode` gives a cell's bounds and `neighbors` the eight cells around it
+//! (fewer at the poles). `nearby_channels` is the cell a position falls in
+//! and its neighbors, so a user near a cell edge still finds the channels
+//! just across it.
 
+use crate::error::MeshError;
+use serde::Serialize;
 use sha2::{Digest, Sha256};
 
+/// Longest geohash (about 3.7 cm by 1.9 cm cells)
+pub const MAX_PRECISION: usize = 12;
+
+const BASE32: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
 /// Derive a geohash channel id from geohash + topic.
 pub fn derive_geo_channel_id(geohash: &str, topic: &str) -> [u8; 32] {
     let mut hasher = Sha256::new();
@@ -17,5 +31,152 @@ pub fn channel_id_to_hex(id: &[u8; 32]) -> String {
     hex::encode(id)
 }
 
+/// The area a geohash covers
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct GeoCell {
+    pub lat: f64,
+    pub lon: f64,
+    /// Half the cell's height and width, in degrees
+    pub lat_err: f64,
+    pub lon_err: f64,
+}
+
+/// A geo channel near a position (`nearby_channels`)
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct NearbyGeoChannel {
+    pub geohash: String,
+    pub channel_id: String,
+    /// The cell the position is in (else a neighbor)
+    pub center: bool,
+}
 
+/// Whether a geohash is 1 to `MAX_PRECISION` base-32 geohash characters.
+pub fn valid_geohash(geohash: &str) -> bool {
+    !geohash.is_empty() && geohash.len() <= MAX_PRECISION && geohash.bytes().all(|c| BASE32.contains(&c))
+}
 
+/// Geohash of a position with `precision` characters.
+pub fn encode(lat: f64, lon: f64, precision: usize) -> Result<String, String> {
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+        return Err(MeshError::InvalidArgument.raise("Coordinates out of range"));
+    }
+    if !(1..=MAX_PRECISION).contains(&precision) {
+        return Err(MeshError::InvalidArgument.raise(format!("Precision must be 1-{}", MAX_PRECISION)));
+    }
+    let (mut lat_range, mut lon_range) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut geohash = String::with_capacity(precision);
+    let mut even = true;
+    for _ in 0..precision {
+        let mut index = 0usize;
+        for _ in 0..5 {
+            // Bits alternate between longitude and latitude, longitude first
+            let (range, value): (&mut (f64, f64), f64) = if even { (&mut lon_range, lon) } else { (&mut lat_range, lat) };
+            let mid = (range.0 + range.1) / 2.0;
+            index <<= 1;
+            if value >= mid {
+                index |= 1;
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+            even = !even;
+        }
+        geohash.push(BASE32[index] as char);
+    }
+    Ok(geohash)
+}
+
+/// Center and size of a geohash's cell.
+pub fn decode(geohash: &str) -> Result<GeoCell, String> {
+    if !valid_geohash(geohash) {
+        return Err(MeshError::InvalidArgument.raise("Invalid geohash"));
+    }
+    let (mut lat_range, mut lon_range) = ((-90.0f64, 90.0f64), (-180.0f64, 180.0f64));
+    let mut even = true;
+    for c in geohash.bytes() {
+        let index = BASE32.iter().position(|&b| b == c).ok_or_else(|| MeshError::InvalidArgument.raise("Invalid geohash"))?;
+        for bit in (0..5).rev() {
+            let range = if even { &mut lon_range } else { &mut lat_range };
+            let mid = (range.0 + range.1) / 2.0;
+            if index & (1 << bit) != 0 {
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+            even = !even;
+        }
+    }
+    Ok(GeoCell {
+        lat: (lat_range.0 + lat_range.1) / 2.0,
+        lon: (lon_range.0 + lon_range.1) / 2.0,
+        lat_err: (lat_range.1 - lat_range.0) / 2.0,
+        lon_err: (lon_range.1 - lon_range.0) / 2.0,
+    })
+}
+
+/// The cells around a geohash, of the same precision: N, NE, E, SE, S, SW,
+/// W, NW. Longitude wraps around; there is nothing beyond the poles.
+pub fn neighbors(geohash: &str) -> Result<Vec<String>, String> {
+    let cell = decode(geohash)?;
+    let (height, width) = (2.0 * cell.lat_err, 2.0 * cell.lon_err);
+    let mut out = Vec::with_capacity(8);
+    for (dlat, dlon) in [(1.0, 0.0), (1.0, 1.0), (0.0, 1.0), (-1.0, 1.0), (-1.0, 0.0), (-1.0, -1.0), (0.0, -1.0), (1.0, -1.0)] {
+        let lat = cell.lat + dlat * height;
+        if !(-90.0..=90.0).contains(&lat) {
+            continue;
+        }
+        let mut lon = cell.lon + dlon * width;
+        if lon > 180.0 {
+            lon -= 360.0;
+        } else if lon < -180.0 {
+            lon += 360.0;
+        }
+        let neighbor = encode(lat, lon, geohash.len())?;
+        if neighbor != geohash && !out.contains(&neighbor) {
+            out.push(neighbor);
+        }
+    }
+    Ok(out)
+}
+
+/// Channels of `topic` in the cell of a position and the cells around it.
+pub fn nearby_channels(lat: f64, lon: f64, precision: usize, topic: &str) -> Result<Vec<NearbyGeoChannel>, String> {
+    let center = encode(lat, lon, precision)?;
+    let around = neighbors(&center)?;
+    Ok(std::iter::once(center)
+        .chain(around)
+        .enumerate()
+        .map(|(i, geohash)| NearbyGeoChannel {
+            channel_id: hex::encode(derive_geo_channel_id(&geohash, topic)),
+            geohash,
+            center: i == 0,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_geohash_round_trip_and_neighbors() {
+        // Reference values from the original geohash.org implementation
+        assert_eq!(encode(57.64911, 10.40744, 11).unwrap(), "u4pruydqqvj");
+        assert_eq!(encode(-25.382708, -49.265506, 8).unwrap(), "6gkzwgjz");
+        let cell = decode("u4pruydqqvj").unwrap();
+        assert!((cell.lat - 57.64911).abs() <= cell.lat_err && (cell.lon - 10.40744).abs() <= cell.lon_err);
+        assert!(encode(91.0, 0.0, 5).is_err());
+        assert!(encode(0.0, 0.0, 13).is_err());
+        assert!(decode("u4a").is_err());
+
+        assert_eq!(neighbors("u4pru").unwrap(), vec!["u4r2h", "u4r2j", "u4prv", "u4prt", "u4prs", "u4pre", "u4prg", "u4r25"]);
+        // Across the antimeridian, and none beyond the pole
+        assert!(neighbors("8").unwrap().contains(&"x".to_string()));
+        assert_eq!(neighbors("b").unwrap().len(), 5);
+
+        let nearby = nearby_channels(57.64911, 10.40744, 5, "general").unwrap();
+        assert_eq!(nearby.len(), 9);
+        assert!(nearby[0].center && nearby[0].geohash == "u4pru");
+        assert_eq!(nearby[0].channel_id, hex::encode(derive_geo_channel_id("u4pru", "general")));
+    }
+}
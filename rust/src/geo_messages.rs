@@ -0,0 +1,161 @@
+//! Signed geo channel messages
+//!
+//! Geo channels are public, but what is said in them is still attributable:
+//! each message is signed by our pseudonym in that channel (see
+//! `pseudonyms`), an Ed25519 key pair derived from the identity key and the
+//! channel id. Readers see the same stable pseudonym for everything one
+//! person says in a channel, and nothing that links it to their identity or
+//! to their pseudonyms in other channels.
+//!
+//! Payload: JSON { text, sent_at, in_reply_to? } || pseudonym key (32) ||
+//! Ed25519 signature (64) over "meshapp-geo-message" || channel_id ||
+//! everything before it. A reply is signed by the pseudonym that wrote the
+//! message it answers, if that was ours.
+//!
+//! On registered geo channels, signed messages that do not verify are
+//! dropped. Messages in another format (older clients, `send_packet`) are
+//! kept but carry no pseudonym.
+
+use crate::error::MeshError;
+use crate::identity::Identity;
+use crate::priority::Priority;
+use crate::pseudonyms;
+use crate::storage::{PseudonymRow, Storage};
+use crate::transport::{Packet, PacketKind};
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// channels.type of geo channels
+pub const GEO_CHANNEL_TYPE: &str = "geo";
+
+/// TTL of geo messages we send
+pub const GEO_MESSAGE_TTL: u8 = 4;
+
+/// Longest message text, in bytes
+pub const MAX_TEXT_LEN: usize = 2048;
+
+const MESSAGE_CONTEXT: &[u8] = b"meshapp-geo-message";
+const SIGNATURE_LEN: usize = 64;
+
+/// What a message carries
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GeoMessage {
+    pub text: String,
+    pub sent_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<String>,
+}
+
+/// Sign a message for a geo channel under our pseudonym there. Returns the
+/// packet and the pseudonym that signed it.
+pub fn seal(
+    identity: &Identity,
+    storage: &Storage,
+    channel_id: [u8; 32],
+    packet_id: [u8; 32],
+    text: &str,
+    in_reply_to: Option<[u8; 32]>,
+    now: i64,
+) -> Result<(Packet, PseudonymRow), String> {
+    if text.trim().is_empty() {
+        return Err(MeshError::InvalidArgument.raise("Message text must not be empty"));
+    }
+    if text.len() > MAX_TEXT_LEN {
+        return Err(MeshError::InvalidArgument.raise(format!("Message text longer than {} bytes", MAX_TEXT_LEN)));
+    }
+    if storage.get_channel_type(channel_id)?.as_deref() != Some(GEO_CHANNEL_TYPE) {
+        return Err(MeshError::Rejected.raise("Not a registered geo channel"));
+    }
+    let (row, key) = pseudonyms::for_reply(identity, storage, channel_id, in_reply_to, now)?;
+
+    let body = GeoMessage { text: text.to_string(), sent_at: now, in_reply_to: in_reply_to.map(hex::encode) };
+    let mut payload = serde_json::to_vec(&body).map_err(|e| format!("Failed to serialize message: {}", e))?;
+    payload.extend_from_slice(&row.ed25519_public);
+    let signature = key.sign(&[MESSAGE_CONTEXT, &channel_id, &payload].concat());
+    payload.extend_from_slice(&signature.to_bytes());
+    pseudonyms::record_sent(storage, packet_id, &row)?;
+    let packet = Packet {
+        packet_id,
+        channel_id,
+        kind: PacketKind::Message,
+        ttl: GEO_MESSAGE_TTL,
+        payload,
+        priority: Priority::Normal,
+    };
+    Ok((packet, row))
+}
+
+/// Read a signed message: its pseudonym key and body. Ok(None) if the
+/// payload is not in the signed format; Err if it is but does not verify.
+pub fn open(channel_id: [u8; 32], payload: &[u8]) -> Result<Option<([u8; 32], GeoMessage)>, String> {
+    if payload.len() < 32 + SIGNATURE_LEN {
+        return Ok(None);
+    }
+    let body_len = payload.len() - 32 - SIGNATURE_LEN;
+    let Ok(body) = serde_json::from_slice::<GeoMessage>(&payload[..body_len]) else {
+        return Ok(None);
+    };
+    let sender: [u8; 32] = crate::codec::read_array(payload, body_len, "pseudonym key")?;
+    let signature: [u8; 64] = crate::codec::read_array(payload, body_len + 32, "signature")?;
+    VerifyingKey::from_bytes(&sender)
+        .map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid pseudonym key: {}", e)))?
+        .verify(&[MESSAGE_CONTEXT, &channel_id, &payload[..body_len + 32]].concat(), &Signature::from_bytes(&signature))
+        .map_err(|_| MeshError::Crypto.raise("Geo message signature does not verify"))?;
+    Ok(Some((sender, body)))
+}
+
+/// Check an incoming message before it is stored: signed messages on
+/// registered geo channels must verify (Err drops them).
+pub fn check(storage: &Storage, packet: &Packet) -> Result<(), String> {
+    if storage.get_channel_type(packet.channel_id)?.as_deref() != Some(GEO_CHANNEL_TYPE) {
+        return Ok(());
+    }
+    open(packet.channel_id, &packet.payload).map(|_| ())
+}
+
+/// Pseudonym hash (SHA-256 of the key, like a user id) and text of a stored
+/// signed geo message, for `get_messages`.
+pub fn attribution(channel_id: [u8; 32], payload: &[u8]) -> Option<(String, String)> {
+    let (sender, body) = open(channel_id, payload).ok()??;
+    Some((hex::encode(Sha256::digest(sender)), body.text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_geo_messages_are_signed_by_channel_pseudonyms() {
+        let storage = Storage::in_memory().unwrap();
+        let identity = Identity::generate();
+        let (here, there) = (crate::geo::derive_geo_channel_id("u4pru", "general"), crate::geo::derive_geo_channel_id("u4prv", "general"));
+        storage.upsert_channel(here, GEO_CHANNEL_TYPE).unwrap();
+        storage.upsert_channel(there, GEO_CHANNEL_TYPE).unwrap();
+        assert!(seal(&identity, &storage, [9u8; 32], [1u8; 32], "hi", None, 100).is_err());
+
+        // One stable pseudonym per channel, none of them the identity key
+        let (first, pseudonym) = seal(&identity, &storage, here, [1u8; 32], "Road closed at the bridge", None, 100).unwrap();
+        let (second, _) = seal(&identity, &storage, here, [2u8; 32], "Open again", Some([1u8; 32]), 200).unwrap();
+        let (elsewhere, _) = seal(&identity, &storage, there, [3u8; 32], "Hello", None, 100).unwrap();
+        let (sender, text) = attribution(here, &first.payload).unwrap();
+        assert_eq!(text, "Road closed at the bridge");
+        assert_eq!(sender, hex::encode(Sha256::digest(pseudonym.ed25519_public)));
+        assert_eq!(attribution(here, &second.payload).unwrap().0, sender);
+        assert_ne!(attribution(there, &elsewhere.payload).unwrap().0, sender);
+        assert_ne!(sender, hex::encode(identity.public().user_id));
+
+        // Forged or moved messages are dropped; unsigned ones pass unattributed
+        let mut forged = first.clone();
+        let at = forged.payload.len() - 70;
+        forged.payload[at] ^= 1;
+        assert!(check(&storage, &forged).is_err());
+        let mut moved = elsewhere.clone();
+        moved.channel_id = here;
+        assert!(check(&storage, &moved).is_err());
+        assert!(check(&storage, &first).is_ok());
+        let plain = Packet { payload: b"plain text from an older client".to_vec(), ..first.clone() };
+        assert!(check(&storage, &plain).is_ok());
+        assert_eq!(attribution(here, &plain.payload), None);
+    }
+}
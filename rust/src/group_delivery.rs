@@ -0,0 +1,357 @@
+//! Acknowledged delivery for small groups
+//!
+//! In a protected group with at most `MAX_ACKED_GROUP_SIZE` members (the
+//! member list set with `set_group_members`), every member that receives a
+//! message answers with a `DeliveryReceipt` packet: message_id (32) ||
+//! member user_id (32) || tag (32), where the tag is
+//! SHA256("meshapp-receipt" || channel key || message_id || user_id), so only
+//! holders of the key can confirm custody.
+//!
+//! We track our own group messages until every other member has confirmed.
+//! Instead of re-flooding the group, `due_retransmits` names the members still
+//! missing each message (with backoff) so the platform can hand the packet to
+//! those members' links when they are in range. After `MAX_ATTEMPTS` the
+//! delivery is given up with a `group_delivery_failed` event.
+//!
+//! A receipt also tells members that a message exists: one for a message
+//! we have not received leaves a placeholder (see `message_futures`).
+
+use crate::error::MeshError;
+use crate::events;
+use crate::message_futures;
+use crate::priority::Priority;
+use crate::send_status::{self, SendState};
+use crate::storage::{GroupDeliveryRow, Storage};
+use crate::transport::{Packet, PacketKind};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+/// Largest group that uses custody receipts
+pub const MAX_ACKED_GROUP_SIZE: usize = 16;
+
+/// Retransmissions before a delivery is given up
+pub const MAX_ATTEMPTS: u32 = 5;
+
+/// Wait before the first retransmission; doubles with every attempt
+pub const RETRY_BASE_SECS: i64 = 30;
+
+/// TTL of receipt packets
+pub const RECEIPT_TTL: u8 = 4;
+
+const RECEIPT_LEN: usize = 96;
+
+/// Who has confirmed a tracked message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryStatus {
+    pub acked: Vec<[u8; 32]>,
+    pub missing: Vec<[u8; 32]>,
+}
+
+/// A message to hand to specific members again
+#[derive(Debug, Clone)]
+pub struct Retransmit {
+    pub message_id: [u8; 32],
+    pub channel_id: [u8; 32],
+    pub missing: Vec<[u8; 32]>,
+    pub packet: Packet,
+}
+
+fn receipt_tag(key: &[u8; 32], message_id: &[u8; 32], user_id: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"meshapp-receipt");
+    hasher.update(key);
+    hasher.update(message_id);
+    hasher.update(user_id);
+    hasher.finalize().into()
+}
+
+/// Members of a group using receipts (None for other channels and larger groups).
+fn acked_members(storage: &Storage, channel_id: [u8; 32]) -> Result<Option<Vec<[u8; 32]>>, String> {
+    if storage.get_channel_type(channel_id)?.as_deref() != Some("group") {
+        return Ok(None);
+    }
+    let members = storage.list_group_members(channel_id)?;
+    Ok((!members.is_empty() && members.len() <= MAX_ACKED_GROUP_SIZE).then_some(members))
+}
+
+fn backoff(attempts: u32) -> i64 {
+    RETRY_BASE_SECS.saturating_mul(1 << attempts.min(16))
+}
+
+/// Start tracking a group message we sent. Does nothing outside small groups.
+pub fn track_sent(storage: &Storage, channel_id: [u8; 32], message_id: [u8; 32], now: i64) -> Result<(), String> {
+    if acked_members(storage, channel_id)?.is_none() {
+        return Ok(());
+    }
+    storage.track_group_delivery(&GroupDeliveryRow {
+        message_id,
+        channel_id,
+        sent_at: now,
+        attempts: 0,
+        next_attempt: now + backoff(0),
+    })
+}
+
+/// The receipt to send for a group message we received, if its group uses
+/// receipts, we are a member and the message is not our own.
+pub fn receipt_for(storage: &Storage, own_user_id: [u8; 32], message: &Packet) -> Result<Option<Packet>, String> {
+    let Some(members) = acked_members(storage, message.channel_id)? else {
+        return Ok(None);
+    };
+    if !members.contains(&own_user_id) || storage.get_group_delivery(message.packet_id)?.is_some() {
+        return Ok(None);
+    }
+    let Some(key) = storage.get_channel_key(message.channel_id)? else {
+        return Ok(None);
+    };
+
+    let mut payload = Vec::with_capacity(RECEIPT_LEN);
+    payload.extend_from_slice(&message.packet_id);
+    payload.extend_from_slice(&own_user_id);
+    payload.extend_from_slice(&receipt_tag(&key, &message.packet_id, &own_user_id));
+    Ok(Some(Packet {
+        packet_id: Sha256::digest(&payload).into(),
+        channel_id: message.channel_id,
+        kind: PacketKind::DeliveryReceipt,
+        ttl: RECEIPT_TTL,
+        payload,
+        priority: Priority::Normal,
+    }))
+}
+
+/// Whether a receipt tag was made with one of the channel's keys.
+fn tag_verifies(storage: &Storage, channel_id: [u8; 32], message_id: &[u8; 32], user_id: &[u8; 32], tag: &[u8; 32]) -> Result<bool, String> {
+    Ok(storage
+        .list_channel_key_epochs(channel_id)?
+        .iter()
+        .any(|epoch| receipt_tag(&epoch.key, message_id, user_id) == *tag))
+}
+
+/// Record a receipt for one of our tracked messages. Emits `delivery_receipt`,
+/// and `group_delivered` once every other member has confirmed (its send
+/// state is then delivered, see `send_status`). A genuine receipt for a
+/// message of our group that we never got leaves a placeholder for it (see
+/// `message_futures`); other receipts for messages we do not
+/// track are ignored (relays only forward them).
+pub fn handle_receipt(storage: &Storage, own_user_id: Option<[u8; 32]>, packet: &Packet, now: i64) -> Result<(), String> {
+    if packet.payload.len() != RECEIPT_LEN {
+        return Err(MeshError::InvalidArgument.raise("Malformed delivery receipt"));
+    }
+    let message_id: [u8; 32] = crate::codec::read_array(&packet.payload, 0, "message id")?;
+    let user_id: [u8; 32] = crate::codec::read_array(&packet.payload, 32, "user id")?;
+    let tag: [u8; 32] = crate::codec::read_array(&packet.payload, 64, "receipt tag")?;
+
+    let Some(delivery) = storage.get_group_delivery(message_id)? else {
+        let is_member = acked_members(storage, packet.channel_id)?
+            .is_some_and(|members| own_user_id.is_some_and(|own| members.contains(&own)));
+        if is_member
+            && storage.get_message(message_id)?.is_none()
+            && tag_verifies(storage, packet.channel_id, &message_id, &user_id, &tag)?
+        {
+            message_futures::expect(storage, packet.channel_id, message_id, now)?;
+        }
+        return Ok(());
+    };
+    let genuine = tag_verifies(storage, delivery.channel_id, &message_id, &user_id, &tag)?;
+    if !genuine || packet.channel_id != delivery.channel_id {
+        return Err(MeshError::Crypto.raise("Delivery receipt does not verify"));
+    }
+    if !storage.add_delivery_receipt(message_id, user_id, now)? {
+        return Ok(());
+    }
+    events::emit(
+        "delivery_receipt",
+        json!({
+            "message_id": hex::encode(message_id),
+            "channel_id": hex::encode(delivery.channel_id),
+            "user_id": hex::encode(user_id),
+        }),
+    );
+
+    if missing_members(storage, &delivery, own_user_id)?.is_empty() {
+        storage.delete_group_delivery(message_id)?;
+        events::emit(
+            "group_delivered",
+            json!({ "message_id": hex::encode(message_id), "channel_id": hex::encode(delivery.channel_id) }),
+        );
+        send_status::advance(storage, message_id, SendState::Delivered, now)?;
+    }
+    Ok(())
+}
+
+fn missing_members(
+    storage: &Storage,
+    delivery: &GroupDeliveryRow,
+    own_user_id: Option<[u8; 32]>,
+) -> Result<Vec<[u8; 32]>, String> {
+    let acked = storage.list_delivery_receipts(delivery.message_id)?;
+    Ok(storage
+        .list_group_members(delivery.channel_id)?
+        .into_iter()
+        .filter(|m| Some(*m) != own_user_id && !acked.contains(m))
+        .collect())
+}
+
+/// Members of a tracked delivery that have and have not confirmed it.
+pub fn status(storage: &Storage, own_user_id: Option<[u8; 32]>, message_id: [u8; 32]) -> Result<Option<DeliveryStatus>, String> {
+    let Some(delivery) = storage.get_group_delivery(message_id)? else {
+        return Ok(None);
+    };
+    Ok(Some(DeliveryStatus {
+        acked: storage.list_delivery_receipts(message_id)?,
+        missing: missing_members(storage, &delivery, own_user_id)?,
+    }))
+}
+
+/// Deliveries due for retransmission, with the members still missing them.
+/// Each returned delivery counts as one attempt; deliveries out of attempts
+/// (or whose message is gone) are dropped.
+pub fn due_retransmits(storage: &Storage, own_user_id: Option<[u8; 32]>, now: i64) -> Result<Vec<Retransmit>, String> {
+    let mut due = Vec::new();
+    for delivery in storage.list_due_group_deliveries(now)? {
+        let missing = missing_members(storage, &delivery, own_user_id)?;
+        let message = storage.get_message(delivery.message_id)?;
+        let Some(message) = message.filter(|_| !missing.is_empty() && delivery.attempts < MAX_ATTEMPTS) else {
+            storage.delete_group_delivery(delivery.message_id)?;
+            if !missing.is_empty() {
+                let missing: Vec<String> = missing.iter().map(hex::encode).collect();
+                events::emit(
+                    "group_delivery_failed",
+                    json!({
+                        "message_id": hex::encode(delivery.message_id),
+                        "channel_id": hex::encode(delivery.channel_id),
+                        "missing": missing,
+                    }),
+                );
+            }
+            continue;
+        };
+
+        let attempts = delivery.attempts + 1;
+        storage.set_group_delivery_attempt(delivery.message_id, attempts, now + backoff(attempts))?;
+        due.push(Retransmit {
+            message_id: delivery.message_id,
+            channel_id: delivery.channel_id,
+            missing,
+            packet: Packet {
+                packet_id: message.message_id,
+                channel_id: message.channel_id,
+                kind: PacketKind::Message,
+                ttl: message.ttl,
+                payload: message.ciphertext,
+                priority: Priority::from_u8(message.priority),
+            },
+        });
+    }
+    Ok(due)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receipts_complete_a_tracked_delivery() {
+        let storage = Storage::in_memory().unwrap();
+        let (channel, key) = ([1u8; 32], [2u8; 32]);
+        let (me, alice, bob) = ([10u8; 32], [11u8; 32], [12u8; 32]);
+        storage.upsert_channel(channel, "group").unwrap();
+        storage.set_channel_key(channel, key, 0).unwrap();
+        storage.set_group_members(channel, &[me, alice, bob], 0).unwrap();
+
+        let message = Packet { packet_id: [5u8; 32], channel_id: channel, kind: PacketKind::Message, ttl: 3, payload: vec![1], priority: Priority::Normal };
+        storage.store_message(message.packet_id, channel, vec![1], 100, 3).unwrap();
+        track_sent(&storage, channel, message.packet_id, 100).unwrap();
+        // Our own message is not acknowledged by us
+        assert!(receipt_for(&storage, me, &message).unwrap().is_none());
+
+        assert!(due_retransmits(&storage, Some(me), 100).unwrap().is_empty());
+        let due = due_retransmits(&storage, Some(me), 100 + RETRY_BASE_SECS).unwrap();
+        assert_eq!(due[0].missing, vec![alice, bob]);
+
+        let mut receipt = Packet { kind: PacketKind::DeliveryReceipt, ..message.clone() };
+        receipt.payload = [message.packet_id, alice, receipt_tag(&key, &message.packet_id, &alice)].concat();
+        handle_receipt(&storage, Some(me), &receipt, 150).unwrap();
+        assert_eq!(status(&storage, Some(me), message.packet_id).unwrap(), Some(DeliveryStatus { acked: vec![alice], missing: vec![bob] }));
+
+        receipt.payload[64] ^= 1;
+        assert!(handle_receipt(&storage, Some(me), &receipt, 150).is_err());
+        receipt.payload = [message.packet_id, bob, receipt_tag(&key, &message.packet_id, &bob)].concat();
+        handle_receipt(&storage, Some(me), &receipt, 160).unwrap();
+        assert_eq!(status(&storage, Some(me), message.packet_id).unwrap(), None);
+
+        // Alice confirming a message we never got leaves a placeholder for it
+        receipt.payload = [[6u8; 32], alice, receipt_tag(&key, &[6u8; 32], &alice)].concat();
+        handle_receipt(&storage, Some(me), &receipt, 170).unwrap();
+        assert_eq!(storage.get_message([6u8; 32]).unwrap().unwrap().kind, crate::storage::MESSAGE_KIND_PENDING);
+    }
+
+    fn group(storage: &Storage, channel: [u8; 32], key: [u8; 32], members: &[[u8; 32]]) {
+        storage.upsert_channel(channel, "group").unwrap();
+        storage.set_channel_key(channel, key, 0).unwrap();
+        storage.set_group_members(channel, members, 0).unwrap();
+    }
+
+    #[test]
+    fn test_retransmits_back_off_then_give_up() {
+        let storage = Storage::in_memory().unwrap();
+        let (channel, me, alice) = ([1u8; 32], [10u8; 32], [11u8; 32]);
+        group(&storage, channel, [2u8; 32], &[me, alice]);
+        storage.store_message([5u8; 32], channel, vec![1, 2], 100, 3).unwrap();
+        track_sent(&storage, channel, [5u8; 32], 100).unwrap();
+
+        let mut now = 100 + backoff(0);
+        for attempt in 1..=MAX_ATTEMPTS {
+            assert!(due_retransmits(&storage, Some(me), now - 1).unwrap().is_empty());
+            let due = due_retransmits(&storage, Some(me), now).unwrap();
+            assert_eq!((due.len(), due[0].missing.clone()), (1, vec![alice]));
+            assert_eq!((due[0].packet.payload.clone(), due[0].packet.ttl), (vec![1, 2], 3));
+            now += backoff(attempt);
+        }
+        // Out of attempts: given up
+        assert!(due_retransmits(&storage, Some(me), now).unwrap().is_empty());
+        assert_eq!(status(&storage, Some(me), [5u8; 32]).unwrap(), None);
+
+        // A delivery whose message is gone is dropped
+        track_sent(&storage, channel, [6u8; 32], 100).unwrap();
+        assert!(due_retransmits(&storage, Some(me), 100 + backoff(0)).unwrap().is_empty());
+        assert_eq!(status(&storage, Some(me), [6u8; 32]).unwrap(), None);
+
+        // Larger groups and other channels are not tracked
+        let crowd: Vec<[u8; 32]> = (0..=MAX_ACKED_GROUP_SIZE as u8).map(|i| [100 + i; 32]).collect();
+        group(&storage, [3u8; 32], [2u8; 32], &crowd);
+        storage.upsert_channel([4u8; 32], "geo").unwrap();
+        for other in [[3u8; 32], [4u8; 32]] {
+            track_sent(&storage, other, [7u8; 32], 100).unwrap();
+            assert_eq!(status(&storage, Some(me), [7u8; 32]).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_only_key_holding_members_confirm() {
+        let (sender, member) = (Storage::in_memory().unwrap(), Storage::in_memory().unwrap());
+        let (channel, key, me, alice) = ([1u8; 32], [2u8; 32], [10u8; 32], [11u8; 32]);
+        group(&sender, channel, key, &[me, alice]);
+        let message = Packet { packet_id: [5u8; 32], channel_id: channel, kind: PacketKind::Message, ttl: 3, payload: vec![1], priority: Priority::Normal };
+        sender.store_message(message.packet_id, channel, vec![1], 100, 3).unwrap();
+        track_sent(&sender, channel, message.packet_id, 100).unwrap();
+
+        // No receipt without membership or the key
+        member.upsert_channel(channel, "group").unwrap();
+        member.set_group_members(channel, &[me, alice], 0).unwrap();
+        assert!(receipt_for(&member, alice, &message).unwrap().is_none());
+        member.set_channel_key(channel, [9u8; 32], 0).unwrap();
+        assert!(receipt_for(&member, [12u8; 32], &message).unwrap().is_none());
+
+        // A receipt made with another key, or moved to another channel, is refused
+        let forged = receipt_for(&member, alice, &message).unwrap().unwrap();
+        assert!(handle_receipt(&sender, Some(me), &forged, 110).is_err());
+        member.set_channel_key(channel, key, 1).unwrap();
+        let receipt = receipt_for(&member, alice, &message).unwrap().unwrap();
+        assert!(handle_receipt(&sender, Some(me), &Packet { channel_id: [3u8; 32], ..receipt.clone() }, 110).is_err());
+        assert_eq!(status(&sender, Some(me), message.packet_id).unwrap().unwrap().acked, Vec::<[u8; 32]>::new());
+
+        handle_receipt(&sender, Some(me), &receipt, 120).unwrap();
+        assert_eq!(status(&sender, Some(me), message.packet_id).unwrap(), None);
+    }
+}
@@ -0,0 +1,216 @@
+//! Group metadata sync
+//!
+//! Any member can change a group's display name and topic with `update`. The
+//! change travels as a group message like any other, sealed under the group
+//! key, whose plaintext is a metadata marker (0x1E) followed by JSON holding
+//! the full new state { name, topic, updated_at }, the author's Ed25519 key
+//! and their signature over it. Members apply it when it arrives (and when
+//! history brings it in) if the author is a member and the signature holds.
+//!
+//! The newest change wins: `updated_at`, then the author's user id, decide,
+//! so members that see changes in different orders still converge. The
+//! state is kept in the `channels` table (name, topic and who set them
+//! when), and the change shows in the group's history as a
+//! `group_metadata_changed` system event.
+
+use crate::codec;
+use crate::error::MeshError;
+use crate::events;
+use crate::groups;
+use crate::identity::Identity;
+use crate::invites;
+use crate::storage::{OutgoingMessage, Storage};
+use crate::system_messages::SystemEvent;
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+/// First character of a metadata change plaintext
+const METADATA_MARKER: char = '\u{1e}';
+
+/// Longest topic (bytes)
+pub const MAX_TOPIC_LEN: usize = 256;
+
+/// A signed change of a group's name and topic
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MetadataUpdate {
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub updated_at: i64,
+    pub author_ed25519_public: String,
+    pub signature: String,
+}
+
+impl MetadataUpdate {
+    fn encode(&self) -> Result<String, String> {
+        let json = serde_json::to_string(self).map_err(|e| format!("Failed to serialize metadata update: {}", e))?;
+        Ok(format!("{}{}", METADATA_MARKER, json))
+    }
+
+    /// The system event showing this change in history.
+    pub fn event(&self) -> SystemEvent {
+        let user_id = codec::parse_id_hex(&self.author_ed25519_public, "author key")
+            .map(|key| hex::encode(Sha256::digest(key)))
+            .unwrap_or_default();
+        SystemEvent::GroupMetadataChanged {
+            user_id,
+            name: self.name.clone(),
+            topic: self.topic.clone(),
+        }
+    }
+}
+
+/// Parse an opened group message as a metadata change (None for ordinary messages).
+pub fn parse(plaintext: &str) -> Option<MetadataUpdate> {
+    serde_json::from_str(plaintext.strip_prefix(METADATA_MARKER)?).ok()
+}
+
+fn check_fields(name: Option<&str>, topic: Option<&str>) -> Result<(), String> {
+    if let Some(name) = name {
+        invites::check_name(name)?;
+    }
+    if topic.is_some_and(|t| t.len() > MAX_TOPIC_LEN) {
+        return Err(MeshError::InvalidArgument.raise(format!("Topic longer than {} bytes", MAX_TOPIC_LEN)));
+    }
+    Ok(())
+}
+
+/// Bytes covered by the author's signature (domain-separated, length-prefixed).
+fn signing_bytes(channel_id: &[u8; 32], name: Option<&str>, topic: Option<&str>, updated_at: i64) -> Vec<u8> {
+    let mut out = b"meshapp-group-metadata".to_vec();
+    out.extend_from_slice(channel_id);
+    for field in [name, topic] {
+        match field {
+            Some(text) => {
+                out.push(1);
+                out.extend_from_slice(&(text.len() as u32).to_be_bytes());
+                out.extend_from_slice(text.as_bytes());
+            }
+            None => out.push(0),
+        }
+    }
+    out.extend_from_slice(&updated_at.to_be_bytes());
+    out
+}
+
+/// Check a change's signature and that its author is a member. Returns the author's user id.
+fn verify(storage: &Storage, channel_id: [u8; 32], update: &MetadataUpdate) -> Result<[u8; 32], String> {
+    check_fields(update.name.as_deref(), update.topic.as_deref())?;
+    let author = codec::parse_id_hex(&update.author_ed25519_public, "author key")?;
+    let signature: [u8; 64] = codec::parse_hex_array(&update.signature, "signature")?;
+    let verifying_key = VerifyingKey::from_bytes(&author).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid author key: {}", e)))?;
+    let message = signing_bytes(&channel_id, update.name.as_deref(), update.topic.as_deref(), update.updated_at);
+    verifying_key
+        .verify(&message, &Signature::from_bytes(&signature))
+        .map_err(|_| MeshError::Crypto.raise("Invalid metadata signature"))?;
+
+    let author_user_id: [u8; 32] = Sha256::digest(author).into();
+    if !storage.list_group_members(channel_id)?.contains(&author_user_id) {
+        return Err(MeshError::Rejected.raise("Metadata change is not from a group member"));
+    }
+    Ok(author_user_id)
+}
+
+/// Keep a verified change if it is newer than ours and emit `group_metadata_changed`.
+/// Returns whether it was kept.
+fn apply(storage: &Storage, channel_id: [u8; 32], update: &MetadataUpdate, author_user_id: [u8; 32]) -> Result<bool, String> {
+    let kept = storage.set_channel_metadata_if_newer(
+        channel_id,
+        update.name.as_deref(),
+        update.topic.as_deref(),
+        update.updated_at,
+        author_user_id,
+    )?;
+    if kept {
+        events::emit(
+            "group_metadata_changed",
+            json!({
+                "channel_id": hex::encode(channel_id),
+                "name": update.name,
+                "topic": update.topic,
+                "user_id": hex::encode(author_user_id),
+            }),
+        );
+    }
+    Ok(kept)
+}
+
+/// Change one of our groups' name and topic. Applies the change here and
+/// returns the message that carries it to the other members.
+pub fn update(
+    identity: &Identity,
+    storage: &Storage,
+    channel_id: [u8; 32],
+    name: Option<&str>,
+    topic: Option<&str>,
+    now: i64,
+) -> Result<OutgoingMessage, String> {
+    check_fields(name, topic)?;
+    let signature = identity.ed25519_signing_key().sign(&signing_bytes(&channel_id, name, topic, now));
+    let update = MetadataUpdate {
+        name: name.map(str::to_string),
+        topic: topic.map(str::to_string),
+        updated_at: now,
+        author_ed25519_public: hex::encode(identity.public().ed25519_public.to_bytes()),
+        signature: hex::encode(signature.to_bytes()),
+    };
+    let outgoing = groups::seal_body(identity, storage, channel_id, update.encode()?.as_bytes(), now)?;
+    apply(storage, channel_id, &update, identity.public().user_id)?;
+    Ok(outgoing)
+}
+
+/// Apply the metadata change a stored group message carries, if it is one.
+pub fn on_message(storage: &Storage, channel_id: [u8; 32], message_id: [u8; 32], ciphertext: &[u8]) -> Result<(), String> {
+    if storage.get_channel_type(channel_id)?.as_deref() != Some("group") {
+        return Ok(());
+    }
+    let Ok(opened) = groups::open_message(storage, channel_id, &message_id, ciphertext) else {
+        return Ok(());
+    };
+    let Some(update) = std::str::from_utf8(&opened).ok().and_then(parse) else {
+        return Ok(());
+    };
+    let author_user_id = verify(storage, channel_id, &update)?;
+    apply(storage, channel_id, &update, author_user_id)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_members_converge_on_the_newest_change() {
+        let storage = Storage::in_memory().unwrap();
+        let (owner, alice, stranger) = (Identity::generate(), Identity::generate(), Identity::generate());
+        let channel = groups::create(&owner, &storage, Some("Hikers"), 100).unwrap();
+        groups::add_member(&owner, &storage, channel, alice.public().user_id, 100).unwrap();
+
+        let newer = update(&owner, &storage, channel, Some("Summit"), Some("Sunday hike"), 200).unwrap();
+        assert_eq!(storage.get_channel_name(channel).unwrap().as_deref(), Some("Summit"));
+        // A change made earlier, arriving late, loses
+        let older = update(&alice, &storage, channel, Some("Valley"), None, 150).unwrap();
+        assert_eq!(storage.get_channel_name(channel).unwrap().as_deref(), Some("Summit"));
+        on_message(&storage, channel, newer.message_id, &newer.ciphertext).unwrap();
+        on_message(&storage, channel, older.message_id, &older.ciphertext).unwrap();
+        assert_eq!(storage.get_channel_topic(channel).unwrap().as_deref(), Some("Sunday hike"));
+
+        let later = update(&alice, &storage, channel, Some("Valley"), None, 300).unwrap();
+        on_message(&storage, channel, later.message_id, &later.ciphertext).unwrap();
+        assert_eq!(storage.get_channel_name(channel).unwrap().as_deref(), Some("Valley"));
+        assert_eq!(storage.get_channel_topic(channel).unwrap(), None);
+
+        // Others cannot change it, even holding the key
+        let forged = MetadataUpdate {
+            name: Some("Pwned".to_string()),
+            topic: None,
+            updated_at: 400,
+            author_ed25519_public: hex::encode(stranger.public().ed25519_public.to_bytes()),
+            signature: hex::encode(stranger.ed25519_signing_key().sign(&signing_bytes(&channel, Some("Pwned"), None, 400)).to_bytes()),
+        };
+        let sealed = groups::seal_body(&owner, &storage, channel, forged.encode().unwrap().as_bytes(), 400).unwrap();
+        assert!(on_message(&storage, channel, sealed.message_id, &sealed.ciphertext).is_err());
+        assert_eq!(storage.get_channel_name(channel).unwrap().as_deref(), Some("Valley"));
+    }
+}
@@ -0,0 +1,358 @@
+//! Groups
+//!
+//! A group is a protected channel (type "group") whose key its members get
+//! over their DMs. `create` makes the channel and its first key with us as
+//! the only member. `add_member` records a friend as a member and returns
+//! the group invite to send them as a DM: a group invite marker (0x1D)
+//! followed by JSON holding a signed channel invite carrying the current key
+//! (see `invites`) and the member list. The friend sees it in the DM history
+//! (`group_invite`) and joins with `accept_invite`, which only takes invites
+//! signed by the friend the DM is with.
+//!
+//! Group messages are sealed under the current key (see
+//! `forward::seal_for_channel`) around JSON { sender, text }; the sender is
+//! claimed by the member who wrote it, not signed. Removing a member rotates
+//! the key: a new epoch is added and every remaining member gets a fresh
+//! invite, so the removed member cannot read what follows. Earlier epochs are
+//! kept, so earlier history still opens.
+//!
+//! The creator is the group's admin. Only admins add or remove members (and
+//! so rotate the key), and a member only takes a new key or member list from
+//! an admin. Invites carry the admin roster, and a member keeps the roster it
+//! joined with; admins moderate the group with signed actions (see
+//! `moderation`).
+
+use crate::compression;
+use crate::error::MeshError;
+use crate::events;
+use crate::forward;
+use crate::identity::Identity;
+use crate::invites::{self, AcceptedInvite, Invite};
+use crate::priority::Priority;
+use crate::storage::{OutgoingMessage, Storage};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+/// First character of a group invite plaintext
+const GROUP_INVITE_MARKER: char = '\u{1d}';
+
+/// TTL of group messages we send
+pub const GROUP_TTL: u8 = 10;
+
+/// A group invite as sent over a DM
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GroupInvite {
+    pub invite: Invite,
+    /// user_id hex of every member, the invitee included
+    pub members: Vec<String>,
+    /// user_id hex of the group's admins
+    #[serde(default)]
+    pub admins: Vec<String>,
+}
+
+impl GroupInvite {
+    /// Plaintext to send over the DM.
+    pub fn encode(&self) -> Result<String, String> {
+        let json = serde_json::to_string(self).map_err(|e| format!("Failed to serialize group invite: {}", e))?;
+        Ok(format!("{}{}", GROUP_INVITE_MARKER, json))
+    }
+}
+
+/// Parse a decrypted DM plaintext as a group invite (None for ordinary text).
+pub fn parse_invite(plaintext: &str) -> Option<GroupInvite> {
+    serde_json::from_str(plaintext.strip_prefix(GROUP_INVITE_MARKER)?).ok()
+}
+
+/// Body of a group message
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct GroupMessageBody {
+    /// Claimed author's user_id hex
+    pub sender: String,
+    pub text: String,
+}
+
+/// Parse an opened group message. Forwards into a group carry a bare forward
+/// envelope instead and have no sender.
+pub fn parse_message(plaintext: &str) -> (Option<String>, String) {
+    match serde_json::from_str::<GroupMessageBody>(plaintext) {
+        Ok(body) => (Some(body.sender), body.text),
+        Err(_) => (None, plaintext.to_string()),
+    }
+}
+
+/// Create a group with a fresh key and us as its only member and admin. Returns the channel id.
+pub fn create(identity: &Identity, storage: &Storage, name: Option<&str>, now: i64) -> Result<[u8; 32], String> {
+    storage.with_transaction(|storage| {
+        let channel_id = invites::create_protected_channel(storage, name, now)?;
+        storage.set_group_members(channel_id, &[identity.public().user_id], now)?;
+        storage.add_group_admins(channel_id, &[identity.public().user_id], now)?;
+        Ok(channel_id)
+    })
+}
+
+/// Members of a group we belong to.
+fn own_group_members(identity: &Identity, storage: &Storage, channel_id: [u8; 32]) -> Result<Vec<[u8; 32]>, String> {
+    if storage.get_channel_type(channel_id)?.as_deref() != Some("group") {
+        return Err(MeshError::Rejected.raise("Not a group channel"));
+    }
+    let members = storage.list_group_members(channel_id)?;
+    if !members.contains(&identity.public().user_id) {
+        return Err(MeshError::Rejected.raise("Not a member of this group"));
+    }
+    Ok(members)
+}
+
+/// Members of a group we are an admin of.
+fn administered_group_members(identity: &Identity, storage: &Storage, channel_id: [u8; 32]) -> Result<Vec<[u8; 32]>, String> {
+    let members = own_group_members(identity, storage, channel_id)?;
+    if !storage.list_group_admins(channel_id)?.contains(&identity.public().user_id) {
+        return Err(MeshError::Rejected.raise("Not an admin of this group"));
+    }
+    Ok(members)
+}
+
+/// The invite to the group as it stands, for any of its members.
+fn current_invite(identity: &Identity, storage: &Storage, channel_id: [u8; 32], members: &[[u8; 32]], now: i64) -> Result<GroupInvite, String> {
+    let name = storage.get_channel_name(channel_id)?;
+    Ok(GroupInvite {
+        invite: invites::create(identity, storage, channel_id, "group", name.as_deref(), None, now)?,
+        members: members.iter().map(hex::encode).collect(),
+        admins: storage.list_group_admins(channel_id)?.iter().map(hex::encode).collect(),
+    })
+}
+
+pub fn emit_members_changed(channel_id: [u8; 32], members: &[[u8; 32]]) {
+    let members: Vec<String> = members.iter().map(hex::encode).collect();
+    events::emit("group_members_changed", json!({ "channel_id": hex::encode(channel_id), "members": members }));
+}
+
+/// Add a member to a group we are an admin of. Returns the invite to send them.
+pub fn add_member(identity: &Identity, storage: &Storage, channel_id: [u8; 32], user_id: [u8; 32], now: i64) -> Result<GroupInvite, String> {
+    let mut members = administered_group_members(identity, storage, channel_id)?;
+    if members.contains(&user_id) {
+        return Err(MeshError::Rejected.raise("Already a member of this group"));
+    }
+    members.push(user_id);
+    storage.set_group_members(channel_id, &members, now)?;
+    emit_members_changed(channel_id, &members);
+    current_invite(identity, storage, channel_id, &members, now)
+}
+
+/// Remove a member from a group we are an admin of and rotate the key. Returns the
+/// invite carrying the new key and the other members to send it to.
+pub fn remove_member(
+    identity: &Identity,
+    storage: &Storage,
+    channel_id: [u8; 32],
+    user_id: [u8; 32],
+    now: i64,
+) -> Result<(GroupInvite, Vec<[u8; 32]>), String> {
+    let mut members = administered_group_members(identity, storage, channel_id)?;
+    if user_id == identity.public().user_id || !members.contains(&user_id) {
+        return Err(MeshError::Rejected.raise("Not a member that can be removed"));
+    }
+    members.retain(|m| *m != user_id);
+    let rotated = storage.with_transaction(|storage| {
+        storage.set_group_members(channel_id, &members, now)?;
+        rotate_key(identity, storage, channel_id, now)
+    })?;
+    emit_members_changed(channel_id, &members);
+    Ok(rotated)
+}
+
+/// Give a group we are an admin of a new key epoch. Returns the invite
+/// carrying the new key and the other members to send it to.
+pub fn rotate_key(identity: &Identity, storage: &Storage, channel_id: [u8; 32], now: i64) -> Result<(GroupInvite, Vec<[u8; 32]>), String> {
+    let own_user_id = identity.public().user_id;
+    let members = administered_group_members(identity, storage, channel_id)?;
+    let mut key = [0u8; 32];
+    crate::rng::rng().fill_bytes(&mut key);
+    storage.set_channel_key(channel_id, key, now)?;
+    invites::key_escrow_changed(channel_id);
+
+    let invite = current_invite(identity, storage, channel_id, &members, now)?;
+    Ok((invite, members.into_iter().filter(|m| *m != own_user_id).collect()))
+}
+
+/// Join a group from an invite a friend sent us over our DM. Joining a group
+/// we are in already takes its new key and member list, if the friend is one
+/// of its admins.
+pub fn accept_invite(
+    identity: &Identity,
+    storage: &Storage,
+    invite: &GroupInvite,
+    from_ed25519_public: [u8; 32],
+    now: i64,
+) -> Result<AcceptedInvite, String> {
+    if invite.invite.channel_type != "group" || invite.invite.inviter_ed25519_public != hex::encode(from_ed25519_public) {
+        return Err(MeshError::Rejected.raise("Group invite is not from the friend who sent it"));
+    }
+    let verified = invites::verify(&invite.invite, now)?;
+    let key = verified.channel_key.ok_or("Group invite carries no key")?;
+    let mut members = invite
+        .members
+        .iter()
+        .map(|m| crate::codec::parse_id_hex(m, "member id"))
+        .collect::<Result<Vec<_>, _>>()?;
+    let own_user_id = identity.public().user_id;
+    if !members.contains(&own_user_id) {
+        members.push(own_user_id);
+    }
+
+    let joined = storage.get_channel_type(verified.channel_id)?.as_deref() == Some("group");
+    if joined && !storage.list_group_admins(verified.channel_id)?.contains(&verified.inviter_user_id) {
+        return Err(MeshError::Rejected.raise("Group change is not from a group admin"));
+    }
+    storage.with_transaction(|storage| {
+        let accepted = if joined {
+            if storage.set_channel_key(verified.channel_id, key, now)? {
+                invites::key_escrow_changed(verified.channel_id);
+            }
+            AcceptedInvite {
+                channel_id: hex::encode(verified.channel_id),
+                channel_type: "group".to_string(),
+                name: invite.invite.name.clone(),
+                protected: true,
+                inviter_user_id: hex::encode(verified.inviter_user_id),
+            }
+        } else {
+            invites::accept(storage, &invite.invite, own_user_id, now)?
+        };
+        storage.set_group_members(verified.channel_id, &members, now)?;
+        if !joined {
+            let admins = invite
+                .admins
+                .iter()
+                .map(|a| crate::codec::parse_id_hex(a, "admin id"))
+                .collect::<Result<Vec<_>, _>>()?;
+            storage.add_group_admins(verified.channel_id, &admins, now)?;
+        }
+        Ok(accepted)
+    })
+}
+
+/// Seal a message for one of our groups under its current key.
+pub fn seal_message(identity: &Identity, storage: &Storage, channel_id: [u8; 32], text: &str, now: i64) -> Result<OutgoingMessage, String> {
+    let body = serde_json::to_vec(&GroupMessageBody {
+        sender: hex::encode(identity.public().user_id),
+        text: text.to_string(),
+    })
+    .map_err(|e| format!("Failed to serialize group message: {}", e))?;
+    seal_body(identity, storage, channel_id, &body, now)
+}
+
+/// Seal any plaintext for one of our groups under its current key.
+pub fn seal_body(identity: &Identity, storage: &Storage, channel_id: [u8; 32], body: &[u8], now: i64) -> Result<OutgoingMessage, String> {
+    own_group_members(identity, storage, channel_id)?;
+    let key = storage.get_channel_key(channel_id)?.ok_or("Group has no key")?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(channel_id);
+    hasher.update(now.to_be_bytes());
+    hasher.update(body);
+    let message_id: [u8; 32] = hasher.finalize().into();
+    Ok(OutgoingMessage {
+        message_id,
+        channel_id,
+        channel_type: "group",
+        ciphertext: forward::seal_for_channel(&key, &message_id, &compression::pack(body))?,
+        timestamp: now,
+        ttl: GROUP_TTL,
+        priority: Priority::Normal as u8,
+    })
+}
+
+/// Open a stored group message with whichever key epoch sealed it.
+pub fn open_message(storage: &Storage, channel_id: [u8; 32], message_id: &[u8; 32], sealed: &[u8]) -> Result<Vec<u8>, String> {
+    storage
+        .list_channel_key_epochs(channel_id)?
+        .iter()
+        .find_map(|epoch| forward::open_for_channel(&epoch.key, message_id, sealed).ok())
+        .ok_or_else(|| MeshError::Crypto.raise("No group key opens this message"))
+        .and_then(compression::unpack)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_removing_a_member_rotates_the_key() {
+        let (owner_storage, alice_storage) = (Storage::in_memory().unwrap(), Storage::in_memory().unwrap());
+        let (owner, alice, bob) = (Identity::generate(), Identity::generate(), Identity::generate());
+        let owner_public = owner.public().ed25519_public.to_bytes();
+
+        let channel = create(&owner, &owner_storage, Some("Hikers"), 100).unwrap();
+        let invite = add_member(&owner, &owner_storage, channel, alice.public().user_id, 110).unwrap();
+        add_member(&owner, &owner_storage, channel, bob.public().user_id, 110).unwrap();
+        let received = parse_invite(&invite.encode().unwrap()).unwrap();
+        // Only the friend the DM is with can invite us through it
+        assert!(accept_invite(&alice, &alice_storage, &received, bob.public().ed25519_public.to_bytes(), 120).is_err());
+        accept_invite(&alice, &alice_storage, &received, owner_public, 120).unwrap();
+        assert_eq!(alice_storage.list_group_members(channel).unwrap().len(), 2);
+
+        let first = seal_message(&owner, &owner_storage, channel, "hi", 130).unwrap();
+        let opened = open_message(&alice_storage, channel, &first.message_id, &first.ciphertext).unwrap();
+        let (sender, text) = parse_message(std::str::from_utf8(&opened).unwrap());
+        assert_eq!((sender, text.as_str()), (Some(hex::encode(owner.public().user_id)), "hi"));
+
+        let (rekey, recipients) = remove_member(&owner, &owner_storage, channel, bob.public().user_id, 140).unwrap();
+        assert_eq!(recipients, vec![alice.public().user_id]);
+        let second = seal_message(&owner, &owner_storage, channel, "bob is out", 150).unwrap();
+        assert!(open_message(&alice_storage, channel, &second.message_id, &second.ciphertext).is_err());
+        accept_invite(&alice, &alice_storage, &rekey, owner_public, 160).unwrap();
+        assert!(open_message(&alice_storage, channel, &second.message_id, &second.ciphertext).is_ok());
+        assert!(open_message(&alice_storage, channel, &first.message_id, &first.ciphertext).is_ok());
+    }
+
+    #[test]
+    fn test_removed_member_cannot_decrypt_after_rotation() {
+        let (owner_storage, bob_storage) = (Storage::in_memory().unwrap(), Storage::in_memory().unwrap());
+        let (owner, bob) = (Identity::generate(), Identity::generate());
+        let owner_public = owner.public().ed25519_public.to_bytes();
+        let channel = create(&owner, &owner_storage, None, 100).unwrap();
+        let invite = add_member(&owner, &owner_storage, channel, bob.public().user_id, 110).unwrap();
+        accept_invite(&bob, &bob_storage, &invite, owner_public, 120).unwrap();
+        let before = seal_message(&owner, &owner_storage, channel, "before", 130).unwrap();
+        assert!(open_message(&bob_storage, channel, &before.message_id, &before.ciphertext).is_ok());
+
+        // Bob gets no rekey invite; what follows is sealed under a key he never had
+        let (_, recipients) = remove_member(&owner, &owner_storage, channel, bob.public().user_id, 140).unwrap();
+        assert!(recipients.is_empty());
+        assert_eq!(owner_storage.list_group_members(channel).unwrap(), vec![owner.public().user_id]);
+        assert_eq!(owner_storage.list_channel_key_epochs(channel).unwrap().len(), 2);
+        let after = seal_message(&owner, &owner_storage, channel, "after", 150).unwrap();
+        assert!(open_message(&bob_storage, channel, &after.message_id, &after.ciphertext).is_err());
+        assert!(open_message(&bob_storage, channel, &before.message_id, &before.ciphertext).is_ok());
+    }
+
+    #[test]
+    fn test_non_admin_changes_are_rejected() {
+        let storages = [(); 3].map(|_| Storage::in_memory().unwrap());
+        let [owner_storage, alice_storage, bob_storage] = &storages;
+        let (owner, alice, bob) = (Identity::generate(), Identity::generate(), Identity::generate());
+        let owner_public = owner.public().ed25519_public.to_bytes();
+        let channel = create(&owner, owner_storage, None, 100).unwrap();
+        let to_alice = add_member(&owner, owner_storage, channel, alice.public().user_id, 110).unwrap();
+        let to_bob = add_member(&owner, owner_storage, channel, bob.public().user_id, 110).unwrap();
+        accept_invite(&alice, alice_storage, &to_alice, owner_public, 120).unwrap();
+        accept_invite(&bob, bob_storage, &to_bob, owner_public, 120).unwrap();
+
+        // Alice is a member, not an admin
+        let carol = Identity::generate().public().user_id;
+        assert!(add_member(&alice, alice_storage, channel, carol, 130).is_err());
+        assert!(remove_member(&alice, alice_storage, channel, bob.public().user_id, 130).is_err());
+        assert!(rotate_key(&alice, alice_storage, channel, 130).is_err());
+
+        // Nor does Bob take a new member list from her
+        let forged = GroupInvite {
+            invite: invites::create(&alice, alice_storage, channel, "group", None, None, 140).unwrap(),
+            members: vec![hex::encode(alice.public().user_id), hex::encode(bob.public().user_id)],
+            admins: Vec::new(),
+        };
+        assert!(accept_invite(&bob, bob_storage, &forged, alice.public().ed25519_public.to_bytes(), 150).is_err());
+        assert_eq!(bob_storage.list_group_members(channel).unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,318 @@
+//! History request/response
+//!
+//! The pull counterpart to gossip: a node asks "messages for channel X
+//! since T" with a `HistoryRequest` packet, and nodes storing the channel
+//! answer with a `HistoryResponse` page. Both payloads are JSON with hex
+//! fields.
+//!
+//! Paging uses a (timestamp, message_id) cursor: a page holds messages
+//! after `(since, after)` in that order, and the response carries the
+//! cursor of the next page if there is one.
+//!
+//! Public channels are served to anyone. DM channels are served only when
+//! the request is signed by the other participant of the DM (we check that
+//! the DM channel id of us and the requester is the requested channel).
+//! Notes are never served. Responses are only accepted for requests we
+//! sent in the last `REQUEST_TIMEOUT_SECS`. Message times too far in our
+//! future are quarantined (see `clock`).
+
+use crate::blocklist;
+use crate::clock;
+use crate::codec;
+use crate::dm_crypto;
+use crate::error::MeshError;
+use crate::events;
+use crate::group_metadata;
+use crate::identity::Identity;
+use crate::message_futures;
+use crate::message_index;
+use crate::moderation;
+use crate::priority::Priority;
+use crate::settings;
+use crate::storage::Storage;
+use crate::transport::{Packet, PacketKind, Router};
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Messages per page when the request asks for 0
+pub const DEFAULT_PAGE_SIZE: u32 = 50;
+/// Most messages in one page
+pub const MAX_PAGE_SIZE: u32 = 100;
+
+/// How long a request stays valid (signature freshness and accepting responses)
+const REQUEST_TIMEOUT_SECS: i64 = 300;
+
+/// (channel_id, sent_at) of a request we sent
+type PendingRequest = ([u8; 32], i64);
+
+/// Our outstanding requests by request_id
+static PENDING: Lazy<Mutex<HashMap<[u8; 32], PendingRequest>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryRequest {
+    pub request_id: String,
+    pub since: i64,
+    /// Message id at `since` to continue after (None: from the start of `since`)
+    #[serde(default)]
+    pub after: Option<String>,
+    pub limit: u32,
+    pub requested_at: i64,
+    /// Requester's Ed25519 public key and signature (DM channels only)
+    #[serde(default)]
+    pub requester: Option<String>,
+    #[serde(default)]
+    pub signature: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryMessage {
+    pub message_id: String,
+    pub ciphertext: String,
+    pub timestamp: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryResponse {
+    pub request_id: String,
+    pub messages: Vec<HistoryMessage>,
+    /// Cursor of the next page (None when this is the last page)
+    #[serde(default)]
+    pub next_since: Option<i64>,
+    #[serde(default)]
+    pub next_after: Option<String>,
+}
+
+/// A page received for one of our requests
+#[derive(Serialize, Debug)]
+pub struct HistoryPage {
+    pub channel_id: String,
+    pub request_id: String,
+    pub stored: usize,
+    pub has_more: bool,
+    pub next_since: Option<i64>,
+    pub next_after: Option<String>,
+}
+
+fn signing_bytes(channel_id: &[u8; 32], request_id: &[u8; 32], since: i64, after: &[u8; 32], limit: u32, requested_at: i64) -> Vec<u8> {
+    let mut out = b"meshapp-history-request".to_vec();
+    out.extend_from_slice(channel_id);
+    out.extend_from_slice(request_id);
+    out.extend_from_slice(&since.to_be_bytes());
+    out.extend_from_slice(after);
+    out.extend_from_slice(&limit.to_be_bytes());
+    out.extend_from_slice(&requested_at.to_be_bytes());
+    out
+}
+
+/// Build a request packet for a page of `channel_id` history after
+/// `(since, after)`. Pass `identity` to sign the request (needed for DMs).
+/// The request is remembered so its responses are accepted.
+pub fn build_request(
+    identity: Option<&Identity>,
+    channel_id: [u8; 32],
+    since: i64,
+    after: Option<[u8; 32]>,
+    limit: u32,
+    ttl: u8,
+    now: i64,
+) -> Result<Packet, String> {
+    let request_id = Router::generate_packet_id();
+    let limit = if limit == 0 { DEFAULT_PAGE_SIZE } else { limit.min(MAX_PAGE_SIZE) };
+
+    let (requester, signature) = match identity {
+        Some(identity) => {
+            let msg = signing_bytes(&channel_id, &request_id, since, &after.unwrap_or([0u8; 32]), limit, now);
+            let signature = identity.ed25519_signing_key().sign(&msg);
+            (
+                Some(hex::encode(identity.public().ed25519_public.as_bytes())),
+                Some(hex::encode(signature.to_bytes())),
+            )
+        }
+        None => (None, None),
+    };
+    let request = HistoryRequest {
+        request_id: hex::encode(request_id),
+        since,
+        after: after.map(hex::encode),
+        limit,
+        requested_at: now,
+        requester,
+        signature,
+    };
+    let payload = serde_json::to_vec(&request).map_err(|e| format!("Failed to serialize history request: {}", e))?;
+
+    let mut pending = PENDING.lock().unwrap();
+    pending.retain(|_, (_, sent_at)| now.saturating_sub(*sent_at) <= REQUEST_TIMEOUT_SECS);
+    pending.insert(request_id, (channel_id, now));
+
+    Ok(Packet {
+        packet_id: request_id,
+        channel_id,
+        kind: PacketKind::HistoryRequest,
+        ttl,
+        payload,
+        priority: Priority::Normal,
+    })
+}
+
+/// Check that a DM history request comes from the other participant.
+fn authorize_dm(request: &HistoryRequest, channel_id: &[u8; 32], after: &[u8; 32], own_public: Option<[u8; 32]>, now: i64) -> Result<(), String> {
+    let own_public = own_public.ok_or_else(|| MeshError::NotInitialized.raise("No identity to check DM history requests against"))?;
+    let requester = codec::parse_id_hex(request.requester.as_deref().ok_or("Unsigned DM history request")?, "requester key")?;
+    if requester == own_public || dm_crypto::derive_dm_channel_id(&own_public, &requester) != *channel_id {
+        return Err(MeshError::Rejected.raise("Requester is not a participant of this DM"));
+    }
+    if (now - request.requested_at).abs() > REQUEST_TIMEOUT_SECS {
+        return Err("Stale DM history request".to_string());
+    }
+
+    let signature: [u8; 64] = codec::parse_hex_array(request.signature.as_deref().unwrap_or(""), "request signature")?;
+    let request_id = codec::parse_id_hex(&request.request_id, "request id")?;
+    let msg = signing_bytes(channel_id, &request_id, request.since, after, request.limit, request.requested_at);
+    VerifyingKey::from_bytes(&requester)
+        .map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid requester key: {}", e)))?
+        .verify(&msg, &Signature::from_bytes(&signature))
+        .map_err(|_| MeshError::Crypto.raise("History request signature does not verify"))
+}
+
+/// Answer a history request for a channel we store. Returns None when we do
+/// not serve the channel; errors for malformed or unauthorized requests.
+pub fn handle_request(storage: &Storage, own_public: Option<[u8; 32]>, packet: &Packet, now: i64) -> Result<Option<Packet>, String> {
+    let request: HistoryRequest =
+        serde_json::from_slice(&packet.payload).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid history request: {}", e)))?;
+    let after = match &request.after {
+        Some(a) => codec::parse_id_hex(a, "history cursor")?,
+        None => [0u8; 32],
+    };
+
+    match storage.get_channel_type(packet.channel_id)?.as_deref() {
+        None | Some("notes") => return Ok(None),
+        Some("dm") => authorize_dm(&request, &packet.channel_id, &after, own_public, now)?,
+        Some(_) => {}
+    }
+
+    let limit = request.limit.clamp(1, MAX_PAGE_SIZE);
+    let max_bytes = settings::get_u64(storage, settings::REPLAY_MAX_BYTES)?;
+    // One extra row tells us whether there is another page
+    let rows = storage.fetch_messages_after(packet.channel_id, request.since, after, limit + 1)?;
+    let has_more = rows.len() > limit as usize;
+
+    let mut messages = Vec::new();
+    let mut bytes = 0u64;
+    let mut truncated = false;
+    for row in rows.into_iter().take(limit as usize) {
+        bytes += row.ciphertext.len() as u64;
+        // Always send at least one message so paging makes progress
+        if bytes > max_bytes && !messages.is_empty() {
+            truncated = true;
+            break;
+        }
+        messages.push(HistoryMessage {
+            message_id: hex::encode(row.message_id),
+            ciphertext: hex::encode(row.ciphertext),
+            timestamp: row.timestamp,
+        });
+    }
+
+    let (next_since, next_after) = match messages.last() {
+        Some(last) if has_more || truncated => (Some(last.timestamp), Some(last.message_id.clone())),
+        _ => (None, None),
+    };
+    let response = HistoryResponse {
+        request_id: request.request_id,
+        messages,
+        next_since,
+        next_after,
+    };
+    let payload = serde_json::to_vec(&response).map_err(|e| format!("Failed to serialize history response: {}", e))?;
+
+    Ok(Some(Packet {
+        packet_id: Router::generate_packet_id(),
+        channel_id: packet.channel_id,
+        kind: PacketKind::HistoryResponse,
+        ttl: packet.ttl,
+        payload,
+        priority: Priority::Normal,
+    }))
+}
+
+/// Store a page answering one of our requests and emit `history_page`.
+/// Responses to requests we did not send (or that timed out) are ignored.
+pub fn handle_response(storage: &Storage, packet: &Packet, now: i64) -> Result<Option<HistoryPage>, String> {
+    let response: HistoryResponse =
+        serde_json::from_slice(&packet.payload).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid history response: {}", e)))?;
+    let request_id = codec::parse_id_hex(&response.request_id, "request id")?;
+
+    let expected = PENDING.lock().unwrap().get(&request_id).copied();
+    match expected {
+        Some((channel_id, sent_at)) if channel_id == packet.channel_id && now - sent_at <= REQUEST_TIMEOUT_SECS => {}
+        _ => return Ok(None),
+    }
+
+    let mut stored = 0;
+    for m in &response.messages {
+        let message_id = codec::parse_id_hex(&m.message_id, "message id")?;
+        let ciphertext = codec::parse_hex_payload(&m.ciphertext)?;
+        if blocklist::is_blocked_sender(storage, packet.channel_id, message_id, &ciphertext)? {
+            continue;
+        }
+        if let Err(e) = group_metadata::on_message(storage, packet.channel_id, message_id, &ciphertext) {
+            eprintln!("Dropping group metadata change: {}", e);
+        }
+        if let Err(e) = moderation::on_message(storage, packet.channel_id, message_id, &ciphertext, now) {
+            eprintln!("Dropping moderation action: {}", e);
+        }
+        let timestamp = clock::admit_timestamp(storage, message_id, packet.channel_id, m.timestamp, now)?;
+        // TTL 0: pulled history is not forwarded again
+        message_futures::store(storage, message_id, packet.channel_id, ciphertext, timestamp, 0)?;
+        message_index::queue_incoming(storage, packet.channel_id, message_id)?;
+        stored += 1;
+    }
+
+    let page = HistoryPage {
+        channel_id: hex::encode(packet.channel_id),
+        request_id: response.request_id,
+        stored,
+        has_more: response.next_since.is_some(),
+        next_since: response.next_since,
+        next_after: response.next_after,
+    };
+    events::emit("history_page", serde_json::to_value(&page).unwrap_or_default());
+    Ok(Some(page))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dm_history_requires_participant_signature() {
+        let storage = Storage::in_memory().unwrap();
+
+        let (server, friend, stranger) = (Identity::generate(), Identity::generate(), Identity::generate());
+        let own = *server.public().ed25519_public.as_bytes();
+        let channel = dm_crypto::derive_dm_channel_id(&own, friend.public().ed25519_public.as_bytes());
+        storage.upsert_channel(channel, "dm").unwrap();
+        for i in 0..3u8 {
+            storage.store_message([i + 1; 32], channel, vec![i; 10], 100 + i as i64, 4).unwrap();
+        }
+
+        let request = build_request(Some(&stranger), channel, 0, None, 2, 3, 1_000).unwrap();
+        assert!(handle_request(&storage, Some(own), &request, 1_000).is_err());
+        let unsigned = build_request(None, channel, 0, None, 2, 3, 1_000).unwrap();
+        assert!(handle_request(&storage, Some(own), &unsigned, 1_000).is_err());
+
+        let request = build_request(Some(&friend), channel, 0, None, 2, 3, 1_000).unwrap();
+        let response = handle_request(&storage, Some(own), &request, 1_000).unwrap().unwrap();
+        let page: HistoryResponse = serde_json::from_slice(&response.payload).unwrap();
+        assert_eq!(page.messages.len(), 2);
+        assert_eq!(page.next_since, Some(101));
+
+        // Accepted because we sent the request
+        let page = handle_response(&storage, &response, 1_001).unwrap().unwrap();
+        assert!(page.has_more);
+    }
+}
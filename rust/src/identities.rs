@@ -0,0 +1,216 @@
+//! Multiple identities (profiles)
+//!
+//! A device can keep several named identities (e.g. "work", "personal"),
+//! each with its own identity.json, mesh.db (friends included) and attachments.
+//! The identity a device started with is "default" and keeps its files at
+//! the root of the data directory, so existing installs need no migration;
+//! others live in `identities/<name>/`.
+//!
+//! `identities.json` at the root lists them and says which one is active.
+//! `storage::data_dir` resolves to the active identity's directory, so every
+//! file the core keeps follows the switch. A device without the index has
+//! only the default identity.
+
+use crate::error::MeshError;
+use crate::identity::Identity;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The identity whose files sit at the root of the data directory
+pub const DEFAULT_IDENTITY: &str = "default";
+
+const INDEX_FILE: &str = "identities.json";
+const IDENTITIES_DIR: &str = "identities";
+const MAX_NAME_LEN: usize = 32;
+
+/// Active identity, cached per root so `data_dir` does not read the index each time
+static ACTIVE: Mutex<Option<(PathBuf, String)>> = Mutex::new(None);
+
+/// An identity on this device
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IdentityEntry {
+    pub name: String,
+    /// Known once the identity has been created or loaded
+    pub user_id: Option<String>,
+    pub created_at: i64,
+}
+
+/// Contents of `identities.json`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IdentityIndex {
+    pub active: String,
+    pub identities: Vec<IdentityEntry>,
+}
+
+impl Default for IdentityIndex {
+    fn default() -> Self {
+        Self {
+            active: DEFAULT_IDENTITY.to_string(),
+            identities: vec![IdentityEntry { name: DEFAULT_IDENTITY.to_string(), user_id: None, created_at: 0 }],
+        }
+    }
+}
+
+impl IdentityIndex {
+    fn find_mut(&mut self, name: &str) -> Option<&mut IdentityEntry> {
+        self.identities.iter_mut().find(|e| e.name == name)
+    }
+}
+
+/// Read the index (the default identity alone if there is none yet).
+pub fn load(root: &Path) -> Result<IdentityIndex, String> {
+    let path = root.join(INDEX_FILE);
+    if !path.exists() {
+        return Ok(IdentityIndex::default());
+    }
+    let data = fs::read(&path).map_err(|e| format!("Failed to read identities index: {}", e))?;
+    serde_json::from_slice(&data).map_err(|e| format!("Failed to parse identities index: {}", e))
+}
+
+fn save(root: &Path, index: &IdentityIndex) -> Result<(), String> {
+    fs::create_dir_all(root).map_err(|e| format!("Failed to create storage directory: {}", e))?;
+    let data = serde_json::to_vec_pretty(index).map_err(|e| format!("Failed to serialize identities index: {}", e))?;
+
+    // Write to temporary file first, then rename (atomic operation)
+    let path = root.join(INDEX_FILE);
+    let temp_path = path.with_extension("tmp");
+    let mut file = fs::File::create(&temp_path).map_err(|e| format!("Failed to create identities index: {}", e))?;
+    file.write_all(&data).map_err(|e| format!("Failed to write identities index: {}", e))?;
+    file.sync_all().map_err(|e| format!("Failed to sync identities index: {}", e))?;
+    drop(file);
+    fs::rename(&temp_path, &path).map_err(|e| format!("Failed to rename identities index: {}", e))
+}
+
+/// Directory holding an identity's files.
+pub fn dir(root: &Path, name: &str) -> PathBuf {
+    if name == DEFAULT_IDENTITY {
+        root.to_path_buf()
+    } else {
+        root.join(IDENTITIES_DIR).join(name)
+    }
+}
+
+/// Directory of the active identity.
+pub fn active_dir(root: &Path) -> Result<PathBuf, String> {
+    let mut active = ACTIVE.lock().unwrap();
+    if let Some((cached_root, name)) = active.as_ref() {
+        if cached_root == root {
+            return Ok(dir(root, name));
+        }
+    }
+    let name = load(root)?.active;
+    let path = dir(root, &name);
+    *active = Some((root.to_path_buf(), name));
+    Ok(path)
+}
+
+/// Name of the active identity.
+pub fn active_name(root: &Path) -> Result<String, String> {
+    if let Some((cached_root, name)) = ACTIVE.lock().unwrap().as_ref() {
+        if cached_root == root {
+            return Ok(name.clone());
+        }
+    }
+    Ok(load(root)?.active)
+}
+
+/// Names are 1 to 32 lowercase letters, digits, '-' or '_', so they are
+/// safe as directory names on every platform.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// Create a new identity called `name` with fresh keys. It does not become
+/// the active one; see `switch`.
+pub fn create(root: &Path, name: &str, now: i64) -> Result<IdentityEntry, String> {
+    if !valid_name(name) {
+        return Err(MeshError::InvalidArgument.raise(format!("Invalid identity name (1-{} of a-z, 0-9, '-', '_')", MAX_NAME_LEN)));
+    }
+    let mut index = load(root)?;
+    if index.find_mut(name).is_some() {
+        return Err(MeshError::Rejected.raise(format!("Identity {} already exists", name)));
+    }
+    let identity = Identity::generate_in(&dir(root, name))?;
+    let entry = IdentityEntry {
+        name: name.to_string(),
+        user_id: Some(hex::encode(identity.public().user_id)),
+        created_at: now,
+    };
+    index.identities.push(entry.clone());
+    save(root, &index)?;
+    Ok(entry)
+}
+
+/// Make `name` the active identity. The caller unloads the current one.
+pub fn switch(root: &Path, name: &str) -> Result<(), String> {
+    let mut index = load(root)?;
+    if index.find_mut(name).is_none() {
+        return Err(MeshError::NotFound.raise(format!("Unknown identity {}", name)));
+    }
+    index.active = name.to_string();
+    save(root, &index)?;
+    *ACTIVE.lock().unwrap() = Some((root.to_path_buf(), name.to_string()));
+    Ok(())
+}
+
+/// Forget the cached active identity (the index was wiped).
+pub fn forget_active() {
+    ACTIVE.lock().unwrap().take();
+}
+
+/// Note the user id of the active identity once it is loaded (the default
+/// identity predates the index).
+pub fn record_user_id(root: &Path, user_id: &[u8; 32]) -> Result<(), String> {
+    let mut index = load(root)?;
+    let name = active_name(root)?;
+    let user_id = hex::encode(user_id);
+    match index.find_mut(&name) {
+        Some(entry) if entry.user_id.as_deref() == Some(user_id.as_str()) => return Ok(()),
+        Some(entry) => entry.user_id = Some(user_id),
+        None => return Ok(()),
+    }
+    save(root, &index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_and_switch_identities() {
+        let root = std::env::temp_dir().join(format!("meshapp-identities-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        // Without an index there is only the default identity, at the root
+        assert_eq!(load(&root).unwrap(), IdentityIndex::default());
+        assert_eq!(active_dir(&root).unwrap(), root);
+
+        let work = create(&root, "work", 100).unwrap();
+        assert!(dir(&root, "work").join("identity.json").exists());
+        assert!(create(&root, "work", 101).is_err());
+        assert!(create(&root, "../escape", 101).is_err());
+        assert!(create(&root, "Work", 101).is_err());
+        assert!(switch(&root, "personal").is_err());
+
+        // Creating does not switch; switching moves every file the core keeps
+        assert_eq!(active_name(&root).unwrap(), DEFAULT_IDENTITY);
+        switch(&root, "work").unwrap();
+        assert_eq!(active_dir(&root).unwrap(), root.join("identities").join("work"));
+        let index = load(&root).unwrap();
+        assert_eq!(index.active, "work");
+        assert_eq!(index.identities.iter().map(|e| e.name.as_str()).collect::<Vec<_>>(), vec!["default", "work"]);
+        assert_eq!(index.identities[1], work);
+
+        switch(&root, DEFAULT_IDENTITY).unwrap();
+        record_user_id(&root, &[7u8; 32]).unwrap();
+        assert_eq!(load(&root).unwrap().identities[0].user_id, Some(hex::encode([7u8; 32])));
+        assert_eq!(active_dir(&root).unwrap(), root);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -4,17 +4,30 @@
 //! - Ed25519 keypair for identity signing
 //! - X25519 keypair for key exchange
 //! - user_id = SHA256(identity_public_key)
-
+//!
+//! Secret key material is wiped from memory when dropped: the dalek key
+//! types zeroize themselves, and raw copies (`IdentityKeys`, `key_bytes`,
+//! `secret_bytes`) are zeroizing wrappers. Errors never include key bytes.
+
+use crate::entropy;
+use crate::error::MeshError;
+use crate::integrity;
+use crate::passphrase;
 use ed25519_dalek::{SigningKey, VerifyingKey};
 use x25519_dalek::{StaticSecret, PublicKey};
 use sha2::{Sha256, Digest};
 use serde::{Serialize, Deserialize};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::io::Write;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+/// Room for a serialized `IdentityKeys`, so the buffer never reallocates
+/// (and leaves a copy of the keys behind) while it is written
+const KEY_FILE_CAPACITY: usize = 512;
 
 /// Identity keys stored securely on device
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Zeroize, ZeroizeOnDrop)]
 struct IdentityKeys {
     ed25519_secret: [u8; 32],
     x25519_secret: [u8; 32],
@@ -36,14 +49,17 @@ pub struct Identity {
 }
 
 impl Identity {
-    /// Generate a new identity
+    /// Generate a new identity (keys drawn from OS randomness mixed with any
+    /// entropy the app contributed, see `entropy`)
     pub fn generate() -> Self {
+        let mut rng = entropy::take_rng();
+
         // Generate Ed25519 keypair for signing
-        let ed25519_signing = SigningKey::generate(&mut rand::thread_rng());
+        let ed25519_signing = SigningKey::generate(&mut rng);
         let ed25519_public = ed25519_signing.verifying_key();
 
         // Generate X25519 keypair for key exchange
-        let x25519_secret = StaticSecret::random_from_rng(&mut rand::thread_rng());
+        let x25519_secret = StaticSecret::random_from_rng(&mut rng);
         let x25519_public = PublicKey::from(&x25519_secret);
 
         // Compute user_id = SHA256(ed25519_public_key)
@@ -64,27 +80,70 @@ impl Identity {
         }
     }
 
-    /// Load identity from storage, or generate if it doesn't exist
+    /// Load the saved identity without creating one (read-only attach).
+    pub fn load_existing() -> Result<Self, String> {
+        let storage_path = get_storage_path()?;
+        if !storage_path.exists() {
+            return Err(MeshError::NotInitialized.raise("No identity in the data directory"));
+        }
+        Self::load_from_storage(&storage_path)
+    }
+
+    /// Load identity from storage, or generate if it doesn't exist (and never
+    /// did: a lost or damaged identity fails its integrity check instead)
     pub fn load_or_generate() -> Result<Self, String> {
         let storage_path = get_storage_path()?;
 
         if storage_path.exists() {
             Self::load_from_storage(&storage_path)
         } else {
+            integrity::verify_missing(&storage_path)?;
             let identity = Self::generate();
             identity.save_to_storage(&storage_path)?;
             Ok(identity)
         }
     }
 
+    /// Generate an identity in another profile's directory (see `identities`).
+    /// Fails if the directory has one already.
+    pub fn generate_in(dir: &Path) -> Result<Self, String> {
+        let storage_path = dir.join("identity.json");
+        if storage_path.exists() {
+            return Err(MeshError::Rejected.raise("This profile has an identity already"));
+        }
+        let identity = Self::generate();
+        identity.save_to_storage(&storage_path)?;
+        Ok(identity)
+    }
+
     /// Load identity from storage file
     fn load_from_storage(path: &PathBuf) -> Result<Self, String> {
         let data = fs::read(path)
             .map_err(|e| format!("Failed to read identity file: {}", e))?;
+        if passphrase::parse_sealed(&data).is_some() {
+            return Err(MeshError::Rejected.raise("Identity is passphrase protected; unlock it with unlock_identity"));
+        }
+        integrity::verify(path, &data)?;
+        Self::from_key_bytes(&data)
+    }
+
+    /// Rebuild an identity from the contents of a (plain or unsealed) identity file
+    pub fn from_key_bytes(data: &[u8]) -> Result<Self, String> {
+        // serde's messages can quote the offending value, so only say where
+        let keys: IdentityKeys = serde_json::from_slice(data)
+            .map_err(|e| format!("Failed to parse identity file at line {} column {}", e.line(), e.column()))?;
+        Ok(Self::from_keys(&keys))
+    }
 
-        let keys: IdentityKeys = serde_json::from_slice(&data)
-            .map_err(|e| format!("Failed to parse identity file: {}", e))?;
+    /// Rebuild an identity from its two secret keys (Ed25519, then X25519)
+    pub fn from_secret_bytes(secrets: &[u8; 64]) -> Self {
+        let mut keys = IdentityKeys { ed25519_secret: [0u8; 32], x25519_secret: [0u8; 32] };
+        keys.ed25519_secret.copy_from_slice(&secrets[..32]);
+        keys.x25519_secret.copy_from_slice(&secrets[32..]);
+        Self::from_keys(&keys)
+    }
 
+    fn from_keys(keys: &IdentityKeys) -> Self {
         // Reconstruct Ed25519 signing key
         let ed25519_signing = SigningKey::from_bytes(&keys.ed25519_secret);
         let ed25519_public = ed25519_signing.verifying_key();
@@ -104,11 +163,40 @@ impl Identity {
             user_id,
         };
 
-        Ok(Self {
+        Self {
             ed25519_signing,
             x25519_secret,
             public,
-        })
+        }
+    }
+
+    /// Contents of the (plain) identity file
+    pub fn key_bytes(&self) -> Result<Zeroizing<Vec<u8>>, String> {
+        let keys = IdentityKeys {
+            ed25519_secret: self.ed25519_signing.to_bytes(),
+            x25519_secret: self.x25519_secret.to_bytes(),
+        };
+        let mut out = Zeroizing::new(Vec::with_capacity(KEY_FILE_CAPACITY));
+        serde_json::to_writer(&mut *out, &keys).map_err(|_| "Failed to serialize identity".to_string())?;
+        Ok(out)
+    }
+
+    /// Both secret keys (Ed25519, then X25519)
+    pub fn secret_bytes(&self) -> Zeroizing<[u8; 64]> {
+        let mut out = Zeroizing::new([0u8; 64]);
+        out[..32].copy_from_slice(self.ed25519_signing.as_bytes());
+        out[32..].copy_from_slice(self.x25519_secret.as_bytes());
+        out
+    }
+
+    /// Save a restored identity as this device's identity. Fails if the
+    /// device has one already, so an identity is never overwritten.
+    pub fn save_restored(&self) -> Result<(), String> {
+        let storage_path = get_storage_path()?;
+        if storage_path.exists() {
+            return Err(MeshError::Rejected.raise("This device has an identity already"));
+        }
+        self.save_to_storage(&storage_path)
     }
 
     /// Save identity to storage file with restricted permissions
@@ -119,13 +207,8 @@ impl Identity {
                 .map_err(|e| format!("Failed to create storage directory: {}", e))?;
         }
 
-        let keys = IdentityKeys {
-            ed25519_secret: self.ed25519_signing.to_bytes(),
-            x25519_secret: self.x25519_secret.to_bytes(),
-        };
-
-        let data = serde_json::to_vec(&keys)
-            .map_err(|e| format!("Failed to serialize identity: {}", e))?;
+        let data = self.key_bytes()?;
+        integrity::begin_save(path, &data)?;
 
         // Write to temporary file first, then rename (atomic operation)
         let temp_path = path.with_extension("tmp");
@@ -142,6 +225,7 @@ impl Identity {
         // Rename temp file to final location (atomic)
         fs::rename(&temp_path, path)
             .map_err(|e| format!("Failed to rename identity file: {}", e))?;
+        integrity::finish_save(path, &data)?;
 
         // Set restrictive permissions (Unix-like systems)
         #[cfg(unix)]
@@ -174,14 +258,20 @@ impl Identity {
     pub fn x25519_secret(&self) -> &StaticSecret {
         &self.x25519_secret
     }
+
+    /// Key for encrypting the database at rest without a passphrase (see
+    /// `Storage::init_with_key`; with one, see `passphrase::unlock_db_keys`)
+    pub fn storage_key(&self) -> Zeroizing<[u8; 32]> {
+        let mut hasher = Sha256::new();
+        hasher.update(b"meshapp-storage-key");
+        hasher.update(self.ed25519_signing.as_bytes());
+        Zeroizing::new(hasher.finalize().into())
+    }
 }
 
 /// Get the storage path for identity file
 fn get_storage_path() -> Result<PathBuf, String> {
-    let data_dir = dirs::data_local_dir()
-        .ok_or("Failed to get data directory")?;
-    
-    Ok(data_dir.join("meshapp").join("identity.json"))
+    Ok(crate::storage::data_dir()?.join("identity.json"))
 }
 
 /// Get user_id as hex string
@@ -0,0 +1,193 @@
+//! Identity backup and restore
+//!
+//! Moving to a new phone means carrying the identity over; without it the
+//! user id, friendships and group memberships are lost. Two forms are offered:
+//!
+//! - an encrypted backup: JSON { version, kdf, m_cost, t_cost, p_cost, salt,
+//!   nonce, ciphertext } (hex fields) holding the identity file, sealed with
+//!   ChaCha20-Poly1305 under Argon2id(passphrase, salt)
+//! - a recovery phrase: both secret keys (64 bytes) as 48 words of the BIP39
+//!   English word list, with the BIP39 checksum (the first 16 bits of
+//!   SHA-256 of the keys) in the last word
+//!
+//! Either restores with `restore_identity` on a device that has no identity
+//! yet; an existing identity is never overwritten.
+
+use crate::error::MeshError;
+use crate::identity::Identity;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+/// Current backup format version
+pub const BACKUP_VERSION: u8 = 1;
+
+const KDF_NAME: &str = "argon2id";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Words in a recovery phrase (64 key bytes plus a 16-bit checksum, 11 bits a word)
+pub const PHRASE_WORDS: usize = 48;
+
+/// Argon2id cost parameters
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    /// Memory in KiB
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    /// 64 MiB, 3 passes, 1 lane
+    fn default() -> Self {
+        Self { m_cost: 64 * 1024, t_cost: 3, p_cost: 1 }
+    }
+}
+
+/// An identity sealed under a passphrase (hex-encoded binary fields)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IdentityBackup {
+    pub version: u8,
+    pub kdf: String,
+    #[serde(flatten)]
+    pub params: KdfParams,
+    pub salt: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], params: KdfParams) -> Result<[u8; 32], String> {
+    let params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))
+        .map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid KDF parameters: {}", e)))?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("Key derivation failed: {}", e))?;
+    Ok(key)
+}
+
+/// Seal an identity under a passphrase.
+pub fn export_encrypted(identity: &Identity, passphrase: &str, params: KdfParams) -> Result<IdentityBackup, String> {
+    if passphrase.is_empty() {
+        return Err(MeshError::InvalidArgument.raise("Passphrase is empty"));
+    }
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    crate::rng::rng().fill_bytes(&mut salt);
+    crate::rng::rng().fill_bytes(&mut nonce);
+    let key = derive_key(passphrase, &salt, params)?;
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&key))
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: &identity.key_bytes()?, aad: &[BACKUP_VERSION] })
+        .map_err(|_| "Failed to seal identity".to_string())?;
+    Ok(IdentityBackup {
+        version: BACKUP_VERSION,
+        kdf: KDF_NAME.to_string(),
+        params,
+        salt: hex::encode(salt),
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+    })
+}
+
+/// Open an encrypted backup with its passphrase.
+pub fn import_encrypted(backup: &IdentityBackup, passphrase: &str) -> Result<Identity, String> {
+    if backup.version != BACKUP_VERSION || backup.kdf != KDF_NAME {
+        return Err(MeshError::InvalidArgument.raise(format!("Unsupported backup format {} ({})", backup.version, backup.kdf)));
+    }
+    let salt = hex::decode(&backup.salt).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid salt: {}", e)))?;
+    let nonce = hex::decode(&backup.nonce).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid nonce: {}", e)))?;
+    let ciphertext = hex::decode(&backup.ciphertext).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid ciphertext: {}", e)))?;
+    if nonce.len() != NONCE_LEN {
+        return Err(MeshError::InvalidArgument.raise("Invalid nonce length"));
+    }
+    let key = derive_key(passphrase, &salt, backup.params)?;
+    let keys = ChaCha20Poly1305::new(Key::from_slice(&key))
+        .decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: &[BACKUP_VERSION] })
+        .map(Zeroizing::new)
+        .map_err(|_| MeshError::Crypto.raise("Wrong passphrase"))?;
+    Identity::from_key_bytes(&keys)
+}
+
+/// The recovery phrase of an identity (48 space-separated words).
+pub fn recovery_phrase(identity: &Identity) -> String {
+    let secrets = identity.secret_bytes();
+    let checksum = Sha256::digest(&secrets[..]);
+    let mut bits = Zeroizing::new(secrets.to_vec());
+    bits.extend_from_slice(&checksum[..2]);
+
+    let words = bip39::Language::English.word_list();
+    (0..PHRASE_WORDS)
+        .map(|i| {
+            let index = (0..11).fold(0usize, |acc, b| {
+                let bit = i * 11 + b;
+                (acc << 1) | ((bits[bit / 8] >> (7 - bit % 8)) & 1) as usize
+            });
+            words[index]
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Rebuild an identity from its recovery phrase (case and spacing are ignored).
+pub fn from_recovery_phrase(phrase: &str) -> Result<Identity, String> {
+    let words: Vec<String> = phrase.split_whitespace().map(str::to_lowercase).collect();
+    if words.len() != PHRASE_WORDS {
+        return Err(MeshError::InvalidArgument.raise(format!("Recovery phrase must have {} words", PHRASE_WORDS)));
+    }
+    let mut bits = Zeroizing::new([0u8; 66]);
+    for (i, word) in words.iter().enumerate() {
+        let index = bip39::Language::English
+            .find_word(word)
+            .ok_or_else(|| MeshError::InvalidArgument.raise(format!("Unknown word '{}'", word)))?;
+        for b in 0..11 {
+            if index & (1 << (10 - b)) != 0 {
+                let bit = i * 11 + b;
+                bits[bit / 8] |= 1 << (7 - bit % 8);
+            }
+        }
+    }
+    let mut secrets = Zeroizing::new([0u8; 64]);
+    secrets.copy_from_slice(&bits[..64]);
+    if Sha256::digest(&secrets[..])[..2] != bits[64..] {
+        return Err("Recovery phrase checksum mismatch".to_string());
+    }
+    Ok(Identity::from_secret_bytes(&secrets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backup_and_phrase_restore_the_identity() {
+        let identity = Identity::generate();
+        let params = KdfParams { m_cost: 256, t_cost: 1, p_cost: 1 };
+
+        let backup = export_encrypted(&identity, "correct horse", params).unwrap();
+        let json = serde_json::to_string(&backup).unwrap();
+        let parsed: IdentityBackup = serde_json::from_str(&json).unwrap();
+        assert!(import_encrypted(&parsed, "wrong horse").is_err());
+        let restored = import_encrypted(&parsed, "correct horse").unwrap();
+        assert_eq!(restored.secret_bytes(), identity.secret_bytes());
+        // Parse errors do not quote key material
+        let error = Identity::from_key_bytes(br#"{"ed25519_secret":"c0ffee","x25519_secret":[]}"#).err().unwrap();
+        assert!(!error.contains("c0ffee"));
+
+        let phrase = recovery_phrase(&identity);
+        assert_eq!(phrase.split(' ').count(), PHRASE_WORDS);
+        let restored = from_recovery_phrase(&format!("  {}\n", phrase.to_uppercase())).unwrap();
+        assert_eq!(restored.public().user_id, identity.public().user_id);
+
+        // A swapped word fails the checksum
+        let mut words: Vec<&str> = phrase.split(' ').collect();
+        words.swap(0, 1);
+        if words[0] != words[1] {
+            assert!(from_recovery_phrase(&words.join(" ")).is_err());
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Ingest backpressure
+//!
+//! Packets from platform transports are normally routed as soon as they
+//! are ingested. When the core is busy (router or storage held by another
+//! call) they wait in a bounded queue instead of blocking the BLE thread,
+//! and once the queue is full they are dropped. Every ingest reports which
+//! of these happened, and `metrics` exposes the queue depth so the
+//! platform layer can slow its reads while the core is saturated.
+//!
+//! Queued packets are charged to the memory budget; when it is exceeded the
+//! lowest-priority queued packets are dropped (see `memory_budget`). SOS
+//! messages, then urgent ones, jump the queue (see `sos`, `priority`).
+
+use crate::memory_budget::{self, Pool, BUDGET};
+use crate::priority;
+use crate::transport::Packet;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Packets waiting to be routed before new ones are dropped
+pub const INGEST_QUEUE_CAPACITY: usize = 512;
+
+/// Queue depth (fraction of capacity, in percent) reported as saturated
+const SATURATION_PERCENT: usize = 75;
+
+pub static INGEST: Lazy<IngestQueue> = Lazy::new(|| IngestQueue::new(INGEST_QUEUE_CAPACITY));
+
+/// What happened to an ingested packet (FFI return codes)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i32)]
+pub enum IngestStatus {
+    /// Routed immediately
+    Accepted = 0,
+    /// Core busy; queued and routed on the next ingest or `process_ingest_queue`
+    Queued = 1,
+    /// Queue full; the packet was discarded
+    DroppedOverloaded = 2,
+}
+
+/// Queue depth and counters since startup
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IngestMetrics {
+    pub depth: usize,
+    pub capacity: usize,
+    pub high_water: usize,
+    pub accepted: u64,
+    pub queued: u64,
+    pub dropped: u64,
+    /// The platform should slow its reads
+    pub saturated: bool,
+}
+
+struct QueueState {
+    packets: VecDeque<Packet>,
+    high_water: usize,
+    accepted: u64,
+    queued: u64,
+    dropped: u64,
+}
+
+pub struct IngestQueue {
+    capacity: usize,
+    state: Mutex<QueueState>,
+}
+
+impl IngestQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(QueueState {
+                packets: VecDeque::new(),
+                high_water: 0,
+                accepted: 0,
+                queued: 0,
+                dropped: 0,
+            }),
+        }
+    }
+
+    /// Queue a packet the core could not route right away.
+    /// SOS and urgent packets go ahead of the rest and, when the queue is
+    /// full, take the place of its lowest-priority packet if it ranks lower.
+    pub fn offer(&self, packet: Packet) -> IngestStatus {
+        let mut state = self.state.lock().unwrap();
+        let priority = priority::jumps_queue(&packet);
+        if state.packets.len() >= self.capacity {
+            let rank = memory_budget::packet_rank(&packet);
+            let victim = memory_budget::lowest_ranked(&state.packets)
+                .filter(|i| priority && memory_budget::packet_rank(&state.packets[*i]) < rank);
+            state.dropped += 1;
+            let Some(victim) = victim.and_then(|i| state.packets.remove(i)) else {
+                return IngestStatus::DroppedOverloaded;
+            };
+            BUDGET.release_shed(Pool::IngestQueue, memory_budget::packet_cost(&victim));
+        }
+        BUDGET.charge(Pool::IngestQueue, memory_budget::packet_cost(&packet));
+        let packet_id = packet.packet_id;
+        if priority {
+            let at = priority::queue_index(&state.packets, &packet);
+            state.packets.insert(at, packet);
+        } else {
+            state.packets.push_back(packet);
+        }
+        let shed = BUDGET.shed_queue(Pool::IngestQueue, &mut state.packets);
+        state.dropped += shed.count as u64;
+        let kept = if priority { state.packets.iter().any(|p| p.packet_id == packet_id) } else { !shed.newest };
+        if !kept {
+            return IngestStatus::DroppedOverloaded;
+        }
+        state.queued += 1;
+        state.high_water = state.high_water.max(state.packets.len());
+        IngestStatus::Queued
+    }
+
+    /// Count packets routed immediately.
+    pub fn record_accepted(&self) {
+        self.state.lock().unwrap().accepted += 1;
+    }
+
+    /// Take every queued packet, oldest first.
+    pub fn drain(&self) -> Vec<Packet> {
+        let packets: Vec<Packet> = self.state.lock().unwrap().packets.drain(..).collect();
+        BUDGET.release(Pool::IngestQueue, packets.iter().map(memory_budget::packet_cost).sum());
+        packets
+    }
+
+    pub fn metrics(&self) -> IngestMetrics {
+        let state = self.state.lock().unwrap();
+        IngestMetrics {
+            depth: state.packets.len(),
+            capacity: self.capacity,
+            high_water: state.high_water,
+            accepted: state.accepted,
+            queued: state.queued,
+            dropped: state.dropped,
+            saturated: state.packets.len() * 100 >= self.capacity * SATURATION_PERCENT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::PacketKind;
+
+    #[test]
+    fn test_queue_reports_saturation_and_drops_when_full() {
+        let queue = IngestQueue::new(4);
+        let packet = Packet {
+            packet_id: [1u8; 32],
+            channel_id: [2u8; 32],
+            kind: PacketKind::Message,
+            ttl: 3,
+            payload: vec![1, 2, 3],
+            priority: priority::Priority::Normal,
+        };
+
+        for _ in 0..3 {
+            assert_eq!(queue.offer(packet.clone()), IngestStatus::Queued);
+        }
+        assert!(queue.metrics().saturated);
+        assert_eq!(queue.offer(packet.clone()), IngestStatus::Queued);
+        assert_eq!(queue.offer(packet), IngestStatus::DroppedOverloaded);
+
+        assert_eq!(queue.drain().len(), 4);
+        let metrics = queue.metrics();
+        assert_eq!((metrics.depth, metrics.high_water, metrics.dropped), (0, 4, 1));
+        assert!(!metrics.saturated);
+    }
+}
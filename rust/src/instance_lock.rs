@@ -0,0 +1,110 @@
+//! Single-instance lock
+//!
+//! Two processes writing one data directory (the app and its background
+//! service, say) can corrupt the identity files and the database. The first
+//! process to load anything takes an advisory lock on `meshapp.lock` in the
+//! root data directory and writes its PID there. Another process then fails
+//! with "Data directory already in use by PID n" (error code `in_use`) until
+//! the first one exits; the OS drops the lock even if it crashes, so a stale
+//! file never blocks anyone.
+//!
+//! A second process that only needs to read can attach read-only instead
+//! (see `storage::enter_read_only_mode`): it takes no lock, opens the
+//! database read-only and never writes a file.
+
+use crate::error::MeshError;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Lock file in the root data directory
+pub const LOCK_FILE: &str = "meshapp.lock";
+
+/// The lock this process holds, with the root it covers
+static HELD: Mutex<Option<(PathBuf, File)>> = Mutex::new(None);
+
+/// Take the lock on `root` for this process (a no-op if it holds it already).
+pub fn acquire(root: &Path) -> Result<(), String> {
+    let mut held = HELD.lock().unwrap();
+    if held.as_ref().is_some_and(|(locked, _)| locked == root) {
+        return Ok(());
+    }
+    std::fs::create_dir_all(root).map_err(|e| format!("Failed to create data directory: {}", e))?;
+    let path = root.join(LOCK_FILE);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(|e| format!("Failed to open lock file: {}", e))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            return Err(match holder(root) {
+                Some(pid) => MeshError::InUse.raise(format!("Data directory already in use by PID {}", pid)),
+                None => MeshError::InUse.raise("Data directory already in use by another process"),
+            });
+        }
+        Err(TryLockError::Error(e)) => return Err(format!("Failed to lock data directory: {}", e)),
+    }
+    file.set_len(0)
+        .and_then(|_| file.seek(SeekFrom::Start(0)))
+        .and_then(|_| writeln!(file, "{}", std::process::id()))
+        .and_then(|_| file.sync_all())
+        .map_err(|e| format!("Failed to write lock file: {}", e))?;
+    // Dropping the file of another root releases its lock
+    *held = Some((root.to_path_buf(), file));
+    Ok(())
+}
+
+/// PID written by the process holding (or last holding) the lock on `root`.
+pub fn holder(root: &Path) -> Option<u32> {
+    let mut contents = String::new();
+    File::open(root.join(LOCK_FILE)).ok()?.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+/// Release the lock, if this process holds one.
+pub fn release() {
+    HELD.lock().unwrap().take();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_holder_is_told_the_pid() {
+        let root = std::env::temp_dir().join(format!("meshapp-instance-lock-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+
+        acquire(&root).unwrap();
+        acquire(&root).unwrap();
+        assert_eq!(holder(&root), Some(std::process::id()));
+
+        // Another open file description stands in for a second process
+        let other = File::open(root.join(LOCK_FILE)).unwrap();
+        assert!(matches!(other.try_lock(), Err(TryLockError::WouldBlock)));
+        release();
+        other.try_lock().unwrap();
+        let error = acquire(&root).unwrap_err();
+        assert_eq!(error, format!("Data directory already in use by PID {}", std::process::id()));
+        drop(other);
+        acquire(&root).unwrap();
+
+        // A second process can still attach read-only
+        let db_path = root.join("mesh.db");
+        let writer = crate::storage::Storage::init(&db_path).unwrap();
+        writer.upsert_channel([1u8; 32], "geo").unwrap();
+        let reader = crate::storage::Storage::open_read_only(&db_path, None).unwrap();
+        assert_eq!(reader.get_channel_type([1u8; 32]).unwrap().as_deref(), Some("geo"));
+        let denied = reader.upsert_channel([2u8; 32], "geo").unwrap_err();
+        assert_eq!(crate::error::code_of(&denied), crate::error::MeshError::InUse);
+        drop((reader, writer));
+        release();
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
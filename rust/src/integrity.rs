@@ -0,0 +1,184 @@
+//! Integrity of the identity and friends files
+//!
+//! `identity.json` and `friends.json` are the two files whose loss cannot be
+//! undone from the network. Each has a checksum file next to it
+//! (`identity.sum`, `friends.sum`) holding the SHA-256 of its contents, and
+//! loading verifies it:
+//! - contents that do not match are reported as failing the integrity check
+//!   instead of being parsed, or worse, replaced
+//! - a missing file whose checksum file exists was saved before and has been
+//!   lost, so no fresh identity (or empty friends list) is created over it
+//! - files from before checksums, or restored without one, are accepted and
+//!   get a checksum on load
+//!
+//! Saves write the checksum file first, listing both the new and the current
+//! checksum, then the file itself, then the checksum file with the new one
+//! only, so a crash at any point leaves a file its checksum file accepts.
+//!
+//! A failure is reported with an `integrity_failed` event { file, reason }
+//! and a `corrupted` error code; the app then offers to `accept` the file as
+//! it is (restored by hand), or to `set_aside` the damaged file, renamed to
+//! `<name>.json.damaged-<time>`, and restore the identity from a backup or
+//! recovery phrase (or start a new friends list).
+//!
+//! Friends now live in the database; friends.json is only read to import
+//! the list of an older version once (see `friends::import_legacy_file`).
+
+use crate::error::MeshError;
+use crate::events;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Files under integrity protection, by name
+pub const PROTECTED_FILES: [&str; 2] = ["identity", "friends"];
+
+/// The checksum file of `path` (`identity.json` -> `identity.sum`)
+fn checksum_path(path: &Path) -> PathBuf {
+    path.with_extension("sum")
+}
+
+fn checksum(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn read_checksums(path: &Path) -> Result<Option<Vec<String>>, String> {
+    match fs::read_to_string(checksum_path(path)) {
+        Ok(text) => Ok(Some(text.lines().map(str::to_string).collect())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("Failed to read checksum file: {}", e)),
+    }
+}
+
+fn write_checksums(path: &Path, checksums: &[String]) -> Result<(), String> {
+    let target = checksum_path(path);
+    let temp = target.with_extension("sum.new");
+    fs::write(&temp, checksums.join("\n")).map_err(|e| format!("Failed to write checksum file: {}", e))?;
+    fs::rename(&temp, &target).map_err(|e| format!("Failed to rename checksum file: {}", e))
+}
+
+/// Drop the checksum of a file moved away on purpose, which would otherwise
+/// count as lost.
+pub fn remove_checksums(path: &Path) -> Result<(), String> {
+    match fs::remove_file(checksum_path(path)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(format!("Failed to remove checksum file: {}", e)),
+        _ => Ok(()),
+    }
+}
+
+fn file_label(path: &Path) -> String {
+    path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+/// Report a failed check to the app and return the error to surface.
+fn integrity_failure(path: &Path, reason: &str) -> String {
+    let file = file_label(path);
+    events::emit("integrity_failed", serde_json::json!({ "file": file, "reason": reason }));
+    MeshError::Corrupted.raise(format!("{} failed its integrity check ({})", file, reason))
+}
+
+/// Check a file read from `path`. Files without a checksum get one.
+pub fn verify(path: &Path, data: &[u8]) -> Result<(), String> {
+    match read_checksums(path)? {
+        Some(sums) if sums.contains(&checksum(data)) => Ok(()),
+        Some(_) => Err(integrity_failure(path, "contents changed")),
+        None => write_checksums(path, &[checksum(data)]),
+    }
+}
+
+/// Check that a missing file was never saved (so creating it loses nothing).
+pub fn verify_missing(path: &Path) -> Result<(), String> {
+    match read_checksums(path)? {
+        Some(_) => Err(integrity_failure(path, "file missing")),
+        None => Ok(()),
+    }
+}
+
+/// Before replacing the file at `path` with `data`: accept both contents.
+pub fn begin_save(path: &Path, data: &[u8]) -> Result<(), String> {
+    let mut sums = vec![checksum(data)];
+    if let Ok(current) = fs::read(path) {
+        sums.push(checksum(&current));
+    }
+    write_checksums(path, &sums)
+}
+
+/// After the file at `path` was replaced with `data`: accept only it.
+pub fn finish_save(path: &Path, data: &[u8]) -> Result<(), String> {
+    write_checksums(path, &[checksum(data)])
+}
+
+fn protected_path(dir: &Path, name: &str) -> Result<PathBuf, String> {
+    if !PROTECTED_FILES.contains(&name) {
+        return Err(MeshError::NotFound.raise(format!("Unknown protected file '{}'", name)));
+    }
+    Ok(dir.join(format!("{}.json", name)))
+}
+
+/// Accept a protected file as it is now (e.g. copied back from a backup).
+pub fn accept(dir: &Path, name: &str) -> Result<(), String> {
+    let path = protected_path(dir, name)?;
+    match fs::read(&path) {
+        Ok(data) => write_checksums(&path, &[checksum(&data)]),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => remove_checksums(&path),
+        Err(e) => Err(format!("Failed to read {}: {}", file_label(&path), e)),
+    }
+}
+
+/// Move a damaged protected file out of the way, keeping it for inspection.
+/// Returns the name it was moved to (None if it was missing).
+pub fn set_aside(dir: &Path, name: &str, now: i64) -> Result<Option<String>, String> {
+    let path = protected_path(dir, name)?;
+    let moved = if path.exists() {
+        let aside = dir.join(format!("{}.json.damaged-{}", name, now));
+        fs::rename(&path, &aside).map_err(|e| format!("Failed to set {} aside: {}", file_label(&path), e))?;
+        Some(file_label(&aside))
+    } else {
+        None
+    };
+    remove_checksums(&path)?;
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_damaged_and_lost_files_are_refused() {
+        let dir = std::env::temp_dir().join(format!("meshapp-integrity-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("identity.json");
+
+        // A file from before checksums is accepted and gets one
+        fs::write(&path, b"v1").unwrap();
+        verify(&path, b"v1").unwrap();
+        assert!(verify(&path, b"v2").is_err());
+
+        // Interrupted save: both the old and the new contents pass
+        begin_save(&path, b"v2").unwrap();
+        verify(&path, b"v1").unwrap();
+        verify(&path, b"v2").unwrap();
+        fs::write(&path, b"v2").unwrap();
+        finish_save(&path, b"v2").unwrap();
+        assert!(verify(&path, b"v1").unwrap_err().contains("integrity check"));
+
+        // A lost file is not silently recreated
+        fs::remove_file(&path).unwrap();
+        assert!(verify_missing(&path).is_err());
+        assert_eq!(set_aside(&dir, "identity", 100).unwrap(), None);
+        verify_missing(&path).unwrap();
+
+        // Damaged contents can be accepted, or set aside
+        fs::write(&path, b"edited").unwrap();
+        finish_save(&path, b"v3").unwrap();
+        accept(&dir, "identity").unwrap();
+        verify(&path, b"edited").unwrap();
+        assert_eq!(set_aside(&dir, "identity", 200).unwrap().as_deref(), Some("identity.json.damaged-200"));
+        assert!(!path.exists() && dir.join("identity.json.damaged-200").exists());
+        assert!(accept(&dir, "keys").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
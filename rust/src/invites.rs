@@ -0,0 +1,294 @@
+//! Channel invites
+//!
+//! An invite lets someone join a geo room or protected group by scanning a
+//! QR code or opening a link. The payload is JSON signed by the inviter's
+//! Ed25519 key:
+//! - channel_id, channel_type ("geo" | "group") and an optional display name
+//! - channel_key: the 32-byte key of a protected channel, if it has one
+//! - inviter_ed25519_public: who issued the invite (user_id = SHA256 of it)
+//! - created_at / expires_at (UNIX seconds; no expiry if absent)
+//!
+//! Anyone holding the invite can join, so invites carrying a key should be
+//! shared like the key itself.
+
+use crate::codec;
+use crate::error::MeshError;
+use crate::events;
+use crate::identity::Identity;
+use crate::storage::Storage;
+use crate::system_messages::{self, SystemEvent};
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Current invite format version
+pub const INVITE_VERSION: u8 = 1;
+
+/// Channel types that can be shared with an invite
+pub const INVITE_CHANNEL_TYPES: &[&str] = &["geo", "group"];
+
+/// Longest accepted channel name, in bytes
+const MAX_NAME_LEN: usize = 64;
+
+/// Signed invite payload (hex-encoded binary fields)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Invite {
+    pub version: u8,
+    pub channel_id: String,
+    pub channel_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_key: Option<String>,
+    pub inviter_ed25519_public: String,
+    pub created_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    pub signature: String,
+}
+
+/// Result of accepting an invite
+#[derive(Serialize, Debug)]
+pub struct AcceptedInvite {
+    pub channel_id: String,
+    pub channel_type: String,
+    pub name: Option<String>,
+    pub protected: bool,
+    pub inviter_user_id: String,
+}
+
+/// Create a random protected group channel and store its key.
+/// Returns the new channel id.
+pub fn create_protected_channel(storage: &Storage, name: Option<&str>, now: i64) -> Result<[u8; 32], String> {
+    use rand::RngCore;
+
+    if let Some(name) = name {
+        check_name(name)?;
+    }
+    let mut channel_id = [0u8; 32];
+    let mut key = [0u8; 32];
+    crate::rng::rng().fill_bytes(&mut channel_id);
+    crate::rng::rng().fill_bytes(&mut key);
+
+    storage.upsert_channel(channel_id, "group")?;
+    storage.set_channel_key(channel_id, key, now)?;
+    key_escrow_changed(channel_id);
+    if let Some(name) = name {
+        storage.set_channel_name(channel_id, name)?;
+    }
+    Ok(channel_id)
+}
+
+/// Tell the app a new channel key exists, so it can refresh the key escrow
+/// it keeps with the identity backup (see `key_escrow`).
+pub fn key_escrow_changed(channel_id: [u8; 32]) {
+    events::emit("key_escrow_changed", serde_json::json!({ "channel_id": hex::encode(channel_id) }));
+}
+
+/// Create a signed invite for a channel. The channel's key is included if
+/// it is protected.
+pub fn create(
+    identity: &Identity,
+    storage: &Storage,
+    channel_id: [u8; 32],
+    channel_type: &str,
+    name: Option<&str>,
+    expires_at: Option<i64>,
+    now: i64,
+) -> Result<Invite, String> {
+    check_channel_type(channel_type)?;
+    if let Some(name) = name {
+        check_name(name)?;
+    }
+
+    let channel_key = storage.get_channel_key(channel_id)?;
+    let inviter = identity.public().ed25519_public.to_bytes();
+    let message = signing_bytes(
+        INVITE_VERSION,
+        &channel_id,
+        channel_type,
+        name,
+        channel_key.as_ref(),
+        &inviter,
+        now,
+        expires_at,
+    );
+    let signature = identity.ed25519_signing_key().sign(&message);
+
+    Ok(Invite {
+        version: INVITE_VERSION,
+        channel_id: hex::encode(channel_id),
+        channel_type: channel_type.to_string(),
+        name: name.map(str::to_string),
+        channel_key: channel_key.map(hex::encode),
+        inviter_ed25519_public: hex::encode(inviter),
+        created_at: now,
+        expires_at,
+        signature: hex::encode(signature.to_bytes()),
+    })
+}
+
+/// Invite fields after signature and expiry checks
+pub struct VerifiedInvite {
+    pub channel_id: [u8; 32],
+    pub channel_key: Option<[u8; 32]>,
+    pub inviter_user_id: [u8; 32],
+}
+
+/// Check an invite's format, expiry and inviter signature.
+pub fn verify(invite: &Invite, now: i64) -> Result<VerifiedInvite, String> {
+    if invite.version != INVITE_VERSION {
+        return Err(MeshError::InvalidArgument.raise(format!("Unsupported invite version: {}", invite.version)));
+    }
+    check_channel_type(&invite.channel_type)?;
+    if let Some(ref name) = invite.name {
+        check_name(name)?;
+    }
+    if invite.expires_at.is_some_and(|t| t <= now) {
+        return Err("Invite has expired".to_string());
+    }
+
+    let channel_id = codec::parse_id_hex(&invite.channel_id, "channel id")?;
+    let channel_key = invite.channel_key.as_deref().map(|k| codec::parse_id_hex(k, "channel key")).transpose()?;
+    let inviter = codec::parse_id_hex(&invite.inviter_ed25519_public, "inviter key")?;
+    let signature_bytes: [u8; 64] = codec::parse_hex_array(&invite.signature, "signature")?;
+
+    let verifying_key = VerifyingKey::from_bytes(&inviter).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid inviter key: {}", e)))?;
+    let message = signing_bytes(
+        invite.version,
+        &channel_id,
+        &invite.channel_type,
+        invite.name.as_deref(),
+        channel_key.as_ref(),
+        &inviter,
+        invite.created_at,
+        invite.expires_at,
+    );
+    verifying_key
+        .verify(&message, &Signature::from_bytes(&signature_bytes))
+        .map_err(|_| MeshError::Crypto.raise("Invalid invite signature"))?;
+
+    Ok(VerifiedInvite {
+        channel_id,
+        channel_key,
+        inviter_user_id: Sha256::digest(inviter).into(),
+    })
+}
+
+/// Verify an invite and join its channel: registers the channel, stores
+/// its key (protected channels), notes the join in the channel history and
+/// emits a `channel_joined` event.
+pub fn accept(storage: &Storage, invite: &Invite, own_user_id: [u8; 32], now: i64) -> Result<AcceptedInvite, String> {
+    let verified = verify(invite, now)?;
+
+    storage.upsert_channel(verified.channel_id, &invite.channel_type)?;
+    if let Some(key) = verified.channel_key {
+        if storage.set_channel_key(verified.channel_id, key, now)? {
+            key_escrow_changed(verified.channel_id);
+        }
+    }
+    if let Some(ref name) = invite.name {
+        storage.set_channel_name(verified.channel_id, name)?;
+    }
+
+    system_messages::record(
+        storage,
+        verified.channel_id,
+        &SystemEvent::MemberJoined {
+            user_id: hex::encode(own_user_id),
+        },
+        now,
+    )?;
+
+    let accepted = AcceptedInvite {
+        channel_id: hex::encode(verified.channel_id),
+        channel_type: invite.channel_type.clone(),
+        name: invite.name.clone(),
+        protected: verified.channel_key.is_some(),
+        inviter_user_id: hex::encode(verified.inviter_user_id),
+    };
+    events::emit(
+        "channel_joined",
+        serde_json::to_value(&accepted).unwrap_or(serde_json::Value::Null),
+    );
+    Ok(accepted)
+}
+
+/// Bytes covered by the inviter's signature (domain-separated, length-prefixed).
+#[allow(clippy::too_many_arguments)]
+fn signing_bytes(
+    version: u8,
+    channel_id: &[u8; 32],
+    channel_type: &str,
+    name: Option<&str>,
+    channel_key: Option<&[u8; 32]>,
+    inviter: &[u8; 32],
+    created_at: i64,
+    expires_at: Option<i64>,
+) -> Vec<u8> {
+    let mut out = b"meshapp-invite".to_vec();
+    out.push(version);
+    out.extend_from_slice(channel_id);
+    out.push(channel_type.len() as u8);
+    out.extend_from_slice(channel_type.as_bytes());
+    let name = name.unwrap_or("");
+    out.push(name.len() as u8);
+    out.extend_from_slice(name.as_bytes());
+    match channel_key {
+        Some(key) => {
+            out.push(1);
+            out.extend_from_slice(key);
+        }
+        None => out.push(0),
+    }
+    out.extend_from_slice(inviter);
+    out.extend_from_slice(&created_at.to_be_bytes());
+    match expires_at {
+        Some(t) => {
+            out.push(1);
+            out.extend_from_slice(&t.to_be_bytes());
+        }
+        None => out.push(0),
+    }
+    out
+}
+
+fn check_channel_type(channel_type: &str) -> Result<(), String> {
+    if INVITE_CHANNEL_TYPES.contains(&channel_type) {
+        Ok(())
+    } else {
+        Err(MeshError::Rejected.raise(format!("Channel type cannot be shared by invite: {}", channel_type)))
+    }
+}
+
+pub fn check_name(name: &str) -> Result<(), String> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(MeshError::InvalidArgument.raise(format!("Channel name longer than {} bytes", MAX_NAME_LEN)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invite_roundtrip_and_tamper() {
+        let storage = Storage::in_memory().unwrap();
+        let identity = Identity::generate();
+
+        let channel_id = create_protected_channel(&storage, Some("Hikers"), 100).unwrap();
+        let invite = create(&identity, &storage, channel_id, "group", Some("Hikers"), Some(1000), 100).unwrap();
+        assert!(invite.channel_key.is_some());
+
+        let own = identity.public().user_id;
+        let accepted = accept(&storage, &invite, own, 200).unwrap();
+        assert!(accepted.protected);
+        assert_eq!(accepted.inviter_user_id, hex::encode(identity.public().user_id));
+
+        assert!(accept(&storage, &invite, own, 1000).is_err());
+        let mut tampered = invite.clone();
+        tampered.name = Some("Other".to_string());
+        assert!(accept(&storage, &tampered, own, 200).is_err());
+    }
+}
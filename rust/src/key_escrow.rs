@@ -0,0 +1,152 @@
+//! Channel key escrow
+//!
+//! A protected channel's key changes when a member is re-invited with a new
+//! one. Every key a channel has had stays in `channel_key_epochs`, so history
+//! from before the change can still be read. The escrow is that list sealed
+//! under our identity: the app keeps it with the identity backup (it is
+//! opaque without the identity). A member who clears app data and restores
+//! the identity imports the escrow to read the old group history again.
+//!
+//! Format: version (1 byte) || nonce (12 bytes) || ChaCha20-Poly1305 of the
+//! JSON entries, keyed with SHA256("meshapp-key-escrow" || X25519 secret),
+//! with our user_id as associated data.
+
+use crate::codec;
+use crate::error::MeshError;
+use crate::identity::Identity;
+use crate::storage::{ChannelKeyEpochRow, Storage};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Current escrow format version
+pub const ESCROW_VERSION: u8 = 1;
+
+const NONCE_LEN: usize = 12;
+
+/// One escrowed key (hex-encoded binary fields)
+#[derive(Serialize, Deserialize, Debug)]
+struct EscrowEntry {
+    channel_id: String,
+    key: String,
+    added_at: i64,
+}
+
+/// Outcome of an escrow import
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub struct EscrowImport {
+    /// Keys in the escrow
+    pub keys: usize,
+    /// Keys this device did not have yet
+    pub restored: usize,
+}
+
+fn escrow_cipher(identity: &Identity) -> ChaCha20Poly1305 {
+    let mut hasher = Sha256::new();
+    hasher.update(b"meshapp-key-escrow");
+    hasher.update(identity.x25519_secret().as_bytes());
+    let key: [u8; 32] = hasher.finalize().into();
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+/// Seal key epochs under the identity.
+pub fn seal(identity: &Identity, rows: &[ChannelKeyEpochRow]) -> Result<Vec<u8>, String> {
+    let entries: Vec<EscrowEntry> = rows
+        .iter()
+        .map(|row| EscrowEntry {
+            channel_id: hex::encode(row.channel_id),
+            key: hex::encode(row.key),
+            added_at: row.added_at,
+        })
+        .collect();
+    let json = serde_json::to_vec(&entries).map_err(|e| format!("Failed to serialize key escrow: {}", e))?;
+
+    let mut nonce = [0u8; NONCE_LEN];
+    crate::rng::rng().fill_bytes(&mut nonce);
+    let ciphertext = escrow_cipher(identity)
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload { msg: &json, aad: &identity.public().user_id },
+        )
+        .map_err(|_| MeshError::Crypto.raise("Failed to encrypt key escrow"))?;
+
+    let mut out = Vec::with_capacity(1 + NONCE_LEN + ciphertext.len());
+    out.push(ESCROW_VERSION);
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Open an escrow sealed by `seal` with the same identity.
+pub fn open(identity: &Identity, sealed: &[u8]) -> Result<Vec<ChannelKeyEpochRow>, String> {
+    if sealed.len() < 1 + NONCE_LEN {
+        return Err(MeshError::InvalidArgument.raise("Key escrow too short"));
+    }
+    if sealed[0] != ESCROW_VERSION {
+        return Err(MeshError::InvalidArgument.raise(format!("Unsupported key escrow version {}", sealed[0])));
+    }
+    let (nonce, ciphertext) = sealed[1..].split_at(NONCE_LEN);
+    let json = escrow_cipher(identity)
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload { msg: ciphertext, aad: &identity.public().user_id },
+        )
+        .map_err(|_| "Key escrow was not sealed by this identity".to_string())?;
+
+    let entries: Vec<EscrowEntry> =
+        serde_json::from_slice(&json).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid key escrow: {}", e)))?;
+    entries
+        .iter()
+        .map(|entry| {
+            Ok(ChannelKeyEpochRow {
+                channel_id: codec::parse_id_hex(&entry.channel_id, "channel id")?,
+                key: codec::parse_id_hex(&entry.key, "channel key")?,
+                added_at: entry.added_at,
+            })
+        })
+        .collect()
+}
+
+/// Seal every stored key epoch.
+pub fn export(identity: &Identity, storage: &Storage) -> Result<Vec<u8>, String> {
+    seal(identity, &storage.list_all_channel_key_epochs()?)
+}
+
+/// Restore the key epochs of an escrow into storage.
+pub fn import(identity: &Identity, storage: &Storage, sealed: &[u8]) -> Result<EscrowImport, String> {
+    let rows = open(identity, sealed)?;
+    let mut outcome = EscrowImport { keys: rows.len(), restored: 0 };
+    for row in &rows {
+        if storage.restore_channel_key_epoch(row)? {
+            outcome.restored += 1;
+        }
+    }
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escrow_opens_only_with_its_identity() {
+        let (owner, other) = (Identity::generate(), Identity::generate());
+        let rows = vec![
+            ChannelKeyEpochRow { channel_id: [1u8; 32], key: [2u8; 32], added_at: 100 },
+            ChannelKeyEpochRow { channel_id: [1u8; 32], key: [3u8; 32], added_at: 200 },
+        ];
+        let sealed = seal(&owner, &rows).unwrap();
+
+        assert_eq!(open(&owner, &sealed).unwrap(), rows);
+        assert!(open(&other, &sealed).is_err());
+
+        // A fresh install re-joined with a newer key keeps it current
+        let storage = Storage::in_memory().unwrap();
+        storage.set_channel_key([1u8; 32], [4u8; 32], 300).unwrap();
+        assert_eq!(import(&owner, &storage, &sealed).unwrap(), EscrowImport { keys: 2, restored: 2 });
+        assert_eq!(storage.list_channel_key_epochs([1u8; 32]).unwrap().len(), 3);
+        assert_eq!(storage.get_channel_key([1u8; 32]).unwrap(), Some([4u8; 32]));
+    }
+}
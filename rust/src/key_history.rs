@@ -0,0 +1,230 @@
+//! Key transparency
+//!
+//! Each user keeps an append-only chain of key epochs. Epoch 0 is the
+//! identity's first key; every later epoch is a key rotation. An entry is
+//! signed by its own key (proving possession) and, after the first, by the
+//! previous epoch's key over a hash of the previous entry (linking the
+//! rotation to the old key). Friends fetch the chain and check that it
+//! verifies and extends what they saw before, so a forged "key rotation"
+//! that the old key never signed, or a rewritten history, is detected.
+//!
+//! Chains are JSON with hex fields and are identified by the user_id of
+//! their first key.
+
+use crate::batch_verify::{self, SignedItem};
+use crate::codec;
+use crate::error::MeshError;
+use crate::identity::Identity;
+use ed25519_dalek::{Signer, SigningKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Current chain format version
+pub const KEY_HISTORY_VERSION: u8 = 1;
+
+/// One key epoch (hex-encoded binary fields)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyEpoch {
+    pub epoch: u32,
+    pub ed25519_public: String,
+    pub x25519_public: String,
+    pub created_at: i64,
+    /// SHA256 of the previous entry (zeros for epoch 0)
+    pub prev_hash: String,
+    /// Signature by this epoch's key
+    pub signature: String,
+    /// Signature by the previous epoch's key (None for epoch 0)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_signature: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyHistory {
+    pub version: u8,
+    pub epochs: Vec<KeyEpoch>,
+}
+
+/// Outcome of checking a chain
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedHistory {
+    /// user_id of the first key (identifies the chain)
+    pub owner_user_id: String,
+    pub epochs: usize,
+    pub current_ed25519_public: String,
+}
+
+fn signing_bytes(epoch: u32, ed25519: &[u8; 32], x25519: &[u8; 32], created_at: i64, prev_hash: &[u8; 32]) -> Vec<u8> {
+    let mut out = b"meshapp-key-epoch".to_vec();
+    out.push(KEY_HISTORY_VERSION);
+    out.extend_from_slice(&epoch.to_be_bytes());
+    out.extend_from_slice(ed25519);
+    out.extend_from_slice(x25519);
+    out.extend_from_slice(&created_at.to_be_bytes());
+    out.extend_from_slice(prev_hash);
+    out
+}
+
+/// Hash of an entry, as referenced by the next one.
+fn entry_hash(entry: &KeyEpoch) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"meshapp-key-epoch-hash");
+    hasher.update(entry.epoch.to_be_bytes());
+    hasher.update(entry.ed25519_public.as_bytes());
+    hasher.update(entry.x25519_public.as_bytes());
+    hasher.update(entry.created_at.to_be_bytes());
+    hasher.update(entry.prev_hash.as_bytes());
+    hasher.update(entry.signature.as_bytes());
+    hasher.update(entry.link_signature.as_deref().unwrap_or("").as_bytes());
+    hasher.finalize().into()
+}
+
+fn user_id_of(ed25519: &[u8; 32]) -> [u8; 32] {
+    Sha256::digest(ed25519).into()
+}
+
+impl KeyHistory {
+    /// Start a chain with the identity's current key as epoch 0.
+    pub fn genesis(identity: &Identity, now: i64) -> Self {
+        let mut history = Self {
+            version: KEY_HISTORY_VERSION,
+            epochs: Vec::new(),
+        };
+        history.push(identity, None, now);
+        history
+    }
+
+    /// Append a rotation to `next`, signed by the current key `current`.
+    #[allow(dead_code)] // Used once identity key rotation exists
+    pub fn rotate(&mut self, current: &Identity, next: &Identity, now: i64) -> Result<(), String> {
+        let last = self.epochs.last().ok_or_else(|| MeshError::InvalidArgument.raise("Empty key history"))?;
+        if codec::parse_id_hex(&last.ed25519_public, "epoch key")? != *current.public().ed25519_public.as_bytes() {
+            return Err(MeshError::InvalidArgument.raise("Rotation must be signed by the current key"));
+        }
+        self.push(next, Some(current.ed25519_signing_key()), now);
+        Ok(())
+    }
+
+    fn push(&mut self, identity: &Identity, previous: Option<&SigningKey>, now: i64) {
+        let epoch = self.epochs.len() as u32;
+        let prev_hash = self.epochs.last().map(entry_hash).unwrap_or([0u8; 32]);
+        let ed25519 = *identity.public().ed25519_public.as_bytes();
+        let x25519 = *identity.public().x25519_public.as_bytes();
+        let msg = signing_bytes(epoch, &ed25519, &x25519, now, &prev_hash);
+        self.epochs.push(KeyEpoch {
+            epoch,
+            ed25519_public: hex::encode(ed25519),
+            x25519_public: hex::encode(x25519),
+            created_at: now,
+            prev_hash: hex::encode(prev_hash),
+            signature: hex::encode(identity.ed25519_signing_key().sign(&msg).to_bytes()),
+            link_signature: previous.map(|key| hex::encode(key.sign(&msg).to_bytes())),
+        });
+    }
+
+    /// Check every signature and link of the chain. Signatures are checked
+    /// as one batch (see `batch_verify`).
+    pub fn verify(&self) -> Result<VerifiedHistory, String> {
+        if self.version != KEY_HISTORY_VERSION {
+            return Err(MeshError::InvalidArgument.raise(format!("Unsupported key history version {}", self.version)));
+        }
+        let first = self.epochs.first().ok_or_else(|| MeshError::InvalidArgument.raise("Empty key history"))?;
+
+        // (epoch key, signing bytes, signature, link signature) per entry
+        let mut signed = Vec::with_capacity(self.epochs.len());
+        let mut previous: Option<&KeyEpoch> = None;
+        for (i, entry) in self.epochs.iter().enumerate() {
+            if entry.epoch as usize != i {
+                return Err(format!("Epoch {} out of order", entry.epoch));
+            }
+            let ed25519 = codec::parse_id_hex(&entry.ed25519_public, "epoch key")?;
+            let x25519 = codec::parse_id_hex(&entry.x25519_public, "epoch x25519 key")?;
+            let prev_hash = codec::parse_id_hex(&entry.prev_hash, "previous epoch hash")?;
+            let signature: [u8; 64] = codec::parse_hex_array(&entry.signature, "epoch signature")?;
+
+            let link = match previous {
+                None => {
+                    if prev_hash != [0u8; 32] || entry.link_signature.is_some() {
+                        return Err(MeshError::InvalidArgument.raise("First epoch must not link to a previous key"));
+                    }
+                    None
+                }
+                Some(prev) => {
+                    if prev_hash != entry_hash(prev) || entry.created_at < prev.created_at {
+                        return Err(format!("Epoch {} does not follow epoch {}", entry.epoch, prev.epoch));
+                    }
+                    let link = entry.link_signature.as_deref().ok_or_else(|| {
+                        format!("Rotation to epoch {} is not signed by the previous key", entry.epoch)
+                    })?;
+                    let prev_key = codec::parse_id_hex(&prev.ed25519_public, "epoch key")?;
+                    Some((prev_key, codec::parse_hex_array::<64>(link, "rotation signature")?))
+                }
+            };
+            let msg = signing_bytes(entry.epoch, &ed25519, &x25519, entry.created_at, &prev_hash);
+            signed.push((ed25519, msg, signature, link));
+            previous = Some(entry);
+        }
+
+        // Items: each epoch's own signature, then its link signature
+        let mut items = Vec::with_capacity(2 * signed.len());
+        let mut labels = Vec::with_capacity(2 * signed.len());
+        for (epoch, (key, msg, signature, link)) in signed.iter().enumerate() {
+            items.push(SignedItem { public_key: *key, message: msg, signature: *signature });
+            labels.push(format!("Epoch {} signature does not verify", epoch));
+            if let Some((prev_key, link_signature)) = link {
+                items.push(SignedItem { public_key: *prev_key, message: msg, signature: *link_signature });
+                labels.push(format!("Rotation to epoch {} is not signed by the previous key", epoch));
+            }
+        }
+        if let Some(&bad) = batch_verify::verify_batch(&items).first() {
+            return Err(labels[bad].clone());
+        }
+
+        let first_key = codec::parse_id_hex(&first.ed25519_public, "epoch key")?;
+        Ok(VerifiedHistory {
+            owner_user_id: hex::encode(user_id_of(&first_key)),
+            epochs: self.epochs.len(),
+            current_ed25519_public: self.epochs[self.epochs.len() - 1].ed25519_public.clone(),
+        })
+    }
+
+    /// Whether the chain holds the given Ed25519 key at some epoch.
+    pub fn contains_key(&self, ed25519: &[u8; 32]) -> bool {
+        let key = hex::encode(ed25519);
+        self.epochs.iter().any(|e| e.ed25519_public == key)
+    }
+
+    /// Check that `self` only appends to `known` (history is never rewritten).
+    pub fn extends(&self, known: &KeyHistory) -> Result<(), String> {
+        if self.epochs.len() < known.epochs.len() || self.epochs[..known.epochs.len()] != known.epochs[..] {
+            return Err("Key history rewrites epochs seen before".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forged_rotation_is_detected() {
+        let (first, second, attacker) = (Identity::generate(), Identity::generate(), Identity::generate());
+        let genesis = KeyHistory::genesis(&first, 100);
+        let mut history = genesis.clone();
+        history.rotate(&first, &second, 200).unwrap();
+        assert_eq!(history.verify().unwrap().epochs, 2);
+        assert!(history.extends(&genesis).is_ok());
+        assert!(history.contains_key(second.public().ed25519_public.as_bytes()));
+
+        // Attacker appends a key the current key never signed
+        let mut forged = history.clone();
+        forged.push(&attacker, Some(attacker.ed25519_signing_key()), 300);
+        assert!(forged.verify().is_err());
+        assert!(history.rotate(&attacker, &attacker, 300).is_err());
+
+        // A different chain for the same person does not extend the known one
+        let rewritten = KeyHistory::genesis(&first, 150);
+        assert!(rewritten.verify().is_ok());
+        assert!(rewritten.extends(&history).is_err());
+    }
+}
@@ -0,0 +1,279 @@
+//! Late-join key distribution for protected geo channels
+//!
+//! A protected geo channel is a geo channel with a channel key (shared with
+//! an invite). Someone joining mid-conversation holds only the current key,
+//! so messages sealed under earlier epochs stay unreadable. They broadcast a
+//! `KeyShareRequest` on the channel and members answer with a `KeyShare`
+//! carrying the channel's recent key epochs, wrapped to the joiner.
+//!
+//! - request: requester Ed25519 key (32) || ephemeral X25519 key (32) ||
+//!   requested_at (i64 BE) || tag (32) || Ed25519 signature (64) over
+//!   "meshapp-key-share-request" || channel_id || everything before it. The
+//!   tag is SHA256("meshapp-key-share-request" || current key || ephemeral
+//!   key), so only holders of the current key can ask.
+//! - share: request_id (32) || sharer's ephemeral X25519 key (32) || tag (32)
+//!   || ChaCha20-Poly1305 ciphertext of (added_at (i64 BE) || key (32)) per
+//!   epoch. The tag is SHA256("meshapp-key-share" || current key || both
+//!   ephemeral keys); the wrapping key comes from X25519 of the two
+//!   ephemeral keys, whose requester half is signed by the joiner's identity.
+//!
+//! Members share the current key plus epochs added within
+//! `channels.late_join_history_secs`, so joiners read recent history but not
+//! older history. Like history requests, a request is only answered within
+//! `REQUEST_TIMEOUT_SECS`, and only the first share for it is accepted.
+
+use crate::codec;
+use crate::error::MeshError;
+use crate::events;
+use crate::identity::Identity;
+use crate::invites;
+use crate::priority::Priority;
+use crate::settings::{self, CHANNELS_LATE_JOIN_HISTORY_SECS};
+use crate::storage::{ChannelKeyEpochRow, Storage};
+use crate::transport::{Packet, PacketKind};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Most key epochs in one share
+pub const MAX_SHARED_EPOCHS: usize = 16;
+
+/// How long a request stays valid (answering it and accepting shares)
+const REQUEST_TIMEOUT_SECS: i64 = 300;
+
+const REQUEST_CONTEXT: &[u8] = b"meshapp-key-share-request";
+const SHARE_CONTEXT: &[u8] = b"meshapp-key-share";
+const WRAP_CONTEXT: &[u8] = b"meshapp-key-share-wrap";
+const REQUEST_SIGNED_LEN: usize = 32 + 32 + 8 + 32;
+const REQUEST_LEN: usize = REQUEST_SIGNED_LEN + 64;
+const SHARE_HEADER_LEN: usize = 96;
+const EPOCH_LEN: usize = 8 + 32;
+
+/// (channel_id, ephemeral secret, sent_at) of a request we sent
+type PendingRequest = ([u8; 32], [u8; 32], i64);
+
+/// Our outstanding requests by request_id
+static PENDING: Lazy<Mutex<HashMap<[u8; 32], PendingRequest>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The current key of a protected geo channel (None for other channels).
+fn protected_geo_key(storage: &Storage, channel_id: [u8; 32]) -> Result<Option<[u8; 32]>, String> {
+    if storage.get_channel_type(channel_id)?.as_deref() != Some("geo") {
+        return Ok(None);
+    }
+    storage.get_channel_key(channel_id)
+}
+
+fn tag(context: &[u8], key: &[u8; 32], ephemeral_keys: &[&[u8; 32]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(context);
+    hasher.update(key);
+    for k in ephemeral_keys {
+        hasher.update(k);
+    }
+    hasher.finalize().into()
+}
+
+fn wrap_cipher(shared: &[u8; 32], requester: &[u8; 32], sharer: &[u8; 32]) -> ChaCha20Poly1305 {
+    let key = tag(WRAP_CONTEXT, shared, &[requester, sharer]);
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+/// Build a request for the recent keys of a protected geo channel we joined.
+pub fn build_request(identity: &Identity, storage: &Storage, channel_id: [u8; 32], ttl: u8, now: i64) -> Result<Packet, String> {
+    let key = protected_geo_key(storage, channel_id)?.ok_or_else(|| MeshError::Rejected.raise("Not a protected geo channel"))?;
+    let secret = StaticSecret::random_from_rng(crate::rng::rng());
+    let ephemeral = PublicKey::from(&secret).to_bytes();
+
+    let mut payload = Vec::with_capacity(REQUEST_LEN);
+    payload.extend_from_slice(identity.public().ed25519_public.as_bytes());
+    payload.extend_from_slice(&ephemeral);
+    payload.extend_from_slice(&now.to_be_bytes());
+    payload.extend_from_slice(&tag(REQUEST_CONTEXT, &key, &[&ephemeral]));
+    let signature = identity.ed25519_signing_key().sign(&[REQUEST_CONTEXT, &channel_id, &payload].concat());
+    payload.extend_from_slice(&signature.to_bytes());
+
+    let request_id: [u8; 32] = Sha256::digest(&payload).into();
+    let mut pending = PENDING.lock().unwrap();
+    pending.retain(|_, (_, _, sent_at)| now.saturating_sub(*sent_at) <= REQUEST_TIMEOUT_SECS);
+    pending.insert(request_id, (channel_id, secret.to_bytes(), now));
+
+    Ok(Packet {
+        packet_id: request_id,
+        channel_id,
+        kind: PacketKind::KeyShareRequest,
+        ttl,
+        payload,
+        priority: Priority::Normal,
+    })
+}
+
+/// Epochs a joiner gets: the current key and those added within the policy
+/// window, newest first.
+fn shareable_epochs(storage: &Storage, channel_id: [u8; 32], current: &[u8; 32], now: i64) -> Result<Vec<ChannelKeyEpochRow>, String> {
+    let window = settings::get_u64(storage, CHANNELS_LATE_JOIN_HISTORY_SECS)?;
+    let cutoff = now.saturating_sub(window.min(i64::MAX as u64) as i64);
+    Ok(storage
+        .list_channel_key_epochs(channel_id)?
+        .into_iter()
+        .filter(|epoch| epoch.key == *current || epoch.added_at >= cutoff)
+        .take(MAX_SHARED_EPOCHS)
+        .collect())
+}
+
+/// Answer a key request for a protected geo channel we hold. Returns None
+/// for channels we cannot share; errors for malformed or forged requests.
+pub fn handle_request(storage: &Storage, packet: &Packet, now: i64) -> Result<Option<Packet>, String> {
+    if packet.payload.len() != REQUEST_LEN {
+        return Err(MeshError::InvalidArgument.raise("Malformed key share request"));
+    }
+    let Some(current) = protected_geo_key(storage, packet.channel_id)? else {
+        return Ok(None);
+    };
+    let requester: [u8; 32] = codec::read_array(&packet.payload, 0, "requester key")?;
+    let requester_ephemeral: [u8; 32] = codec::read_array(&packet.payload, 32, "ephemeral key")?;
+    let requested_at = codec::read_i64_be(&packet.payload, 64, "requested_at")?;
+    let request_tag: [u8; 32] = codec::read_array(&packet.payload, 72, "request tag")?;
+    let signature: [u8; 64] = codec::read_array(&packet.payload, REQUEST_SIGNED_LEN, "signature")?;
+
+    if (now - requested_at).abs() > REQUEST_TIMEOUT_SECS {
+        return Err("Stale key share request".to_string());
+    }
+    if request_tag != tag(REQUEST_CONTEXT, &current, &[&requester_ephemeral]) {
+        return Err("Key share request does not prove the channel key".to_string());
+    }
+    VerifyingKey::from_bytes(&requester)
+        .map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid requester key: {}", e)))?
+        .verify(
+            &[REQUEST_CONTEXT, &packet.channel_id, &packet.payload[..REQUEST_SIGNED_LEN]].concat(),
+            &Signature::from_bytes(&signature),
+        )
+        .map_err(|_| MeshError::Crypto.raise("Key share request signature does not verify"))?;
+
+    let mut plaintext = Vec::new();
+    for epoch in shareable_epochs(storage, packet.channel_id, &current, now)? {
+        plaintext.extend_from_slice(&epoch.added_at.to_be_bytes());
+        plaintext.extend_from_slice(&epoch.key);
+    }
+
+    let secret = StaticSecret::random_from_rng(crate::rng::rng());
+    let ephemeral = PublicKey::from(&secret).to_bytes();
+    let shared = secret.diffie_hellman(&PublicKey::from(requester_ephemeral)).to_bytes();
+    let aad = [packet.channel_id, packet.packet_id].concat();
+    let ciphertext = wrap_cipher(&shared, &requester_ephemeral, &ephemeral)
+        .encrypt(Nonce::from_slice(&[0u8; 12]), Payload { msg: &plaintext, aad: &aad })
+        .map_err(|_| "Failed to wrap channel keys".to_string())?;
+
+    let mut payload = Vec::with_capacity(SHARE_HEADER_LEN + ciphertext.len());
+    payload.extend_from_slice(&packet.packet_id);
+    payload.extend_from_slice(&ephemeral);
+    payload.extend_from_slice(&tag(SHARE_CONTEXT, &current, &[&requester_ephemeral, &ephemeral]));
+    payload.extend_from_slice(&ciphertext);
+    Ok(Some(Packet {
+        packet_id: Sha256::digest(&payload).into(),
+        channel_id: packet.channel_id,
+        kind: PacketKind::KeyShare,
+        ttl: packet.ttl,
+        payload,
+        priority: Priority::Normal,
+    }))
+}
+
+/// Store the keys of a share answering one of our requests and emit
+/// `channel_keys_shared` { channel_id, received, new }. Shares for requests
+/// we did not send (or already answered) are ignored and return None.
+pub fn handle_share(storage: &Storage, packet: &Packet, now: i64) -> Result<Option<usize>, String> {
+    if packet.payload.len() < SHARE_HEADER_LEN {
+        return Err(MeshError::InvalidArgument.raise("Malformed key share"));
+    }
+    let request_id: [u8; 32] = codec::read_array(&packet.payload, 0, "request id")?;
+    let sharer_ephemeral: [u8; 32] = codec::read_array(&packet.payload, 32, "ephemeral key")?;
+    let share_tag: [u8; 32] = codec::read_array(&packet.payload, 64, "share tag")?;
+
+    let mut pending = PENDING.lock().unwrap();
+    let Some(&(channel_id, secret, sent_at)) = pending.get(&request_id) else {
+        return Ok(None);
+    };
+    if channel_id != packet.channel_id || now.saturating_sub(sent_at) > REQUEST_TIMEOUT_SECS {
+        return Ok(None);
+    }
+    let current = protected_geo_key(storage, channel_id)?.ok_or_else(|| MeshError::Rejected.raise("Not a protected geo channel"))?;
+    let secret = StaticSecret::from(secret);
+    let requester_ephemeral = PublicKey::from(&secret).to_bytes();
+    if share_tag != tag(SHARE_CONTEXT, &current, &[&requester_ephemeral, &sharer_ephemeral]) {
+        return Err("Key share does not prove the channel key".to_string());
+    }
+
+    let shared = secret.diffie_hellman(&PublicKey::from(sharer_ephemeral)).to_bytes();
+    let aad = [channel_id, request_id].concat();
+    let plaintext = wrap_cipher(&shared, &requester_ephemeral, &sharer_ephemeral)
+        .decrypt(Nonce::from_slice(&[0u8; 12]), Payload { msg: &packet.payload[SHARE_HEADER_LEN..], aad: &aad })
+        .map_err(|_| MeshError::Crypto.raise("Key share does not decrypt"))?;
+    if plaintext.len() % EPOCH_LEN != 0 || plaintext.len() / EPOCH_LEN > MAX_SHARED_EPOCHS {
+        return Err(MeshError::InvalidArgument.raise("Malformed key share epochs"));
+    }
+    pending.remove(&request_id);
+    drop(pending);
+
+    let epochs: Vec<ChannelKeyEpochRow> = plaintext
+        .chunks(EPOCH_LEN)
+        .map(|chunk| {
+            Ok(ChannelKeyEpochRow {
+                channel_id,
+                key: codec::read_array(chunk, 8, "epoch key")?,
+                added_at: codec::read_i64_be(chunk, 0, "epoch added_at")?,
+            })
+        })
+        .collect::<Result<_, String>>()?;
+    let mut new = 0;
+    for epoch in &epochs {
+        if storage.restore_channel_key_epoch(epoch)? {
+            new += 1;
+        }
+    }
+    if new > 0 {
+        invites::key_escrow_changed(channel_id);
+    }
+    events::emit(
+        "channel_keys_shared",
+        serde_json::json!({ "channel_id": hex::encode(channel_id), "received": epochs.len(), "new": new }),
+    );
+    Ok(Some(new))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_late_joiner_gets_recent_epochs_only() {
+        let (member, joiner) = (Storage::in_memory().unwrap(), Storage::in_memory().unwrap());
+        let channel = crate::geo::derive_geo_channel_id("u4pru", "general");
+        let now = 1_000_000;
+        let window = settings::get_u64(&member, CHANNELS_LATE_JOIN_HISTORY_SECS).unwrap() as i64;
+
+        member.upsert_channel(channel, "geo").unwrap();
+        member.set_channel_key(channel, [1u8; 32], now - window - 10).unwrap();
+        member.set_channel_key(channel, [2u8; 32], now - 60).unwrap();
+        member.set_channel_key(channel, [3u8; 32], now - 30).unwrap();
+        joiner.upsert_channel(channel, "geo").unwrap();
+        joiner.set_channel_key(channel, [3u8; 32], now).unwrap();
+
+        let identity = Identity::generate();
+        let request = build_request(&identity, &joiner, channel, 3, now).unwrap();
+        let mut forged = request.clone();
+        forged.payload[40] ^= 1;
+        assert!(handle_request(&member, &forged, now).is_err());
+
+        let share = handle_request(&member, &request, now).unwrap().unwrap();
+        assert_eq!(handle_share(&joiner, &share, now).unwrap(), Some(1));
+        let keys: Vec<[u8; 32]> = joiner.list_channel_key_epochs(channel).unwrap().iter().map(|e| e.key).collect();
+        assert!(keys.contains(&[2u8; 32]) && !keys.contains(&[1u8; 32]));
+        // Only the first share for a request is accepted
+        assert_eq!(handle_share(&joiner, &share, now).unwrap(), None);
+    }
+}
@@ -8,20 +8,104 @@
 //!
 //! Phase 1: Identity generation and secure storage
 
+// FFI entry points take raw C pointers by design; null checks are done inline.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+// The `json!` of every FFI payload schema (see `ffi_types::schema`) nests deeper than the default
+#![recursion_limit = "256"]
+
+mod codec;
+mod error;
+mod clock;
+mod batch_verify;
+mod backup;
+mod crypto_backends;
+mod ffi_types;
+mod entropy;
+mod passphrase;
 mod identity;
+mod identity_backup;
+mod identities;
+mod instance_lock;
+mod lifecycle;
+mod integrity;
 mod friends;
+mod safety_number;
+mod blocklist;
+mod contact_import;
+mod diagnostics;
 mod dm_crypto;
+mod ratchet;
+mod compression;
 mod storage;
 mod transport;
 mod geo;
+mod geo_messages;
 mod mentions;
 mod optimization;
+mod priority;
+mod events;
+mod recovery;
+mod attachments;
+mod notifications;
+mod settings;
+mod invites;
+mod uri;
+mod qr;
+mod notes;
+mod system_messages;
+mod search;
+mod relay_snapshot;
+mod peer_capabilities;
+mod replay;
+mod sync;
+mod history;
+mod ingest;
+mod memory_budget;
+mod retention;
+mod sos;
+mod forward;
+mod link_preview;
+mod batch;
+mod client_tokens;
+mod group_delivery;
+mod starred;
+mod wipe;
+mod key_history;
+mod key_escrow;
+mod key_share;
+mod receipts;
+mod send_status;
+mod read_state;
+mod message_index;
+mod crypto_queue;
+mod outbox;
+mod message_futures;
+mod groups;
+mod group_metadata;
+mod pseudonyms;
+mod messaging;
+mod channel_directory;
+mod broadcasts;
+mod moderation;
+mod relay_budget;
+mod relay_policy;
+mod rng;
+mod node_roles;
+#[cfg(feature = "open-profile")]
+mod open_profile;
+#[cfg(feature = "audit")]
+mod audit;
+#[cfg(feature = "async")]
+pub mod async_api;
+pub mod webhooks;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 
 use std::ffi::CString;
 use std::os::raw::c_char;
 use std::sync::Mutex;
 use once_cell::sync::Lazy;
-use std::time::{SystemTime, UNIX_EPOCH};
+use error::MeshError;
 
 // Global identity instance (lazy-loaded, thread-safe)
 static IDENTITY: Lazy<Mutex<Option<identity::Identity>>> = Lazy::new(|| Mutex::new(None));
@@ -37,19 +121,162 @@ static ROUTER: Lazy<Mutex<Option<transport::Router>>> = Lazy::new(|| Mutex::new(
 static LOOPBACK: Lazy<Mutex<Option<std::sync::Arc<transport::LoopbackTransport>>>> =
     Lazy::new(|| Mutex::new(None));
 
+// Held across sends that carry a client_token, so two taps cannot both miss the lookup
+static CLIENT_TOKENS: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+// Startup recovery runs once per data directory (see `identities`), before the first init touches it
+static RECOVERED: Lazy<Mutex<std::collections::HashSet<std::path::PathBuf>>> = Lazy::new(|| Mutex::new(Default::default()));
+
+/// Clean up leftovers from interrupted saves and report via the event queue
+fn ensure_startup_recovery() {
+    let data_dir = match storage::data_dir() {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("Startup recovery skipped: {}", e);
+            return;
+        }
+    };
+    if RECOVERED.lock().unwrap().insert(data_dir.clone()) {
+        let summary = recovery::recover_files(&data_dir);
+        events::emit(
+            "recovery_summary",
+            serde_json::to_value(&summary).unwrap_or(serde_json::Value::Null),
+        );
+        if passphrase::status(&data_dir).reencryption_pending {
+            events::emit("reencryption_incomplete", serde_json::json!({}));
+        }
+    }
+}
+
+// Outbox recovery runs once per database, when it is first opened
+static OUTBOX_RECOVERED: Lazy<Mutex<std::collections::HashSet<std::path::PathBuf>>> = Lazy::new(|| Mutex::new(Default::default()));
+
+/// Repair the outbox of a database opened for the first time and report it
+/// via the event queue (see `recovery`).
+fn ensure_outbox_recovery(storage: &storage::Storage, db_path: std::path::PathBuf) {
+    if storage::is_read_only_mode() || !OUTBOX_RECOVERED.lock().unwrap().insert(db_path) {
+        return;
+    }
+    match recovery::recover_outbox(storage, now_ts()) {
+        Ok(summary) if !summary.requeued_messages.is_empty() || !summary.dropped_outbox_packets.is_empty() => events::emit(
+            "recovery_summary",
+            serde_json::to_value(&summary).unwrap_or(serde_json::Value::Null),
+        ),
+        Ok(_) => {}
+        Err(e) => eprintln!("Outbox recovery failed: {}", e),
+    }
+}
+
+/// Take the data directory lock (see `instance_lock`), then run startup
+/// recovery. Attached read-only, neither happens and calls `for_writing`
+/// are refused: every entry point that writes files in the data directory
+/// (outside the database) passes it. Returns the failure to return, if any.
+fn claim_data_dir<T: FfiFailure>(for_writing: bool) -> Result<(), T> {
+    if storage::is_read_only_mode() {
+        if for_writing {
+            if let Err(e) = storage::ensure_writable() {
+                return Err(fail(MeshError::InUse, &e));
+            }
+        }
+        return Ok(());
+    }
+    if !storage::is_in_memory_mode() {
+        if let Err(e) = storage::root_dir().and_then(|root| instance_lock::acquire(&root)) {
+            return Err(failed(e));
+        }
+    }
+    ensure_startup_recovery();
+    Ok(())
+}
+
+/// Make `id` the loaded identity, noting its user id in the identities index.
+fn install_identity(identity_guard: &mut Option<identity::Identity>, id: identity::Identity) {
+    if storage::is_read_only_mode() {
+        *identity_guard = Some(id);
+        return;
+    }
+    if let Err(e) = storage::root_dir().and_then(|root| identities::record_user_id(&root, &id.public().user_id)) {
+        eprintln!("Failed to update the identities index: {}", e);
+    }
+    *identity_guard = Some(id);
+}
+
+/// Keep the identity, friends, database and every other file under `path`
+/// (absolute; created if missing) instead of the platform's local data
+/// directory. Mobile apps must pass their sandbox's app-documents directory.
+/// Call before init_identity; fails once anything is loaded.
+/// Returns 0 on success, a negative error code on error
+#[no_mangle]
+pub extern "C" fn set_data_directory(path: *const c_char) -> i32 {
+    let Some(path) = parse_c_str(path) else {
+        return invalid_argument("path");
+    };
+    let identity_guard = IDENTITY.lock().unwrap();
+    let friends_guard = FRIENDS.lock().unwrap();
+    let storage_guard = STORAGE.lock().unwrap();
+    if identity_guard.is_some() || friends_guard.is_some() || storage_guard.is_some() {
+        return fail(MeshError::Rejected, "set_data_directory failed: call it before init_identity");
+    }
+    match storage::set_root_dir(std::path::PathBuf::from(path)) {
+        Ok(()) => 0,
+        Err(e) => failed(format!("set_data_directory failed: {}", e)),
+    }
+}
+
+/// Attach to a data directory another process holds (the app, while this is
+/// its background service, say) to read it: no lock is taken, init_identity
+/// only loads an existing identity, init_storage opens the database
+/// read-only and nothing is ever written. Writes fail with InUse. Call before
+/// init_identity; fails once anything is loaded.
+/// Returns 0 on success, a negative error code on error
+#[no_mangle]
+pub extern "C" fn attach_read_only() -> i32 {
+    let identity_guard = IDENTITY.lock().unwrap();
+    let friends_guard = FRIENDS.lock().unwrap();
+    let storage_guard = STORAGE.lock().unwrap();
+    if identity_guard.is_some() || friends_guard.is_some() || storage_guard.is_some() {
+        return fail(MeshError::Rejected, "attach_read_only failed: call it before init_identity");
+    }
+    if storage::is_in_memory_mode() {
+        return fail(MeshError::Rejected, "attach_read_only failed: running in memory");
+    }
+    storage::enter_read_only_mode();
+    0
+}
+
 /// Initialize identity (loads from storage or generates new one)
-/// Returns 0 on success, -1 on error
+/// The first init call takes the data directory for this process (see
+/// `instance_lock`); while another process holds it, it fails with InUse.
+/// Returns 0 on success, a negative error code on error
 #[no_mangle]
 pub extern "C" fn init_identity() -> i32 {
-    match identity::Identity::load_or_generate() {
+    if let Err(failure) = claim_data_dir(false) {
+        return failure;
+    }
+    let loaded = if storage::is_read_only_mode() {
+        identity::Identity::load_existing()
+    } else {
+        identity::Identity::load_or_generate()
+    };
+    match loaded {
         Ok(id) => {
-            *IDENTITY.lock().unwrap() = Some(id);
+            install_identity(&mut IDENTITY.lock().unwrap(), id);
             0
         }
-        Err(e) => {
-            eprintln!("Failed to initialize identity: {}", e);
-            -1
-        }
+        Err(e) => failed(format!("Failed to initialize identity: {}", e)),
+    }
+}
+
+/// Contribute extra entropy (sensor noise, tap timings) for identity creation.
+/// Call before init_identity creates a new identity; the bytes are mixed
+/// with OS randomness, never used alone.
+/// bytes_hex: hex-encoded bytes
+/// Returns the number of bytes contributed so far, a negative error code on error
+#[no_mangle]
+pub extern "C" fn add_entropy(bytes_hex: *const c_char) -> i64 {
+    match parse_hex_vec(bytes_hex) {
+        Some(bytes) => entropy::add(&bytes) as i64,
+        None => invalid_argument("bytes_hex"),
     }
 }
 
@@ -65,7 +292,7 @@ pub extern "C" fn get_user_id() -> *mut c_char {
             .map(|s| s.into_raw())
             .unwrap_or(std::ptr::null_mut())
     } else {
-        std::ptr::null_mut()
+        not_initialized("Identity")
     }
 }
 
@@ -81,7 +308,7 @@ pub extern "C" fn get_ed25519_public_key() -> *mut c_char {
             .map(|s| s.into_raw())
             .unwrap_or(std::ptr::null_mut())
     } else {
-        std::ptr::null_mut()
+        not_initialized("Identity")
     }
 }
 
@@ -97,7 +324,7 @@ pub extern "C" fn get_x25519_public_key() -> *mut c_char {
             .map(|s| s.into_raw())
             .unwrap_or(std::ptr::null_mut())
     } else {
-        std::ptr::null_mut()
+        not_initialized("Identity")
     }
 }
 
@@ -114,233 +341,687 @@ pub extern "C" fn get_fingerprint() -> *mut c_char {
             .map(|s| s.into_raw())
             .unwrap_or(std::ptr::null_mut())
     } else {
-        std::ptr::null_mut()
+        not_initialized("Identity")
     }
 }
 
-// ========== Friends Management ==========
+// ========== Errors ==========
 
-/// Initialize friends manager
-/// Returns 0 on success, -1 on error
+/// Code of the last failure on the calling thread (see `MeshError`): 0 if
+/// none yet. Failing i32/i64 functions also return this code; functions
+/// returning strings return null, so read it right after the null.
 #[no_mangle]
-pub extern "C" fn init_friends() -> i32 {
-    match friends::FriendManager::new() {
-        Ok(fm) => {
-            *FRIENDS.lock().unwrap() = Some(fm);
+pub extern "C" fn last_error_code() -> i32 {
+    error::last().map(|(e, _)| e.code()).unwrap_or(0)
+}
+
+/// The last failure on the calling thread as JSON {code, name, message},
+/// null if none yet.
+#[no_mangle]
+pub extern "C" fn last_error_message() -> *mut c_char {
+    let Some((error, message)) = error::last() else {
+        return std::ptr::null_mut();
+    };
+    let json = serde_json::to_string(&ffi_types::ErrorInfo::new(error, message)).unwrap_or_default();
+    CString::new(json).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut())
+}
+
+// ========== Passphrase ==========
+
+/// Protect the identity with a passphrase (call once, after init_identity).
+/// The identity file is sealed and a sealed database key is created, which
+/// SQLCipher builds rekey the open database to; from then on the identity
+/// is loaded with unlock_identity.
+/// Returns 0 on success, a negative error code on error (including when a passphrase is already set).
+#[no_mangle]
+pub extern "C" fn set_passphrase(passphrase: *const c_char) -> i32 {
+    if let Err(failure) = claim_data_dir(true) {
+        return failure;
+    }
+    let Some(passphrase) = parse_c_str(passphrase) else {
+        return invalid_argument("passphrase");
+    };
+    let result = storage::data_dir()
+        .and_then(|dir| passphrase::protect(&dir, passphrase, passphrase::KDF_ITERATIONS))
+        .and_then(|()| match passphrase::db_keys().first() {
+            Some(key) => rekey_open_database(key),
+            None => Ok(()),
+        });
+    match result {
+        Ok(()) => 0,
+        Err(e) => failed(format!("set_passphrase failed: {}", e)),
+    }
+}
+
+/// Load a passphrase-protected identity (instead of init_identity), and the
+/// database key sealed with it.
+/// Returns 0 on success, a negative error code on error (including a wrong passphrase).
+#[no_mangle]
+pub extern "C" fn unlock_identity(passphrase: *const c_char) -> i32 {
+    if let Err(failure) = claim_data_dir(false) {
+        return failure;
+    }
+    let Some(passphrase) = parse_c_str(passphrase) else {
+        return invalid_argument("passphrase");
+    };
+    let result = storage::data_dir().and_then(|dir| {
+        let id = passphrase::unlock_identity(&dir, passphrase).and_then(|keys| identity::Identity::from_key_bytes(&keys))?;
+        passphrase::unlock_db_keys(&dir, passphrase)?;
+        Ok(id)
+    });
+    match result {
+        Ok(id) => {
+            install_identity(&mut IDENTITY.lock().unwrap(), id);
             0
         }
-        Err(e) => {
-            eprintln!("Failed to initialize friends: {}", e);
-            -1
-        }
+        Err(e) => failed(format!("unlock_identity failed: {}", e)),
     }
 }
 
-/// Add a friend from Ed25519 public key (hex) and nickname
-/// Returns user_id (hex) on success, null on error
+/// Change the passphrase. SQLCipher builds first rekey the open database
+/// under a new database key. Re-encryption runs in the background and reports
+/// `reencryption_progress` { done, total, file }, then `reencryption_done`
+/// or `reencryption_failed` { error }. After a `reencryption_incomplete`
+/// event at startup, call again with the same passphrases to resume.
+/// Returns 0 if the job started, a negative error code on error (wrong passphrase, job already running).
 #[no_mangle]
-pub extern "C" fn add_friend(ed25519_public_hex: *const c_char, nickname: *const c_char) -> *mut c_char {
-    let public_key_str = unsafe {
-        if ed25519_public_hex.is_null() {
-            return std::ptr::null_mut();
+pub extern "C" fn change_passphrase(old_passphrase: *const c_char, new_passphrase: *const c_char) -> i32 {
+    if let Err(failure) = claim_data_dir(true) {
+        return failure;
+    }
+    let (Some(old), Some(new)) = (parse_c_str(old_passphrase), parse_c_str(new_passphrase)) else {
+        return invalid_argument("old_passphrase");
+    };
+    let result = storage::data_dir().and_then(|dir| {
+        if STORAGE.lock().unwrap().as_ref().is_some_and(|s| s.is_encrypted()) && !passphrase::db_keys().is_empty() {
+            passphrase::rotate_db_key(&dir, old, passphrase::KDF_ITERATIONS, rekey_open_database)?;
         }
-        match std::ffi::CStr::from_ptr(ed25519_public_hex).to_str() {
-            Ok(s) => s,
-            Err(_) => return std::ptr::null_mut(),
+        passphrase::start_rekey(dir, old.to_string(), new.to_string())
+    });
+    match result {
+        Ok(()) => 0,
+        Err(e) => failed(format!("change_passphrase failed: {}", e)),
+    }
+}
+
+/// Re-encrypt the open database under `key`, if it is encrypted, and reopen
+/// it with that key from now on.
+fn rekey_open_database(key: &[u8; 32]) -> Result<(), String> {
+    let storage_guard = STORAGE.lock().unwrap();
+    match storage_guard.as_ref() {
+        Some(storage) if storage.is_encrypted() => {
+            storage.rekey(key)?;
+            lifecycle::opened(Some(zeroize::Zeroizing::new(*key)));
+            Ok(())
         }
+        _ => Ok(()),
+    }
+}
+
+/// Passphrase state as JSON { protected, reencryption_pending, reencryption_running }, null on error.
+#[no_mangle]
+pub extern "C" fn get_passphrase_status() -> *mut c_char {
+    let status = match storage::data_dir() {
+        Ok(dir) => passphrase::status(&dir),
+        Err(e) => return failed(format!("get_passphrase_status failed: {}", e)),
     };
+    match serde_json::to_string(&status) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_passphrase_status failed: {}", e)),
+    }
+}
 
-    let nickname_str = unsafe {
-        if nickname.is_null() {
-            return std::ptr::null_mut();
-        }
-        match std::ffi::CStr::from_ptr(nickname).to_str() {
-            Ok(s) => s.to_string(),
-            Err(_) => return std::ptr::null_mut(),
-        }
+// ========== Identity Backup ==========
+
+/// Seal the identity under a backup passphrase, to restore it on another
+/// device (see `identity_backup`). Returns the backup as JSON, null on error.
+#[no_mangle]
+pub extern "C" fn export_identity_encrypted(passphrase: *const c_char) -> *mut c_char {
+    let Some(passphrase) = parse_c_str(passphrase) else {
+        return invalid_argument("passphrase");
+    };
+    let identity_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = identity_guard.as_ref() else {
+        return not_initialized("Identity");
     };
+    let backup = identity_backup::export_encrypted(identity, passphrase, identity_backup::KdfParams::default())
+        .and_then(|b| serde_json::to_string(&b).map_err(|e| e.to_string()));
+    match backup {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("export_identity_encrypted failed: {}", e)),
+    }
+}
 
-    let public_key_bytes = match hex::decode(public_key_str) {
-        Ok(bytes) => bytes,
-        Err(_) => return std::ptr::null_mut(),
+/// Restore an identity from an encrypted backup (instead of init_identity,
+/// on a device without an identity yet). The identity is saved unprotected;
+/// call set_passphrase to protect it. Returns 0 on success, a negative error
+/// code on error (including a wrong passphrase or an existing identity).
+#[no_mangle]
+pub extern "C" fn import_identity_encrypted(backup_json: *const c_char, passphrase: *const c_char) -> i32 {
+    if let Err(failure) = claim_data_dir(true) {
+        return failure;
+    }
+    let Some(backup) = parse_c_str(backup_json).and_then(|s| serde_json::from_str::<identity_backup::IdentityBackup>(s).ok()) else {
+        return invalid_argument("backup_json");
+    };
+    let Some(passphrase) = parse_c_str(passphrase) else {
+        return invalid_argument("passphrase");
     };
+    match identity_backup::import_encrypted(&backup, passphrase).and_then(install_restored_identity) {
+        Ok(()) => 0,
+        Err(e) => failed(format!("import_identity_encrypted failed: {}", e)),
+    }
+}
 
-    if public_key_bytes.len() != 32 {
-        return std::ptr::null_mut();
+/// The identity's recovery phrase (48 words), null on error. Anyone holding
+/// it holds the identity: show it once, for the user to write down.
+#[no_mangle]
+pub extern "C" fn get_recovery_phrase() -> *mut c_char {
+    let identity_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = identity_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    CString::new(identity_backup::recovery_phrase(identity))
+        .ok()
+        .map(|s| s.into_raw())
+        .unwrap_or(std::ptr::null_mut())
+}
+
+/// Restore an identity from its recovery phrase (as import_identity_encrypted).
+/// Returns 0 on success, a negative error code on error.
+#[no_mangle]
+pub extern "C" fn restore_identity_from_phrase(phrase: *const c_char) -> i32 {
+    if let Err(failure) = claim_data_dir(true) {
+        return failure;
+    }
+    let Some(phrase) = parse_c_str(phrase) else {
+        return invalid_argument("phrase");
+    };
+    match identity_backup::from_recovery_phrase(phrase).and_then(install_restored_identity) {
+        Ok(()) => 0,
+        Err(e) => failed(format!("restore_identity_from_phrase failed: {}", e)),
+    }
+}
+
+/// Save a restored identity and load it, unless the device has one already.
+fn install_restored_identity(identity: identity::Identity) -> Result<(), String> {
+    let mut identity_guard = IDENTITY.lock().unwrap();
+    if identity_guard.is_some() {
+        return Err(MeshError::Rejected.raise("An identity is loaded already"));
+    }
+    identity.save_restored()?;
+    install_identity(&mut identity_guard, identity);
+    Ok(())
+}
+
+// ========== Identities ==========
+
+/// List the identities on this device (see `identities`).
+/// Returns JSON { active, identities: [{ name, user_id?, created_at }] }, null on error.
+#[no_mangle]
+pub extern "C" fn list_identities() -> *mut c_char {
+    match storage::root_dir()
+        .and_then(|root| identities::load(&root))
+        .and_then(|index| serde_json::to_string(&index).map_err(|e| e.to_string()))
+    {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("list_identities failed: {}", e)),
     }
+}
 
-    let mut key = [0u8; 32];
-    key.copy_from_slice(&public_key_bytes);
+/// Create a new identity (fresh keys, empty friends list and database) called
+/// `name`: 1-32 of a-z, 0-9, '-', '_'. The active identity stays; see switch_identity.
+/// Returns JSON { name, user_id, created_at }, null on error.
+#[no_mangle]
+pub extern "C" fn create_identity(name: *const c_char) -> *mut c_char {
+    if let Err(failure) = claim_data_dir(true) {
+        return failure;
+    }
+    let Some(name) = parse_c_str(name) else {
+        return invalid_argument("name");
+    };
+    match storage::root_dir()
+        .and_then(|root| identities::create(&root, name, now_ts()))
+        .and_then(|entry| serde_json::to_string(&entry).map_err(|e| e.to_string()))
+    {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("create_identity failed: {}", e)),
+    }
+}
 
+/// Make `name` the active identity. The loaded identity, friends list and
+/// storage are unloaded; call init_identity (or unlock_identity),
+/// init_storage and init_friends again to load the new one's.
+/// Returns 0 on success, a negative error code on error (including an unknown name).
+#[no_mangle]
+pub extern "C" fn switch_identity(name: *const c_char) -> i32 {
+    if let Err(failure) = claim_data_dir(true) {
+        return failure;
+    }
+    let Some(name) = parse_c_str(name) else {
+        return invalid_argument("name");
+    };
+    let mut identity_guard = IDENTITY.lock().unwrap();
     let mut friends_guard = FRIENDS.lock().unwrap();
-    if let Some(ref mut fm) = *friends_guard {
-        match fm.add_friend(key, nickname_str) {
-            Ok(user_id) => {
-                let user_id_hex = hex::encode(user_id);
-                CString::new(user_id_hex)
-                    .ok()
-                    .map(|s| s.into_raw())
-                    .unwrap_or(std::ptr::null_mut())
-            }
-            Err(_) => std::ptr::null_mut(),
+    let mut storage_guard = STORAGE.lock().unwrap();
+    match storage::root_dir().and_then(|root| identities::switch(&root, name)) {
+        Ok(()) => {
+            *identity_guard = None;
+            *friends_guard = None;
+            *storage_guard = None;
+            passphrase::forget_db_keys();
+            0
         }
-    } else {
-        std::ptr::null_mut()
+        Err(e) => failed(format!("switch_identity failed: {}", e)),
     }
 }
 
-/// Remove a friend by user_id (hex)
-/// Returns 1 if removed, 0 if not found, -1 on error
+// ========== Backups ==========
+
+/// Back up the database (see `backup`): only the rows changed since the last
+/// backup, or everything if `full` is non-zero or there is no backup yet.
+/// Keep every backup of a chain; after losing one, make a full backup.
+/// Returns JSON { version, manifest: { chain_id, index, change_seq,
+/// created_at }, nonce, ciphertext }, null on error.
 #[no_mangle]
-pub extern "C" fn remove_friend(user_id_hex: *const c_char) -> i32 {
-    let user_id_str = unsafe {
-        if user_id_hex.is_null() {
-            return -1;
-        }
-        match std::ffi::CStr::from_ptr(user_id_hex).to_str() {
-            Ok(s) => s,
-            Err(_) => return -1,
-        }
+pub extern "C" fn create_backup(full: i32) -> *mut c_char {
+    let identity_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = identity_guard.as_ref() else {
+        return not_initialized("Identity");
     };
-
-    let user_id_bytes = match hex::decode(user_id_str) {
-        Ok(bytes) => bytes,
-        Err(_) => return -1,
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
     };
+    match backup::create(identity, storage, full != 0, now_ts())
+        .and_then(|b| serde_json::to_string(&b).map_err(|e| e.to_string()))
+    {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("create_backup failed: {}", e)),
+    }
+}
 
-    if user_id_bytes.len() != 32 {
-        return -1;
+/// Restore the database from a backup chain made by this identity: a JSON
+/// array of the full backup, then every delta after it in order. Rows of the
+/// tables in the backup replace what the database holds.
+/// Returns JSON { chain_id, backups, rows }, null on error.
+#[no_mangle]
+pub extern "C" fn restore_backup(chain_json: *const c_char) -> *mut c_char {
+    let Some(chain) = parse_c_str(chain_json).and_then(|s| serde_json::from_str::<Vec<backup::Backup>>(s).ok()) else {
+        return invalid_argument("chain_json");
+    };
+    let identity_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = identity_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    let restored = backup::restore(identity, storage, &chain);
+    if restored.is_ok() {
+        load_storage_state(storage);
+        drop(storage_guard);
+        drop(identity_guard);
+        sync_friend_x25519_keys();
+    }
+    match restored.and_then(|summary| serde_json::to_string(&summary).map_err(|e| e.to_string())) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("restore_backup failed: {}", e)),
     }
+}
 
-    let mut user_id = [0u8; 32];
-    user_id.copy_from_slice(&user_id_bytes);
+// ========== Integrity ==========
 
-    let mut friends_guard = FRIENDS.lock().unwrap();
-    if let Some(ref mut fm) = *friends_guard {
-        match fm.remove_friend(&user_id) {
-            Ok(true) => 1,
-            Ok(false) => 0,
-            Err(_) => -1,
-        }
-    } else {
-        -1
+/// Accept a protected file ("identity" or "friends") that failed its
+/// integrity check as it is now, e.g. after copying it back from a backup;
+/// then call init_identity / init_friends again.
+/// Returns 0 on success, a negative error code on error.
+#[no_mangle]
+pub extern "C" fn accept_protected_file(name: *const c_char) -> i32 {
+    if let Err(failure) = claim_data_dir(true) {
+        return failure;
+    }
+    let Some(name) = parse_c_str(name) else {
+        return invalid_argument("name");
+    };
+    match storage::data_dir().and_then(|dir| integrity::accept(&dir, name)) {
+        Ok(()) => 0,
+        Err(e) => failed(format!("accept_protected_file failed: {}", e)),
     }
 }
 
-/// Get all friends as JSON array
-/// Returns JSON string, null on error
+/// Move a protected file ("identity" or "friends") that failed its integrity
+/// check out of the way, so the identity can be restored with
+/// import_identity_encrypted / restore_identity_from_phrase (or a new friends
+/// list started with init_friends). Not allowed while the file is loaded.
+/// Returns the name the damaged file was kept under ("" if it was missing),
+/// null on error.
 #[no_mangle]
-pub extern "C" fn get_all_friends() -> *mut c_char {
-    let friends_guard = FRIENDS.lock().unwrap();
-    if let Some(ref fm) = *friends_guard {
-        let friends_list: Vec<serde_json::Value> = fm.get_all_friends()
-            .iter()
-            .map(|f| {
-                let display_name = f.custom_display_name.as_ref()
-                    .unwrap_or(&f.nickname);
-                serde_json::json!({
-                    "user_id": hex::encode(f.user_id),
-                    "ed25519_public": hex::encode(f.ed25519_public),
-                    "nickname": f.nickname,
-                    "display_name": display_name,
-                    "notes": f.notes,
-                    "tags": f.tags,
-                })
-            })
-            .collect();
+pub extern "C" fn set_aside_protected_file(name: *const c_char) -> *mut c_char {
+    if let Err(failure) = claim_data_dir(true) {
+        return failure;
+    }
+    let Some(name) = parse_c_str(name) else {
+        return invalid_argument("name");
+    };
+    let loaded = match name {
+        "identity" => IDENTITY.lock().unwrap().is_some(),
+        "friends" => FRIENDS.lock().unwrap().is_some(),
+        _ => false,
+    };
+    if loaded {
+        return fail(MeshError::Rejected, &format!("The {} file is loaded and cannot be set aside", name));
+    }
+    match storage::data_dir().and_then(|dir| integrity::set_aside(&dir, name, now_ts())) {
+        Ok(moved) => CString::new(moved.unwrap_or_default()).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("set_aside_protected_file failed: {}", e)),
+    }
+}
 
-        match serde_json::to_string(&friends_list) {
-            Ok(json) => CString::new(json)
-                .ok()
-                .map(|s| s.into_raw())
-                .unwrap_or(std::ptr::null_mut()),
-            Err(_) => std::ptr::null_mut(),
+// ========== Friends Management ==========
+
+/// Initialize friends manager: loads the friend list from the database, so
+/// call after init_storage. The friends.json of older versions is imported
+/// on the first call.
+/// Returns 0 on success, a negative error code on error
+#[no_mangle]
+pub extern "C" fn init_friends() -> i32 {
+    if let Err(failure) = claim_data_dir(false) {
+        return failure;
+    }
+    let loaded = {
+        let mut friends_guard = FRIENDS.lock().unwrap();
+        let storage_guard = STORAGE.lock().unwrap();
+        let Some(storage) = storage_guard.as_ref() else {
+            return not_initialized("Storage");
+        };
+        let imported = match storage::data_dir() {
+            Ok(_) if storage::is_read_only_mode() => Ok(()),
+            Ok(dir) => friends::import_legacy_file(storage, &dir).map(|_| ()),
+            Err(_) => Ok(()), // in memory: there is no file
+        };
+        imported
+            .and_then(|_| friends::FriendManager::load(storage))
+            .map(|fm| *friends_guard = Some(fm))
+    };
+    match loaded {
+        Ok(()) => {
+            sync_friend_x25519_keys();
+            0
         }
-    } else {
-        std::ptr::null_mut()
+        Err(e) => failed(format!("Failed to initialize friends: {}", e)),
     }
 }
 
-/// Update friend nickname
-/// Returns 0 on success, -1 on error
+/// Change the friend list: `op` gets the friends and the storage they are
+/// kept in (taking FRIENDS, then STORAGE). Err names what is not initialized.
+fn change_friends<T>(
+    op: impl FnOnce(&mut friends::FriendManager, &storage::Storage) -> Result<T, String>,
+) -> Result<Result<T, String>, &'static str> {
+    let mut friends_guard = FRIENDS.lock().unwrap();
+    let Some(friends) = friends_guard.as_mut() else {
+        return Err("Friends");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return Err("Storage");
+    };
+    Ok(op(friends, storage))
+}
+
+/// Add a friend from Ed25519 public key (hex) and nickname
+/// Returns user_id (hex) on success, null on error
 #[no_mangle]
-pub extern "C" fn update_friend_nickname(user_id_hex: *const c_char, nickname: *const c_char) -> i32 {
-    let user_id_str = unsafe {
-        if user_id_hex.is_null() {
-            return -1;
-        }
-        match std::ffi::CStr::from_ptr(user_id_hex).to_str() {
-            Ok(s) => s,
-            Err(_) => return -1,
-        }
+pub extern "C" fn add_friend(ed25519_public_hex: *const c_char, nickname: *const c_char) -> *mut c_char {
+    let key = match parse_hex_32(ed25519_public_hex) {
+        Some(v) => v,
+        None => return invalid_argument("ed25519_public_hex"),
     };
 
     let nickname_str = unsafe {
         if nickname.is_null() {
-            return -1;
+            return invalid_argument("nickname");
         }
         match std::ffi::CStr::from_ptr(nickname).to_str() {
             Ok(s) => s.to_string(),
-            Err(_) => return -1,
+            Err(_) => return invalid_argument("nickname"),
         }
     };
 
-    let user_id_bytes = match hex::decode(user_id_str) {
-        Ok(bytes) => bytes,
-        Err(_) => return -1,
+    let added = match change_friends(|fm, storage| fm.add_friend(storage, key, nickname_str)) {
+        Ok(added) => added,
+        Err(what) => return not_initialized(what),
     };
 
-    if user_id_bytes.len() != 32 {
-        return -1;
+    match added {
+        Ok(user_id) => {
+            record_friend_added(&key, &user_id);
+            let user_id_hex = hex::encode(user_id);
+            CString::new(user_id_hex)
+                .ok()
+                .map(|s| s.into_raw())
+                .unwrap_or(std::ptr::null_mut())
+        }
+        Err(e) => failed(format!("add_friend failed: {}", e)),
     }
+}
 
-    let mut user_id = [0u8; 32];
-    user_id.copy_from_slice(&user_id_bytes);
-
-    let mut friends_guard = FRIENDS.lock().unwrap();
-    if let Some(ref mut fm) = *friends_guard {
-        match fm.update_nickname(&user_id, nickname_str) {
-            Ok(_) => 0,
-            Err(_) => -1,
+/// Note a newly added friend in the history of their DM channel.
+/// Takes IDENTITY and STORAGE, so call it without holding FRIENDS.
+fn record_friend_added(ed25519_public: &[u8; 32], user_id: &[u8; 32]) {
+    let identity_guard = IDENTITY.lock().unwrap();
+    let storage_guard = STORAGE.lock().unwrap();
+    if let (Some(id), Some(storage)) = (identity_guard.as_ref(), storage_guard.as_ref()) {
+        let channel_id = dm_crypto::derive_dm_channel_id(id.public().ed25519_public.as_bytes(), ed25519_public);
+        let event = system_messages::SystemEvent::FriendAdded {
+            user_id: hex::encode(user_id),
+        };
+        let recorded = storage
+            .upsert_channel(channel_id, "dm")
+            .and_then(|_| system_messages::record(storage, channel_id, &event, now_ts()));
+        if let Err(e) = recorded {
+            eprintln!("Failed to record friend_added: {}", e);
         }
-    } else {
-        -1
     }
 }
 
-/// Update friend profile (all customizable fields)
-/// Parameters: user_id_hex, nickname (null = no change), notes (null = no change), 
-///             tags_json (null = no change, JSON array of strings), custom_display_name (null = no change, empty string = clear)
-/// Returns 0 on success, -1 on error
+/// Remove a friend by user_id (hex)
+/// Returns 1 if removed, 0 if not found, a negative error code on error
 #[no_mangle]
-pub extern "C" fn update_friend_profile(
-    user_id_hex: *const c_char,
-    nickname: *const c_char,
-    notes: *const c_char,
-    tags_json: *const c_char,
-    custom_display_name: *const c_char,
-) -> i32 {
-    let user_id_str = unsafe {
-        if user_id_hex.is_null() {
-            return -1;
+pub extern "C" fn remove_friend(user_id_hex: *const c_char) -> i32 {
+    let user_id = match parse_hex_32(user_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("user_id_hex"),
+    };
+
+    match change_friends(|fm, storage| fm.remove_friend(storage, &user_id)) {
+        Ok(Ok(true)) => 1,
+        Ok(Ok(false)) => 0,
+        Ok(Err(e)) => failed(format!("remove_friend failed: {}", e)),
+        Err(what) => not_initialized(what),
+    }
+}
+
+/// Get all friends as JSON array
+/// Returns JSON string, null on error
+#[no_mangle]
+pub extern "C" fn get_all_friends() -> *mut c_char {
+    let friends_guard = FRIENDS.lock().unwrap();
+    if let Some(ref fm) = *friends_guard {
+        let friends_list: Vec<ffi_types::FriendInfo> = fm.get_all_friends()
+            .iter()
+            .map(|f| ffi_types::FriendInfo::from(*f))
+            .collect();
+
+        match serde_json::to_string(&friends_list) {
+            Ok(json) => CString::new(json)
+                .ok()
+                .map(|s| s.into_raw())
+                .unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("get_all_friends failed: {}", e)),
         }
-        match std::ffi::CStr::from_ptr(user_id_hex).to_str() {
-            Ok(s) => s,
-            Err(_) => return -1,
+    } else {
+        not_initialized("Friends")
+    }
+}
+
+/// The safety number to compare with a friend before marking them verified
+/// (see `safety_number`). Returns JSON { user_id, safety_number, verified,
+/// verified_at }, the number in groups of five digits, null on error.
+#[no_mangle]
+pub extern "C" fn get_safety_number(user_id_hex: *const c_char) -> *mut c_char {
+    let Some(user_id) = parse_hex_32(user_id_hex) else {
+        return invalid_argument("user_id_hex");
+    };
+    let Some(own_public) = own_ed25519_public() else {
+        return not_initialized("Identity");
+    };
+    let friends_guard = FRIENDS.lock().unwrap();
+    let Some(fm) = friends_guard.as_ref() else {
+        return not_initialized("Friends");
+    };
+    let Some(friend) = fm.get_friend(&user_id) else {
+        return fail(MeshError::NotFound, "get_safety_number failed: Friend not found");
+    };
+    let number = safety_number::compute(&own_public, &friend.ed25519_public);
+    let json = ffi_types::SafetyNumber {
+        user_id: hex::encode(user_id),
+        safety_number: safety_number::display(&number),
+        verified: friend.verified_at.is_some(),
+        verified_at: friend.verified_at,
+    };
+    let json = serde_json::to_string(&json).unwrap_or_default();
+    CString::new(json).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut())
+}
+
+/// Mark a friend verified after comparing safety numbers (verified = 1), or
+/// clear it (verified = 0).
+/// Returns 0 on success, a negative error code on error
+#[no_mangle]
+pub extern "C" fn mark_friend_verified(user_id_hex: *const c_char, verified: i32) -> i32 {
+    let Some(user_id) = parse_hex_32(user_id_hex) else {
+        return invalid_argument("user_id_hex");
+    };
+    match change_friends(|fm, storage| fm.set_verified(storage, &user_id, (verified != 0).then(now_ts))) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => failed(format!("mark_friend_verified failed: {}", e)),
+        Err(what) => not_initialized(what),
+    }
+}
+
+// ========== Blocklist ==========
+
+/// Block a user: their DM packets (if they are a friend, so their key is
+/// known) and their group messages are dropped before storage (see `blocklist`).
+/// Returns 0 on success, a negative error code on error
+#[no_mangle]
+pub extern "C" fn block_user(user_id_hex: *const c_char) -> i32 {
+    let Some(user_id) = parse_hex_32(user_id_hex) else {
+        return invalid_argument("user_id_hex");
+    };
+    let own_public = own_ed25519_public();
+    let ed25519_public = friend_keys().into_iter().find(|(id, _)| *id == user_id).map(|(_, key)| key);
+    update_blocklist("block_user", own_public, |storage| {
+        blocklist::block(storage, user_id, ed25519_public, now_ts()).map(|_| ())
+    })
+}
+
+/// Unblock a user.
+/// Returns 0 on success, a negative error code on error or if the user was not blocked.
+#[no_mangle]
+pub extern "C" fn unblock_user(user_id_hex: *const c_char) -> i32 {
+    let Some(user_id) = parse_hex_32(user_id_hex) else {
+        return invalid_argument("user_id_hex");
+    };
+    let own_public = own_ed25519_public();
+    update_blocklist("unblock_user", own_public, |storage| match blocklist::unblock(storage, user_id)? {
+        true => Ok(()),
+        false => Err(MeshError::Rejected.raise("User is not blocked")),
+    })
+}
+
+/// List blocked users.
+/// Returns JSON array [{ user_id, blocked_at }], null on error.
+#[no_mangle]
+pub extern "C" fn get_blocked_users() -> *mut c_char {
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match blocklist::list(storage).and_then(|users| serde_json::to_string(&users).map_err(|e| e.to_string())) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_blocked_users failed: {}", e)),
+    }
+}
+
+/// Change the blocklist, then give the router the blocked DM channels.
+fn update_blocklist<F>(api: &str, own_public: Option<[u8; 32]>, change: F) -> i32
+where
+    F: FnOnce(&storage::Storage) -> Result<(), String>,
+{
+    let blocked = {
+        let storage_guard = STORAGE.lock().unwrap();
+        let Some(storage) = storage_guard.as_ref() else {
+            return not_initialized("Storage");
+        };
+        let result = change(storage).and_then(|()| match own_public {
+            Some(own_public) => blocklist::blocked_channels(storage, &own_public),
+            None => Ok(Vec::new()),
+        });
+        match result {
+            Ok(ids) => ids,
+            Err(e) => return failed(format!("{} failed: {}", api, e)),
         }
     };
+    if let Some(router) = ROUTER.lock().unwrap().as_ref() {
+        router.set_blocked_channels(&blocked);
+    }
+    0
+}
+
+/// Update friend nickname
+/// Returns 0 on success, a negative error code on error
+#[no_mangle]
+pub extern "C" fn update_friend_nickname(user_id_hex: *const c_char, nickname: *const c_char) -> i32 {
+    let user_id = match parse_hex_32(user_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("user_id_hex"),
+    };
 
-    let user_id_bytes = match hex::decode(user_id_str) {
-        Ok(bytes) => bytes,
-        Err(_) => return -1,
+    let nickname_str = unsafe {
+        if nickname.is_null() {
+            return invalid_argument("nickname");
+        }
+        match std::ffi::CStr::from_ptr(nickname).to_str() {
+            Ok(s) => s.to_string(),
+            Err(_) => return invalid_argument("nickname"),
+        }
     };
 
-    if user_id_bytes.len() != 32 {
-        return -1;
+    match change_friends(|fm, storage| fm.update_nickname(storage, &user_id, nickname_str)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => failed(format!("update_friend_nickname failed: {}", e)),
+        Err(what) => not_initialized(what),
     }
+}
 
-    let mut user_id = [0u8; 32];
-    user_id.copy_from_slice(&user_id_bytes);
+/// Update friend profile (all customizable fields)
+/// Parameters: user_id_hex, nickname (null = no change), notes (null = no change), 
+///             tags_json (null = no change, JSON array of strings), custom_display_name (null = no change, empty string = clear)
+/// Returns 0 on success, a negative error code on error
+#[no_mangle]
+pub extern "C" fn update_friend_profile(
+    user_id_hex: *const c_char,
+    nickname: *const c_char,
+    notes: *const c_char,
+    tags_json: *const c_char,
+    custom_display_name: *const c_char,
+) -> i32 {
+    let user_id = match parse_hex_32(user_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("user_id_hex"),
+    };
 
     let nickname_opt = if nickname.is_null() {
         None
@@ -369,12 +1050,7 @@ pub extern "C" fn update_friend_profile(
     } else {
         unsafe {
             match std::ffi::CStr::from_ptr(tags_json).to_str() {
-                Ok(s) => {
-                    match serde_json::from_str::<Vec<String>>(s) {
-                        Ok(tags) => Some(tags),
-                        Err(_) => None,
-                    }
-                }
+                Ok(s) => serde_json::from_str::<Vec<String>>(s).ok(),
                 Err(_) => None,
             }
         }
@@ -385,21 +1061,19 @@ pub extern "C" fn update_friend_profile(
     } else {
         unsafe {
             match std::ffi::CStr::from_ptr(custom_display_name).to_str() {
-                Ok(s) if s.is_empty() => Some(None), // Clear custom display name
+                Ok("") => Some(None), // Clear custom display name
                 Ok(s) => Some(Some(s.to_string())),
                 Err(_) => None,
             }
         }
     };
 
-    let mut friends_guard = FRIENDS.lock().unwrap();
-    if let Some(ref mut fm) = *friends_guard {
-        match fm.update_profile(&user_id, nickname_opt, notes_opt, tags_opt, custom_display_name_opt) {
-            Ok(_) => 0,
-            Err(_) => -1,
-        }
-    } else {
-        -1
+    match change_friends(|fm, storage| {
+        fm.update_profile(storage, &user_id, nickname_opt, notes_opt, tags_opt, custom_display_name_opt)
+    }) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => failed(format!("update_friend_profile failed: {}", e)),
+        Err(what) => not_initialized(what),
     }
 }
 
@@ -409,20 +1083,21 @@ pub extern "C" fn update_friend_profile(
 pub extern "C" fn export_own_identity() -> *mut c_char {
     let identity_guard = IDENTITY.lock().unwrap();
     if let Some(ref id) = *identity_guard {
-        let export = serde_json::json!({
-            "user_id": identity::user_id_to_hex(&id.public().user_id),
-            "ed25519_public": identity::public_key_to_hex(id.public().ed25519_public.as_bytes()),
-        });
+        let export = ffi_types::IdentityExport {
+            user_id: identity::user_id_to_hex(&id.public().user_id),
+            ed25519_public: identity::public_key_to_hex(id.public().ed25519_public.as_bytes()),
+            x25519_public: identity::public_key_to_hex(id.public().x25519_public.as_bytes()),
+        };
 
         match serde_json::to_string(&export) {
             Ok(json) => CString::new(json)
                 .ok()
                 .map(|s| s.into_raw())
                 .unwrap_or(std::ptr::null_mut()),
-            Err(_) => std::ptr::null_mut(),
+            Err(e) => failed(format!("export_own_identity failed: {}", e)),
         }
     } else {
-        std::ptr::null_mut()
+        not_initialized("Identity")
     }
 }
 
@@ -432,74 +1107,363 @@ pub extern "C" fn export_own_identity() -> *mut c_char {
 pub extern "C" fn import_friend_from_json(json: *const c_char, nickname: *const c_char) -> *mut c_char {
     let json_str = unsafe {
         if json.is_null() {
-            return std::ptr::null_mut();
+            return invalid_argument("json");
         }
         match std::ffi::CStr::from_ptr(json).to_str() {
             Ok(s) => s,
-            Err(_) => return std::ptr::null_mut(),
+            Err(_) => return invalid_argument("json"),
         }
     };
 
     let nickname_str = unsafe {
         if nickname.is_null() {
-            return std::ptr::null_mut();
+            return invalid_argument("nickname");
         }
         match std::ffi::CStr::from_ptr(nickname).to_str() {
             Ok(s) => s.to_string(),
-            Err(_) => return std::ptr::null_mut(),
+            Err(_) => return invalid_argument("nickname"),
         }
     };
 
     match friends::parse_friend_from_json(json_str) {
-        Ok((_, ed25519_public)) => {
-            let mut friends_guard = FRIENDS.lock().unwrap();
-            if let Some(ref mut fm) = *friends_guard {
-                match fm.add_friend(ed25519_public, nickname_str) {
-                    Ok(user_id) => {
-                        let user_id_hex = hex::encode(user_id);
-                        CString::new(user_id_hex)
-                            .ok()
-                            .map(|s| s.into_raw())
-                            .unwrap_or(std::ptr::null_mut())
+        Ok(parsed) => {
+            let ed25519_public = parsed.ed25519_public;
+            let added = match change_friends(|fm, storage| fm.add_friend(storage, ed25519_public, nickname_str)) {
+                Ok(added) => added,
+                Err(what) => return not_initialized(what),
+            };
+            match added {
+                Ok(user_id) => {
+                    record_friend_added(&ed25519_public, &user_id);
+                    if let Some(x25519_public) = parsed.x25519_public {
+                        if let Err(e) = register_friend_x25519_key(user_id, x25519_public) {
+                            eprintln!("Failed to store friend X25519 key: {}", e);
+                        }
                     }
-                    Err(_) => std::ptr::null_mut(),
+                    let user_id_hex = hex::encode(user_id);
+                    CString::new(user_id_hex)
+                        .ok()
+                        .map(|s| s.into_raw())
+                        .unwrap_or(std::ptr::null_mut())
                 }
-            } else {
-                std::ptr::null_mut()
+                Err(e) => failed(format!("import_friend_from_json failed: {}", e)),
+            }
+        }
+        Err(e) => failed(format!("import_friend_from_json failed: {}", e)),
+    }
+}
+
+/// Import contacts exported by another mesh app as friends.
+/// format: "bitchat" | "meshtastic" (see `contact_import`); dry_run != 0 only
+/// reports what would be created or merged.
+/// Returns JSON { format, dry_run, entries: [{action, user_id, nickname}], skipped, created, merged },
+/// null on error.
+#[no_mangle]
+pub extern "C" fn import_contacts(format: *const c_char, data: *const c_char, dry_run: i32) -> *mut c_char {
+    let (Some(format), Some(data)) = (parse_c_str(format), parse_c_str(data)) else {
+        return invalid_argument("format");
+    };
+
+    let result = match change_friends(|friends, storage| {
+        if dry_run != 0 {
+            contact_import::plan(friends, format, data).map(|report| (report, Vec::new()))
+        } else {
+            contact_import::apply(friends, storage, format, data)
+        }
+    }) {
+        Ok(result) => result,
+        Err(what) => return not_initialized(what),
+    };
+
+    match result {
+        Ok((report, created)) => {
+            for (ed25519_public, user_id) in &created {
+                record_friend_added(ed25519_public, user_id);
+            }
+            match serde_json::to_string(&report) {
+                Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+                Err(e) => failed(format!("import_contacts failed: {}", e)),
             }
         }
-        Err(_) => std::ptr::null_mut(),
+        Err(e) => failed(format!("import_contacts failed: {}", e)),
     }
 }
 
 // ========== Storage (Phase 4) ==========
 
 /// Initialize SQLite storage
-/// Returns 0 on success, -1 on error
+/// Built with the `sqlcipher` feature, call after init_identity (or
+/// unlock_identity): the database is then encrypted at rest under the key
+/// sealed with the passphrase, or one derived from the identity without a
+/// passphrase, and an existing unencrypted one is migrated.
+/// Returns 0 on success, a negative error code on error
 #[no_mangle]
 pub extern "C" fn init_storage() -> i32 {
+    if !cfg!(feature = "sqlcipher") {
+        return open_storage(None);
+    }
+    // Newest first; the others open a database whose rekey was interrupted
+    let mut keys = passphrase::db_keys();
+    keys.extend(IDENTITY.lock().unwrap().as_ref().map(|id| id.storage_key()));
+    let fallback = storage::db_path()
+        .ok()
+        .and_then(|path| keys.iter().position(|key| storage::Storage::opens_with(&path, key)))
+        .filter(|&i| i > 0);
+    let Some(i) = fallback else {
+        return open_storage(keys.into_iter().next());
+    };
+    let result = open_storage(Some(keys[i].clone()));
+    if result == 0 && !storage::is_read_only_mode() {
+        if let Err(e) = rekey_open_database(&keys[0]) {
+            eprintln!("Failed to finish the database rekey: {}", e);
+        }
+    }
+    result
+}
+
+/// Initialize storage encrypted at rest under a key the platform keeps (e.g.
+/// in the OS keystore) instead of one derived from the identity. Needs the
+/// `sqlcipher` feature. An existing unencrypted database is encrypted on the
+/// first call; later calls must pass the same key.
+/// key_hex: 32 bytes hex
+/// Returns 0 on success, a negative error code on error (including a wrong key)
+#[no_mangle]
+pub extern "C" fn init_storage_with_key(key_hex: *const c_char) -> i32 {
+    let Some(key) = parse_hex_32(key_hex) else {
+        return invalid_argument("key_hex");
+    };
+    open_storage(Some(zeroize::Zeroizing::new(key)))
+}
+
+fn open_storage(key: Option<zeroize::Zeroizing<[u8; 32]>>) -> i32 {
+    if let Err(failure) = claim_data_dir(false) {
+        return failure;
+    }
     let db_path = match storage::db_path() {
         Ok(p) => p,
-        Err(e) => {
-            eprintln!("Failed to get db path: {}", e);
-            return -1;
-        }
+        Err(e) => return failed(format!("Failed to get db path: {}", e)),
     };
 
-    match storage::Storage::init(&db_path) {
+    let opened = match &key {
+        _ if storage::is_read_only_mode() => storage::Storage::open_read_only(&db_path, key.as_deref()),
+        Some(key) => storage::Storage::init_with_key(&db_path, key),
+        None => storage::Storage::init(&db_path),
+    };
+    match opened {
         Ok(s) => {
-            *STORAGE.lock().unwrap() = Some(s);
+            ensure_outbox_recovery(&s, db_path);
+            install_storage(s);
+            lifecycle::opened(key);
             0
         }
-        Err(e) => {
-            eprintln!("Failed to initialize storage: {}", e);
-            -1
+        Err(e) => failed(format!("Failed to initialize storage: {}", e)),
+    }
+}
+
+/// Load what the core keeps in the database and make it the storage.
+fn install_storage(s: storage::Storage) {
+    load_storage_state(&s);
+    *STORAGE.lock().unwrap() = Some(s);
+    if !storage::is_read_only_mode() {
+        sync_friend_x25519_keys();
+    }
+}
+
+/// Load what the core keeps in the database (on open, and after a restore).
+fn load_storage_state(s: &storage::Storage) {
+    if let Err(e) = settings::apply_memory_budget(s) {
+        eprintln!("Failed to load memory budget: {}", e);
+    }
+    if let Err(e) = clock::load(s) {
+        eprintln!("Failed to load clock high-water mark: {}", e);
+    }
+    if message_index::enabled(s).unwrap_or(false) && !storage::is_read_only_mode() {
+        if let Err(e) = message_index::backfill_search(s) {
+            eprintln!("Failed to backfill the search index: {}", e);
+        }
+    }
+}
+
+/// Run this process in memory only (guest sessions, tests): a new identity,
+/// an empty friend list and an in-memory database, none of them ever written
+/// to disk and all gone when the process exits. Call instead of
+/// init_identity, init_friends and init_storage. There is no data directory
+/// from then on, so those three and everything that keeps files (attachments,
+/// passphrases, identity backups, diagnostics bundles) fail for the rest of
+/// the process.
+/// Returns 0 on success, a negative error code on error
+#[no_mangle]
+pub extern "C" fn init_in_memory() -> i32 {
+    storage::enter_in_memory_mode();
+    let s = match storage::Storage::in_memory() {
+        Ok(s) => s,
+        Err(e) => return failed(format!("Failed to initialize storage: {}", e)),
+    };
+    let friends = match friends::FriendManager::load(&s) {
+        Ok(f) => f,
+        Err(e) => return failed(format!("Failed to initialize friends: {}", e)),
+    };
+    *IDENTITY.lock().unwrap() = Some(identity::Identity::generate());
+    *FRIENDS.lock().unwrap() = Some(friends);
+    install_storage(s);
+    0
+}
+
+// ========== Lifecycle ==========
+
+/// Get ready for the OS to kill the process (call when the app goes to the
+/// background; see `lifecycle`): packets queued for the core are routed, the
+/// router state and the database are flushed, then the database and the data
+/// directory lock are released. Identity, friends and router stay loaded.
+/// Until `resume`, calls that need the database fail and ingested packets are
+/// queued. Running in memory, only the flush happens.
+/// Returns 0 on success (also if already suspended), a negative error code on error
+#[no_mangle]
+pub extern "C" fn suspend() -> i32 {
+    if lifecycle::is_suspended() {
+        return 0;
+    }
+    let own_public = own_ed25519_public();
+    let r_guard = ROUTER.lock().unwrap();
+    let mut storage_guard = STORAGE.lock().unwrap();
+    if let Some(router) = r_guard.as_ref() {
+        for packet in ingest::INGEST.drain() {
+            ingest_routed(router, storage_guard.as_ref(), own_public, packet);
+        }
+        save_router_state(router, storage_guard.as_ref());
+    }
+    if storage::is_in_memory_mode() {
+        return 0;
+    }
+    if let Some(storage) = storage_guard.as_ref() {
+        if !storage::is_read_only_mode() {
+            if let Err(e) = storage.checkpoint() {
+                return failed(format!("suspend failed: {}", e));
+            }
+        }
+    }
+    // Marked before the locks are let go, so nothing is ingested into a closed database
+    lifecycle::suspend(storage_guard.take().is_some());
+    drop(storage_guard);
+    drop(r_guard);
+    instance_lock::release();
+    0
+}
+
+/// Come back from `suspend` (call when the app returns to the foreground):
+/// the data directory lock is taken again, the database reopened with the key
+/// it was opened with, friends and the router's state reloaded and packets
+/// queued meanwhile routed. A process that was not suspended (or is a fresh
+/// start after the OS killed it) is only checked: a database that no longer
+/// answers is reopened.
+/// Returns JSON { reopened, identity, friends, storage, router } saying what
+/// is loaded, so the app inits only what is missing; null on error: InUse if
+/// another process took the data directory meanwhile, or the identity on disk
+/// is no longer the one loaded (the app inits again). Either way the process
+/// stays suspended.
+#[no_mangle]
+pub extern "C" fn resume() -> *mut c_char {
+    let reopen = match lifecycle::suspended() {
+        Some(state) if state.storage => Some(state.key),
+        Some(_) => {
+            if let Err(failure) = claim_data_dir(false) {
+                return failure;
+            }
+            lifecycle::end_suspension();
+            None
+        }
+        None if storage::is_in_memory_mode() => None,
+        None => {
+            let stale = STORAGE.lock().unwrap().as_ref().is_some_and(|s| s.schema_version().is_err());
+            stale.then(lifecycle::opened_key)
+        }
+    };
+    let reopened = reopen.is_some();
+    if let Some(key) = reopen {
+        if let Err(failure) = claim_data_dir(false) {
+            return failure;
+        }
+        if let Err(e) = check_identity_on_disk() {
+            return failed(format!("resume failed: {}", e));
+        }
+        if open_storage(key) != 0 {
+            return std::ptr::null_mut();
+        }
+        if let Err(e) = reload_after_resume() {
+            return failed(format!("resume failed: {}", e));
+        }
+        if ROUTER.lock().unwrap().is_some() {
+            process_ingest_queue();
+        }
+    }
+
+    let report = lifecycle::LifecycleReport {
+        reopened,
+        identity: IDENTITY.lock().unwrap().is_some(),
+        friends: FRIENDS.lock().unwrap().is_some(),
+        storage: STORAGE.lock().unwrap().is_some(),
+        router: ROUTER.lock().unwrap().is_some(),
+    };
+    match serde_json::to_string(&report) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("resume failed: {}", e)),
+    }
+}
+
+/// Err if the identity in the data directory is not the one loaded (another
+/// process switched, wiped or restored it while this one was suspended).
+fn check_identity_on_disk() -> Result<(), String> {
+    let Some(user_id) = IDENTITY.lock().unwrap().as_ref().map(|i| i.public().user_id) else {
+        return Ok(());
+    };
+    let root = storage::root_dir()?;
+    let index = identities::load(&root)?;
+    if index.active != identities::active_name(&root)? {
+        return Err("Another identity was made active; init again".to_string());
+    }
+    let recorded = index.identities.iter().find(|e| e.name == index.active).and_then(|e| e.user_id.clone());
+    if recorded.is_some_and(|recorded| recorded != hex::encode(user_id)) {
+        return Err(MeshError::Rejected.raise("The identity on disk is not the one loaded; init again"));
+    }
+    let path = storage::data_dir()?.join("identity.json");
+    if !path.exists() {
+        return Err("The identity is gone from the data directory; init again".to_string());
+    }
+    match identity::Identity::load_existing() {
+        Ok(on_disk) if on_disk.public().user_id != user_id => Err(MeshError::Rejected.raise("The identity on disk is not the one loaded; init again")),
+        // A protected identity is only checked against the index
+        _ => Ok(()),
+    }
+}
+
+/// Reload what another process may have changed while this one was
+/// suspended: friends, the router's observed, blocked and seen packets, and
+/// the friends' X25519 keys (`open_storage` loads the rest).
+fn reload_after_resume() -> Result<(), String> {
+    let own_public = own_ed25519_public();
+    {
+        let mut friends_guard = FRIENDS.lock().unwrap();
+        let storage_guard = STORAGE.lock().unwrap();
+        let Some(storage) = storage_guard.as_ref() else {
+            return Ok(());
+        };
+        if friends_guard.is_some() {
+            *friends_guard = Some(friends::FriendManager::load(storage)?);
         }
     }
+    if let (Some(router), Some(storage)) = (ROUTER.lock().unwrap().as_ref(), STORAGE.lock().unwrap().as_ref()) {
+        load_router_state(router, storage, own_public);
+    }
+    if !storage::is_read_only_mode() {
+        sync_friend_x25519_keys();
+    }
+    Ok(())
 }
 
 /// Store a message
-/// Returns 0 on success, -1 on error
+/// A timestamp too far in the future is quarantined (see get_quarantined_timestamps).
+/// The arrival counts toward the quiet-hours repeat override.
+/// Returns 0 on success, a negative error code on error
 #[no_mangle]
 pub extern "C" fn store_message(
     message_id_hex: *const c_char,
@@ -510,32 +1474,34 @@ pub extern "C" fn store_message(
 ) -> i32 {
     let message_id = match parse_hex_32(message_id_hex) {
         Some(v) => v,
-        None => return -1,
+        None => return invalid_argument("message_id_hex"),
     };
     let channel_id = match parse_hex_32(channel_id_hex) {
         Some(v) => v,
-        None => return -1,
+        None => return invalid_argument("channel_id_hex"),
     };
     let ciphertext = match parse_hex_vec(ciphertext_hex) {
         Some(v) => v,
-        None => return -1,
+        None => return invalid_argument("ciphertext_hex"),
     };
 
     let storage_guard = STORAGE.lock().unwrap();
     if let Some(ref storage) = *storage_guard {
-        match storage.store_message(message_id, channel_id, ciphertext, timestamp, ttl) {
+        // The time is another node's claim (see `clock::admit_timestamp`)
+        let stored = clock::admit_timestamp(storage, message_id, channel_id, timestamp, now_ts())
+            .and_then(|timestamp| storage.store_message(message_id, channel_id, ciphertext, timestamp, ttl))
+            .and_then(|()| notifications::on_arrival(storage, channel_id, now_ts()));
+        match stored {
             Ok(_) => 0,
-            Err(e) => {
-                eprintln!("store_message failed: {}", e);
-                -1
-            }
+            Err(e) => failed(format!("store_message failed: {}", e)),
         }
     } else {
-        -1
+        not_initialized("Storage")
     }
 }
 
 /// Get messages for a channel as JSON
+/// Signed geo messages also carry sender_pseudonym (see send_geo_message) and text.
 /// Returns JSON string or null on error
 #[no_mangle]
 pub extern "C" fn get_messages(
@@ -545,23 +1511,34 @@ pub extern "C" fn get_messages(
 ) -> *mut c_char {
     let channel_id = match parse_hex_32(channel_id_hex) {
         Some(v) => v,
-        None => return std::ptr::null_mut(),
+        None => return invalid_argument("channel_id_hex"),
     };
 
     let storage_guard = STORAGE.lock().unwrap();
     if let Some(ref storage) = *storage_guard {
-        match storage.fetch_messages(channel_id, limit, offset) {
-            Ok(rows) => {
-                let json_rows: Vec<serde_json::Value> = rows
+        let now = now_ts();
+        let fetched = storage
+            .fetch_live_messages(channel_id, limit, offset, now)
+            .and_then(|rows| Ok((rows, retention::Retention::for_channel(storage, channel_id)?)))
+            .and_then(|(rows, retention)| Ok((rows, retention, storage.get_channel_type(channel_id)?)));
+        match fetched {
+            Ok((rows, retention, channel_type)) => {
+                let geo = channel_type.as_deref() == Some(geo_messages::GEO_CHANNEL_TYPE);
+                let json_rows: Vec<ffi_types::StoredMessage> = rows
                     .into_iter()
-                    .map(|r| {
-                        serde_json::json!({
-                            "message_id": hex::encode(r.message_id),
-                            "channel_id": hex::encode(r.channel_id),
-                            "ciphertext": hex::encode(r.ciphertext),
-                            "timestamp": r.timestamp,
-                            "ttl": r.ttl,
-                        })
+                    .map(|row| {
+                        let signed = if geo && row.kind == storage::MESSAGE_KIND_USER {
+                            geo_messages::attribution(channel_id, &row.ciphertext)
+                        } else {
+                            None
+                        };
+                        let (sender_pseudonym, text) = signed.unzip();
+                        ffi_types::StoredMessage {
+                            expires_in: retention.expires_in(row.timestamp, now),
+                            sender_pseudonym,
+                            text,
+                            ..ffi_types::StoredMessage::from(row)
+                        }
                     })
                     .collect();
                 match serde_json::to_string(&json_rows) {
@@ -569,63 +1546,91 @@ pub extern "C" fn get_messages(
                         .ok()
                         .map(|s| s.into_raw())
                         .unwrap_or(std::ptr::null_mut()),
-                    Err(_) => std::ptr::null_mut(),
+                    Err(e) => failed(format!("get_messages failed: {}", e)),
                 }
             }
-            Err(e) => {
-                eprintln!("get_messages failed: {}", e);
-                std::ptr::null_mut()
-            }
+            Err(e) => failed(format!("get_messages failed: {}", e)),
         }
     } else {
-        std::ptr::null_mut()
+        not_initialized("Storage")
     }
 }
 
-// ========== DM Cryptography ==========
-
-/// Derive DM channel ID from two user IDs (Ed25519 public keys as hex)
-/// Returns channel_id (hex) on success, null on error
+/// Delete messages past their channel type's retention policy (see
+/// `retention`; call periodically). Emits `messages_expired` per channel
+/// and reclaims attachments only the deleted messages referenced.
+/// Returns the number of messages deleted, or a negative error code on error.
 #[no_mangle]
-pub extern "C" fn derive_dm_channel_id(user_id_a_hex: *const c_char, user_id_b_hex: *const c_char) -> *mut c_char {
-    let user_id_a_str = unsafe {
-        if user_id_a_hex.is_null() {
-            return std::ptr::null_mut();
-        }
-        match std::ffi::CStr::from_ptr(user_id_a_hex).to_str() {
-            Ok(s) => s,
-            Err(_) => return std::ptr::null_mut(),
-        }
+pub extern "C" fn prune_expired_messages() -> i32 {
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
     };
-
-    let user_id_b_str = unsafe {
-        if user_id_b_hex.is_null() {
-            return std::ptr::null_mut();
-        }
-        match std::ffi::CStr::from_ptr(user_id_b_hex).to_str() {
-            Ok(s) => s,
-            Err(_) => return std::ptr::null_mut(),
+    match retention::prune(storage, now_ts()) {
+        Ok(count) => {
+            if count > 0 {
+                if let Err(e) = attachments::gc(storage) {
+                    eprintln!("Attachment GC after prune failed: {}", e);
+                }
+            }
+            count as i32
         }
-    };
+        Err(e) => failed(format!("prune_expired_messages failed: {}", e)),
+    }
+}
 
-    let user_id_a_bytes = match hex::decode(user_id_a_str) {
-        Ok(bytes) => bytes,
-        Err(_) => return std::ptr::null_mut(),
+/// Make a conversation's messages disappear `retention_secs` after they were
+/// sent (0: back to its channel type's policy, see `retention`). Fetches leave
+/// out due messages at once; prune_expired_messages deletes them.
+/// Returns 0 on success, a negative error code on error (NotFound for unknown channels).
+#[no_mangle]
+pub extern "C" fn set_channel_retention(channel_id_hex: *const c_char, retention_secs: u64) -> i32 {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
     };
-
-    let user_id_b_bytes = match hex::decode(user_id_b_str) {
-        Ok(bytes) => bytes,
-        Err(_) => return std::ptr::null_mut(),
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
     };
+    match storage.set_channel_retention(channel_id, (retention_secs > 0).then_some(retention_secs)) {
+        Ok(true) => 0,
+        Ok(false) => fail(MeshError::NotFound, "Unknown channel"),
+        Err(e) => failed(format!("set_channel_retention failed: {}", e)),
+    }
+}
 
-    if user_id_a_bytes.len() != 32 || user_id_b_bytes.len() != 32 {
-        return std::ptr::null_mut();
+/// A conversation's disappearing-message time in seconds (0 if it follows its
+/// channel type's policy), or a negative error code on error (NotFound for unknown channels).
+#[no_mangle]
+pub extern "C" fn get_channel_retention(channel_id_hex: *const c_char) -> i64 {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match storage.get_channel_type(channel_id).and_then(|t| Ok((t, storage.get_channel_retention(channel_id)?))) {
+        Ok((Some(_), secs)) => secs.unwrap_or(0).min(i64::MAX as u64) as i64,
+        Ok((None, _)) => fail(MeshError::NotFound, "Unknown channel"),
+        Err(e) => failed(format!("get_channel_retention failed: {}", e)),
     }
+}
 
-    let mut pub_a = [0u8; 32];
-    let mut pub_b = [0u8; 32];
-    pub_a.copy_from_slice(&user_id_a_bytes);
-    pub_b.copy_from_slice(&user_id_b_bytes);
+// ========== DM Cryptography ==========
+
+/// Derive DM channel ID from two user IDs (Ed25519 public keys as hex)
+/// Returns channel_id (hex) on success, null on error
+#[no_mangle]
+pub extern "C" fn derive_dm_channel_id(user_id_a_hex: *const c_char, user_id_b_hex: *const c_char) -> *mut c_char {
+    let pub_a = match parse_hex_32(user_id_a_hex) {
+        Some(v) => v,
+        None => return invalid_argument("user_id_a_hex"),
+    };
+    let pub_b = match parse_hex_32(user_id_b_hex) {
+        Some(v) => v,
+        None => return invalid_argument("user_id_b_hex"),
+    };
 
     let channel_id = dm_crypto::derive_dm_channel_id(&pub_a, &pub_b);
     let channel_id_hex = dm_crypto::dm_channel_id_to_hex(&channel_id);
@@ -636,66 +1641,281 @@ pub extern "C" fn derive_dm_channel_id(user_id_a_hex: *const c_char, user_id_b_h
         .unwrap_or(std::ptr::null_mut())
 }
 
-/// Helper to parse hex string to [u8; 32]
-fn parse_hex_32(hex_ptr: *const c_char) -> Option<[u8; 32]> {
-    if hex_ptr.is_null() {
-        return None;
-    }
-    
-    let hex_str = unsafe {
-        match std::ffi::CStr::from_ptr(hex_ptr).to_str() {
-            Ok(s) => s,
-            Err(_) => return None,
-        }
-    };
-
-    let bytes = match hex::decode(hex_str) {
-        Ok(b) => b,
-        Err(_) => return None,
+/// Register a friend's X25519 public key (their `get_x25519_public_key`),
+/// the static key DM sessions with them are set up against. Needed before
+/// sending them a DM or reading theirs; changing it starts a new session.
+/// Keys in pairing exports (`export_own_identity`) are registered on import.
+/// Returns 0 on success, a negative error code on error
+#[no_mangle]
+pub extern "C" fn set_friend_x25519_key(friend_user_id_hex: *const c_char, x25519_public_hex: *const c_char) -> i32 {
+    let (Some(friend_user_id), Some(x25519_public)) = (parse_hex_32(friend_user_id_hex), parse_hex_32(x25519_public_hex)) else {
+        return invalid_argument("friend_user_id_hex");
     };
-    
-    if bytes.len() != 32 {
-        return None;
+    match register_friend_x25519_key(friend_user_id, x25519_public) {
+        Ok(()) => 0,
+        Err(e) => failed(format!("set_friend_x25519_key failed: {}", e)),
     }
-
-    let mut result = [0u8; 32];
-    result.copy_from_slice(&bytes);
-    Some(result)
 }
 
-/// Helper to parse hex string to Vec<u8>
-fn parse_hex_vec(hex_ptr: *const c_char) -> Option<Vec<u8>> {
-    if hex_ptr.is_null() {
+fn register_friend_x25519_key(friend_user_id: [u8; 32], x25519_public: [u8; 32]) -> Result<(), String> {
+    let identity_guard = IDENTITY.lock().unwrap();
+    let identity = identity_guard.as_ref().ok_or_else(|| MeshError::NotInitialized.raise("Identity not initialized"))?;
+    let mut friends_guard = FRIENDS.lock().unwrap();
+    let friends = friends_guard.as_mut().ok_or_else(|| MeshError::NotInitialized.raise("Friends not initialized"))?;
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = storage_guard.as_ref().ok_or_else(|| MeshError::NotInitialized.raise("Storage not initialized"))?;
+    store_friend_x25519_key(identity, friends, storage, &friend_user_id, x25519_public, now_ts())
+}
+
+/// Save a friend's X25519 key on the friend and as the DM channel's peer key.
+fn store_friend_x25519_key(
+    identity: &identity::Identity,
+    friends: &mut friends::FriendManager,
+    storage: &storage::Storage,
+    friend_user_id: &[u8; 32],
+    x25519_public: [u8; 32],
+    now: i64,
+) -> Result<(), String> {
+    let friend_ed25519_public = friends
+        .get_friend(friend_user_id)
+        .map(|f| f.ed25519_public)
+        .ok_or_else(|| MeshError::NotFound.raise("Not a friend"))?;
+    let channel_id = dm_crypto::derive_dm_channel_id(identity.public().ed25519_public.as_bytes(), &friend_ed25519_public);
+    storage.with_transaction(|s| {
+        s.set_dm_peer_key(channel_id, x25519_public, now)?;
+        friends.set_x25519_public(s, friend_user_id, x25519_public)
+    })
+}
+
+/// Reconcile friends' X25519 keys with the DM peer keys once identity,
+/// friends and storage are all loaded: keys on friends are the source of
+/// truth, and friends migrated without one pick up a key registered earlier.
+fn sync_friend_x25519_keys() {
+    let identity_guard = IDENTITY.lock().unwrap();
+    let mut friends_guard = FRIENDS.lock().unwrap();
+    let storage_guard = STORAGE.lock().unwrap();
+    let (Some(identity), Some(friends), Some(storage)) = (identity_guard.as_ref(), friends_guard.as_mut(), storage_guard.as_ref()) else {
+        return;
+    };
+    let own_ed25519 = identity.public().ed25519_public.as_bytes();
+    let keys: Vec<_> = friends
+        .get_all_friends()
+        .into_iter()
+        .map(|f| (f.user_id, f.ed25519_public, f.x25519_public))
+        .collect();
+    for (user_id, ed25519_public, x25519_public) in keys {
+        let channel_id = dm_crypto::derive_dm_channel_id(own_ed25519, &ed25519_public);
+        let synced = storage.get_dm_peer_key(channel_id).and_then(|stored| match (x25519_public, stored) {
+            (Some(key), stored) if stored != Some(key) => storage.set_dm_peer_key(channel_id, key, now_ts()),
+            (None, Some(stored)) => friends.set_x25519_public(storage, &user_id, stored),
+            _ => Ok(()),
+        });
+        if let Err(e) = synced {
+            eprintln!("Failed to sync friend X25519 key: {}", e);
+        }
+    }
+}
+
+/// Helper to parse hex string to [u8; 32]
+fn parse_hex_32(hex_ptr: *const c_char) -> Option<[u8; 32]> {
+    parse_c_str(hex_ptr).and_then(|s| codec::parse_id_hex(s, "id").ok())
+}
+
+/// Helper to read a UTF-8 C string
+fn parse_c_str<'a>(ptr: *const c_char) -> Option<&'a str> {
+    if ptr.is_null() {
         return None;
     }
+    unsafe { std::ffi::CStr::from_ptr(ptr).to_str().ok() }
+}
 
-    let hex_str = unsafe {
-        match std::ffi::CStr::from_ptr(hex_ptr).to_str() {
-            Ok(s) => s,
-            Err(_) => return None,
+/// Helper to parse hex string to Vec<u8> (bounded by `codec::MAX_PAYLOAD_BYTES`)
+fn parse_hex_vec(hex_ptr: *const c_char) -> Option<Vec<u8>> {
+    parse_c_str(hex_ptr).and_then(|s| codec::parse_hex_payload(s).ok())
+}
+
+/// What an FFI function returns on failure: the error code, or null for
+/// functions returning strings (see `error`).
+trait FfiFailure {
+    fn failure(error: MeshError) -> Self;
+}
+
+impl FfiFailure for i32 {
+    fn failure(error: MeshError) -> Self {
+        error.code()
+    }
+}
+
+impl FfiFailure for i64 {
+    fn failure(error: MeshError) -> Self {
+        error.code() as i64
+    }
+}
+
+impl FfiFailure for *mut c_char {
+    fn failure(_: MeshError) -> Self {
+        std::ptr::null_mut()
+    }
+}
+
+/// Record a failure for last_error_code / last_error_message and return it.
+fn fail<T: FfiFailure>(error: MeshError, message: &str) -> T {
+    T::failure(error::set(error, message))
+}
+
+/// Log an error from the core modules and fail with the code it was raised
+/// with (see `MeshError::raise`).
+fn failed<T: FfiFailure>(message: String) -> T {
+    eprintln!("{}", message);
+    fail(error::code_of(&message), &message)
+}
+
+fn invalid_argument<T: FfiFailure>(name: &str) -> T {
+    fail(MeshError::InvalidArgument, &format!("Invalid {}", name))
+}
+
+fn not_initialized<T: FfiFailure>(what: &str) -> T {
+    fail(MeshError::NotInitialized, &format!("{} not initialized", what))
+}
+
+/// Duplicate suppression for send APIs (see `client_tokens`). With a
+/// client_token, a repeat of the same call within the window returns the first
+/// call's result instead of sending again; a null token always sends.
+/// `args` are the call's C string arguments, which make up its fingerprint.
+fn dedup_send(client_token: *const c_char, api: &str, args: &[*const c_char], send: impl FnOnce() -> *mut c_char) -> *mut c_char {
+    if client_token.is_null() {
+        return send();
+    }
+    let Some(token) = parse_c_str(client_token) else {
+        return invalid_argument("client_token");
+    };
+    let args: Vec<&[u8]> = args
+        .iter()
+        .map(|&p| if p.is_null() { &[][..] } else { unsafe { std::ffi::CStr::from_ptr(p) }.to_bytes() })
+        .collect();
+    let fingerprint = client_tokens::fingerprint(api, &args);
+
+    let _serial = CLIENT_TOKENS.lock().unwrap();
+    let earlier = match STORAGE.lock().unwrap().as_ref() {
+        Some(storage) => client_tokens::lookup(storage, token, &fingerprint, now_ts()),
+        None => return not_initialized("Storage"),
+    };
+    match earlier {
+        Ok(Some(result)) => {
+            return CString::new(result).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut());
+        }
+        Ok(None) => {}
+        Err(e) => return failed(format!("{} failed: {}", api, e)),
+    }
+
+    let result = send();
+    if !result.is_null() {
+        let text = unsafe { std::ffi::CStr::from_ptr(result) }.to_string_lossy().into_owned();
+        if let Some(storage) = STORAGE.lock().unwrap().as_ref() {
+            if let Err(e) = client_tokens::remember(storage, token, fingerprint, &text, now_ts()) {
+                eprintln!("{}: failed to remember client_token: {}", api, e);
+            }
+        }
+    }
+    result
+}
+
+/// Send a message to any conversation (see `messaging`): channel_ref is
+/// `user:<user_id hex>` (a friend's DM, or our notes) or a channel id hex of a
+/// DM, our notes, one of our groups or a registered geo channel. The message
+/// is encrypted (or signed) for that channel, stored and routed; notes stay local.
+/// options_json (nullable): { in_reply_to?: hex (geo only), priority?: "background" | "normal" | "urgent" };
+/// without a priority the one set with `set_next_message_priority` applies.
+/// client_token is optional (see `dedup_send`).
+/// Returns JSON { message_id, channel_id, channel_type, pseudonym?, routed }, null on error.
+#[no_mangle]
+pub extern "C" fn send_message(
+    channel_ref: *const c_char,
+    plaintext: *const c_char,
+    options_json: *const c_char,
+    client_token: *const c_char,
+) -> *mut c_char {
+    dedup_send(client_token, "send_message", &[channel_ref, plaintext, options_json], || send_message_once(channel_ref, plaintext, options_json))
+}
+
+fn send_message_once(channel_ref: *const c_char, plaintext: *const c_char, options_json: *const c_char) -> *mut c_char {
+    let next_priority = priority::take_next();
+    let Some(channel_ref) = parse_c_str(channel_ref) else {
+        return invalid_argument("channel_ref");
+    };
+    let Some(plaintext) = parse_c_str(plaintext) else {
+        return invalid_argument("plaintext");
+    };
+    let mut options = if options_json.is_null() {
+        messaging::SendOptions::default()
+    } else {
+        match parse_c_str(options_json).map(messaging::SendOptions::from_json) {
+            Some(Ok(o)) => o,
+            Some(Err(e)) => return fail(MeshError::InvalidArgument, &e),
+            None => return invalid_argument("options_json"),
         }
     };
+    options.priority = options.priority.or(next_priority);
 
-    hex::decode(hex_str).ok()
+    let id_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = id_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let own_public = *identity.public().ed25519_public.as_bytes();
+    let friends = friend_keys();
+    let r_guard = ROUTER.lock().unwrap();
+    let Some(router) = r_guard.as_ref() else {
+        return not_initialized("Router");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    let now = now_ts();
+    let sent = messaging::resolve(identity, &friends, storage, channel_ref).and_then(|target| messaging::send(identity, storage, target, plaintext, &options, now));
+    match sent {
+        Ok(sent) => {
+            if let Some(packet) = sent.packet {
+                let packet_id = packet.packet_id;
+                route_packet(router, Some(storage), Some(own_public), packet);
+                if sent.stored {
+                    if let Err(e) = send_status::sent(storage, packet_id, now) {
+                        eprintln!("send_message: failed to record send state: {}", e);
+                    }
+                }
+            }
+            match serde_json::to_string(&sent.result) {
+                Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+                Err(e) => failed(format!("send_message failed: {}", e)),
+            }
+        }
+        Err(e) => failed(format!("send_message failed: {}", e)),
+    }
 }
 
 /// Send a DM message (encrypt and store)
-/// Parameters: friend_user_id_hex, plaintext message
+/// Parameters: friend_user_id_hex, plaintext message, optional client_token (see `dedup_send`)
 /// Returns message_id (hex) on success, null on error
 #[no_mangle]
-pub extern "C" fn send_dm_message(friend_user_id_hex: *const c_char, plaintext: *const c_char) -> *mut c_char {
+pub extern "C" fn send_dm_message(friend_user_id_hex: *const c_char, plaintext: *const c_char, client_token: *const c_char) -> *mut c_char {
+    dedup_send(client_token, "send_dm_message", &[friend_user_id_hex, plaintext], || send_dm_message_once(friend_user_id_hex, plaintext))
+}
+
+fn send_dm_message_once(friend_user_id_hex: *const c_char, plaintext: *const c_char) -> *mut c_char {
+    let priority = priority::take_next().unwrap_or_default();
     let friend_user_id = match parse_hex_32(friend_user_id_hex) {
         Some(v) => v,
-        None => return std::ptr::null_mut(),
+        None => return invalid_argument("friend_user_id_hex"),
     };
 
     let plaintext_str = unsafe {
         if plaintext.is_null() {
-            return std::ptr::null_mut();
+            return invalid_argument("plaintext");
         }
         match std::ffi::CStr::from_ptr(plaintext).to_str() {
             Ok(s) => s,
-            Err(_) => return std::ptr::null_mut(),
+            Err(_) => return invalid_argument("plaintext"),
         }
     };
 
@@ -703,118 +1923,173 @@ pub extern "C" fn send_dm_message(friend_user_id_hex: *const c_char, plaintext:
     let identity_guard = IDENTITY.lock().unwrap();
     let identity = match identity_guard.as_ref() {
         Some(id) => id,
-        None => return std::ptr::null_mut(),
+        None => return not_initialized("Identity"),
     };
 
-    let our_user_id = identity.public().user_id;
-    let local_ed25519 = identity.public().ed25519_public.as_bytes();
-    let local_x25519_secret = identity.x25519_secret().as_bytes();
-    let local_x25519_public = identity.public().x25519_public.as_bytes();
-
-    // Check if messaging yourself - use deterministic encryption
-    let is_self = friend_user_id == our_user_id;
-    
-    let (remote_ed25519, remote_x25519_public, remote_x25519_secret, is_initiator) = 
-        if is_self {
-            // For self-messaging, we'll use deterministic encryption
-            // These values won't be used, but we need them for the type
-            let remote_ed25519 = *local_ed25519;
-            let remote_x25519_public = *local_x25519_public;
-            let remote_x25519_secret = *local_x25519_secret;
-            (remote_ed25519, remote_x25519_public, remote_x25519_secret, true)
-        } else {
-            // Get friend's public key (clone to avoid borrow issues)
-            let friend_ed25519_public = {
-                let friends_guard = FRIENDS.lock().unwrap();
-                match friends_guard.as_ref() {
-                    Some(fm) => {
-                        match fm.get_friend(&friend_user_id) {
-                            Some(f) => f.ed25519_public,
-                            None => return std::ptr::null_mut(),
-                        }
-                    }
-                    None => return std::ptr::null_mut(),
-                }
-            };
-
-            let remote_ed25519 = friend_ed25519_public; // Copy the array
-            // For now, use placeholder approach: treat Ed25519 bytes as X25519 (not secure, testing only)
-            let remote_x25519_public = friend_ed25519_public; // Placeholder
-            let remote_x25519_secret = friend_ed25519_public; // Placeholder
-            let is_initiator = our_user_id < friend_user_id;
-            
-            (remote_ed25519, remote_x25519_public, remote_x25519_secret, is_initiator)
-        };
-    
-    // Derive channel ID
-    let channel_id = if is_self {
-        dm_crypto::derive_dm_channel_id(local_ed25519, local_ed25519)
+    // Messages to ourselves are notes; anyone else must be a friend
+    let target = if friend_user_id == identity.public().user_id {
+        messaging::Target::Note
     } else {
-        dm_crypto::derive_dm_channel_id(local_ed25519, &remote_ed25519)
+        let friends_guard = FRIENDS.lock().unwrap();
+        match friends_guard.as_ref().and_then(|fm| fm.get_friend(&friend_user_id)) {
+            Some(f) => messaging::Target::Dm { user_id: friend_user_id, ed25519_public: f.ed25519_public },
+            None => return fail(MeshError::NotFound, "Not a friend"),
+        }
     };
-    
-    // Generate message ID (hash of channel_id + timestamp + plaintext)
-    let timestamp = now_ts();
-    use sha2::{Sha256, Digest};
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(ref storage) = *storage_guard else {
+        return not_initialized("Storage");
+    };
+
+    let options = messaging::SendOptions { priority: Some(priority), ..Default::default() };
+    match messaging::send(identity, storage, target, plaintext_str, &options, now_ts()) {
+        Ok(sent) => CString::new(sent.result.message_id).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("send_dm_message failed: {}", e)),
+    }
+}
+
+/// TTL of DMs we send
+const DM_TTL: u8 = 10;
+
+/// Id of a message we send: SHA256(channel_id || timestamp || plaintext).
+fn outgoing_message_id(channel_id: &[u8; 32], timestamp: i64, plaintext: &str) -> [u8; 32] {
+    use sha2::{Digest, Sha256};
+
     let mut hasher = Sha256::new();
-    hasher.update(&channel_id);
+    hasher.update(channel_id);
     hasher.update(timestamp.to_be_bytes());
-    hasher.update(plaintext_str.as_bytes());
-    let message_id: [u8; 32] = hasher.finalize().into();
-    
-    // Encrypt message
-    let ciphertext = if is_self {
-        // Use deterministic encryption for self-messaging
-        match dm_crypto::encrypt_self_message(&channel_id, &message_id, plaintext_str.as_bytes()) {
-            Ok(c) => c,
-            Err(e) => {
-                eprintln!("Failed to encrypt self-message: {}", e);
-                return std::ptr::null_mut();
-            }
-        }
-    } else {
-        // Use Noise Protocol for friend messaging
-        let mut session = match dm_crypto::create_test_session(
-            local_ed25519,
-            local_x25519_secret,
-            local_x25519_public,
-            &remote_ed25519,
-            &remote_x25519_secret,
-            &remote_x25519_public,
-            is_initiator,
-        ) {
-            Ok(s) => s,
-            Err(e) => {
-                eprintln!("Failed to create session: {}", e);
-                return std::ptr::null_mut();
-            }
-        };
-        
-        match session.encrypt(plaintext_str.as_bytes()) {
-            Ok(c) => c,
-            Err(e) => {
-                eprintln!("Failed to encrypt message: {}", e);
-                return std::ptr::null_mut();
-            }
+    hasher.update(plaintext.as_bytes());
+    hasher.finalize().into()
+}
+
+/// Encrypt a DM (or a note when `friend_ed25519_public` is None) ready for storage.
+/// DMs go out on the friend's ratchet (see `dm_crypto::DmSessionManager`).
+fn encrypt_outgoing_dm(
+    identity: &identity::Identity,
+    storage: &storage::Storage,
+    friend_user_id: [u8; 32],
+    friend_ed25519_public: Option<[u8; 32]>,
+    plaintext: &str,
+    timestamp: i64,
+) -> Result<storage::OutgoingMessage, String> {
+    let local_ed25519 = identity.public().ed25519_public.as_bytes();
+    let channel_id = dm_crypto::derive_dm_channel_id(local_ed25519, friend_ed25519_public.as_ref().unwrap_or(local_ed25519));
+    let message_id = outgoing_message_id(&channel_id, timestamp, plaintext);
+
+    let (ciphertext, channel_type) = match friend_ed25519_public {
+        // Messages to ourselves are notes, encrypted under our own secret
+        None => (notes::encrypt_note(identity, &message_id, plaintext.as_bytes())?, notes::NOTES_CHANNEL_TYPE),
+        Some(_) => {
+            let remote_static = storage
+                .get_dm_peer_key(channel_id)?
+                .ok_or_else(|| format!("No X25519 key registered for friend {}", hex::encode(friend_user_id)))?;
+            let sessions = dm_crypto::DmSessionManager::new(storage, identity.x25519_secret());
+            (sessions.encrypt(channel_id, remote_static, message_id, plaintext.as_bytes(), timestamp)?, "dm")
         }
     };
 
-    // Store message
+    Ok(storage::OutgoingMessage {
+        message_id,
+        channel_id,
+        channel_type,
+        ciphertext,
+        timestamp,
+        ttl: DM_TTL,
+        priority: priority::Priority::Normal as u8,
+    })
+}
+
+/// Send the same text to several friends (or yourself, as a note).
+/// user_ids_json: JSON array of user_id hex. Each recipient gets its own
+/// encryption; all messages are stored in one transaction.
+/// Returns JSON array [{ user_id, message_id, error }] in input order (message_id
+/// null and error set for recipients that failed), or null on error.
+/// client_token is optional (see `dedup_send`).
+#[no_mangle]
+pub extern "C" fn send_dm_to_many(user_ids_json: *const c_char, plaintext: *const c_char, client_token: *const c_char) -> *mut c_char {
+    dedup_send(client_token, "send_dm_to_many", &[user_ids_json, plaintext], || send_dm_to_many_once(user_ids_json, plaintext))
+}
+
+fn send_dm_to_many_once(user_ids_json: *const c_char, plaintext: *const c_char) -> *mut c_char {
+    let priority = priority::take_next().unwrap_or_default();
+    let user_ids: Vec<String> = match parse_c_str(user_ids_json).and_then(|s| serde_json::from_str(s).ok()) {
+        Some(v) => v,
+        None => return invalid_argument("user_ids_json"),
+    };
+    let plaintext = match parse_c_str(plaintext) {
+        Some(s) => s,
+        None => return invalid_argument("plaintext"),
+    };
+
+    let identity_guard = IDENTITY.lock().unwrap();
+    let identity = match identity_guard.as_ref() {
+        Some(id) => id,
+        None => return not_initialized("Identity"),
+    };
+    let own_user_id = identity.public().user_id;
+
+    // Resolve recipients first so FRIENDS is released before STORAGE is taken
+    let recipients: Vec<_> = {
+        let friends_guard = FRIENDS.lock().unwrap();
+        user_ids
+            .iter()
+            .map(|hex_id| -> Result<_, String> {
+                let user_id = codec::parse_id_hex(hex_id, "user id")?;
+                if user_id == own_user_id {
+                    return Ok((user_id, None));
+                }
+                friends_guard
+                    .as_ref()
+                    .and_then(|fm| fm.get_friend(&user_id))
+                    .map(|f| (user_id, Some(f.ed25519_public)))
+                    .ok_or_else(|| MeshError::NotFound.raise("Not a friend"))
+            })
+            .collect()
+    };
+
     let storage_guard = STORAGE.lock().unwrap();
-    if let Some(ref storage) = *storage_guard {
-        if let Err(_) = storage.store_message(message_id, channel_id, ciphertext, timestamp, 10) {
-            return std::ptr::null_mut();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    let timestamp = now_ts();
+    let mut results: Vec<ffi_types::BulkSendResult> = Vec::with_capacity(user_ids.len());
+    let mut outgoing = Vec::new();
+    let mut has_note = false;
+    for (hex_id, recipient) in user_ids.iter().zip(recipients) {
+        let encrypted = recipient.and_then(|(user_id, key)| {
+            has_note |= key.is_none();
+            encrypt_outgoing_dm(identity, storage, user_id, key, plaintext, timestamp)
+        });
+        results.push(ffi_types::BulkSendResult {
+            user_id: hex_id.clone(),
+            message_id: encrypted.as_ref().ok().map(|m| hex::encode(m.message_id)),
+            error: encrypted.as_ref().err().cloned(),
+        });
+        if let Ok(m) = encrypted {
+            outgoing.push(storage::OutgoingMessage { priority: priority as u8, ..m });
         }
+    }
+
+    let stored = if has_note {
+        notes::ensure_channel(storage, identity).map(|_| ())
     } else {
-        return std::ptr::null_mut();
+        Ok(())
+    }
+    .and_then(|_| storage.store_outgoing_batch(&outgoing));
+    if let Err(e) = stored {
+        return failed(format!("send_dm_to_many failed: {}", e));
+    }
+    if let Err(e) = message_index::record_sent(storage, &outgoing, plaintext, timestamp) {
+        eprintln!("Message index error: {}", e);
     }
 
-    // Return message_id
-    let message_id_hex = hex::encode(message_id);
-    CString::new(message_id_hex)
-        .ok()
-        .map(|s| s.into_raw())
-        .unwrap_or(std::ptr::null_mut())
+    match serde_json::to_string(&results) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("send_dm_to_many failed: {}", e)),
+    }
 }
 
 /// Get and decrypt messages for a DM channel
@@ -824,270 +2099,341 @@ pub extern "C" fn send_dm_message(friend_user_id_hex: *const c_char, plaintext:
 pub extern "C" fn get_dm_messages(friend_user_id_hex: *const c_char, limit: u32, offset: u32) -> *mut c_char {
     let friend_user_id = match parse_hex_32(friend_user_id_hex) {
         Some(v) => v,
-        None => return std::ptr::null_mut(),
+        None => return invalid_argument("friend_user_id_hex"),
     };
 
     // Get our identity
     let identity_guard = IDENTITY.lock().unwrap();
     let identity = match identity_guard.as_ref() {
         Some(id) => id,
-        None => return std::ptr::null_mut(),
+        None => return not_initialized("Identity"),
     };
 
-    let our_user_id = identity.public().user_id;
-    let our_ed25519 = identity.public().ed25519_public.as_bytes();
-    let local_x25519_secret = identity.x25519_secret().as_bytes();
-    let local_x25519_public = identity.public().x25519_public.as_bytes();
-
-    // Check if messaging yourself and get remote keys
-    let (remote_ed25519, remote_x25519_public, remote_x25519_secret, encrypt_role, channel_id) = 
-        if friend_user_id == our_user_id {
-            // Messaging yourself - use your own keys (proper X25519 keys)
-            let channel_id = dm_crypto::derive_dm_channel_id(our_ed25519, our_ed25519);
-            let remote_ed25519 = *our_ed25519; // Copy the array
-            let remote_x25519_public = *local_x25519_public; // Our own X25519 public
-            let remote_x25519_secret = *local_x25519_secret; // Our own X25519 secret
-            (remote_ed25519, remote_x25519_public, remote_x25519_secret, true, channel_id) // Always initiator for self
-        } else {
-            // Get friend's public key (clone to avoid borrow issues)
-            let friend_ed25519_public = {
-                let friends_guard = FRIENDS.lock().unwrap();
-                match friends_guard.as_ref() {
-                    Some(fm) => {
-                        match fm.get_friend(&friend_user_id) {
-                            Some(f) => f.ed25519_public,
-                            None => return std::ptr::null_mut(),
-                        }
-                    }
-                    None => return std::ptr::null_mut(),
-                }
-            };
-
-            let channel_id = dm_crypto::derive_dm_channel_id(our_ed25519, &friend_ed25519_public);
-            let remote_ed25519 = friend_ed25519_public; // Copy the array
-            // TODO: In production, we'd store X25519 public keys for friends
-            // For now, use placeholder approach: treat Ed25519 bytes as X25519 (not secure, testing only)
-            let remote_x25519_public = friend_ed25519_public; // Placeholder - should be friend's X25519 public
-            let remote_x25519_secret = friend_ed25519_public; // Placeholder - we don't have friend's X25519 secret
-            let is_initiator = our_user_id < friend_user_id;
-            
-            (remote_ed25519, remote_x25519_public, remote_x25519_secret, is_initiator, channel_id)
-        };
+    let friend_ed25519_public = match dm_history_peer(identity, friend_user_id) {
+        Ok(key) => key,
+        Err(failure) => return failure,
+    };
 
-    // Get messages from storage
     let storage_guard = STORAGE.lock().unwrap();
-    let messages = match storage_guard.as_ref() {
-        Some(storage) => {
-            match storage.fetch_messages(channel_id, limit, offset) {
-                Ok(rows) => rows,
-                Err(e) => {
-                    eprintln!("Failed to fetch messages: {}", e);
-                    return std::ptr::null_mut();
-                }
-            }
-        }
-        None => {
-            eprintln!("Storage not initialized");
-            return std::ptr::null_mut();
-        }
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
     };
 
-    eprintln!("Found {} messages for channel_id: {}", messages.len(), hex::encode(channel_id));
-
-    // Keys for decryption
-    let local_ed25519 = our_ed25519;
-
-    // Decrypt messages
-    let mut decrypted_messages = Vec::new();
-    let is_self = friend_user_id == our_user_id;
-    
-    for msg in messages {
-        let plaintext_result: Result<Vec<u8>, String> = if is_self {
-            // Try deterministic decryption first (new method)
-            let mut result = dm_crypto::decrypt_self_message(&channel_id, &msg.message_id, &msg.ciphertext);
-            
-            // If deterministic decryption fails, try Noise Protocol (old method for backwards compatibility)
-            if result.is_err() {
-                eprintln!("Deterministic decryption failed, trying Noise Protocol for message {}", hex::encode(msg.message_id));
-                let decrypt_role = !encrypt_role; // Try opposite role
-                
-                // Try to create session with opposite role
-                let mut session_opt = dm_crypto::create_test_session(
-                    local_ed25519,
-                    local_x25519_secret,
-                    local_x25519_public,
-                    &remote_ed25519,
-                    &remote_x25519_secret,
-                    &remote_x25519_public,
-                    decrypt_role,
-                ).ok();
-                
-                // If that failed, try same role as fallback
-                if session_opt.is_none() {
-                    session_opt = dm_crypto::create_test_session(
-                        local_ed25519,
-                        local_x25519_secret,
-                        local_x25519_public,
-                        &remote_ed25519,
-                        &remote_x25519_secret,
-                        &remote_x25519_public,
-                        encrypt_role,
-                    ).ok();
-                }
-                
-                // Try to decrypt with Noise session if we have one
-                if let Some(mut session) = session_opt {
-                    result = session.decrypt(&msg.ciphertext);
-                } else {
-                    eprintln!("Failed to create Noise session with either role for message {}", hex::encode(msg.message_id));
-                }
-            }
-            result
-        } else {
-            // Use Noise Protocol for friend messaging
-            // In Noise IK pattern:
-            // - Initiator encrypts with write_message, responder decrypts with read_message
-            // - Responder encrypts with write_message, initiator decrypts with read_message
-            // So if we encrypted as initiator, we must decrypt as responder (and vice versa)
-            let decrypt_role = !encrypt_role;
-            
-            // Try decrypting with the opposite role first (correct approach)
-            let mut session = match dm_crypto::create_test_session(
-                local_ed25519,
-                local_x25519_secret,
-                local_x25519_public,
-                &remote_ed25519,
-                &remote_x25519_secret,
-                &remote_x25519_public,
-                decrypt_role,
-            ) {
-                Ok(s) => s,
-                Err(e) => {
-                    eprintln!("Failed to create decrypt session (role {}): {}", decrypt_role, e);
-                    continue;
-                }
-            };
-            
-            match session.decrypt(&msg.ciphertext) {
-                Ok(bytes) => Ok(bytes),
-                Err(e) => {
-                    eprintln!("Failed to decrypt message {} with role {}: {}", hex::encode(msg.message_id), decrypt_role, e);
-                    // Try same role as fallback
-                    let mut fallback_session = match dm_crypto::create_test_session(
-                        local_ed25519,
-                        local_x25519_secret,
-                        local_x25519_public,
-                        &remote_ed25519,
-                        &remote_x25519_secret,
-                        &remote_x25519_public,
-                        encrypt_role,
-                    ) {
-                        Ok(s) => s,
-                        Err(_) => {
-                            eprintln!("Could not decrypt message {} with either role", hex::encode(msg.message_id));
-                            continue;
-                        }
-                    };
-                    fallback_session.decrypt(&msg.ciphertext)
-                }
-            }
+    let decrypted_messages =
+        match read_dm_history(identity, storage, friend_ed25519_public, limit, offset) {
+            Ok(m) => m,
+            Err(e) => return failed(format!("Failed to fetch messages: {}", e)),
         };
-        
-        match plaintext_result {
-            Ok(plaintext_bytes) => {
-                match String::from_utf8(plaintext_bytes) {
-                    Ok(plaintext) => {
-                        decrypted_messages.push(serde_json::json!({
-                            "message_id": hex::encode(msg.message_id),
-                            "plaintext": plaintext,
-                            "timestamp": msg.timestamp,
-                            "is_sent": is_self || encrypt_role, // Self-messages are always sent by us
-                        }));
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to decode plaintext as UTF-8: {}", e);
-                    }
-                }
-            }
-            Err(e) => {
-                eprintln!("Failed to decrypt message {}: {}", hex::encode(msg.message_id), e);
-            }
-        }
-    }
 
     match serde_json::to_string(&decrypted_messages) {
         Ok(s) => CString::new(s)
             .ok()
             .map(|s| s.into_raw())
             .unwrap_or(std::ptr::null_mut()),
-        Err(_) => std::ptr::null_mut(),
+        Err(e) => failed(format!("get_dm_messages failed: {}", e)),
     }
 }
 
-/// Clear all messages for a DM channel
-/// Parameters: friend_user_id_hex
-/// Returns 0 on success, -1 on error
+/// Get and decrypt the messages of a DM channel stored since a cursor, for
+/// incremental updates instead of paging again after every packet. Messages
+/// come in the order they were stored (a placeholder filled by its message
+/// comes again), not by timestamp.
+/// Parameters: friend_user_id_hex, after_seq (0 for everything, else the
+/// cursor of the previous call), limit
+/// Returns JSON { messages: [message as in get_dm_messages], cursor }, null on error
 #[no_mangle]
-pub extern "C" fn clear_dm_messages(friend_user_id_hex: *const c_char) -> i32 {
-    let friend_user_id = match parse_hex_32(friend_user_id_hex) {
-        Some(v) => v,
-        None => return -1,
+pub extern "C" fn get_dm_messages_since(friend_user_id_hex: *const c_char, after_seq: i64, limit: u32) -> *mut c_char {
+    let Some(friend_user_id) = parse_hex_32(friend_user_id_hex) else {
+        return invalid_argument("friend_user_id_hex");
     };
-
-    // Get our identity
     let identity_guard = IDENTITY.lock().unwrap();
-    let identity = match identity_guard.as_ref() {
-        Some(id) => id,
-        None => return -1,
+    let Some(identity) = identity_guard.as_ref() else {
+        return not_initialized("Identity");
     };
-
-    let our_ed25519 = identity.public().ed25519_public.as_bytes();
-    
-    // Derive channel ID
-    let channel_id = if friend_user_id == identity.public().user_id {
-        // Self-messaging
-        dm_crypto::derive_dm_channel_id(our_ed25519, our_ed25519)
-    } else {
-        // Get friend's public key
-        let friends_guard = FRIENDS.lock().unwrap();
-        let friend_ed25519_public = match friends_guard.as_ref() {
-            Some(fm) => {
-                match fm.get_friend(&friend_user_id) {
-                    Some(f) => f.ed25519_public,
-                    None => return -1,
-                }
-            }
-            None => return -1,
-        };
-        dm_crypto::derive_dm_channel_id(our_ed25519, &friend_ed25519_public)
+    let friend_ed25519_public = match dm_history_peer(identity, friend_user_id) {
+        Ok(key) => key,
+        Err(failure) => return failure,
     };
-
-    // Delete messages
     let storage_guard = STORAGE.lock().unwrap();
-    match storage_guard.as_ref() {
-        Some(storage) => {
-            match storage.delete_channel_messages(channel_id) {
-                Ok(_) => 0,
-                Err(_) => -1,
-            }
-        }
-        None => -1,
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    match read_dm_changes(identity, storage, friend_ed25519_public, after_seq, limit)
+        .and_then(|changes| serde_json::to_string(&changes).map_err(|e| e.to_string()))
+    {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_dm_messages_since failed: {}", e)),
     }
 }
 
-/// Helper: current timestamp seconds since UNIX_EPOCH
-fn now_ts() -> i64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs() as i64
+/// The friend whose DMs are read: their Ed25519 key, or None for our own
+/// user id (notes).
+fn dm_history_peer(identity: &identity::Identity, friend_user_id: [u8; 32]) -> Result<Option<[u8; 32]>, *mut c_char> {
+    if friend_user_id == identity.public().user_id {
+        return Ok(None);
+    }
+    let friends_guard = FRIENDS.lock().unwrap();
+    match friends_guard.as_ref().and_then(|fm| fm.get_friend(&friend_user_id)) {
+        Some(f) => Ok(Some(f.ed25519_public)),
+        None => Err(fail(MeshError::NotFound, "Not a friend")),
+    }
 }
 
-/// Test encrypt/decrypt roundtrip (Phase 3 testing)
-/// 
-/// This function demonstrates the encryption/decryption APIs work correctly.
-/// It requires both peers' keys to simulate the handshake.
-/// 
+/// Keys for decrypting one DM conversation.
+struct DmKeys {
+    channel_id: [u8; 32],
+    is_self: bool,
+}
+
+/// Keys for the DM with a friend, or with ourselves (notes) when
+/// friend_ed25519_public is None.
+fn dm_keys(identity: &identity::Identity, friend_ed25519_public: Option<[u8; 32]>) -> DmKeys {
+    let our_ed25519 = identity.public().ed25519_public.as_bytes();
+    DmKeys {
+        channel_id: dm_crypto::derive_dm_channel_id(our_ed25519, friend_ed25519_public.as_ref().unwrap_or(our_ed25519)),
+        is_self: friend_ed25519_public.is_none(),
+    }
+}
+
+/// Decrypt one stored user message of a DM conversation.
+fn decrypt_dm_row(
+    identity: &identity::Identity,
+    storage: &storage::Storage,
+    keys: &DmKeys,
+    msg: &storage::MessageRow,
+) -> Result<dm_crypto::OpenedDm, String> {
+    if keys.is_self {
+        // Self-messages are always sent by us
+        let outgoing = |plaintext| dm_crypto::OpenedDm { plaintext, outgoing: true };
+        // Try deterministic decryption first (new method)
+        let mut result = notes::decrypt_note(identity, &msg.message_id, &msg.ciphertext);
+
+        // If deterministic decryption fails, try Noise Protocol (old method for backwards compatibility)
+        if result.is_err() {
+            eprintln!("Deterministic decryption failed, trying Noise Protocol for message {}", hex::encode(msg.message_id));
+            let local_ed25519 = identity.public().ed25519_public.as_bytes();
+            let local_x25519_secret = identity.x25519_secret().as_bytes();
+            let local_x25519_public = identity.public().x25519_public.as_bytes();
+            let session_for = |role: bool| {
+                dm_crypto::create_test_session(
+                    local_ed25519,
+                    local_x25519_secret,
+                    local_x25519_public,
+                    local_ed25519,
+                    local_x25519_secret,
+                    local_x25519_public,
+                    role,
+                )
+            };
+            // Try the responder role, then the initiator role as fallback
+            let session_opt = session_for(false).ok().or_else(|| session_for(true).ok());
+
+            // Try to decrypt with Noise session if we have one
+            if let Some(mut session) = session_opt {
+                result = session.decrypt(&msg.ciphertext);
+            } else {
+                eprintln!("Failed to create Noise session with either role for message {}", hex::encode(msg.message_id));
+            }
+        }
+        return result.map(outgoing);
+    }
+
+    let sessions = dm_crypto::DmSessionManager::new(storage, identity.x25519_secret());
+    let remote_static = storage.get_dm_peer_key(keys.channel_id)?;
+    sessions.decrypt(keys.channel_id, remote_static, msg.message_id, &msg.ciphertext, now_ts())
+}
+
+/// Fetch and decrypt a DM channel's history as JSON message objects.
+/// friend_ed25519_public is None for the channel with ourselves (notes).
+fn read_dm_history(
+    identity: &identity::Identity,
+    storage: &storage::Storage,
+    friend_ed25519_public: Option<[u8; 32]>,
+    limit: u32,
+    offset: u32,
+) -> Result<Vec<ffi_types::DmMessage>, String> {
+    let keys = dm_keys(identity, friend_ed25519_public);
+    register_dm_channel(identity, storage, &keys);
+    let now = now_ts();
+    let messages = storage.fetch_live_messages(keys.channel_id, limit, offset, now)?;
+
+    eprintln!("Found {} messages for channel_id: {}", messages.len(), hex::encode(keys.channel_id));
+    open_dm_messages(identity, storage, &keys, messages, now)
+}
+
+/// A DM channel's messages stored since the cursor `after_seq` (see
+/// `Storage::fetch_messages_since_seq`), with the cursor to pass next time.
+fn read_dm_changes(
+    identity: &identity::Identity,
+    storage: &storage::Storage,
+    friend_ed25519_public: Option<[u8; 32]>,
+    after_seq: i64,
+    limit: u32,
+) -> Result<ffi_types::DmMessagesSince, String> {
+    let keys = dm_keys(identity, friend_ed25519_public);
+    register_dm_channel(identity, storage, &keys);
+    let now = now_ts();
+    let rows = storage.fetch_messages_since_seq(keys.channel_id, after_seq, limit, now)?;
+    // Past messages that do not open too, so they are not fetched again
+    let cursor = rows.last().map_or(after_seq, |(seq, _)| *seq);
+    let messages = open_dm_messages(identity, storage, &keys, rows.into_iter().map(|(_, row)| row).collect(), now)?;
+    Ok(ffi_types::DmMessagesSince { messages, cursor })
+}
+
+/// Register a DM channel (or the notes channel) before reading it.
+fn register_dm_channel(identity: &identity::Identity, storage: &storage::Storage, keys: &DmKeys) {
+    let registered = if keys.is_self {
+        notes::ensure_channel(storage, identity).map(|_| ())
+    } else {
+        storage.upsert_channel(keys.channel_id, "dm")
+    };
+    if let Err(e) = registered {
+        eprintln!("Failed to register DM channel: {}", e);
+    }
+}
+
+/// Decrypt stored DM messages as JSON message objects (messages that do not
+/// open are skipped).
+fn open_dm_messages(
+    identity: &identity::Identity,
+    storage: &storage::Storage,
+    keys: &DmKeys,
+    messages: Vec<storage::MessageRow>,
+    now: i64,
+) -> Result<Vec<ffi_types::DmMessage>, String> {
+    let channel_id = keys.channel_id;
+    let retention = retention::Retention::for_channel(storage, channel_id)?;
+    let index = message_index::enabled(storage)?;
+
+    // Decrypt messages
+    let mut decrypted_messages = Vec::new();
+    for msg in messages {
+        if msg.kind == storage::MESSAGE_KIND_PENDING {
+            decrypted_messages.push(ffi_types::DmMessage {
+                expires_in: retention.expires_in(msg.timestamp, now),
+                ..ffi_types::DmMessage::pending(&msg.message_id, msg.timestamp)
+            });
+            continue;
+        }
+        if msg.kind == storage::MESSAGE_KIND_SYSTEM {
+            match system_messages::parse(&msg.ciphertext) {
+                Ok(event) => decrypted_messages.push(ffi_types::DmMessage {
+                    expires_in: retention.expires_in(msg.timestamp, now),
+                    ..ffi_types::DmMessage::system(&msg.message_id, event, msg.timestamp)
+                }),
+                Err(e) => eprintln!("Skipping system message {}: {}", hex::encode(msg.message_id), e),
+            }
+            continue;
+        }
+
+        // Decrypted once already when the message index is on
+        let indexed = if index { storage.get_message_plaintext(msg.message_id)? } else { None };
+        let opened = match indexed {
+            Some(row) => Ok((row.plaintext, row.outgoing)),
+            None => decrypt_dm_row(identity, storage, keys, &msg).and_then(|opened| {
+                let plaintext = String::from_utf8(opened.plaintext)
+                    .map_err(|e| format!("Failed to decode plaintext as UTF-8: {}", e))?;
+                if index {
+                    if let Err(e) = message_index::keep(storage, msg.message_id, channel_id, opened.outgoing, &plaintext, now) {
+                        eprintln!("Message index error: {}", e);
+                    }
+                }
+                Ok((plaintext, opened.outgoing))
+            }),
+        };
+        match opened {
+            Ok((plaintext, outgoing)) => {
+                let receipt = if outgoing && !keys.is_self {
+                    receipts::summary(storage, identity.public().user_id, msg.message_id)?
+                } else {
+                    None
+                };
+                let send_state = if outgoing && !keys.is_self { send_status::get(storage, msg.message_id)? } else { None };
+                decrypted_messages.push(ffi_types::DmMessage {
+                    expires_in: retention.expires_in(msg.timestamp, now),
+                    receipt: receipt.map(receipts::ReceiptStatus::name),
+                    send_state: send_state.and_then(|row| send_status::SendState::from_u8(row.state)).map(send_status::SendState::name),
+                    priority: ffi_types::shown_priority(msg.priority),
+                    ..ffi_types::DmMessage::user(&msg.message_id, plaintext, msg.timestamp, outgoing)
+                });
+            }
+            Err(e) => {
+                eprintln!("Failed to decrypt message {}: {}", hex::encode(msg.message_id), e);
+            }
+        }
+    }
+
+    Ok(decrypted_messages)
+}
+
+/// Clear all messages for a DM channel
+/// Parameters: friend_user_id_hex
+/// Returns 0 on success, a negative error code on error
+#[no_mangle]
+pub extern "C" fn clear_dm_messages(friend_user_id_hex: *const c_char) -> i32 {
+    let friend_user_id = match parse_hex_32(friend_user_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("friend_user_id_hex"),
+    };
+
+    // Get our identity
+    let identity_guard = IDENTITY.lock().unwrap();
+    let identity = match identity_guard.as_ref() {
+        Some(id) => id,
+        None => return not_initialized("Identity"),
+    };
+
+    let our_ed25519 = identity.public().ed25519_public.as_bytes();
+    
+    // Derive channel ID
+    let channel_id = if friend_user_id == identity.public().user_id {
+        // Self-messaging
+        dm_crypto::derive_dm_channel_id(our_ed25519, our_ed25519)
+    } else {
+        // Get friend's public key
+        let friends_guard = FRIENDS.lock().unwrap();
+        let friend_ed25519_public = match friends_guard.as_ref() {
+            Some(fm) => {
+                match fm.get_friend(&friend_user_id) {
+                    Some(f) => f.ed25519_public,
+                    None => return fail(MeshError::NotFound, "Not a friend"),
+                }
+            }
+            None => return not_initialized("Friends"),
+        };
+        dm_crypto::derive_dm_channel_id(our_ed25519, &friend_ed25519_public)
+    };
+
+    // Delete messages, then reclaim attachments they were the last reference to
+    let storage_guard = STORAGE.lock().unwrap();
+    match storage_guard.as_ref() {
+        Some(storage) => {
+            match storage.delete_channel_messages(channel_id) {
+                Ok(_) => {
+                    if let Err(e) = attachments::gc(storage) {
+                        eprintln!("Attachment GC after clear failed: {}", e);
+                    }
+                    0
+                }
+                Err(e) => failed(format!("clear_dm_messages failed: {}", e)),
+            }
+        }
+        None => not_initialized("Storage"),
+    }
+}
+
+/// Helper: current timestamp seconds since UNIX_EPOCH, as corrected by `clock`
+fn now_ts() -> i64 {
+    clock::now()
+}
+
+/// Test encrypt/decrypt roundtrip (Phase 3 testing)
+/// 
+/// This function demonstrates the encryption/decryption APIs work correctly.
+/// It requires both peers' keys to simulate the handshake.
+/// 
 /// Returns: "OK" on success, error message on failure
 #[no_mangle]
 pub extern "C" fn test_dm_encrypt_decrypt(
@@ -1231,341 +2577,4859 @@ pub extern "C" fn test_dm_encrypt_decrypt(
         .map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut())
 }
 
-// ========== Geohash Channels (Phase 7) ==========
+// ========== Message Priority ==========
 
-/// Derive a geohash channel id from geohash + topic.
-/// Returns channel_id hex on success, null on error.
+/// Mark the next message sent from this thread (send_dm_message,
+/// send_dm_to_many, send_group_message, send_packet) "urgent", "normal" or
+/// "background" (see `priority`). The send resets it to normal.
+/// Returns 0 on success, a negative error code on error.
 #[no_mangle]
-pub extern "C" fn derive_geo_channel_id(
-    geohash_ptr: *const c_char,
-    topic_ptr: *const c_char,
-) -> *mut c_char {
-    let geohash = unsafe {
-        if geohash_ptr.is_null() {
-            return std::ptr::null_mut();
+pub extern "C" fn set_next_message_priority(priority: *const c_char) -> i32 {
+    match parse_c_str(priority).map(priority::Priority::parse) {
+        Some(Ok(p)) => {
+            priority::set_next(p);
+            0
         }
-        match std::ffi::CStr::from_ptr(geohash_ptr).to_str() {
-            Ok(s) => s,
-            Err(_) => return std::ptr::null_mut(),
+        _ => invalid_argument("priority"),
+    }
+}
+
+// ========== Forwarding ==========
+
+/// (user_id, ed25519_public) of every friend, copied so FRIENDS is released
+/// before STORAGE is taken.
+fn friend_keys() -> Vec<([u8; 32], [u8; 32])> {
+    FRIENDS
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|fm| fm.get_all_friends().iter().map(|f| (f.user_id, f.ed25519_public)).collect())
+        .unwrap_or_default()
+}
+
+/// The friend (user_id, ed25519_public) whose DM with us is `channel_id`.
+fn dm_friend_for_channel(
+    identity: &identity::Identity,
+    friends: &[([u8; 32], [u8; 32])],
+    channel_id: [u8; 32],
+) -> Option<([u8; 32], [u8; 32])> {
+    let own_ed25519 = identity.public().ed25519_public.as_bytes();
+    friends
+        .iter()
+        .copied()
+        .find(|(_, key)| dm_crypto::derive_dm_channel_id(own_ed25519, key) == channel_id)
+}
+
+/// Key material that decrypts a stored user message of a notes, DM or protected channel.
+fn message_key_material(
+    identity: &identity::Identity,
+    friends: &[([u8; 32], [u8; 32])],
+    storage: &storage::Storage,
+    row: &storage::MessageRow,
+) -> Result<starred::KeyMaterial, String> {
+    if row.kind != storage::MESSAGE_KIND_USER {
+        return Err(MeshError::Rejected.raise("Not a user message"));
+    }
+    match storage.get_channel_type(row.channel_id)?.as_deref() {
+        Some(notes::NOTES_CHANNEL_TYPE) => Ok(starred::KeyMaterial::Notes),
+        Some("dm") => dm_friend_for_channel(identity, friends, row.channel_id)
+            .map(|(_, key)| starred::KeyMaterial::Dm { friend_ed25519_public: key })
+            .ok_or_else(|| MeshError::NotFound.raise("DM is with someone who is not a friend")),
+        _ => channel_key_for_message(storage, row)?
+            .map(|key| starred::KeyMaterial::Channel { key })
+            .ok_or_else(|| MeshError::Crypto.raise("Messages of this channel cannot be decrypted")),
+    }
+}
+
+/// The key of a protected channel that opens a stored message: the key
+/// epoch it was sealed under, else the current key.
+fn channel_key_for_message(storage: &storage::Storage, row: &storage::MessageRow) -> Result<Option<[u8; 32]>, String> {
+    let epochs = storage.list_channel_key_epochs(row.channel_id)?;
+    let sealed_with = epochs
+        .iter()
+        .map(|epoch| epoch.key)
+        .find(|key| forward::open_for_channel(key, &row.message_id, &row.ciphertext).is_ok());
+    match sealed_with {
+        Some(key) => Ok(Some(key)),
+        None => storage.get_channel_key(row.channel_id),
+    }
+}
+
+/// Decrypt a user message and work out who wrote it (None when the channel
+/// does not tell us).
+fn decrypt_with_material(
+    identity: &identity::Identity,
+    storage: &storage::Storage,
+    material: starred::KeyMaterial,
+    row: &storage::MessageRow,
+) -> Result<(String, Option<[u8; 32]>), String> {
+    let own_user_id = identity.public().user_id;
+    let (plaintext, author) = match material {
+        starred::KeyMaterial::Notes => {
+            let keys = dm_keys(identity, None);
+            (decrypt_dm_row(identity, storage, &keys, row)?.plaintext, Some(own_user_id))
+        }
+        starred::KeyMaterial::Dm { friend_ed25519_public } => {
+            let user_id = starred::KeyMaterial::friend_user_id(&friend_ed25519_public);
+            let keys = dm_keys(identity, Some(friend_ed25519_public));
+            let opened = decrypt_dm_row(identity, storage, &keys, row)?;
+            (opened.plaintext, Some(if opened.outgoing { own_user_id } else { user_id }))
+        }
+        starred::KeyMaterial::Channel { key } => {
+            let opened = compression::unpack(forward::open_for_channel(&key, &row.message_id, &row.ciphertext)?)?;
+            let opened = String::from_utf8(opened).map_err(|e| MeshError::InvalidArgument.raise(format!("Message is not UTF-8: {}", e)))?;
+            let (sender, text) = groups::parse_message(&opened);
+            let author = sender.and_then(|s| codec::parse_id_hex(&s, "sender").ok());
+            return Ok((text, author));
         }
     };
+    let plaintext = String::from_utf8(plaintext).map_err(|e| MeshError::InvalidArgument.raise(format!("Message is not UTF-8: {}", e)))?;
+    Ok((plaintext, author))
+}
 
-    let topic = unsafe {
-        if topic_ptr.is_null() {
-            return std::ptr::null_mut();
-        }
-        match std::ffi::CStr::from_ptr(topic_ptr).to_str() {
-            Ok(s) => s,
-            Err(_) => return std::ptr::null_mut(),
+/// Re-wrap a stored message for `target` and store the copy. Returns the new message id.
+fn forward_stored_message(
+    identity: &identity::Identity,
+    friends: &[([u8; 32], [u8; 32])],
+    storage: &storage::Storage,
+    message_id: [u8; 32],
+    target: [u8; 32],
+    timestamp: i64,
+) -> Result<[u8; 32], String> {
+    let row = storage.get_message(message_id)?.ok_or_else(|| MeshError::NotFound.raise("Message not found"))?;
+    let material = message_key_material(identity, friends, storage, &row)?;
+    let (plaintext, author) = decrypt_with_material(identity, storage, material, &row)?;
+    let attachments: Vec<[u8; 32]> = storage
+        .list_message_attachments(message_id)?
+        .into_iter()
+        .map(|a| a.attachment_id)
+        .collect();
+
+    let origin = forward::ForwardedFrom {
+        author_user_id: author.map(hex::encode),
+        message_id: hex::encode(row.message_id),
+        channel_id: hex::encode(row.channel_id),
+        timestamp: row.timestamp,
+    };
+    let envelope = forward::ForwardEnvelope::wrap(&plaintext, origin, attachments.iter().map(hex::encode).collect());
+    let body = envelope.encode()?;
+
+    let own_user_id = identity.public().user_id;
+    let own_ed25519 = identity.public().ed25519_public.as_bytes();
+    let outgoing = if target == dm_crypto::derive_dm_channel_id(own_ed25519, own_ed25519) {
+        notes::ensure_channel(storage, identity)?;
+        encrypt_outgoing_dm(identity, storage, own_user_id, None, &body, timestamp)?
+    } else if let Some((user_id, key)) = dm_friend_for_channel(identity, friends, target) {
+        encrypt_outgoing_dm(identity, storage, user_id, Some(key), &body, timestamp)?
+    } else if let Some(key) = storage.get_channel_key(target)? {
+        let message_id = outgoing_message_id(&target, timestamp, &body);
+        storage::OutgoingMessage {
+            message_id,
+            channel_id: target,
+            channel_type: "group",
+            ciphertext: forward::seal_for_channel(&key, &message_id, &compression::pack(body.as_bytes()))?,
+            timestamp,
+            ttl: DM_TTL,
+            priority: priority::Priority::Normal as u8,
         }
+    } else {
+        return Err(MeshError::Rejected.raise("Target is not a DM, notes or protected group channel"));
     };
 
-    let id = geo::derive_geo_channel_id(geohash, topic);
-    let hex_str = geo::channel_id_to_hex(&id);
-    CString::new(hex_str)
-        .ok()
-        .map(|s| s.into_raw())
-        .unwrap_or(std::ptr::null_mut())
+    let new_id = outgoing.message_id;
+    let is_group = outgoing.channel_type == "group";
+    storage.store_outgoing_batch(&[outgoing])?;
+    if is_group {
+        group_delivery::track_sent(storage, target, new_id, timestamp)?;
+    }
+    for attachment_id in attachments {
+        storage.add_attachment_ref(attachment_id, new_id)?;
+    }
+    Ok(new_id)
+}
+
+/// Forward a stored message to a DM, your notes or a protected group channel.
+/// The text is re-encrypted for the target inside an envelope that keeps the
+/// original author (forwards of forwards keep the first author), and the
+/// message's attachments are referenced by the copy. client_token is optional
+/// (see `dedup_send`).
+/// Returns the new message_id hex, null on error.
+#[no_mangle]
+pub extern "C" fn forward_message(message_id_hex: *const c_char, target_channel_id_hex: *const c_char, client_token: *const c_char) -> *mut c_char {
+    dedup_send(client_token, "forward_message", &[message_id_hex, target_channel_id_hex], || forward_message_once(message_id_hex, target_channel_id_hex))
+}
+
+fn forward_message_once(message_id_hex: *const c_char, target_channel_id_hex: *const c_char) -> *mut c_char {
+    let (Some(message_id), Some(target)) = (parse_hex_32(message_id_hex), parse_hex_32(target_channel_id_hex)) else {
+        return invalid_argument("message_id_hex");
+    };
+
+    let identity_guard = IDENTITY.lock().unwrap();
+    let identity = match identity_guard.as_ref() {
+        Some(id) => id,
+        None => return not_initialized("Identity"),
+    };
+    let friends = friend_keys();
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match forward_stored_message(identity, &friends, storage, message_id, target, now_ts()) {
+        Ok(id) => CString::new(hex::encode(id)).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("forward_message failed: {}", e)),
+    }
+}
+
+// ========== Starred Messages ==========
+
+/// Star a message so it survives its channel being cleared.
+/// mode: "encrypted" keeps the ciphertext and the key material to decrypt it;
+/// "snapshot" keeps the decrypted text. Starring again replaces the star.
+/// Returns 0 on success, a negative error code on error
+#[no_mangle]
+pub extern "C" fn star_message(message_id_hex: *const c_char, mode: *const c_char) -> i32 {
+    let Some(message_id) = parse_hex_32(message_id_hex) else {
+        return invalid_argument("message_id_hex");
+    };
+    let mode = match parse_c_str(mode).map(starred::StarMode::parse) {
+        Some(Ok(m)) => m,
+        _ => return invalid_argument("mode"),
+    };
+
+    let identity_guard = IDENTITY.lock().unwrap();
+    let identity = match identity_guard.as_ref() {
+        Some(id) => id,
+        None => return not_initialized("Identity"),
+    };
+    let friends = friend_keys();
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    let starred = storage.get_message(message_id).and_then(|row| {
+        let row = row.ok_or_else(|| MeshError::NotFound.raise("Message not found"))?;
+        let material = message_key_material(identity, &friends, storage, &row)?;
+        let (plaintext, author) = decrypt_with_material(identity, storage, material, &row)?;
+        storage.star_message(&starred::star_row(mode, &row, author, material, plaintext, now_ts()))
+    });
+    match starred {
+        Ok(()) => 0,
+        Err(e) => failed(format!("star_message failed: {}", e)),
+    }
+}
+
+/// Remove a star.
+/// Returns 0 on success, a negative error code on error or if the message was not starred
+#[no_mangle]
+pub extern "C" fn unstar_message(message_id_hex: *const c_char) -> i32 {
+    let Some(message_id) = parse_hex_32(message_id_hex) else {
+        return invalid_argument("message_id_hex");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    match storage_guard.as_ref().map(|s| s.unstar_message(message_id)) {
+        Some(Ok(true)) => 0,
+        Some(Ok(false)) => fail(MeshError::NotFound, "Message is not starred"),
+        Some(Err(e)) => failed(format!("unstar_message failed: {}", e)),
+        None => not_initialized("Storage"),
+    }
+}
+
+/// List starred messages, most recently starred first. Encrypted stars are
+/// decrypted with their saved key material (plaintext is null if that fails).
+/// Returns JSON array of starred messages, null on error
+#[no_mangle]
+pub extern "C" fn list_starred(limit: u32, offset: u32) -> *mut c_char {
+    let identity_guard = IDENTITY.lock().unwrap();
+    let identity = match identity_guard.as_ref() {
+        Some(id) => id,
+        None => return not_initialized("Identity"),
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    let rows = match storage.list_starred(limit, offset) {
+        Ok(r) => r,
+        Err(e) => return failed(format!("list_starred failed: {}", e)),
+    };
+    let starred: Vec<ffi_types::StarredMessage> = rows
+        .into_iter()
+        .map(|star| {
+            let plaintext = match (&star.plaintext, &star.ciphertext, &star.key_kind) {
+                (Some(text), _, _) => Some(text.clone()),
+                (None, Some(ciphertext), Some(kind)) => starred::KeyMaterial::from_columns(kind, star.key)
+                    .and_then(|material| {
+                        let row = storage::MessageRow {
+                            message_id: star.message_id,
+                            channel_id: star.channel_id,
+                            ciphertext: ciphertext.clone(),
+                            timestamp: star.timestamp,
+                            ttl: 0,
+                            kind: storage::MESSAGE_KIND_USER,
+                            priority: priority::Priority::Normal as u8,
+                        };
+                        decrypt_with_material(identity, storage, material, &row)
+                    })
+                    .map_err(|e| eprintln!("Failed to decrypt starred message {}: {}", hex::encode(star.message_id), e))
+                    .ok()
+                    .map(|(text, _)| text),
+                _ => None,
+            };
+            ffi_types::StarredMessage::new(&star, plaintext)
+        })
+        .collect();
+
+    match serde_json::to_string(&starred) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("list_starred failed: {}", e)),
+    }
+}
+
+// ========== Notes ==========
+
+/// Get the channel id of the local notes-to-self channel.
+/// Returns channel_id hex, null if identity is not initialized.
+#[no_mangle]
+pub extern "C" fn get_notes_channel_id() -> *mut c_char {
+    let identity_guard = IDENTITY.lock().unwrap();
+    match identity_guard.as_ref() {
+        Some(id) => CString::new(hex::encode(notes::notes_channel_id(id)))
+            .ok()
+            .map(|s| s.into_raw())
+            .unwrap_or(std::ptr::null_mut()),
+        None => not_initialized("Identity"),
+    }
+}
+
+/// Write a note to self (stored locally, never routed). client_token is
+/// optional (see `dedup_send`).
+/// Returns message_id hex, null on error.
+#[no_mangle]
+pub extern "C" fn send_note(plaintext: *const c_char, client_token: *const c_char) -> *mut c_char {
+    dedup_send(client_token, "send_note", &[plaintext], || send_note_once(plaintext))
+}
+
+fn send_note_once(plaintext: *const c_char) -> *mut c_char {
+    let plaintext = match parse_c_str(plaintext) {
+        Some(s) => s,
+        None => return invalid_argument("plaintext"),
+    };
+
+    let identity_guard = IDENTITY.lock().unwrap();
+    let identity = match identity_guard.as_ref() {
+        Some(id) => id,
+        None => return not_initialized("Identity"),
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match notes::send(storage, identity, plaintext, now_ts()) {
+        Ok(id) => CString::new(hex::encode(id)).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("send_note failed: {}", e)),
+    }
+}
+
+/// Get decrypted notes, oldest first.
+/// Returns JSON array [{ message_id, plaintext, timestamp }], null on error.
+#[no_mangle]
+pub extern "C" fn get_notes(limit: u32, offset: u32) -> *mut c_char {
+    let identity_guard = IDENTITY.lock().unwrap();
+    let identity = match identity_guard.as_ref() {
+        Some(id) => id,
+        None => return not_initialized("Identity"),
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match notes::fetch(storage, identity, limit, offset) {
+        Ok(list) => match serde_json::to_string(&list) {
+            Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("get_notes failed: {}", e)),
+        },
+        Err(e) => failed(format!("get_notes failed: {}", e)),
+    }
+}
+
+/// Delete all notes. Returns 0 on success, a negative error code on error.
+#[no_mangle]
+pub extern "C" fn clear_notes() -> i32 {
+    let identity_guard = IDENTITY.lock().unwrap();
+    let identity = match identity_guard.as_ref() {
+        Some(id) => id,
+        None => return not_initialized("Identity"),
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match storage.delete_channel_messages(notes::notes_channel_id(identity)) {
+        Ok(_) => {
+            if let Err(e) = attachments::gc(storage) {
+                eprintln!("Attachment GC after clear failed: {}", e);
+            }
+            0
+        }
+        Err(e) => failed(format!("clear_notes failed: {}", e)),
+    }
+}
+
+// ========== Conversations ==========
+
+/// Channel types shown in the conversation list
+const CONVERSATION_TYPES: &[&str] = &["dm", notes::NOTES_CHANNEL_TYPE, "geo", "group", sos::SOS_CHANNEL_TYPE];
+
+/// Longest conversation preview, in characters
+const PREVIEW_CHARS: usize = 100;
+
+/// The decrypted text of a channel's newest user message, cut to
+/// PREVIEW_CHARS (None if there is none or it does not open).
+fn conversation_preview(
+    identity: &identity::Identity,
+    friends: &[([u8; 32], [u8; 32])],
+    storage: &storage::Storage,
+    channel_id: [u8; 32],
+    now: i64,
+) -> Result<Option<String>, String> {
+    let Some(row) = storage.last_user_message(channel_id, now)? else {
+        return Ok(None);
+    };
+    let indexed = if message_index::enabled(storage)? { storage.get_message_plaintext(row.message_id)? } else { None };
+    let plaintext = match indexed {
+        Some(indexed) => indexed.plaintext,
+        None => match message_key_material(identity, friends, storage, &row)
+            .and_then(|material| decrypt_with_material(identity, storage, material, &row))
+        {
+            Ok((text, _)) => text,
+            Err(e) => {
+                eprintln!("No preview for message {}: {}", hex::encode(row.message_id), e);
+                return Ok(None);
+            }
+        },
+    };
+    // Envelopes are unwrapped as in history; group invites have no text
+    let text = ffi_types::DmMessage::user(&row.message_id, plaintext, row.timestamp, false).plaintext;
+    Ok(text.map(|text| match text.char_indices().nth(PREVIEW_CHARS) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text,
+    }))
+}
+
+/// List conversations (DMs, notes, geo rooms, groups): pinned first, then in the
+/// order set with set_channel_order, then most recently active.
+/// Returns JSON array [{ channel_id, type, name, pinned, sort_order, ui_metadata,
+/// observe_only, peer_user_id, message_count, last_message_at, last_message_preview,
+/// unread_count }];
+/// peer_user_id is set for DMs with known friends. last_message_preview is the
+/// decrypted start of the newest message (null if it cannot be decrypted), and
+/// unread_count counts messages after the read marker (see mark_channel_read).
+/// Null on error.
+#[no_mangle]
+pub extern "C" fn get_conversations() -> *mut c_char {
+    let identity_guard = IDENTITY.lock().unwrap();
+    let identity = identity_guard.as_ref();
+    let friends = friend_keys();
+    // Map DM channel ids back to friends
+    let dm_peers: std::collections::HashMap<[u8; 32], [u8; 32]> = match identity {
+        Some(id) => {
+            let own = id.public().ed25519_public.as_bytes();
+            friends
+                .iter()
+                .map(|(user_id, key)| (dm_crypto::derive_dm_channel_id(own, key), *user_id))
+                .collect()
+        }
+        None => Default::default(),
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    let now = now_ts();
+    let listed = storage.list_conversations(CONVERSATION_TYPES).and_then(|rows| {
+        rows.into_iter()
+            .map(|c| {
+                let last_message_preview = match identity {
+                    Some(id) => conversation_preview(id, &friends, storage, c.channel_id, now)?,
+                    None => None,
+                };
+                Ok(ffi_types::ConversationInfo {
+                    peer_user_id: dm_peers.get(&c.channel_id).map(hex::encode),
+                    channel_id: hex::encode(c.channel_id),
+                    channel_type: c.channel_type,
+                    name: c.name,
+                    pinned: c.pinned,
+                    sort_order: c.sort_order,
+                    ui_metadata: c.ui_metadata,
+                    observe_only: c.observe_only,
+                    message_count: c.message_count,
+                    last_message_at: c.last_message_at,
+                    last_message_preview,
+                    unread_count: c.unread_count,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()
+    });
+    match listed.and_then(|json| serde_json::to_string(&json).map_err(|e| e.to_string())) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_conversations failed: {}", e)),
+    }
+}
+
+/// Largest UI metadata blob per channel, in bytes
+const MAX_UI_METADATA_BYTES: usize = 4096;
+
+/// Set a channel's UI metadata: an opaque string owned by the app (theme,
+/// wallpaper id, nickname colours...). It lives with the channel so it is
+/// part of the core's backup and device sync. Null or "" clears it.
+/// Returns 0 on success, a negative error code on error or unknown channel.
+#[no_mangle]
+pub extern "C" fn set_channel_ui_metadata(channel_id_hex: *const c_char, metadata: *const c_char) -> i32 {
+    let channel_id = match parse_hex_32(channel_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("channel_id_hex"),
+    };
+    let metadata = if metadata.is_null() {
+        None
+    } else {
+        match parse_c_str(metadata) {
+            Some("") => None,
+            Some(m) if m.len() <= MAX_UI_METADATA_BYTES => Some(m),
+            _ => return invalid_argument("metadata"),
+        }
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match storage.set_channel_ui_metadata(channel_id, metadata) {
+        Ok(true) => 0,
+        Ok(false) => fail(MeshError::NotFound, "Unknown channel"),
+        Err(e) => failed(format!("set_channel_ui_metadata failed: {}", e)),
+    }
+}
+
+/// Get a channel's UI metadata.
+/// Returns the stored string, null if unset, unknown channel or on error.
+#[no_mangle]
+pub extern "C" fn get_channel_ui_metadata(channel_id_hex: *const c_char) -> *mut c_char {
+    let channel_id = match parse_hex_32(channel_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("channel_id_hex"),
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match storage.get_channel_ui_metadata(channel_id) {
+        Ok(Some(m)) => CString::new(m).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Ok(None) => fail(MeshError::NotFound, "No UI metadata for this channel"),
+        Err(e) => failed(format!("get_channel_ui_metadata failed: {}", e)),
+    }
+}
+
+/// Pin (pinned != 0) or unpin a conversation.
+/// Returns 0 on success, a negative error code on error or unknown channel.
+#[no_mangle]
+pub extern "C" fn pin_channel(channel_id_hex: *const c_char, pinned: i32) -> i32 {
+    let channel_id = match parse_hex_32(channel_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("channel_id_hex"),
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match storage.set_channel_pinned(channel_id, pinned != 0) {
+        Ok(true) => 0,
+        Ok(false) => fail(MeshError::NotFound, "Unknown channel"),
+        Err(e) => failed(format!("pin_channel failed: {}", e)),
+    }
+}
+
+/// Set the custom order of conversations.
+/// channel_ids_json: JSON array of channel_id hex in display order; channels not
+/// listed keep their position. Returns 0 on success, a negative error code on error.
+#[no_mangle]
+pub extern "C" fn set_channel_order(channel_ids_json: *const c_char) -> i32 {
+    let ids: Vec<String> = match parse_c_str(channel_ids_json).and_then(|s| serde_json::from_str(s).ok()) {
+        Some(v) => v,
+        None => return invalid_argument("channel_ids_json"),
+    };
+    let mut channel_ids = Vec::with_capacity(ids.len());
+    for id in ids {
+        match codec::parse_id_hex(&id, "channel id") {
+            Ok(v) => channel_ids.push(v),
+            Err(e) => return failed(format!("set_channel_order failed: {}", e)),
+        }
+    }
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match storage.set_channel_order(&channel_ids) {
+        Ok(_) => 0,
+        Err(e) => failed(format!("set_channel_order failed: {}", e)),
+    }
+}
+
+/// Activity statistics of a channel, for sparklines and retention decisions.
+/// Returns JSON { channel_id, message_count, days: [{ day_start, messages }],
+/// distinct_senders, bytes_stored, message_bytes, attachment_bytes }, or null on error.
+#[no_mangle]
+pub extern "C" fn get_channel_stats(channel_id_hex: *const c_char) -> *mut c_char {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    match storage.channel_stats(channel_id) {
+        Ok(row) => serde_json::to_string(&ffi_types::ChannelStats::new(channel_id, row))
+            .ok()
+            .and_then(|s| CString::new(s).ok())
+            .map(|s| s.into_raw())
+            .unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_channel_stats failed: {}", e)),
+    }
+}
+
+// ========== Batches ==========
+
+/// Apply one batch operation (see `batch`).
+fn apply_batch_op(
+    identity: &identity::Identity,
+    friends: &mut friends::FriendManager,
+    storage: &storage::Storage,
+    op: &batch::BatchOp,
+    now: i64,
+) -> Result<ffi_types::BatchOpResult, String> {
+    use batch::BatchOp;
+
+    let own_ed25519 = identity.public().ed25519_public.as_bytes();
+    let friend_ed25519 = |friends: &friends::FriendManager, user_id: &[u8; 32]| {
+        friends.get_friend(user_id).map(|f| f.ed25519_public).ok_or_else(|| MeshError::NotFound.raise("Not a friend"))
+    };
+    match op {
+        BatchOp::AddFriend { ed25519_public, nickname } => {
+            let key = codec::parse_id_hex(ed25519_public, "ed25519 key")?;
+            let user_id = friends.add_friend(storage, key, nickname.clone())?;
+            let channel_id = dm_crypto::derive_dm_channel_id(own_ed25519, &key);
+            let event = system_messages::SystemEvent::FriendAdded { user_id: hex::encode(user_id) };
+            storage.upsert_channel(channel_id, "dm")?;
+            system_messages::record(storage, channel_id, &event, now)?;
+            Ok(ffi_types::BatchOpResult { user_id: Some(hex::encode(user_id)), ..Default::default() })
+        }
+        BatchOp::SetFriendX25519Key { user_id, x25519_public } => {
+            let user_id = codec::parse_id_hex(user_id, "user id")?;
+            let x25519_public = codec::parse_id_hex(x25519_public, "x25519 key")?;
+            store_friend_x25519_key(identity, friends, storage, &user_id, x25519_public, now)?;
+            Ok(ffi_types::BatchOpResult::default())
+        }
+        BatchOp::RegisterChannel { channel_id, channel_type } => {
+            storage.upsert_channel(codec::parse_id_hex(channel_id, "channel id")?, channel_type)?;
+            Ok(ffi_types::BatchOpResult::default())
+        }
+        BatchOp::JoinGeoChannel { geohash, topic } => {
+            let channel_id = geo::derive_geo_channel_id(geohash, topic);
+            storage.upsert_channel(channel_id, "geo")?;
+            Ok(ffi_types::BatchOpResult { channel_id: Some(hex::encode(channel_id)), ..Default::default() })
+        }
+        BatchOp::SendDm { user_id, text } => {
+            let user_id = codec::parse_id_hex(user_id, "user id")?;
+            let key = if user_id == identity.public().user_id {
+                notes::ensure_channel(storage, identity)?;
+                None
+            } else {
+                Some(friend_ed25519(friends, &user_id)?)
+            };
+            let outgoing = encrypt_outgoing_dm(identity, storage, user_id, key, text, now)?;
+            let message_id = outgoing.message_id;
+            storage.store_outgoing_batch(&[outgoing])?;
+            Ok(ffi_types::BatchOpResult { message_id: Some(hex::encode(message_id)), ..Default::default() })
+        }
+    }
+}
+
+/// Run a batch of operations atomically (see `batch` for the operations):
+/// either all of them apply or none does.
+/// ops_json: JSON array of operations.
+/// Returns JSON array of each operation's result in order (`BatchOpResult`),
+/// null on error (nothing applied).
+#[no_mangle]
+pub extern "C" fn run_batch(ops_json: *const c_char) -> *mut c_char {
+    let ops = match parse_c_str(ops_json).map(batch::parse) {
+        Some(Ok(ops)) => ops,
+        Some(Err(e)) => return failed(format!("run_batch failed: {}", e)),
+        None => return invalid_argument("ops_json"),
+    };
+
+    let identity_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = identity_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let mut friends_guard = FRIENDS.lock().unwrap();
+    let Some(friends) = friends_guard.as_mut() else {
+        return not_initialized("Friends");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(ref storage) = *storage_guard else {
+        return not_initialized("Storage");
+    };
+
+    let now = now_ts();
+    let applied = storage.with_transaction(|storage| {
+        ops.iter()
+            .enumerate()
+            .map(|(i, op)| {
+                apply_batch_op(identity, friends, storage, op, now)
+                    .map_err(|e| format!("operation {} ({}): {}", i, op.name(), e))
+            })
+            .collect::<Result<Vec<_>, _>>()
+    });
+
+    match applied {
+        Ok(results) => match serde_json::to_string(&results) {
+            Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("run_batch failed: {}", e)),
+        },
+        Err(e) => {
+            // Rolled back: drop what the friends cache took of it
+            match friends::FriendManager::load(storage) {
+                Ok(reloaded) => *friends = reloaded,
+                Err(e) => eprintln!("run_batch: failed to reload friends: {}", e),
+            }
+            failed(format!("run_batch failed: {}", e))
+        }
+    }
+}
+
+// ========== Search ==========
+
+/// Search contacts, channel names and readable messages (DMs and notes) in one call.
+/// limit: max results per group (0 = default of 20).
+/// Returns JSON {
+///   contacts: [{ user_id, display_name, score }],
+///   channels: [{ channel_id, channel_type, name, score }],
+///   messages: [{ channel_id, message_id, peer_user_id, snippet, timestamp, score }]
+/// }, each group best match first; null on error.
+#[no_mangle]
+pub extern "C" fn global_search(query_ptr: *const c_char, limit: u32) -> *mut c_char {
+    let query = match parse_c_str(query_ptr) {
+        Some(q) => q.trim(),
+        None => return invalid_argument("query_ptr"),
+    };
+
+    let identity_guard = IDENTITY.lock().unwrap();
+    let identity = match identity_guard.as_ref() {
+        Some(id) => id,
+        None => return not_initialized("Identity"),
+    };
+    let friends_guard = FRIENDS.lock().unwrap();
+    let friends: Vec<friends::Friend> = match friends_guard.as_ref() {
+        Some(fm) => fm.get_all_friends().into_iter().cloned().collect(),
+        None => Vec::new(),
+    };
+    drop(friends_guard);
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    if query.is_empty() {
+        return CString::new(r#"{"contacts":[],"channels":[],"messages":[]}"#)
+            .ok()
+            .map(|s| s.into_raw())
+            .unwrap_or(std::ptr::null_mut());
+    }
+
+    let mut results = search::SearchResults::default();
+    for f in &friends {
+        let display_name = f.custom_display_name.clone().unwrap_or_else(|| f.nickname.clone());
+        let score = [
+            search::match_score(&display_name, query),
+            search::match_score(&f.nickname, query),
+            f.tags.iter().filter_map(|t| search::match_score(t, query)).max(),
+            search::match_score(&f.notes, query).map(|s| s / 2),
+        ]
+        .into_iter()
+        .flatten()
+        .max();
+        if let Some(score) = score {
+            results.contacts.push(search::ContactHit {
+                user_id: hex::encode(f.user_id),
+                display_name,
+                score,
+            });
+        }
+    }
+
+    match storage.list_conversations(CONVERSATION_TYPES) {
+        Ok(rows) => {
+            for c in rows {
+                let name = match c.name {
+                    Some(n) => n,
+                    None => continue,
+                };
+                if let Some(score) = search::match_score(&name, query) {
+                    results.channels.push(search::ChannelHit {
+                        channel_id: hex::encode(c.channel_id),
+                        channel_type: c.channel_type,
+                        name,
+                        score,
+                    });
+                }
+            }
+        }
+        Err(e) => return failed(format!("global_search failed: {}", e)),
+    }
+
+    match find_messages(identity, storage, &friends, query) {
+        Ok(hits) => results.messages = hits,
+        Err(e) => return failed(format!("global_search failed: {}", e)),
+    }
+
+    results.rank(limit as usize);
+    match serde_json::to_string(&results) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("global_search failed: {}", e)),
+    }
+}
+
+/// Search readable messages (DMs and notes) only, best match first.
+/// Uses the full-text index when the message index is on (see
+/// `message_index`), else decrypts and scans each conversation.
+/// limit: max results (0 = default of 20).
+/// Returns JSON array [{ channel_id, message_id, peer_user_id, snippet, timestamp, score }], null on error.
+#[no_mangle]
+pub extern "C" fn search_messages(query_ptr: *const c_char, limit: u32) -> *mut c_char {
+    let Some(query) = parse_c_str(query_ptr).map(str::trim) else {
+        return invalid_argument("query_ptr");
+    };
+    let identity_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = identity_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let friends: Vec<friends::Friend> = match FRIENDS.lock().unwrap().as_ref() {
+        Some(fm) => fm.get_all_friends().into_iter().cloned().collect(),
+        None => Vec::new(),
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    let mut results = search::SearchResults::default();
+    if !query.is_empty() {
+        match find_messages(identity, storage, &friends, query) {
+            Ok(hits) => results.messages = hits,
+            Err(e) => return failed(format!("search_messages failed: {}", e)),
+        }
+    }
+    results.rank(limit as usize);
+    match serde_json::to_string(&results.messages) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("search_messages failed: {}", e)),
+    }
+}
+
+/// Messages matching `query` in DMs and notes, unranked.
+fn find_messages(
+    identity: &identity::Identity,
+    storage: &storage::Storage,
+    friends: &[friends::Friend],
+    query: &str,
+) -> Result<Vec<search::MessageHit>, String> {
+    // Ourselves first, then each friend
+    let own = identity.public().ed25519_public.as_bytes();
+    let peers: Vec<([u8; 32], Option<[u8; 32]>)> = std::iter::once((identity.public().user_id, None))
+        .chain(friends.iter().map(|f| (f.user_id, Some(f.ed25519_public))))
+        .collect();
+    let channel_of = |ed25519_public: &Option<[u8; 32]>| dm_crypto::derive_dm_channel_id(own, ed25519_public.as_ref().unwrap_or(own));
+
+    let mut hits = Vec::new();
+    if message_index::enabled(storage)? {
+        for row in message_index::search(storage, query, search::SEARCH_SCAN_LIMIT)? {
+            let Some((user_id, ed25519_public)) = peers.iter().find(|(_, key)| channel_of(key) == row.channel_id) else {
+                continue;
+            };
+            let (snippet, score) = search::snippet(&row.text, query).unwrap_or_else(|| (row.text.clone(), 10));
+            hits.push(search::MessageHit {
+                channel_id: hex::encode(row.channel_id),
+                message_id: hex::encode(row.message_id),
+                peer_user_id: ed25519_public.map(|_| hex::encode(user_id)),
+                snippet,
+                timestamp: row.timestamp,
+                score,
+            });
+        }
+        return Ok(hits);
+    }
+
+    // Without the index, only DMs and notes can be decrypted locally
+    for (user_id, ed25519_public) in &peers {
+        let history = match read_dm_history(identity, storage, *ed25519_public, search::SEARCH_SCAN_LIMIT, 0) {
+            Ok(h) => h,
+            Err(e) => {
+                eprintln!("Message search: skipping conversation: {}", e);
+                continue;
+            }
+        };
+        let channel_id = channel_of(ed25519_public);
+        for msg in history {
+            let plaintext = match msg.plaintext.as_deref() {
+                Some(p) => p,
+                None => continue,
+            };
+            if let Some((snippet, score)) = search::snippet(plaintext, query) {
+                hits.push(search::MessageHit {
+                    channel_id: hex::encode(channel_id),
+                    message_id: msg.message_id.clone(),
+                    peer_user_id: ed25519_public.map(|_| hex::encode(user_id)),
+                    snippet,
+                    timestamp: msg.timestamp,
+                    score,
+                });
+            }
+        }
+    }
+    Ok(hits)
+}
+
+// ========== Geohash Channels (Phase 7) ==========
+
+/// Derive a geohash channel id from geohash + topic.
+/// Returns channel_id hex on success, null on error.
+#[no_mangle]
+pub extern "C" fn derive_geo_channel_id(
+    geohash_ptr: *const c_char,
+    topic_ptr: *const c_char,
+) -> *mut c_char {
+    let geohash = unsafe {
+        if geohash_ptr.is_null() {
+            return invalid_argument("geohash_ptr");
+        }
+        match std::ffi::CStr::from_ptr(geohash_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return invalid_argument("geohash_ptr"),
+        }
+    };
+
+    let topic = unsafe {
+        if topic_ptr.is_null() {
+            return invalid_argument("topic_ptr");
+        }
+        match std::ffi::CStr::from_ptr(topic_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return invalid_argument("topic_ptr"),
+        }
+    };
+
+    let id = geo::derive_geo_channel_id(geohash, topic);
+    let hex_str = geo::channel_id_to_hex(&id);
+    CString::new(hex_str)
+        .ok()
+        .map(|s| s.into_raw())
+        .unwrap_or(std::ptr::null_mut())
+}
+
+/// Geo channels of a topic around a GPS position: the geohash cell of
+/// `precision` characters (1-12) the position falls in, then its neighbors.
+/// Returns JSON [{geohash, channel_id, center}], null on error.
+#[no_mangle]
+pub extern "C" fn get_nearby_geo_channels(lat: f64, lon: f64, precision: u32, topic: *const c_char) -> *mut c_char {
+    let Some(topic) = parse_c_str(topic) else {
+        return invalid_argument("topic");
+    };
+    match geo::nearby_channels(lat, lon, precision as usize, topic)
+        .and_then(|c| serde_json::to_string(&c).map_err(|e| e.to_string()))
+    {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_nearby_geo_channels failed: {}", e)),
+    }
+}
+
+/// Register a geohash channel in local storage.
+/// channel_id_hex must be 32 bytes hex; returns 0 on success, a negative error code on error.
+#[no_mangle]
+pub extern "C" fn register_geo_channel(channel_id_hex: *const c_char) -> i32 {
+    let channel_id = match parse_hex_32(channel_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("channel_id_hex"),
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    if let Some(ref storage) = *storage_guard {
+        match storage.upsert_channel(channel_id, "geo") {
+            Ok(_) => 0,
+            Err(e) => failed(format!("register_geo_channel failed: {}", e)),
+        }
+    } else {
+        not_initialized("Storage")
+    }
+}
+
+/// Send a message on a registered geo channel, signed by our pseudonym there
+/// (see `geo_messages`). A reply (in_reply_to_hex, nullable) is signed by the
+/// pseudonym that wrote the message it answers. client_token is optional
+/// (see `dedup_send`). Returns JSON { message_id, channel_id, channel_type, pseudonym, routed }
+/// (`SendResult`), null on error.
+#[no_mangle]
+pub extern "C" fn send_geo_message(
+    channel_id_hex: *const c_char,
+    text: *const c_char,
+    in_reply_to_hex: *const c_char,
+    client_token: *const c_char,
+) -> *mut c_char {
+    dedup_send(client_token, "send_geo_message", &[channel_id_hex, text, in_reply_to_hex], || {
+        send_geo_message_once(channel_id_hex, text, in_reply_to_hex)
+    })
+}
+
+fn send_geo_message_once(channel_id_hex: *const c_char, text: *const c_char, in_reply_to_hex: *const c_char) -> *mut c_char {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+    let Some(text) = parse_c_str(text) else {
+        return invalid_argument("text");
+    };
+    let in_reply_to = if in_reply_to_hex.is_null() {
+        None
+    } else {
+        match parse_hex_32(in_reply_to_hex) {
+            Some(v) => Some(v),
+            None => return invalid_argument("in_reply_to_hex"),
+        }
+    };
+    let id_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = id_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let own_public = *identity.public().ed25519_public.as_bytes();
+    let r_guard = ROUTER.lock().unwrap();
+    let Some(router) = r_guard.as_ref() else {
+        return not_initialized("Router");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    let options = messaging::SendOptions { in_reply_to, priority: priority::take_next() };
+    match messaging::send(identity, storage, messaging::Target::Geo(channel_id), text, &options, now_ts()) {
+        Ok(sent) => {
+            if let Some(packet) = sent.packet {
+                route_packet(router, Some(storage), Some(own_public), packet);
+            }
+            let result = serde_json::to_string(&sent.result).unwrap_or_default();
+            CString::new(result).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut())
+        }
+        Err(e) => failed(format!("send_geo_message failed: {}", e)),
+    }
+}
+
+/// Turn observer (lurker) mode of a geohash channel on (enabled != 0) or off.
+/// An observed channel is still stored and shown, but the router never relays,
+/// replays or sends packets on it, our own included.
+/// Returns 0 on success, a negative error code on error or if the channel is not a registered geo channel.
+#[no_mangle]
+pub extern "C" fn set_channel_observe_only(channel_id_hex: *const c_char, enabled: i32) -> i32 {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+
+    let observed = {
+        let storage_guard = STORAGE.lock().unwrap();
+        let Some(storage) = storage_guard.as_ref() else {
+            return not_initialized("Storage");
+        };
+        match storage
+            .set_channel_observe_only(channel_id, enabled != 0)
+            .and_then(|found| Ok(found.then_some(storage.list_observed_channels()?)))
+        {
+            Ok(Some(ids)) => ids,
+            Ok(None) => return fail(MeshError::NotFound, "Unknown channel"),
+            Err(e) => return failed(format!("set_channel_observe_only failed: {}", e)),
+        }
+    };
+    if let Some(router) = ROUTER.lock().unwrap().as_ref() {
+        router.set_observed_channels(&observed);
+    }
+    0
+}
+
+/// List registered geohash channels.
+/// Returns JSON array [{ channel_id, type }] or null on error.
+#[no_mangle]
+pub extern "C" fn get_geo_channels() -> *mut c_char {
+    let storage_guard = STORAGE.lock().unwrap();
+    if let Some(ref storage) = *storage_guard {
+        match storage.list_channels_by_type("geo") {
+            Ok(channels) => {
+                let json: Vec<ffi_types::ChannelInfo> = channels
+                    .into_iter()
+                    .map(|c| ffi_types::ChannelInfo {
+                        channel_id: hex::encode(c.channel_id),
+                        channel_type: c.channel_type,
+                    })
+                    .collect();
+                match serde_json::to_string(&json) {
+                    Ok(s) => CString::new(s)
+                        .ok()
+                        .map(|s| s.into_raw())
+                        .unwrap_or(std::ptr::null_mut()),
+                    Err(e) => failed(format!("get_geo_channels failed: {}", e)),
+                }
+            }
+            Err(e) => failed(format!("get_geo_channels failed: {}", e)),
+        }
+    } else {
+        not_initialized("Storage")
+    }
+}
+
+// ========== Channel Directory ==========
+
+/// Announce a geohash channel to nodes nearby (enabled != 0), registering it
+/// if needed, or stop announcing it (see `channel_directory`). name may be
+/// null. Announcements go out with send_channel_announcements.
+/// Returns 0 on success, a negative error code on error.
+#[no_mangle]
+pub extern "C" fn announce_geo_channel(
+    geohash: *const c_char,
+    topic: *const c_char,
+    name: *const c_char,
+    enabled: i32,
+) -> i32 {
+    let Some(geohash) = parse_c_str(geohash) else {
+        return invalid_argument("geohash");
+    };
+    let Some(topic) = parse_c_str(topic) else {
+        return invalid_argument("topic");
+    };
+    let name = if name.is_null() {
+        None
+    } else {
+        match parse_c_str(name) {
+            Some(s) => Some(s),
+            None => return invalid_argument("name"),
+        }
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    if enabled == 0 {
+        return match storage.remove_announced_channel(geo::derive_geo_channel_id(geohash, topic)) {
+            Ok(true) => 0,
+            Ok(false) => fail(MeshError::NotFound, "Channel is not announced"),
+            Err(e) => failed(format!("announce_geo_channel failed: {}", e)),
+        };
+    }
+    match channel_directory::set_announced(storage, geohash, topic, name.map(str::to_string)) {
+        Ok(_) => 0,
+        Err(e) => failed(format!("announce_geo_channel failed: {}", e)),
+    }
+}
+
+/// Send the announcements that are due (call periodically; each channel goes
+/// out at most every `ANNOUNCE_INTERVAL_SECS`).
+/// Returns how many were sent, a negative error code on error.
+#[no_mangle]
+pub extern "C" fn send_channel_announcements() -> i32 {
+    let id_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = id_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let r_guard = ROUTER.lock().unwrap();
+    let Some(router) = r_guard.as_ref() else {
+        return not_initialized("Router");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    match channel_directory::announcement_packets(identity, storage, now_ts()) {
+        Ok(packets) => {
+            let count = packets.len();
+            for packet in packets {
+                router.route(packet, |_| {});
+            }
+            save_router_state(router, Some(storage));
+            count as i32
+        }
+        Err(e) => failed(format!("send_channel_announcements failed: {}", e)),
+    }
+}
+
+/// Public channels announced around a geohash ("channels near you"): every
+/// announced channel whose geohash starts with it, most announced and busiest
+/// first, spam left out.
+/// Returns JSON [{channel_id, geohash, topic, name?, activity, announcers,
+/// last_announced_at, subscribed}], null on error.
+#[no_mangle]
+pub extern "C" fn get_channels_near(geohash: *const c_char) -> *mut c_char {
+    let Some(geohash) = parse_c_str(geohash) else {
+        return invalid_argument("geohash");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match channel_directory::near(storage, geohash, now_ts())
+        .and_then(|c| serde_json::to_string(&c).map_err(|e| e.to_string()))
+    {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_channels_near failed: {}", e)),
+    }
+}
+
+// ========== Broadcasts ==========
+
+/// Post on our broadcast channel (see `broadcasts`): signed, readable by
+/// anyone who watches our key. client_token is optional (see `dedup_send`).
+/// Returns the message_id hex, or null on error.
+#[no_mangle]
+pub extern "C" fn post_broadcast(text: *const c_char, client_token: *const c_char) -> *mut c_char {
+    dedup_send(client_token, "post_broadcast", &[text], || post_broadcast_once(text))
+}
+
+fn post_broadcast_once(text: *const c_char) -> *mut c_char {
+    let Some(text) = parse_c_str(text) else {
+        return invalid_argument("text");
+    };
+    let id_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = id_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let own_public = *identity.public().ed25519_public.as_bytes();
+    let r_guard = ROUTER.lock().unwrap();
+    let Some(router) = r_guard.as_ref() else {
+        return not_initialized("Router");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    match broadcasts::post(identity, storage, text, now_ts()) {
+        Ok(packet) => {
+            let packet_id = packet.packet_id;
+            route_packet(router, Some(storage), Some(own_public), packet);
+            CString::new(hex::encode(packet_id)).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut())
+        }
+        Err(e) => failed(format!("post_broadcast failed: {}", e)),
+    }
+}
+
+/// Follow a key's broadcast channel without friending it: its signed posts
+/// are stored and shown, but no friend, DM or session is created.
+/// label may be null. Returns JSON {ed25519_public, user_id, channel_id,
+/// label?, added_at}, null on error.
+#[no_mangle]
+pub extern "C" fn watch_contact(ed25519_public_hex: *const c_char, label: *const c_char) -> *mut c_char {
+    let Some(ed25519_public) = parse_hex_32(ed25519_public_hex) else {
+        return invalid_argument("ed25519_public_hex");
+    };
+    let label = if label.is_null() {
+        None
+    } else {
+        match parse_c_str(label) {
+            Some(s) => Some(s.to_string()),
+            None => return invalid_argument("label"),
+        }
+    };
+    let Some(own_public) = own_ed25519_public() else {
+        return not_initialized("Identity");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match broadcasts::watch(storage, own_public, ed25519_public, label, now_ts())
+        .and_then(|w| serde_json::to_string(&w).map_err(|e| e.to_string()))
+    {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("watch_contact failed: {}", e)),
+    }
+}
+
+/// Stop following a key and delete its stored posts.
+/// Returns 0 on success, a negative error code on error or if the key is not watched.
+#[no_mangle]
+pub extern "C" fn unwatch_contact(ed25519_public_hex: *const c_char) -> i32 {
+    let Some(ed25519_public) = parse_hex_32(ed25519_public_hex) else {
+        return invalid_argument("ed25519_public_hex");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match broadcasts::unwatch(storage, ed25519_public) {
+        Ok(true) => 0,
+        Ok(false) => fail(MeshError::NotFound, "Contact is not watched"),
+        Err(e) => failed(format!("unwatch_contact failed: {}", e)),
+    }
+}
+
+/// Keys we follow, oldest first, as JSON [{ed25519_public, user_id,
+/// channel_id, label?, added_at}], null on error.
+#[no_mangle]
+pub extern "C" fn list_watched_contacts() -> *mut c_char {
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    let listed = storage.list_watched_contacts().and_then(|rows| {
+        let watched: Vec<broadcasts::WatchedContact> = rows.iter().map(broadcasts::WatchedContact::from).collect();
+        serde_json::to_string(&watched).map_err(|e| e.to_string())
+    });
+    match listed {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("list_watched_contacts failed: {}", e)),
+    }
+}
+
+/// Verified posts of a key's broadcast channel (our own key too), oldest first.
+/// Returns JSON [{message_id, author, text, sent_at, timestamp}], null on error.
+#[no_mangle]
+pub extern "C" fn get_broadcast_posts(ed25519_public_hex: *const c_char, limit: u32, offset: u32) -> *mut c_char {
+    let Some(ed25519_public) = parse_hex_32(ed25519_public_hex) else {
+        return invalid_argument("ed25519_public_hex");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match broadcasts::posts(storage, ed25519_public, limit, offset, now_ts())
+        .and_then(|p| serde_json::to_string(&p).map_err(|e| e.to_string()))
+    {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_broadcast_posts failed: {}", e)),
+    }
+}
+
+// ========== Channel Invites ==========
+
+/// Create a protected group channel with a random id and key.
+/// name may be null. Returns channel_id hex, null on error.
+#[no_mangle]
+pub extern "C" fn create_protected_channel(name: *const c_char) -> *mut c_char {
+    let name = if name.is_null() {
+        None
+    } else {
+        match parse_c_str(name) {
+            Some(s) => Some(s),
+            None => return invalid_argument("name"),
+        }
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match invites::create_protected_channel(storage, name, now_ts()) {
+        Ok(id) => CString::new(hex::encode(id)).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("create_protected_channel failed: {}", e)),
+    }
+}
+
+/// Create a signed invite for a channel, to be shown as a QR code or shared as a link.
+/// channel_type: "geo" | "group"; name may be null; expires_in_secs <= 0 means no expiry.
+/// The channel key is included for protected channels.
+/// Returns the invite payload (JSON), null on error.
+#[no_mangle]
+pub extern "C" fn create_channel_invite(
+    channel_id_hex: *const c_char,
+    channel_type: *const c_char,
+    name: *const c_char,
+    expires_in_secs: i64,
+) -> *mut c_char {
+    let channel_id = match parse_hex_32(channel_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("channel_id_hex"),
+    };
+    let channel_type = match parse_c_str(channel_type) {
+        Some(s) => s,
+        None => return invalid_argument("channel_type"),
+    };
+    let name = if name.is_null() {
+        None
+    } else {
+        match parse_c_str(name) {
+            Some(s) => Some(s),
+            None => return invalid_argument("name"),
+        }
+    };
+
+    let identity_guard = IDENTITY.lock().unwrap();
+    let identity = match identity_guard.as_ref() {
+        Some(id) => id,
+        None => return not_initialized("Identity"),
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    let now = now_ts();
+    let expires_at = if expires_in_secs > 0 { Some(now + expires_in_secs) } else { None };
+    match invites::create(identity, storage, channel_id, channel_type, name, expires_at, now) {
+        Ok(invite) => match serde_json::to_string(&invite) {
+            Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("create_channel_invite failed: {}", e)),
+        },
+        Err(e) => failed(format!("create_channel_invite failed: {}", e)),
+    }
+}
+
+/// Accept an invite payload: verifies the inviter's signature and expiry, then joins
+/// the channel, stores its key and records a member_joined system message.
+/// Emits a `channel_joined` event.
+/// Returns JSON { channel_id, channel_type, name, protected, inviter_user_id }, null on error.
+#[no_mangle]
+pub extern "C" fn accept_invite(payload: *const c_char) -> *mut c_char {
+    let invite: invites::Invite = match parse_c_str(payload).and_then(|s| serde_json::from_str(s).ok()) {
+        Some(v) => v,
+        None => return invalid_argument("payload"),
+    };
+
+    let our_user_id = match IDENTITY.lock().unwrap().as_ref() {
+        Some(id) => id.public().user_id,
+        None => return not_initialized("Identity"),
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match invites::accept(storage, &invite, our_user_id, now_ts()) {
+        Ok(accepted) => match serde_json::to_string(&accepted) {
+            Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("accept_invite failed: {}", e)),
+        },
+        Err(e) => failed(format!("accept_invite failed: {}", e)),
+    }
+}
+
+/// Parse a mesh:// link (from a QR code or a shared link).
+/// Supports mesh://add-friend/... and mesh://join/...; payloads are validated
+/// (friend key/user_id match, invite signature and expiry).
+/// Returns JSON { kind: "add-friend" | "join", payload: {...} }, null on error.
+/// Pass the payload on to import_friend_from_json or accept_invite.
+#[no_mangle]
+pub extern "C" fn parse_mesh_uri(uri_ptr: *const c_char) -> *mut c_char {
+    let uri_str = match parse_c_str(uri_ptr) {
+        Some(s) => s,
+        None => return invalid_argument("uri_ptr"),
+    };
+
+    match uri::parse(uri_str, now_ts()) {
+        Ok((kind, payload)) => {
+            let json = ffi_types::MeshUriInfo {
+                kind: kind.name(),
+                payload,
+            };
+            let json = serde_json::to_string(&json).unwrap_or_default();
+            CString::new(json).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut())
+        }
+        Err(e) => failed(format!("parse_mesh_uri failed: {}", e)),
+    }
+}
+
+/// Build a mesh:// link from a payload.
+/// kind: "add-friend" (payload from export_own_identity) or "join" (payload from create_channel_invite).
+/// Returns the URI, null if the kind is unknown or the payload is invalid.
+#[no_mangle]
+pub extern "C" fn build_mesh_uri(kind_ptr: *const c_char, payload_json: *const c_char) -> *mut c_char {
+    let kind = match parse_c_str(kind_ptr).and_then(uri::UriKind::from_name) {
+        Some(k) => k,
+        None => return invalid_argument("kind_ptr"),
+    };
+    let payload = match parse_c_str(payload_json) {
+        Some(s) => s,
+        None => return invalid_argument("payload_json"),
+    };
+
+    match uri::build(kind, payload, now_ts()) {
+        Ok(u) => CString::new(u).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("build_mesh_uri failed: {}", e)),
+    }
+}
+
+/// Split a payload (e.g. a mesh:// link) into frames for an animated QR code.
+/// chunk_size: payload bytes per frame (clamped to 16..=2048).
+/// Returns JSON array of frame strings, null on error.
+#[no_mangle]
+pub extern "C" fn export_qr_frames(payload_ptr: *const c_char, chunk_size: u32) -> *mut c_char {
+    let payload = match parse_c_str(payload_ptr) {
+        Some(s) => s,
+        None => return invalid_argument("payload_ptr"),
+    };
+
+    match qr::export_frames(payload, chunk_size as usize) {
+        Ok(frames) => match serde_json::to_string(&frames) {
+            Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("export_qr_frames failed: {}", e)),
+        },
+        Err(e) => failed(format!("export_qr_frames failed: {}", e)),
+    }
+}
+
+/// Feed one scanned QR frame to the reassembler (any order, repeats are fine).
+/// Returns JSON { payload_id, received, total, missing: [index], payload: string | null };
+/// payload is set once all frames are in. Null if the frame is invalid.
+#[no_mangle]
+pub extern "C" fn import_qr_frame(frame_ptr: *const c_char) -> *mut c_char {
+    let frame = match parse_c_str(frame_ptr) {
+        Some(s) => s,
+        None => return invalid_argument("frame_ptr"),
+    };
+
+    match qr::import_frame(frame) {
+        Ok(status) => match serde_json::to_string(&status) {
+            Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("import_qr_frame failed: {}", e)),
+        },
+        Err(e) => failed(format!("import_qr_frame failed: {}", e)),
+    }
+}
+
+/// Discard partially scanned QR sequences.
+#[no_mangle]
+pub extern "C" fn reset_qr_import() {
+    qr::reset_imports();
+}
+
+// ========== Settings ==========
+
+/// Get a setting as JSON (the stored value, else the core default, else `null`).
+/// Known keys: battery.mode, retention.max_age_days, retention.max_messages,
+/// retention.by_channel_type, storage.max_message_bytes, node.role, relay.enabled,
+/// relay.max_ttl, relay.channel_rules, privacy.read_receipts, notifications.quiet_hours.
+/// Returns null on error.
+#[no_mangle]
+pub extern "C" fn get_setting(key: *const c_char) -> *mut c_char {
+    let key = match parse_c_str(key) {
+        Some(k) => k,
+        None => return invalid_argument("key"),
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match settings::get_value(storage, key) {
+        Ok(v) => CString::new(v.to_string()).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_setting failed: {}", e)),
+    }
+}
+
+/// Set a setting from a JSON value (e.g. `true`, `7`, `"PowerSaving"`).
+/// Values for known keys are type-checked; a `setting_changed` event is emitted on change.
+/// Returns 0 on success, a negative error code on error.
+#[no_mangle]
+pub extern "C" fn set_setting(key: *const c_char, value_json: *const c_char) -> i32 {
+    let key = match parse_c_str(key) {
+        Some(k) => k,
+        None => return invalid_argument("key"),
+    };
+    let value: serde_json::Value = match parse_c_str(value_json).and_then(|s| serde_json::from_str(s).ok()) {
+        Some(v) => v,
+        None => return invalid_argument("value_json"),
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match settings::set_value(storage, key, value) {
+        Ok(_) => 0,
+        Err(e) => failed(format!("set_setting failed: {}", e)),
+    }
+}
+
+/// Get all settings as a JSON object { key: value }, with defaults filled in. Returns null on error.
+#[no_mangle]
+pub extern "C" fn get_all_settings() -> *mut c_char {
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match settings::all(storage) {
+        Ok(map) => match serde_json::to_string(&map) {
+            Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("get_all_settings failed: {}", e)),
+        },
+        Err(e) => failed(format!("get_all_settings failed: {}", e)),
+    }
+}
+
+/// Select a node role preset: "phone", "tablet-hub", "solar-relay" or
+/// "internet-gateway" (see `node_roles`). Sets the role's relay policy,
+/// battery mode, storage quota and retention settings, and `node.role`.
+/// Returns 0 on success, a negative error code on error.
+#[no_mangle]
+pub extern "C" fn set_node_role(role: *const c_char) -> i32 {
+    let Some(role) = parse_c_str(role).and_then(node_roles::NodeRole::from_name) else {
+        return invalid_argument("role");
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match node_roles::select(storage, role) {
+        Ok(()) => 0,
+        Err(e) => failed(format!("set_node_role failed: {}", e)),
+    }
+}
+
+// ========== Notification Settings ==========
+
+/// Get a channel's notification settings.
+/// Returns JSON { muted, mention_only, sound_profile } (defaults for unknown channels), null on error.
+#[no_mangle]
+pub extern "C" fn get_channel_notification_settings(channel_id_hex: *const c_char) -> *mut c_char {
+    let channel_id = match parse_hex_32(channel_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("channel_id_hex"),
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match storage.get_notification_settings(channel_id) {
+        Ok(settings) => match serde_json::to_string(&settings.unwrap_or_default()) {
+            Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("get_channel_notification_settings failed: {}", e)),
+        },
+        Err(e) => failed(format!("get_channel_notification_settings failed: {}", e)),
+    }
+}
+
+/// Set a channel's notification settings.
+/// settings_json: { "muted": bool, "mention_only": bool, "sound_profile": string | null }
+/// (missing fields reset to defaults). Returns 0 on success, a negative error code on error or unknown channel.
+#[no_mangle]
+pub extern "C" fn set_channel_notification_settings(
+    channel_id_hex: *const c_char,
+    settings_json: *const c_char,
+) -> i32 {
+    let channel_id = match parse_hex_32(channel_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("channel_id_hex"),
+    };
+    let settings: notifications::NotificationSettings = match parse_c_str(settings_json)
+        .and_then(|s| serde_json::from_str(s).ok())
+    {
+        Some(v) => v,
+        None => return invalid_argument("settings_json"),
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match storage.set_notification_settings(channel_id, &settings) {
+        Ok(true) => 0,
+        Ok(false) => fail(MeshError::NotFound, "Unknown channel"),
+        Err(e) => failed(format!("set_channel_notification_settings failed: {}", e)),
+    }
+}
+
+/// Decide whether a new message in a channel should notify the user (for
+/// messages the core did not store; see get_message_notification_decision).
+/// mentions_me: non-zero if the message mentions us (see extract_mentions_from_text).
+/// sender_verified: non-zero if the sender is a verified friend.
+/// Returns JSON { notify, sound_profile, reason }, null on error.
+#[no_mangle]
+pub extern "C" fn get_notification_decision(
+    channel_id_hex: *const c_char,
+    mentions_me: i32,
+    sender_verified: i32,
+) -> *mut c_char {
+    let channel_id = match parse_hex_32(channel_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("channel_id_hex"),
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    let ctx = notifications::MessageContext {
+        mentions_me: mentions_me != 0,
+        sender_verified: sender_verified != 0,
+        ..Default::default()
+    };
+    match notification_decision(storage, channel_id, ctx).and_then(|d| serde_json::to_string(&d).map_err(|e| e.to_string())) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_notification_decision failed: {}", e)),
+    }
+}
+
+/// `get_notification_decision` for a stored message, read from the message:
+/// its channel, the priority it was sent with (urgent messages break through
+/// mention-only channels and quiet hours, background ones never notify),
+/// whether its sender is a verified friend and whether it mentions
+/// own_nickname (the name friends mention us by; may be null).
+/// Returns JSON { notify, sound_profile, reason }, null on error (NotFound for unknown messages).
+#[no_mangle]
+pub extern "C" fn get_message_notification_decision(message_id_hex: *const c_char, own_nickname: *const c_char) -> *mut c_char {
+    let Some(message_id) = parse_hex_32(message_id_hex) else {
+        return invalid_argument("message_id_hex");
+    };
+    let own_nickname = parse_c_str(own_nickname);
+    let own_public = own_ed25519_public();
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    let message = match storage.get_message(message_id) {
+        Ok(Some(m)) => m,
+        Ok(None) => return fail(MeshError::NotFound, "Unknown message"),
+        Err(e) => return failed(format!("get_message_notification_decision failed: {}", e)),
+    };
+    match notifications::message_context(storage, &message, own_public, own_nickname)
+        .and_then(|ctx| notification_decision(storage, message.channel_id, ctx))
+        .and_then(|d| serde_json::to_string(&d).map_err(|e| e.to_string())) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_message_notification_decision failed: {}", e)),
+    }
+}
+
+/// Decide on one incoming message (arrivals are counted on ingest).
+fn notification_decision(
+    storage: &storage::Storage,
+    channel_id: [u8; 32],
+    mut ctx: notifications::MessageContext,
+) -> Result<notifications::NotificationDecision, String> {
+    let settings = storage.get_notification_settings(channel_id)?.unwrap_or_default();
+    let quiet_hours = notifications::load_quiet_hours(storage)?;
+    let now = now_ts();
+    ctx.recent_in_channel = notifications::recent_arrivals(channel_id, now, quiet_hours.repeat_window_secs);
+    Ok(notifications::decide(&settings, &quiet_hours, &ctx, now))
+}
+
+/// Get the quiet-hours schedule.
+/// Returns JSON { enabled, start_minute, end_minute, utc_offset_minutes,
+/// allow_verified_friends, repeat_threshold, repeat_window_secs }, null on error.
+#[no_mangle]
+pub extern "C" fn get_quiet_hours() -> *mut c_char {
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match notifications::load_quiet_hours(storage) {
+        Ok(q) => match serde_json::to_string(&q) {
+            Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("get_quiet_hours failed: {}", e)),
+        },
+        Err(e) => failed(format!("get_quiet_hours failed: {}", e)),
+    }
+}
+
+/// Set the quiet-hours schedule (same JSON shape as get_quiet_hours; missing fields use defaults).
+/// Minutes are counted from local midnight, e.g. 23:00–07:00 is start 1380, end 420.
+/// Returns 0 on success, a negative error code on error.
+#[no_mangle]
+pub extern "C" fn set_quiet_hours(quiet_hours_json: *const c_char) -> i32 {
+    let quiet_hours: notifications::QuietHours = match parse_c_str(quiet_hours_json)
+        .and_then(|s| serde_json::from_str(s).ok())
+    {
+        Some(v) => v,
+        None => return invalid_argument("quiet_hours_json"),
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match notifications::save_quiet_hours(storage, &quiet_hours) {
+        Ok(_) => 0,
+        Err(e) => failed(format!("set_quiet_hours failed: {}", e)),
+    }
+}
+
+// ========== Mentions (Phase 8) ==========
+
+/// Extract mentions from message text.
+/// 
+/// friends_json: JSON array of friends, e.g.:
+///   [{ "user_id": "...", "nickname": "Alice" }, ...]
+/// Returns JSON array of mentions:
+///   [{ "user_id": "...", "nickname": "Alice" }, ...]
+#[no_mangle]
+pub extern "C" fn extract_mentions_from_text(
+    text_ptr: *const c_char,
+    friends_json_ptr: *const c_char,
+) -> *mut c_char {
+    let text = unsafe {
+        if text_ptr.is_null() {
+            return invalid_argument("text_ptr");
+        }
+        match std::ffi::CStr::from_ptr(text_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return invalid_argument("text_ptr"),
+        }
+    };
+
+    let friends_json = unsafe {
+        if friends_json_ptr.is_null() {
+            return invalid_argument("friends_json_ptr");
+        }
+        match std::ffi::CStr::from_ptr(friends_json_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return invalid_argument("friends_json_ptr"),
+        }
+    };
+
+    let friends: Vec<mentions::FriendInfo> = match serde_json::from_str(friends_json) {
+        Ok(v) => v,
+        Err(e) => return failed(format!("extract_mentions_from_text failed: {}", e)),
+    };
+
+    let mentions = mentions::extract_mentions(text, &friends);
+    match serde_json::to_string(&mentions) {
+        Ok(s) => CString::new(s)
+            .ok()
+            .map(|s| s.into_raw())
+            .unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("extract_mentions_from_text failed: {}", e)),
+    }
+}
+
+/// Test function to verify FFI connectivity
+/// 
+/// Returns a test string to confirm Rust ↔ Flutter communication works
+#[no_mangle]
+pub extern "C" fn test_ffi() -> *mut c_char {
+    let test_message = CString::new("FFI connection successful! Rust ↔ Flutter is working.")
+        .expect("Failed to create CString");
+    test_message.into_raw()
+}
+
+/// Free a CString allocated by Rust
+/// 
+/// Call this from Dart after reading the string to prevent memory leaks
+#[no_mangle]
+pub extern "C" fn free_string(ptr: *mut c_char) {
+    if !ptr.is_null() {
+        unsafe {
+            let _ = CString::from_raw(ptr);
+        }
+    }
+}
+
+// ========== Core Info ==========
+
+/// Build and platform details as JSON: {version, schema_version, target_arch,
+/// target_os, crypto_backends: {sha256, chacha20, poly1305, cpu_features}}.
+/// crypto_backends shows which accelerated code paths this device uses.
+#[no_mangle]
+pub extern "C" fn get_core_info() -> *mut c_char {
+    let info = ffi_types::CoreInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        schema_version: ffi_types::SCHEMA_VERSION,
+        target_arch: std::env::consts::ARCH,
+        target_os: std::env::consts::OS,
+        crypto_backends: crypto_backends::backends().clone(),
+    };
+    match serde_json::to_string(&info) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_core_info failed: {}", e)),
+    }
+}
+
+/// Write a diagnostics bundle for bug reports (see `diagnostics`): a .tar.gz
+/// of recent errors, router, transport and queue stats, the database layout
+/// and the core settings, with no message content, keys or names, into the
+/// diagnostics folder of the data directory. Returns the archive's path,
+/// null on error.
+#[no_mangle]
+pub extern "C" fn create_diagnostics_bundle() -> *mut c_char {
+    if let Err(failure) = claim_data_dir(true) {
+        return failure;
+    }
+    let r_guard = ROUTER.lock().unwrap();
+    let storage_guard = STORAGE.lock().unwrap();
+    let created = storage::data_dir()
+        .and_then(|dir| diagnostics::create(r_guard.as_ref(), storage_guard.as_ref(), &dir.join("diagnostics"), now_ts()));
+    match created {
+        Ok(path) => CString::new(path.to_string_lossy().into_owned())
+            .ok()
+            .map(|s| s.into_raw())
+            .unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("create_diagnostics_bundle failed: {}", e)),
+    }
+}
+
+/// Serve the core's router, transport and storage metrics in the Prometheus
+/// format at http://`addr`/metrics (see `metrics`), for relay daemons. Must be
+/// called within a Tokio runtime; abort the returned task to stop serving.
+#[cfg(feature = "metrics")]
+pub async fn serve_metrics(addr: &str) -> Result<tokio::task::JoinHandle<()>, String> {
+    metrics::serve(addr, || {
+        let r_guard = ROUTER.lock().unwrap();
+        let storage_guard = STORAGE.lock().unwrap();
+        metrics::render(r_guard.as_ref(), storage_guard.as_ref(), now_ts())
+    })
+    .await
+}
+
+// ========== Schema ==========
+
+/// JSON Schema of the payloads returned across the FFI (see `ffi_types`), with
+/// its `version`. Returns JSON, null on error.
+#[no_mangle]
+pub extern "C" fn get_schema() -> *mut c_char {
+    CString::new(ffi_types::schema().to_string())
+        .ok()
+        .map(|s| s.into_raw())
+        .unwrap_or(std::ptr::null_mut())
+}
+
+// ========== Events ==========
+
+/// Drain queued core events.
+/// Returns JSON array [{ kind, timestamp, payload }] (oldest first), null on error.
+#[no_mangle]
+pub extern "C" fn poll_events() -> *mut c_char {
+    let events = events::drain();
+    match serde_json::to_string(&events) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("poll_events failed: {}", e)),
+    }
+}
+
+/// Push events to `callback` as they happen instead of queueing them for
+/// poll_events (null: back to queueing). The callback gets each event as a
+/// JSON { kind, timestamp, payload } string it owns and must free with
+/// free_string; events queued before are handed over first. It runs on the
+/// core's thread while the core may hold locks, so it must not call into the
+/// core: post the string elsewhere (Dart: NativeCallable.listener) and return.
+/// Returns 0.
+#[no_mangle]
+pub extern "C" fn register_event_callback(callback: Option<events::EventCallback>) -> i32 {
+    events::set_callback(callback);
+    0
+}
+
+// ========== Clock ==========
+
+/// Report a trusted time (GPS, NTP, a trusted peer) in seconds since
+/// UNIX_EPOCH; the core uses it instead of the system clock from now on
+/// (see `clock`). Emits `clock_skew` if the system clock is off.
+/// Returns JSON { local_time, trusted_time, skew_secs, state, network_time }, null on error.
+#[no_mangle]
+pub extern "C" fn report_network_time(unix_secs: i64) -> *mut c_char {
+    if unix_secs <= 0 {
+        return invalid_argument("unix_secs");
+    }
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match clock::report_network_time(storage, unix_secs).and_then(|s| serde_json::to_string(&s).map_err(|e| e.to_string())) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("report_network_time failed: {}", e)),
+    }
+}
+
+/// How the system clock compares with the time the core uses: state "ok",
+/// "behind" (e.g. reset after months offline) or "ahead".
+/// Returns JSON { local_time, trusted_time, skew_secs, state, network_time }, null on error.
+#[no_mangle]
+pub extern "C" fn get_clock_status() -> *mut c_char {
+    let status = clock::status();
+    if let Some(storage) = STORAGE.lock().unwrap().as_ref() {
+        if let Err(e) = clock::persist(storage) {
+            eprintln!("Failed to persist clock high-water mark: {}", e);
+        }
+    }
+    match serde_json::to_string(&status) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_clock_status failed: {}", e)),
+    }
+}
+
+/// Message times other nodes claimed too far in the future, newest first.
+/// Returns JSON array [{ message_id, channel_id, claimed, stored, quarantined_at }], null on error.
+#[no_mangle]
+pub extern "C" fn get_quarantined_timestamps(limit: u32) -> *mut c_char {
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    let rows = storage
+        .list_quarantined_timestamps(limit)
+        .map(|rows| rows.iter().map(ffi_types::QuarantinedMessage::from).collect::<Vec<_>>());
+    match rows.and_then(|r| serde_json::to_string(&r).map_err(|e| e.to_string())) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_quarantined_timestamps failed: {}", e)),
+    }
+}
+
+// ========== Transport / Router (Phase 6) ==========
+
+/// Initialize router with loopback transport (for testing / local dev).
+/// Requires storage to be initialized for on_new persistence.
+/// Returns 0 on success, a negative error code on error.
+#[no_mangle]
+pub extern "C" fn init_router_with_loopback() -> i32 {
+    let loopback = std::sync::Arc::new(transport::LoopbackTransport::new());
+    let router = transport::Router::new(vec![loopback.clone()]);
+    let own_public = own_ed25519_public();
+    if let Some(storage) = STORAGE.lock().unwrap().as_ref() {
+        load_router_state(&router, storage, own_public);
+    }
+
+    {
+        let mut lb_guard = LOOPBACK.lock().unwrap();
+        *lb_guard = Some(loopback);
+    }
+    {
+        let mut r_guard = ROUTER.lock().unwrap();
+        *r_guard = Some(router);
+    }
+    0
+}
+
+/// Retry outbox packets whose backoff has passed (see `outbox`; call periodically).
+/// Returns JSON {sent, failed, expired}, null on error.
+#[no_mangle]
+pub extern "C" fn retry_outbox() -> *mut c_char {
+    run_outbox_retry(false, "retry_outbox")
+}
+
+/// Tell the core a transport has become available: every outbox packet is
+/// retried at once, whatever its backoff.
+/// Returns JSON {sent, failed, expired}, null on error.
+#[no_mangle]
+pub extern "C" fn notify_transport_available() -> *mut c_char {
+    run_outbox_retry(true, "notify_transport_available")
+}
+
+fn run_outbox_retry(all: bool, api: &str) -> *mut c_char {
+    let r_guard = ROUTER.lock().unwrap();
+    let Some(router) = r_guard.as_ref() else {
+        return not_initialized("Router");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    save_router_state(router, Some(storage));
+    let result = outbox::retry(storage, router, now_ts(), all)
+        .and_then(|outcome| serde_json::to_string(&outcome).map_err(|e| e.to_string()));
+    match result {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("{} failed: {}", api, e)),
+    }
+}
+
+/// Outbox state as JSON {queued, next_attempt, oldest_queued_at}
+/// (times in UNIX seconds, null when the outbox is empty), null on error.
+#[no_mangle]
+pub extern "C" fn get_outbox_status() -> *mut c_char {
+    let r_guard = ROUTER.lock().unwrap();
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    if let Some(router) = r_guard.as_ref() {
+        save_router_state(router, Some(storage));
+    }
+
+    match outbox::status(storage).and_then(|status| serde_json::to_string(&status).map_err(|e| e.to_string())) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_outbox_status failed: {}", e)),
+    }
+}
+
+/// Forget persisted packet ids past `SEEN_PERSIST_SECS` and mark the rest
+/// seen, newest first up to what the dedup cache holds.
+/// Load the observed and blocked channels and the seen packets into the router.
+fn load_router_state(router: &transport::Router, storage: &storage::Storage, own_public: Option<[u8; 32]>) {
+    match storage.list_observed_channels() {
+        Ok(ids) => router.set_observed_channels(&ids),
+        Err(e) => eprintln!("Failed to load observed channels: {}", e),
+    }
+    if let Some(own_public) = own_public {
+        match blocklist::blocked_channels(storage, &own_public) {
+            Ok(ids) => router.set_blocked_channels(&ids),
+            Err(e) => eprintln!("Failed to load blocked channels: {}", e),
+        }
+    }
+    if let Err(e) = load_seen_packets(router, storage) {
+        eprintln!("Failed to load seen packets: {}", e);
+    }
+}
+
+fn load_seen_packets(router: &transport::Router, storage: &storage::Storage) -> Result<(), String> {
+    let cutoff = now_ts() - transport::SEEN_PERSIST_SECS;
+    storage.prune_seen_packets(cutoff)?;
+    let limit = memory_budget::BUDGET.dedup_limit() as usize / memory_budget::DEDUP_ENTRY_BYTES;
+    router.mark_seen(&storage.list_seen_packets(cutoff, limit)?);
+    Ok(())
+}
+
+/// Persist what the router collected since the last call: the packet ids it
+/// routed, and the packets no transport took (into the outbox).
+fn save_router_state(router: &transport::Router, storage: Option<&storage::Storage>) {
+    let ids = router.take_unsaved();
+    let held = router.take_held();
+    let Some(storage) = storage else {
+        return;
+    };
+    if !ids.is_empty() {
+        if let Err(e) = storage.record_seen_packets(&ids, now_ts()) {
+            eprintln!("Failed to persist seen packets: {}", e);
+        }
+    }
+    if !held.is_empty() {
+        let messages: Vec<[u8; 32]> = held
+            .iter()
+            .filter(|p| p.kind == transport::PacketKind::Message)
+            .map(|p| p.packet_id)
+            .collect();
+        if let Err(e) = outbox::hold(storage, held, now_ts()) {
+            eprintln!("Failed to queue unsent packets: {}", e);
+        }
+        for message_id in messages {
+            if let Err(e) = send_status::advance(storage, message_id, send_status::SendState::Queued, now_ts()) {
+                eprintln!("Send state error: {}", e);
+            }
+        }
+    }
+}
+
+/// Send a packet (builds packet_id if not provided) via router.
+/// packet_id_hex: optional (null pointer -> auto-generate)
+/// channel_id_hex, payload_hex: required
+/// ttl: hop limit
+/// The packet carries the priority set with `set_next_message_priority`, else
+/// the priority the message with this packet_id was stored with.
+/// Returns packet_id_hex on success, null on error.
+#[no_mangle]
+pub extern "C" fn send_packet(
+    packet_id_hex: *const c_char,
+    channel_id_hex: *const c_char,
+    payload_hex: *const c_char,
+    ttl: u8,
+) -> *mut c_char {
+    let next_priority = priority::take_next();
+    let channel_id = match parse_hex_32(channel_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("channel_id_hex"),
+    };
+    let payload = match parse_hex_vec(payload_hex) {
+        Some(v) => v,
+        None => return invalid_argument("payload_hex"),
+    };
+
+    let packet_id = if packet_id_hex.is_null() {
+        transport::Router::generate_packet_id()
+    } else {
+        match parse_hex_32(packet_id_hex) {
+            Some(v) => v,
+            None => return invalid_argument("packet_id_hex"),
+        }
+    };
+
+    let mut packet = transport::Packet {
+        packet_id,
+        channel_id,
+        kind: transport::PacketKind::Message,
+        ttl,
+        payload,
+        priority: next_priority.unwrap_or_default(),
+    };
+
+    // Route and store on new.
+    let own_public = own_ed25519_public();
+    {
+        let r_guard = ROUTER.lock().unwrap();
+        if let Some(ref router) = *r_guard {
+            let storage_guard = STORAGE.lock().unwrap();
+            // Tracked before routing, so we do not acknowledge our own message
+            if let Some(storage) = storage_guard.as_ref() {
+                if next_priority.is_none() {
+                    if let Ok(Some(stored)) = storage.get_message(packet_id) {
+                        packet.priority = priority::Priority::from_u8(stored.priority);
+                    }
+                }
+                if let Err(e) = group_delivery::track_sent(storage, channel_id, packet_id, now_ts()) {
+                    eprintln!("send_packet: failed to track group delivery: {}", e);
+                }
+            }
+            route_packet(router, storage_guard.as_ref(), own_public, packet);
+            if let Some(storage) = storage_guard.as_ref() {
+                if let Err(e) = send_status::sent(storage, packet_id, now_ts()) {
+                    eprintln!("send_packet: failed to record send state: {}", e);
+                }
+            }
+        } else {
+            return not_initialized("Storage");
+        }
+    }
+
+    CString::new(hex::encode(packet_id))
+        .ok()
+        .map(|s| s.into_raw())
+        .unwrap_or(std::ptr::null_mut())
+}
+
+/// Broadcast an emergency message on the SOS channel (see `sos`): plain text,
+/// maximum TTL, sent ahead of other traffic. client_token is optional (see
+/// `dedup_send`).
+/// Returns the message_id hex, or null on error.
+#[no_mangle]
+pub extern "C" fn send_sos_message(text: *const c_char, client_token: *const c_char) -> *mut c_char {
+    dedup_send(client_token, "send_sos_message", &[text], || send_sos_message_once(text))
+}
+
+fn send_sos_message_once(text: *const c_char) -> *mut c_char {
+    let Some(text) = parse_c_str(text) else {
+        return invalid_argument("text");
+    };
+    let packet = match sos::packet(transport::Router::generate_packet_id(), text) {
+        Ok(p) => p,
+        Err(e) => return failed(format!("send_sos_message failed: {}", e)),
+    };
+    let packet_id = packet.packet_id;
+
+    let own_public = own_ed25519_public();
+    {
+        let r_guard = ROUTER.lock().unwrap();
+        let Some(ref router) = *r_guard else {
+            return not_initialized("Router");
+        };
+        let storage_guard = STORAGE.lock().unwrap();
+        route_packet(router, storage_guard.as_ref(), own_public, packet);
+    }
+
+    CString::new(hex::encode(packet_id))
+        .ok()
+        .map(|s| s.into_raw())
+        .unwrap_or(std::ptr::null_mut())
+}
+
+/// The SOS channel id hex (registered like any conversation once a message is seen).
+#[no_mangle]
+pub extern "C" fn get_sos_channel_id() -> *mut c_char {
+    CString::new(hex::encode(*sos::SOS_CHANNEL_ID))
+        .ok()
+        .map(|s| s.into_raw())
+        .unwrap_or(std::ptr::null_mut())
+}
+
+/// Inject a received packet (e.g., from BLE) into the router.
+/// packet_id_hex, channel_id_hex, payload_hex required; ttl as received.
+/// Returns an ingest status like `ingest_typed_packet`.
+#[no_mangle]
+pub extern "C" fn ingest_packet(
+    packet_id_hex: *const c_char,
+    channel_id_hex: *const c_char,
+    payload_hex: *const c_char,
+    ttl: u8,
+) -> i32 {
+    ingest_typed_packet(
+        transport::PacketKind::Message as u8,
+        packet_id_hex,
+        channel_id_hex,
+        payload_hex,
+        ttl,
+    )
+}
+
+/// Inject a received packet of any kind (see `transport::PacketKind`).
+/// The forwarding TTL is capped by the relay.enabled / relay.max_ttl settings,
+/// and packets of channels relay.channel_rules denies are not forwarded.
+/// If the core is busy the packet is queued rather than blocking the caller.
+/// Returns 0 accepted, 1 queued, 2 dropped (overloaded), a negative error code on error (including unknown kinds).
+#[no_mangle]
+pub extern "C" fn ingest_typed_packet(
+    kind: u8,
+    packet_id_hex: *const c_char,
+    channel_id_hex: *const c_char,
+    payload_hex: *const c_char,
+    ttl: u8,
+) -> i32 {
+    ingest_prioritized_packet(kind, priority::Priority::Normal as u8, packet_id_hex, channel_id_hex, payload_hex, ttl)
+}
+
+/// `ingest_typed_packet` for a packet that carried a priority header
+/// (0 background, 1 normal, 2 urgent; see `priority`). Unknown values count
+/// as normal.
+#[no_mangle]
+pub extern "C" fn ingest_prioritized_packet(
+    kind: u8,
+    priority: u8,
+    packet_id_hex: *const c_char,
+    channel_id_hex: *const c_char,
+    payload_hex: *const c_char,
+    ttl: u8,
+) -> i32 {
+    let kind = match transport::PacketKind::from_u8(kind) {
+        Some(k) => k,
+        None => return invalid_argument("kind"),
+    };
+    let packet_id = match parse_hex_32(packet_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("packet_id_hex"),
+    };
+    let channel_id = match parse_hex_32(channel_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("channel_id_hex"),
+    };
+    let payload = match parse_hex_vec(payload_hex) {
+        Some(v) => v,
+        None => return invalid_argument("payload_hex"),
+    };
+    ingest_received(transport::Packet {
+        packet_id,
+        channel_id,
+        kind,
+        ttl,
+        payload,
+        priority: priority::Priority::from_u8(priority),
+    })
+}
+
+/// Route a received packet, or queue it if the core is busy (ingest status).
+fn ingest_received(packet: transport::Packet) -> i32 {
+    // Routed on resume, with the database open to keep it
+    if lifecycle::is_suspended() {
+        return ingest::INGEST.offer(packet) as i32;
+    }
+    let own_public = own_ed25519_public();
+    let Ok(r_guard) = ROUTER.try_lock() else {
+        return ingest::INGEST.offer(packet) as i32;
+    };
+    let router = match r_guard.as_ref() {
+        Some(r) => r,
+        None => return not_initialized("Router"),
+    };
+    let Ok(storage_guard) = STORAGE.try_lock() else {
+        return ingest::INGEST.offer(packet) as i32;
+    };
+
+    // Packets that waited for the core go first
+    for queued in ingest::INGEST.drain() {
+        ingest_routed(router, storage_guard.as_ref(), own_public, queued);
+    }
+    ingest_routed(router, storage_guard.as_ref(), own_public, packet);
+    ingest::INGEST.record_accepted();
+    drop(storage_guard);
+    drop(r_guard);
+    index_pending_messages();
+    run_pending_wipe();
+    ingest::IngestStatus::Accepted as i32
+}
+
+/// Route a packet from another node with its TTL capped by the relay settings,
+/// channel rules and daily budget.
+fn ingest_routed(
+    router: &transport::Router,
+    storage: Option<&storage::Storage>,
+    own_public: Option<[u8; 32]>,
+    mut packet: transport::Packet,
+) {
+    match storage.map(relay_ttl_limit) {
+        // SOS messages keep their TTL unless relaying is off
+        Some(Ok(limit)) if sos::is_sos(&packet) && limit > 0 => {}
+        Some(Ok(limit)) => packet.ttl = packet.ttl.min(limit),
+        Some(Err(e)) => {
+            eprintln!("ingest_packet failed: {}", e);
+            return;
+        }
+        None => {}
+    }
+    if let Some(Err(e)) = storage.map(|storage| relay_policy::apply(storage, &mut packet)) {
+        eprintln!("ingest_packet failed: {}", e);
+        return;
+    }
+    // Our own message coming back means another node forwarded it
+    if packet.kind == transport::PacketKind::Message && router.has_seen(&packet.packet_id) {
+        if let Some(Err(e)) = storage.map(|s| send_status::advance(s, packet.packet_id, send_status::SendState::Relayed, now_ts())) {
+            eprintln!("Send state error: {}", e);
+        }
+    }
+    // Duplicates are dropped by the router, so they do not count against the budget
+    if !router.has_seen(&packet.packet_id) {
+        if let Some(Err(e)) = storage.map(|storage| relay_budget::apply(storage, &mut packet, now_ts())) {
+            eprintln!("Relay budget error: {}", e);
+        }
+    }
+    if router.will_forward(&packet) {
+        events::emit(
+            "packet_relayed",
+            serde_json::json!({
+                "packet_id": hex::encode(packet.packet_id),
+                "channel_id": hex::encode(packet.channel_id),
+                "kind": packet.kind as u8,
+                "ttl": packet.ttl - 1,
+            }),
+        );
+    }
+    route_packet(router, storage, own_public, packet);
+}
+
+/// Route packets queued while the core was busy (call when `get_ingest_metrics`
+/// reports a backlog and no new packets are arriving).
+/// Returns the number of packets routed, or a negative error code if the router is not initialized.
+#[no_mangle]
+pub extern "C" fn process_ingest_queue() -> i32 {
+    let own_public = own_ed25519_public();
+    let r_guard = ROUTER.lock().unwrap();
+    let router = match r_guard.as_ref() {
+        Some(r) => r,
+        None => return not_initialized("Router"),
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let queued = ingest::INGEST.drain();
+    let count = queued.len();
+    for packet in queued {
+        ingest_routed(router, storage_guard.as_ref(), own_public, packet);
+    }
+    drop(storage_guard);
+    drop(r_guard);
+    index_pending_messages();
+    run_pending_wipe();
+    count as i32
+}
+
+/// Ingest queue metrics as JSON:
+/// {depth, capacity, high_water, accepted, queued, dropped, saturated}.
+/// Platform transports should slow their reads while `saturated` is true.
+#[no_mangle]
+pub extern "C" fn get_ingest_metrics() -> *mut c_char {
+    match serde_json::to_string(&ingest::INGEST.metrics()) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_ingest_metrics failed: {}", e)),
+    }
+}
+
+/// Deferred crypto work queue state as JSON: {pending, done, total, running}.
+/// Progress is also reported as `crypto_progress` / `crypto_done` events.
+#[no_mangle]
+pub extern "C" fn get_crypto_queue_status() -> *mut c_char {
+    match serde_json::to_string(&crypto_queue::status()) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_crypto_queue_status failed: {}", e)),
+    }
+}
+
+/// Drop the queued crypto work; the worker stops after its current batch
+/// and emits `crypto_cancelled`. Dropped messages are decrypted when read.
+/// Returns how many queued messages were dropped.
+#[no_mangle]
+pub extern "C" fn cancel_crypto_work() -> i32 {
+    crypto_queue::cancel().min(i32::MAX as usize) as i32
+}
+
+/// Memory budget usage as JSON:
+/// {limit_bytes, used_bytes, over_budget, pools: [{pool, bytes, shed}]}.
+/// The limit is the `memory.budget_bytes` setting.
+#[no_mangle]
+pub extern "C" fn get_memory_metrics() -> *mut c_char {
+    match serde_json::to_string(&memory_budget::BUDGET.metrics()) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_memory_metrics failed: {}", e)),
+    }
+}
+
+/// Relay channel rule counters as JSON:
+/// {rules: [{packets, bytes}] in rule order, default_relayed, default_denied}.
+/// The rules are the `relay.channel_rules` setting; setting them resets the counters.
+#[no_mangle]
+pub extern "C" fn get_relay_rule_stats() -> *mut c_char {
+    match serde_json::to_string(&relay_policy::stats()) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_relay_rule_stats failed: {}", e)),
+    }
+}
+
+/// Today's relay traffic against the `relay.daily_budget_bytes` setting as JSON:
+/// {day, budget_bytes, used_bytes, remaining_bytes?, relayed_packets,
+/// throttled_packets, exhausted, resets_at}. Days are UTC; our own sends never count.
+#[no_mangle]
+pub extern "C" fn get_relay_budget_status() -> *mut c_char {
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match relay_budget::status(storage, now_ts()).and_then(|s| serde_json::to_string(&s).map_err(|e| e.to_string())) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_relay_budget_status failed: {}", e)),
+    }
+}
+
+/// Highest TTL we forward other nodes' packets with, per the relay settings (0 = no relaying).
+fn relay_ttl_limit(storage: &storage::Storage) -> Result<u8, String> {
+    if !settings::get_bool(storage, settings::RELAY_ENABLED)? {
+        return Ok(0);
+    }
+    Ok(settings::get_u64(storage, settings::RELAY_MAX_TTL)?.min(u8::MAX as u64) as u8)
+}
+
+/// Our Ed25519 public key, copied out so packet handling does not hold IDENTITY.
+fn own_ed25519_public() -> Option<[u8; 32]> {
+    IDENTITY
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|i| *i.public().ed25519_public.as_bytes())
+}
+
+/// Our user id, copied out like `own_ed25519_public`.
+fn own_user_id() -> Option<[u8; 32]> {
+    IDENTITY.lock().unwrap().as_ref().map(|i| i.public().user_id)
+}
+
+/// Route a packet, handling it locally the first time it is seen, and
+/// persist the router state (see `save_router_state`).
+/// `own_public` is our Ed25519 key (for authenticating DM history requests).
+fn route_packet(
+    router: &transport::Router,
+    storage: Option<&storage::Storage>,
+    own_public: Option<[u8; 32]>,
+    packet: transport::Packet,
+) {
+    // A group message handed to us again means our receipt was lost: repeat it
+    if packet.kind == transport::PacketKind::Message && router.has_seen(&packet.packet_id) {
+        if let (Some(storage), Some(own_public)) = (storage, own_public) {
+            use sha2::Digest;
+            let own_user_id: [u8; 32] = sha2::Sha256::digest(own_public).into();
+            match group_delivery::receipt_for(storage, own_user_id, &packet) {
+                Ok(Some(receipt)) => router.send_direct(&receipt),
+                Ok(None) => {}
+                Err(e) => eprintln!("Delivery receipt error: {}", e),
+            }
+        }
+    }
+    router.route(packet, |p| handle_new_packet(router, storage, own_public, p));
+    save_router_state(router, storage);
+}
+
+/// On-new handler: persist or act on a packet according to its kind.
+fn handle_new_packet(
+    router: &transport::Router,
+    storage: Option<&storage::Storage>,
+    own_public: Option<[u8; 32]>,
+    p: &transport::Packet,
+) {
+    let storage = match storage {
+        Some(s) => s,
+        None => return,
+    };
+
+    match p.kind {
+        transport::PacketKind::Message => {
+            match blocklist::is_blocked_sender(storage, p.channel_id, p.packet_id, &p.payload) {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(e) => eprintln!("Blocklist error: {}", e),
+            }
+            let post = match broadcasts::check(storage, p, now_ts()) {
+                Ok(post) => post,
+                Err(e) => {
+                    eprintln!("Dropping broadcast post: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = geo_messages::check(storage, p) {
+                eprintln!("Dropping geo message: {}", e);
+                return;
+            }
+            // Persist message (ciphertext) for offline-first
+            let _ = message_futures::store(storage, p.packet_id, p.channel_id, p.payload.clone(), now_ts(), p.ttl);
+            if let Some(post) = post {
+                broadcasts::on_stored(&post);
+            }
+            if let Err(e) = notifications::on_arrival(storage, p.channel_id, now_ts()) {
+                eprintln!("Failed to count message arrival: {}", e);
+            }
+            if p.priority != priority::Priority::Normal {
+                if let Err(e) = storage.set_message_priority(p.packet_id, p.priority as u8) {
+                    eprintln!("Failed to record message priority: {}", e);
+                }
+            }
+            if let Err(e) = message_index::queue_incoming(storage, p.channel_id, p.packet_id) {
+                eprintln!("Message index error: {}", e);
+            }
+            if let Err(e) = group_metadata::on_message(storage, p.channel_id, p.packet_id, &p.payload) {
+                eprintln!("Dropping group metadata change: {}", e);
+            }
+            if let Err(e) = moderation::on_message(storage, p.channel_id, p.packet_id, &p.payload, now_ts()) {
+                eprintln!("Dropping moderation action: {}", e);
+            }
+            if sos::is_sos(p) {
+                if let Err(e) = sos::on_message(storage, p, now_ts()) {
+                    eprintln!("SOS message error: {}", e);
+                }
+            }
+            if let Some(own_public) = own_public {
+                use sha2::Digest;
+                let own_user_id: [u8; 32] = sha2::Sha256::digest(own_public).into();
+                match group_delivery::receipt_for(storage, own_user_id, p) {
+                    Ok(Some(receipt)) => router.route(receipt, |_| {}),
+                    Ok(None) => {}
+                    Err(e) => eprintln!("Delivery receipt error: {}", e),
+                }
+            }
+        }
+        transport::PacketKind::AttachmentManifest => {
+            if let Err(e) = attachments::ingest_manifest(storage, p) {
+                eprintln!("Dropping attachment manifest: {}", e);
+            }
+        }
+        transport::PacketKind::AttachmentChunk => match attachments::ingest_chunk(storage, p) {
+            Ok(Some(row)) => events::emit(
+                "attachment_complete",
+                serde_json::json!({
+                    "attachment_id": hex::encode(row.attachment_id),
+                    "message_id": hex::encode(row.message_id),
+                    "is_thumbnail": row.parent_id.is_some(),
+                }),
+            ),
+            Ok(None) => {}
+            Err(e) => eprintln!("Attachment chunk error: {}", e),
+        },
+        transport::PacketKind::AttachmentRequest => match attachments::handle_request(storage, p) {
+            Ok(packets) => {
+                for packet in packets {
+                    router.route(packet, |_| {});
+                }
+            }
+            Err(e) => eprintln!("Attachment request error: {}", e),
+        },
+        transport::PacketKind::HistoryRequest => match history::handle_request(storage, own_public, p, now_ts()) {
+            Ok(Some(response)) => router.route(response, |_| {}),
+            Ok(None) => {}
+            Err(e) => eprintln!("History request error: {}", e),
+        },
+        transport::PacketKind::HistoryResponse => {
+            if let Err(e) = history::handle_response(storage, p, now_ts()) {
+                eprintln!("History response error: {}", e);
+            }
+        }
+        transport::PacketKind::KeyShareRequest => match key_share::handle_request(storage, p, now_ts()) {
+            Ok(Some(share)) => router.route(share, |_| {}),
+            Ok(None) => {}
+            Err(e) => eprintln!("Dropping key share request: {}", e),
+        },
+        transport::PacketKind::KeyShare => {
+            if let Err(e) = key_share::handle_share(storage, p, now_ts()) {
+                eprintln!("Dropping key share: {}", e);
+            }
+        }
+        transport::PacketKind::MessageReceipt => {
+            if let Err(e) = receipts::handle_receipt(storage, own_public, p, now_ts()) {
+                eprintln!("Dropping message receipt: {}", e);
+            }
+        }
+        transport::PacketKind::SyncInventory => match sync::handle_inventory(storage, p, now_ts()) {
+            Ok(answer) => {
+                for packet in &answer.messages {
+                    router.send_direct(packet);
+                }
+                if let Some(inventory) = answer.inventory {
+                    router.route(inventory, |_| {});
+                }
+            }
+            Err(e) => eprintln!("Dropping sync inventory: {}", e),
+        },
+        transport::PacketKind::ChannelAnnouncement => {
+            if let Err(e) = channel_directory::handle_announcement(storage, p, now_ts()) {
+                eprintln!("Dropping channel announcement: {}", e);
+            }
+        }
+        transport::PacketKind::DeliveryReceipt => {
+            use sha2::Digest;
+            let own_user_id = own_public.map(|k| sha2::Sha256::digest(k).into());
+            if let Err(e) = group_delivery::handle_receipt(storage, own_user_id, p, now_ts()) {
+                eprintln!("Dropping delivery receipt: {}", e);
+            }
+        }
+        transport::PacketKind::DeviceControl => {
+            use sha2::Digest;
+            let Some(own_public) = own_public else {
+                return;
+            };
+            let own_user_id: [u8; 32] = sha2::Sha256::digest(own_public).into();
+            match wipe::verify_command(storage, own_user_id, p, now_ts()) {
+                Ok(true) => {
+                    events::emit("remote_wipe", serde_json::json!({ "packet_id": hex::encode(p.packet_id) }));
+                    wipe::request();
+                }
+                Ok(false) => {}
+                Err(e) => eprintln!("Dropping device control packet: {}", e),
+            }
+        }
+    }
+}
+
+/// Drain loopback transport packets (testing helper).
+/// Returns JSON array of packets {packet_id, channel_id, kind, ttl, payload} hex-encoded.
+#[no_mangle]
+pub extern "C" fn drain_loopback_packets() -> *mut c_char {
+    let lb_guard = LOOPBACK.lock().unwrap();
+    if let Some(ref lb) = *lb_guard {
+        let packets = lb.drain();
+        let json: Vec<ffi_types::WirePacket> = packets.iter().map(ffi_types::WirePacket::from).collect();
+        match serde_json::to_string(&json) {
+            Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("drain_loopback_packets failed: {}", e)),
+        }
+    } else {
+        not_initialized("Router")
+    }
+}
+
+// ========== Open Mesh Profile ==========
+
+/// Send a public message on a geohash channel as an open mesh profile frame
+/// (see `open_profile`). The packet is routed like `send_packet`; the frame is
+/// returned for bridges and third-party transports.
+/// Returns the frame hex, or null on error.
+#[cfg(feature = "open-profile")]
+#[no_mangle]
+pub extern "C" fn omp_send_public_message(
+    geohash: *const c_char,
+    topic: *const c_char,
+    nickname: *const c_char,
+    text: *const c_char,
+    ttl: u8,
+) -> *mut c_char {
+    let (Some(geohash), Some(topic), Some(nickname), Some(text)) =
+        (parse_c_str(geohash), parse_c_str(topic), parse_c_str(nickname), parse_c_str(text))
+    else {
+        return invalid_argument("geohash");
+    };
+
+    let payload = {
+        let identity_guard = IDENTITY.lock().unwrap();
+        let Some(identity) = identity_guard.as_ref() else {
+            return not_initialized("Identity");
+        };
+        match open_profile::seal_envelope(identity.ed25519_signing_key(), nickname, text, now_ts()) {
+            Ok(p) => p,
+            Err(e) => return failed(format!("omp_send_public_message failed: {}", e)),
+        }
+    };
+    let packet = transport::Packet {
+        packet_id: transport::Router::generate_packet_id(),
+        channel_id: open_profile::public_channel_id(geohash, topic),
+        kind: transport::PacketKind::Message,
+        ttl,
+        payload,
+        priority: priority::Priority::Normal,
+    };
+    match route_open_profile_packet(packet, "omp_send_public_message") {
+        Ok(frame) => CString::new(hex::encode(frame))
+            .ok()
+            .map(|s| s.into_raw())
+            .unwrap_or(std::ptr::null_mut()),
+        Err(failure) => failure,
+    }
+}
+
+/// Send a public message on a geohash channel under our pseudonym there
+/// instead of our identity (see `pseudonyms`); a reply (in_reply_to_hex,
+/// nullable) goes out under the pseudonym that wrote the message it answers.
+/// Returns JSON { packet_id, frame, pseudonym: { channel_id, generation,
+/// ed25519_public, user_id, created_at, retired_at? } }, or null on error.
+#[cfg(feature = "open-profile")]
+#[no_mangle]
+pub extern "C" fn omp_send_pseudonymous_message(
+    geohash: *const c_char,
+    topic: *const c_char,
+    nickname: *const c_char,
+    text: *const c_char,
+    ttl: u8,
+    in_reply_to_hex: *const c_char,
+) -> *mut c_char {
+    let (Some(geohash), Some(topic), Some(nickname), Some(text)) =
+        (parse_c_str(geohash), parse_c_str(topic), parse_c_str(nickname), parse_c_str(text))
+    else {
+        return invalid_argument("geohash");
+    };
+    let in_reply_to = if in_reply_to_hex.is_null() {
+        None
+    } else {
+        match parse_hex_32(in_reply_to_hex) {
+            Some(v) => Some(v),
+            None => return invalid_argument("in_reply_to_hex"),
+        }
+    };
+
+    let channel_id = open_profile::public_channel_id(geohash, topic);
+    let packet_id = transport::Router::generate_packet_id();
+    let (payload, pseudonym) = {
+        let identity_guard = IDENTITY.lock().unwrap();
+        let Some(identity) = identity_guard.as_ref() else {
+            return not_initialized("Identity");
+        };
+        let storage_guard = STORAGE.lock().unwrap();
+        let Some(storage) = storage_guard.as_ref() else {
+            return not_initialized("Storage");
+        };
+        let now = now_ts();
+        let sealed = pseudonyms::for_reply(identity, storage, channel_id, in_reply_to, now).and_then(|(row, key)| {
+            let payload = open_profile::seal_envelope(&key, nickname, text, now)?;
+            pseudonyms::record_sent(storage, packet_id, &row)?;
+            Ok((payload, row))
+        });
+        match sealed {
+            Ok(sealed) => sealed,
+            Err(e) => return failed(format!("omp_send_pseudonymous_message failed: {}", e)),
+        }
+    };
+    let packet = transport::Packet {
+        packet_id,
+        channel_id,
+        kind: transport::PacketKind::Message,
+        ttl,
+        payload,
+        priority: priority::Priority::Normal,
+    };
+    let frame = match route_open_profile_packet(packet, "omp_send_pseudonymous_message") {
+        Ok(frame) => frame,
+        Err(failure) => return failure,
+    };
+    let result = ffi_types::PseudonymFrame {
+        packet_id: hex::encode(packet_id),
+        frame: hex::encode(frame),
+        pseudonym: pseudonyms::Pseudonym::from(&pseudonym),
+    };
+    CString::new(serde_json::to_string(&result).unwrap_or_default())
+        .ok()
+        .map(|s| s.into_raw())
+        .unwrap_or(std::ptr::null_mut())
+}
+
+/// Encode an open mesh profile packet and route it like `send_packet`.
+/// Returns the frame, or the failure to return from `api`.
+#[cfg(feature = "open-profile")]
+fn route_open_profile_packet(packet: transport::Packet, api: &str) -> Result<Vec<u8>, *mut c_char> {
+    let frame = open_profile::encode_frame(&packet).map_err(|e| failed(format!("{} failed: {}", api, e)))?;
+
+    let own_public = own_ed25519_public();
+    let r_guard = ROUTER.lock().unwrap();
+    let Some(ref router) = *r_guard else {
+        return Err(not_initialized("Router"));
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    route_packet(router, storage_guard.as_ref(), own_public, packet);
+    Ok(frame)
+}
+
+/// Decode an open mesh profile frame and verify its envelope.
+/// Returns JSON { packet_id, channel_id, ttl, envelope: { timestamp,
+/// sender_ed25519_public, sender_user_id, nickname, text } }, or null if the
+/// frame is malformed or its signature does not verify.
+#[cfg(feature = "open-profile")]
+#[no_mangle]
+pub extern "C" fn omp_decode_frame(frame_hex: *const c_char) -> *mut c_char {
+    let Some(frame) = parse_hex_vec(frame_hex) else {
+        return invalid_argument("frame_hex");
+    };
+    match open_profile::decode(&frame) {
+        Ok((_, decoded)) => serde_json::to_string(&decoded)
+            .ok()
+            .and_then(|s| CString::new(s).ok())
+            .map(|s| s.into_raw())
+            .unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("omp_decode_frame failed: {}", e)),
+    }
+}
+
+/// Inject an open mesh profile frame received from a bridge or third-party
+/// node. Frames whose envelope does not verify are rejected; the rest are
+/// routed like `ingest_packet` (the stored payload is the envelope).
+/// Returns an ingest status like `ingest_typed_packet`.
+#[cfg(feature = "open-profile")]
+#[no_mangle]
+pub extern "C" fn omp_ingest_frame(frame_hex: *const c_char) -> i32 {
+    let Some(frame) = parse_hex_vec(frame_hex) else {
+        return invalid_argument("frame_hex");
+    };
+    match open_profile::decode(&frame) {
+        Ok((packet, _)) => ingest_received(packet),
+        Err(e) => failed(format!("omp_ingest_frame failed: {}", e)),
+    }
+}
+
+// ========== Audit (audit feature) ==========
+
+/// Choose the channel auditor exports are allowed for (null: none).
+/// Returns 0 on success, a negative error code on error
+#[cfg(feature = "audit")]
+#[no_mangle]
+pub extern "C" fn audit_select_channel(channel_id_hex: *const c_char) -> i32 {
+    let channel_id = if channel_id_hex.is_null() {
+        None
+    } else {
+        match parse_hex_32(channel_id_hex) {
+            Some(id) => Some(id),
+            None => return invalid_argument("channel_id_hex"),
+        }
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match audit::select_channel(storage, channel_id) {
+        Ok(()) => 0,
+        Err(e) => failed(format!("audit_select_channel failed: {}", e)),
+    }
+}
+
+/// Export the selected channel's session transcripts and key schedule, keys
+/// included, for security review (see `audit`).
+/// Returns JSON { channel_id, channel_type, noise_pattern?, local_static?,
+/// sessions, key_epochs, transcript }, null on error or for any other channel.
+#[cfg(feature = "audit")]
+#[no_mangle]
+pub extern "C" fn audit_export_channel(channel_id_hex: *const c_char) -> *mut c_char {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+    let identity_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = identity_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match audit::export_channel(identity, storage, channel_id, now_ts())
+        .and_then(|export| serde_json::to_string(&export).map_err(|e| e.to_string()))
+    {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("audit_export_channel failed: {}", e)),
+    }
+}
+
+// ========== Simulation (seeded-rng feature) ==========
+
+/// Draw all randomness (keys, nonces, packet ids, Noise ephemerals) from a
+/// stream seeded with `seed`, so simulation runs and golden tests repeat
+/// exactly (see `rng`). Call before init_identity. Returns 0.
+#[cfg(feature = "seeded-rng")]
+#[no_mangle]
+pub extern "C" fn seed_rng(seed: u64) -> i32 {
+    rng::seed(seed);
+    0
+}
+
+// ========== History Replay ==========
+
+/// Call when a peer subscribes to a channel (e.g. joins a geo room): sends it
+/// recent history of that channel within the replay budget and rate limit, and
+/// records the peer as a routing hint for the channel.
+/// Returns the number of messages sent (0 if private or rate limited), a negative error code on error.
+#[no_mangle]
+pub extern "C" fn replay_channel_history(peer_id_hex: *const c_char, channel_id_hex: *const c_char) -> i32 {
+    let peer_id = match parse_hex_32(peer_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("peer_id_hex"),
+    };
+    let channel_id = match parse_hex_32(channel_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("channel_id_hex"),
+    };
+
+    let r_guard = ROUTER.lock().unwrap();
+    let router = match r_guard.as_ref() {
+        Some(r) => r,
+        None => return not_initialized("Router"),
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    let now = now_ts();
+    let result = storage
+        .upsert_routing_hint(channel_id, peer_id, now)
+        .and_then(|_| replay::replay_for_peer(storage, peer_id, channel_id, now));
+    match result {
+        Ok(packets) => {
+            for packet in &packets {
+                router.send_direct(packet);
+            }
+            save_router_state(router, Some(storage));
+            packets.len() as i32
+        }
+        Err(e) => failed(format!("replay_channel_history failed: {}", e)),
+    }
+}
+
+/// Call when a peer comes in range: offers it our inventory of a channel
+/// (see `sync`). The peer sends back the messages we lack and its own
+/// inventory, which we answer with the messages it lacks.
+/// Returns 1 if an inventory was sent, 0 if the channel is private, a negative error code on error.
+#[no_mangle]
+pub extern "C" fn sync_channel(channel_id_hex: *const c_char) -> i32 {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+    let r_guard = ROUTER.lock().unwrap();
+    let Some(router) = r_guard.as_ref() else {
+        return not_initialized("Router");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    match sync::inventory_packet(storage, channel_id, true, now_ts()) {
+        Ok(Some(packet)) => {
+            router.route(packet, |_| {});
+            save_router_state(router, Some(storage));
+            1
+        }
+        Ok(None) => 0,
+        Err(e) => failed(format!("sync_channel failed: {}", e)),
+    }
+}
+
+// ========== Groups ==========
+
+/// Create a group (see `groups`) with a fresh key and us as its only member.
+/// name may be null. Returns channel_id hex, null on error.
+#[no_mangle]
+pub extern "C" fn create_group(name: *const c_char) -> *mut c_char {
+    let name = if name.is_null() {
+        None
+    } else {
+        match parse_c_str(name) {
+            Some(s) => Some(s),
+            None => return invalid_argument("name"),
+        }
+    };
+
+    let identity_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = identity_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match groups::create(identity, storage, name, now_ts()) {
+        Ok(id) => CString::new(hex::encode(id)).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("create_group failed: {}", e)),
+    }
+}
+
+/// Encrypt a group invite to a friend and store it as a DM. Returns the DM's message id.
+fn send_group_invite(
+    identity: &identity::Identity,
+    storage: &storage::Storage,
+    friend: ([u8; 32], [u8; 32]),
+    invite: &groups::GroupInvite,
+    timestamp: i64,
+) -> Result<[u8; 32], String> {
+    let outgoing = encrypt_outgoing_dm(identity, storage, friend.0, Some(friend.1), &invite.encode()?, timestamp)?;
+    let message_id = outgoing.message_id;
+    storage.store_outgoing_batch(&[outgoing])?;
+    Ok(message_id)
+}
+
+/// Add a friend to one of our groups and send them the group key as a DM.
+/// Returns the invite DM's message_id hex, null on error (the friend is not
+/// added if the invite cannot be encrypted to them).
+#[no_mangle]
+pub extern "C" fn add_group_member(channel_id_hex: *const c_char, user_id_hex: *const c_char) -> *mut c_char {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+    let Some(user_id) = parse_hex_32(user_id_hex) else {
+        return invalid_argument("user_id_hex");
+    };
+
+    let identity_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = identity_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let Some(friend) = friend_keys().into_iter().find(|(id, _)| *id == user_id) else {
+        return fail(MeshError::NotFound, "Not a friend");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    let now = now_ts();
+    let sent = storage.with_transaction(|storage| {
+        let invite = groups::add_member(identity, storage, channel_id, user_id, now)?;
+        send_group_invite(identity, storage, friend, &invite, now)
+    });
+    match sent {
+        Ok(id) => CString::new(hex::encode(id)).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("add_group_member failed: {}", e)),
+    }
+}
+
+/// Remove a member from one of our groups. The group key is rotated and the
+/// new key sent to every remaining member as a DM.
+/// Returns JSON [{ user_id, message_id, error }] for the remaining members
+/// (error set for those the new key could not be sent to), null on error.
+#[no_mangle]
+pub extern "C" fn remove_group_member(channel_id_hex: *const c_char, user_id_hex: *const c_char) -> *mut c_char {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+    let Some(user_id) = parse_hex_32(user_id_hex) else {
+        return invalid_argument("user_id_hex");
+    };
+
+    let identity_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = identity_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let friends = friend_keys();
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    let now = now_ts();
+    let (invite, members) = match groups::remove_member(identity, storage, channel_id, user_id, now) {
+        Ok(v) => v,
+        Err(e) => return failed(format!("remove_group_member failed: {}", e)),
+    };
+    let results = send_rekey_invites(identity, storage, &friends, &invite, members, now);
+    match serde_json::to_string(&results) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("remove_group_member failed: {}", e)),
+    }
+}
+
+/// Send a group's new key to the members that remain, one invite DM each.
+fn send_rekey_invites(
+    identity: &identity::Identity,
+    storage: &storage::Storage,
+    friends: &[([u8; 32], [u8; 32])],
+    invite: &groups::GroupInvite,
+    members: Vec<[u8; 32]>,
+    now: i64,
+) -> Vec<ffi_types::BulkSendResult> {
+    members
+        .into_iter()
+        .map(|member| {
+            let sent = friends
+                .iter()
+                .find(|(id, _)| *id == member)
+                .ok_or_else(|| MeshError::NotFound.raise("Not a friend"))
+                .and_then(|friend| send_group_invite(identity, storage, *friend, invite, now));
+            ffi_types::BulkSendResult {
+                user_id: hex::encode(member),
+                message_id: sent.as_ref().ok().map(hex::encode),
+                error: sent.err(),
+            }
+        })
+        .collect()
+}
+
+/// Join a group from an invite a friend sent us (a DM message with
+/// `group_invite`). Joining a group we are in already takes its new key.
+/// Returns JSON { channel_id, channel_type, name, protected, inviter_user_id },
+/// null on error.
+#[no_mangle]
+pub extern "C" fn accept_group_invite(message_id_hex: *const c_char) -> *mut c_char {
+    let Some(message_id) = parse_hex_32(message_id_hex) else {
+        return invalid_argument("message_id_hex");
+    };
+
+    let identity_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = identity_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let friends = friend_keys();
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    let accepted = storage.get_message(message_id).and_then(|row| {
+        let row = row.ok_or_else(|| MeshError::NotFound.raise("Message not found"))?;
+        let (_, friend_key) = dm_friend_for_channel(identity, &friends, row.channel_id).ok_or_else(|| MeshError::Rejected.raise("Not a DM with a friend"))?;
+        let opened = decrypt_dm_row(identity, storage, &dm_keys(identity, Some(friend_key)), &row)?;
+        let invite = String::from_utf8(opened.plaintext)
+            .ok()
+            .filter(|_| !opened.outgoing)
+            .and_then(|text| groups::parse_invite(&text))
+            .ok_or_else(|| MeshError::Rejected.raise("Not a group invite we received"))?;
+        groups::accept_invite(identity, storage, &invite, friend_key, now_ts())
+    });
+    match accepted {
+        Ok(a) => CString::new(serde_json::to_string(&a).unwrap_or_default())
+            .ok()
+            .map(|s| s.into_raw())
+            .unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("accept_group_invite failed: {}", e)),
+    }
+}
+
+/// Send a message to one of our groups, sealed under its current key.
+/// client_token is optional (see `dedup_send`).
+/// Returns message_id hex, null on error.
+#[no_mangle]
+pub extern "C" fn send_group_message(channel_id_hex: *const c_char, text: *const c_char, client_token: *const c_char) -> *mut c_char {
+    dedup_send(client_token, "send_group_message", &[channel_id_hex, text], || send_group_message_once(channel_id_hex, text))
+}
+
+fn send_group_message_once(channel_id_hex: *const c_char, text: *const c_char) -> *mut c_char {
+    let priority = priority::take_next().unwrap_or_default();
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+    let Some(text) = parse_c_str(text) else {
+        return invalid_argument("text");
+    };
+
+    let identity_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = identity_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    let options = messaging::SendOptions { priority: Some(priority), ..Default::default() };
+    match messaging::send(identity, storage, messaging::Target::Group(channel_id), text, &options, now_ts()) {
+        Ok(sent) => CString::new(sent.result.message_id).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("send_group_message failed: {}", e)),
+    }
+}
+
+/// Change one of our groups' name and topic (null clears either). The change
+/// is signed, applied here and sent to the members as a group message (see
+/// `group_metadata`). Returns message_id hex, null on error.
+#[no_mangle]
+pub extern "C" fn set_group_metadata(channel_id_hex: *const c_char, name: *const c_char, topic: *const c_char) -> *mut c_char {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+    let name = if name.is_null() {
+        None
+    } else {
+        match parse_c_str(name) {
+            Some(n) => Some(n),
+            None => return invalid_argument("name"),
+        }
+    };
+    let topic = if topic.is_null() {
+        None
+    } else {
+        match parse_c_str(topic) {
+            Some(t) => Some(t),
+            None => return invalid_argument("topic"),
+        }
+    };
+
+    let identity_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = identity_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    let now = now_ts();
+    let sent = group_metadata::update(identity, storage, channel_id, name, topic, now).and_then(|outgoing| {
+        let message_id = outgoing.message_id;
+        storage.store_outgoing_batch(&[outgoing])?;
+        group_delivery::track_sent(storage, channel_id, message_id, now)?;
+        Ok(message_id)
+    });
+    match sent {
+        Ok(id) => CString::new(hex::encode(id)).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("set_group_metadata failed: {}", e)),
+    }
+}
+
+/// Take a moderation action as a group admin: "remove_member", "mute_member"
+/// or "unmute_member" a member (user_id hex), or "delete_message" a message
+/// (message_id hex). The action is signed, applied here and sent to the
+/// members (see `moderation`); removing a member also rotates the group key
+/// for the rest. Returns JSON { message_id, rekey: [BulkSendResult] } (`ModerationResult`),
+/// null on error.
+#[no_mangle]
+pub extern "C" fn moderate_group(channel_id_hex: *const c_char, action: *const c_char, target_hex: *const c_char) -> *mut c_char {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+    let Some(action) = parse_c_str(action).and_then(moderation::Action::from_name) else {
+        return invalid_argument("action");
+    };
+    let Some(target) = parse_hex_32(target_hex) else {
+        return invalid_argument("target_hex");
+    };
+
+    let identity_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = identity_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let friends = friend_keys();
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    let now = now_ts();
+    let sent = moderation::issue(identity, storage, channel_id, action, target, now).and_then(|outgoing| {
+        let message_id = outgoing.message_id;
+        storage.store_outgoing_batch(&[outgoing])?;
+        group_delivery::track_sent(storage, channel_id, message_id, now)?;
+        let rekey = if action == moderation::Action::RemoveMember {
+            let (invite, members) = groups::rotate_key(identity, storage, channel_id, now)?;
+            send_rekey_invites(identity, storage, &friends, &invite, members, now)
+        } else {
+            Vec::new()
+        };
+        Ok(ffi_types::ModerationResult { message_id: hex::encode(message_id), rekey })
+    });
+    match sent {
+        Ok(result) => CString::new(serde_json::to_string(&result).unwrap_or_default()).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("moderate_group failed: {}", e)),
+    }
+}
+
+/// A group's current name and topic as JSON { channel_id, name, topic }, null on error.
+#[no_mangle]
+pub extern "C" fn get_group_metadata(channel_id_hex: *const c_char) -> *mut c_char {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    let metadata = storage.get_channel_type(channel_id).and_then(|channel_type| {
+        if channel_type.as_deref() != Some("group") {
+            return Err(MeshError::Rejected.raise("Not a group channel"));
+        }
+        Ok(serde_json::json!({
+            "channel_id": hex::encode(channel_id),
+            "name": storage.get_channel_name(channel_id)?,
+            "topic": storage.get_channel_topic(channel_id)?,
+        }))
+    });
+    match metadata {
+        Ok(json) => CString::new(json.to_string()).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_group_metadata failed: {}", e)),
+    }
+}
+
+/// Get and decrypt a group's messages, oldest first. Messages no key epoch
+/// of ours opens are skipped; name and topic changes show as system events,
+/// moderation actions through the `moderation` events they recorded, and
+/// messages from muted or blocked members are hidden.
+/// Returns JSON array of messages (as `get_dm_messages`, with sender_user_id), null on error.
+#[no_mangle]
+pub extern "C" fn get_group_messages(channel_id_hex: *const c_char, limit: u32, offset: u32) -> *mut c_char {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+    let Some(own_user_id) = own_user_id() else {
+        return not_initialized("Identity");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    let now = now_ts();
+    let history = storage.fetch_live_messages(channel_id, limit, offset, now).and_then(|rows| {
+        let retention = retention::Retention::for_channel(storage, channel_id)?;
+        let hidden: Vec<String> = storage
+            .list_group_mutes(channel_id)?
+            .iter()
+            .chain(storage.list_blocked_users()?.iter().map(|row| &row.user_id))
+            .map(hex::encode)
+            .collect();
+        let mut messages = Vec::new();
+        for row in rows {
+            let message = match row.kind {
+                storage::MESSAGE_KIND_PENDING => ffi_types::DmMessage::pending(&row.message_id, row.timestamp),
+                storage::MESSAGE_KIND_SYSTEM => match system_messages::parse(&row.ciphertext) {
+                    Ok(event) => ffi_types::DmMessage::system(&row.message_id, event, row.timestamp),
+                    Err(e) => {
+                        eprintln!("Skipping system message {}: {}", hex::encode(row.message_id), e);
+                        continue;
+                    }
+                },
+                _ => {
+                    let opened = groups::open_message(storage, channel_id, &row.message_id, &row.ciphertext)
+                        .and_then(|p| String::from_utf8(p).map_err(|e| MeshError::InvalidArgument.raise(format!("Message is not UTF-8: {}", e))));
+                    let plaintext = match opened {
+                        Ok(p) => p,
+                        Err(e) => {
+                            eprintln!("Failed to open group message {}: {}", hex::encode(row.message_id), e);
+                            continue;
+                        }
+                    };
+                    if let Some(update) = group_metadata::parse(&plaintext) {
+                        ffi_types::DmMessage::system(&row.message_id, update.event(), row.timestamp)
+                    } else if moderation::parse(&plaintext).is_some() {
+                        continue;
+                    } else {
+                        let (sender, text) = groups::parse_message(&plaintext);
+                        if sender.as_ref().is_some_and(|s| hidden.contains(s)) {
+                            continue;
+                        }
+                        let is_sent = sender.as_deref() == Some(hex::encode(own_user_id).as_str());
+                        ffi_types::DmMessage {
+                            sender_user_id: sender,
+                            ..ffi_types::DmMessage::user(&row.message_id, text, row.timestamp, is_sent)
+                        }
+                    }
+                }
+            };
+            messages.push(ffi_types::DmMessage {
+                expires_in: retention.expires_in(row.timestamp, now),
+                priority: ffi_types::shown_priority(row.priority),
+                ..message
+            });
+        }
+        Ok(messages)
+    });
+    match history.and_then(|m| serde_json::to_string(&m).map_err(|e| e.to_string())) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_group_messages failed: {}", e)),
+    }
+}
+
+// ========== Group Delivery ==========
+
+/// Set the member list of a protected group (JSON array of user_id hex, ourselves
+/// included). Groups of up to 16 members use custody receipts (see `group_delivery`).
+/// Returns 0 on success, a negative error code on error or if the channel is not a group.
+#[no_mangle]
+pub extern "C" fn set_group_members(channel_id_hex: *const c_char, user_ids_json: *const c_char) -> i32 {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+    let Some(ids) = parse_c_str(user_ids_json).and_then(|s| serde_json::from_str::<Vec<String>>(s).ok()) else {
+        return invalid_argument("user_ids_json");
+    };
+    let Ok(user_ids) = ids.iter().map(|id| codec::parse_id_hex(id, "user id")).collect::<Result<Vec<_>, _>>() else {
+        return invalid_argument("user_ids_json");
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    let result = storage.get_channel_type(channel_id).and_then(|t| match t.as_deref() {
+        Some("group") => storage.set_group_members(channel_id, &user_ids, now_ts()),
+        _ => Err(MeshError::Rejected.raise("Not a group channel")),
+    });
+    match result {
+        Ok(()) => 0,
+        Err(e) => failed(format!("set_group_members failed: {}", e)),
+    }
+}
+
+/// Custody receipts of one of our group messages.
+/// Returns JSON { message_id, acked: [user_id], missing: [user_id] }, or null if
+/// the message is not tracked (delivered to everyone, given up or not a small group).
+#[no_mangle]
+pub extern "C" fn get_group_delivery_status(message_id_hex: *const c_char) -> *mut c_char {
+    let Some(message_id) = parse_hex_32(message_id_hex) else {
+        return invalid_argument("message_id_hex");
+    };
+    let own_user_id = own_user_id();
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match group_delivery::status(storage, own_user_id, message_id) {
+        Ok(Some(status)) => {
+            let json = serde_json::to_string(&ffi_types::GroupDeliveryStatus::new(message_id, &status)).unwrap_or_default();
+            CString::new(json).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut())
+        }
+        Ok(None) => fail(MeshError::NotFound, "Message is not tracked"),
+        Err(e) => failed(format!("get_group_delivery_status failed: {}", e)),
+    }
+}
+
+/// Group messages due for retransmission (call periodically). Hand each packet
+/// to the links of the listed members instead of flooding it again.
+/// Returns JSON [{ message_id, channel_id, missing: [user_id], packet }], null on error.
+#[no_mangle]
+pub extern "C" fn get_due_group_retransmits() -> *mut c_char {
+    let own_user_id = own_user_id();
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match group_delivery::due_retransmits(storage, own_user_id, now_ts()) {
+        Ok(due) => {
+            let json: Vec<ffi_types::GroupRetransmit> = due.iter().map(ffi_types::GroupRetransmit::from).collect();
+            match serde_json::to_string(&json) {
+                Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+                Err(e) => failed(format!("get_due_group_retransmits failed: {}", e)),
+            }
+        }
+        Err(e) => failed(format!("get_due_group_retransmits failed: {}", e)),
+    }
+}
+
+// ========== History Requests ==========
+
+/// Ask peers for a page of a channel's history after the cursor `(since, after_hex)`.
+/// `after_hex` may be null for the first page; `limit` 0 uses the default page size.
+/// DM requests are signed with our identity. Pages arrive as `history_page` events
+/// carrying the next cursor. Returns the request id hex, or null on error.
+#[no_mangle]
+pub extern "C" fn request_channel_history(
+    channel_id_hex: *const c_char,
+    since: i64,
+    after_hex: *const c_char,
+    limit: u32,
+    ttl: u8,
+) -> *mut c_char {
+    let channel_id = match parse_hex_32(channel_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("channel_id_hex"),
+    };
+    let after = if after_hex.is_null() {
+        None
+    } else {
+        match parse_hex_32(after_hex) {
+            Some(v) => Some(v),
+            None => return invalid_argument("after_hex"),
+        }
+    };
+
+    let id_guard = IDENTITY.lock().unwrap();
+    let r_guard = ROUTER.lock().unwrap();
+    let router = match r_guard.as_ref() {
+        Some(r) => r,
+        None => return not_initialized("Router"),
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    // Only DMs need to identify the requester
+    let is_dm = match storage.get_channel_type(channel_id) {
+        Ok(t) => t.as_deref() == Some("dm"),
+        Err(e) => return failed(format!("request_channel_history failed: {}", e)),
+    };
+    let identity = if is_dm { id_guard.as_ref() } else { None };
+    if is_dm && identity.is_none() {
+        return not_initialized("Identity");
+    }
+
+    match history::build_request(identity, channel_id, since, after, limit, ttl, now_ts()) {
+        Ok(packet) => {
+            let request_id = hex::encode(packet.packet_id);
+            router.route(packet, |_| {});
+            save_router_state(router, Some(storage));
+            CString::new(request_id).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut())
+        }
+        Err(e) => failed(format!("request_channel_history failed: {}", e)),
+    }
+}
+
+/// Ask members of a protected geo channel we joined late for its recent keys
+/// (see `key_share`), so recent history sealed under earlier keys becomes
+/// readable. Members share keys added within their
+/// `channels.late_join_history_secs`; received keys raise a
+/// `channel_keys_shared` event. Returns the request id hex, or null on error.
+#[no_mangle]
+pub extern "C" fn request_channel_keys(channel_id_hex: *const c_char, ttl: u8) -> *mut c_char {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+
+    let id_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = id_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let r_guard = ROUTER.lock().unwrap();
+    let Some(router) = r_guard.as_ref() else {
+        return not_initialized("Router");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    match key_share::build_request(identity, storage, channel_id, ttl, now_ts()) {
+        Ok(packet) => {
+            let request_id = hex::encode(packet.packet_id);
+            router.route(packet, |_| {});
+            save_router_state(router, Some(storage));
+            CString::new(request_id).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut())
+        }
+        Err(e) => failed(format!("request_channel_keys failed: {}", e)),
+    }
+}
+
+// ========== Receipts ==========
+
+/// Record our delivery or read status for a stored message and send the
+/// receipt on its channel (see `receipts`).
+fn mark_message(message_id_hex: *const c_char, status: receipts::ReceiptStatus, name: &str) -> i32 {
+    let Some(message_id) = parse_hex_32(message_id_hex) else {
+        return invalid_argument("message_id_hex");
+    };
+
+    let id_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = id_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let r_guard = ROUTER.lock().unwrap();
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    match receipts::mark(identity, storage, message_id, status, now_ts()) {
+        Ok(receipt) => {
+            if let (Some(receipt), Some(router)) = (receipt, r_guard.as_ref()) {
+                router.route(receipt, |_| {});
+                save_router_state(router, Some(storage));
+            }
+            0
+        }
+        Err(e) => failed(format!("{} failed: {}", name, e)),
+    }
+}
+
+/// Mark a received message as delivered to this device.
+/// Returns 0 on success (also when already marked), a negative error code on error
+#[no_mangle]
+pub extern "C" fn mark_message_delivered(message_id_hex: *const c_char) -> i32 {
+    mark_message(message_id_hex, receipts::ReceiptStatus::Delivered, "mark_message_delivered")
+}
+
+/// Mark a received message as read. The receipt is only sent while the
+/// privacy.read_receipts setting is on.
+/// Returns 0 on success (also when already marked), a negative error code on error
+#[no_mangle]
+pub extern "C" fn mark_message_read(message_id_hex: *const c_char) -> i32 {
+    mark_message(message_id_hex, receipts::ReceiptStatus::Read, "mark_message_read")
+}
+
+/// Mark a channel read up to a message (null: up to its newest message).
+/// The marker only moves forward.
+/// Returns 1 if it moved, 0 if not, a negative error code on error
+#[no_mangle]
+pub extern "C" fn mark_channel_read(channel_id_hex: *const c_char, message_id_hex: *const c_char) -> i32 {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+    let message_id = if message_id_hex.is_null() {
+        None
+    } else {
+        match parse_hex_32(message_id_hex) {
+            Some(id) => Some(id),
+            None => return invalid_argument("message_id_hex"),
+        }
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match read_state::mark_read(storage, channel_id, message_id, now_ts()) {
+        Ok(moved) => moved as i32,
+        Err(e) => failed(format!("mark_channel_read failed: {}", e)),
+    }
+}
+
+/// Where to open a channel: its first unread message as JSON { channel_id,
+/// message_id, timestamp, offset, unread_count } (offset for the history
+/// calls), "null" if all is read, null on error.
+#[no_mangle]
+pub extern "C" fn get_first_unread(channel_id_hex: *const c_char) -> *mut c_char {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match read_state::first_unread(storage, channel_id).and_then(|u| serde_json::to_string(&u).map_err(|e| e.to_string())) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_first_unread failed: {}", e)),
+    }
+}
+
+/// The first unread message of every channel with unread messages, as a
+/// JSON array (as get_first_unread), null on error.
+#[no_mangle]
+pub extern "C" fn list_first_unread() -> *mut c_char {
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match read_state::first_unread_all(storage).and_then(|u| serde_json::to_string(&u).map_err(|e| e.to_string())) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("list_first_unread failed: {}", e)),
+    }
+}
+
+/// Find a stored message in its channel's history, for jumping to it.
+/// Returns JSON { channel_id, message_id, timestamp, offset, page,
+/// page_offset, page_size } (fetch the page with limit page_size and offset
+/// page_offset), "null" if the message is not stored, null on error.
+#[no_mangle]
+pub extern "C" fn locate_message(message_id_hex: *const c_char, page_size: u32) -> *mut c_char {
+    let Some(message_id) = parse_hex_32(message_id_hex) else {
+        return invalid_argument("message_id_hex");
+    };
+    if page_size == 0 {
+        return invalid_argument("page_size");
+    }
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match read_state::locate(storage, message_id, page_size).and_then(|l| serde_json::to_string(&l).map_err(|e| e.to_string())) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("locate_message failed: {}", e)),
+    }
+}
+
+/// Receipts other readers sent for a message, for checkmarks; updates come
+/// as `message_receipt` events.
+/// Returns JSON { message_id, status: "delivered" | "read" | null,
+/// receipts: [{ user_id, status, updated_at }] }, null on error.
+#[no_mangle]
+pub extern "C" fn get_message_receipts(message_id_hex: *const c_char) -> *mut c_char {
+    let Some(message_id) = parse_hex_32(message_id_hex) else {
+        return invalid_argument("message_id_hex");
+    };
+    let Some(own_user_id) = own_user_id() else {
+        return not_initialized("Identity");
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match receipts::list(storage, own_user_id, message_id) {
+        Ok(rows) => {
+            let json = serde_json::to_string(&ffi_types::MessageReceipts::new(&message_id, &rows)).unwrap_or_default();
+            CString::new(json).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut())
+        }
+        Err(e) => failed(format!("get_message_receipts failed: {}", e)),
+    }
+}
+
+/// Where a message we sent is on its way (see `send_status`); changes come as
+/// `send_state` events.
+/// Returns JSON { message_id, channel_id, state: "composing" | "queued" |
+/// "routed" | "relayed" | "delivered" | "read" | "failed", attempts,
+/// updated_at }, "null" if we did not send it, null on error.
+#[no_mangle]
+pub extern "C" fn get_send_state(message_id_hex: *const c_char) -> *mut c_char {
+    let Some(message_id) = parse_hex_32(message_id_hex) else {
+        return invalid_argument("message_id_hex");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match send_status::get(storage, message_id) {
+        Ok(row) => {
+            let json = serde_json::to_string(&row.as_ref().map(send_status::SendStatus::from)).unwrap_or_default();
+            CString::new(json).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut())
+        }
+        Err(e) => failed(format!("get_send_state failed: {}", e)),
+    }
+}
+
+/// Record that a message exists before it arrives (e.g. a peer's sync digest
+/// lists it): a placeholder at `expected_at` shows in history as kind
+/// "pending" until the ciphertext arrives. Emits `message_pending`, then
+/// `message_arrived` when it is filled.
+/// Returns 1 if a placeholder was stored, 0 if the message is already known,
+/// a negative error code on error
+#[no_mangle]
+pub extern "C" fn expect_message(channel_id_hex: *const c_char, message_id_hex: *const c_char, expected_at: i64) -> i32 {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+    let Some(message_id) = parse_hex_32(message_id_hex) else {
+        return invalid_argument("message_id_hex");
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match message_futures::expect(storage, channel_id, message_id, expected_at) {
+        Ok(true) => 1,
+        Ok(false) => 0,
+        Err(e) => failed(format!("expect_message failed: {}", e)),
+    }
+}
+
+// ========== Pseudonyms ==========
+
+/// Our pseudonym in a public channel (see `pseudonyms`), created on first use.
+/// Returns JSON { channel_id, generation, ed25519_public, user_id, created_at },
+/// or null on error (e.g. for private channels).
+#[no_mangle]
+pub extern "C" fn get_channel_pseudonym(channel_id_hex: *const c_char) -> *mut c_char {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+    with_pseudonym("get_channel_pseudonym", |identity, storage| {
+        pseudonyms::current(identity, storage, channel_id, now_ts())
+    })
+}
+
+/// Retire a channel's pseudonym and switch to a new one, unlinkable to it.
+/// Replies to messages of the retired pseudonym still go out under it.
+/// Returns the new pseudonym as JSON (see get_channel_pseudonym), or null on error.
+#[no_mangle]
+pub extern "C" fn regenerate_pseudonym(channel_id_hex: *const c_char) -> *mut c_char {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+    with_pseudonym("regenerate_pseudonym", |identity, storage| {
+        pseudonyms::regenerate(identity, storage, channel_id, now_ts())
+    })
+}
+
+/// Which of our pseudonyms (current or retired) a key is, e.g. the addressee
+/// of an incoming reply. Returns its JSON (with retired_at if retired), or
+/// null if the key is not one of ours (NotFound) or on error.
+#[no_mangle]
+pub extern "C" fn resolve_pseudonym(ed25519_public_hex: *const c_char) -> *mut c_char {
+    let Some(ed25519_public) = parse_hex_32(ed25519_public_hex) else {
+        return invalid_argument("ed25519_public_hex");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    match pseudonyms::resolve(storage, ed25519_public) {
+        Ok(Some(row)) => match serde_json::to_string(&pseudonyms::Pseudonym::from(&row)) {
+            Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("resolve_pseudonym failed: {}", e)),
+        },
+        Ok(None) => fail(MeshError::NotFound, "Not one of our pseudonyms"),
+        Err(e) => failed(format!("resolve_pseudonym failed: {}", e)),
+    }
+}
+
+/// All our pseudonyms, current and retired, by channel, newest generation first.
+/// Returns a JSON array (see resolve_pseudonym), or null on error.
+#[no_mangle]
+pub extern "C" fn list_pseudonyms() -> *mut c_char {
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    let listed = storage.list_pseudonyms().and_then(|rows| {
+        let list: Vec<pseudonyms::Pseudonym> = rows.iter().map(pseudonyms::Pseudonym::from).collect();
+        serde_json::to_string(&list).map_err(|e| e.to_string())
+    });
+    match listed {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("list_pseudonyms failed: {}", e)),
+    }
+}
+
+/// Sign data for a public channel message under our pseudonym there, for
+/// transports that build their own envelopes. A reply (in_reply_to_hex,
+/// nullable) is signed by the pseudonym that wrote the message it answers;
+/// message_id_hex is recorded as written by the signing pseudonym.
+/// Returns JSON { ed25519_public, signature }, or null on error.
+#[no_mangle]
+pub extern "C" fn sign_as_pseudonym(
+    channel_id_hex: *const c_char,
+    message_id_hex: *const c_char,
+    in_reply_to_hex: *const c_char,
+    data_hex: *const c_char,
+) -> *mut c_char {
+    let Some(channel_id) = parse_hex_32(channel_id_hex) else {
+        return invalid_argument("channel_id_hex");
+    };
+    let Some(message_id) = parse_hex_32(message_id_hex) else {
+        return invalid_argument("message_id_hex");
+    };
+    let in_reply_to = if in_reply_to_hex.is_null() {
+        None
+    } else {
+        match parse_hex_32(in_reply_to_hex) {
+            Some(v) => Some(v),
+            None => return invalid_argument("in_reply_to_hex"),
+        }
+    };
+    let Some(data) = parse_hex_vec(data_hex) else {
+        return invalid_argument("data_hex");
+    };
+
+    let identity_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = identity_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    let signed = pseudonyms::for_reply(identity, storage, channel_id, in_reply_to, now_ts()).and_then(|(row, key)| {
+        pseudonyms::record_sent(storage, message_id, &row)?;
+        Ok(ffi_types::PseudonymSignature {
+            ed25519_public: hex::encode(row.ed25519_public),
+            signature: hex::encode(ed25519_dalek::Signer::sign(&key, &data).to_bytes()),
+        })
+    });
+    match signed {
+        Ok(signature) => CString::new(serde_json::to_string(&signature).unwrap_or_default()).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("sign_as_pseudonym failed: {}", e)),
+    }
+}
+
+/// Run a pseudonym operation with the identity and storage, returning its JSON.
+fn with_pseudonym(
+    api: &str,
+    op: impl FnOnce(&identity::Identity, &storage::Storage) -> Result<storage::PseudonymRow, String>,
+) -> *mut c_char {
+    let identity_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = identity_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+    let result = op(identity, storage)
+        .and_then(|row| serde_json::to_string(&pseudonyms::Pseudonym::from(&row)).map_err(|e| e.to_string()));
+    match result {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("{} failed: {}", api, e)),
+    }
+}
+
+// ========== Peers ==========
+
+/// Cache the capabilities a transport negotiated with a peer (see
+/// `peer_capabilities`). capabilities_json: { protocol_version, ciphers: [..],
+/// max_mtu }. Returns the expiry (UNIX seconds), or a negative error code on error.
+#[no_mangle]
+pub extern "C" fn set_peer_capabilities(peer_id_hex: *const c_char, capabilities_json: *const c_char) -> i64 {
+    let Some(peer_id) = parse_hex_32(peer_id_hex) else {
+        return invalid_argument("peer_id_hex");
+    };
+    let capabilities: peer_capabilities::PeerCapabilities =
+        match parse_c_str(capabilities_json).and_then(|s| serde_json::from_str(s).ok()) {
+            Some(c) => c,
+            None => return invalid_argument("capabilities_json"),
+        };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(ref storage) = *storage_guard else {
+        return not_initialized("Storage");
+    };
+    match peer_capabilities::remember(storage, peer_id, &capabilities, now_ts()) {
+        Ok(expires_at) => expires_at,
+        Err(e) => failed(format!("set_peer_capabilities failed: {}", e)),
+    }
+}
+
+/// Cached capabilities of a peer, so a reconnect can skip negotiation.
+/// Returns JSON { protocol_version, ciphers, max_mtu, negotiated_at, expires_at },
+/// or null if none are cached (or they expired) or on error.
+#[no_mangle]
+pub extern "C" fn get_peer_capabilities(peer_id_hex: *const c_char) -> *mut c_char {
+    let Some(peer_id) = parse_hex_32(peer_id_hex) else {
+        return invalid_argument("peer_id_hex");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(ref storage) = *storage_guard else {
+        return not_initialized("Storage");
+    };
+    match peer_capabilities::lookup(storage, peer_id, now_ts()) {
+        Ok(Some(cached)) => match serde_json::to_string(&cached) {
+            Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("get_peer_capabilities failed: {}", e)),
+        },
+        Ok(None) => fail(MeshError::NotFound, "No capabilities cached for this peer"),
+        Err(e) => failed(format!("get_peer_capabilities failed: {}", e)),
+    }
+}
+
+/// Known peers, most recently seen first, with their cached capabilities.
+/// Returns JSON array [{ peer_id, last_seen, capabilities }], null on error.
+#[no_mangle]
+pub extern "C" fn get_peers() -> *mut c_char {
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(ref storage) = *storage_guard else {
+        return not_initialized("Storage");
+    };
+    let now = now_ts();
+    let peers = storage.list_peers().and_then(|peers| {
+        peers
+            .iter()
+            .map(|peer| Ok(ffi_types::PeerInfo::new(peer, peer_capabilities::lookup(storage, peer.peer_id, now)?)))
+            .collect::<Result<Vec<_>, String>>()
+    });
+    match peers.and_then(|p| serde_json::to_string(&p).map_err(|e| e.to_string())) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_peers failed: {}", e)),
+    }
+}
+
+// ========== Relay Handover ==========
+
+/// A peer not heard from for this long counts as joining again when it is
+const PEER_REJOIN_SECS: i64 = 10 * 60;
+
+/// Record that a transport heard from a peer, optionally on a channel (routing hint).
+/// Emits `peer_joined` { peer_id } for a peer that is new or was away for ten minutes.
+/// `channel_id_hex` may be null. Returns 0 on success, a negative error code on error.
+#[no_mangle]
+pub extern "C" fn report_peer_seen(peer_id_hex: *const c_char, channel_id_hex: *const c_char) -> i32 {
+    let peer_id = match parse_hex_32(peer_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("peer_id_hex"),
+    };
+    let channel_id = if channel_id_hex.is_null() {
+        None
+    } else {
+        match parse_hex_32(channel_id_hex) {
+            Some(v) => Some(v),
+            None => return invalid_argument("channel_id_hex"),
+        }
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+    let now = now_ts();
+    let joined = match storage.get_peer_last_seen(peer_id) {
+        Ok(last_seen) => last_seen.is_none_or(|t| now - t >= PEER_REJOIN_SECS),
+        Err(e) => return failed(format!("report_peer_seen failed: {}", e)),
+    };
+    let result = storage
+        .upsert_peer(peer_id, now)
+        .and_then(|_| match channel_id {
+            Some(c) => storage.upsert_routing_hint(c, peer_id, now),
+            None => Ok(()),
+        });
+    if joined && result.is_ok() {
+        events::emit("peer_joined", serde_json::json!({ "peer_id": hex::encode(peer_id) }));
+    }
+    match result {
+        Ok(()) => 0,
+        Err(e) => failed(format!("report_peer_seen failed: {}", e)),
+    }
+}
+
+/// Export seen-packet state, peers and routing hints as a compact snapshot string
+/// for handing relay duty to another device. Returns null on error.
+#[no_mangle]
+pub extern "C" fn export_relay_snapshot() -> *mut c_char {
+    let r_guard = ROUTER.lock().unwrap();
+    let storage_guard = STORAGE.lock().unwrap();
+    match relay_snapshot::export(r_guard.as_ref(), storage_guard.as_ref()) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("export_relay_snapshot failed: {}", e)),
+    }
+}
+
+/// Merge a snapshot from `export_relay_snapshot` into this node.
+/// Returns JSON {seen, peers, routing_hints} with what was added, or null on error.
+#[no_mangle]
+pub extern "C" fn import_relay_snapshot(snapshot: *const c_char) -> *mut c_char {
+    let snapshot = match parse_c_str(snapshot) {
+        Some(s) => s,
+        None => return invalid_argument("snapshot"),
+    };
+    let r_guard = ROUTER.lock().unwrap();
+    let storage_guard = STORAGE.lock().unwrap();
+    let result = relay_snapshot::import(snapshot, r_guard.as_ref(), storage_guard.as_ref())
+        .and_then(|stats| serde_json::to_string(&stats).map_err(|e| e.to_string()));
+    match result {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("import_relay_snapshot failed: {}", e)),
+    }
+}
+
+// ========== Key Transparency ==========
+
+/// Get our key history (append-only chain of key epochs) for friends to
+/// verify. Created with our current key as epoch 0 on first use.
+/// Returns the chain JSON, null on error.
+#[no_mangle]
+pub extern "C" fn get_own_key_history() -> *mut c_char {
+    let identity_guard = IDENTITY.lock().unwrap();
+    let identity = match identity_guard.as_ref() {
+        Some(id) => id,
+        None => return not_initialized("Identity"),
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    let user_id = identity.public().user_id;
+    let history = storage.get_key_history(user_id).and_then(|stored| match stored {
+        Some(json) => Ok(json),
+        None => {
+            let json = serde_json::to_string(&key_history::KeyHistory::genesis(identity, now_ts()))
+                .map_err(|e| format!("Failed to serialize key history: {}", e))?;
+            storage.set_key_history(user_id, &json, now_ts())?;
+            Ok(json)
+        }
+    });
+    match history {
+        Ok(json) => CString::new(json).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_own_key_history failed: {}", e)),
+    }
+}
+
+/// Verify a friend's key history and remember it. The chain must verify,
+/// contain the key we know the friend by, and only append to the chain
+/// accepted before; otherwise a `key_history_mismatch` event {user_id, error}
+/// is emitted (a forged or rewritten rotation).
+/// Returns JSON { owner_user_id, epochs, current_ed25519_public, rotated },
+/// null if the history is rejected or on error.
+#[no_mangle]
+pub extern "C" fn verify_key_history(friend_user_id_hex: *const c_char, history_json: *const c_char) -> *mut c_char {
+    let friend_user_id = match parse_hex_32(friend_user_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("friend_user_id_hex"),
+    };
+    let history: key_history::KeyHistory = match parse_c_str(history_json).and_then(|s| serde_json::from_str(s).ok()) {
+        Some(h) => h,
+        None => return invalid_argument("history_json"),
+    };
+
+    let friend_key = {
+        let friends_guard = FRIENDS.lock().unwrap();
+        match friends_guard.as_ref().and_then(|fm| fm.get_friend(&friend_user_id)) {
+            Some(f) => f.ed25519_public,
+            None => return fail(MeshError::NotFound, "Not a friend"),
+        }
+    };
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    let checked = history.verify().and_then(|verified| {
+        if !history.contains_key(&friend_key) {
+            return Err("Key history does not contain the friend's key".to_string());
+        }
+        if let Some(known) = storage.get_key_history(friend_user_id)? {
+            let known: key_history::KeyHistory =
+                serde_json::from_str(&known).map_err(|e| format!("Stored key history is corrupt: {}", e))?;
+            history.extends(&known)?;
+        }
+        let json = serde_json::to_string(&history).map_err(|e| format!("Failed to serialize key history: {}", e))?;
+        storage.set_key_history(friend_user_id, &json, now_ts())?;
+        Ok(ffi_types::KeyHistoryCheck {
+            rotated: verified.current_ed25519_public != hex::encode(friend_key),
+            owner_user_id: verified.owner_user_id,
+            epochs: verified.epochs,
+            current_ed25519_public: verified.current_ed25519_public,
+        })
+    });
+
+    match checked {
+        Ok(check) => match serde_json::to_string(&check) {
+            Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("verify_key_history failed: {}", e)),
+        },
+        Err(e) => {
+            events::emit(
+                "key_history_mismatch",
+                serde_json::json!({ "user_id": hex::encode(friend_user_id), "error": e }),
+            );
+            fail(MeshError::Crypto, &e)
+        }
+    }
+}
+
+/// Verify many Ed25519 signatures at once (e.g. signed beacons gossiped by
+/// relay nodes), as one batch with a per-item fallback on failure.
+/// items_json: JSON array [{ public_key, message, signature }] (hex)
+/// Returns JSON array of the indices that fail (empty if all verify), null on error.
+#[no_mangle]
+pub extern "C" fn verify_signatures_batch(items_json: *const c_char) -> *mut c_char {
+    let items: Vec<batch_verify::HexSignedItem> = match parse_c_str(items_json).and_then(|s| serde_json::from_str(s).ok()) {
+        Some(v) => v,
+        None => return invalid_argument("items_json"),
+    };
+    match serde_json::to_string(&batch_verify::verify_hex_batch(&items)) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("verify_signatures_batch failed: {}", e)),
+    }
+}
+
+// ========== Key Escrow ==========
+
+/// Export every key our protected channels have had, sealed under our
+/// identity, for the app to keep with the identity backup. Refresh it on
+/// `key_escrow_changed` events.
+/// Returns the escrow as hex, null on error.
+#[no_mangle]
+pub extern "C" fn export_key_escrow() -> *mut c_char {
+    let identity_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = identity_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    match key_escrow::export(identity, storage) {
+        Ok(sealed) => CString::new(hex::encode(sealed)).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("export_key_escrow failed: {}", e)),
+    }
+}
+
+/// Restore channel keys from an escrow exported by this identity (e.g.
+/// after reinstalling and restoring the identity backup), so old group
+/// history can be decrypted again.
+/// Returns JSON { keys, restored }, null on error (including escrows of other identities).
+#[no_mangle]
+pub extern "C" fn import_key_escrow(escrow_hex: *const c_char) -> *mut c_char {
+    let Some(sealed) = parse_hex_vec(escrow_hex) else {
+        return invalid_argument("escrow_hex");
+    };
+
+    let identity_guard = IDENTITY.lock().unwrap();
+    let Some(identity) = identity_guard.as_ref() else {
+        return not_initialized("Identity");
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
+    };
+
+    match key_escrow::import(identity, storage, &sealed) {
+        Ok(outcome) => match serde_json::to_string(&outcome) {
+            Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("import_key_escrow failed: {}", e)),
+        },
+        Err(e) => failed(format!("import_key_escrow failed: {}", e)),
+    }
+}
+
+// ========== Linked Devices ==========
+
+/// Link this device to a primary identity, allowing it to send this device
+/// a remote wipe command. Returns 0 on success, a negative error code on error.
+#[no_mangle]
+pub extern "C" fn link_primary_device(primary_ed25519_hex: *const c_char) -> i32 {
+    let primary = match parse_hex_32(primary_ed25519_hex) {
+        Some(v) => v,
+        None => return invalid_argument("primary_ed25519_hex"),
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    match storage_guard.as_ref().map(|s| s.link_primary(primary, now_ts())) {
+        Some(Ok(())) => 0,
+        Some(Err(e)) => failed(format!("link_primary_device failed: {}", e)),
+        None => not_initialized("Storage"),
+    }
+}
+
+/// Remove a primary link. Returns 0 on success, a negative error code on error or if not linked.
+#[no_mangle]
+pub extern "C" fn unlink_primary_device(primary_ed25519_hex: *const c_char) -> i32 {
+    let primary = match parse_hex_32(primary_ed25519_hex) {
+        Some(v) => v,
+        None => return invalid_argument("primary_ed25519_hex"),
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    match storage_guard.as_ref().map(|s| s.unlink_primary(primary)) {
+        Some(Ok(true)) => 0,
+        Some(Ok(false)) => fail(MeshError::NotFound, "Not linked to this primary"),
+        Some(Err(e)) => failed(format!("unlink_primary_device failed: {}", e)),
+        None => not_initialized("Storage"),
+    }
+}
+
+/// Send a signed wipe command to a device linked to our identity.
+/// device_user_id_hex: the target device's user_id; ttl: hops to relay;
+/// client_token is optional (see `dedup_send`).
+/// Returns the packet_id hex, null on error.
+#[no_mangle]
+pub extern "C" fn send_wipe_command(device_user_id_hex: *const c_char, ttl: u8, client_token: *const c_char) -> *mut c_char {
+    let ttl_arg = CString::new(ttl.to_string()).unwrap();
+    dedup_send(client_token, "send_wipe_command", &[device_user_id_hex, ttl_arg.as_ptr()], || send_wipe_command_once(device_user_id_hex, ttl))
+}
+
+fn send_wipe_command_once(device_user_id_hex: *const c_char, ttl: u8) -> *mut c_char {
+    let device_user_id = match parse_hex_32(device_user_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("device_user_id_hex"),
+    };
+    let packet = {
+        let identity_guard = IDENTITY.lock().unwrap();
+        let identity = match identity_guard.as_ref() {
+            Some(id) => id,
+            None => return not_initialized("Identity"),
+        };
+        match wipe::build_command(identity, device_user_id, ttl, now_ts()) {
+            Ok(p) => p,
+            Err(e) => return failed(format!("send_wipe_command failed: {}", e)),
+        }
+    };
+    let packet_id = packet.packet_id;
+
+    let own_public = own_ed25519_public();
+    let r_guard = ROUTER.lock().unwrap();
+    let router = match r_guard.as_ref() {
+        Some(r) => r,
+        None => return not_initialized("Router"),
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    route_packet(router, storage_guard.as_ref(), own_public, packet);
+
+    CString::new(hex::encode(packet_id))
+        .ok()
+        .map(|s| s.into_raw())
+        .unwrap_or(std::ptr::null_mut())
+}
+
+/// Start the background worker decrypting DMs queued for the message index
+/// while packets were handled (see `crypto_queue`); returns immediately.
+fn index_pending_messages() {
+    crypto_queue::start(|batch| {
+        let identity_guard = IDENTITY.lock().unwrap();
+        let storage_guard = STORAGE.lock().unwrap();
+        if let (Some(identity), Some(storage)) = (identity_guard.as_ref(), storage_guard.as_ref()) {
+            if let Err(e) = message_index::index_messages(identity, storage, batch, now_ts()) {
+                eprintln!("Message index error: {}", e);
+            }
+        }
+    });
+}
+
+/// Run a wipe requested by a verified remote command, if any.
+/// Must be called with no core lock held.
+fn run_pending_wipe() {
+    if wipe::take_pending() {
+        secure_wipe_all();
+    }
+}
+
+/// Securely erase everything this core stores: every identity on the device
+/// (not only the active one) with its friends, messages, attachments and
+/// settings, and the identities index. In-memory state is dropped first, then
+/// every file under the root data directory is overwritten and removed. Emits
+/// `device_wiped` with {files_wiped, errors}. The data directory lock is
+/// released with it. Attached read-only, nothing is wiped.
+/// Returns 0 on success, a negative error code if some files could not be wiped.
+#[no_mangle]
+pub extern "C" fn secure_wipe_all() -> i32 {
+    if let Err(failure) = claim_data_dir(true) {
+        return failure;
+    }
+    // One lock at a time, so no lock order applies
+    *IDENTITY.lock().unwrap() = None;
+    *FRIENDS.lock().unwrap() = None;
+    *ROUTER.lock().unwrap() = None;
+    *LOOPBACK.lock().unwrap() = None;
+    // Closes the database before its files are wiped
+    *STORAGE.lock().unwrap() = None;
+    passphrase::forget_db_keys();
+    ingest::INGEST.drain();
+    lifecycle::end_suspension();
+
+    // The lock file goes too (and cannot be removed while locked on Windows)
+    instance_lock::release();
+    let summary = match storage::root_dir() {
+        Ok(root) => wipe::wipe_dir(&root),
+        Err(e) => wipe::WipeSummary { files_wiped: 0, errors: vec![e] },
+    };
+    identities::forget_active();
+    let ok = summary.errors.is_empty();
+    events::emit("device_wiped", serde_json::to_value(&summary).unwrap_or_default());
+    if ok {
+        0
+    } else {
+        fail(MeshError::Storage, &summary.errors.join("; "))
+    }
+}
+
+// ========== Link Previews ==========
+
+/// Pull a link preview out of a page the app fetched (the sender makes
+/// previews; receivers may be offline). Returns JSON { url, title,
+/// description, image_url }, image_url being the preview image to fetch,
+/// null on error.
+#[no_mangle]
+pub extern "C" fn extract_link_preview(url: *const c_char, html: *const c_char) -> *mut c_char {
+    let Some(url) = parse_c_str(url) else {
+        return invalid_argument("url");
+    };
+    let Some(html) = parse_c_str(html) else {
+        return invalid_argument("html");
+    };
+    match link_preview::extract(url, html).and_then(|m| serde_json::to_string(&m).map_err(|e| e.to_string())) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("extract_link_preview failed: {}", e)),
+    }
+}
+
+/// The plaintext for a message with link previews, to pass to any send call.
+/// previews_json: JSON array [{ url, title, description, thumbnail_id }]; each
+/// url must appear in the text, and thumbnail_id is the id of the preview
+/// image (SHA-256 of its bytes), stored with store_attachment for the sent
+/// message and announced with send_attachment. Returns the plaintext, null on error.
+#[no_mangle]
+pub extern "C" fn build_link_preview_message(text: *const c_char, previews_json: *const c_char) -> *mut c_char {
+    let Some(text) = parse_c_str(text) else {
+        return invalid_argument("text");
+    };
+    let Some(previews) = parse_c_str(previews_json).and_then(|s| serde_json::from_str::<Vec<link_preview::LinkPreview>>(s).ok()) else {
+        return invalid_argument("previews_json");
+    };
+    match link_preview::wrap(text, previews) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("build_link_preview_message failed: {}", e)),
+    }
 }
 
-/// Register a geohash channel in local storage.
-/// channel_id_hex must be 32 bytes hex; returns 0 on success, -1 on error.
+// ========== Attachments ==========
+
+/// Store a local attachment for a message.
+/// data_hex: attachment bytes; returns attachment_id (hex) on success, null on error.
 #[no_mangle]
-pub extern "C" fn register_geo_channel(channel_id_hex: *const c_char) -> i32 {
+pub extern "C" fn store_attachment(
+    message_id_hex: *const c_char,
+    channel_id_hex: *const c_char,
+    mime: *const c_char,
+    data_hex: *const c_char,
+) -> *mut c_char {
+    if let Err(failure) = claim_data_dir(true) {
+        return failure;
+    }
+    let message_id = match parse_hex_32(message_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("message_id_hex"),
+    };
     let channel_id = match parse_hex_32(channel_id_hex) {
         Some(v) => v,
-        None => return -1,
+        None => return invalid_argument("channel_id_hex"),
+    };
+    let mime = match parse_c_str(mime) {
+        Some(s) => s,
+        None => return invalid_argument("mime"),
+    };
+    let data = match parse_hex_vec(data_hex) {
+        Some(v) => v,
+        None => return invalid_argument("data_hex"),
     };
 
     let storage_guard = STORAGE.lock().unwrap();
     if let Some(ref storage) = *storage_guard {
-        match storage.upsert_channel(channel_id, "geo") {
-            Ok(_) => 0,
-            Err(e) => {
-                eprintln!("register_geo_channel failed: {}", e);
-                -1
-            }
+        match attachments::store_local(storage, message_id, channel_id, mime, &data) {
+            Ok(id) => CString::new(hex::encode(id)).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("store_attachment failed: {}", e)),
         }
     } else {
-        -1
+        not_initialized("Storage")
     }
 }
 
-/// List registered geohash channels.
-/// Returns JSON array [{ channel_id, type }] or null on error.
+/// Attach a thumbnail (encoded by the platform, max 32 KiB) to an attachment.
+/// Returns the thumbnail's attachment_id (hex), null on error.
 #[no_mangle]
-pub extern "C" fn get_geo_channels() -> *mut c_char {
+pub extern "C" fn set_attachment_thumbnail(
+    attachment_id_hex: *const c_char,
+    mime: *const c_char,
+    data_hex: *const c_char,
+) -> *mut c_char {
+    if let Err(failure) = claim_data_dir(true) {
+        return failure;
+    }
+    let attachment_id = match parse_hex_32(attachment_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("attachment_id_hex"),
+    };
+    let mime = match parse_c_str(mime) {
+        Some(s) => s,
+        None => return invalid_argument("mime"),
+    };
+    let data = match parse_hex_vec(data_hex) {
+        Some(v) => v,
+        None => return invalid_argument("data_hex"),
+    };
+
     let storage_guard = STORAGE.lock().unwrap();
     if let Some(ref storage) = *storage_guard {
-        match storage.list_channels_by_type("geo") {
-            Ok(channels) => {
-                let json: Vec<serde_json::Value> = channels
-                    .into_iter()
-                    .map(|c| {
-                        serde_json::json!({
-                            "channel_id": hex::encode(c.channel_id),
-                            "type": c.channel_type,
-                        })
-                    })
-                    .collect();
-                match serde_json::to_string(&json) {
-                    Ok(s) => CString::new(s)
-                        .ok()
-                        .map(|s| s.into_raw())
-                        .unwrap_or(std::ptr::null_mut()),
-                    Err(_) => std::ptr::null_mut(),
-                }
-            }
-            Err(e) => {
-                eprintln!("get_geo_channels failed: {}", e);
-                std::ptr::null_mut()
-            }
+        match attachments::set_thumbnail(storage, attachment_id, mime, &data) {
+            Ok(id) => CString::new(hex::encode(id)).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("set_attachment_thumbnail failed: {}", e)),
         }
     } else {
-        std::ptr::null_mut()
+        not_initialized("Storage")
     }
 }
 
-// ========== Mentions (Phase 8) ==========
-
-/// Extract mentions from message text.
-/// 
-/// friends_json: JSON array of friends, e.g.:
-///   [{ "user_id": "...", "nickname": "Alice" }, ...]
-/// Returns JSON array of mentions:
-///   [{ "user_id": "...", "nickname": "Alice" }, ...]
+/// Make the thumbnail of a stored image attachment in the core (see
+/// `attachments::derive_thumbnail`) instead of passing one to
+/// `set_attachment_thumbnail`.
+/// Returns the thumbnail's attachment_id (hex), null on error.
+#[cfg(feature = "thumbnails")]
 #[no_mangle]
-pub extern "C" fn extract_mentions_from_text(
-    text_ptr: *const c_char,
-    friends_json_ptr: *const c_char,
-) -> *mut c_char {
-    let text = unsafe {
-        if text_ptr.is_null() {
-            return std::ptr::null_mut();
-        }
-        match std::ffi::CStr::from_ptr(text_ptr).to_str() {
-            Ok(s) => s,
-            Err(_) => return std::ptr::null_mut(),
-        }
+pub extern "C" fn derive_attachment_thumbnail(attachment_id_hex: *const c_char) -> *mut c_char {
+    if let Err(failure) = claim_data_dir(true) {
+        return failure;
+    }
+    let Some(attachment_id) = parse_hex_32(attachment_id_hex) else {
+        return invalid_argument("attachment_id_hex");
     };
 
-    let friends_json = unsafe {
-        if friends_json_ptr.is_null() {
-            return std::ptr::null_mut();
-        }
-        match std::ffi::CStr::from_ptr(friends_json_ptr).to_str() {
-            Ok(s) => s,
-            Err(_) => return std::ptr::null_mut(),
-        }
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
     };
+    let result = storage
+        .get_attachment(attachment_id)
+        .and_then(|row| row.ok_or_else(|| MeshError::NotFound.raise("Attachment not found")))
+        .and_then(|row| attachments::derive_thumbnail(storage, attachment_id, &attachments::read_blob(&attachment_id)?, &row.mime));
+    match result {
+        Ok(id) => CString::new(hex::encode(id)).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("derive_attachment_thumbnail failed: {}", e)),
+    }
+}
 
-    let friends: Vec<mentions::FriendInfo> = match serde_json::from_str(friends_json) {
-        Ok(v) => v,
-        Err(_) => return std::ptr::null_mut(),
+/// Announce an attachment on its channel (thumbnail first, full data on request).
+/// On private channels the chunks are encrypted; in a DM, send the message
+/// first, as its session seals the attachment's key. client_token is optional
+/// (see `dedup_send`): a repeat does not announce the attachment again.
+/// Returns 0 on success, a negative error code on error.
+#[no_mangle]
+pub extern "C" fn send_attachment(attachment_id_hex: *const c_char, ttl: u8, client_token: *const c_char) -> i32 {
+    let ttl_arg = CString::new(ttl.to_string()).unwrap();
+    let sent = dedup_send(client_token, "send_attachment", &[attachment_id_hex, ttl_arg.as_ptr()], || send_attachment_once(attachment_id_hex, ttl));
+    if sent.is_null() {
+        return last_error_code();
+    }
+    free_string(sent);
+    0
+}
+
+/// Returns the attachment_id hex, null on error.
+fn send_attachment_once(attachment_id_hex: *const c_char, ttl: u8) -> *mut c_char {
+    let attachment_id = match parse_hex_32(attachment_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("attachment_id_hex"),
     };
 
-    let mentions = mentions::extract_mentions(text, &friends);
-    match serde_json::to_string(&mentions) {
-        Ok(s) => CString::new(s)
-            .ok()
-            .map(|s| s.into_raw())
-            .unwrap_or(std::ptr::null_mut()),
-        Err(_) => std::ptr::null_mut(),
+    let r_guard = ROUTER.lock().unwrap();
+    let router = match r_guard.as_ref() {
+        Some(r) => r,
+        None => return not_initialized("Router"),
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match attachments::announce_packets(storage, attachment_id, ttl) {
+        Ok(packets) => {
+            for packet in packets {
+                // Our own blob: nothing to ingest locally
+                router.route(packet, |_| {});
+            }
+            save_router_state(router, Some(storage));
+            CString::new(hex::encode(attachment_id))
+                .ok()
+                .map(|s| s.into_raw())
+                .unwrap_or(std::ptr::null_mut())
+        }
+        Err(e) => failed(format!("send_attachment failed: {}", e)),
     }
 }
 
-/// Test function to verify FFI connectivity
-/// 
-/// Returns a test string to confirm Rust ↔ Flutter communication works
+/// Ask the mesh for the full data of an announced attachment.
+/// Returns 0 on success, a negative error code on error.
 #[no_mangle]
-pub extern "C" fn test_ffi() -> *mut c_char {
-    let test_message = CString::new("FFI connection successful! Rust ↔ Flutter is working.")
-        .expect("Failed to create CString");
-    test_message.into_raw()
+pub extern "C" fn request_attachment(attachment_id_hex: *const c_char, ttl: u8) -> i32 {
+    let attachment_id = match parse_hex_32(attachment_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("attachment_id_hex"),
+    };
+
+    let r_guard = ROUTER.lock().unwrap();
+    let router = match r_guard.as_ref() {
+        Some(r) => r,
+        None => return not_initialized("Router"),
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match attachments::request_packet(storage, attachment_id, ttl) {
+        Ok(packet) => {
+            router.route(packet, |_| {});
+            save_router_state(router, Some(storage));
+            0
+        }
+        Err(e) => failed(format!("request_attachment failed: {}", e)),
+    }
 }
 
-/// Free a CString allocated by Rust
-/// 
-/// Call this from Dart after reading the string to prevent memory leaks
+/// Re-request every wanted attachment that has not fully arrived, asking only
+/// for missing chunks. Call after a transport reconnects.
+/// Returns the number of requests sent, a negative error code on error.
 #[no_mangle]
-pub extern "C" fn free_string(ptr: *mut c_char) {
-    if !ptr.is_null() {
-        unsafe {
-            let _ = CString::from_raw(ptr);
+pub extern "C" fn resume_attachment_downloads(ttl: u8) -> i32 {
+    let r_guard = ROUTER.lock().unwrap();
+    let router = match r_guard.as_ref() {
+        Some(r) => r,
+        None => return not_initialized("Router"),
+    };
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match attachments::resume_packets(storage, ttl) {
+        Ok(packets) => {
+            let count = packets.len() as i32;
+            for packet in packets {
+                router.route(packet, |_| {});
+            }
+            save_router_state(router, Some(storage));
+            count
         }
+        Err(e) => failed(format!("resume_attachment_downloads failed: {}", e)),
     }
 }
 
-// ========== Transport / Router (Phase 6) ==========
-
-/// Initialize router with loopback transport (for testing / local dev).
-/// Requires storage to be initialized for on_new persistence.
-/// Returns 0 on success, -1 on error.
+/// Get download progress of an attachment.
+/// Returns JSON { attachment_id, received_chunks, chunk_count, received_bytes, size, complete, requested },
+/// null on error.
 #[no_mangle]
-pub extern "C" fn init_router_with_loopback() -> i32 {
-    let loopback = std::sync::Arc::new(transport::LoopbackTransport::new());
-    let router = transport::Router::new(vec![loopback.clone()]);
+pub extern "C" fn get_attachment_progress(attachment_id_hex: *const c_char) -> *mut c_char {
+    let attachment_id = match parse_hex_32(attachment_id_hex) {
+        Some(v) => v,
+        None => return invalid_argument("attachment_id_hex"),
+    };
 
-    {
-        let mut lb_guard = LOOPBACK.lock().unwrap();
-        *lb_guard = Some(loopback);
-    }
-    {
-        let mut r_guard = ROUTER.lock().unwrap();
-        *r_guard = Some(router);
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
+    };
+
+    match attachments::progress(storage, attachment_id) {
+        Ok(progress) => match serde_json::to_string(&progress) {
+            Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("get_attachment_progress failed: {}", e)),
+        },
+        Err(e) => failed(format!("get_attachment_progress failed: {}", e)),
     }
-    0
 }
 
-/// Send a packet (builds packet_id if not provided) via router.
-/// packet_id_hex: optional (null pointer -> auto-generate)
-/// channel_id_hex, payload_hex: required
-/// ttl: hop limit
-/// Returns packet_id_hex on success, null on error.
+/// Get attachment bytes as hex.
+/// Returns null if the attachment is unknown or not yet complete.
 #[no_mangle]
-pub extern "C" fn send_packet(
-    packet_id_hex: *const c_char,
-    channel_id_hex: *const c_char,
-    payload_hex: *const c_char,
-    ttl: u8,
-) -> *mut c_char {
-    let channel_id = match parse_hex_32(channel_id_hex) {
+pub extern "C" fn get_attachment_data(attachment_id_hex: *const c_char) -> *mut c_char {
+    let attachment_id = match parse_hex_32(attachment_id_hex) {
         Some(v) => v,
-        None => return std::ptr::null_mut(),
+        None => return invalid_argument("attachment_id_hex"),
     };
-    let payload = match parse_hex_vec(payload_hex) {
-        Some(v) => v,
-        None => return std::ptr::null_mut(),
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
     };
 
-    let packet_id = if packet_id_hex.is_null() {
-        transport::Router::generate_packet_id()
-    } else {
-        match parse_hex_32(packet_id_hex) {
-            Some(v) => v,
-            None => return std::ptr::null_mut(),
-        }
+    match storage.get_attachment(attachment_id) {
+        Ok(Some(row)) if row.complete => match attachments::read_blob(&attachment_id) {
+            Ok(data) => CString::new(hex::encode(data)).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("get_attachment_data failed: {}", e)),
+        },
+        Ok(_) => fail(MeshError::NotFound, "Unknown or incomplete attachment"),
+        Err(e) => failed(format!("get_attachment_data failed: {}", e)),
+    }
+}
+
+/// Get one chunk of attachment bytes as hex, so large files can be read
+/// piecewise (`chunk_count` from `get_attachment_progress`).
+/// Returns null if the attachment is unknown, incomplete or the index is out of range.
+#[no_mangle]
+pub extern "C" fn get_attachment_chunk(attachment_id_hex: *const c_char, chunk_index: u32) -> *mut c_char {
+    let Some(attachment_id) = parse_hex_32(attachment_id_hex) else {
+        return invalid_argument("attachment_id_hex");
     };
 
-    let packet = transport::Packet {
-        packet_id,
-        channel_id,
-        ttl,
-        payload,
+    let storage_guard = STORAGE.lock().unwrap();
+    let Some(storage) = storage_guard.as_ref() else {
+        return not_initialized("Storage");
     };
 
-    // Route and store on new.
-    {
-        let r_guard = ROUTER.lock().unwrap();
-        if let Some(ref router) = *r_guard {
-            let storage_guard = STORAGE.lock().unwrap();
-            let storage_opt = storage_guard.as_ref().map(|s| s as *const _);
-            router.route(packet.clone(), |p| {
-                // On new: persist message (ciphertext) for offline-first
-                if let Some(storage_ptr) = storage_opt {
-                    // Safety: storage_ptr derived from &storage; read-only here.
-                    let storage: &storage::Storage = unsafe { &*storage_ptr };
-                    let _ = storage.store_message(
-                        p.packet_id,
-                        p.channel_id,
-                        p.payload.clone(),
-                        now_ts(),
-                        p.ttl,
-                    );
-                }
-            });
-        } else {
-            return std::ptr::null_mut();
-        }
+    match storage.get_attachment(attachment_id) {
+        Ok(Some(row)) if row.complete => match attachments::read_chunk(&attachment_id, chunk_index) {
+            Ok(data) => CString::new(hex::encode(data)).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("get_attachment_chunk failed: {}", e)),
+        },
+        Ok(_) => fail(MeshError::NotFound, "Unknown or incomplete attachment"),
+        Err(e) => failed(format!("get_attachment_chunk failed: {}", e)),
     }
-
-    CString::new(hex::encode(packet_id))
-        .ok()
-        .map(|s| s.into_raw())
-        .unwrap_or(std::ptr::null_mut())
 }
 
-/// Inject a received packet (e.g., from BLE) into the router.
-/// packet_id_hex, channel_id_hex, payload_hex required; ttl as received.
+/// Reclaim attachment blobs no message references any more.
+/// Returns JSON { attachments_removed, bytes_reclaimed }, null on error.
 #[no_mangle]
-pub extern "C" fn ingest_packet(
-    packet_id_hex: *const c_char,
-    channel_id_hex: *const c_char,
-    payload_hex: *const c_char,
-    ttl: u8,
-) -> i32 {
-    let packet_id = match parse_hex_32(packet_id_hex) {
-        Some(v) => v,
-        None => return -1,
+pub extern "C" fn gc_attachments() -> *mut c_char {
+    if let Err(failure) = claim_data_dir(true) {
+        return failure;
+    }
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
     };
-    let channel_id = match parse_hex_32(channel_id_hex) {
+
+    match attachments::gc(storage) {
+        Ok(stats) => match serde_json::to_string(&stats) {
+            Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+            Err(e) => failed(format!("gc_attachments failed: {}", e)),
+        },
+        Err(e) => failed(format!("gc_attachments failed: {}", e)),
+    }
+}
+
+/// List a message's attachments with thumbnail availability.
+/// Returns JSON array [{ attachment_id, mime, size, complete, thumbnail_id, thumbnail_complete }],
+/// null on error.
+#[no_mangle]
+pub extern "C" fn get_message_attachments(message_id_hex: *const c_char) -> *mut c_char {
+    let message_id = match parse_hex_32(message_id_hex) {
         Some(v) => v,
-        None => return -1,
+        None => return invalid_argument("message_id_hex"),
     };
-    let payload = match parse_hex_vec(payload_hex) {
-        Some(v) => v,
-        None => return -1,
+
+    let storage_guard = STORAGE.lock().unwrap();
+    let storage = match storage_guard.as_ref() {
+        Some(s) => s,
+        None => return not_initialized("Storage"),
     };
 
-    let packet = transport::Packet {
-        packet_id,
-        channel_id,
-        ttl,
-        payload,
+    let rows = match storage.list_message_attachments(message_id) {
+        Ok(r) => r,
+        Err(e) => return failed(format!("get_message_attachments failed: {}", e)),
     };
 
-    let r_guard = ROUTER.lock().unwrap();
-    if let Some(ref router) = *r_guard {
-        let storage_guard = STORAGE.lock().unwrap();
-        let storage_opt = storage_guard.as_ref().map(|s| s as *const _);
-        router.route(packet, |p| {
-            if let Some(storage_ptr) = storage_opt {
-                let storage: &storage::Storage = unsafe { &*storage_ptr };
-                let _ = storage.store_message(
-                    p.packet_id,
-                    p.channel_id,
-                    p.payload.clone(),
-                    now_ts(),
-                    p.ttl,
-                );
-            }
+    let mut json = Vec::new();
+    for row in rows {
+        let thumb = storage.get_thumbnail(row.attachment_id).ok().flatten();
+        json.push(ffi_types::AttachmentInfo {
+            attachment_id: hex::encode(row.attachment_id),
+            mime: row.mime,
+            size: row.size,
+            complete: row.complete,
+            thumbnail_id: thumb.as_ref().map(|t| hex::encode(t.attachment_id)),
+            thumbnail_complete: thumb.as_ref().map(|t| t.complete).unwrap_or(false),
         });
-        0
-    } else {
-        -1
     }
-}
 
-/// Drain loopback transport packets (testing helper).
-/// Returns JSON array of packets {packet_id, channel_id, ttl, payload} hex-encoded.
-#[no_mangle]
-pub extern "C" fn drain_loopback_packets() -> *mut c_char {
-    let lb_guard = LOOPBACK.lock().unwrap();
-    if let Some(ref lb) = *lb_guard {
-        let packets = lb.drain();
-        let json: Vec<serde_json::Value> = packets
-            .into_iter()
-            .map(|p| {
-                serde_json::json!({
-                    "packet_id": hex::encode(p.packet_id),
-                    "channel_id": hex::encode(p.channel_id),
-                    "ttl": p.ttl,
-                    "payload": hex::encode(p.payload),
-                })
-            })
-            .collect();
-        match serde_json::to_string(&json) {
-            Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
-            Err(_) => std::ptr::null_mut(),
-        }
-    } else {
-        std::ptr::null_mut()
+    match serde_json::to_string(&json) {
+        Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
+        Err(e) => failed(format!("get_message_attachments failed: {}", e)),
     }
 }
 
 // ========== Optimization (Phase 9) ==========
 
 /// Get recommended optimization config as JSON
+///
+/// battery_mode_str: "Performance" | "Balanced" | "PowerSaving", or null to use
+/// the `battery.mode` setting (Balanced if storage is not initialized).
 /// 
 /// Returns JSON: {
 ///   "battery_mode": "Performance" | "Balanced" | "PowerSaving",
@@ -1576,41 +7440,33 @@ pub extern "C" fn drain_loopback_packets() -> *mut c_char {
 /// }
 #[no_mangle]
 pub extern "C" fn get_optimization_config(battery_mode_str: *const c_char) -> *mut c_char {
-    let mode_str = unsafe {
-        if battery_mode_str.is_null() {
-            return std::ptr::null_mut();
+    let battery_mode = if battery_mode_str.is_null() {
+        let storage_guard = STORAGE.lock().unwrap();
+        match storage_guard.as_ref().map(settings::battery_mode) {
+            Some(Ok(mode)) => mode,
+            Some(Err(e)) => return failed(format!("get_optimization_config failed: {}", e)),
+            None => optimization::BatteryMode::Balanced,
         }
-        match std::ffi::CStr::from_ptr(battery_mode_str).to_str() {
-            Ok(s) => s,
-            Err(_) => return std::ptr::null_mut(),
+    } else {
+        match parse_c_str(battery_mode_str) {
+            Some(s) => optimization::BatteryMode::from_name(s).unwrap_or(optimization::BatteryMode::Balanced),
+            None => return invalid_argument("battery_mode_str"),
         }
     };
 
-    let battery_mode = match mode_str.to_lowercase().as_str() {
-        "performance" => optimization::BatteryMode::Performance,
-        "balanced" => optimization::BatteryMode::Balanced,
-        "powersaving" | "power_saving" => optimization::BatteryMode::PowerSaving,
-        _ => optimization::BatteryMode::Balanced,
-    };
-
     let config = optimization::OptimizationConfig::from_battery_mode(battery_mode);
-    let mode_name = match battery_mode {
-        optimization::BatteryMode::Performance => "Performance",
-        optimization::BatteryMode::Balanced => "Balanced",
-        optimization::BatteryMode::PowerSaving => "PowerSaving",
-    };
-
-    let json = serde_json::json!({
-        "battery_mode": mode_name,
-        "scan_interval_ms": config.scan_interval.as_millis(),
-        "scan_window_ms": config.scan_interval.scan_window_ms(),
-        "batch_size": config.batch_size,
-        "batch_age_secs": config.batch_age_secs,
-    });
+
+    let json = ffi_types::OptimizationConfigInfo {
+        battery_mode: battery_mode.name(),
+        scan_interval_ms: config.scan_interval.as_millis(),
+        scan_window_ms: config.scan_interval.scan_window_ms(),
+        batch_size: config.batch_size,
+        batch_age_secs: config.batch_age_secs,
+    };
 
     match serde_json::to_string(&json) {
         Ok(s) => CString::new(s).ok().map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut()),
-        Err(_) => std::ptr::null_mut(),
+        Err(e) => failed(format!("get_optimization_config failed: {}", e)),
     }
 }
 
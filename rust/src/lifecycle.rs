@@ -0,0 +1,109 @@
+//! Mobile lifecycle: suspend and resume
+//!
+//! Android and iOS may kill a backgrounded app without warning, or keep the
+//! process and bring it back later. `suspend` (call when the app goes to the
+//! background) routes what is queued in memory, persists the router state,
+//! checkpoints the WAL, then closes the database and gives up the data
+//! directory lock (see `instance_lock`): a process killed while suspended
+//! leaves nothing half-written, and a background service can take the data
+//! directory in the meantime.
+//!
+//! Identity, friends and router stay loaded. `resume` retakes the lock and
+//! reopens the database with the key it was first opened with, instead of the
+//! app running every init again (which would load a second router and lose
+//! its state). What another process may have changed meanwhile (friends, the
+//! router's seen packets) is reloaded, and an identity no longer the one on
+//! disk fails the resume. Resuming a process that was not suspended only checks that
+//! the database still answers. Packets ingested while suspended are queued
+//! and routed on resume.
+
+use serde::Serialize;
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+/// What `resume` reopens
+#[derive(Clone)]
+pub struct Suspended {
+    /// The database was open (and with this key, if any)
+    pub storage: bool,
+    pub key: Option<Zeroizing<[u8; 32]>>,
+}
+
+/// What is loaded after `resume`, so the app inits only what is missing
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LifecycleReport {
+    /// The database was reopened (the process had been suspended)
+    pub reopened: bool,
+    pub identity: bool,
+    pub friends: bool,
+    pub storage: bool,
+    pub router: bool,
+}
+
+/// Key of the database as last opened
+static OPENED_WITH: Mutex<Option<Zeroizing<[u8; 32]>>> = Mutex::new(None);
+
+static SUSPENDED: Mutex<Option<Suspended>> = Mutex::new(None);
+
+/// Note the key the database was opened with; opening it also ends a
+/// suspension.
+pub fn opened(key: Option<Zeroizing<[u8; 32]>>) {
+    *OPENED_WITH.lock().unwrap() = key;
+    SUSPENDED.lock().unwrap().take();
+}
+
+/// Key of the database as last opened, to open it again.
+pub fn opened_key() -> Option<Zeroizing<[u8; 32]>> {
+    OPENED_WITH.lock().unwrap().clone()
+}
+
+/// Mark the process suspended. False if it already was.
+pub fn suspend(storage: bool) -> bool {
+    let mut suspended = SUSPENDED.lock().unwrap();
+    if suspended.is_some() {
+        return false;
+    }
+    *suspended = Some(Suspended { storage, key: OPENED_WITH.lock().unwrap().clone() });
+    true
+}
+
+/// The suspension to resume from, if any (it lasts until the database is
+/// reopened, see `opened`, or `end_suspension`).
+pub fn suspended() -> Option<Suspended> {
+    SUSPENDED.lock().unwrap().clone()
+}
+
+/// End a suspension without reopening the database (it was not open, or
+/// is gone).
+pub fn end_suspension() {
+    SUSPENDED.lock().unwrap().take();
+}
+
+pub fn is_suspended() -> bool {
+    SUSPENDED.lock().unwrap().is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suspend_keeps_the_database_key() {
+        opened(Some(Zeroizing::new([5u8; 32])));
+        assert!(!is_suspended());
+        assert!(suspend(true));
+        assert!(!suspend(false));
+        let state = suspended().unwrap();
+        assert!(state.storage);
+        assert_eq!(state.key.as_deref(), Some(&[5u8; 32]));
+        assert_eq!(opened_key().as_deref(), Some(&[5u8; 32]));
+
+        // Still suspended until the database is open again
+        assert!(is_suspended());
+        opened(state.key);
+        assert!(!is_suspended());
+        assert!(suspend(false));
+        end_suspension();
+        assert!(suspended().is_none());
+    }
+}
@@ -0,0 +1,264 @@
+//! Link previews
+//!
+//! Receivers are usually offline and could not fetch a link themselves, so
+//! previews are made by the sender at send time: the app fetches the page,
+//! `extract` pulls the title, description and preview image URL out of its
+//! HTML, and the app stores the downscaled image as an attachment. The
+//! message's plaintext then becomes a preview envelope: a file-separator
+//! marker (0x1C) followed by JSON { version, text, previews: [{ url, title,
+//! description, thumbnail_id }] }, thumbnail_id being the attachment id of
+//! the image.
+//!
+//! Previews are checked again on receipt: at most `MAX_PREVIEWS`, http(s)
+//! URLs that appear in the text, bounded fields without control characters.
+//! Previews that fail are dropped; the message itself is kept.
+
+use crate::error::MeshError;
+use serde::{Deserialize, Serialize};
+
+/// Current envelope format version
+pub const PREVIEW_VERSION: u8 = 1;
+
+/// First character of a preview envelope plaintext
+const PREVIEW_MARKER: char = '\u{1c}';
+
+/// Previews per message
+pub const MAX_PREVIEWS: usize = 3;
+
+/// Longest URL (bytes)
+pub const MAX_URL_LEN: usize = 2048;
+
+/// Longest title (bytes)
+pub const MAX_TITLE_LEN: usize = 256;
+
+/// Longest description (bytes)
+pub const MAX_DESCRIPTION_LEN: usize = 1024;
+
+/// A sender-made preview of a link in the message text
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LinkPreview {
+    pub url: String,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Attachment id hex of the preview image
+    #[serde(default)]
+    pub thumbnail_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+struct PreviewEnvelope {
+    version: u8,
+    text: String,
+    previews: Vec<LinkPreview>,
+}
+
+/// What `extract` found in a page
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PageMetadata {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Absolute URL of the page's preview image, for the app to fetch
+    pub image_url: Option<String>,
+}
+
+fn is_http_url(url: &str) -> bool {
+    (url.starts_with("https://") || url.starts_with("http://"))
+        && url.len() <= MAX_URL_LEN
+        && !url.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+fn check_field(name: &str, value: Option<&str>, max_len: usize) -> Result<(), String> {
+    match value {
+        Some(v) if v.len() > max_len => Err(MeshError::InvalidArgument.raise(format!("Preview {} longer than {} bytes", name, max_len))),
+        Some(v) if v.chars().any(|c| c.is_control() && c != '\n') => Err(format!("Preview {} has control characters", name)),
+        _ => Ok(()),
+    }
+}
+
+/// Check a preview against the text it belongs to.
+fn validate(text: &str, preview: &LinkPreview) -> Result<(), String> {
+    if !is_http_url(&preview.url) {
+        return Err(MeshError::InvalidArgument.raise("Preview URL must be an http(s) URL"));
+    }
+    if !text.contains(&preview.url) {
+        return Err(MeshError::Rejected.raise("Preview URL is not in the message text"));
+    }
+    check_field("title", preview.title.as_deref(), MAX_TITLE_LEN)?;
+    check_field("description", preview.description.as_deref(), MAX_DESCRIPTION_LEN)?;
+    if let Some(id) = &preview.thumbnail_id {
+        crate::codec::parse_id_hex(id, "thumbnail id")?;
+    }
+    Ok(())
+}
+
+/// Plaintext for a message with previews (the text alone if there are none).
+pub fn wrap(text: &str, previews: Vec<LinkPreview>) -> Result<String, String> {
+    if previews.is_empty() {
+        return Ok(text.to_string());
+    }
+    if previews.len() > MAX_PREVIEWS {
+        return Err(format!("At most {} previews per message", MAX_PREVIEWS));
+    }
+    for preview in &previews {
+        validate(text, preview)?;
+    }
+    let envelope = PreviewEnvelope { version: PREVIEW_VERSION, text: text.to_string(), previews };
+    let json = serde_json::to_string(&envelope).map_err(|e| format!("Failed to serialize previews: {}", e))?;
+    Ok(format!("{}{}", PREVIEW_MARKER, json))
+}
+
+/// Parse a decrypted plaintext as a preview envelope: the text and its valid
+/// previews (None for ordinary text).
+pub fn parse(plaintext: &str) -> Option<(String, Vec<LinkPreview>)> {
+    let envelope: PreviewEnvelope = serde_json::from_str(plaintext.strip_prefix(PREVIEW_MARKER)?).ok()?;
+    let previews = envelope
+        .previews
+        .into_iter()
+        .take(MAX_PREVIEWS)
+        .filter(|p| validate(&envelope.text, p).is_ok())
+        .collect();
+    Some((envelope.text, previews))
+}
+
+/// Decode the few HTML entities pages put in titles.
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+/// Collapse whitespace and cut to `max_len` bytes on a character boundary.
+fn clean(text: &str, max_len: usize) -> Option<String> {
+    let mut out = decode_entities(text).split_whitespace().collect::<Vec<_>>().join(" ");
+    if out.len() > max_len {
+        let mut end = max_len;
+        while !out.is_char_boundary(end) {
+            end -= 1;
+        }
+        out.truncate(end);
+    }
+    (!out.is_empty()).then_some(out)
+}
+
+/// The value of an attribute in a tag's text (`name="value"` or `name='value'`).
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let lower = tag.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(found) = lower[from..].find(name) {
+        let start = from + found;
+        from = start + name.len();
+        let preceded = start == 0 || lower.as_bytes()[start - 1].is_ascii_whitespace();
+        let rest = lower[from..].trim_start();
+        if !preceded || !rest.starts_with('=') {
+            continue;
+        }
+        let value_start = tag.len() - rest[1..].trim_start().len();
+        let quote = tag[value_start..].chars().next()?;
+        if quote != '"' && quote != '\'' {
+            return tag[value_start..].split(|c: char| c.is_whitespace() || c == '>' || c == '/').next();
+        }
+        let body = &tag[value_start + 1..];
+        return body.find(quote).map(|end| &body[..end]);
+    }
+    None
+}
+
+/// Resolve an image URL from a page against the page's URL.
+fn resolve(page_url: &str, src: &str) -> Option<String> {
+    let src = src.trim();
+    if is_http_url(src) {
+        return Some(src.to_string());
+    }
+    let scheme_end = page_url.find("://")? + 3;
+    let url = if let Some(rest) = src.strip_prefix("//") {
+        format!("{}{}", &page_url[..scheme_end], rest)
+    } else if src.starts_with('/') {
+        let host_end = page_url[scheme_end..].find('/').map_or(page_url.len(), |i| scheme_end + i);
+        format!("{}{}", &page_url[..host_end], src)
+    } else {
+        return None;
+    };
+    is_http_url(&url).then_some(url)
+}
+
+/// Pull a preview's title, description and image URL out of a page's HTML
+/// (Open Graph tags first, then `<title>` and the description meta tag).
+pub fn extract(page_url: &str, html: &str) -> Result<PageMetadata, String> {
+    if !is_http_url(page_url) {
+        return Err("Not an http(s) URL".to_string());
+    }
+    let lower = html.to_ascii_lowercase();
+    let mut meta = std::collections::HashMap::new();
+    let mut from = 0;
+    while let Some(found) = lower[from..].find("<meta") {
+        let start = from + found;
+        let end = lower[start..].find('>').map_or(html.len(), |i| start + i);
+        let tag = &html[start..end];
+        from = end;
+        let key = attribute(tag, "property").or_else(|| attribute(tag, "name"));
+        if let (Some(key), Some(content)) = (key, attribute(tag, "content")) {
+            meta.entry(key.to_ascii_lowercase()).or_insert(content);
+        }
+    }
+    let title_tag = lower.find("<title").and_then(|start| {
+        let open_end = start + lower[start..].find('>')? + 1;
+        let close = open_end + lower[open_end..].find("</title")?;
+        Some(&html[open_end..close])
+    });
+
+    Ok(PageMetadata {
+        url: page_url.to_string(),
+        title: meta.get("og:title").copied().or(title_tag).and_then(|t| clean(t, MAX_TITLE_LEN)),
+        description: meta
+            .get("og:description")
+            .or_else(|| meta.get("description"))
+            .and_then(|d| clean(d, MAX_DESCRIPTION_LEN)),
+        image_url: meta.get("og:image").and_then(|src| resolve(page_url, &decode_entities(src))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_previews_are_extracted_sent_and_checked() {
+        let html = r#"<html><head><TITLE>Trail  map &amp; notes</TITLE>
+            <meta name="description" content="Fallback">
+            <meta property='og:description' content='Where the path forks'>
+            <meta property="og:image" content="/img/map.png"></head></html>"#;
+        let page = extract("https://example.org/trail/1", html).unwrap();
+        assert_eq!(page.title.as_deref(), Some("Trail map & notes"));
+        assert_eq!(page.description.as_deref(), Some("Where the path forks"));
+        assert_eq!(page.image_url.as_deref(), Some("https://example.org/img/map.png"));
+
+        let text = "see https://example.org/trail/1";
+        let preview = LinkPreview {
+            url: page.url.clone(),
+            title: page.title.clone(),
+            description: page.description.clone(),
+            thumbnail_id: Some("ab".repeat(32)),
+        };
+        let plaintext = wrap(text, vec![preview.clone()]).unwrap();
+        assert_eq!(parse(&plaintext), Some((text.to_string(), vec![preview.clone()])));
+        assert_eq!(wrap(text, Vec::new()).unwrap(), text);
+        assert!(parse(text).is_none());
+
+        // A preview for a link the text does not hold is refused when sending, dropped on receipt
+        let stray = LinkPreview { url: "https://evil.example/".to_string(), ..preview };
+        assert!(wrap(text, vec![stray.clone()]).is_err());
+        let forged = format!(
+            "{}{}",
+            PREVIEW_MARKER,
+            serde_json::to_string(&PreviewEnvelope { version: PREVIEW_VERSION, text: text.to_string(), previews: vec![stray] }).unwrap()
+        );
+        assert_eq!(parse(&forged), Some((text.to_string(), Vec::new())));
+    }
+}
@@ -0,0 +1,291 @@
+//! Memory budget
+//!
+//! Background relaying must never grow the app's memory without bound. The
+//! in-memory holders on the relay path charge their bytes against one
+//! global budget (the `memory.budget_bytes` setting):
+//! - the ingest queue (packets waiting for the core)
+//! - packet batchers (packets waiting for a transport)
+//! - the router's dedup cache (packet ids already seen)
+//!
+//! Attachment chunks are reassembled in the database, not in memory, so
+//! they do not count here.
+//!
+//! When the total goes over budget, a queue holding more than its share (what
+//! the dedup cache may not use, split evenly between the queues) sheds queued
+//! packets lowest priority first (`shed_rank`), oldest first within a
+//! priority, down to its share: one queue never empties itself to make room
+//! for another. The dedup
+//! cache may use at most `DEDUP_SHARE_PERCENT` of the budget and forgets its
+//! oldest ids beyond that, so it can never crowd out queued messages. A
+//! lowered budget takes effect as items are next added.
+
+use crate::priority::{self, Priority};
+use crate::sos;
+use crate::transport::{Packet, PacketKind};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Budget used until the setting is loaded
+pub const DEFAULT_BUDGET_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Smallest budget the setting accepts
+pub const MIN_BUDGET_BYTES: u64 = 256 * 1024;
+
+/// Share of the budget (percent) the dedup cache may use
+pub const DEDUP_SHARE_PERCENT: u64 = 25;
+
+/// Approximate bytes per dedup cache entry (id in the set and in the age queue)
+pub const DEDUP_ENTRY_BYTES: usize = 96;
+
+pub static BUDGET: Lazy<MemoryBudget> = Lazy::new(|| MemoryBudget::new(DEFAULT_BUDGET_BYTES));
+
+/// Holders charged against the budget
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pool {
+    IngestQueue = 0,
+    Batcher = 1,
+    DedupCache = 2,
+}
+
+const POOLS: [Pool; 3] = [Pool::IngestQueue, Pool::Batcher, Pool::DedupCache];
+
+/// Pools that queue packets and share what the dedup cache leaves
+const QUEUE_POOLS: u64 = 2;
+
+impl Pool {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pool::IngestQueue => "ingest_queue",
+            Pool::Batcher => "batcher",
+            Pool::DedupCache => "dedup_cache",
+        }
+    }
+}
+
+/// Usage of one pool
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PoolUsage {
+    pub pool: &'static str,
+    pub bytes: u64,
+    /// Items shed from this pool since startup
+    pub shed: u64,
+}
+
+/// Budget usage as reported to the platform layer
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MemoryMetrics {
+    pub limit_bytes: u64,
+    pub used_bytes: u64,
+    pub over_budget: bool,
+    pub pools: Vec<PoolUsage>,
+}
+
+pub struct MemoryBudget {
+    limit: AtomicU64,
+    used: [AtomicU64; 3],
+    shed: [AtomicU64; 3],
+}
+
+/// Bytes of a packet held in memory.
+pub fn packet_cost(packet: &Packet) -> usize {
+    std::mem::size_of::<Packet>() + packet.payload.len()
+}
+
+/// Shedding order: lower ranks are shed first. Bulk transfers can be
+/// re-requested and announcements are repeated, so they go before messages
+/// and device commands.
+pub fn shed_rank(kind: PacketKind) -> u8 {
+    match kind {
+        PacketKind::AttachmentChunk => 0,
+        PacketKind::HistoryResponse | PacketKind::ChannelAnnouncement => 1,
+        PacketKind::AttachmentManifest
+        | PacketKind::AttachmentRequest
+        | PacketKind::HistoryRequest
+        | PacketKind::DeliveryReceipt
+        | PacketKind::KeyShareRequest
+        | PacketKind::KeyShare
+        | PacketKind::MessageReceipt
+        | PacketKind::SyncInventory => 2,
+        PacketKind::Message => 3,
+        PacketKind::DeviceControl => 4,
+    }
+}
+
+/// Shed rank of a queued packet; SOS messages are shed last, then urgent
+/// ones; background ones go first (see `priority`).
+pub fn packet_rank(packet: &Packet) -> u8 {
+    if sos::is_sos(packet) {
+        return sos::SOS_SHED_RANK;
+    }
+    match packet.priority {
+        Priority::Urgent => priority::URGENT_SHED_RANK,
+        Priority::Background => priority::BACKGROUND_SHED_RANK,
+        Priority::Normal => shed_rank(packet.kind),
+    }
+}
+
+/// Index of the packet to shed first: lowest rank, then oldest.
+pub fn lowest_ranked(packets: &VecDeque<Packet>) -> Option<usize> {
+    packets.iter().enumerate().min_by_key(|(i, p)| (packet_rank(p), *i)).map(|(i, _)| i)
+}
+
+/// What `shed_queue` removed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Shed {
+    pub count: usize,
+    /// The most recently queued packet was among them
+    pub newest: bool,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit: AtomicU64::new(limit),
+            used: Default::default(),
+            shed: Default::default(),
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    pub fn set_limit(&self, limit: u64) {
+        self.limit.store(limit.max(MIN_BUDGET_BYTES), Ordering::Relaxed);
+    }
+
+    pub fn charge(&self, pool: Pool, bytes: usize) {
+        self.used[pool as usize].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn release(&self, pool: Pool, bytes: usize) {
+        // Saturate rather than wrap if accounting ever drifts
+        let _ = self.used[pool as usize].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+            Some(used.saturating_sub(bytes as u64))
+        });
+    }
+
+    /// Release the bytes of an item dropped to stay within budget.
+    pub fn release_shed(&self, pool: Pool, bytes: usize) {
+        self.release(pool, bytes);
+        self.shed[pool as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn pool_bytes(&self, pool: Pool) -> u64 {
+        self.used[pool as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn used(&self) -> u64 {
+        POOLS.iter().map(|p| self.pool_bytes(*p)).sum()
+    }
+
+    pub fn over(&self) -> bool {
+        self.used() > self.limit()
+    }
+
+    /// Bytes the dedup cache may hold.
+    pub fn dedup_limit(&self) -> u64 {
+        self.limit() / 100 * DEDUP_SHARE_PERCENT
+    }
+
+    /// Bytes `pool` may hold when the budget is full.
+    pub fn share(&self, pool: Pool) -> u64 {
+        match pool {
+            Pool::DedupCache => self.dedup_limit(),
+            Pool::IngestQueue | Pool::Batcher => (self.limit() - self.dedup_limit()) / QUEUE_POOLS,
+        }
+    }
+
+    /// While over budget, remove the lowest-ranked (then oldest) packets
+    /// of a queue charged to `pool` until it is within its share.
+    pub fn shed_queue(&self, pool: Pool, packets: &mut VecDeque<Packet>) -> Shed {
+        let mut shed = Shed::default();
+        while self.over() && self.pool_bytes(pool) > self.share(pool) {
+            let Some(index) = lowest_ranked(packets) else {
+                break;
+            };
+            shed.newest |= index + 1 == packets.len();
+            if let Some(packet) = packets.remove(index) {
+                self.release_shed(pool, packet_cost(&packet));
+                shed.count += 1;
+            }
+        }
+        shed
+    }
+
+    pub fn metrics(&self) -> MemoryMetrics {
+        MemoryMetrics {
+            limit_bytes: self.limit(),
+            used_bytes: self.used(),
+            over_budget: self.over(),
+            pools: POOLS
+                .iter()
+                .map(|p| PoolUsage {
+                    pool: p.name(),
+                    bytes: self.pool_bytes(*p),
+                    shed: self.shed[*p as usize].load(Ordering::Relaxed),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(kind: PacketKind, len: usize) -> Packet {
+        Packet {
+            packet_id: [0u8; 32],
+            channel_id: [0u8; 32],
+            kind,
+            ttl: 1,
+            payload: vec![0u8; len],
+            priority: Priority::Normal,
+        }
+    }
+
+    #[test]
+    fn test_sheds_lowest_priority_first() {
+        let budget = MemoryBudget::new(MIN_BUDGET_BYTES);
+        let mut queue = VecDeque::new();
+        for p in [
+            packet(PacketKind::Message, 100 * 1024),
+            packet(PacketKind::AttachmentChunk, 100 * 1024),
+            packet(PacketKind::Message, 100 * 1024),
+        ] {
+            budget.charge(Pool::IngestQueue, packet_cost(&p));
+            queue.push_back(p);
+        }
+        assert!(budget.over());
+
+        let shed = budget.shed_queue(Pool::IngestQueue, &mut queue);
+        assert_eq!(shed, Shed { count: 1, newest: false });
+        assert!(queue.iter().all(|p| p.kind == PacketKind::Message));
+        assert!(!budget.over());
+        assert_eq!(budget.metrics().pools[0].shed, 1);
+    }
+
+    #[test]
+    fn test_queue_sheds_only_its_own_excess() {
+        let budget = MemoryBudget::new(MIN_BUDGET_BYTES);
+        let (mut ingest, mut batch) = (VecDeque::new(), VecDeque::new());
+        for (pool, queue, count) in [(Pool::IngestQueue, &mut ingest, 2), (Pool::Batcher, &mut batch, 10)] {
+            for _ in 0..count {
+                let p = packet(PacketKind::Message, 30 * 1024);
+                budget.charge(pool, packet_cost(&p));
+                queue.push_back(p);
+            }
+        }
+        assert!(budget.over());
+        assert!(budget.pool_bytes(Pool::IngestQueue) < budget.share(Pool::IngestQueue));
+
+        // The ingest queue is within its share: the batcher is the one to shed
+        assert_eq!(budget.shed_queue(Pool::IngestQueue, &mut ingest).count, 0);
+        assert_eq!(ingest.len(), 2);
+        assert_eq!(budget.shed_queue(Pool::Batcher, &mut batch).count, 4);
+        assert!(!budget.over());
+    }
+}
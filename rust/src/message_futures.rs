@@ -0,0 +1,98 @@
+//! Message futures
+//!
+//! A message can be known to exist before it reaches us: another member of
+//! a small group confirms custody of it (see `group_delivery`), or the app
+//! learns its id from a peer's sync digest (`expect_message`). We then store
+//! a placeholder row at its expected position (kind `MESSAGE_KIND_PENDING`,
+//! no ciphertext) and emit `message_pending`, so the conversation shows
+//! "message pending from mesh" there instead of a silent gap.
+//!
+//! When the ciphertext arrives, `store` fills the placeholder in place (it
+//! keeps the expected timestamp) and emits `message_arrived`. Placeholders
+//! are never routed, served as history or decrypted, and retention deletes
+//! them like any other message.
+
+use crate::error::MeshError;
+use crate::events;
+use crate::storage::{Storage, MESSAGE_KIND_PENDING};
+use serde_json::json;
+
+/// Store a placeholder for a message expected in a registered channel and
+/// emit `message_pending`. Returns false if the message (or a placeholder
+/// for it) is already stored.
+pub fn expect(storage: &Storage, channel_id: [u8; 32], message_id: [u8; 32], expected_at: i64) -> Result<bool, String> {
+    if storage.get_channel_type(channel_id)?.is_none() {
+        return Err(MeshError::NotFound.raise("Unknown channel"));
+    }
+    if !storage.store_message_placeholder(message_id, channel_id, expected_at)? {
+        return Ok(false);
+    }
+    events::emit(
+        "message_pending",
+        json!({
+            "message_id": hex::encode(message_id),
+            "channel_id": hex::encode(channel_id),
+            "expected_at": expected_at,
+        }),
+    );
+    Ok(true)
+}
+
+/// Store an arrived message, filling its placeholder if there is one in the
+/// same channel (then `message_arrived` is emitted). Emits `message_received`
+/// for every message not stored before.
+pub fn store(storage: &Storage, message_id: [u8; 32], channel_id: [u8; 32], ciphertext: Vec<u8>, timestamp: i64, ttl: u8) -> Result<(), String> {
+    let existing = storage.get_message(message_id)?;
+    let pending = existing
+        .as_ref()
+        .is_some_and(|row| row.kind == MESSAGE_KIND_PENDING && row.channel_id == channel_id);
+    storage.store_message(message_id, channel_id, ciphertext, timestamp, ttl)?;
+    if existing.is_none() || pending {
+        events::emit(
+            "message_received",
+            json!({ "message_id": hex::encode(message_id), "channel_id": hex::encode(channel_id), "timestamp": timestamp }),
+        );
+    }
+    if pending {
+        events::emit(
+            "message_arrived",
+            json!({ "message_id": hex::encode(message_id), "channel_id": hex::encode(channel_id) }),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MESSAGE_KIND_USER;
+
+    #[test]
+    fn test_placeholder_is_filled_in_place() {
+        let storage = Storage::in_memory().unwrap();
+        let (channel, other) = ([4u8; 32], [5u8; 32]);
+        assert!(expect(&storage, channel, [1u8; 32], 100).is_err());
+        storage.upsert_channel(channel, "group").unwrap();
+        storage.upsert_channel(other, "group").unwrap();
+
+        assert!(expect(&storage, channel, [1u8; 32], 100).unwrap());
+        assert!(!expect(&storage, channel, [1u8; 32], 150).unwrap());
+        let cursor = storage.fetch_messages_since_seq(channel, 0, 10, 0).unwrap()[0].0;
+        // The same id on another channel does not fill it
+        store(&storage, [1u8; 32], other, vec![9], 200, 3).unwrap();
+        assert_eq!(storage.get_message([1u8; 32]).unwrap().unwrap().kind, MESSAGE_KIND_PENDING);
+
+        store(&storage, [1u8; 32], channel, vec![7, 7], 200, 3).unwrap();
+        let row = storage.get_message([1u8; 32]).unwrap().unwrap();
+        assert_eq!((row.kind, row.ciphertext, row.timestamp, row.ttl), (MESSAGE_KIND_USER, vec![7, 7], 100, 3));
+        // Filling moves it past an incremental reader's cursor
+        let changed = storage.fetch_messages_since_seq(channel, cursor, 10, 0).unwrap();
+        assert_eq!(changed.iter().map(|(_, m)| m.message_id).collect::<Vec<_>>(), vec![[1u8; 32]]);
+        let cursor = changed[0].0;
+        // A stored message is not replaced by a later placeholder or copy
+        assert!(!expect(&storage, channel, [1u8; 32], 300).unwrap());
+        store(&storage, [1u8; 32], channel, vec![8], 300, 3).unwrap();
+        assert_eq!(storage.get_message([1u8; 32]).unwrap().unwrap().ciphertext, vec![7, 7]);
+        assert!(storage.fetch_messages_since_seq(channel, cursor, 10, 0).unwrap().is_empty());
+    }
+}
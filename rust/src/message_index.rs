@@ -0,0 +1,145 @@
+//! Decrypt-on-ingest message index
+//!
+//! With `messages.decrypt_on_ingest` on and the database encrypted at rest
+//! (`Storage::init_with_key`), DM messages are decrypted once and their
+//! plaintext kept in `message_plaintexts`: incoming ones right after they
+//! arrive, our own when we send them, and older ones the first time their
+//! history is read. Reading a conversation (and searching it) then reads rows
+//! instead of running a decryption, and possibly a handshake, per message.
+//! A message whose session cannot be opened yet (the friend's key is not
+//! registered) stays ciphertext until a later read succeeds.
+//!
+//! Packet handling runs without IDENTITY, so arriving messages (including
+//! those a history sync pulls in) are only queued here; a background worker
+//! decrypts them in batches (`index_messages`, see `crypto_queue`). Turning
+//! the setting off drops every kept plaintext.
+//!
+//! Each kept plaintext's displayed text also goes into the `message_search`
+//! full-text index (see `search`), which is therefore only built where
+//! plaintexts may be kept. `backfill_search` indexes plaintexts kept before
+//! the index existed.
+
+use crate::crypto_queue;
+use crate::dm_crypto::DmSessionManager;
+use crate::identity::Identity;
+use crate::search;
+use crate::settings::{self, MESSAGES_DECRYPT_ON_INGEST};
+use crate::storage::{MessageSearchRow, OutgoingMessage, Storage};
+
+/// Whether plaintexts are kept: the setting is on and the database is encrypted.
+pub fn enabled(storage: &Storage) -> Result<bool, String> {
+    Ok(storage.is_encrypted() && settings::get_bool(storage, MESSAGES_DECRYPT_ON_INGEST)?)
+}
+
+/// Queue a stored incoming message for decryption if it is a DM.
+pub fn queue_incoming(storage: &Storage, channel_id: [u8; 32], message_id: [u8; 32]) -> Result<(), String> {
+    if !enabled(storage)? {
+        return Ok(());
+    }
+    let is_dm = storage.get_channel_type(channel_id)?.as_deref() == Some("dm") || storage.get_dm_peer_key(channel_id)?.is_some();
+    if is_dm {
+        crypto_queue::push(message_id);
+    }
+    Ok(())
+}
+
+/// Keep a decrypted message's plaintext and index its text for search.
+pub fn keep(storage: &Storage, message_id: [u8; 32], channel_id: [u8; 32], outgoing: bool, plaintext: &str, now: i64) -> Result<(), String> {
+    storage.store_message_plaintext(message_id, channel_id, outgoing, plaintext, now)?;
+    match search::indexable_text(plaintext) {
+        Some(text) => storage.index_message_text(message_id, channel_id, &text),
+        None => Ok(()),
+    }
+}
+
+/// Index plaintexts kept before the search index existed. Returns how many were indexed.
+pub fn backfill_search(storage: &Storage) -> Result<usize, String> {
+    let mut indexed = 0;
+    for (message_id, channel_id, plaintext) in storage.list_unindexed_plaintexts(u32::MAX)? {
+        // Group invites have no text; index them empty so they are not listed again
+        let text = search::indexable_text(&plaintext).unwrap_or_default();
+        storage.index_message_text(message_id, channel_id, &text)?;
+        indexed += 1;
+    }
+    Ok(indexed)
+}
+
+/// Kept messages matching what the user typed, best match first.
+pub fn search(storage: &Storage, query: &str, limit: u32) -> Result<Vec<MessageSearchRow>, String> {
+    match search::fts_query(query) {
+        Some(fts_query) => storage.search_message_text(&fts_query, limit),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Decrypt and keep a batch of queued messages. Returns how many were
+/// indexed; messages that do not open yet are left to the read path.
+pub fn index_messages(identity: &Identity, storage: &Storage, message_ids: &[[u8; 32]], now: i64) -> Result<usize, String> {
+    if !enabled(storage)? {
+        return Ok(0);
+    }
+    let sessions = DmSessionManager::new(storage, identity.x25519_secret());
+    let mut indexed = 0;
+    for &message_id in message_ids {
+        let Some(row) = storage.get_message(message_id)? else {
+            continue;
+        };
+        if storage.get_message_plaintext(message_id)?.is_some() {
+            continue;
+        }
+        let remote_static = storage.get_dm_peer_key(row.channel_id)?;
+        let Ok(opened) = sessions.decrypt(row.channel_id, remote_static, message_id, &row.ciphertext, now) else {
+            continue;
+        };
+        if let Ok(plaintext) = String::from_utf8(opened.plaintext) {
+            keep(storage, message_id, row.channel_id, opened.outgoing, &plaintext, now)?;
+            indexed += 1;
+        }
+    }
+    Ok(indexed)
+}
+
+/// Keep the plaintext of messages we just sent.
+pub fn record_sent(storage: &Storage, messages: &[OutgoingMessage], plaintext: &str, now: i64) -> Result<(), String> {
+    if !enabled(storage)? {
+        return Ok(());
+    }
+    for m in messages {
+        keep(storage, m.message_id, m.channel_id, true, plaintext, now)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plaintexts_follow_their_messages() {
+        let storage = Storage::in_memory().unwrap();
+        let channel = [3u8; 32];
+        storage.upsert_channel(channel, "dm").unwrap();
+        storage.store_message([1u8; 32], channel, vec![1], 100, 3).unwrap();
+        storage.store_message_plaintext([1u8; 32], channel, false, "hello", 100).unwrap();
+
+        // Nothing is kept in an unencrypted database, whatever the setting says
+        settings::set_value(&storage, MESSAGES_DECRYPT_ON_INGEST, serde_json::json!(true)).unwrap();
+        assert!(!enabled(&storage).unwrap());
+
+        let row = storage.get_message_plaintext([1u8; 32]).unwrap().unwrap();
+        assert_eq!((row.outgoing, row.plaintext.as_str()), (false, "hello"));
+
+        // Kept plaintexts are searchable, earlier ones once backfilled
+        assert!(search(&storage, "hel", 10).unwrap().is_empty());
+        assert_eq!(backfill_search(&storage).unwrap(), 1);
+        storage.store_message([2u8; 32], channel, vec![2], 101, 3).unwrap();
+        keep(&storage, [2u8; 32], channel, true, "Héllo again, trail crew", 101).unwrap();
+        let hits = search(&storage, "hello", 10).unwrap();
+        assert_eq!(hits.len(), 2);
+        assert_eq!(search(&storage, "trail cr", 10).unwrap()[0].message_id, [2u8; 32]);
+
+        storage.delete_channel_messages(channel).unwrap();
+        assert_eq!(storage.get_message_plaintext([1u8; 32]).unwrap(), None);
+        assert!(search(&storage, "hello", 10).unwrap().is_empty());
+    }
+}
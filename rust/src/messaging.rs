@@ -0,0 +1,218 @@
+//! One send path for every kind of conversation
+//!
+//! `send_message` takes a channel reference and works out what it is: our
+//! notes, a DM with a friend, one of our groups or a registered geo channel.
+//! It then applies that channel's crypto (notes key, DM ratchet, group key or
+//! geo pseudonym signature) and stores the message where the channel keeps
+//! its own. The packet to route is handed back; notes never leave the device.
+//!
+//! A channel reference is `user:<user_id hex>` (a friend's DM, or our notes
+//! for our own user id) or a channel id hex, optionally as `channel:<hex>`.
+
+use crate::codec;
+use crate::error::MeshError;
+use crate::ffi_types::SendResult;
+use crate::geo_messages::{self, GEO_CHANNEL_TYPE};
+use crate::groups;
+use crate::identity::Identity;
+use crate::message_index;
+use crate::notes;
+use crate::priority::Priority;
+use crate::pseudonyms::Pseudonym;
+use crate::storage::{OutgoingMessage, Storage};
+use crate::transport::{Packet, PacketKind};
+use serde::Deserialize;
+
+/// Where a message goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Note,
+    Dm { user_id: [u8; 32], ed25519_public: [u8; 32] },
+    Group([u8; 32]),
+    Geo([u8; 32]),
+}
+
+/// Options of `send_message`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SendOptions {
+    /// Message answered (geo channels only, see `geo_messages`)
+    pub in_reply_to: Option<[u8; 32]>,
+    pub priority: Option<Priority>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SendOptionsJson {
+    #[serde(default)]
+    in_reply_to: Option<String>,
+    #[serde(default)]
+    priority: Option<String>,
+}
+
+impl SendOptions {
+    /// Parse JSON { in_reply_to?: hex, priority?: "background" | "normal" | "urgent" }.
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let raw: SendOptionsJson = serde_json::from_str(json).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid options: {}", e)))?;
+        Ok(SendOptions {
+            in_reply_to: raw.in_reply_to.as_deref().map(|r| codec::parse_id_hex(r, "in_reply_to")).transpose()?,
+            priority: raw.priority.as_deref().map(Priority::parse).transpose()?,
+        })
+    }
+}
+
+/// A sent message and the packet to route, if any
+#[derive(Debug)]
+pub struct Sent {
+    pub result: SendResult,
+    pub packet: Option<Packet>,
+    /// Stored with our other outgoing messages (tracked by `send_status`)
+    pub stored: bool,
+}
+
+/// Resolve a channel reference. `friends` are (user_id, ed25519_public).
+pub fn resolve(identity: &Identity, friends: &[([u8; 32], [u8; 32])], storage: &Storage, channel_ref: &str) -> Result<Target, String> {
+    if let Some(user_hex) = channel_ref.strip_prefix("user:") {
+        let user_id = codec::parse_id_hex(user_hex, "user id")?;
+        if user_id == identity.public().user_id {
+            return Ok(Target::Note);
+        }
+        return friends
+            .iter()
+            .find(|(id, _)| *id == user_id)
+            .map(|&(user_id, ed25519_public)| Target::Dm { user_id, ed25519_public })
+            .ok_or_else(|| MeshError::NotFound.raise("Not a friend"));
+    }
+    let channel_id = codec::parse_id_hex(channel_ref.strip_prefix("channel:").unwrap_or(channel_ref), "channel id")?;
+    if channel_id == notes::notes_channel_id(identity) {
+        return Ok(Target::Note);
+    }
+    if let Some((user_id, ed25519_public)) = crate::dm_friend_for_channel(identity, friends, channel_id) {
+        return Ok(Target::Dm { user_id, ed25519_public });
+    }
+    match storage.get_channel_type(channel_id)?.as_deref() {
+        Some("group") => Ok(Target::Group(channel_id)),
+        Some(GEO_CHANNEL_TYPE) => Ok(Target::Geo(channel_id)),
+        Some(other) => Err(MeshError::Rejected.raise(format!("Cannot send to a {} channel", other))),
+        None => Err(MeshError::NotFound.raise("Unknown channel")),
+    }
+}
+
+/// Encrypt (or sign) and store a message for `target`.
+pub fn send(identity: &Identity, storage: &Storage, target: Target, plaintext: &str, options: &SendOptions, now: i64) -> Result<Sent, String> {
+    let (priority, in_reply_to) = (options.priority.unwrap_or_default(), options.in_reply_to);
+    if in_reply_to.is_some() && !matches!(target, Target::Geo(_)) {
+        return Err(MeshError::Rejected.raise("in_reply_to is only supported on geo channels"));
+    }
+
+    let outgoing = match target {
+        Target::Note => {
+            notes::ensure_channel(storage, identity)?;
+            crate::encrypt_outgoing_dm(identity, storage, identity.public().user_id, None, plaintext, now)?
+        }
+        Target::Dm { user_id, ed25519_public } => crate::encrypt_outgoing_dm(identity, storage, user_id, Some(ed25519_public), plaintext, now)?,
+        Target::Group(channel_id) => groups::seal_message(identity, storage, channel_id, plaintext, now)?,
+        Target::Geo(channel_id) => return send_geo(identity, storage, channel_id, plaintext, in_reply_to, priority, now),
+    };
+    let outgoing = [OutgoingMessage { priority: priority as u8, ..outgoing }];
+    storage.store_outgoing_batch(&outgoing)?;
+    let [outgoing] = outgoing;
+    if let Target::Group(channel_id) = target {
+        crate::group_delivery::track_sent(storage, channel_id, outgoing.message_id, now)?;
+    } else if let Err(e) = message_index::record_sent(storage, std::slice::from_ref(&outgoing), plaintext, now) {
+        eprintln!("Message index error: {}", e);
+    }
+
+    let routed = target != Target::Note;
+    Ok(Sent {
+        result: SendResult {
+            message_id: hex::encode(outgoing.message_id),
+            channel_id: hex::encode(outgoing.channel_id),
+            channel_type: outgoing.channel_type.to_string(),
+            pseudonym: None,
+            routed,
+        },
+        packet: routed.then_some(Packet {
+            packet_id: outgoing.message_id,
+            channel_id: outgoing.channel_id,
+            kind: PacketKind::Message,
+            ttl: outgoing.ttl,
+            payload: outgoing.ciphertext,
+            priority,
+        }),
+        stored: true,
+    })
+}
+
+/// Geo messages are signed and routed; they are stored when routed, like
+/// anyone else's.
+fn send_geo(
+    identity: &Identity,
+    storage: &Storage,
+    channel_id: [u8; 32],
+    text: &str,
+    in_reply_to: Option<[u8; 32]>,
+    priority: Priority,
+    now: i64,
+) -> Result<Sent, String> {
+    let packet_id = crate::transport::Router::generate_packet_id();
+    let (packet, pseudonym) = geo_messages::seal(identity, storage, channel_id, packet_id, text, in_reply_to, now)?;
+    Ok(Sent {
+        result: SendResult {
+            message_id: hex::encode(packet_id),
+            channel_id: hex::encode(channel_id),
+            channel_type: GEO_CHANNEL_TYPE.to_string(),
+            pseudonym: Some(Pseudonym::from(&pseudonym)),
+            routed: true,
+        },
+        packet: Some(Packet { priority, ..packet }),
+        stored: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_message_resolves_channel_types() {
+        let storage = Storage::in_memory().unwrap();
+        let identity = Identity::generate();
+        let friend = Identity::generate();
+        let friends = [(friend.public().user_id, *friend.public().ed25519_public.as_bytes())];
+        let geo = crate::geo::derive_geo_channel_id("u4pru", "general");
+        storage.upsert_channel(geo, GEO_CHANNEL_TYPE).unwrap();
+        storage.upsert_channel([7u8; 32], "broadcast").unwrap();
+
+        let own = format!("user:{}", hex::encode(identity.public().user_id));
+        assert_eq!(resolve(&identity, &friends, &storage, &own).unwrap(), Target::Note);
+        assert_eq!(resolve(&identity, &friends, &storage, &hex::encode(notes::notes_channel_id(&identity))).unwrap(), Target::Note);
+        let dm = Target::Dm { user_id: friends[0].0, ed25519_public: friends[0].1 };
+        assert_eq!(resolve(&identity, &friends, &storage, &format!("user:{}", hex::encode(friends[0].0))).unwrap(), dm);
+        let dm_channel = crate::dm_crypto::derive_dm_channel_id(identity.public().ed25519_public.as_bytes(), &friends[0].1);
+        assert_eq!(resolve(&identity, &friends, &storage, &format!("channel:{}", hex::encode(dm_channel))).unwrap(), dm);
+        assert_eq!(resolve(&identity, &friends, &storage, &hex::encode(geo)).unwrap(), Target::Geo(geo));
+        assert!(resolve(&identity, &friends, &storage, &format!("user:{}", hex::encode([3u8; 32]))).is_err());
+        assert!(resolve(&identity, &friends, &storage, &hex::encode([7u8; 32])).is_err());
+        assert!(resolve(&identity, &friends, &storage, &hex::encode([8u8; 32])).is_err());
+
+        // Notes are stored, never routed
+        let note = send(&identity, &storage, Target::Note, "buy milk", &SendOptions::default(), 100).unwrap();
+        assert!(note.packet.is_none() && note.stored && !note.result.routed);
+        assert_eq!(note.result.channel_type, notes::NOTES_CHANNEL_TYPE);
+        let message_id = codec::parse_id_hex(&note.result.message_id, "message id").unwrap();
+        assert!(storage.get_message(message_id).unwrap().is_some());
+
+        // Geo messages are signed and routed with the priority asked for
+        let options = SendOptions::from_json(r#"{"priority": "urgent"}"#).unwrap();
+        let sent = send(&identity, &storage, Target::Geo(geo), "Road closed", &options, 100).unwrap();
+        let packet = sent.packet.unwrap();
+        assert_eq!(packet.priority, Priority::Urgent);
+        assert_eq!(geo_messages::attribution(geo, &packet.payload).unwrap().0, sent.result.pseudonym.unwrap().user_id);
+
+        let reply = SendOptions::from_json(&format!(r#"{{"in_reply_to": "{}"}}"#, hex::encode(packet.packet_id))).unwrap();
+        assert_eq!(reply.in_reply_to, Some(packet.packet_id));
+        assert!(send(&identity, &storage, Target::Note, "x", &reply, 100).is_err());
+        assert!(SendOptions::from_json(r#"{"priority": "loud"}"#).is_err());
+        assert!(SendOptions::from_json(r#"{"ttl": 3}"#).is_err());
+    }
+}
@@ -0,0 +1,259 @@
+//! Prometheus metrics for relay nodes (feature `metrics`)
+//!
+//! `render` writes router, transport, queue and storage state in the
+//! Prometheus text format, and `serve` answers GET /metrics with it on a
+//! local address, so relay operators can scrape a node and graph mesh health
+//! over time. Families are prefixed `meshapp_`; counters (`_total`) count
+//! since the process started.
+//!
+//! Like diagnostics bundles, metrics carry no ids, keys or message content:
+//! labels are transport, pool, table and rule names only.
+
+use crate::outbox;
+use crate::relay_budget;
+use crate::storage::Storage;
+use crate::transport::Router;
+use crate::{ingest, memory_budget, relay_policy, webhooks};
+use std::fmt::{Display, Write};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// Peers heard within this many seconds count as active
+pub const ACTIVE_PEER_SECS: i64 = 10 * 60;
+
+/// Longest a scraper may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest request head read
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+/// Text exposition being written
+#[derive(Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP meshapp_{} {}\n# TYPE meshapp_{} {}", name, help, name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let _ = write!(self.0, "meshapp_{}", name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+                .collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {}", value);
+    }
+
+    /// A family with a single unlabelled sample.
+    fn single(&mut self, name: &str, kind: &str, help: &str, value: impl Display) {
+        self.family(name, kind, help);
+        self.sample(name, &[], value);
+    }
+}
+
+/// Current metrics in the Prometheus text format. The router and storage
+/// families are left out when not initialized.
+pub fn render(router: Option<&Router>, storage: Option<&Storage>, now: i64) -> Result<String, String> {
+    let mut out = Exposition::default();
+    out.family("build_info", "gauge", "Core version");
+    out.sample("build_info", &[("version", env!("CARGO_PKG_VERSION"))], 1);
+
+    if let Some(router) = router {
+        let stats = router.stats();
+        out.family("transport_available", "gauge", "Whether a transport can send (1) or not (0)");
+        for transport in &stats.transports {
+            out.sample("transport_available", &[("transport", transport.name)], u8::from(transport.available));
+        }
+        out.single("router_seen_ids", "gauge", "Packet ids in the dedup cache", stats.seen_ids);
+        out.single("router_held_packets", "gauge", "Packets no transport took, not queued in the outbox yet", stats.held_packets);
+        out.single("router_unsaved_ids", "gauge", "Routed packet ids not persisted yet", stats.unsaved_ids);
+        out.single("router_observed_channels", "gauge", "Channels in observer mode", stats.observed_channels);
+        out.single("router_blocked_channels", "gauge", "Channels of blocked users", stats.blocked_channels);
+    }
+
+    let ingest = ingest::INGEST.metrics();
+    out.single("ingest_queue_depth", "gauge", "Packets waiting in the ingest queue", ingest.depth);
+    out.single("ingest_queue_capacity", "gauge", "Ingest queue capacity", ingest.capacity);
+    out.single("ingest_queue_high_water", "gauge", "Deepest the ingest queue has been", ingest.high_water);
+    out.single("ingest_accepted_total", "counter", "Packets routed on arrival", ingest.accepted);
+    out.single("ingest_queued_total", "counter", "Packets queued because the core was busy", ingest.queued);
+    out.single("ingest_dropped_total", "counter", "Packets dropped from a full ingest queue", ingest.dropped);
+
+    let memory = memory_budget::BUDGET.metrics();
+    out.single("memory_limit_bytes", "gauge", "Memory budget of the relay path", memory.limit_bytes);
+    out.single("memory_used_bytes", "gauge", "Memory held by the relay path", memory.used_bytes);
+    out.family("memory_pool_bytes", "gauge", "Memory held per pool");
+    for pool in &memory.pools {
+        out.sample("memory_pool_bytes", &[("pool", pool.pool)], pool.bytes);
+    }
+    out.family("memory_pool_shed_total", "counter", "Items shed per pool to stay within budget");
+    for pool in &memory.pools {
+        out.sample("memory_pool_shed_total", &[("pool", pool.pool)], pool.shed);
+    }
+
+    let rules = relay_policy::stats();
+    out.family("relay_rule_packets_total", "counter", "Packets matched per relay channel rule");
+    for (i, rule) in rules.rules.iter().enumerate() {
+        out.sample("relay_rule_packets_total", &[("rule", &i.to_string())], rule.packets);
+    }
+    out.sample("relay_rule_packets_total", &[("rule", "default_relayed")], rules.default_relayed.packets);
+    out.sample("relay_rule_packets_total", &[("rule", "default_denied")], rules.default_denied.packets);
+    out.family("relay_rule_bytes_total", "counter", "Bytes matched per relay channel rule");
+    for (i, rule) in rules.rules.iter().enumerate() {
+        out.sample("relay_rule_bytes_total", &[("rule", &i.to_string())], rule.bytes);
+    }
+    out.sample("relay_rule_bytes_total", &[("rule", "default_relayed")], rules.default_relayed.bytes);
+    out.sample("relay_rule_bytes_total", &[("rule", "default_denied")], rules.default_denied.bytes);
+
+    let hooks = webhooks::stats();
+    out.single("webhook_delivered_total", "counter", "Webhook posts answered with a 2xx status", hooks.delivered);
+    out.single("webhook_failed_total", "counter", "Webhook posts that failed", hooks.failed);
+    out.single("webhook_dropped_total", "counter", "Webhook events dropped from a full queue", hooks.dropped);
+
+    if let Some(storage) = storage {
+        let budget = relay_budget::status(storage, now)?;
+        out.single("relay_budget_bytes", "gauge", "Daily relay budget (0: no cap)", budget.budget_bytes);
+        out.single("relay_budget_used_bytes", "gauge", "Bytes relayed for other nodes today", budget.used_bytes);
+        out.single("relay_budget_relayed_packets", "gauge", "Packets relayed for other nodes today", budget.relayed_packets);
+        out.single("relay_budget_throttled_packets", "gauge", "Packets held back by the budget today", budget.throttled_packets);
+        out.single("relay_budget_exhausted", "gauge", "Whether relaying is held back for the rest of the day", u8::from(budget.exhausted));
+
+        let outbox = outbox::status(storage)?;
+        out.single("outbox_queued", "gauge", "Packets waiting in the outbox for a transport", outbox.queued);
+        out.single(
+            "outbox_oldest_age_seconds",
+            "gauge",
+            "Age of the oldest packet in the outbox",
+            outbox.oldest_queued_at.map_or(0, |at| (now - at).max(0)),
+        );
+
+        let peers = storage.list_peers()?;
+        out.single("peers_known", "gauge", "Peers ever reported by transports", peers.len());
+        out.single(
+            "peers_active",
+            "gauge",
+            "Peers heard in the last ten minutes",
+            peers.iter().filter(|p| now - p.last_seen < ACTIVE_PEER_SECS).count(),
+        );
+
+        out.family("storage_rows", "gauge", "Rows per database table");
+        for table in storage.table_summaries()? {
+            out.sample("storage_rows", &[("table", &table.name)], table.rows);
+        }
+    }
+    Ok(out.0)
+}
+
+/// Answer GET /metrics on `addr` (e.g. "127.0.0.1:9464") with what `source`
+/// renders, until the returned task is aborted. Must be called within a
+/// Tokio runtime.
+pub async fn serve<F>(addr: &str, source: F) -> Result<JoinHandle<()>, String>
+where
+    F: Fn() -> Result<String, String> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr).await.map_err(|e| format!("Failed to bind metrics endpoint {}: {}", addr, e))?;
+    let source = Arc::new(source);
+    Ok(tokio::spawn(async move {
+        loop {
+            let socket = match listener.accept().await {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    eprintln!("Metrics endpoint accept failed: {}", e);
+                    continue;
+                }
+            };
+            let source = Arc::clone(&source);
+            tokio::spawn(async move {
+                if let Err(e) = respond(socket, move || source()).await {
+                    eprintln!("Metrics request failed: {}", e);
+                }
+            });
+        }
+    }))
+}
+
+/// Read one request and answer it. `source` runs on the blocking pool, as it
+/// takes the core's locks and reads SQLite.
+async fn respond(mut socket: TcpStream, source: impl FnOnce() -> Result<String, String> + Send + 'static) -> Result<(), String> {
+    let head = tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut socket))
+        .await
+        .map_err(|_| "Request timed out".to_string())??;
+    let target: Vec<&str> = head.split_whitespace().take(2).collect();
+    let (status, body) = match target[..] {
+        ["GET", "/metrics"] => match tokio::task::spawn_blocking(source).await.map_err(|e| format!("Metrics task failed: {}", e)) {
+            Ok(Ok(text)) => ("200 OK", text),
+            Ok(Err(e)) | Err(e) => {
+                eprintln!("Failed to render metrics: {}", e);
+                ("500 Internal Server Error", "Failed to render metrics\n".to_string())
+            }
+        },
+        ["GET", _] => ("404 Not Found", "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await.map_err(|e| format!("Failed to write response: {}", e))?;
+    socket.shutdown().await.map_err(|e| format!("Failed to close connection: {}", e))
+}
+
+async fn read_head(socket: &mut TcpStream) -> Result<String, String> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() >= MAX_REQUEST_BYTES {
+            return Err("Request too large".to_string());
+        }
+        let n = socket.read(&mut buf).await.map_err(|e| format!("Failed to read request: {}", e))?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_are_rendered_and_served() {
+        let storage = Storage::in_memory().unwrap();
+        storage.upsert_peer([1u8; 32], 1_000).unwrap();
+        storage.upsert_peer([2u8; 32], 10).unwrap();
+        let router = Router::new(Vec::new());
+        let text = render(Some(&router), Some(&storage), 1_100).unwrap();
+        assert!(text.contains("# TYPE meshapp_ingest_accepted_total counter\n"));
+        assert!(text.contains("meshapp_peers_known 2\n") && text.contains("meshapp_peers_active 1\n"));
+        assert!(text.contains("meshapp_storage_rows{table=\"peers\"} 2\n"));
+        assert!(!render(None, None, 1_100).unwrap().contains("meshapp_router_seen_ids"));
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let probe = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = probe.local_addr().unwrap().to_string();
+            drop(probe);
+            let server = serve(&addr, || Ok("meshapp_up 1\n".to_string())).await.unwrap();
+
+            for (path, expected) in [("/metrics", "HTTP/1.1 200 OK"), ("/", "HTTP/1.1 404 Not Found")] {
+                let mut socket = TcpStream::connect(&addr).await.unwrap();
+                socket.write_all(format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", path).as_bytes()).await.unwrap();
+                let mut response = String::new();
+                socket.read_to_string(&mut response).await.unwrap();
+                assert!(response.starts_with(expected));
+                assert_eq!(response.ends_with("meshapp_up 1\n"), path == "/metrics");
+            }
+            server.abort();
+        });
+    }
+}
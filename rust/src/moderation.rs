@@ -0,0 +1,235 @@
+//! Group moderation
+//!
+//! A group's admins (see `groups`) can remove a member, mute or unmute one,
+//! or delete a message. An action travels as a group message sealed under
+//! the group key, whose plaintext is a moderation marker (0x1F) followed by
+//! JSON { action, target, issued_at } with the admin's Ed25519 key and their
+//! signature over it. Every member checks the signature and the admin roster
+//! it holds, then applies the action locally:
+//!
+//! - "remove_member": the target leaves our member list (the admin also
+//!   rotates the key, so they cannot read on)
+//! - "mute_member" / "unmute_member": a muted member's messages are hidden
+//! - "delete_message": the target message is deleted here
+//!
+//! Admins cannot be removed or muted. Each applied action is recorded in the
+//! group's history as a `moderation` system event, timestamped when it was
+//! issued; that record is the audit trail, and also keeps an action seen
+//! twice (say, again through history) from being applied twice.
+
+use crate::codec;
+use crate::error::MeshError;
+use crate::events;
+use crate::groups;
+use crate::identity::Identity;
+use crate::storage::{OutgoingMessage, Storage};
+use crate::system_messages::{self, SystemEvent};
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+/// First character of a moderation action plaintext
+const MODERATION_MARKER: char = '\u{1f}';
+
+/// What an admin does
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    RemoveMember,
+    MuteMember,
+    UnmuteMember,
+    DeleteMessage,
+}
+
+impl Action {
+    /// Parse an action name ("remove_member", "mute_member", "unmute_member", "delete_message")
+    pub fn from_name(name: &str) -> Option<Self> {
+        serde_json::from_value(json!(name)).ok()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::RemoveMember => "remove_member",
+            Action::MuteMember => "mute_member",
+            Action::UnmuteMember => "unmute_member",
+            Action::DeleteMessage => "delete_message",
+        }
+    }
+}
+
+/// A signed moderation action
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ModerationAction {
+    pub action: Action,
+    /// user_id hex of the member, or message_id hex for "delete_message"
+    pub target: String,
+    pub issued_at: i64,
+    pub admin_ed25519_public: String,
+    pub signature: String,
+}
+
+impl ModerationAction {
+    fn encode(&self) -> Result<String, String> {
+        let json = serde_json::to_string(self).map_err(|e| format!("Failed to serialize moderation action: {}", e))?;
+        Ok(format!("{}{}", MODERATION_MARKER, json))
+    }
+}
+
+/// Parse an opened group message as a moderation action (None for ordinary messages).
+pub fn parse(plaintext: &str) -> Option<ModerationAction> {
+    serde_json::from_str(plaintext.strip_prefix(MODERATION_MARKER)?).ok()
+}
+
+/// Bytes covered by the admin's signature (domain-separated).
+fn signing_bytes(channel_id: &[u8; 32], action: Action, target: &[u8; 32], issued_at: i64) -> Vec<u8> {
+    let mut out = b"meshapp-moderation".to_vec();
+    out.extend_from_slice(channel_id);
+    out.push(action.name().len() as u8);
+    out.extend_from_slice(action.name().as_bytes());
+    out.extend_from_slice(target);
+    out.extend_from_slice(&issued_at.to_be_bytes());
+    out
+}
+
+/// Check an action's signature and that its admin is on our roster. Returns the admin's user id.
+fn verify(storage: &Storage, channel_id: [u8; 32], action: &ModerationAction) -> Result<[u8; 32], String> {
+    let admin = codec::parse_id_hex(&action.admin_ed25519_public, "admin key")?;
+    let target = codec::parse_id_hex(&action.target, "target")?;
+    let signature: [u8; 64] = codec::parse_hex_array(&action.signature, "signature")?;
+    let verifying_key = VerifyingKey::from_bytes(&admin).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid admin key: {}", e)))?;
+    verifying_key
+        .verify(&signing_bytes(&channel_id, action.action, &target, action.issued_at), &Signature::from_bytes(&signature))
+        .map_err(|_| MeshError::Crypto.raise("Invalid moderation signature"))?;
+
+    let admin_user_id: [u8; 32] = Sha256::digest(admin).into();
+    if !storage.list_group_admins(channel_id)?.contains(&admin_user_id) {
+        return Err(MeshError::Rejected.raise("Moderation action is not from a group admin"));
+    }
+    Ok(admin_user_id)
+}
+
+/// Apply a verified action once, recording it in the group's history.
+/// Returns false if it was applied already.
+fn apply(storage: &Storage, channel_id: [u8; 32], action: &ModerationAction, admin_user_id: [u8; 32], now: i64) -> Result<bool, String> {
+    let event = SystemEvent::Moderation {
+        user_id: hex::encode(admin_user_id),
+        action: action.action.name().to_string(),
+        target: action.target.clone(),
+    };
+    if system_messages::is_recorded(storage, channel_id, &event, action.issued_at)? {
+        return Ok(false);
+    }
+    let target = codec::parse_id_hex(&action.target, "target")?;
+    let admins = storage.list_group_admins(channel_id)?;
+    if action.action != Action::DeleteMessage && admins.contains(&target) {
+        return Err(MeshError::Rejected.raise("Admins cannot be moderated"));
+    }
+
+    storage.with_transaction(|storage| {
+        match action.action {
+            Action::RemoveMember => {
+                let mut members = storage.list_group_members(channel_id)?;
+                if members.contains(&target) {
+                    members.retain(|m| *m != target);
+                    storage.set_group_members(channel_id, &members, now)?;
+                    groups::emit_members_changed(channel_id, &members);
+                }
+            }
+            Action::MuteMember => storage.set_group_member_muted(channel_id, target, true, now)?,
+            Action::UnmuteMember => storage.set_group_member_muted(channel_id, target, false, now)?,
+            Action::DeleteMessage => {
+                if storage.get_message(target)?.is_some_and(|row| row.channel_id == channel_id) {
+                    storage.delete_message(target)?;
+                }
+            }
+        }
+        system_messages::record(storage, channel_id, &event, action.issued_at)
+    })?;
+    events::emit(
+        "group_moderation",
+        json!({
+            "channel_id": hex::encode(channel_id),
+            "admin_user_id": hex::encode(admin_user_id),
+            "action": action.action.name(),
+            "target": action.target,
+        }),
+    );
+    Ok(true)
+}
+
+/// Take a moderation action in a group we are an admin of. The returned
+/// message (sealed under the current key, before any removal rotates it)
+/// carries it to the members; it is applied here too.
+pub fn issue(
+    identity: &Identity,
+    storage: &Storage,
+    channel_id: [u8; 32],
+    action: Action,
+    target: [u8; 32],
+    now: i64,
+) -> Result<OutgoingMessage, String> {
+    let own_user_id = identity.public().user_id;
+    if !storage.list_group_admins(channel_id)?.contains(&own_user_id) {
+        return Err(MeshError::Rejected.raise("Not an admin of this group"));
+    }
+    let signature = identity.ed25519_signing_key().sign(&signing_bytes(&channel_id, action, &target, now));
+    let signed = ModerationAction {
+        action,
+        target: hex::encode(target),
+        issued_at: now,
+        admin_ed25519_public: hex::encode(identity.public().ed25519_public.to_bytes()),
+        signature: hex::encode(signature.to_bytes()),
+    };
+    let outgoing = groups::seal_body(identity, storage, channel_id, signed.encode()?.as_bytes(), now)?;
+    apply(storage, channel_id, &signed, own_user_id, now)?;
+    Ok(outgoing)
+}
+
+/// Apply the moderation action a group message carries, if it is one.
+pub fn on_message(storage: &Storage, channel_id: [u8; 32], message_id: [u8; 32], ciphertext: &[u8], now: i64) -> Result<(), String> {
+    if storage.get_channel_type(channel_id)?.as_deref() != Some("group") {
+        return Ok(());
+    }
+    let Ok(opened) = groups::open_message(storage, channel_id, &message_id, ciphertext) else {
+        return Ok(());
+    };
+    let Some(action) = std::str::from_utf8(&opened).ok().and_then(parse) else {
+        return Ok(());
+    };
+    let admin_user_id = verify(storage, channel_id, &action)?;
+    apply(storage, channel_id, &action, admin_user_id, now)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MESSAGE_KIND_SYSTEM;
+
+    #[test]
+    fn test_members_apply_admin_actions_once() {
+        let (admin_storage, alice_storage) = (Storage::in_memory().unwrap(), Storage::in_memory().unwrap());
+        let (admin, alice, bob) = (Identity::generate(), Identity::generate(), Identity::generate());
+
+        let channel = groups::create(&admin, &admin_storage, Some("Hikers"), 100).unwrap();
+        let invite = groups::add_member(&admin, &admin_storage, channel, alice.public().user_id, 100).unwrap();
+        groups::add_member(&admin, &admin_storage, channel, bob.public().user_id, 100).unwrap();
+        groups::accept_invite(&alice, &alice_storage, &invite, admin.public().ed25519_public.to_bytes(), 110).unwrap();
+        assert_eq!(alice_storage.list_group_admins(channel).unwrap(), vec![admin.public().user_id]);
+        // Only admins can act
+        assert!(issue(&alice, &alice_storage, channel, Action::MuteMember, bob.public().user_id, 115).is_err());
+
+        let mute = issue(&admin, &admin_storage, channel, Action::MuteMember, bob.public().user_id, 120).unwrap();
+        on_message(&alice_storage, channel, mute.message_id, &mute.ciphertext, 125).unwrap();
+        on_message(&alice_storage, channel, mute.message_id, &mute.ciphertext, 126).unwrap();
+        assert_eq!(alice_storage.list_group_mutes(channel).unwrap(), vec![bob.public().user_id]);
+        let audit = alice_storage.fetch_messages(channel, 10, 0).unwrap();
+        assert_eq!(audit.iter().filter(|m| m.kind == MESSAGE_KIND_SYSTEM).count(), 2); // joined + muted
+
+        let remove = issue(&admin, &admin_storage, channel, Action::RemoveMember, bob.public().user_id, 130).unwrap();
+        on_message(&alice_storage, channel, remove.message_id, &remove.ciphertext, 135).unwrap();
+        assert!(!alice_storage.list_group_members(channel).unwrap().contains(&bob.public().user_id));
+        assert!(issue(&admin, &admin_storage, channel, Action::RemoveMember, admin.public().user_id, 140).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+//! Node roles
+//!
+//! A role is a preset for the kind of device a node runs on. Selecting one
+//! (`select`, or setting `node.role`) sets the relay policy, battery mode,
+//! storage quota and retention settings it bundles in one step:
+//!
+//! - "phone": balanced battery, relays up to TTL 8, no quota, default retention
+//! - "tablet-hub": performance, relays up to TTL 12, 512 MiB quota, public
+//!   channels kept two weeks
+//! - "solar-relay": power saving, relays up to TTL 8, 64 MiB quota, everything
+//!   kept three days and public channels less
+//! - "internet-gateway": performance, relays up to TTL 16, 1 GiB quota,
+//!   everything kept a week
+//!
+//! Every preset clears `relay.channel_rules`; gateway operators add their own
+//! afterwards. The settings stay individually adjustable, and `node.role`
+//! only records the preset last applied.
+
+use crate::optimization::BatteryMode;
+use crate::retention;
+use crate::settings::{
+    self, BATTERY_MODE, NODE_ROLE, RELAY_CHANNEL_RULES, RELAY_ENABLED, RELAY_MAX_TTL, RETENTION_BY_CHANNEL_TYPE,
+    RETENTION_MAX_AGE_DAYS, RETENTION_MAX_MESSAGES, STORAGE_MAX_MESSAGE_BYTES,
+};
+use crate::storage::Storage;
+use serde_json::{json, Value};
+
+const MIB: u64 = 1024 * 1024;
+
+/// A node role preset
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeRole {
+    Phone,
+    TabletHub,
+    SolarRelay,
+    InternetGateway,
+}
+
+impl NodeRole {
+    /// Parse a role name ("phone", "tablet-hub", "solar-relay", "internet-gateway")
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "phone" => Some(NodeRole::Phone),
+            "tablet-hub" => Some(NodeRole::TabletHub),
+            "solar-relay" => Some(NodeRole::SolarRelay),
+            "internet-gateway" => Some(NodeRole::InternetGateway),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            NodeRole::Phone => "phone",
+            NodeRole::TabletHub => "tablet-hub",
+            NodeRole::SolarRelay => "solar-relay",
+            NodeRole::InternetGateway => "internet-gateway",
+        }
+    }
+
+    /// The settings this role sets, in the order they are applied
+    pub fn preset(&self) -> Vec<(&'static str, Value)> {
+        let (battery, max_ttl, quota_bytes, max_age_days, by_channel_type) = match self {
+            NodeRole::Phone => (BatteryMode::Balanced, 8, 0, 0, retention::default_channel_type_policies()),
+            NodeRole::TabletHub => (
+                BatteryMode::Performance,
+                12,
+                512 * MIB,
+                0,
+                json!({
+                    "geo": { "max_age_days": 14, "max_messages": 5000 },
+                    "sos": { "max_age_days": 7, "max_messages": 0 },
+                }),
+            ),
+            NodeRole::SolarRelay => (
+                BatteryMode::PowerSaving,
+                8,
+                64 * MIB,
+                3,
+                json!({
+                    "geo": { "max_age_days": 2, "max_messages": 1000 },
+                    "sos": { "max_age_days": 3, "max_messages": 0 },
+                }),
+            ),
+            NodeRole::InternetGateway => (BatteryMode::Performance, 16, 1024 * MIB, 7, json!({})),
+        };
+        vec![
+            (BATTERY_MODE, json!(battery.name())),
+            (RELAY_ENABLED, json!(true)),
+            (RELAY_MAX_TTL, json!(max_ttl)),
+            (RELAY_CHANNEL_RULES, json!([])),
+            (STORAGE_MAX_MESSAGE_BYTES, json!(quota_bytes)),
+            (RETENTION_MAX_AGE_DAYS, json!(max_age_days)),
+            (RETENTION_MAX_MESSAGES, json!(0)),
+            (RETENTION_BY_CHANNEL_TYPE, by_channel_type),
+        ]
+    }
+}
+
+/// Set every setting of a role's preset (`node.role` is left as it is).
+pub fn apply_preset(storage: &Storage, role: NodeRole) -> Result<(), String> {
+    role.preset()
+        .into_iter()
+        .try_for_each(|(key, value)| settings::set_value(storage, key, value))
+}
+
+/// Apply a role's preset and record it as the node's role.
+pub fn select(storage: &Storage, role: NodeRole) -> Result<(), String> {
+    storage.with_transaction(|storage| {
+        apply_preset(storage, role)?;
+        settings::set_value(storage, NODE_ROLE, json!(role.name()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_applies_the_preset() {
+        let storage = Storage::in_memory().unwrap();
+        assert_eq!(settings::get_value(&storage, NODE_ROLE).unwrap(), json!("phone"));
+
+        select(&storage, NodeRole::SolarRelay).unwrap();
+        assert_eq!(settings::battery_mode(&storage).unwrap().name(), "PowerSaving");
+        assert_eq!(settings::get_u64(&storage, STORAGE_MAX_MESSAGE_BYTES).unwrap(), 64 * MIB);
+        assert_eq!(settings::get_u64(&storage, RETENTION_MAX_AGE_DAYS).unwrap(), 3);
+
+        // Adjusting a setting keeps the role; selecting it again restores the preset
+        settings::set_value(&storage, RELAY_MAX_TTL, json!(2)).unwrap();
+        select(&storage, NodeRole::SolarRelay).unwrap();
+        assert_eq!(settings::get_u64(&storage, RELAY_MAX_TTL).unwrap(), 8);
+
+        // Setting node.role directly applies the preset too
+        settings::set_value(&storage, NODE_ROLE, json!("internet-gateway")).unwrap();
+        assert_eq!(settings::get_u64(&storage, RELAY_MAX_TTL).unwrap(), 16);
+        assert!(settings::set_value(&storage, NODE_ROLE, json!("toaster")).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+//! Notes-to-self channel
+//!
+//! Messages to yourself live in a local-only "notes" channel: no friend
+//! lookup and nothing is routed. The channel id is the DM channel id with
+//! ourselves, so notes written through `send_dm_message` land here too.
+//!
+//! Notes are encrypted with ChaCha20-Poly1305 under a key derived from our
+//! X25519 secret (earlier self-messages used a key derived from the public
+//! channel id; those are still readable):
+//! `NOTE_FORMAT_V1 || nonce (12) || ciphertext`, with the message id as AAD.
+
+use crate::dm_crypto;
+use crate::error::MeshError;
+use crate::identity::Identity;
+use crate::storage::{Storage, MESSAGE_KIND_USER};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Channel type of the notes channel in the channels table
+pub const NOTES_CHANNEL_TYPE: &str = "notes";
+
+const NOTE_FORMAT_V1: u8 = 1;
+
+/// A decrypted note
+#[derive(Serialize, Debug)]
+pub struct Note {
+    pub message_id: String,
+    pub plaintext: String,
+    pub timestamp: i64,
+}
+
+/// Channel id of our notes channel.
+pub fn notes_channel_id(identity: &Identity) -> [u8; 32] {
+    let own = identity.public().ed25519_public.as_bytes();
+    dm_crypto::derive_dm_channel_id(own, own)
+}
+
+fn notes_key(identity: &Identity) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"meshapp-notes-key");
+    hasher.update(identity.x25519_secret().as_bytes());
+    hasher.finalize().into()
+}
+
+/// Encrypt a note for storage.
+pub fn encrypt_note(identity: &Identity, message_id: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    let mut nonce = [0u8; 12];
+    crate::rng::rng().fill_bytes(&mut nonce);
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&notes_key(identity)));
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: message_id })
+        .map_err(|e| MeshError::Crypto.raise(format!("Failed to encrypt note: {}", e)))?;
+
+    let mut out = Vec::with_capacity(1 + nonce.len() + ciphertext.len());
+    out.push(NOTE_FORMAT_V1);
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Decrypt a stored note, falling back to the legacy self-message format.
+pub fn decrypt_note(identity: &Identity, message_id: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() > 13 && data[0] == NOTE_FORMAT_V1 {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&notes_key(identity)));
+        let nonce = Nonce::from_slice(&data[1..13]);
+        if let Ok(plaintext) = cipher.decrypt(nonce, Payload { msg: &data[13..], aad: message_id }) {
+            return Ok(plaintext);
+        }
+    }
+    dm_crypto::decrypt_self_message(&notes_channel_id(identity), message_id, data)
+}
+
+/// Register the notes channel (converting a self-DM channel from older versions).
+pub fn ensure_channel(storage: &Storage, identity: &Identity) -> Result<[u8; 32], String> {
+    let channel_id = notes_channel_id(identity);
+    storage.upsert_channel(channel_id, NOTES_CHANNEL_TYPE)?;
+    storage.set_channel_type(channel_id, NOTES_CHANNEL_TYPE)?;
+    Ok(channel_id)
+}
+
+/// Store a new note. Returns its message id.
+pub fn send(storage: &Storage, identity: &Identity, plaintext: &str, now: i64) -> Result<[u8; 32], String> {
+    let channel_id = ensure_channel(storage, identity)?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(channel_id);
+    hasher.update(now.to_be_bytes());
+    hasher.update(plaintext.as_bytes());
+    let message_id: [u8; 32] = hasher.finalize().into();
+
+    let ciphertext = encrypt_note(identity, &message_id, plaintext.as_bytes())?;
+    // TTL 0: notes never leave this device
+    storage.store_message(message_id, channel_id, ciphertext, now, 0)?;
+    Ok(message_id)
+}
+
+/// Fetch and decrypt notes, oldest first. Notes that fail to decrypt are skipped.
+pub fn fetch(storage: &Storage, identity: &Identity, limit: u32, offset: u32) -> Result<Vec<Note>, String> {
+    let channel_id = ensure_channel(storage, identity)?;
+    let rows = storage.fetch_messages(channel_id, limit, offset)?;
+
+    let mut notes = Vec::with_capacity(rows.len());
+    for row in rows.into_iter().filter(|r| r.kind == MESSAGE_KIND_USER) {
+        match decrypt_note(identity, &row.message_id, &row.ciphertext).map(String::from_utf8) {
+            Ok(Ok(plaintext)) => notes.push(Note {
+                message_id: hex::encode(row.message_id),
+                plaintext,
+                timestamp: row.timestamp,
+            }),
+            _ => eprintln!("Failed to decrypt note {}", hex::encode(row.message_id)),
+        }
+    }
+    Ok(notes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_note_encryption_is_bound_to_identity_and_message() {
+        let identity = Identity::generate();
+        let message_id = [7u8; 32];
+        let data = encrypt_note(&identity, &message_id, b"buy milk").unwrap();
+
+        assert_eq!(decrypt_note(&identity, &message_id, &data).unwrap(), b"buy milk");
+        assert!(decrypt_note(&identity, &[8u8; 32], &data).is_err());
+        assert!(decrypt_note(&Identity::generate(), &message_id, &data).is_err());
+    }
+}
@@ -0,0 +1,346 @@
+//! Notification decisions
+//!
+//! The core decides whether an incoming message should notify the user, so
+//! every platform applies the same rules. Per-channel settings live in the
+//! `channels` table:
+//! - muted: never notify
+//! - mention_only: notify only when the message mentions us
+//! - sound_profile: app-defined sound id passed back with the decision
+//!
+//! A global quiet-hours schedule (stored under `QUIET_HOURS_KEY` in the
+//! settings table) silences everything else inside its window, except
+//! messages from verified friends (if allowed) and channels that receive
+//! several messages in a short burst (someone repeatedly trying to reach us).
+//!
+//! The sender's priority (see `priority`) counts too: urgent messages break
+//! through mention-only channels and quiet hours, but not a mute; background
+//! messages never notify.
+//!
+//! Arrivals are counted as messages are ingested (`on_arrival`); deciding
+//! only reads the count, so asking twice about one message does not make a
+//! burst. For a stored message, the sender and whether it mentions us are
+//! read from the message itself (`message_context`).
+
+use crate::dm_crypto;
+use crate::error::MeshError;
+use crate::geo_messages::{self, GEO_CHANNEL_TYPE};
+use crate::groups;
+use crate::mentions;
+use crate::priority::Priority;
+use crate::search;
+use crate::settings;
+use crate::storage::{MessageRow, Storage};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Settings key holding the quiet-hours schedule (JSON)
+pub const QUIET_HOURS_KEY: &str = "notifications.quiet_hours";
+
+/// Per-channel notification settings
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct NotificationSettings {
+    #[serde(default)]
+    pub muted: bool,
+    #[serde(default)]
+    pub mention_only: bool,
+    #[serde(default)]
+    pub sound_profile: Option<String>,
+}
+
+/// Do-not-disturb schedule, in minutes after local midnight.
+/// The window may wrap midnight (e.g., 23:00–07:00 is 1380..420).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct QuietHours {
+    pub enabled: bool,
+    pub start_minute: u16,
+    pub end_minute: u16,
+    /// Local time offset from UTC; kept current by the app
+    pub utc_offset_minutes: i16,
+    /// Verified friends still notify during quiet hours
+    pub allow_verified_friends: bool,
+    /// This many messages in one channel within `repeat_window_secs`
+    /// break through quiet hours (0 disables the override)
+    pub repeat_threshold: u32,
+    pub repeat_window_secs: u32,
+}
+
+impl Default for QuietHours {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            start_minute: 23 * 60,
+            end_minute: 7 * 60,
+            utc_offset_minutes: 0,
+            allow_verified_friends: true,
+            repeat_threshold: 3,
+            repeat_window_secs: 180,
+        }
+    }
+}
+
+impl QuietHours {
+    /// Check that the window boundaries are valid times of day.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.start_minute >= 1440 || self.end_minute >= 1440 {
+            return Err(MeshError::InvalidArgument.raise("Quiet hours must be within 00:00–23:59"));
+        }
+        Ok(())
+    }
+
+    /// Whether the schedule silences notifications at UNIX time `now`.
+    pub fn is_active(&self, now: i64) -> bool {
+        if !self.enabled || self.start_minute == self.end_minute {
+            return false;
+        }
+        let local = now + self.utc_offset_minutes as i64 * 60;
+        let minute = (local.rem_euclid(86_400) / 60) as u16;
+
+        if self.start_minute < self.end_minute {
+            minute >= self.start_minute && minute < self.end_minute
+        } else {
+            minute >= self.start_minute || minute < self.end_minute
+        }
+    }
+}
+
+/// Load the quiet-hours schedule (defaults if never set).
+pub fn load_quiet_hours(storage: &Storage) -> Result<QuietHours, String> {
+    settings::get(storage, QUIET_HOURS_KEY)
+}
+
+/// Persist the quiet-hours schedule.
+pub fn save_quiet_hours(storage: &Storage, quiet_hours: &QuietHours) -> Result<(), String> {
+    let value = serde_json::to_value(quiet_hours).map_err(|e| format!("Failed to serialize quiet hours: {}", e))?;
+    settings::set_value(storage, QUIET_HOURS_KEY, value)
+}
+
+/// What is known about the message being decided on
+#[derive(Debug, Default)]
+pub struct MessageContext {
+    pub mentions_me: bool,
+    pub sender_verified: bool,
+    /// Messages seen in this channel within the repeat window (this one included)
+    pub recent_in_channel: u32,
+    /// As the sender marked the message
+    pub priority: Priority,
+}
+
+/// Outcome of the notification decision for one message
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct NotificationDecision {
+    pub notify: bool,
+    pub sound_profile: Option<String>,
+    /// Why: "default", "muted", "background", "mention_only", "quiet_hours",
+    /// "quiet_hours_override", or "urgent"
+    pub reason: &'static str,
+}
+
+/// Decide whether a message in a channel with `settings` should notify.
+pub fn decide(
+    settings: &NotificationSettings,
+    quiet_hours: &QuietHours,
+    ctx: &MessageContext,
+    now: i64,
+) -> NotificationDecision {
+    let silent = |reason| NotificationDecision {
+        notify: false,
+        sound_profile: None,
+        reason,
+    };
+
+    if settings.muted {
+        return silent("muted");
+    }
+    match ctx.priority {
+        Priority::Background => return silent("background"),
+        Priority::Urgent => {
+            return NotificationDecision {
+                notify: true,
+                sound_profile: settings.sound_profile.clone(),
+                reason: "urgent",
+            }
+        }
+        Priority::Normal => {}
+    }
+    if settings.mention_only && !ctx.mentions_me {
+        return silent("mention_only");
+    }
+
+    let mut reason = "default";
+    if quiet_hours.is_active(now) {
+        let verified = quiet_hours.allow_verified_friends && ctx.sender_verified;
+        let repeated = quiet_hours.repeat_threshold > 0 && ctx.recent_in_channel >= quiet_hours.repeat_threshold;
+        if !verified && !repeated {
+            return silent("quiet_hours");
+        }
+        reason = "quiet_hours_override";
+    }
+
+    NotificationDecision {
+        notify: true,
+        sound_profile: settings.sound_profile.clone(),
+        reason,
+    }
+}
+
+/// Who sent a stored message and whether it mentions us (by
+/// `own_nickname`), where this device can tell: group messages name their
+/// sender, a DM comes from the friend the channel is shared with and its
+/// text is the plaintext `message_index` kept, geo messages carry their text.
+pub fn message_context(storage: &Storage, message: &MessageRow, own_ed25519: Option<[u8; 32]>, own_nickname: Option<&str>) -> Result<MessageContext, String> {
+    let friends = storage.list_friends()?;
+    let (sender, text) = match storage.get_channel_type(message.channel_id)?.as_deref() {
+        Some("group") => match groups::open_message(storage, message.channel_id, &message.message_id, &message.ciphertext) {
+            Ok(plaintext) => {
+                let (sender, text) = groups::parse_message(&String::from_utf8_lossy(&plaintext));
+                (sender.and_then(|s| crate::codec::parse_id_hex(&s, "sender").ok()), Some(text))
+            }
+            Err(_) => (None, None),
+        },
+        Some(GEO_CHANNEL_TYPE) => (None, geo_messages::open(message.channel_id, &message.ciphertext).ok().flatten().map(|(_, body)| body.text)),
+        _ => {
+            let sender = own_ed25519.and_then(|own| {
+                friends
+                    .iter()
+                    .find(|f| dm_crypto::derive_dm_channel_id(&own, &f.ed25519_public) == message.channel_id)
+                    .map(|f| f.user_id)
+            });
+            let kept = storage.get_message_plaintext(message.message_id)?;
+            (sender, kept.filter(|p| !p.outgoing).and_then(|p| search::indexable_text(&p.plaintext)))
+        }
+    };
+    let mentions_me = match (text, own_nickname) {
+        (Some(text), Some(nickname)) => {
+            let me = mentions::FriendInfo { user_id: String::new(), nickname: nickname.to_string() };
+            !mentions::extract_mentions(&text, &[me]).is_empty()
+        }
+        _ => false,
+    };
+    Ok(MessageContext {
+        mentions_me,
+        sender_verified: sender.is_some_and(|sender| friends.iter().any(|f| f.user_id == sender && f.verified_at.is_some())),
+        recent_in_channel: 0,
+        priority: Priority::from_u8(message.priority),
+    })
+}
+
+// Recent message arrivals per channel, for the repeated-message override
+static RECENT: Lazy<Mutex<HashMap<[u8; 32], VecDeque<i64>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Count a message arriving in a channel (call as it is ingested).
+pub fn on_arrival(storage: &Storage, channel_id: [u8; 32], now: i64) -> Result<(), String> {
+    record_arrival(channel_id, now, load_quiet_hours(storage)?.repeat_window_secs);
+    Ok(())
+}
+
+/// Record a message arrival in a channel. Arrivals older than `window_secs`
+/// are dropped, and channels with none left are forgotten.
+pub fn record_arrival(channel_id: [u8; 32], now: i64, window_secs: u32) {
+    let cutoff = now - window_secs as i64;
+    let mut recent = RECENT.lock().unwrap();
+    recent.entry(channel_id).or_default().push_back(now);
+    recent.retain(|_, arrivals| {
+        while arrivals.front().is_some_and(|t| *t <= cutoff) {
+            arrivals.pop_front();
+        }
+        !arrivals.is_empty()
+    });
+}
+
+/// Messages that arrived in a channel within the last `window_secs`.
+pub fn recent_arrivals(channel_id: [u8; 32], now: i64, window_secs: u32) -> u32 {
+    let cutoff = now - window_secs as i64;
+    RECENT
+        .lock()
+        .unwrap()
+        .get(&channel_id)
+        .map_or(0, |arrivals| arrivals.iter().filter(|t| **t > cutoff).count() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quiet_hours_wrap_midnight_and_overrides() {
+        let quiet = QuietHours {
+            enabled: true,
+            utc_offset_minutes: 60,
+            ..QuietHours::default()
+        };
+        // 22:30 UTC is 23:30 local: inside 23:00–07:00
+        let night = 22 * 3600 + 30 * 60;
+        // 11:00 UTC is 12:00 local: outside
+        let noon = 11 * 3600;
+        assert!(quiet.is_active(night));
+        assert!(!quiet.is_active(noon));
+
+        let settings = NotificationSettings::default();
+        let plain = MessageContext { recent_in_channel: 1, ..Default::default() };
+        assert_eq!(decide(&settings, &quiet, &plain, night).reason, "quiet_hours");
+        assert!(decide(&settings, &quiet, &plain, noon).notify);
+
+        let verified = MessageContext { sender_verified: true, ..Default::default() };
+        assert_eq!(decide(&settings, &quiet, &verified, night).reason, "quiet_hours_override");
+
+        let repeated = MessageContext { recent_in_channel: 3, ..Default::default() };
+        assert!(decide(&settings, &quiet, &repeated, night).notify);
+
+        let muted = NotificationSettings { muted: true, ..Default::default() };
+        assert!(!decide(&muted, &quiet, &verified, noon).notify);
+
+        // Urgent messages break through quiet hours and mention-only, not a mute
+        let urgent = MessageContext { priority: Priority::Urgent, ..Default::default() };
+        let mention_only = NotificationSettings { mention_only: true, ..Default::default() };
+        assert_eq!(decide(&mention_only, &quiet, &urgent, night).reason, "urgent");
+        assert!(!decide(&muted, &quiet, &urgent, noon).notify);
+        let background = MessageContext { priority: Priority::Background, ..Default::default() };
+        assert_eq!(decide(&settings, &quiet, &background, noon).reason, "background");
+    }
+
+    #[test]
+    fn test_arrivals_are_counted_on_record_only() {
+        let (channel, other) = ([41u8; 32], [42u8; 32]);
+        record_arrival(other, 1_000, 60);
+        record_arrival(channel, 1_010, 60);
+        record_arrival(channel, 1_020, 60);
+        assert_eq!(recent_arrivals(channel, 1_030, 60), 2);
+        assert_eq!(recent_arrivals(channel, 1_030, 60), 2);
+        assert_eq!(recent_arrivals(channel, 1_075, 60), 1);
+
+        // Channels with nothing inside the window are forgotten
+        record_arrival(channel, 1_100, 60);
+        assert!(!RECENT.lock().unwrap().contains_key(&other));
+        assert_eq!(recent_arrivals(channel, 1_100, 60), 1);
+    }
+
+    #[test]
+    fn test_stored_message_context() {
+        use crate::identity::Identity;
+
+        let storage = Storage::in_memory().unwrap();
+        let (me, alice) = (Identity::generate(), Identity::generate());
+        let alice_storage = Storage::in_memory().unwrap();
+        let channel = groups::create(&me, &storage, None, 100).unwrap();
+        let invite = groups::add_member(&me, &storage, channel, alice.public().user_id, 100).unwrap();
+        groups::accept_invite(&alice, &alice_storage, &invite, me.public().ed25519_public.to_bytes(), 110).unwrap();
+
+        let sealed = groups::seal_message(&alice, &alice_storage, channel, "@sam are you there?", 120).unwrap();
+        storage.store_message(sealed.message_id, channel, sealed.ciphertext.clone(), 120, 3).unwrap();
+        let message = storage.get_message(sealed.message_id).unwrap().unwrap();
+        let own = Some(me.public().ed25519_public.to_bytes());
+        let ctx = message_context(&storage, &message, own, Some("sam")).unwrap();
+        assert!(ctx.mentions_me && !ctx.sender_verified);
+        assert!(!message_context(&storage, &message, own, Some("alex")).unwrap().mentions_me);
+
+        // Verified in the friends table
+        let mut friends = crate::friends::FriendManager::load(&storage).unwrap();
+        let alice_id = friends.add_friend(&storage, alice.public().ed25519_public.to_bytes(), "alice".to_string()).unwrap();
+        assert!(!message_context(&storage, &message, own, None).unwrap().sender_verified);
+        friends.set_verified(&storage, &alice_id, Some(130)).unwrap();
+        assert!(message_context(&storage, &message, own, None).unwrap().sender_verified);
+    }
+}
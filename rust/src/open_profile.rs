@@ -0,0 +1,209 @@
+//! Open mesh profile (feature `open-profile`)
+//!
+//! A minimal public wire profile so third-party firmware and bridges can
+//! exchange public channel messages with meshapp nodes. The full
+//! specification is in `docs/open-mesh-profile.md`; in short:
+//! - frame: "OMP1" || version || kind || ttl || flags || packet_id (32) ||
+//!   channel_id (32) || payload length (u16 BE) || payload
+//! - public channel id: SHA256(geohash || topic), as in `geo`
+//! - envelope (frame payload): version || timestamp (i64 BE) || sender Ed25519
+//!   key (32) || nickname length (u8) || nickname || text length (u16 BE) ||
+//!   text || Ed25519 signature (64) over "omp-envelope" || everything before it
+//!
+//! Envelopes are signed, not encrypted: the profile only covers public
+//! channels. Only `Message` frames are defined.
+
+use crate::error::MeshError;
+use crate::priority::Priority;
+use crate::transport::{Packet, PacketKind};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+pub const FRAME_MAGIC: &[u8; 4] = b"OMP1";
+pub const PROFILE_VERSION: u8 = 1;
+
+/// Frame header bytes before the payload
+pub const FRAME_HEADER_LEN: usize = 4 + 4 + 32 + 32 + 2;
+
+/// Longest payload a frame can carry
+pub const MAX_PAYLOAD_LEN: usize = u16::MAX as usize;
+
+/// Longest nickname in an envelope
+pub const MAX_NICKNAME_LEN: usize = 64;
+
+const ENVELOPE_CONTEXT: &[u8] = b"omp-envelope";
+
+/// A public channel message
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub timestamp: i64,
+    /// Hex Ed25519 key of the sender
+    pub sender_ed25519_public: String,
+    pub sender_user_id: String,
+    pub nickname: String,
+    pub text: String,
+}
+
+/// A decoded frame with its verified envelope
+#[derive(Serialize, Debug, Clone)]
+pub struct DecodedFrame {
+    pub packet_id: String,
+    pub channel_id: String,
+    pub ttl: u8,
+    pub envelope: Envelope,
+}
+
+/// Decode a frame and verify its envelope.
+pub fn decode(frame: &[u8]) -> Result<(Packet, DecodedFrame), String> {
+    let packet = decode_frame(frame)?;
+    let decoded = DecodedFrame {
+        packet_id: hex::encode(packet.packet_id),
+        channel_id: hex::encode(packet.channel_id),
+        ttl: packet.ttl,
+        envelope: open_envelope(&packet.payload)?,
+    };
+    Ok((packet, decoded))
+}
+
+/// Channel id of a public (geohash) channel.
+pub fn public_channel_id(geohash: &str, topic: &str) -> [u8; 32] {
+    crate::geo::derive_geo_channel_id(geohash, topic)
+}
+
+/// Encode a packet as a profile frame.
+pub fn encode_frame(packet: &Packet) -> Result<Vec<u8>, String> {
+    if packet.kind != PacketKind::Message {
+        return Err(MeshError::Rejected.raise("Only message packets are part of the open profile"));
+    }
+    if packet.payload.len() > MAX_PAYLOAD_LEN {
+        return Err(MeshError::InvalidArgument.raise("Payload too large for an open profile frame"));
+    }
+    let mut out = Vec::with_capacity(FRAME_HEADER_LEN + packet.payload.len());
+    out.extend_from_slice(FRAME_MAGIC);
+    out.extend_from_slice(&[PROFILE_VERSION, packet.kind as u8, packet.ttl, 0]);
+    out.extend_from_slice(&packet.packet_id);
+    out.extend_from_slice(&packet.channel_id);
+    out.extend_from_slice(&(packet.payload.len() as u16).to_be_bytes());
+    out.extend_from_slice(&packet.payload);
+    Ok(out)
+}
+
+/// Decode a profile frame.
+pub fn decode_frame(frame: &[u8]) -> Result<Packet, String> {
+    if frame.len() < FRAME_HEADER_LEN || &frame[..4] != FRAME_MAGIC {
+        return Err("Not an open profile frame".to_string());
+    }
+    if frame[4] != PROFILE_VERSION {
+        return Err(MeshError::InvalidArgument.raise(format!("Unsupported open profile version {}", frame[4])));
+    }
+    if frame[5] != PacketKind::Message as u8 {
+        return Err(MeshError::InvalidArgument.raise(format!("Unsupported open profile frame kind {}", frame[5])));
+    }
+    if frame[7] != 0 {
+        return Err(MeshError::InvalidArgument.raise("Unknown open profile frame flags"));
+    }
+    let payload_len = u16::from_be_bytes([frame[72], frame[73]]) as usize;
+    if frame.len() != FRAME_HEADER_LEN + payload_len {
+        return Err("Open profile frame length does not match its header".to_string());
+    }
+    Ok(Packet {
+        packet_id: crate::codec::read_array(frame, 8, "packet id")?,
+        channel_id: crate::codec::read_array(frame, 40, "channel id")?,
+        kind: PacketKind::Message,
+        ttl: frame[6],
+        payload: frame[FRAME_HEADER_LEN..].to_vec(),
+        priority: Priority::Normal,
+    })
+}
+
+/// Build and sign an envelope with `key` (our identity key, or a pseudonym's).
+pub fn seal_envelope(key: &SigningKey, nickname: &str, text: &str, timestamp: i64) -> Result<Vec<u8>, String> {
+    if nickname.len() > MAX_NICKNAME_LEN {
+        return Err(MeshError::InvalidArgument.raise(format!("Nickname is longer than {} bytes", MAX_NICKNAME_LEN)));
+    }
+    if text.len() > u16::MAX as usize {
+        return Err(MeshError::InvalidArgument.raise("Message text too long"));
+    }
+    let mut out = vec![PROFILE_VERSION];
+    out.extend_from_slice(&timestamp.to_be_bytes());
+    out.extend_from_slice(key.verifying_key().as_bytes());
+    out.push(nickname.len() as u8);
+    out.extend_from_slice(nickname.as_bytes());
+    out.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    out.extend_from_slice(text.as_bytes());
+
+    let signature = key.sign(&[ENVELOPE_CONTEXT, &out].concat());
+    out.extend_from_slice(&signature.to_bytes());
+    Ok(out)
+}
+
+/// Parse and verify an envelope.
+pub fn open_envelope(payload: &[u8]) -> Result<Envelope, String> {
+    let truncated = || MeshError::InvalidArgument.raise("Open profile envelope is truncated");
+    if payload.first() != Some(&PROFILE_VERSION) {
+        return Err(MeshError::InvalidArgument.raise("Unsupported open profile envelope"));
+    }
+    if payload.len() < 1 + 8 + 32 + 1 + 2 + 64 {
+        return Err(truncated());
+    }
+    let timestamp = crate::codec::read_i64_be(payload, 1, "timestamp")?;
+    let sender: [u8; 32] = crate::codec::read_array(payload, 9, "sender key")?;
+    let nickname_len = payload[41] as usize;
+    let text_len_at = 42 + nickname_len;
+    let text_len = payload
+        .get(text_len_at..text_len_at + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+        .ok_or_else(truncated)?;
+    let signed_len = text_len_at + 2 + text_len;
+    if payload.len() != signed_len + 64 {
+        return Err(truncated());
+    }
+
+    let signature: [u8; 64] = crate::codec::read_array(payload, signed_len, "signature")?;
+    VerifyingKey::from_bytes(&sender)
+        .map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid sender key: {}", e)))?
+        .verify(&[ENVELOPE_CONTEXT, &payload[..signed_len]].concat(), &Signature::from_bytes(&signature))
+        .map_err(|_| MeshError::Crypto.raise("Open profile envelope signature does not verify"))?;
+
+    let utf8 = |bytes: &[u8]| String::from_utf8(bytes.to_vec()).map_err(|e| MeshError::InvalidArgument.raise(format!("Envelope text is not UTF-8: {}", e)));
+    Ok(Envelope {
+        timestamp,
+        sender_ed25519_public: hex::encode(sender),
+        sender_user_id: hex::encode(Sha256::digest(sender)),
+        nickname: utf8(&payload[42..text_len_at])?,
+        text: utf8(&payload[text_len_at + 2..signed_len])?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Identity;
+
+    #[test]
+    fn test_frame_and_envelope_roundtrip() {
+        let identity = Identity::generate();
+        let payload = seal_envelope(identity.ed25519_signing_key(), "ana", "hello mesh", 1_700_000_000).unwrap();
+        let packet = Packet {
+            packet_id: [5u8; 32],
+            channel_id: public_channel_id("u4pruyd", "general"),
+            kind: PacketKind::Message,
+            ttl: 4,
+            payload,
+            priority: Priority::Normal,
+        };
+        let frame = encode_frame(&packet).unwrap();
+        let (decoded, info) = decode(&frame).unwrap();
+        assert_eq!((decoded.packet_id, decoded.channel_id, decoded.ttl), (packet.packet_id, packet.channel_id, 4));
+
+        let envelope = info.envelope;
+        assert_eq!((envelope.nickname.as_str(), envelope.text.as_str()), ("ana", "hello mesh"));
+        assert_eq!(envelope.sender_user_id, hex::encode(identity.public().user_id));
+
+        let mut tampered = decoded.payload.clone();
+        tampered[50] ^= 1;
+        assert!(open_envelope(&tampered).is_err());
+        assert!(decode_frame(&frame[..frame.len() - 1]).is_err());
+    }
+}
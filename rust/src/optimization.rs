@@ -5,18 +5,22 @@
 //! - Scanning intervals (BLE power management)
 //! - Battery usage hints
 
+use crate::memory_budget::{self, Pool, BUDGET};
+use crate::priority;
 use crate::transport::Packet;
+use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 /// Batched packet sender for efficient transport usage
 /// 
-/// Collects packets and sends them in batches to reduce overhead
+/// Collects packets and sends them in batches to reduce overhead.
+/// Batched packets count against the memory budget.
 /// 
 /// Will be used when BLE transport is implemented
 #[allow(dead_code)] // Will be used in Phase 10+ (BLE transport)
 pub struct PacketBatcher {
-    batch: Arc<Mutex<Vec<Packet>>>,
+    batch: Arc<Mutex<VecDeque<Packet>>>,
     max_batch_size: usize,
     max_batch_age: Duration,
     last_flush: Arc<Mutex<Instant>>,
@@ -27,7 +31,7 @@ impl PacketBatcher {
     /// Create a new batcher with specified limits
     pub fn new(max_batch_size: usize, max_batch_age_secs: u64) -> Self {
         Self {
-            batch: Arc::new(Mutex::new(Vec::new())),
+            batch: Arc::new(Mutex::new(VecDeque::new())),
             max_batch_size,
             max_batch_age: Duration::from_secs(max_batch_age_secs),
             last_flush: Arc::new(Mutex::new(Instant::now())),
@@ -36,16 +40,23 @@ impl PacketBatcher {
 
     /// Add a packet to the batch
     /// Returns true if batch should be flushed immediately
+    /// (lowest-priority packets may be shed to stay within the memory budget)
+    /// SOS and urgent packets are not held back: they go first (SOS ahead of
+    /// urgent) and flush the batch at once.
     pub fn add(&self, packet: Packet) -> bool {
         let mut batch = self.batch.lock().unwrap();
-        batch.push(packet);
-        
-        // Flush if batch is full
-        if batch.len() >= self.max_batch_size {
-            true
+        BUDGET.charge(Pool::Batcher, memory_budget::packet_cost(&packet));
+        let priority = priority::jumps_queue(&packet);
+        if priority {
+            let at = priority::queue_index(batch.iter(), &packet);
+            batch.insert(at, packet);
         } else {
-            false
+            batch.push_back(packet);
         }
+        BUDGET.shed_queue(Pool::Batcher, &mut batch);
+        
+        // Flush if batch is full
+        priority || batch.len() >= self.max_batch_size
     }
 
     /// Check if batch should be flushed due to age
@@ -56,9 +67,8 @@ impl PacketBatcher {
 
     /// Take all packets from the batch (clears batch)
     pub fn take_batch(&self) -> Vec<Packet> {
-        let mut batch = self.batch.lock().unwrap();
-        let packets = batch.clone();
-        batch.clear();
+        let packets: Vec<Packet> = self.batch.lock().unwrap().drain(..).collect();
+        BUDGET.release(Pool::Batcher, packets.iter().map(memory_budget::packet_cost).sum());
         *self.last_flush.lock().unwrap() = Instant::now();
         packets
     }
@@ -69,6 +79,13 @@ impl PacketBatcher {
     }
 }
 
+impl Drop for PacketBatcher {
+    fn drop(&mut self) {
+        let batch = self.batch.lock().unwrap();
+        BUDGET.release(Pool::Batcher, batch.iter().map(memory_budget::packet_cost).sum());
+    }
+}
+
 /// Scanning interval configuration for BLE
 #[derive(Clone, Copy, Debug)]
 pub enum ScanInterval {
@@ -112,6 +129,25 @@ pub enum BatteryMode {
 }
 
 impl BatteryMode {
+    /// Parse a mode name ("performance", "balanced", "power_saving"), case-insensitive
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "performance" => Some(BatteryMode::Performance),
+            "balanced" => Some(BatteryMode::Balanced),
+            "powersaving" | "power_saving" => Some(BatteryMode::PowerSaving),
+            _ => None,
+        }
+    }
+
+    /// Display name used in JSON
+    pub fn name(&self) -> &'static str {
+        match self {
+            BatteryMode::Performance => "Performance",
+            BatteryMode::Balanced => "Balanced",
+            BatteryMode::PowerSaving => "PowerSaving",
+        }
+    }
+
     /// Get recommended scan interval for this battery mode
     pub fn recommended_scan_interval(&self) -> ScanInterval {
         match self {
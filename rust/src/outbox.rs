@@ -0,0 +1,178 @@
+//! Store-and-forward outbox
+//!
+//! Packets no transport took when they were routed (see `transport::Router`)
+//! are queued in the `outbox` table instead of being lost, so they survive a
+//! restart too. `retry` hands due packets to the transports again; a packet
+//! that still finds none waits `RETRY_BASE_SECS`, doubling with every failed
+//! retry up to `MAX_RETRY_SECS`. When a transport reports it is available
+//! again every queued packet is tried at once, whatever its backoff.
+//!
+//! Packets still queued `MAX_AGE_SECS` after they were first held are given
+//! up with an `outbox_expired` event { packet_ids }. Messages of ours move
+//! along their send state as they are sent, retried or given up (see
+//! `send_status`).
+
+use crate::events;
+use crate::priority::Priority;
+use crate::send_status::{self, SendState};
+use crate::storage::{OutboxRow, Storage};
+use crate::transport::{Packet, PacketKind, Router};
+use serde::Serialize;
+use serde_json::json;
+
+/// Wait before the first retry; doubles with every failed one
+pub const RETRY_BASE_SECS: i64 = 5;
+
+/// Longest wait between retries
+pub const MAX_RETRY_SECS: i64 = 10 * 60;
+
+/// How long a packet is retried before it is given up
+pub const MAX_AGE_SECS: i64 = 24 * 60 * 60;
+
+fn backoff(attempts: u32) -> i64 {
+    RETRY_BASE_SECS.saturating_mul(1 << attempts.min(16)).min(MAX_RETRY_SECS)
+}
+
+/// Outbox state as reported by `get_outbox_status`
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutboxStatus {
+    pub queued: u64,
+    /// When the next retry is due (None when empty)
+    pub next_attempt: Option<i64>,
+    /// When the oldest queued packet was held
+    pub oldest_queued_at: Option<i64>,
+}
+
+/// What a retry pass did
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryOutcome {
+    pub sent: usize,
+    pub failed: usize,
+    pub expired: usize,
+}
+
+/// Queue packets the router held. Returns how many were queued.
+pub fn hold(storage: &Storage, packets: Vec<Packet>, now: i64) -> Result<usize, String> {
+    let count = packets.len();
+    storage.with_transaction(|storage| {
+        for packet in packets {
+            storage.enqueue_outbox(&OutboxRow {
+                packet_id: packet.packet_id,
+                channel_id: packet.channel_id,
+                kind: packet.kind as u8,
+                ttl: packet.ttl,
+                payload: packet.payload,
+                priority: packet.priority as u8,
+                created_at: now,
+                attempts: 0,
+                next_attempt: now + backoff(0),
+            })?;
+        }
+        Ok(())
+    })?;
+    Ok(count)
+}
+
+/// Hand queued packets to the router's transports again: the due ones, or
+/// all of them when `all` (a transport just became available).
+pub fn retry(storage: &Storage, router: &Router, now: i64, all: bool) -> Result<RetryOutcome, String> {
+    let due = storage.list_due_outbox(if all { i64::MAX } else { now })?;
+    let mut outcome = RetryOutcome::default();
+    let mut expired = Vec::new();
+    for row in due {
+        let Some(kind) = PacketKind::from_u8(row.kind) else {
+            storage.delete_outbox(row.packet_id)?;
+            continue;
+        };
+        let packet = Packet {
+            packet_id: row.packet_id,
+            channel_id: row.channel_id,
+            kind,
+            ttl: row.ttl,
+            payload: row.payload,
+            priority: Priority::from_u8(row.priority),
+        };
+        if router.try_send(&packet) {
+            storage.delete_outbox(row.packet_id)?;
+            send_status::advance(storage, row.packet_id, SendState::Routed, now)?;
+            outcome.sent += 1;
+        } else if now - row.created_at >= MAX_AGE_SECS {
+            storage.delete_outbox(row.packet_id)?;
+            send_status::advance(storage, row.packet_id, SendState::Failed, now)?;
+            expired.push(hex::encode(row.packet_id));
+        } else {
+            let attempts = row.attempts + 1;
+            storage.reschedule_outbox(row.packet_id, attempts, now + backoff(attempts))?;
+            send_status::retried(storage, row.packet_id, now)?;
+            outcome.failed += 1;
+        }
+    }
+    outcome.expired = expired.len();
+    if !expired.is_empty() {
+        events::emit("outbox_expired", json!({ "packet_ids": expired }));
+    }
+    Ok(outcome)
+}
+
+/// Current outbox size and schedule.
+pub fn status(storage: &Storage) -> Result<OutboxStatus, String> {
+    let (queued, next_attempt, oldest_queued_at) = storage.outbox_summary()?;
+    Ok(OutboxStatus { queued, next_attempt, oldest_queued_at })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::Transport;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    struct Link {
+        up: AtomicBool,
+        sent: Mutex<Vec<[u8; 32]>>,
+    }
+
+    impl Transport for Link {
+        fn send(&self, packet: &Packet) -> Result<(), String> {
+            self.sent.lock().unwrap().push(packet.packet_id);
+            Ok(())
+        }
+
+        fn is_available(&self) -> bool {
+            self.up.load(Ordering::SeqCst)
+        }
+    }
+
+    #[test]
+    fn test_held_packets_are_retried_with_backoff() {
+        let storage = Storage::in_memory().unwrap();
+        let link = Arc::new(Link { up: AtomicBool::new(false), sent: Mutex::new(Vec::new()) });
+        let router = Router::new(vec![link.clone()]);
+        let packet = Packet {
+            packet_id: [1u8; 32],
+            channel_id: [2u8; 32],
+            kind: PacketKind::Message,
+            ttl: 3,
+            payload: vec![7],
+            priority: Priority::Normal,
+        };
+
+        router.route(packet, |_| {});
+        assert_eq!(hold(&storage, router.take_held(), 100).unwrap(), 1);
+        assert_eq!(retry(&storage, &router, 100, false).unwrap(), RetryOutcome::default());
+        assert_eq!(retry(&storage, &router, 105, false).unwrap().failed, 1);
+        assert_eq!(status(&storage).unwrap().next_attempt, Some(105 + 2 * RETRY_BASE_SECS));
+
+        // A transport coming back sends it at once
+        link.up.store(true, Ordering::SeqCst);
+        assert_eq!(retry(&storage, &router, 106, true).unwrap().sent, 1);
+        assert_eq!(*link.sent.lock().unwrap(), vec![[1u8; 32]]);
+        assert_eq!(status(&storage).unwrap().queued, 0);
+
+        link.up.store(false, Ordering::SeqCst);
+        router.send_direct(&Packet { packet_id: [3u8; 32], channel_id: [2u8; 32], kind: PacketKind::Message, ttl: 1, payload: vec![], priority: Priority::Normal });
+        hold(&storage, router.take_held(), 200).unwrap();
+        assert_eq!(retry(&storage, &router, 200 + MAX_AGE_SECS, true).unwrap().expired, 1);
+        assert_eq!(status(&storage).unwrap().queued, 0);
+    }
+}
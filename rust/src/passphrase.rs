@@ -0,0 +1,408 @@
+//! Passphrase protection and re-encryption
+//!
+//! With a passphrase set, the identity file and the database key file
+//! (`db_key.json`, a random key for database encryption) are stored sealed:
+//! `{ version, salt, iterations, nonce, ciphertext }` (hex fields), with
+//! ChaCha20-Poly1305 under PBKDF2-HMAC-SHA256(passphrase, salt). SQLCipher
+//! builds encrypt the database under that key (`unlock_db_keys`); without a
+//! passphrase, under one derived from the identity (`Identity::storage_key`).
+//!
+//! Changing the passphrase first replaces the database key and rekeys the
+//! open database (`rotate_db_key`). Until the database is rekeyed the sealed
+//! file holds the previous key after the new one, so a crash in between
+//! leaves a key that opens it.
+//!
+//! Changing the passphrase runs as a background job that rewraps each file
+//! in turn (temp file, then rename, so a file is never half-written) and
+//! emits `reencryption_progress` events. The job is journaled in
+//! `rekey.json`. If it is interrupted, startup reports
+//! `reencryption_incomplete`, and calling `change_passphrase` again with the
+//! same passphrases finishes the job: files that already open with the new
+//! passphrase are skipped.
+
+use crate::error::MeshError;
+use crate::events;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+/// Current sealed file format version
+pub const SEALED_VERSION: u8 = 1;
+
+/// PBKDF2 iterations for newly sealed files
+pub const KDF_ITERATIONS: u32 = 200_000;
+
+pub const IDENTITY_FILE: &str = "identity.json";
+pub const DB_KEY_FILE: &str = "db_key.json";
+const JOURNAL_FILE: &str = "rekey.json";
+
+/// Files sealed under the passphrase, in rewrap order
+pub const PROTECTED_FILES: [&str; 2] = [IDENTITY_FILE, DB_KEY_FILE];
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Set while a re-encryption job runs
+static JOB_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Database keys opened with the passphrase, newest first
+static DB_KEYS: Mutex<Vec<Zeroizing<[u8; 32]>>> = Mutex::new(Vec::new());
+
+/// A file sealed under a passphrase (hex-encoded binary fields)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SealedFile {
+    pub version: u8,
+    pub salt: String,
+    pub iterations: u32,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+/// Passphrase state reported to the app
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PassphraseStatus {
+    pub protected: bool,
+    /// A re-encryption job was interrupted and needs to be resumed
+    pub reencryption_pending: bool,
+    pub reencryption_running: bool,
+}
+
+/// Files rewrapped so far by an unfinished job
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Journal {
+    started_at: i64,
+    done: Vec<String>,
+}
+
+/// PBKDF2-HMAC-SHA256 with a single 32-byte output block.
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, iterations, key.as_mut());
+    key
+}
+
+/// Seal `plaintext` under a passphrase.
+pub fn seal(passphrase: &str, plaintext: &[u8], iterations: u32) -> Result<SealedFile, String> {
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    crate::rng::rng().fill_bytes(&mut salt);
+    crate::rng::rng().fill_bytes(&mut nonce);
+    let key = derive_key(passphrase, &salt, iterations);
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(key.as_ref()))
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: &[SEALED_VERSION] })
+        .map_err(|_| "Failed to seal file".to_string())?;
+    Ok(SealedFile {
+        version: SEALED_VERSION,
+        salt: hex::encode(salt),
+        iterations,
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+    })
+}
+
+/// Open a sealed file with a passphrase.
+pub fn open(passphrase: &str, sealed: &SealedFile) -> Result<Vec<u8>, String> {
+    if sealed.version != SEALED_VERSION {
+        return Err(MeshError::InvalidArgument.raise(format!("Unsupported sealed file version {}", sealed.version)));
+    }
+    let salt = hex::decode(&sealed.salt).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid salt: {}", e)))?;
+    let nonce = hex::decode(&sealed.nonce).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid nonce: {}", e)))?;
+    let ciphertext = hex::decode(&sealed.ciphertext).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid ciphertext: {}", e)))?;
+    if nonce.len() != NONCE_LEN {
+        return Err(MeshError::InvalidArgument.raise("Invalid nonce length"));
+    }
+    let key = derive_key(passphrase, &salt, sealed.iterations);
+    ChaCha20Poly1305::new(Key::from_slice(key.as_ref()))
+        .decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: &[SEALED_VERSION] })
+        .map_err(|_| MeshError::Crypto.raise("Wrong passphrase"))
+}
+
+/// Parse file contents as a sealed file (None for plain contents).
+pub fn parse_sealed(data: &[u8]) -> Option<SealedFile> {
+    serde_json::from_slice(data).ok()
+}
+
+fn read_sealed(path: &Path) -> Result<Option<SealedFile>, String> {
+    let data = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    Ok(parse_sealed(&data))
+}
+
+/// Write a file atomically (`<name>.tmp`, then rename), readable by us only.
+fn write_atomic(path: &Path, data: &[u8]) -> Result<(), String> {
+    let temp_path = path.with_extension("tmp");
+    let mut file = fs::File::create(&temp_path).map_err(|e| format!("Failed to create {}: {}", temp_path.display(), e))?;
+    file.write_all(data)
+        .map_err(|e| format!("Failed to write {}: {}", temp_path.display(), e))?;
+    file.sync_all()
+        .map_err(|e| format!("Failed to sync {}: {}", temp_path.display(), e))?;
+    drop(file);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&temp_path, fs::Permissions::from_mode(0o600))
+            .map_err(|e| format!("Failed to set file permissions: {}", e))?;
+    }
+    fs::rename(&temp_path, path).map_err(|e| format!("Failed to rename {}: {}", temp_path.display(), e))
+}
+
+fn write_sealed(path: &Path, sealed: &SealedFile) -> Result<(), String> {
+    let data = serde_json::to_vec(sealed).map_err(|e| format!("Failed to serialize sealed file: {}", e))?;
+    write_atomic(path, &data)
+}
+
+/// Whether the identity file in `dir` is sealed.
+pub fn is_protected(dir: &Path) -> bool {
+    fs::read(dir.join(IDENTITY_FILE)).ok().and_then(|d| parse_sealed(&d)).is_some()
+}
+
+pub fn status(dir: &Path) -> PassphraseStatus {
+    PassphraseStatus {
+        protected: is_protected(dir),
+        reencryption_pending: dir.join(JOURNAL_FILE).exists(),
+        reencryption_running: JOB_RUNNING.load(Ordering::SeqCst),
+    }
+}
+
+/// Set the first passphrase: seal the plain identity file and create a
+/// sealed database key.
+pub fn protect(dir: &Path, passphrase: &str, iterations: u32) -> Result<(), String> {
+    if passphrase.is_empty() {
+        return Err(MeshError::InvalidArgument.raise("Passphrase must not be empty"));
+    }
+    let identity_path = dir.join(IDENTITY_FILE);
+    let plain = Zeroizing::new(fs::read(&identity_path).map_err(|e| format!("Failed to read identity file: {}", e))?);
+    if parse_sealed(&plain).is_some() {
+        return Err(MeshError::Rejected.raise("A passphrase is already set; use change_passphrase"));
+    }
+
+    let db_key = random_key();
+    // Database key first: a crash in between leaves the identity plain
+    write_sealed(&dir.join(DB_KEY_FILE), &seal(passphrase, db_key.as_ref(), iterations)?)?;
+    write_sealed(&identity_path, &seal(passphrase, &plain, iterations)?)?;
+    *DB_KEYS.lock().unwrap() = vec![db_key];
+    Ok(())
+}
+
+fn random_key() -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    crate::rng::rng().fill_bytes(key.as_mut());
+    key
+}
+
+/// Keys in an opened database key file, newest first.
+fn parse_db_keys(plaintext: &[u8]) -> Result<Vec<Zeroizing<[u8; 32]>>, String> {
+    if plaintext.is_empty() || !plaintext.len().is_multiple_of(32) {
+        return Err(MeshError::InvalidArgument.raise("Invalid database key file"));
+    }
+    Ok(plaintext.chunks(32).map(|c| Zeroizing::new(c.try_into().unwrap())).collect())
+}
+
+/// Keys in the sealed database key file, newest first (empty if there is none).
+pub fn open_db_keys(dir: &Path, passphrase: &str) -> Result<Vec<Zeroizing<[u8; 32]>>, String> {
+    let path = dir.join(DB_KEY_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let sealed = read_sealed(&path)?.ok_or_else(|| MeshError::Rejected.raise("Database key is not passphrase protected"))?;
+    parse_db_keys(&Zeroizing::new(open(passphrase, &sealed)?))
+}
+
+/// Open the sealed database key, if there is one, and keep it for `db_keys`.
+pub fn unlock_db_keys(dir: &Path, passphrase: &str) -> Result<(), String> {
+    *DB_KEYS.lock().unwrap() = open_db_keys(dir, passphrase)?;
+    Ok(())
+}
+
+/// Database keys opened with the passphrase, newest first (empty without one).
+pub fn db_keys() -> Vec<Zeroizing<[u8; 32]>> {
+    DB_KEYS.lock().unwrap().clone()
+}
+
+/// Forget the database keys (the identity was unloaded).
+pub fn forget_db_keys() {
+    DB_KEYS.lock().unwrap().clear();
+}
+
+/// Replace the database key before a passphrase change: the new key is
+/// sealed under the current passphrase with the previous one after it, the
+/// open database is rekeyed with `rekey_database`, then the previous key is
+/// dropped. Nothing is done while a re-encryption job is pending (the key
+/// was replaced when it started).
+pub fn rotate_db_key(
+    dir: &Path,
+    passphrase: &str,
+    iterations: u32,
+    rekey_database: impl FnOnce(&[u8; 32]) -> Result<(), String>,
+) -> Result<(), String> {
+    if JOB_RUNNING.load(Ordering::SeqCst) {
+        return Err(MeshError::Rejected.raise("A re-encryption job is already running"));
+    }
+    if dir.join(JOURNAL_FILE).exists() {
+        return Ok(());
+    }
+    let path = dir.join(DB_KEY_FILE);
+    let previous = open_db_keys(dir, passphrase)?.into_iter().next().ok_or("No database key to replace")?;
+    let next = random_key();
+    let mut both = Zeroizing::new(next.to_vec());
+    both.extend_from_slice(previous.as_ref());
+    write_sealed(&path, &seal(passphrase, &both, iterations)?)?;
+    *DB_KEYS.lock().unwrap() = vec![next.clone(), previous];
+
+    rekey_database(&next)?;
+    write_sealed(&path, &seal(passphrase, next.as_ref(), iterations)?)?;
+    *DB_KEYS.lock().unwrap() = vec![next];
+    Ok(())
+}
+
+/// Identity key bytes from the sealed identity file.
+pub fn unlock_identity(dir: &Path, passphrase: &str) -> Result<Zeroizing<Vec<u8>>, String> {
+    let sealed = read_sealed(&dir.join(IDENTITY_FILE))?.ok_or_else(|| MeshError::Rejected.raise("Identity is not passphrase protected"))?;
+    open(passphrase, &sealed).map(Zeroizing::new)
+}
+
+/// Rewrap every protected file from `old` to `new`, resuming an interrupted
+/// job. `progress(done, total, file)` is called after each file.
+pub fn rekey(
+    dir: &Path,
+    old: &str,
+    new: &str,
+    iterations: u32,
+    mut progress: impl FnMut(usize, usize, &str),
+) -> Result<(), String> {
+    if new.is_empty() {
+        return Err(MeshError::InvalidArgument.raise("Passphrase must not be empty"));
+    }
+    let journal_path = dir.join(JOURNAL_FILE);
+    let mut journal: Journal = match fs::read(&journal_path) {
+        Ok(data) => serde_json::from_slice(&data).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid re-encryption journal: {}", e)))?,
+        Err(_) => Journal { started_at: crate::now_ts(), done: Vec::new() },
+    };
+    let write_journal = |journal: &Journal| {
+        let data = serde_json::to_vec(journal).map_err(|e| format!("Failed to serialize journal: {}", e))?;
+        write_atomic(&journal_path, &data)
+    };
+    write_journal(&journal)?;
+
+    for (i, name) in PROTECTED_FILES.iter().enumerate() {
+        if !journal.done.iter().any(|d| d == name) {
+            let path = dir.join(name);
+            let sealed = read_sealed(&path)?.ok_or_else(|| MeshError::Rejected.raise(format!("{} is not passphrase protected", name)))?;
+            // Already rewrapped before an interruption that lost the journal update
+            if open(new, &sealed).is_err() {
+                let plaintext = Zeroizing::new(open(old, &sealed).map_err(|_| format!("Old passphrase does not open {}", name))?);
+                write_sealed(&path, &seal(new, &plaintext, iterations)?)?;
+            }
+            journal.done.push(name.to_string());
+            write_journal(&journal)?;
+        }
+        progress(i + 1, PROTECTED_FILES.len(), name);
+    }
+
+    fs::remove_file(&journal_path).map_err(|e| format!("Failed to remove re-encryption journal: {}", e))
+}
+
+/// Start `rekey` on a background thread. Progress is reported as
+/// `reencryption_progress` events, the outcome as `reencryption_done` or
+/// `reencryption_failed`. The old (or, when resuming, new) passphrase is
+/// checked before the job starts.
+pub fn start_rekey(dir: PathBuf, old: String, new: String) -> Result<(), String> {
+    let sealed = read_sealed(&dir.join(IDENTITY_FILE))?.ok_or_else(|| MeshError::Rejected.raise("Identity is not passphrase protected"))?;
+    if open(&old, &sealed).is_err() && open(&new, &sealed).is_err() {
+        return Err(MeshError::Crypto.raise("Wrong passphrase"));
+    }
+    if JOB_RUNNING.swap(true, Ordering::SeqCst) {
+        return Err(MeshError::Rejected.raise("A re-encryption job is already running"));
+    }
+
+    std::thread::spawn(move || {
+        let outcome = rekey(&dir, &old, &new, KDF_ITERATIONS, |done, total, file| {
+            events::emit("reencryption_progress", json!({ "done": done, "total": total, "file": file }));
+        });
+        match outcome {
+            Ok(()) => events::emit("reencryption_done", json!({})),
+            Err(e) => events::emit("reencryption_failed", json!({ "error": e })),
+        }
+        JOB_RUNNING.store(false, Ordering::SeqCst);
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derive_key_is_pbkdf2_hmac_sha256() {
+        // RFC 7914 section 11, first 32 bytes
+        let expected = "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc";
+        assert_eq!(hex::encode(*derive_key("passwd", b"salt", 1)), expected);
+    }
+
+    #[test]
+    fn test_rekey_resumes_after_interruption() {
+        let dir = std::env::temp_dir().join(format!("meshapp-passphrase-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(IDENTITY_FILE), b"{\"keys\":1}").unwrap();
+
+        protect(&dir, "old", 10).unwrap();
+        assert!(protect(&dir, "again", 10).is_err());
+        assert_eq!(*unlock_identity(&dir, "old").unwrap(), b"{\"keys\":1}");
+
+        // Interrupted after the identity was rewrapped, before the journal noted it
+        write_sealed(&dir.join(IDENTITY_FILE), &seal("new", b"{\"keys\":1}", 10).unwrap()).unwrap();
+        let mut steps = Vec::new();
+        rekey(&dir, "old", "new", 10, |done, total, _| steps.push((done, total))).unwrap();
+        assert_eq!(steps, vec![(1, 2), (2, 2)]);
+
+        assert_eq!(*unlock_identity(&dir, "new").unwrap(), b"{\"keys\":1}");
+        assert!(unlock_identity(&dir, "old").is_err());
+        let db_key = read_sealed(&dir.join(DB_KEY_FILE)).unwrap().unwrap();
+        assert_eq!(open("new", &db_key).unwrap().len(), 32);
+        assert!(!status(&dir).reencryption_pending);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[test]
+    fn test_database_key_rotation_survives_interruption() {
+        use crate::storage::Storage;
+
+        let dir = std::env::temp_dir().join(format!("meshapp-passphrase-db-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(IDENTITY_FILE), b"{\"keys\":1}").unwrap();
+        let db_path = dir.join("mesh.db");
+        protect(&dir, "old", 10).unwrap();
+        let first = open_db_keys(&dir, "old").unwrap().swap_remove(0);
+        let storage = Storage::init_with_key(&db_path, &first).unwrap();
+        storage.upsert_channel([1u8; 32], "geo").unwrap();
+
+        // Interrupted before the database was rekeyed: the previous key still opens it
+        assert!(rotate_db_key(&dir, "old", 10, |_| Err("killed".to_string())).is_err());
+        let keys = open_db_keys(&dir, "old").unwrap();
+        assert_eq!((keys.len(), &keys[1]), (2, &first));
+        assert!(Storage::opens_with(&db_path, &keys[1]));
+
+        rotate_db_key(&dir, "old", 10, |key| storage.rekey(key)).unwrap();
+        drop(storage);
+        let keys = open_db_keys(&dir, "old").unwrap();
+        assert_eq!(keys.len(), 1);
+        assert!(Storage::opens_with(&db_path, &keys[0]) && !Storage::opens_with(&db_path, &first));
+
+        // The rotated key is what the passphrase change rewraps
+        rekey(&dir, "old", "new", 10, |_, _, _| {}).unwrap();
+        assert_eq!(open_db_keys(&dir, "new").unwrap(), keys);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+//! Peer capability cache
+//!
+//! Transports negotiate capabilities with a peer when they connect: protocol
+//! version, supported ciphers and largest frame (MTU). The result is kept per
+//! peer for `peers.capability_ttl_secs`, so a reconnect within that time can
+//! skip renegotiation. `get_peers` shows the cached set to debug interop.
+
+use crate::error::MeshError;
+use crate::settings::{self, PEERS_CAPABILITY_TTL_SECS};
+use crate::storage::{PeerCapabilitiesRow, Storage};
+use serde::{Deserialize, Serialize};
+
+/// Most cipher names per peer
+pub const MAX_CIPHERS: usize = 16;
+/// Longest cipher name
+pub const MAX_CIPHER_NAME_LEN: usize = 64;
+/// Smallest MTU a transport can report (the BLE ATT minimum)
+pub const MIN_MTU: u32 = 23;
+
+/// What was negotiated with a peer
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PeerCapabilities {
+    pub protocol_version: u32,
+    pub ciphers: Vec<String>,
+    pub max_mtu: u32,
+}
+
+/// Capabilities as cached, with when they were negotiated and expire
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CachedCapabilities {
+    #[serde(flatten)]
+    pub capabilities: PeerCapabilities,
+    pub negotiated_at: i64,
+    pub expires_at: i64,
+}
+
+impl PeerCapabilities {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.ciphers.len() > MAX_CIPHERS {
+            return Err(format!("At most {} ciphers per peer", MAX_CIPHERS));
+        }
+        if self.ciphers.iter().any(|c| c.is_empty() || c.len() > MAX_CIPHER_NAME_LEN) {
+            return Err(MeshError::InvalidArgument.raise(format!("Cipher names must be 1 to {} bytes", MAX_CIPHER_NAME_LEN)));
+        }
+        if self.max_mtu < MIN_MTU {
+            return Err(MeshError::InvalidArgument.raise(format!("max_mtu must be at least {}", MIN_MTU)));
+        }
+        Ok(())
+    }
+}
+
+/// Cache what was negotiated with a peer (and note the peer as seen).
+/// Returns when the entry expires.
+pub fn remember(storage: &Storage, peer_id: [u8; 32], capabilities: &PeerCapabilities, now: i64) -> Result<i64, String> {
+    capabilities.validate()?;
+    let ttl = settings::get_u64(storage, PEERS_CAPABILITY_TTL_SECS)?;
+    let expires_at = now.saturating_add(ttl.min(i64::MAX as u64) as i64);
+    let ciphers = serde_json::to_string(&capabilities.ciphers).map_err(|e| format!("Failed to serialize ciphers: {}", e))?;
+    storage.upsert_peer(peer_id, now)?;
+    storage.put_peer_capabilities(&PeerCapabilitiesRow {
+        peer_id,
+        protocol_version: capabilities.protocol_version,
+        ciphers,
+        max_mtu: capabilities.max_mtu,
+        negotiated_at: now,
+        expires_at,
+    })?;
+    Ok(expires_at)
+}
+
+/// Cached capabilities of a peer, unless they expired (expired entries are dropped).
+pub fn lookup(storage: &Storage, peer_id: [u8; 32], now: i64) -> Result<Option<CachedCapabilities>, String> {
+    let Some(row) = storage.get_peer_capabilities(peer_id)? else {
+        return Ok(None);
+    };
+    if row.expires_at <= now {
+        storage.delete_peer_capabilities(peer_id)?;
+        return Ok(None);
+    }
+    let ciphers = serde_json::from_str(&row.ciphers).map_err(|e| format!("Invalid cached ciphers: {}", e))?;
+    Ok(Some(CachedCapabilities {
+        capabilities: PeerCapabilities { protocol_version: row.protocol_version, ciphers, max_mtu: row.max_mtu },
+        negotiated_at: row.negotiated_at,
+        expires_at: row.expires_at,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities_expire_after_ttl() {
+        let storage = Storage::in_memory().unwrap();
+        settings::set_value(&storage, PEERS_CAPABILITY_TTL_SECS, serde_json::json!(60)).unwrap();
+        let caps = PeerCapabilities { protocol_version: 2, ciphers: vec!["ChaChaPoly".to_string()], max_mtu: 185 };
+
+        assert_eq!(remember(&storage, [1u8; 32], &caps, 100).unwrap(), 160);
+        assert_eq!(lookup(&storage, [1u8; 32], 159).unwrap().unwrap().capabilities, caps);
+        assert_eq!(lookup(&storage, [1u8; 32], 160).unwrap(), None);
+        assert_eq!(storage.get_peer_capabilities([1u8; 32]).unwrap(), None);
+        assert_eq!(storage.list_peers().unwrap().len(), 1);
+        assert!(remember(&storage, [1u8; 32], &PeerCapabilities { max_mtu: 1, ..caps }, 100).is_err());
+    }
+}
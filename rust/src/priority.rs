@@ -0,0 +1,139 @@
+//! Message priority
+//!
+//! Senders mark a message urgent, normal or background. The mark travels in
+//! the packet header (`Packet::priority`, outside the encrypted payload, so
+//! relays act on it too) and is stored with the message for display:
+//! - urgent packets skip batching and go ahead of normal ones in the ingest
+//!   queue, the batcher and the router's held packets (behind SOS messages),
+//!   and are shed last after SOS
+//! - background packets are shed first, with bulk transfers
+//! - notifications: urgent messages break through mention-only channels and
+//!   quiet hours (not a mute); background ones never notify
+//!
+//! The app sets the priority of the next message it sends from a thread with
+//! `set_next`; the send that takes it resets it to normal.
+
+use crate::error::MeshError;
+use crate::sos;
+use crate::transport::Packet;
+use std::cell::Cell;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Priority {
+    Background = 0,
+    #[default]
+    Normal = 1,
+    Urgent = 2,
+}
+
+impl Priority {
+    /// Unknown values (from newer nodes) count as normal.
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Priority::Background,
+            2 => Priority::Urgent,
+            _ => Priority::Normal,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Priority::Background => "background",
+            Priority::Normal => "normal",
+            Priority::Urgent => "urgent",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "background" => Ok(Priority::Background),
+            "normal" => Ok(Priority::Normal),
+            "urgent" => Ok(Priority::Urgent),
+            _ => Err(MeshError::InvalidArgument.raise(format!("Invalid priority '{}'", name))),
+        }
+    }
+}
+
+/// Shed rank of urgent packets: above every packet kind, below SOS
+/// (see `memory_budget::shed_rank`)
+pub const URGENT_SHED_RANK: u8 = sos::SOS_SHED_RANK - 1;
+
+/// Shed rank of background packets: with bulk transfers
+pub const BACKGROUND_SHED_RANK: u8 = 0;
+
+thread_local! {
+    static NEXT: Cell<Option<Priority>> = const { Cell::new(None) };
+}
+
+/// Set the priority of the next message sent from this thread.
+pub fn set_next(priority: Priority) {
+    NEXT.with(|next| next.set(Some(priority)));
+}
+
+/// Take the priority set for this send, if any (the next one is normal again).
+pub fn take_next() -> Option<Priority> {
+    NEXT.with(|next| next.take())
+}
+
+/// Queue class: SOS, then urgent packets, then everything else.
+fn queue_class(packet: &Packet) -> u8 {
+    if sos::is_sos(packet) {
+        2
+    } else if packet.priority == Priority::Urgent {
+        1
+    } else {
+        0
+    }
+}
+
+/// Whether a packet goes ahead of others in queues (and skips batching).
+pub fn jumps_queue(packet: &Packet) -> bool {
+    queue_class(packet) > 0
+}
+
+/// Index at which a packet that jumps the queue joins it: behind packets of
+/// its class or above, ahead of the rest.
+pub fn queue_index<'a>(packets: impl IntoIterator<Item = &'a Packet>, packet: &Packet) -> usize {
+    let class = queue_class(packet);
+    packets.into_iter().take_while(|p| queue_class(p) >= class).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_budget;
+    use crate::optimization::PacketBatcher;
+    use crate::transport::PacketKind;
+
+    #[test]
+    fn test_urgent_packets_go_ahead_of_normal_ones() {
+        let packet = |id: u8, priority| Packet {
+            packet_id: [id; 32],
+            channel_id: [2u8; 32],
+            kind: PacketKind::Message,
+            ttl: 3,
+            payload: vec![id],
+            priority,
+        };
+        let batcher = PacketBatcher::new(10, 60);
+        assert!(!batcher.add(packet(1, Priority::Normal)));
+        assert!(!batcher.add(packet(2, Priority::Background)));
+        // Urgent packets flush at once, behind SOS, ahead of the rest
+        assert!(batcher.add(packet(3, Priority::Urgent)));
+        assert!(batcher.add(sos::packet([4u8; 32], "help").unwrap()));
+        assert!(batcher.add(packet(5, Priority::Urgent)));
+        let order: Vec<u8> = batcher.take_batch().iter().map(|p| p.packet_id[0]).collect();
+        assert_eq!(order, vec![4, 3, 5, 1, 2]);
+
+        assert_eq!(memory_budget::packet_rank(&packet(1, Priority::Background)), BACKGROUND_SHED_RANK);
+        assert_eq!(memory_budget::packet_rank(&packet(1, Priority::Urgent)), URGENT_SHED_RANK);
+
+        assert_eq!(take_next(), None);
+        set_next(Priority::parse("urgent").unwrap());
+        assert_eq!(take_next(), Some(Priority::Urgent));
+        assert_eq!(take_next(), None);
+        assert_eq!(Priority::from_u8(9), Priority::Normal);
+        assert!(Priority::parse("high").is_err());
+    }
+}
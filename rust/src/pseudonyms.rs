@@ -0,0 +1,170 @@
+//! Channel-scoped pseudonyms
+//!
+//! In public channels (geo channels, and open-profile channels we have not
+//! registered) the user can speak under a pseudonym instead of their
+//! identity key: an Ed25519 key pair derived from the identity's signing
+//! key, the channel id and a generation number. Without the identity key
+//! nobody can link two pseudonyms, whether of two channels or two
+//! generations of one channel, to each other or to the identity. The key
+//! pairs are never stored; they are derived again whenever they sign.
+//!
+//! `pseudonyms` records the public keys in use:
+//! - `current` is a channel's pseudonym, created (generation 0) on first use
+//! - `regenerate` retires it and starts the next generation; retired ones
+//!   are kept so replies to what they said still resolve to us
+//!
+//! Messages signed under a pseudonym are recorded, so a reply goes out under
+//! the pseudonym that wrote the message it answers (`for_reply`), and
+//! `resolve` tells which of our pseudonyms a key addressed by another node is.
+//! Geo channel messages are signed with them (see `geo_messages`).
+
+use crate::identity::Identity;
+use crate::storage::{PseudonymRow, Storage};
+use ed25519_dalek::SigningKey;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Channel types pseudonyms can be used in (unregistered channels too)
+pub const PUBLIC_CHANNEL_TYPES: &[&str] = &["geo"];
+
+/// A pseudonym as reported to the app
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Pseudonym {
+    pub channel_id: String,
+    pub generation: u32,
+    pub ed25519_public: String,
+    /// SHA-256 of the key, like a user id
+    pub user_id: String,
+    pub created_at: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retired_at: Option<i64>,
+}
+
+impl From<&PseudonymRow> for Pseudonym {
+    fn from(row: &PseudonymRow) -> Self {
+        Self {
+            channel_id: hex::encode(row.channel_id),
+            generation: row.generation,
+            ed25519_public: hex::encode(row.ed25519_public),
+            user_id: hex::encode(Sha256::digest(row.ed25519_public)),
+            created_at: row.created_at,
+            retired_at: row.retired_at,
+        }
+    }
+}
+
+/// The signing key of a pseudonym.
+pub fn derive(identity: &Identity, channel_id: &[u8; 32], generation: u32) -> SigningKey {
+    let mut hasher = Sha256::new();
+    hasher.update(b"meshapp-pseudonym-v1");
+    hasher.update(identity.ed25519_signing_key().to_bytes());
+    hasher.update(channel_id);
+    hasher.update(generation.to_be_bytes());
+    SigningKey::from_bytes(&hasher.finalize().into())
+}
+
+fn ensure_public(storage: &Storage, channel_id: [u8; 32]) -> Result<(), String> {
+    match storage.get_channel_type(channel_id)? {
+        Some(t) if !PUBLIC_CHANNEL_TYPES.contains(&t.as_str()) => {
+            Err(format!("Pseudonyms are for public channels, not {} channels", t))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn create(identity: &Identity, storage: &Storage, channel_id: [u8; 32], generation: u32, now: i64) -> Result<PseudonymRow, String> {
+    let row = PseudonymRow {
+        channel_id,
+        generation,
+        ed25519_public: derive(identity, &channel_id, generation).verifying_key().to_bytes(),
+        created_at: now,
+        retired_at: None,
+    };
+    storage.insert_pseudonym(&row)?;
+    Ok(row)
+}
+
+/// A channel's current pseudonym (created on first use).
+pub fn current(identity: &Identity, storage: &Storage, channel_id: [u8; 32], now: i64) -> Result<PseudonymRow, String> {
+    ensure_public(storage, channel_id)?;
+    match storage.latest_pseudonym(channel_id)? {
+        Some(row) if row.retired_at.is_none() => Ok(row),
+        Some(row) => create(identity, storage, channel_id, row.generation + 1, now),
+        None => create(identity, storage, channel_id, 0, now),
+    }
+}
+
+/// Retire a channel's pseudonym and start a new one.
+pub fn regenerate(identity: &Identity, storage: &Storage, channel_id: [u8; 32], now: i64) -> Result<PseudonymRow, String> {
+    ensure_public(storage, channel_id)?;
+    storage.retire_pseudonym(channel_id, now)?;
+    current(identity, storage, channel_id, now)
+}
+
+/// The pseudonym to answer `in_reply_to` with: the one that wrote it if it
+/// is ours, else the channel's current one. Returns it with its signing key.
+pub fn for_reply(
+    identity: &Identity,
+    storage: &Storage,
+    channel_id: [u8; 32],
+    in_reply_to: Option<[u8; 32]>,
+    now: i64,
+) -> Result<(PseudonymRow, SigningKey), String> {
+    let wrote = match in_reply_to {
+        Some(message_id) => storage.pseudonym_of_message(message_id, channel_id)?,
+        None => None,
+    };
+    let row = match wrote.map(|generation| storage.get_pseudonym(channel_id, generation)).transpose()?.flatten() {
+        Some(row) => row,
+        None => current(identity, storage, channel_id, now)?,
+    };
+    let key = derive(identity, &channel_id, row.generation);
+    if key.verifying_key().to_bytes() != row.ed25519_public {
+        return Err("Pseudonym belongs to another identity".to_string());
+    }
+    Ok((row, key))
+}
+
+/// Remember that a pseudonym wrote a message, for `for_reply`.
+pub fn record_sent(storage: &Storage, message_id: [u8; 32], row: &PseudonymRow) -> Result<(), String> {
+    storage.record_pseudonym_message(message_id, row.channel_id, row.generation)
+}
+
+/// Which of our pseudonyms a key is, if any.
+pub fn resolve(storage: &Storage, ed25519_public: [u8; 32]) -> Result<Option<PseudonymRow>, String> {
+    storage.find_pseudonym(ed25519_public)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pseudonyms_are_unlinkable_and_answer_replies() {
+        let storage = Storage::in_memory().unwrap();
+        let identity = Identity::generate();
+        let (geo, other_geo, dm) = ([1u8; 32], [2u8; 32], [3u8; 32]);
+        storage.upsert_channel(geo, "geo").unwrap();
+        storage.upsert_channel(dm, "dm").unwrap();
+
+        let first = current(&identity, &storage, geo, 100).unwrap();
+        assert_eq!(current(&identity, &storage, geo, 200).unwrap(), first);
+        let elsewhere = current(&identity, &storage, other_geo, 100).unwrap();
+        assert_ne!(first.ed25519_public, elsewhere.ed25519_public);
+        assert_ne!(&first.ed25519_public, identity.public().ed25519_public.as_bytes());
+        assert!(current(&identity, &storage, dm, 100).is_err());
+
+        // A reply to a message of the retired pseudonym goes out under it
+        let (row, _) = for_reply(&identity, &storage, geo, None, 300).unwrap();
+        record_sent(&storage, [9u8; 32], &row).unwrap();
+        let second = regenerate(&identity, &storage, geo, 400).unwrap();
+        assert_eq!(second.generation, 1);
+        assert_eq!(for_reply(&identity, &storage, geo, Some([9u8; 32]), 500).unwrap().0.generation, 0);
+        assert_eq!(for_reply(&identity, &storage, geo, Some([8u8; 32]), 500).unwrap().0.generation, 1);
+        assert_eq!(resolve(&storage, first.ed25519_public).unwrap().unwrap().retired_at, Some(400));
+        assert!(resolve(&storage, [7u8; 32]).unwrap().is_none());
+
+        // Another identity cannot sign for these pseudonyms
+        assert!(for_reply(&Identity::generate(), &storage, geo, None, 600).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! Multi-part QR export
+//!
+//! Payloads too large for one QR code (identity bundles with prekeys, group
+//! invites) are split into frames shown as an animated QR sequence:
+//!
+//! `MQR1/<payload_id>/<index>/<total>/<data>`
+//!
+//! - payload_id: first 8 bytes of SHA256(payload), hex; identifies the
+//!   sequence and checks the reassembled payload
+//! - index (1-based) / total: position in the sequence
+//! - data: this frame's slice of the payload, base64url
+//!
+//! Frames can be scanned in any order and repeated; the importer reports
+//! which ones are still missing.
+
+use crate::error::MeshError;
+use crate::uri::{base64url_decode, base64url_encode};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+const FRAME_PREFIX: &str = "MQR1";
+
+/// Bounds on payload bytes per frame
+pub const MIN_CHUNK_SIZE: usize = 16;
+pub const MAX_CHUNK_SIZE: usize = 2048;
+
+/// Most frames in one sequence
+const MAX_FRAMES: usize = 256;
+
+/// Sequences being scanned, keyed by payload_id
+static IMPORTS: Lazy<Mutex<HashMap<String, Assembly>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct Assembly {
+    frames: Vec<Option<Vec<u8>>>,
+}
+
+/// Import progress after scanning a frame
+#[derive(Serialize, Debug)]
+pub struct ImportStatus {
+    pub payload_id: String,
+    pub received: usize,
+    pub total: usize,
+    /// 1-based indices not yet scanned
+    pub missing: Vec<usize>,
+    /// Set once every frame has been scanned
+    pub payload: Option<String>,
+}
+
+/// Split a payload into QR frames carrying up to `chunk_size` bytes each.
+pub fn export_frames(payload: &str, chunk_size: usize) -> Result<Vec<String>, String> {
+    if payload.is_empty() {
+        return Err(MeshError::InvalidArgument.raise("Payload is empty"));
+    }
+    let chunk_size = chunk_size.clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE);
+    let total = payload.len().div_ceil(chunk_size);
+    if total > MAX_FRAMES {
+        return Err(format!("Payload needs {} frames (max {})", total, MAX_FRAMES));
+    }
+
+    let id = payload_id(payload.as_bytes());
+    Ok(payload
+        .as_bytes()
+        .chunks(chunk_size)
+        .enumerate()
+        .map(|(i, chunk)| format!("{}/{}/{}/{}/{}", FRAME_PREFIX, id, i + 1, total, base64url_encode(chunk)))
+        .collect())
+}
+
+/// Add a scanned frame to its sequence. When the last missing frame arrives
+/// the payload is verified, returned and the sequence is forgotten.
+pub fn import_frame(frame: &str) -> Result<ImportStatus, String> {
+    let parts: Vec<&str> = frame.trim().split('/').collect();
+    if parts.len() != 5 || parts[0] != FRAME_PREFIX {
+        return Err(MeshError::Rejected.raise("Not a multi-part QR frame"));
+    }
+    let id = parts[1].to_lowercase();
+    if id.len() != 16 || hex::decode(&id).is_err() {
+        return Err(MeshError::InvalidArgument.raise("Invalid frame payload id"));
+    }
+    let index: usize = parts[2].parse().map_err(|_| MeshError::InvalidArgument.raise("Invalid frame index"))?;
+    let total: usize = parts[3].parse().map_err(|_| MeshError::InvalidArgument.raise("Invalid frame count"))?;
+    if total == 0 || total > MAX_FRAMES || index == 0 || index > total {
+        return Err(MeshError::InvalidArgument.raise(format!("Frame {}/{} out of range", index, total)));
+    }
+    let data = base64url_decode(parts[4])?;
+
+    let mut imports = IMPORTS.lock().unwrap();
+    let assembly = imports.entry(id.clone()).or_insert_with(|| Assembly {
+        frames: vec![None; total],
+    });
+    if assembly.frames.len() != total {
+        return Err("Frame count does not match earlier frames".to_string());
+    }
+    assembly.frames[index - 1] = Some(data);
+
+    let missing: Vec<usize> = assembly
+        .frames
+        .iter()
+        .enumerate()
+        .filter(|(_, f)| f.is_none())
+        .map(|(i, _)| i + 1)
+        .collect();
+    let mut status = ImportStatus {
+        payload_id: id.clone(),
+        received: total - missing.len(),
+        total,
+        missing,
+        payload: None,
+    };
+    if !status.missing.is_empty() {
+        return Ok(status);
+    }
+
+    let bytes: Vec<u8> = assembly.frames.iter().flatten().flatten().copied().collect();
+    imports.remove(&id);
+    if payload_id(&bytes) != id {
+        return Err("Reassembled payload does not match its id".to_string());
+    }
+    status.payload = Some(String::from_utf8(bytes).map_err(|_| MeshError::InvalidArgument.raise("Payload is not UTF-8"))?);
+    Ok(status)
+}
+
+/// Forget all partially scanned sequences (e.g. when the scanner closes).
+pub fn reset_imports() {
+    IMPORTS.lock().unwrap().clear();
+}
+
+fn payload_id(payload: &[u8]) -> String {
+    hex::encode(&Sha256::digest(payload)[..8])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_reassemble_out_of_order() {
+        let payload = "mesh://join/".to_string() + &"x".repeat(100);
+        let frames = export_frames(&payload, 40).unwrap();
+        assert_eq!(frames.len(), 3);
+
+        let status = import_frame(&frames[2]).unwrap();
+        assert_eq!(status.missing, vec![1, 2]);
+        import_frame(&frames[2]).unwrap();
+        import_frame(&frames[0]).unwrap();
+        let status = import_frame(&frames[1]).unwrap();
+        assert_eq!(status.payload.as_deref(), Some(payload.as_str()));
+    }
+}
@@ -0,0 +1,367 @@
+//! Double Ratchet for DMs
+//!
+//! A Noise session alone (see `dm_crypto::DmSessionManager`) encrypts every
+//! message of a conversation under the one key of its split, so whoever
+//! steals a static key later opens everything captured before. Each Noise
+//! session now seeds a Double Ratchet (the Signal spec, with header
+//! encryption) instead:
+//! - the root key and the first two header keys come from the split
+//! - the initiator's first ratchet key pair meets the friend's static X25519
+//!   key; every reply from the other side takes a new DH ratchet step
+//! - every message has its own key from a symmetric chain, deleted once used
+//! - headers (ratchet public key and counters) are encrypted, so relays
+//!   cannot tell which messages belong to which ratchet step
+//! - keys skipped on the way to a later message are kept for when the
+//!   earlier ones arrive (at most `MAX_SKIP` per chain, `MAX_SKIPPED_KEYS`
+//!   per ratchet)
+//!
+//! Messages sent before the friend's first reply are only as safe as the
+//! Noise handshake and the initiator's ratchet key.
+//!
+//! Stored DMs are decrypted again whenever history is read, so the key of
+//! each stored message is kept with it in `dm_message_keys` and deleted with
+//! it. Forward secrecy covers what went over the air, not what the device
+//! still shows.
+//!
+//! Ratchet message (after the Noise handshake, see `dm_crypto`): encrypted
+//! header (nonce 12 || ChaCha20-Poly1305 of dh (32) || previous chain count
+//! (u32 BE) || count (u32 BE)) || ChaCha20-Poly1305 ciphertext under the
+//! message key (zero nonce, AD = channel id || encrypted header).
+
+use crate::dm_crypto::NoiseSplit;
+use crate::error::MeshError;
+use crate::storage::{DmRatchetRow, Storage};
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Most message keys skipped in one chain for a later message
+pub const MAX_SKIP: u32 = 1000;
+
+/// Most skipped message keys kept per ratchet (the oldest go first)
+pub const MAX_SKIPPED_KEYS: u32 = 2000;
+
+const HEADER_LEN: usize = 32 + 4 + 4;
+
+/// Encrypted header bytes: nonce || header || tag
+pub const ENCRYPTED_HEADER_LEN: usize = 12 + HEADER_LEN + 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    dh: [u8; 32],
+    prev_count: u32,
+    count: u32,
+}
+
+/// A decrypted ratchet message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Opened {
+    pub plaintext: Vec<u8>,
+    pub message_key: [u8; 32],
+}
+
+fn hash_kdf(label: &[u8], index: u8, inputs: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(label);
+    hasher.update([index]);
+    for input in inputs {
+        hasher.update(input);
+    }
+    hasher.finalize().into()
+}
+
+/// Root key, initiator's header key and responder's next header key from a
+/// Noise split.
+fn seed(split: &NoiseSplit) -> ([u8; 32], [u8; 32], [u8; 32]) {
+    let derive = |i| hash_kdf(b"meshapp-ratchet-seed", i, &[&split.0, &split.1]);
+    (derive(0), derive(1), derive(2))
+}
+
+/// KDF_RK: new root key, chain key and next header key.
+fn kdf_root(root_key: &[u8; 32], dh_out: &[u8; 32]) -> ([u8; 32], [u8; 32], [u8; 32]) {
+    let derive = |i| hash_kdf(b"meshapp-ratchet-root", i, &[root_key, dh_out]);
+    (derive(0), derive(1), derive(2))
+}
+
+/// KDF_CK: next chain key and message key.
+fn kdf_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    (
+        hash_kdf(b"meshapp-ratchet-chain", 0, &[chain_key]),
+        hash_kdf(b"meshapp-ratchet-chain", 1, &[chain_key]),
+    )
+}
+
+fn dh(secret: &[u8; 32], public: &[u8; 32]) -> [u8; 32] {
+    StaticSecret::from(*secret).diffie_hellman(&PublicKey::from(*public)).to_bytes()
+}
+
+fn public_of(secret: &[u8; 32]) -> [u8; 32] {
+    PublicKey::from(&StaticSecret::from(*secret)).to_bytes()
+}
+
+fn generate_dh() -> [u8; 32] {
+    StaticSecret::random_from_rng(crate::rng::rng()).to_bytes()
+}
+
+fn seal_header(header_key: &[u8; 32], header: &Header) -> Result<Vec<u8>, String> {
+    let mut plain = Vec::with_capacity(HEADER_LEN);
+    plain.extend_from_slice(&header.dh);
+    plain.extend_from_slice(&header.prev_count.to_be_bytes());
+    plain.extend_from_slice(&header.count.to_be_bytes());
+    let mut nonce = [0u8; 12];
+    crate::rng::rng().fill_bytes(&mut nonce);
+    let sealed = ChaCha20Poly1305::new(header_key.into())
+        .encrypt(&nonce.into(), plain.as_slice())
+        .map_err(|e| MeshError::Crypto.raise(format!("Header encryption failed: {}", e)))?;
+    Ok([&nonce[..], &sealed].concat())
+}
+
+fn open_header(header_key: &[u8; 32], encrypted: &[u8]) -> Option<Header> {
+    let (nonce, sealed) = encrypted.split_at_checked(12)?;
+    let plain = ChaCha20Poly1305::new(header_key.into())
+        .decrypt(nonce.into(), sealed)
+        .ok()?;
+    Some(Header {
+        dh: crate::codec::read_array(&plain, 0, "ratchet key").ok()?,
+        prev_count: crate::codec::read_array(&plain, 32, "previous count").map(u32::from_be_bytes).ok()?,
+        count: crate::codec::read_array(&plain, 36, "count").map(u32::from_be_bytes).ok()?,
+    })
+}
+
+fn message_ad(channel_id: &[u8; 32], encrypted_header: &[u8]) -> Vec<u8> {
+    [&channel_id[..], encrypted_header].concat()
+}
+
+/// Open a ratchet message with a known message key (e.g. a stored one).
+pub fn open_with_key(message_key: &[u8; 32], channel_id: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() < ENCRYPTED_HEADER_LEN + 16 {
+        return Err(MeshError::InvalidArgument.raise("Ratchet message is truncated"));
+    }
+    let (header, ciphertext) = data.split_at(ENCRYPTED_HEADER_LEN);
+    ChaCha20Poly1305::new(message_key.into())
+        .decrypt(&[0u8; 12].into(), Payload { msg: ciphertext, aad: &message_ad(channel_id, header) })
+        .map_err(|e| MeshError::Crypto.raise(format!("Decryption failed: {}", e)))
+}
+
+/// A ratchet we seed by sending first, with the friend's static key as
+/// their first ratchet key.
+pub fn initiate(
+    ratchet_id: [u8; 32],
+    channel_id: [u8; 32],
+    remote_static: [u8; 32],
+    handshake: Vec<u8>,
+    split: &NoiseSplit,
+    now: i64,
+) -> DmRatchetRow {
+    let (root_key, header_key, next_recv_header_key) = seed(split);
+    let dh_secret = generate_dh();
+    let (root_key, send_chain, next_send_header_key) = kdf_root(&root_key, &dh(&dh_secret, &remote_static));
+    DmRatchetRow {
+        ratchet_id,
+        channel_id,
+        initiator: true,
+        remote_static,
+        handshake,
+        root_key,
+        dh_secret,
+        dh_remote: Some(remote_static),
+        send_chain: Some(send_chain),
+        recv_chain: None,
+        send_count: 0,
+        recv_count: 0,
+        prev_send_count: 0,
+        send_header_key: Some(header_key),
+        recv_header_key: None,
+        next_send_header_key,
+        next_recv_header_key,
+        received_at: None,
+        created_at: now,
+    }
+}
+
+/// A ratchet the friend seeded; it can send once a message arrived on it.
+pub fn respond(
+    ratchet_id: [u8; 32],
+    channel_id: [u8; 32],
+    remote_static: [u8; 32],
+    handshake: Vec<u8>,
+    split: &NoiseSplit,
+    local_static_secret: [u8; 32],
+    now: i64,
+) -> DmRatchetRow {
+    let (root_key, header_key, next_send_header_key) = seed(split);
+    DmRatchetRow {
+        ratchet_id,
+        channel_id,
+        initiator: false,
+        remote_static,
+        handshake,
+        root_key,
+        dh_secret: local_static_secret,
+        dh_remote: None,
+        send_chain: None,
+        recv_chain: None,
+        send_count: 0,
+        recv_count: 0,
+        prev_send_count: 0,
+        send_header_key: None,
+        recv_header_key: None,
+        next_send_header_key,
+        next_recv_header_key: header_key,
+        received_at: None,
+        created_at: now,
+    }
+}
+
+/// Encrypt the next message of a ratchet and keep its key as a stored message's.
+pub fn encrypt(storage: &Storage, ratchet: &DmRatchetRow, message_id: [u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    let mut state = ratchet.clone();
+    let (Some(chain), Some(header_key)) = (state.send_chain, state.send_header_key) else {
+        return Err(MeshError::Rejected.raise("DM ratchet cannot send before a message arrived on it"));
+    };
+    let (next_chain, message_key) = kdf_chain(&chain);
+    let header = Header { dh: public_of(&state.dh_secret), prev_count: state.prev_send_count, count: state.send_count };
+    let mut out = seal_header(&header_key, &header)?;
+    let ciphertext = ChaCha20Poly1305::new((&message_key).into())
+        .encrypt(&[0u8; 12].into(), Payload { msg: plaintext, aad: &message_ad(&state.channel_id, &out) })
+        .map_err(|e| MeshError::Crypto.raise(format!("Encryption failed: {}", e)))?;
+    out.extend_from_slice(&ciphertext);
+    state.send_chain = Some(next_chain);
+    state.send_count += 1;
+
+    storage.with_transaction(|s| {
+        s.put_dm_ratchet(&state)?;
+        s.put_dm_message_key(message_id, state.channel_id, true, message_key)
+    })?;
+    Ok(out)
+}
+
+/// Advance the receiving chain to `until`, keeping the keys passed over.
+fn skip(state: &mut DmRatchetRow, until: u32, skipped: &mut Vec<([u8; 32], u32, [u8; 32])>) -> Result<(), String> {
+    let (Some(mut chain), Some(header_key)) = (state.recv_chain, state.recv_header_key) else {
+        return Ok(());
+    };
+    if until > state.recv_count.saturating_add(MAX_SKIP) {
+        return Err(MeshError::Rejected.raise("Too many DM messages skipped"));
+    }
+    while state.recv_count < until {
+        let (next_chain, message_key) = kdf_chain(&chain);
+        skipped.push((header_key, state.recv_count, message_key));
+        chain = next_chain;
+        state.recv_count += 1;
+    }
+    state.recv_chain = Some(chain);
+    Ok(())
+}
+
+/// A DH ratchet step on the friend's new ratchet key.
+fn dh_step(state: &mut DmRatchetRow, remote: [u8; 32]) {
+    state.prev_send_count = state.send_count;
+    state.send_count = 0;
+    state.recv_count = 0;
+    state.send_header_key = Some(state.next_send_header_key);
+    state.recv_header_key = Some(state.next_recv_header_key);
+    state.dh_remote = Some(remote);
+    let (root_key, recv_chain, next_recv_header_key) = kdf_root(&state.root_key, &dh(&state.dh_secret, &remote));
+    state.recv_chain = Some(recv_chain);
+    state.next_recv_header_key = next_recv_header_key;
+    state.dh_secret = generate_dh();
+    let (root_key, send_chain, next_send_header_key) = kdf_root(&root_key, &dh(&state.dh_secret, &remote));
+    state.root_key = root_key;
+    state.send_chain = Some(send_chain);
+    state.next_send_header_key = next_send_header_key;
+}
+
+/// Decrypt a message of a ratchet and keep its key as a stored message's.
+/// Nothing changes unless it opens.
+pub fn decrypt(storage: &Storage, ratchet: &DmRatchetRow, message_id: [u8; 32], data: &[u8], now: i64) -> Result<Opened, String> {
+    let channel_id = ratchet.channel_id;
+    let encrypted_header = data.get(..ENCRYPTED_HEADER_LEN).ok_or_else(|| MeshError::InvalidArgument.raise("Ratchet message is truncated"))?;
+
+    // A message whose key was skipped earlier (its chain may still be the
+    // current one, so a miss falls through)
+    for header_key in storage.skipped_dm_header_keys(ratchet.ratchet_id)? {
+        let Some(header) = open_header(&header_key, encrypted_header) else {
+            continue;
+        };
+        let opened = storage.with_transaction(|s| {
+            let Some(message_key) = s.take_skipped_dm_key(ratchet.ratchet_id, header_key, header.count)? else {
+                return Ok(None);
+            };
+            let plaintext = open_with_key(&message_key, &channel_id, data)?;
+            s.put_dm_message_key(message_id, channel_id, false, message_key)?;
+            Ok(Some(Opened { plaintext, message_key }))
+        })?;
+        if let Some(opened) = opened {
+            return Ok(opened);
+        }
+    }
+
+    let mut state = ratchet.clone();
+    let current = state.recv_header_key.and_then(|key| open_header(&key, encrypted_header));
+    let (header, new_step) = match current {
+        Some(header) => (header, false),
+        None => (open_header(&state.next_recv_header_key, encrypted_header).ok_or("DM message header does not open")?, true),
+    };
+    let mut skipped = Vec::new();
+    if new_step {
+        skip(&mut state, header.prev_count, &mut skipped)?;
+        dh_step(&mut state, header.dh);
+    } else if header.count < state.recv_count {
+        return Err(MeshError::Rejected.raise("DM message key already used"));
+    }
+    skip(&mut state, header.count, &mut skipped)?;
+    let (next_chain, message_key) = kdf_chain(&state.recv_chain.ok_or("DM ratchet has no receiving chain")?);
+    let plaintext = open_with_key(&message_key, &channel_id, data)?;
+    state.recv_chain = Some(next_chain);
+    state.recv_count += 1;
+    state.received_at = Some(now);
+
+    storage.with_transaction(|s| {
+        s.put_dm_ratchet(&state)?;
+        for &(header_key, counter, key) in &skipped {
+            s.add_skipped_dm_key(state.ratchet_id, header_key, counter, key, now, MAX_SKIPPED_KEYS)?;
+        }
+        s.put_dm_message_key(message_id, channel_id, false, message_key)
+    })?;
+    Ok(Opened { plaintext, message_key })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ratchet_steps_and_handles_out_of_order_messages() {
+        let (a, b) = (Storage::in_memory().unwrap(), Storage::in_memory().unwrap());
+        let (channel, split, bob_static) = ([5u8; 32], ([1u8; 32], [2u8; 32]), [3u8; 32]);
+        a.put_dm_ratchet(&initiate([9u8; 32], channel, public_of(&bob_static), vec![], &split, 100)).unwrap();
+        b.put_dm_ratchet(&respond([9u8; 32], channel, [4u8; 32], vec![], &split, bob_static, 100)).unwrap();
+        let ratchet = |s: &Storage| s.get_dm_ratchet([9u8; 32]).unwrap().unwrap();
+        let send = |s: &Storage, id: u8, text: &[u8]| encrypt(s, &ratchet(s), [id; 32], text).unwrap();
+        let recv = |s: &Storage, id: u8, data: &[u8]| decrypt(s, &ratchet(s), [id; 32], data, 200).map(|o| o.plaintext);
+
+        // Bob cannot send before Alice's first message arrives
+        assert!(encrypt(&b, &ratchet(&b), [1u8; 32], b"early").is_err());
+        let (m1, m2, m3) = (send(&a, 1, b"one"), send(&a, 2, b"two"), send(&a, 3, b"three"));
+        assert_eq!(recv(&b, 3, &m3).unwrap(), b"three");
+        let reply = send(&b, 4, b"reply");
+        assert_eq!(recv(&a, 4, &reply).unwrap(), b"reply");
+        // A new DH step on Alice's side; the skipped messages still open
+        let m5 = send(&a, 5, b"five");
+        assert_eq!(ratchet(&a).prev_send_count, 3);
+        assert_eq!(recv(&b, 5, &m5).unwrap(), b"five");
+        assert_eq!(recv(&b, 1, &m1).unwrap(), b"one");
+        assert_eq!(recv(&b, 2, &m2).unwrap(), b"two");
+
+        // Each key opens once; stored ones read history again
+        assert!(recv(&b, 6, &m2).is_err());
+        let (key, outgoing) = b.get_dm_message_key([2u8; 32]).unwrap().unwrap();
+        assert_eq!((open_with_key(&key, &channel, &m2).unwrap(), outgoing), (b"two".to_vec(), false));
+        assert!(a.get_dm_message_key([1u8; 32]).unwrap().unwrap().1);
+        // Headers and bodies are tied to the channel
+        assert!(open_with_key(&key, &[6u8; 32], &m2).is_err());
+    }
+}
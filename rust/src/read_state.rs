@@ -0,0 +1,152 @@
+//! Read markers and message anchors
+//!
+//! Each channel has a local read marker: the last message read, as its
+//! (timestamp, message_id) position in history. Marking a message read
+//! (`mark_read`, or a read receipt) moves the marker forward, never back;
+//! sending a message moves it to that message. Every user message after the
+//! marker is unread, so the UI can open a conversation at the first of them.
+//!
+//! `locate` turns a message id into its position in `fetch_messages` order
+//! (the offset, the page of a given size and the page's offset), so a quote
+//! or search hit can be opened without fetching the history before it.
+
+use crate::error::MeshError;
+use crate::storage::Storage;
+use serde::Serialize;
+
+/// Where to open a channel with unread messages
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FirstUnread {
+    pub channel_id: String,
+    pub message_id: String,
+    pub timestamp: i64,
+    /// Offset of the message in the channel's history (oldest first)
+    pub offset: u64,
+    pub unread_count: u64,
+}
+
+/// A message's place in its channel's history
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MessageLocation {
+    pub channel_id: String,
+    pub message_id: String,
+    pub timestamp: i64,
+    /// Offset of the message in the channel's history (oldest first)
+    pub offset: u64,
+    /// Page holding it, for pages of `page_size`
+    pub page: u64,
+    /// Offset to fetch that page with
+    pub page_offset: u64,
+    pub page_size: u32,
+}
+
+/// Mark a channel read up to a message (`None`: up to its newest).
+/// Returns whether the marker moved.
+pub fn mark_read(storage: &Storage, channel_id: [u8; 32], message_id: Option<[u8; 32]>, now: i64) -> Result<bool, String> {
+    let position = match message_id {
+        Some(id) => {
+            let row = storage.get_message(id)?.filter(|row| row.channel_id == channel_id);
+            Some((row.ok_or("Message not in this channel")?.timestamp, id))
+        }
+        None => storage.last_message(channel_id)?,
+    };
+    match position {
+        Some((timestamp, id)) => storage.advance_read_marker(channel_id, timestamp, id, now),
+        None => Ok(false),
+    }
+}
+
+/// The first unread message of a channel, None if all is read.
+pub fn first_unread(storage: &Storage, channel_id: [u8; 32]) -> Result<Option<FirstUnread>, String> {
+    let marker = storage.get_read_marker(channel_id)?;
+    let Some(((timestamp, message_id), unread_count)) = storage.first_user_message_after(channel_id, marker)? else {
+        return Ok(None);
+    };
+    Ok(Some(FirstUnread {
+        channel_id: hex::encode(channel_id),
+        message_id: hex::encode(message_id),
+        timestamp,
+        offset: storage.message_offset(channel_id, timestamp, message_id)?,
+        unread_count,
+    }))
+}
+
+/// The first unread message of every channel that has one.
+pub fn first_unread_all(storage: &Storage) -> Result<Vec<FirstUnread>, String> {
+    let mut out = Vec::new();
+    for (channel_id, _) in storage.list_message_channels()? {
+        if let Some(unread) = first_unread(storage, channel_id)? {
+            out.push(unread);
+        }
+    }
+    Ok(out)
+}
+
+/// Where a stored message sits in its channel's history, None if unknown.
+pub fn locate(storage: &Storage, message_id: [u8; 32], page_size: u32) -> Result<Option<MessageLocation>, String> {
+    if page_size == 0 {
+        return Err(MeshError::InvalidArgument.raise("Page size must be positive"));
+    }
+    let Some(row) = storage.get_message(message_id)? else {
+        return Ok(None);
+    };
+    let offset = storage.message_offset(row.channel_id, row.timestamp, message_id)?;
+    let page = offset / page_size as u64;
+    Ok(Some(MessageLocation {
+        channel_id: hex::encode(row.channel_id),
+        message_id: hex::encode(message_id),
+        timestamp: row.timestamp,
+        offset,
+        page,
+        page_offset: page * page_size as u64,
+        page_size,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::OutgoingMessage;
+
+    #[test]
+    fn test_first_unread_follows_the_marker() {
+        let storage = Storage::in_memory().unwrap();
+        let channel = [1u8; 32];
+        storage.upsert_channel(channel, "dm").unwrap();
+        for i in 0..5u8 {
+            storage.store_message([10 + i; 32], channel, vec![i], 100 + i as i64, 3).unwrap();
+        }
+        assert_eq!(first_unread(&storage, channel).unwrap().unwrap().unread_count, 5);
+
+        assert!(mark_read(&storage, channel, Some([12u8; 32]), 200).unwrap());
+        // Marking an earlier message does not move it back
+        assert!(!mark_read(&storage, channel, Some([11u8; 32]), 201).unwrap());
+        let unread = first_unread(&storage, channel).unwrap().unwrap();
+        assert_eq!((unread.message_id, unread.offset, unread.unread_count), (hex::encode([13u8; 32]), 3, 2));
+        assert!(mark_read(&storage, channel, Some([1u8; 32]), 202).is_err());
+        let unread_in_list = |s: &Storage| s.list_conversations(&["dm"]).unwrap()[0].unread_count;
+        assert_eq!(unread_in_list(&storage), 2);
+        assert_eq!(storage.last_user_message(channel, 300).unwrap().unwrap().message_id, [14u8; 32]);
+
+        let location = locate(&storage, [14u8; 32], 2).unwrap().unwrap();
+        assert_eq!((location.offset, location.page, location.page_offset), (4, 2, 4));
+        let page = storage.fetch_messages(channel, 2, location.page_offset as u32).unwrap();
+        assert_eq!(page[0].message_id, [14u8; 32]);
+
+        // Sending reads everything before
+        storage
+            .store_outgoing_batch(&[OutgoingMessage {
+                message_id: [20u8; 32],
+                channel_id: channel,
+                channel_type: "dm",
+                ciphertext: vec![],
+                timestamp: 300,
+                ttl: 3,
+                priority: 1,
+            }])
+            .unwrap();
+        assert_eq!(first_unread(&storage, channel).unwrap(), None);
+        assert_eq!(unread_in_list(&storage), 0);
+        assert!(first_unread_all(&storage).unwrap().is_empty());
+    }
+}
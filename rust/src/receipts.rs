@@ -0,0 +1,197 @@
+//! Message delivery and read receipts
+//!
+//! The app calls `mark_message_delivered` when a message reaches it and
+//! `mark_message_read` when the user has seen it. Each records our status
+//! for the message and sends a `MessageReceipt` packet on the message's
+//! channel, so it travels back to the sender the way the message came.
+//! Payload: message_id (32) || status (1) || reader Ed25519 key (32) ||
+//! timestamp (i64 BE) || Ed25519 signature (64) over
+//! "meshapp-message-receipt" || channel_id || everything before it.
+//!
+//! A status only moves forward (delivered, then read). Read receipts are
+//! only sent while `privacy.read_receipts` is on; reading still records our
+//! own status (and moves the channel's read marker, see `read_state`). In
+//! DMs only the other participant's receipts are accepted. Receipts for
+//! messages we sent also move their send state (see `send_status`).
+
+use crate::dm_crypto;
+use crate::error::MeshError;
+use crate::events;
+use crate::identity::Identity;
+use crate::priority::Priority;
+use crate::send_status::{self, SendState};
+use crate::settings::{self, PRIVACY_READ_RECEIPTS};
+use crate::storage::{MessageReceiptRow, Storage, MESSAGE_KIND_USER};
+use crate::transport::{Packet, PacketKind};
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+/// TTL of receipt packets
+pub const RECEIPT_TTL: u8 = 4;
+
+const RECEIPT_CONTEXT: &[u8] = b"meshapp-message-receipt";
+const SIGNED_LEN: usize = 32 + 1 + 32 + 8;
+const RECEIPT_LEN: usize = SIGNED_LEN + 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ReceiptStatus {
+    Delivered = 1,
+    Read = 2,
+}
+
+impl ReceiptStatus {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(ReceiptStatus::Delivered),
+            2 => Some(ReceiptStatus::Read),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ReceiptStatus::Delivered => "delivered",
+            ReceiptStatus::Read => "read",
+        }
+    }
+}
+
+/// Record our status for a stored message. Returns the receipt to send, or
+/// None if the status did not advance or read receipts are turned off.
+pub fn mark(identity: &Identity, storage: &Storage, message_id: [u8; 32], status: ReceiptStatus, now: i64) -> Result<Option<Packet>, String> {
+    let message = storage.get_message(message_id)?.ok_or_else(|| MeshError::NotFound.raise("Unknown message"))?;
+    if message.kind != MESSAGE_KIND_USER {
+        return Err(MeshError::Rejected.raise("Not a user message"));
+    }
+    if status == ReceiptStatus::Read {
+        storage.advance_read_marker(message.channel_id, message.timestamp, message_id, now)?;
+    }
+    let own_user_id = identity.public().user_id;
+    if !storage.set_message_receipt(message_id, message.channel_id, own_user_id, status as u8, now)? {
+        return Ok(None);
+    }
+    if status == ReceiptStatus::Read && !settings::get_bool(storage, PRIVACY_READ_RECEIPTS)? {
+        return Ok(None);
+    }
+
+    let mut payload = Vec::with_capacity(RECEIPT_LEN);
+    payload.extend_from_slice(&message_id);
+    payload.push(status as u8);
+    payload.extend_from_slice(identity.public().ed25519_public.as_bytes());
+    payload.extend_from_slice(&now.to_be_bytes());
+    let signature = identity.ed25519_signing_key().sign(&[RECEIPT_CONTEXT, &message.channel_id, &payload].concat());
+    payload.extend_from_slice(&signature.to_bytes());
+    Ok(Some(Packet {
+        packet_id: Sha256::digest(&payload).into(),
+        channel_id: message.channel_id,
+        kind: PacketKind::MessageReceipt,
+        ttl: RECEIPT_TTL,
+        payload,
+        priority: Priority::Normal,
+    }))
+}
+
+/// Record a receipt from another reader and emit `message_receipt`
+/// { message_id, channel_id, user_id, status } when it advances their status.
+/// Receipts for messages we do not store are ignored (relays only forward them).
+pub fn handle_receipt(storage: &Storage, own_public: Option<[u8; 32]>, packet: &Packet, now: i64) -> Result<(), String> {
+    if packet.payload.len() != RECEIPT_LEN {
+        return Err(MeshError::InvalidArgument.raise("Malformed message receipt"));
+    }
+    let message_id: [u8; 32] = crate::codec::read_array(&packet.payload, 0, "message id")?;
+    let status = ReceiptStatus::from_u8(packet.payload[32]).ok_or_else(|| MeshError::InvalidArgument.raise("Unknown receipt status"))?;
+    let reader: [u8; 32] = crate::codec::read_array(&packet.payload, 33, "reader key")?;
+    let signature: [u8; 64] = crate::codec::read_array(&packet.payload, SIGNED_LEN, "signature")?;
+    VerifyingKey::from_bytes(&reader)
+        .map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid reader key: {}", e)))?
+        .verify(
+            &[RECEIPT_CONTEXT, &packet.channel_id, &packet.payload[..SIGNED_LEN]].concat(),
+            &Signature::from_bytes(&signature),
+        )
+        .map_err(|_| MeshError::Crypto.raise("Message receipt signature does not verify"))?;
+
+    if Some(reader) == own_public {
+        return Ok(());
+    }
+    match storage.get_message(message_id)? {
+        Some(message) if message.channel_id == packet.channel_id => {}
+        _ => return Ok(()),
+    }
+    if storage.get_channel_type(packet.channel_id)?.as_deref() == Some("dm") {
+        let own_public = own_public.ok_or_else(|| MeshError::NotInitialized.raise("No identity to check DM receipts against"))?;
+        if dm_crypto::derive_dm_channel_id(&own_public, &reader) != packet.channel_id {
+            return Err(MeshError::Rejected.raise("Receipt is not from a participant of this DM"));
+        }
+    }
+
+    let user_id: [u8; 32] = Sha256::digest(reader).into();
+    if storage.set_message_receipt(message_id, packet.channel_id, user_id, status as u8, now)? {
+        events::emit(
+            "message_receipt",
+            json!({
+                "message_id": hex::encode(message_id),
+                "channel_id": hex::encode(packet.channel_id),
+                "user_id": hex::encode(user_id),
+                "status": status.name(),
+            }),
+        );
+        let state = match status {
+            ReceiptStatus::Delivered => SendState::Delivered,
+            ReceiptStatus::Read => SendState::Read,
+        };
+        send_status::advance(storage, message_id, state, now)?;
+    }
+    Ok(())
+}
+
+/// Receipts others sent for a message, without our own status.
+pub fn list(storage: &Storage, own_user_id: [u8; 32], message_id: [u8; 32]) -> Result<Vec<MessageReceiptRow>, String> {
+    Ok(storage
+        .list_message_receipts(message_id)?
+        .into_iter()
+        .filter(|r| r.user_id != own_user_id)
+        .collect())
+}
+
+/// The furthest status any other reader has reported for a message (what a
+/// sender's checkmarks show).
+pub fn summary(storage: &Storage, own_user_id: [u8; 32], message_id: [u8; 32]) -> Result<Option<ReceiptStatus>, String> {
+    Ok(list(storage, own_user_id, message_id)?
+        .iter()
+        .filter_map(|r| ReceiptStatus::from_u8(r.status))
+        .max())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receipts_advance_sender_status() {
+        let (sender_storage, reader_storage) = (Storage::in_memory().unwrap(), Storage::in_memory().unwrap());
+        let (sender, reader) = (Identity::generate(), Identity::generate());
+        let channel = dm_crypto::derive_dm_channel_id(sender.public().ed25519_public.as_bytes(), reader.public().ed25519_public.as_bytes());
+        for storage in [&sender_storage, &reader_storage] {
+            storage.upsert_channel(channel, "dm").unwrap();
+            storage.store_message([7u8; 32], channel, vec![1], 100, 3).unwrap();
+        }
+        let sender_public = Some(*sender.public().ed25519_public.as_bytes());
+        let sender_id = sender.public().user_id;
+
+        let delivered = mark(&reader, &reader_storage, [7u8; 32], ReceiptStatus::Delivered, 110).unwrap().unwrap();
+        assert!(mark(&reader, &reader_storage, [7u8; 32], ReceiptStatus::Delivered, 111).unwrap().is_none());
+        handle_receipt(&sender_storage, sender_public, &delivered, 112).unwrap();
+        assert_eq!(summary(&sender_storage, sender_id, [7u8; 32]).unwrap(), Some(ReceiptStatus::Delivered));
+
+        let read = mark(&reader, &reader_storage, [7u8; 32], ReceiptStatus::Read, 120).unwrap().unwrap();
+        handle_receipt(&sender_storage, sender_public, &read, 121).unwrap();
+        // A late delivered receipt does not move the status back
+        handle_receipt(&sender_storage, sender_public, &delivered, 122).unwrap();
+        assert_eq!(summary(&sender_storage, sender_id, [7u8; 32]).unwrap(), Some(ReceiptStatus::Read));
+
+        let mut forged = read.clone();
+        forged.payload[32] = ReceiptStatus::Delivered as u8;
+        assert!(handle_receipt(&sender_storage, sender_public, &forged, 123).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+//! Startup recovery
+//!
+//! Identity and friends files are saved atomically (write `<name>.tmp`, then
+//! rename). A crash between those two steps leaves a `.tmp` file behind:
+//! - if the final file is missing and the temp file holds valid JSON, the
+//!   temp file is promoted so no data (in particular, no identity) is lost
+//! - otherwise the temp file is stale and removed
+//!
+//! Attachment blobs are written as `attachments/<id>.part` and renamed to
+//! `.blob` once complete, so any `.part` file left at startup is half-written
+//! and removed (incoming chunks stay in SQLite and are reassembled later).
+//!
+//! Once the database is open, the outbox is repaired too: a message of ours
+//! still composing (stored, but the process died before the router had it)
+//! is queued in the outbox to be sent, and packets of messages deleted since
+//! they were queued are dropped.
+//!
+//! The outcome is reported to the app as a `recovery_summary` event: for the
+//! files when the data directory is first used, and for the outbox when the
+//! database is first opened (if anything was repaired).
+
+use crate::outbox;
+use crate::priority::Priority;
+use crate::send_status::{self, SendState};
+use crate::storage::Storage;
+use crate::transport::{Packet, PacketKind};
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+/// What startup recovery found and did.
+#[derive(Serialize, Default, Debug)]
+pub struct RecoverySummary {
+    /// Temp files renamed into place because the final file was missing
+    pub restored_tmp_files: Vec<String>,
+    /// Stale temp files deleted
+    pub removed_tmp_files: Vec<String>,
+    /// Half-written attachment blobs deleted
+    pub removed_partial_blobs: Vec<String>,
+    /// Our messages left composing, queued in the outbox (message ids)
+    pub requeued_messages: Vec<String>,
+    /// Outbox packets of deleted messages, dropped (packet ids)
+    pub dropped_outbox_packets: Vec<String>,
+    /// Files that could not be cleaned up (name: error)
+    pub errors: Vec<String>,
+}
+
+/// Clean up leftovers from interrupted atomic saves in `data_dir`.
+pub fn recover_files(data_dir: &Path) -> RecoverySummary {
+    let mut summary = RecoverySummary::default();
+
+    let entries = match fs::read_dir(data_dir) {
+        Ok(e) => e,
+        // Nothing to recover on first launch
+        Err(_) => return summary,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("tmp") {
+            continue;
+        }
+        let name = file_name(&path);
+        let target = path.with_extension("json");
+
+        if !target.exists() && is_valid_json(&path) {
+            match fs::rename(&path, &target) {
+                Ok(_) => summary.restored_tmp_files.push(name),
+                Err(e) => summary.errors.push(format!("{}: {}", name, e)),
+            }
+        } else {
+            match fs::remove_file(&path) {
+                Ok(_) => summary.removed_tmp_files.push(name),
+                Err(e) => summary.errors.push(format!("{}: {}", name, e)),
+            }
+        }
+    }
+
+    if let Ok(entries) = fs::read_dir(data_dir.join(crate::attachments::ATTACHMENTS_DIR)) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("part") {
+                continue;
+            }
+            let name = file_name(&path);
+            match fs::remove_file(&path) {
+                Ok(_) => summary.removed_partial_blobs.push(name),
+                Err(e) => summary.errors.push(format!("{}: {}", name, e)),
+            }
+        }
+    }
+
+    summary
+}
+
+/// Repair the outbox of a freshly opened database.
+pub fn recover_outbox(storage: &Storage, now: i64) -> Result<RecoverySummary, String> {
+    let mut summary = RecoverySummary::default();
+    for packet_id in storage.list_outbox_without_message(PacketKind::Message as u8)? {
+        storage.delete_outbox(packet_id)?;
+        summary.dropped_outbox_packets.push(hex::encode(packet_id));
+    }
+    for message_id in storage.list_send_state_ids(SendState::Composing as u8)? {
+        let Some(row) = storage.get_message(message_id)? else {
+            continue;
+        };
+        let packet = Packet {
+            packet_id: row.message_id,
+            channel_id: row.channel_id,
+            kind: PacketKind::Message,
+            ttl: row.ttl,
+            payload: row.ciphertext,
+            priority: Priority::from_u8(row.priority),
+        };
+        outbox::hold(storage, vec![packet], now)?;
+        send_status::advance(storage, message_id, SendState::Queued, now)?;
+        summary.requeued_messages.push(hex::encode(message_id));
+    }
+    Ok(summary)
+}
+
+fn is_valid_json(path: &Path) -> bool {
+    fs::read(path)
+        .ok()
+        .and_then(|data| serde_json::from_slice::<serde_json::Value>(&data).ok())
+        .is_some()
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recover_files_promotes_and_removes() {
+        let dir = std::env::temp_dir().join(format!("meshapp-recovery-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // Final file missing, temp file complete -> promoted
+        fs::write(dir.join("identity.tmp"), b"{\"ok\":true}").unwrap();
+        // Final file present -> temp file is stale
+        fs::write(dir.join("friends.json"), b"{}").unwrap();
+        fs::write(dir.join("friends.tmp"), b"{\"friends\":").unwrap();
+
+        let summary = recover_files(&dir);
+        assert_eq!(summary.restored_tmp_files, vec!["identity.tmp".to_string()]);
+        assert_eq!(summary.removed_tmp_files, vec!["friends.tmp".to_string()]);
+        assert!(dir.join("identity.json").exists());
+        assert!(!dir.join("friends.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recover_outbox_requeues_and_drops() {
+        let storage = Storage::in_memory().unwrap();
+        let outgoing = |message_id| crate::storage::OutgoingMessage {
+            message_id,
+            channel_id: [2u8; 32],
+            channel_type: "dm",
+            ciphertext: vec![1],
+            timestamp: 100,
+            ttl: 3,
+            priority: 1,
+        };
+        // Stored, never routed; and one the router took
+        storage.store_outgoing_batch(&[outgoing([1u8; 32]), outgoing([3u8; 32])]).unwrap();
+        send_status::advance(&storage, [3u8; 32], SendState::Routed, 100).unwrap();
+        // Queued packets of a deleted message and of a receipt
+        let queued = |packet_id, kind: PacketKind| crate::storage::OutboxRow {
+            packet_id,
+            channel_id: [2u8; 32],
+            kind: kind as u8,
+            ttl: 3,
+            payload: vec![1],
+            priority: 1,
+            created_at: 100,
+            attempts: 0,
+            next_attempt: 105,
+        };
+        storage.enqueue_outbox(&queued([4u8; 32], PacketKind::Message)).unwrap();
+        storage.enqueue_outbox(&queued([5u8; 32], PacketKind::MessageReceipt)).unwrap();
+
+        let summary = recover_outbox(&storage, 200).unwrap();
+        assert_eq!(summary.requeued_messages, vec![hex::encode([1u8; 32])]);
+        assert_eq!(summary.dropped_outbox_packets, vec![hex::encode([4u8; 32])]);
+        let state = storage.get_send_state([1u8; 32]).unwrap().unwrap().state;
+        assert_eq!(state, SendState::Queued as u8);
+        assert_eq!(outbox::status(&storage).unwrap().queued, 2);
+
+        // Nothing left to repair; deleting a queued message unqueues it
+        let again = recover_outbox(&storage, 300).unwrap();
+        assert!(again.requeued_messages.is_empty() && again.dropped_outbox_packets.is_empty());
+        storage.delete_message([1u8; 32]).unwrap();
+        assert_eq!(outbox::status(&storage).unwrap().queued, 1);
+    }
+}
@@ -0,0 +1,149 @@
+//! Daily relay budget
+//!
+//! Relaying other nodes' packets costs radio time, and so battery: on a node
+//! running off a battery bank it has to stop somewhere. The
+//! `relay.daily_budget_bytes` setting caps the bytes (header and payload)
+//! relayed per UTC day; 0 relays without a cap. Usage is counted per day in
+//! `relay_usage`, so a restart does not reset it.
+//!
+//! Once a packet would take the day over budget it is still handled locally,
+//! it just leaves with TTL 0, like a packet the relay rules deny. Our own
+//! sends never count and are never throttled, and SOS messages are relayed
+//! regardless. The first packet held back in a day emits a
+//! `relay_budget_exhausted` event { day, used_bytes, budget_bytes }.
+//!
+//! Usage is kept for `KEEP_DAYS` days.
+
+use crate::events;
+use crate::settings::{self, RELAY_DAILY_BUDGET_BYTES};
+use crate::sos;
+use crate::storage::Storage;
+use crate::transport::Packet;
+use serde::Serialize;
+
+/// Bytes a packet takes on the wire besides its payload (packet id, channel
+/// id, kind, TTL and priority)
+pub const PACKET_HEADER_BYTES: u64 = 32 + 32 + 3;
+
+/// Days of usage kept, today included
+pub const KEEP_DAYS: i64 = 30;
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Days since UNIX_EPOCH (UTC) at `now`.
+pub fn day_of(now: i64) -> i64 {
+    now.div_euclid(SECS_PER_DAY)
+}
+
+/// Bytes relaying a packet costs.
+pub fn packet_bytes(packet: &Packet) -> u64 {
+    PACKET_HEADER_BYTES + packet.payload.len() as u64
+}
+
+/// Today's relay traffic against the budget (`get_relay_budget_status`)
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RelayBudgetStatus {
+    pub day: i64,
+    /// 0: no cap
+    pub budget_bytes: u64,
+    pub used_bytes: u64,
+    /// Absent without a cap
+    pub remaining_bytes: Option<u64>,
+    pub relayed_packets: u64,
+    pub throttled_packets: u64,
+    /// Whether relaying is being held back today
+    pub exhausted: bool,
+    /// When the next day's budget starts
+    pub resets_at: i64,
+}
+
+/// Today's relay traffic.
+pub fn status(storage: &Storage, now: i64) -> Result<RelayBudgetStatus, String> {
+    let budget = settings::get_u64(storage, RELAY_DAILY_BUDGET_BYTES)?;
+    let day = day_of(now);
+    let usage = storage.get_relay_usage(day)?;
+    Ok(RelayBudgetStatus {
+        day,
+        budget_bytes: budget,
+        used_bytes: usage.bytes,
+        remaining_bytes: (budget > 0).then(|| budget.saturating_sub(usage.bytes)),
+        relayed_packets: usage.packets,
+        throttled_packets: usage.throttled,
+        exhausted: budget > 0 && (usage.throttled > 0 || usage.bytes >= budget),
+        resets_at: (day + 1) * SECS_PER_DAY,
+    })
+}
+
+/// Apply the budget to a packet from another node about to be routed: it is
+/// counted if it will be relayed, or its TTL set to 0 if that would take the
+/// day over budget.
+pub fn apply(storage: &Storage, packet: &mut Packet, now: i64) -> Result<(), String> {
+    if packet.ttl == 0 || sos::is_sos(packet) {
+        return Ok(());
+    }
+    let budget = settings::get_u64(storage, RELAY_DAILY_BUDGET_BYTES)?;
+    let day = day_of(now);
+    let usage = storage.get_relay_usage(day)?;
+    if usage.packets == 0 && usage.throttled == 0 {
+        storage.prune_relay_usage(day - KEEP_DAYS + 1)?;
+    }
+    let cost = packet_bytes(packet);
+    let throttled = budget > 0 && usage.bytes.saturating_add(cost) > budget;
+    storage.add_relay_usage(day, cost, throttled)?;
+    if throttled {
+        packet.ttl = 0;
+        if usage.throttled == 0 {
+            events::emit(
+                "relay_budget_exhausted",
+                serde_json::json!({ "day": day, "used_bytes": usage.bytes, "budget_bytes": budget }),
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::priority::Priority;
+    use crate::transport::PacketKind;
+    use serde_json::json;
+
+    #[test]
+    fn test_relaying_stops_at_the_daily_budget() {
+        let storage = Storage::in_memory().unwrap();
+        let packet = Packet {
+            packet_id: [1u8; 32],
+            channel_id: [2u8; 32],
+            kind: PacketKind::Message,
+            ttl: 3,
+            payload: vec![0u8; 100 - PACKET_HEADER_BYTES as usize],
+            priority: Priority::Normal,
+        };
+        let now = 10 * SECS_PER_DAY + 5;
+
+        // No cap: counted, never throttled
+        let mut relayed = packet.clone();
+        apply(&storage, &mut relayed, now).unwrap();
+        assert_eq!((relayed.ttl, status(&storage, now).unwrap().used_bytes), (3, 100));
+
+        settings::set_value(&storage, RELAY_DAILY_BUDGET_BYTES, json!(250)).unwrap();
+        let mut second = packet.clone();
+        apply(&storage, &mut second, now).unwrap();
+        let mut third = packet.clone();
+        apply(&storage, &mut third, now + 60).unwrap();
+        assert_eq!((second.ttl, third.ttl), (3, 0));
+        let today = status(&storage, now).unwrap();
+        assert_eq!((today.used_bytes, today.remaining_bytes, today.throttled_packets), (200, Some(50), 1));
+        assert!(today.exhausted);
+
+        // SOS messages still go out, and the next day starts afresh
+        let mut sos = sos::packet([3u8; 32], "help").unwrap();
+        apply(&storage, &mut sos, now).unwrap();
+        assert_eq!(sos.ttl, sos::SOS_TTL);
+        let mut tomorrow = packet.clone();
+        apply(&storage, &mut tomorrow, now + SECS_PER_DAY).unwrap();
+        assert_eq!(tomorrow.ttl, 3);
+        assert!(!status(&storage, now + SECS_PER_DAY).unwrap().exhausted);
+    }
+}
@@ -0,0 +1,179 @@
+//! Relay channel rules
+//!
+//! Gateway and daemon operators choose which channels their node relays
+//! (and so bridges onto its internet transport) with `relay.channel_rules`:
+//! an ordered list of rules, each matching a channel-id hex prefix, a
+//! channel type, or both, and allowing or denying relay. The first matching
+//! rule decides. With no rules everything is relayed; once any rule allows,
+//! channels no rule matches are denied, so a list of allow rules is an
+//! allowlist.
+//!
+//! A denied packet is still handled locally, it just leaves with TTL 0.
+//! Channels we have not registered have no type and only prefix rules match
+//! them. Each rule counts the packets and payload bytes it decided (and so
+//! does the default) since the rules were last set, for `get_relay_rule_stats`.
+
+use crate::error::MeshError;
+use crate::settings::{self, RELAY_CHANNEL_RULES};
+use crate::storage::Storage;
+use crate::transport::Packet;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+/// What a matching rule does
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    Allow,
+    Deny,
+}
+
+/// One entry of `relay.channel_rules`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct RelayRule {
+    pub action: RuleAction,
+    /// Hex prefix of the channel id (any number of digits)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_prefix: Option<String>,
+    /// Channel type, e.g. "geo"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_type: Option<String>,
+}
+
+impl RelayRule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.channel_prefix.is_none() && self.channel_type.is_none() {
+            return Err("Relay rule needs a channel_prefix or channel_type".to_string());
+        }
+        if let Some(prefix) = &self.channel_prefix {
+            if prefix.is_empty() || prefix.len() > 64 || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(MeshError::InvalidArgument.raise(format!("Invalid channel_prefix: {}", prefix)));
+            }
+        }
+        Ok(())
+    }
+
+    fn matches(&self, channel_hex: &str, channel_type: Option<&str>) -> bool {
+        self.channel_prefix
+            .as_ref()
+            .is_none_or(|prefix| channel_hex.starts_with(&prefix.to_ascii_lowercase()))
+            && self.channel_type.as_ref().is_none_or(|t| channel_type == Some(t.as_str()))
+    }
+}
+
+/// Parse and check a `relay.channel_rules` value.
+pub fn parse_rules(value: &serde_json::Value) -> Result<Vec<RelayRule>, String> {
+    let rules: Vec<RelayRule> = serde_json::from_value(value.clone()).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid relay rules: {}", e)))?;
+    rules.iter().try_for_each(RelayRule::validate)?;
+    Ok(rules)
+}
+
+/// Whether a channel is relayed, and the index of the rule that decided (None for the default).
+pub fn decide(rules: &[RelayRule], channel_id: &[u8; 32], channel_type: Option<&str>) -> (bool, Option<usize>) {
+    let channel_hex = hex::encode(channel_id);
+    match rules.iter().position(|rule| rule.matches(&channel_hex, channel_type)) {
+        Some(index) => (rules[index].action == RuleAction::Allow, Some(index)),
+        None => (!rules.iter().any(|rule| rule.action == RuleAction::Allow), None),
+    }
+}
+
+/// Packets and payload bytes one rule (or the default) decided
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RuleCounter {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl RuleCounter {
+    fn add(&mut self, packet: &Packet) {
+        self.packets += 1;
+        self.bytes += packet.payload.len() as u64;
+    }
+}
+
+/// Rule counters as reported by `get_relay_rule_stats`
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RelayRuleStats {
+    /// Per rule, in rule order
+    pub rules: Vec<RuleCounter>,
+    /// Packets no rule matched that were relayed
+    pub default_relayed: RuleCounter,
+    /// Packets no rule matched that were not relayed
+    pub default_denied: RuleCounter,
+}
+
+static STATS: Lazy<Mutex<RelayRuleStats>> = Lazy::new(|| Mutex::new(RelayRuleStats::default()));
+
+/// Counters since the rules were last set.
+pub fn stats() -> RelayRuleStats {
+    STATS.lock().unwrap().clone()
+}
+
+/// Forget the counters (the rules changed).
+pub fn reset_stats() {
+    *STATS.lock().unwrap() = RelayRuleStats::default();
+}
+
+fn record(rule_count: usize, decided_by: Option<usize>, relayed: bool, packet: &Packet) {
+    let mut stats = STATS.lock().unwrap();
+    if stats.rules.len() != rule_count {
+        stats.rules.resize(rule_count, RuleCounter::default());
+    }
+    match decided_by {
+        Some(index) => stats.rules[index].add(packet),
+        None if relayed => stats.default_relayed.add(packet),
+        None => stats.default_denied.add(packet),
+    }
+}
+
+/// Apply the relay rules to a packet about to be routed: a denied packet's
+/// TTL is set to 0. Packets that would not be relayed anyway are not counted.
+pub fn apply(storage: &Storage, packet: &mut Packet) -> Result<(), String> {
+    if packet.ttl == 0 {
+        return Ok(());
+    }
+    let rules = parse_rules(&settings::get_value(storage, RELAY_CHANNEL_RULES)?)?;
+    if rules.is_empty() {
+        return Ok(());
+    }
+    let channel_type = storage.get_channel_type(packet.channel_id)?;
+    let (relayed, decided_by) = decide(&rules, &packet.channel_id, channel_type.as_deref());
+    record(rules.len(), decided_by, relayed, packet);
+    if !relayed {
+        packet.ttl = 0;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_first_matching_rule_decides() {
+        let deny_only = parse_rules(&json!([{ "action": "deny", "channel_type": "geo" }])).unwrap();
+        assert_eq!(decide(&deny_only, &[0xab; 32], Some("geo")), (false, Some(0)));
+        assert_eq!(decide(&deny_only, &[0xab; 32], None), (true, None));
+
+        let allowlist = parse_rules(&json!([
+            { "action": "deny", "channel_prefix": "ABCD" },
+            { "action": "allow", "channel_prefix": "ab" },
+            { "action": "allow", "channel_type": "sos" },
+        ]))
+        .unwrap();
+        assert_eq!(decide(&allowlist, &[0xab; 32], None), (true, Some(1)));
+        let mut id = [0xab; 32];
+        id[1] = 0xcd;
+        assert_eq!(decide(&allowlist, &id, None), (false, Some(0)));
+        assert_eq!(decide(&allowlist, &[0x11; 32], Some("sos")), (true, Some(2)));
+        // Allow rules make everything else denied
+        assert_eq!(decide(&allowlist, &[0x11; 32], Some("geo")), (false, None));
+
+        assert!(parse_rules(&json!([{ "action": "allow" }])).is_err());
+        assert!(parse_rules(&json!([{ "action": "allow", "channel_prefix": "xyz" }])).is_err());
+        assert!(parse_rules(&json!([{ "action": "block", "channel_type": "geo" }])).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+//! Relay handover snapshots
+//!
+//! Operators replacing a relay/gateway device can carry its routing state
+//! over so the new node neither re-forwards packets the old one already
+//! handled nor has to relearn who is around. A snapshot holds:
+//! - the Router's seen packet ids (dedup state)
+//! - the peer table
+//! - routing hints (which peers were heard on which channels)
+//!
+//! Binary layout (big-endian), sent as base64url text:
+//! `"MSNP" | version u8 | seen count u32 | peer count u32 | hint count u32 |
+//!  seen ids (32 each) | peers (id 32, last_seen i64) |
+//!  hints (channel 32, peer 32, last_seen i64) | SHA256(prefix)[..8]`
+//!
+//! Importing merges into the current state; nothing is removed.
+
+use crate::codec;
+use crate::error::MeshError;
+use crate::storage::{PeerRow, RoutingHintRow, Storage};
+use crate::transport::Router;
+use crate::uri::{base64url_decode, base64url_encode};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+const MAGIC: &[u8; 4] = b"MSNP";
+const SNAPSHOT_VERSION: u8 = 1;
+const HEADER_LEN: usize = 4 + 1 + 4 * 3;
+const CHECKSUM_LEN: usize = 8;
+
+/// What an import added to the current state
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub struct ImportStats {
+    /// Packet ids that were not already marked seen
+    pub seen: usize,
+    pub peers: usize,
+    pub routing_hints: usize,
+}
+
+/// Snapshot the router's dedup state plus the stored peer table and routing hints.
+/// The router and storage are both optional; a missing one contributes nothing.
+pub fn export(router: Option<&Router>, storage: Option<&Storage>) -> Result<String, String> {
+    let seen = router.map(Router::seen_ids).unwrap_or_default();
+    let (peers, hints) = match storage {
+        Some(s) => (s.list_peers()?, s.list_routing_hints()?),
+        None => (Vec::new(), Vec::new()),
+    };
+    Ok(base64url_encode(&encode(&seen, &peers, &hints)))
+}
+
+/// Merge a snapshot into the router and storage.
+pub fn import(snapshot: &str, router: Option<&Router>, storage: Option<&Storage>) -> Result<ImportStats, String> {
+    let (seen, peers, hints) = decode(&base64url_decode(snapshot.trim())?)?;
+    let mut stats = ImportStats::default();
+
+    if let Some(router) = router {
+        stats.seen = router.mark_seen(&seen);
+    }
+    if let Some(storage) = storage {
+        for p in &peers {
+            storage.upsert_peer(p.peer_id, p.last_seen)?;
+        }
+        for h in &hints {
+            storage.upsert_routing_hint(h.channel_id, h.peer_id, h.last_seen)?;
+        }
+        stats.peers = peers.len();
+        stats.routing_hints = hints.len();
+    }
+    Ok(stats)
+}
+
+fn encode(seen: &[[u8; 32]], peers: &[PeerRow], hints: &[RoutingHintRow]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN + seen.len() * 32 + peers.len() * 40 + hints.len() * 72 + CHECKSUM_LEN);
+    out.extend_from_slice(MAGIC);
+    out.push(SNAPSHOT_VERSION);
+    out.extend_from_slice(&(seen.len() as u32).to_be_bytes());
+    out.extend_from_slice(&(peers.len() as u32).to_be_bytes());
+    out.extend_from_slice(&(hints.len() as u32).to_be_bytes());
+    for id in seen {
+        out.extend_from_slice(id);
+    }
+    for p in peers {
+        out.extend_from_slice(&p.peer_id);
+        out.extend_from_slice(&p.last_seen.to_be_bytes());
+    }
+    for h in hints {
+        out.extend_from_slice(&h.channel_id);
+        out.extend_from_slice(&h.peer_id);
+        out.extend_from_slice(&h.last_seen.to_be_bytes());
+    }
+    let checksum = Sha256::digest(&out);
+    out.extend_from_slice(&checksum[..CHECKSUM_LEN]);
+    out
+}
+
+type Decoded = (Vec<[u8; 32]>, Vec<PeerRow>, Vec<RoutingHintRow>);
+
+fn decode(data: &[u8]) -> Result<Decoded, String> {
+    if data.len() < HEADER_LEN + CHECKSUM_LEN || &data[..4] != MAGIC {
+        return Err(MeshError::Rejected.raise("Not a relay snapshot"));
+    }
+    if data[4] != SNAPSHOT_VERSION {
+        return Err(MeshError::InvalidArgument.raise(format!("Unsupported snapshot version: {}", data[4])));
+    }
+    let (body, checksum) = data.split_at(data.len() - CHECKSUM_LEN);
+    if Sha256::digest(body)[..CHECKSUM_LEN] != *checksum {
+        return Err("Snapshot checksum mismatch".to_string());
+    }
+
+    let count = |at: usize| codec::read_u32_be(body, at, "snapshot count").map(|n| n as usize);
+    let (n_seen, n_peers, n_hints) = (count(5)?, count(9)?, count(13)?);
+    let expected = n_seen
+        .checked_mul(32)
+        .zip(n_peers.checked_mul(40))
+        .zip(n_hints.checked_mul(72))
+        .and_then(|((a, b), c)| a.checked_add(b)?.checked_add(c)?.checked_add(HEADER_LEN));
+    if expected != Some(body.len()) {
+        return Err("Snapshot length does not match its counts".to_string());
+    }
+
+    let id = |at: usize| codec::read_array(body, at, "snapshot id");
+    let ts = |at: usize| codec::read_i64_be(body, at, "snapshot timestamp");
+
+    let mut at = HEADER_LEN;
+    let mut seen = Vec::with_capacity(n_seen);
+    for _ in 0..n_seen {
+        seen.push(id(at)?);
+        at += 32;
+    }
+    let mut peers = Vec::with_capacity(n_peers);
+    for _ in 0..n_peers {
+        peers.push(PeerRow {
+            peer_id: id(at)?,
+            last_seen: ts(at + 32)?,
+        });
+        at += 40;
+    }
+    let mut hints = Vec::with_capacity(n_hints);
+    for _ in 0..n_hints {
+        hints.push(RoutingHintRow {
+            channel_id: id(at)?,
+            peer_id: id(at + 32)?,
+            last_seen: ts(at + 64)?,
+        });
+        at += 72;
+    }
+    Ok((seen, peers, hints))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_roundtrip_into_new_router() {
+        let old = Router::new(Vec::new());
+        old.mark_seen(&[[1u8; 32], [2u8; 32]]);
+        let snapshot = export(Some(&old), None).unwrap();
+
+        let new = Router::new(Vec::new());
+        new.mark_seen(&[[2u8; 32]]);
+        let stats = import(&snapshot, Some(&new), None).unwrap();
+        assert_eq!(stats.seen, 1);
+        assert_eq!(new.seen_ids().len(), 2);
+
+        let mut corrupted = base64url_decode(&snapshot).unwrap();
+        corrupted[HEADER_LEN] ^= 1;
+        assert!(import(&base64url_encode(&corrupted), Some(&new), None).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+//! History replay
+//!
+//! When a peer joins a channel we store (e.g. a newcomer in a geo room), we
+//! can send it recent history so it is not starting from an empty room.
+//! Replays are bounded by settings:
+//! - `replay.max_age_secs`: only messages newer than this
+//! - `replay.max_bytes`: total ciphertext per replay (newest messages win)
+//! - `replay.min_interval_secs`: per peer and channel rate limit
+//!
+//! Replayed packets keep their original packet id, so peers that already
+//! have a message drop it as a duplicate, and go out with TTL 0 so they
+//! reach only the neighbour that asked. DM and notes channels are never
+//! replayed.
+
+use crate::priority::Priority;
+use crate::settings;
+use crate::storage::Storage;
+use crate::transport::{Packet, PacketKind};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Most messages considered per replay, before the byte budget applies
+const REPLAY_SCAN_LIMIT: u32 = 500;
+
+/// Channel types whose history is never replayed
+const PRIVATE_CHANNEL_TYPES: &[&str] = &["dm", "notes"];
+
+/// (peer_id, channel_id)
+type ReplayKey = ([u8; 32], [u8; 32]);
+
+/// Last replay time per peer and channel
+static LAST_REPLAY: Lazy<Mutex<HashMap<ReplayKey, i64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Limits on what one replay may send
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayBudget {
+    pub max_age_secs: u64,
+    pub max_bytes: u64,
+}
+
+impl ReplayBudget {
+    /// The budget configured in settings.
+    pub fn load(storage: &Storage) -> Result<Self, String> {
+        Ok(Self {
+            max_age_secs: settings::get_u64(storage, settings::REPLAY_MAX_AGE_SECS)?,
+            max_bytes: settings::get_u64(storage, settings::REPLAY_MAX_BYTES)?,
+        })
+    }
+}
+
+/// Whether a channel's history may be replayed to other nodes.
+pub fn is_replayable(storage: &Storage, channel_id: [u8; 32]) -> Result<bool, String> {
+    Ok(storage
+        .get_channel_type(channel_id)?
+        .is_some_and(|t| !PRIVATE_CHANNEL_TYPES.contains(&t.as_str())))
+}
+
+/// Check and record the per peer/channel rate limit. Returns false if the
+/// peer was served this channel less than `min_interval_secs` ago.
+pub fn allow(peer_id: [u8; 32], channel_id: [u8; 32], now: i64, min_interval_secs: u64) -> bool {
+    let mut last = LAST_REPLAY.lock().unwrap();
+    let key = (peer_id, channel_id);
+    if let Some(&at) = last.get(&key) {
+        if now.saturating_sub(at) < min_interval_secs as i64 {
+            return false;
+        }
+    }
+    last.insert(key, now);
+    true
+}
+
+/// Message packets for a channel's recent history within `budget`, oldest first.
+pub fn history_packets(
+    storage: &Storage,
+    channel_id: [u8; 32],
+    budget: ReplayBudget,
+    now: i64,
+) -> Result<Vec<Packet>, String> {
+    let since = now.saturating_sub(budget.max_age_secs.min(i64::MAX as u64) as i64);
+    let rows = storage.fetch_messages_since(channel_id, since, REPLAY_SCAN_LIMIT)?;
+
+    let mut packets = Vec::new();
+    let mut bytes = 0u64;
+    for row in rows {
+        bytes += row.ciphertext.len() as u64;
+        if bytes > budget.max_bytes {
+            break;
+        }
+        packets.push(Packet {
+            packet_id: row.message_id,
+            channel_id: row.channel_id,
+            kind: PacketKind::Message,
+            ttl: 0,
+            payload: row.ciphertext,
+            priority: Priority::from_u8(row.priority),
+        });
+    }
+    packets.reverse();
+    Ok(packets)
+}
+
+/// Replay a channel's recent history to a peer that joined it, subject to
+/// the replay settings. Returns the packets to send (empty when the channel
+/// is private or the peer is rate limited).
+pub fn replay_for_peer(storage: &Storage, peer_id: [u8; 32], channel_id: [u8; 32], now: i64) -> Result<Vec<Packet>, String> {
+    if !is_replayable(storage, channel_id)? {
+        return Ok(Vec::new());
+    }
+    let min_interval = settings::get_u64(storage, settings::REPLAY_MIN_INTERVAL_SECS)?;
+    if !allow(peer_id, channel_id, now, min_interval) {
+        return Ok(Vec::new());
+    }
+    history_packets(storage, channel_id, ReplayBudget::load(storage)?, now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_respects_budget_and_rate_limit() {
+        let storage = Storage::in_memory().unwrap();
+        let channel = [9u8; 32];
+        storage.upsert_channel(channel, "geo").unwrap();
+        storage.store_message([1u8; 32], channel, vec![0; 100], 1_000, 4).unwrap();
+        storage.store_message([2u8; 32], channel, vec![0; 100], 5_000, 4).unwrap();
+        storage.store_message([3u8; 32], channel, vec![0; 100], 6_000, 4).unwrap();
+
+        // Too old for max age, then the byte budget keeps only the newest
+        let budget = ReplayBudget { max_age_secs: 2_000, max_bytes: 150 };
+        let packets = history_packets(&storage, channel, budget, 6_500).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].packet_id, [3u8; 32]);
+
+        assert!(allow([5u8; 32], channel, 100, 60));
+        assert!(!allow([5u8; 32], channel, 130, 60));
+        assert!(allow([5u8; 32], channel, 160, 60));
+
+        storage.upsert_channel([8u8; 32], "dm").unwrap();
+        assert!(!is_replayable(&storage, [8u8; 32]).unwrap());
+    }
+}
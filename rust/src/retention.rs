@@ -0,0 +1,241 @@
+//! Message retention
+//!
+//! A retention policy keeps a channel's messages forever, for N days from
+//! their timestamp, to the newest N messages, or both limits at once. The
+//! global policy (`retention.max_age_days`, `retention.max_messages`) applies
+//! to every channel type without its own entry in
+//! `retention.by_channel_type`. By default DMs, notes and groups are kept
+//! and public chatter (geo and SOS) expires.
+//!
+//! A single conversation can have disappearing messages: its own retention
+//! in seconds (`set_channel_retention`, the channel's `retention_secs`),
+//! which replaces its type's age limit (the message count limit still
+//! applies). Display fetches (`Storage::fetch_live_messages`) leave out its
+//! messages as soon as they are due, before `prune` deletes them.
+//!
+//! Fetch results carry `expires_in` (seconds left by age, absent when the
+//! channel has no age limit), computed here so every screen counts down from
+//! the same source. `prune` is the job that removes expired messages; it emits a
+//! `messages_expired` event per channel so the UI can drop them from view.
+//!
+//! `storage.max_message_bytes` is a quota on top of the policies: once the
+//! stored ciphertext exceeds it, `prune` deletes the oldest messages of any
+//! channel until it fits.
+//!
+//! `prune` stamps each message with its `expires_at` the first time it sees
+//! it, so finding what is due is one indexed query however many messages a
+//! channel keeps. A change to any retention setting clears the stamps; the
+//! next run stamps again under the new policy. (A packet's `ttl` is a hop
+//! count and plays no part here.)
+
+use crate::events;
+use crate::settings::{self, RETENTION_BY_CHANNEL_TYPE, RETENTION_MAX_AGE_DAYS, RETENTION_MAX_MESSAGES, STORAGE_MAX_MESSAGE_BYTES};
+use crate::storage::Storage;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// How long a channel's messages are kept (0 means no limit)
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionPolicy {
+    #[serde(default)]
+    pub max_age_days: u64,
+    #[serde(default)]
+    pub max_messages: u64,
+}
+
+impl RetentionPolicy {
+    pub fn lifetime_secs(&self) -> Option<i64> {
+        (self.max_age_days > 0).then(|| (self.max_age_days.min(i64::MAX as u64) as i64).saturating_mul(SECS_PER_DAY))
+    }
+
+    pub fn keeps_forever(&self) -> bool {
+        self.max_age_days == 0 && self.max_messages == 0
+    }
+}
+
+/// Per channel type policies (`retention.by_channel_type`)
+pub type ChannelTypePolicies = BTreeMap<String, RetentionPolicy>;
+
+/// Default of `retention.by_channel_type`
+pub fn default_channel_type_policies() -> Value {
+    json!({
+        "geo": { "max_age_days": 7, "max_messages": 1000 },
+        "sos": { "max_age_days": 3, "max_messages": 0 },
+    })
+}
+
+/// The policy of a channel type (None for unregistered channels).
+pub fn policy_for(storage: &Storage, channel_type: Option<&str>) -> Result<RetentionPolicy, String> {
+    let by_type: ChannelTypePolicies = settings::get(storage, RETENTION_BY_CHANNEL_TYPE)?;
+    match channel_type.and_then(|t| by_type.get(t)) {
+        Some(policy) => Ok(*policy),
+        None => global_policy(storage),
+    }
+}
+
+fn global_policy(storage: &Storage) -> Result<RetentionPolicy, String> {
+    Ok(RetentionPolicy {
+        max_age_days: settings::get_u64(storage, RETENTION_MAX_AGE_DAYS)?,
+        max_messages: settings::get_u64(storage, RETENTION_MAX_MESSAGES)?,
+    })
+}
+
+/// The configured message lifetime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    /// None keeps messages forever
+    pub lifetime_secs: Option<i64>,
+}
+
+impl Retention {
+    /// The message lifetime of a channel: its own retention, else its type's policy.
+    pub fn for_channel(storage: &Storage, channel_id: [u8; 32]) -> Result<Self, String> {
+        let channel_type = storage.get_channel_type(channel_id)?;
+        let policy = policy_for(storage, channel_type.as_deref())?;
+        Ok(Self { lifetime_secs: channel_lifetime(storage, channel_id, &policy)? })
+    }
+
+    /// Seconds until a message sent at `timestamp` expires (0 once due).
+    pub fn expires_in(&self, timestamp: i64, now: i64) -> Option<i64> {
+        self.lifetime_secs.map(|life| (timestamp.saturating_add(life) - now).max(0))
+    }
+}
+
+/// A channel's message lifetime under `policy`, or its own retention if set.
+fn channel_lifetime(storage: &Storage, channel_id: [u8; 32], policy: &RetentionPolicy) -> Result<Option<i64>, String> {
+    Ok(match storage.get_channel_retention(channel_id)? {
+        Some(secs) => Some(secs.min(i64::MAX as u64) as i64),
+        None => policy.lifetime_secs(),
+    })
+}
+
+/// Delete messages past their channel's policy and emit `messages_expired`
+/// { channel_id, message_ids } for each channel that lost some. Returns how
+/// many were deleted.
+pub fn prune(storage: &Storage, now: i64) -> Result<usize, String> {
+    let mut count_limits = Vec::new();
+    for (channel_id, channel_type) in storage.list_message_channels()? {
+        let policy = policy_for(storage, channel_type.as_deref())?;
+        let lifetime = channel_lifetime(storage, channel_id, &policy)?;
+        if policy.keeps_forever() && lifetime.is_none() {
+            continue;
+        }
+        if let Some(life) = lifetime {
+            storage.stamp_message_expiry(channel_id, life)?;
+        }
+        if policy.max_messages > 0 {
+            count_limits.push((channel_id, policy.max_messages));
+        }
+    }
+
+    let mut deleted: BTreeMap<[u8; 32], Vec<[u8; 32]>> = BTreeMap::new();
+    for (channel_id, message_id) in storage.purge_expired_messages(now)? {
+        deleted.entry(channel_id).or_default().push(message_id);
+    }
+    for (channel_id, max_messages) in count_limits {
+        let pruned = storage.prune_channel_messages(channel_id, max_messages)?;
+        if !pruned.is_empty() {
+            deleted.entry(channel_id).or_default().extend(pruned);
+        }
+    }
+    let quota = settings::get_u64(storage, STORAGE_MAX_MESSAGE_BYTES)?;
+    if quota > 0 {
+        for (channel_id, message_id) in storage.prune_messages_to_size(quota)? {
+            deleted.entry(channel_id).or_default().push(message_id);
+        }
+    }
+
+    let mut total = 0;
+    for (channel_id, ids) in deleted {
+        let message_ids: Vec<String> = ids.iter().map(hex::encode).collect();
+        events::emit(
+            "messages_expired",
+            json!({ "channel_id": hex::encode(channel_id), "message_ids": message_ids }),
+        );
+        total += ids.len();
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expires_in_and_prune() {
+        let storage = Storage::in_memory().unwrap();
+        let now = 10 * SECS_PER_DAY;
+        storage.store_message([1u8; 32], [9u8; 32], vec![1], now - 3 * SECS_PER_DAY, 1).unwrap();
+        storage.store_message([2u8; 32], [9u8; 32], vec![2], now - 60, 1).unwrap();
+
+        assert_eq!(Retention::for_channel(&storage, [9u8; 32]).unwrap().expires_in(now, now), None);
+        assert_eq!(prune(&storage, now).unwrap(), 0);
+
+        settings::set_value(&storage, RETENTION_MAX_AGE_DAYS, json!(2)).unwrap();
+        let retention = Retention::for_channel(&storage, [9u8; 32]).unwrap();
+        assert_eq!(retention.expires_in(now - 60, now), Some(2 * SECS_PER_DAY - 60));
+        assert_eq!(retention.expires_in(now - 3 * SECS_PER_DAY, now), Some(0));
+        assert_eq!(prune(&storage, now).unwrap(), 1);
+        assert_eq!(storage.fetch_messages([9u8; 32], 10, 0).unwrap().len(), 1);
+
+        // Stamped under the 2 day policy; a longer one replaces the stamps
+        settings::set_value(&storage, RETENTION_MAX_AGE_DAYS, json!(30)).unwrap();
+        assert_eq!(prune(&storage, now + 2 * SECS_PER_DAY).unwrap(), 0);
+        assert_eq!(storage.purge_expired_messages(now + 30 * SECS_PER_DAY - 61).unwrap(), vec![]);
+        assert_eq!(storage.purge_expired_messages(now + 30 * SECS_PER_DAY - 60).unwrap(), vec![([9u8; 32], [2u8; 32])]);
+
+        // The quota deletes the oldest messages of any channel
+        storage.store_message([3u8; 32], [8u8; 32], vec![3; 4], now, 1).unwrap();
+        storage.store_message([4u8; 32], [9u8; 32], vec![4; 4], now + 1, 1).unwrap();
+        storage.store_message([5u8; 32], [8u8; 32], vec![5; 4], now + 2, 1).unwrap();
+        settings::set_value(&storage, STORAGE_MAX_MESSAGE_BYTES, json!(8)).unwrap();
+        assert_eq!(prune(&storage, now).unwrap(), 1);
+        assert!(storage.get_message([3u8; 32]).unwrap().is_none());
+        assert!(storage.get_message([4u8; 32]).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_channel_type_policies() {
+        let storage = Storage::in_memory().unwrap();
+        let now = 30 * SECS_PER_DAY;
+        let (dm, geo) = ([1u8; 32], [2u8; 32]);
+        storage.upsert_channel(dm, "dm").unwrap();
+        storage.upsert_channel(geo, "geo").unwrap();
+        for i in 0..4u8 {
+            storage.store_message([10 + i; 32], dm, vec![i], now - 20 * SECS_PER_DAY + i as i64, 1).unwrap();
+            storage.store_message([20 + i; 32], geo, vec![i], now - i as i64, 1).unwrap();
+        }
+        storage.store_message([30u8; 32], geo, vec![9], now - 8 * SECS_PER_DAY, 1).unwrap();
+
+        // Defaults keep DMs and expire old geo messages
+        assert_eq!(Retention::for_channel(&storage, dm).unwrap().lifetime_secs, None);
+        assert_eq!(prune(&storage, now).unwrap(), 1);
+        assert_eq!(storage.fetch_messages(dm, 10, 0).unwrap().len(), 4);
+
+        let policies = json!({ "geo": { "max_messages": 2 }, "dm": { "max_age_days": 0, "max_messages": 0 } });
+        settings::set_value(&storage, RETENTION_BY_CHANNEL_TYPE, policies).unwrap();
+        settings::set_value(&storage, RETENTION_MAX_AGE_DAYS, json!(1)).unwrap();
+        assert!(settings::set_value(&storage, RETENTION_BY_CHANNEL_TYPE, json!({ "geo": { "days": 1 } })).is_err());
+        assert_eq!(prune(&storage, now).unwrap(), 2);
+        let kept: Vec<[u8; 32]> = storage.fetch_messages(geo, 10, 0).unwrap().iter().map(|m| m.message_id).collect();
+        assert_eq!(kept.len(), 2);
+        assert!(kept.contains(&[20u8; 32]) && kept.contains(&[21u8; 32]));
+        assert_eq!(storage.fetch_messages(dm, 10, 0).unwrap().len(), 4);
+
+        // Disappearing messages: hidden once due, deleted by the next prune
+        assert!(storage.set_channel_retention(dm, Some(60)).unwrap());
+        assert!(!storage.set_channel_retention([3u8; 32], Some(60)).unwrap());
+        storage.store_message([40u8; 32], dm, vec![1], now - 30, 1).unwrap();
+        assert_eq!(storage.fetch_live_messages(dm, 10, 0, now).unwrap().len(), 1);
+        assert_eq!(storage.fetch_live_messages(dm, 10, 0, now + 30).unwrap().len(), 0);
+        assert_eq!(Retention::for_channel(&storage, dm).unwrap().expires_in(now - 30, now), Some(30));
+        assert_eq!(prune(&storage, now).unwrap(), 4);
+        assert_eq!(storage.fetch_messages(dm, 10, 0).unwrap().len(), 1);
+        storage.set_channel_retention(dm, None).unwrap();
+        assert_eq!(prune(&storage, now + 60).unwrap(), 0);
+    }
+}
@@ -0,0 +1,131 @@
+//! Randomness
+//!
+//! Everything the core draws at random (keys, nonces, salts, packet and
+//! channel ids, Noise ephemerals) comes from `rng()`. Production builds
+//! always get the OS RNG (through `rand::thread_rng`, seeded from the OS).
+//!
+//! Built with the `seeded-rng` feature, the simulation harness and golden
+//! tests can `seed` a ChaCha20 stream first (FFI `seed_rng`, before
+//! init_identity), so runs repeat exactly: same identity, same packet ids,
+//! same ciphertexts. The stream is shared by all threads, so a run only
+//! repeats when its calls come in the same order. Keys of such a build are
+//! only as secret as the seed: never ship it.
+
+use rand::{CryptoRng, RngCore};
+
+#[cfg(feature = "seeded-rng")]
+static SEEDED: std::sync::Mutex<Option<rand_chacha::ChaCha20Rng>> = std::sync::Mutex::new(None);
+
+/// The core's source of randomness (see the module docs)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CoreRng;
+
+pub fn rng() -> CoreRng {
+    CoreRng
+}
+
+/// Draw from a ChaCha20 stream seeded with `seed` from now on.
+#[cfg(feature = "seeded-rng")]
+pub fn seed(seed: u64) {
+    use rand::SeedableRng;
+    *SEEDED.lock().unwrap() = Some(rand_chacha::ChaCha20Rng::seed_from_u64(seed));
+}
+
+impl RngCore for CoreRng {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0u8; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0u8; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        #[cfg(feature = "seeded-rng")]
+        if let Some(seeded) = SEEDED.lock().unwrap().as_mut() {
+            seeded.fill_bytes(dest);
+            return;
+        }
+        rand::thread_rng().fill_bytes(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for CoreRng {}
+
+impl snow::types::Random for CoreRng {
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), snow::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Noise primitives from snow's default resolver, randomness from `rng()`
+struct NoiseResolver;
+
+impl snow::resolvers::CryptoResolver for NoiseResolver {
+    fn resolve_rng(&self) -> Option<Box<dyn snow::types::Random>> {
+        Some(Box::new(CoreRng))
+    }
+
+    fn resolve_dh(&self, _: &snow::params::DHChoice) -> Option<Box<dyn snow::types::Dh>> {
+        None
+    }
+
+    fn resolve_hash(&self, _: &snow::params::HashChoice) -> Option<Box<dyn snow::types::Hash>> {
+        None
+    }
+
+    fn resolve_cipher(&self, _: &snow::params::CipherChoice) -> Option<Box<dyn snow::types::Cipher>> {
+        None
+    }
+}
+
+/// A Noise builder drawing its ephemeral keys from `rng()`.
+pub fn noise_builder(params: snow::params::NoiseParams) -> snow::Builder<'static> {
+    snow::Builder::with_resolver(
+        params,
+        Box::new(snow::resolvers::FallbackResolver::new(
+            Box::new(NoiseResolver),
+            Box::new(snow::resolvers::DefaultResolver),
+        )),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_streams_repeat() {
+        let draw = || {
+            let mut bytes = [0u8; 32];
+            rng().fill_bytes(&mut bytes);
+            bytes
+        };
+        assert_ne!(draw(), draw());
+
+        #[cfg(feature = "seeded-rng")]
+        {
+            // Other tests draw concurrently, so compare streams taken under one lock
+            let stream = |seed_value: u64| {
+                let mut seeded = SEEDED.lock().unwrap();
+                *seeded = Some(<rand_chacha::ChaCha20Rng as rand::SeedableRng>::seed_from_u64(seed_value));
+                let mut bytes = [0u8; 64];
+                seeded.as_mut().unwrap().fill_bytes(&mut bytes);
+                *seeded = None;
+                bytes
+            };
+            assert_eq!(stream(7), stream(7));
+            assert_ne!(stream(7), stream(8));
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Safety numbers
+//!
+//! A scanned or pasted friend key could have been swapped on the way. To
+//! rule that out, both users compare a safety number, in person or over a
+//! channel they trust, and mark the friend verified when it matches.
+//!
+//! The number has 60 digits. Each side's Ed25519 key gives 30 of them: the
+//! key is hashed with SHA-512, `ITERATIONS` times, and the first 30 bytes of
+//! the result are read as six 5-byte big-endian numbers, each modulo 100000.
+//! The two halves are put in ascending order, so both users see the same
+//! number, shown in twelve groups of five.
+
+use sha2::{Digest, Sha512};
+
+/// Format version, hashed in with each key
+const VERSION: u16 = 0;
+
+/// SHA-512 rounds per key (slows down searching for a colliding key)
+pub const ITERATIONS: u32 = 5200;
+
+/// 30 digits for one key
+fn fingerprint(ed25519_public: &[u8; 32]) -> String {
+    let mut hash = Sha512::new()
+        .chain_update(VERSION.to_be_bytes())
+        .chain_update(b"meshapp-safety-number")
+        .chain_update(ed25519_public)
+        .finalize();
+    for _ in 1..ITERATIONS {
+        hash = Sha512::new().chain_update(hash).chain_update(ed25519_public).finalize();
+    }
+    hash[..30]
+        .chunks(5)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect()
+}
+
+/// The safety number of two Ed25519 keys, the same whichever side computes it.
+pub fn compute(own_ed25519: &[u8; 32], friend_ed25519: &[u8; 32]) -> String {
+    let mut halves = [fingerprint(own_ed25519), fingerprint(friend_ed25519)];
+    halves.sort();
+    halves.concat()
+}
+
+/// A safety number in groups of five digits.
+pub fn display(number: &str) -> String {
+    number
+        .as_bytes()
+        .chunks(5)
+        .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_both_sides_see_the_same_number() {
+        let (alice, bob, mallory) = ([1u8; 32], [2u8; 32], [3u8; 32]);
+        let number = compute(&alice, &bob);
+        assert_eq!(number.len(), 60);
+        assert!(number.chars().all(|c| c.is_ascii_digit()));
+        assert_eq!(number, compute(&bob, &alice));
+        assert_ne!(number, compute(&alice, &mallory));
+
+        let shown = display(&number);
+        assert_eq!(shown.split(' ').count(), 12);
+        assert_eq!(shown.replace(' ', ""), number);
+    }
+}
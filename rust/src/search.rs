@@ -0,0 +1,176 @@
+//! Unified search
+//!
+//! Matching and ranking for `global_search`, which looks through contacts,
+//! channel names and readable message history at once. Matching is
+//! case-insensitive; a hit scores higher the closer it is to a whole-field
+//! match:
+//! - 100: the whole field equals the query
+//! - 75: the field starts with the query
+//! - 50: a word in the field starts with the query
+//! - 25: the query appears anywhere else
+//!
+//! With the message index on (see `message_index`), messages are found
+//! through the `message_search` FTS5 table instead of by decrypting and
+//! scanning each conversation; the hits are then scored as above.
+
+use crate::forward;
+use crate::groups;
+use crate::link_preview;
+use serde::Serialize;
+
+/// Results per group when the caller passes 0
+pub const DEFAULT_RESULT_LIMIT: usize = 20;
+
+/// Messages scanned per conversation without the search index, and index
+/// matches considered with it
+pub const SEARCH_SCAN_LIMIT: u32 = 2000;
+
+/// Characters of context kept on each side of a match in snippets
+const SNIPPET_CONTEXT_CHARS: usize = 30;
+
+#[derive(Serialize, Debug)]
+pub struct ContactHit {
+    pub user_id: String,
+    pub display_name: String,
+    pub score: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChannelHit {
+    pub channel_id: String,
+    pub channel_type: String,
+    pub name: String,
+    pub score: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MessageHit {
+    pub channel_id: String,
+    pub message_id: String,
+    /// Friend the DM is with (None for notes)
+    pub peer_user_id: Option<String>,
+    pub snippet: String,
+    pub timestamp: i64,
+    pub score: u32,
+}
+
+/// Grouped results, each group best match first
+#[derive(Serialize, Debug, Default)]
+pub struct SearchResults {
+    pub contacts: Vec<ContactHit>,
+    pub channels: Vec<ChannelHit>,
+    pub messages: Vec<MessageHit>,
+}
+
+impl SearchResults {
+    /// Sort each group by score (messages: then newest first) and cut to `limit`.
+    pub fn rank(&mut self, limit: usize) {
+        let limit = if limit == 0 { DEFAULT_RESULT_LIMIT } else { limit };
+        self.contacts.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| a.display_name.cmp(&b.display_name)));
+        self.channels.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| a.name.cmp(&b.name)));
+        self.messages.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| b.timestamp.cmp(&a.timestamp)));
+        self.contacts.truncate(limit);
+        self.channels.truncate(limit);
+        self.messages.truncate(limit);
+    }
+}
+
+/// Lowercase char by char so positions line up with the original text.
+fn fold(text: &str) -> Vec<char> {
+    text.chars().map(|c| c.to_lowercase().next().unwrap_or(c)).collect()
+}
+
+/// Char position of the best-scoring occurrence of `query` in `text`.
+fn find(text: &[char], query: &[char]) -> Option<(usize, u32)> {
+    if query.is_empty() || query.len() > text.len() {
+        return None;
+    }
+    if text == query {
+        return Some((0, 100));
+    }
+
+    let mut best: Option<(usize, u32)> = None;
+    for start in 0..=text.len() - query.len() {
+        if text[start..start + query.len()] != *query {
+            continue;
+        }
+        let score = if start == 0 {
+            75
+        } else if !text[start - 1].is_alphanumeric() {
+            50
+        } else {
+            25
+        };
+        if best.is_none_or(|(_, s)| score > s) {
+            best = Some((start, score));
+        }
+        if score >= 50 {
+            break;
+        }
+    }
+    best
+}
+
+/// Score `text` against `query` (None if it does not match).
+pub fn match_score(text: &str, query: &str) -> Option<u32> {
+    find(&fold(text), &fold(query)).map(|(_, score)| score)
+}
+
+/// Score and excerpt a message: the match with some context on each side.
+pub fn snippet(text: &str, query: &str) -> Option<(String, u32)> {
+    let (start, score) = find(&fold(text), &fold(query))?;
+    let chars: Vec<char> = text.chars().collect();
+    let from = start.saturating_sub(SNIPPET_CONTEXT_CHARS);
+    let to = (start + query.chars().count() + SNIPPET_CONTEXT_CHARS).min(chars.len());
+
+    let mut out = String::new();
+    if from > 0 {
+        out.push('…');
+    }
+    out.extend(&chars[from..to]);
+    if to < chars.len() {
+        out.push('…');
+    }
+    Some((out, score))
+}
+
+/// The text a message shows, as indexed for search (None for group invites).
+pub fn indexable_text(plaintext: &str) -> Option<String> {
+    if groups::parse_invite(plaintext).is_some() {
+        return None;
+    }
+    let text = forward::parse(plaintext).map_or_else(|| plaintext.to_string(), |env| env.text);
+    Some(link_preview::parse(&text).map_or(text, |(text, _)| text))
+}
+
+/// An FTS5 query for what the user typed: every word must appear, the last
+/// one as a prefix (None if there are no words).
+pub fn fts_query(query: &str) -> Option<String> {
+    let words: Vec<String> = query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| format!("\"{}\"", w))
+        .collect();
+    (!words.is_empty()).then(|| format!("{}*", words.join(" ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_match_score_and_snippet() {
+        assert_eq!(match_score("Alice", "alice"), Some(100));
+        assert_eq!(match_score("Alice Smith", "ali"), Some(75));
+        assert_eq!(match_score("Hiking club", "CLUB"), Some(50));
+        assert_eq!(match_score("Malice", "ali"), Some(25));
+        assert_eq!(match_score("Bob", "ali"), None);
+
+        let text = format!("{} meet at the trailhead {}", "x".repeat(50), "y".repeat(50));
+        let (s, _) = snippet(&text, "trailhead").unwrap();
+        assert!(s.starts_with('…') && s.ends_with('…') && s.contains("trailhead"));
+
+        assert_eq!(fts_query("meet at trail").as_deref(), Some("\"meet\" \"at\" \"trail\"*"));
+        assert_eq!(fts_query(" \"*( "), None);
+    }
+}
@@ -0,0 +1,205 @@
+//! Send status of our messages
+//!
+//! Every message we send (stored with `Storage::store_outgoing_batch`) moves
+//! through one state machine, kept in `send_states`, so the ticks the app
+//! shows never contradict each other:
+//!
+//!   composing → queued → routed → relayed → delivered → read
+//!                  ↘ failed ↗
+//!
+//! - composing: stored, not handed to the router yet
+//! - queued: no transport took it; it waits in the outbox (see `outbox`)
+//! - routed: a transport took it
+//! - relayed: another node forwarded it (we heard it back)
+//! - delivered / read: a receipt said so (see `receipts`, `group_delivery`)
+//! - failed: the outbox gave up on it
+//!
+//! A message only moves forward along the line, skipping states when a later
+//! signal comes first (a receipt before any relay is heard). A failed message
+//! leaves that state when it is sent again or a late receipt arrives. Other
+//! moves are refused: signals in a mesh arrive late and twice, and a stale
+//! one must not take the ticks back. `attempts` counts outbox retries that
+//! found no transport.
+//!
+//! Every change emits `send_state` { message_id, channel_id, state, previous,
+//! attempts }.
+
+use crate::events;
+use crate::storage::{SendStateRow, Storage};
+use serde::Serialize;
+use serde_json::json;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SendState {
+    Composing = 0,
+    Queued = 1,
+    Routed = 2,
+    Relayed = 3,
+    Delivered = 4,
+    Read = 5,
+    Failed = 6,
+}
+
+impl SendState {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(SendState::Composing),
+            1 => Some(SendState::Queued),
+            2 => Some(SendState::Routed),
+            3 => Some(SendState::Relayed),
+            4 => Some(SendState::Delivered),
+            5 => Some(SendState::Read),
+            6 => Some(SendState::Failed),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SendState::Composing => "composing",
+            SendState::Queued => "queued",
+            SendState::Routed => "routed",
+            SendState::Relayed => "relayed",
+            SendState::Delivered => "delivered",
+            SendState::Read => "read",
+            SendState::Failed => "failed",
+        }
+    }
+
+    /// Whether a message may move from this state to `to`.
+    pub fn can_move_to(self, to: SendState) -> bool {
+        match (self, to) {
+            (SendState::Failed, SendState::Failed | SendState::Composing | SendState::Relayed) => false,
+            (SendState::Failed, _) => true,
+            (from, SendState::Failed) => matches!(from, SendState::Composing | SendState::Queued),
+            (from, to) => to > from,
+        }
+    }
+}
+
+/// A message's send state as reported to the app
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SendStatus {
+    pub message_id: String,
+    pub channel_id: String,
+    pub state: &'static str,
+    pub attempts: u32,
+    pub updated_at: i64,
+}
+
+impl From<&SendStateRow> for SendStatus {
+    fn from(row: &SendStateRow) -> Self {
+        Self {
+            message_id: hex::encode(row.message_id),
+            channel_id: hex::encode(row.channel_id),
+            state: SendState::from_u8(row.state).unwrap_or(SendState::Composing).name(),
+            attempts: row.attempts,
+            updated_at: row.updated_at,
+        }
+    }
+}
+
+/// A message's current state (None if we did not send it).
+pub fn get(storage: &Storage, message_id: [u8; 32]) -> Result<Option<SendStateRow>, String> {
+    storage.get_send_state(message_id)
+}
+
+/// Move a message to `to`. Returns false (and changes nothing) if we did not
+/// send it or the move is not legal from where it is.
+pub fn advance(storage: &Storage, message_id: [u8; 32], to: SendState, now: i64) -> Result<bool, String> {
+    advance_from(storage, message_id, |_| true, to, now)
+}
+
+fn advance_from(
+    storage: &Storage,
+    message_id: [u8; 32],
+    allowed: impl Fn(SendState) -> bool,
+    to: SendState,
+    now: i64,
+) -> Result<bool, String> {
+    let moved = storage.with_transaction(|s| {
+        let Some(row) = s.get_send_state(message_id)? else {
+            return Ok(None);
+        };
+        let from = SendState::from_u8(row.state).ok_or("Unknown send state")?;
+        if !allowed(from) || !from.can_move_to(to) {
+            return Ok(None);
+        }
+        let moved = SendStateRow { state: to as u8, updated_at: now, ..row };
+        s.put_send_state(&moved)?;
+        Ok(Some((from, moved)))
+    })?;
+    let Some((from, row)) = moved else {
+        return Ok(false);
+    };
+    events::emit(
+        "send_state",
+        json!({
+            "message_id": hex::encode(row.message_id),
+            "channel_id": hex::encode(row.channel_id),
+            "state": to.name(),
+            "previous": from.name(),
+            "attempts": row.attempts,
+        }),
+    );
+    Ok(true)
+}
+
+/// A message the router was handed: routed, unless no transport took it and
+/// it is queued already.
+pub fn sent(storage: &Storage, message_id: [u8; 32], now: i64) -> Result<bool, String> {
+    advance_from(storage, message_id, |from| matches!(from, SendState::Composing | SendState::Failed), SendState::Routed, now)
+}
+
+/// Count an outbox retry that found no transport.
+pub fn retried(storage: &Storage, message_id: [u8; 32], now: i64) -> Result<(), String> {
+    if let Some(row) = storage.get_send_state(message_id)? {
+        storage.put_send_state(&SendStateRow { attempts: row.attempts + 1, updated_at: now, ..row })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::OutgoingMessage;
+
+    #[test]
+    fn test_states_only_move_forward() {
+        let storage = Storage::in_memory().unwrap();
+        let message_id = [1u8; 32];
+        storage
+            .store_outgoing_batch(&[OutgoingMessage {
+                message_id,
+                channel_id: [2u8; 32],
+                channel_type: "dm",
+                ciphertext: vec![1],
+                timestamp: 100,
+                ttl: 3,
+                priority: 1,
+            }])
+            .unwrap();
+        let state = |s: &Storage| SendState::from_u8(get(s, message_id).unwrap().unwrap().state).unwrap();
+        assert_eq!(state(&storage), SendState::Composing);
+
+        // Held, retried twice, then given up; a resend goes out
+        assert!(advance(&storage, message_id, SendState::Queued, 101).unwrap());
+        assert!(!sent(&storage, message_id, 101).unwrap());
+        retried(&storage, message_id, 102).unwrap();
+        retried(&storage, message_id, 103).unwrap();
+        assert!(advance(&storage, message_id, SendState::Failed, 104).unwrap());
+        assert!(sent(&storage, message_id, 105).unwrap());
+        assert_eq!((state(&storage), get(&storage, message_id).unwrap().unwrap().attempts), (SendState::Routed, 2));
+
+        // A receipt can come before the relay is heard; stale signals do not go back
+        assert!(advance(&storage, message_id, SendState::Read, 106).unwrap());
+        assert!(!advance(&storage, message_id, SendState::Delivered, 107).unwrap());
+        assert!(!advance(&storage, message_id, SendState::Relayed, 107).unwrap());
+        assert!(!advance(&storage, message_id, SendState::Failed, 107).unwrap());
+        assert_eq!(state(&storage), SendState::Read);
+
+        // Messages we did not send have no state
+        assert!(!advance(&storage, [9u8; 32], SendState::Delivered, 108).unwrap());
+        assert!(get(&storage, [9u8; 32]).unwrap().is_none());
+    }
+}
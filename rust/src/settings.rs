@@ -0,0 +1,265 @@
+//! Settings
+//!
+//! Core and app configuration, stored as JSON values in the `settings`
+//! table under dotted keys. Known keys have a default and a type that
+//! `set_value` enforces; any other key is stored as given, so the app can
+//! keep its own preferences (e.g. "app.theme") alongside the core's.
+//!
+//! Every change emits a `setting_changed` event with the key and new value.
+
+use crate::error::MeshError;
+use crate::events;
+use crate::memory_budget::{self, BUDGET};
+use crate::node_roles::{self, NodeRole};
+use crate::notifications::{QuietHours, QUIET_HOURS_KEY};
+use crate::optimization::BatteryMode;
+use crate::relay_policy;
+use crate::retention;
+use crate::storage::Storage;
+use crate::webhooks;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+
+/// Battery mode name ("Performance" | "Balanced" | "PowerSaving")
+pub const BATTERY_MODE: &str = "battery.mode";
+/// Delete messages older than this many days (0 keeps them forever)
+pub const RETENTION_MAX_AGE_DAYS: &str = "retention.max_age_days";
+/// Keep only this many newest messages per channel (0 keeps any number)
+pub const RETENTION_MAX_MESSAGES: &str = "retention.max_messages";
+/// Retention policies by channel type, overriding the two above (see `retention`)
+pub const RETENTION_BY_CHANNEL_TYPE: &str = "retention.by_channel_type";
+/// Most message ciphertext bytes kept; the oldest go first (0 keeps any amount)
+pub const STORAGE_MAX_MESSAGE_BYTES: &str = "storage.max_message_bytes";
+/// The node role whose preset was last applied (see `node_roles`)
+pub const NODE_ROLE: &str = "node.role";
+/// Whether packets from other nodes are forwarded
+pub const RELAY_ENABLED: &str = "relay.enabled";
+/// Upper bound on the TTL of forwarded packets
+pub const RELAY_MAX_TTL: &str = "relay.max_ttl";
+/// Which channels are relayed, by channel-id prefix or type (see `relay_policy`)
+pub const RELAY_CHANNEL_RULES: &str = "relay.channel_rules";
+/// Most bytes relayed for other nodes per UTC day (0: no cap, see `relay_budget`)
+pub const RELAY_DAILY_BUDGET_BYTES: &str = "relay.daily_budget_bytes";
+/// Whether read receipts are sent to others
+pub const PRIVACY_READ_RECEIPTS: &str = "privacy.read_receipts";
+/// Oldest history (seconds) replayed to a peer that joins a channel
+pub const REPLAY_MAX_AGE_SECS: &str = "replay.max_age_secs";
+/// Most ciphertext bytes replayed per request
+pub const REPLAY_MAX_BYTES: &str = "replay.max_bytes";
+/// Minimum seconds between replays of one channel to the same peer
+pub const REPLAY_MIN_INTERVAL_SECS: &str = "replay.min_interval_secs";
+/// Memory the relay path may hold in queues and caches (bytes)
+pub const MEMORY_BUDGET_BYTES: &str = "memory.budget_bytes";
+/// How long negotiated peer capabilities are reused (seconds)
+pub const PEERS_CAPABILITY_TTL_SECS: &str = "peers.capability_ttl_secs";
+/// How far back (seconds) the key epochs shared with a late joiner of a protected geo channel go
+pub const CHANNELS_LATE_JOIN_HISTORY_SECS: &str = "channels.late_join_history_secs";
+/// Decrypt DMs once on arrival and keep their plaintext (encrypted databases only, see `message_index`)
+pub const MESSAGES_DECRYPT_ON_INGEST: &str = "messages.decrypt_on_ingest";
+
+/// Local webhooks of relay and gateway daemons, [{ url, events?, secret? }] (see `webhooks`)
+pub const DAEMON_WEBHOOKS: &str = "daemon.webhooks";
+
+/// Every key with a core default, in the order `all` reports them
+pub const KNOWN_KEYS: &[&str] = &[
+    BATTERY_MODE,
+    RETENTION_MAX_AGE_DAYS,
+    RETENTION_MAX_MESSAGES,
+    RETENTION_BY_CHANNEL_TYPE,
+    STORAGE_MAX_MESSAGE_BYTES,
+    NODE_ROLE,
+    RELAY_ENABLED,
+    RELAY_MAX_TTL,
+    RELAY_CHANNEL_RULES,
+    RELAY_DAILY_BUDGET_BYTES,
+    PRIVACY_READ_RECEIPTS,
+    REPLAY_MAX_AGE_SECS,
+    REPLAY_MAX_BYTES,
+    REPLAY_MIN_INTERVAL_SECS,
+    MEMORY_BUDGET_BYTES,
+    PEERS_CAPABILITY_TTL_SECS,
+    CHANNELS_LATE_JOIN_HISTORY_SECS,
+    MESSAGES_DECRYPT_ON_INGEST,
+    DAEMON_WEBHOOKS,
+    QUIET_HOURS_KEY,
+];
+
+/// Default value of a known key (None for app-defined keys).
+pub fn default_value(key: &str) -> Option<Value> {
+    let value = match key {
+        BATTERY_MODE => json!(BatteryMode::Balanced.name()),
+        RETENTION_MAX_AGE_DAYS => json!(0),
+        RETENTION_MAX_MESSAGES => json!(0),
+        RETENTION_BY_CHANNEL_TYPE => retention::default_channel_type_policies(),
+        STORAGE_MAX_MESSAGE_BYTES => json!(0),
+        NODE_ROLE => json!(NodeRole::Phone.name()),
+        RELAY_ENABLED => json!(true),
+        RELAY_MAX_TTL => json!(8),
+        RELAY_CHANNEL_RULES => json!([]),
+        RELAY_DAILY_BUDGET_BYTES => json!(0),
+        PRIVACY_READ_RECEIPTS => json!(true),
+        REPLAY_MAX_AGE_SECS => json!(24 * 60 * 60),
+        REPLAY_MAX_BYTES => json!(64 * 1024),
+        REPLAY_MIN_INTERVAL_SECS => json!(60),
+        MEMORY_BUDGET_BYTES => json!(memory_budget::DEFAULT_BUDGET_BYTES),
+        PEERS_CAPABILITY_TTL_SECS => json!(7 * 24 * 60 * 60),
+        CHANNELS_LATE_JOIN_HISTORY_SECS => json!(24 * 60 * 60),
+        MESSAGES_DECRYPT_ON_INGEST => json!(false),
+        DAEMON_WEBHOOKS => json!([]),
+        QUIET_HOURS_KEY => serde_json::to_value(QuietHours::default()).ok()?,
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Check a value against the type of a known key.
+fn validate(key: &str, value: &Value) -> Result<(), String> {
+    let ok = match key {
+        BATTERY_MODE => value.as_str().and_then(BatteryMode::from_name).is_some(),
+        NODE_ROLE => value.as_str().and_then(NodeRole::from_name).is_some(),
+        RETENTION_MAX_AGE_DAYS
+        | RETENTION_MAX_MESSAGES
+        | REPLAY_MAX_AGE_SECS
+        | REPLAY_MAX_BYTES
+        | REPLAY_MIN_INTERVAL_SECS
+        | PEERS_CAPABILITY_TTL_SECS
+        | CHANNELS_LATE_JOIN_HISTORY_SECS
+        | RELAY_DAILY_BUDGET_BYTES
+        | STORAGE_MAX_MESSAGE_BYTES => {
+            value.as_u64().is_some()
+        }
+        RELAY_ENABLED | PRIVACY_READ_RECEIPTS | MESSAGES_DECRYPT_ON_INGEST => value.is_boolean(),
+        RELAY_MAX_TTL => value.as_u64().is_some_and(|v| v <= u8::MAX as u64),
+        MEMORY_BUDGET_BYTES => value.as_u64().is_some_and(|v| v >= memory_budget::MIN_BUDGET_BYTES),
+        RETENTION_BY_CHANNEL_TYPE => {
+            serde_json::from_value::<retention::ChannelTypePolicies>(value.clone())
+                .map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid value for {}: {}", key, e)))?;
+            true
+        }
+        RELAY_CHANNEL_RULES => {
+            relay_policy::parse_rules(value).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid value for {}: {}", key, e)))?;
+            true
+        }
+        DAEMON_WEBHOOKS => {
+            webhooks::parse_hooks(value).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid value for {}: {}", key, e)))?;
+            true
+        }
+        QUIET_HOURS_KEY => {
+            let quiet: QuietHours =
+                serde_json::from_value(value.clone()).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid value for {}: {}", key, e)))?;
+            quiet.validate()?;
+            true
+        }
+        _ => true,
+    };
+    if ok {
+        Ok(())
+    } else {
+        Err(MeshError::InvalidArgument.raise(format!("Invalid value for {}: {}", key, value)))
+    }
+}
+
+/// Get a setting's value: the stored value, else the key's default, else null.
+pub fn get_value(storage: &Storage, key: &str) -> Result<Value, String> {
+    match storage.get_setting(key)? {
+        Some(raw) => serde_json::from_str(&raw).map_err(|e| format!("Failed to parse setting {}: {}", key, e)),
+        None => Ok(default_value(key).unwrap_or(Value::Null)),
+    }
+}
+
+/// Get a setting as a typed value.
+pub fn get<T: DeserializeOwned>(storage: &Storage, key: &str) -> Result<T, String> {
+    serde_json::from_value(get_value(storage, key)?).map_err(|e| format!("Failed to read setting {}: {}", key, e))
+}
+
+/// Get a boolean setting.
+pub fn get_bool(storage: &Storage, key: &str) -> Result<bool, String> {
+    get(storage, key)
+}
+
+/// Get an unsigned integer setting.
+pub fn get_u64(storage: &Storage, key: &str) -> Result<u64, String> {
+    get(storage, key)
+}
+
+/// The configured battery mode.
+pub fn battery_mode(storage: &Storage) -> Result<BatteryMode, String> {
+    let name: String = get(storage, BATTERY_MODE)?;
+    BatteryMode::from_name(&name).ok_or_else(|| MeshError::InvalidArgument.raise(format!("Invalid battery mode: {}", name)))
+}
+
+/// Load the memory budget setting into the running budget.
+pub fn apply_memory_budget(storage: &Storage) -> Result<(), String> {
+    BUDGET.set_limit(get_u64(storage, MEMORY_BUDGET_BYTES)?);
+    Ok(())
+}
+
+/// Set a setting, validating known keys. Emits `setting_changed` if the value changed.
+pub fn set_value(storage: &Storage, key: &str, value: Value) -> Result<(), String> {
+    if key.is_empty() {
+        return Err(MeshError::InvalidArgument.raise("Setting key must not be empty"));
+    }
+    validate(key, &value)?;
+
+    if get_value(storage, key)? == value {
+        return Ok(());
+    }
+    storage.set_setting(key, &value.to_string())?;
+    if key == MEMORY_BUDGET_BYTES {
+        apply_memory_budget(storage)?;
+    }
+    if key == NODE_ROLE {
+        if let Some(role) = value.as_str().and_then(NodeRole::from_name) {
+            node_roles::apply_preset(storage, role)?;
+        }
+    }
+    if key == RELAY_CHANNEL_RULES {
+        relay_policy::reset_stats();
+    }
+    if matches!(key, RETENTION_MAX_AGE_DAYS | RETENTION_BY_CHANNEL_TYPE) {
+        storage.clear_message_expiry()?;
+    }
+    if key == MESSAGES_DECRYPT_ON_INGEST && value == json!(false) {
+        storage.clear_message_plaintexts()?;
+    }
+    events::emit("setting_changed", json!({ "key": key, "value": value }));
+    Ok(())
+}
+
+/// All settings: known keys (with defaults filled in) plus any app-defined keys.
+pub fn all(storage: &Storage) -> Result<Map<String, Value>, String> {
+    let mut map = Map::new();
+    for key in KNOWN_KEYS {
+        map.insert(key.to_string(), get_value(storage, key)?);
+    }
+    for (key, raw) in storage.list_settings()? {
+        if !map.contains_key(&key) {
+            let value = serde_json::from_str(&raw).map_err(|e| format!("Failed to parse setting {}: {}", key, e))?;
+            map.insert(key, value);
+        }
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings_defaults_validation_and_app_keys() {
+        let storage = Storage::in_memory().unwrap();
+
+        assert!(get_bool(&storage, RELAY_ENABLED).unwrap());
+        assert!(matches!(battery_mode(&storage).unwrap(), BatteryMode::Balanced));
+
+        assert!(set_value(&storage, BATTERY_MODE, json!("turbo")).is_err());
+        assert!(set_value(&storage, RELAY_MAX_TTL, json!(300)).is_err());
+        set_value(&storage, BATTERY_MODE, json!("PowerSaving")).unwrap();
+        assert!(matches!(battery_mode(&storage).unwrap(), BatteryMode::PowerSaving));
+
+        set_value(&storage, "app.theme", json!("dark")).unwrap();
+        let all = all(&storage).unwrap();
+        assert_eq!(all["app.theme"], json!("dark"));
+        assert_eq!(all[RETENTION_MAX_AGE_DAYS], json!(0));
+    }
+}
@@ -0,0 +1,104 @@
+//! Emergency (SOS) broadcast channel
+//!
+//! One well-known channel every node recognizes without joining it, for
+//! disaster relief. Its messages:
+//! - are sent with `SOS_TTL` and relayed without the `relay.max_ttl` cap
+//!   (dedup stops them from looping)
+//! - skip batching and go ahead of everything else in the ingest queue and
+//!   batcher; memory budget shedding drops them last
+//! - raise an `sos_message` event instead of waiting for a normal fetch
+//!
+//! SOS payloads are plain UTF-8 text so any node can read them. They are not
+//! authenticated; the app should present them as unverified reports.
+
+use crate::error::MeshError;
+use crate::events;
+use crate::priority::Priority;
+use crate::storage::Storage;
+use crate::transport::{Packet, PacketKind};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+
+/// channels.type of the SOS channel
+pub const SOS_CHANNEL_TYPE: &str = "sos";
+
+/// TTL of SOS messages we send
+pub const SOS_TTL: u8 = u8::MAX;
+
+/// Shed rank above every packet kind (see `memory_budget::shed_rank`)
+pub const SOS_SHED_RANK: u8 = u8::MAX;
+
+/// Longest SOS text, in bytes
+pub const MAX_SOS_TEXT_LEN: usize = 1024;
+
+/// SHA256("meshapp-sos-v1")
+pub static SOS_CHANNEL_ID: Lazy<[u8; 32]> = Lazy::new(|| Sha256::digest(b"meshapp-sos-v1").into());
+
+pub fn is_sos(packet: &Packet) -> bool {
+    packet.kind == PacketKind::Message && packet.channel_id == *SOS_CHANNEL_ID
+}
+
+/// Build an SOS message packet.
+pub fn packet(packet_id: [u8; 32], text: &str) -> Result<Packet, String> {
+    if text.trim().is_empty() {
+        return Err(MeshError::InvalidArgument.raise("SOS text must not be empty"));
+    }
+    if text.len() > MAX_SOS_TEXT_LEN {
+        return Err(MeshError::InvalidArgument.raise(format!("SOS text longer than {} bytes", MAX_SOS_TEXT_LEN)));
+    }
+    Ok(Packet {
+        packet_id,
+        channel_id: *SOS_CHANNEL_ID,
+        kind: PacketKind::Message,
+        ttl: SOS_TTL,
+        payload: text.as_bytes().to_vec(),
+        priority: Priority::Urgent,
+    })
+}
+
+/// Handle a new SOS message (already stored): make sure the channel is listed
+/// and emit `sos_message` { message_id, channel_id, text, hops_left, timestamp }.
+pub fn on_message(storage: &Storage, packet: &Packet, now: i64) -> Result<(), String> {
+    storage.upsert_channel(packet.channel_id, SOS_CHANNEL_TYPE)?;
+    events::emit(
+        "sos_message",
+        serde_json::json!({
+            "message_id": hex::encode(packet.packet_id),
+            "channel_id": hex::encode(packet.channel_id),
+            "text": String::from_utf8_lossy(&packet.payload),
+            "hops_left": packet.ttl,
+            "timestamp": now,
+        }),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::{IngestQueue, IngestStatus};
+
+    #[test]
+    fn test_sos_preempts_a_full_ingest_queue() {
+        let queue = IngestQueue::new(2);
+        let normal = Packet {
+            packet_id: [1u8; 32],
+            channel_id: [2u8; 32],
+            kind: PacketKind::Message,
+            ttl: 3,
+            payload: vec![1],
+            priority: Priority::Normal,
+        };
+        assert_eq!(queue.offer(normal.clone()), IngestStatus::Queued);
+        assert_eq!(queue.offer(normal.clone()), IngestStatus::Queued);
+        assert_eq!(queue.offer(normal), IngestStatus::DroppedOverloaded);
+
+        let sos = packet([9u8; 32], "trapped at the bridge").unwrap();
+        assert!(is_sos(&sos));
+        assert_eq!(queue.offer(sos), IngestStatus::Queued);
+        let drained = queue.drain();
+        assert_eq!(drained.len(), 2);
+        assert_eq!(drained[0].packet_id, [9u8; 32]);
+        assert!(packet([0u8; 32], " ").is_err());
+    }
+}
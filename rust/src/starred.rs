@@ -0,0 +1,129 @@
+//! Starred messages
+//!
+//! Starring copies a message out of its channel, so the star survives the
+//! channel being cleared. The user picks how it is kept:
+//! - `encrypted`: the ciphertext plus the key material needed to decrypt it
+//!   again (the friend's public key for DMs, the channel key for protected
+//!   groups; notes need only our identity)
+//! - `snapshot`: the decrypted text
+//!
+//! Stars are local-only and never routed.
+
+use crate::error::MeshError;
+use crate::storage::{MessageRow, StarredRow};
+use sha2::{Digest, Sha256};
+
+/// How a starred message is kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StarMode {
+    Encrypted,
+    Snapshot,
+}
+
+impl StarMode {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "encrypted" => Ok(Self::Encrypted),
+            "snapshot" => Ok(Self::Snapshot),
+            other => Err(MeshError::InvalidArgument.raise(format!("Unknown star mode: {}", other))),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Encrypted => "encrypted",
+            Self::Snapshot => "snapshot",
+        }
+    }
+}
+
+/// What is needed (besides our identity) to decrypt a stored message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyMaterial {
+    Notes,
+    Dm { friend_ed25519_public: [u8; 32] },
+    Channel { key: [u8; 32] },
+}
+
+impl KeyMaterial {
+    /// (key_kind, key) columns of `starred_messages`
+    pub fn to_columns(self) -> (&'static str, Option<[u8; 32]>) {
+        match self {
+            Self::Notes => ("notes", None),
+            Self::Dm { friend_ed25519_public } => ("dm", Some(friend_ed25519_public)),
+            Self::Channel { key } => ("channel", Some(key)),
+        }
+    }
+
+    pub fn from_columns(kind: &str, key: Option<[u8; 32]>) -> Result<Self, String> {
+        match (kind, key) {
+            ("notes", _) => Ok(Self::Notes),
+            ("dm", Some(friend_ed25519_public)) => Ok(Self::Dm { friend_ed25519_public }),
+            ("channel", Some(key)) => Ok(Self::Channel { key }),
+            _ => Err(MeshError::InvalidArgument.raise(format!("Invalid key material: {}", kind))),
+        }
+    }
+
+    /// User id of the friend a DM is with.
+    pub fn friend_user_id(friend_ed25519_public: &[u8; 32]) -> [u8; 32] {
+        Sha256::digest(friend_ed25519_public).into()
+    }
+}
+
+/// Build the row starring `message`. Its ciphertext and `material` are kept
+/// for `Encrypted`, `plaintext` for `Snapshot`.
+pub fn star_row(
+    mode: StarMode,
+    message: &MessageRow,
+    author: Option<[u8; 32]>,
+    material: KeyMaterial,
+    plaintext: String,
+    starred_at: i64,
+) -> StarredRow {
+    let (ciphertext, key_kind, key, plaintext) = match mode {
+        StarMode::Encrypted => {
+            let (kind, key) = material.to_columns();
+            (Some(message.ciphertext.clone()), Some(kind.to_string()), key, None)
+        }
+        StarMode::Snapshot => (None, None, None, Some(plaintext)),
+    };
+    StarredRow {
+        message_id: message.message_id,
+        channel_id: message.channel_id,
+        timestamp: message.timestamp,
+        author,
+        starred_at,
+        mode: mode.as_str().to_string(),
+        ciphertext,
+        key_kind,
+        key,
+        plaintext,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_star_modes_keep_only_what_was_chosen() {
+        let message = MessageRow {
+            message_id: [1u8; 32],
+            channel_id: [2u8; 32],
+            ciphertext: vec![1, 2],
+            timestamp: 10,
+            ttl: 0,
+            kind: 0,
+            priority: 1,
+        };
+        let material = KeyMaterial::Channel { key: [7u8; 32] };
+        let row = star_row(StarMode::Encrypted, &message, None, material, "hi".into(), 20);
+        assert!(row.plaintext.is_none());
+        assert_eq!(KeyMaterial::from_columns(row.key_kind.as_deref().unwrap(), row.key).unwrap(), material);
+
+        let row = star_row(StarMode::Snapshot, &message, None, material, "hi".into(), 20);
+        assert!(row.ciphertext.is_none() && row.key.is_none());
+        assert_eq!(row.plaintext.as_deref(), Some("hi"));
+        assert!(StarMode::parse("cloud").is_err());
+    }
+}
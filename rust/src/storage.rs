@@ -2,16 +2,165 @@
 //!
 //! SQLite-backed offline-first storage for messages and channels.
 //! Tables:
-//! - messages(message_id BLOB PRIMARY KEY, channel_id BLOB, ciphertext BLOB, timestamp INTEGER, ttl INTEGER)
-//! - channels(channel_id BLOB PRIMARY KEY, type TEXT)
+//! - messages(message_id BLOB PRIMARY KEY, channel_id BLOB, ciphertext BLOB, timestamp INTEGER, ttl INTEGER, kind INTEGER,
+//!   expires_at INTEGER, priority INTEGER, seq INTEGER) (expires_at: when retention deletes the message, stamped lazily,
+//!   see `retention`; priority: as the sender marked it, see `priority`; seq: increases with every message stored or
+//!   placeholder filled, a cursor for incremental reads, see `fetch_messages_since_seq`)
+//!   (system messages hold a JSON event in `ciphertext`, see `system_messages`; placeholders for messages
+//!   still on their way hold nothing, see `message_futures`)
+//! - channels(channel_id BLOB PRIMARY KEY, type TEXT, muted INTEGER, mention_only INTEGER, sound_profile TEXT, name TEXT,
+//!   pinned INTEGER, sort_order INTEGER, ui_metadata TEXT, observe_only INTEGER, topic TEXT, metadata_updated_at INTEGER,
+//!   metadata_updated_by BLOB, retention_secs INTEGER)
+//!   (the channels we subscribe to; observe_only geo channels are stored but never relayed or beaconed on; a
+//!   group's name and topic were last set by metadata_updated_by at metadata_updated_at, see `group_metadata`;
+//!   retention_secs makes the channel's messages disappear that long after they were sent, see `retention`)
+//! - channel_keys(channel_id BLOB PRIMARY KEY, key BLOB, added_at INTEGER): keys of protected channels
+//! - attachments(attachment_id BLOB PRIMARY KEY, message_id BLOB, channel_id BLOB, parent_id BLOB,
+//!   mime TEXT, size INTEGER, chunk_count INTEGER, complete INTEGER, created_at INTEGER, requested INTEGER,
+//!   chunk_key BLOB) (chunk_key encrypts the chunks of attachments of private channels, see `attachments`)
+//! - attachment_chunks(attachment_id BLOB, chunk_index INTEGER, data BLOB) for incoming transfers
+//! - attachment_refs(attachment_id BLOB, message_id BLOB): messages referencing a blob
+//! - settings(key TEXT PRIMARY KEY, value TEXT): app/core configuration
+//! - peers(peer_id BLOB PRIMARY KEY, last_seen INTEGER): nearby nodes reported by transports
+//! - routing_hints(channel_id BLOB, peer_id BLOB, last_seen INTEGER): peers recently heard on a channel
+//! - peer_capabilities(peer_id BLOB PRIMARY KEY, protocol_version INTEGER, ciphers TEXT, max_mtu INTEGER,
+//!   negotiated_at INTEGER, expires_at INTEGER): negotiated capabilities cached per peer (see `peer_capabilities`)
+//! - starred_messages(message_id BLOB PRIMARY KEY, channel_id BLOB, timestamp INTEGER, author BLOB, starred_at INTEGER,
+//!   mode TEXT, ciphertext BLOB, key_kind TEXT, key BLOB, plaintext TEXT): copies kept when a channel is cleared
+//! - linked_primaries(primary_ed25519 BLOB PRIMARY KEY, linked_at INTEGER): identities allowed to wipe this device
+//! - key_histories(user_id BLOB PRIMARY KEY, history TEXT, updated_at INTEGER): verified key chains (ours and friends')
+//! - channel_key_epochs(channel_id BLOB, key BLOB, added_at INTEGER): every key a protected channel has had,
+//!   so history from before a re-key stays readable (see `key_escrow`)
+//! - group_members(channel_id BLOB, user_id BLOB, added_at INTEGER): members of a protected group
+//! - group_admins(channel_id BLOB, user_id BLOB, added_at INTEGER): members whose moderation actions a group
+//!   accepts (see `moderation`)
+//! - group_mutes(channel_id BLOB, user_id BLOB, muted_at INTEGER): members an admin muted
+//! - group_deliveries(message_id BLOB PRIMARY KEY, channel_id BLOB, sent_at INTEGER, attempts INTEGER,
+//!   next_attempt INTEGER): our group messages awaiting custody receipts (see `group_delivery`)
+//! - delivery_receipts(message_id BLOB, user_id BLOB, received_at INTEGER): members that confirmed custody
+//! - client_tokens(token TEXT PRIMARY KEY, fingerprint BLOB, result TEXT, created_at INTEGER): recent sends
+//!   by app-chosen token, so repeats return the first result (see `client_tokens`)
+//! - dm_peer_keys(channel_id BLOB PRIMARY KEY, x25519_public BLOB, updated_at INTEGER): the friend's Noise
+//!   static key for a DM channel
+//! - dm_sessions(session_id BLOB PRIMARY KEY, channel_id BLOB, outgoing INTEGER, remote_static BLOB, handshake BLOB,
+//!   key BLOB, next_counter INTEGER, created_at INTEGER): established DM sessions (see `dm_crypto::DmSessionManager`)
+//! - dm_ratchets(ratchet_id BLOB PRIMARY KEY, channel_id BLOB, initiator INTEGER, remote_static BLOB, handshake BLOB,
+//!   root_key BLOB, dh_secret BLOB, dh_remote BLOB, send_chain BLOB, recv_chain BLOB, send_count INTEGER,
+//!   recv_count INTEGER, prev_send_count INTEGER, send_header_key BLOB, recv_header_key BLOB,
+//!   next_send_header_key BLOB, next_recv_header_key BLOB, received_at INTEGER, created_at INTEGER): DM Double
+//!   Ratchets seeded by Noise sessions (see `ratchet`)
+//! - dm_skipped_keys(ratchet_id BLOB, header_key BLOB, counter INTEGER, message_key BLOB, created_at INTEGER):
+//!   message keys of ratchet messages that have not arrived yet
+//! - dm_message_keys(message_id BLOB PRIMARY KEY, channel_id BLOB, outgoing INTEGER, message_key BLOB): the key
+//!   of each stored ratchet message, deleted with it, so history can be read again
+//! - message_receipts(message_id BLOB, user_id BLOB, channel_id BLOB, status INTEGER, updated_at INTEGER): how far
+//!   each reader (ourselves included) got with a message (see `receipts`)
+//! - send_states(message_id BLOB PRIMARY KEY, channel_id BLOB, state INTEGER, attempts INTEGER, updated_at INTEGER):
+//!   how far each message we sent got, and how often the outbox retried it (see `send_status`)
+//! - message_plaintexts(message_id BLOB PRIMARY KEY, channel_id BLOB, outgoing INTEGER, plaintext TEXT,
+//!   indexed_at INTEGER): DM messages decrypted once, on ingest (see `message_index`; encrypted databases only)
+//! - seen_packets(packet_id BLOB PRIMARY KEY, seen_at INTEGER): packet ids the router has handled, so its
+//!   dedup cache survives a restart (see `transport::Router`)
+//! - outbox(packet_id BLOB PRIMARY KEY, channel_id BLOB, kind INTEGER, ttl INTEGER, payload BLOB, created_at INTEGER,
+//!   attempts INTEGER, next_attempt INTEGER, priority INTEGER): packets no transport took, retried with backoff (see `outbox`)
+//! - read_markers(channel_id BLOB PRIMARY KEY, timestamp INTEGER, message_id BLOB, updated_at INTEGER): the
+//!   last message read in each channel; what follows it is unread (see `read_state`)
+//! - blocked_users(user_id BLOB PRIMARY KEY, ed25519_public BLOB, blocked_at INTEGER): users whose packets are
+//!   dropped (ed25519_public, when known, gives the DM channel the router drops; see `blocklist`)
+//! - quarantined_timestamps(message_id BLOB PRIMARY KEY, channel_id BLOB, claimed INTEGER, stored INTEGER,
+//!   quarantined_at INTEGER): message times other nodes claimed too far in the future, and what was stored
+//!   instead (see `clock`)
+//! - relay_usage(day INTEGER PRIMARY KEY, bytes INTEGER, packets INTEGER, throttled INTEGER): bytes and
+//!   packets relayed for other nodes per UTC day, and packets held back by the daily budget (see `relay_budget`)
+//! - pseudonyms(channel_id BLOB, generation INTEGER, ed25519_public BLOB UNIQUE, created_at INTEGER,
+//!   retired_at INTEGER, PRIMARY KEY(channel_id, generation)): our per-channel pseudonyms in public channels
+//!   (the keys are derived, never stored, see `pseudonyms`)
+//! - pseudonym_messages(message_id BLOB PRIMARY KEY, channel_id BLOB, generation INTEGER): which pseudonym
+//!   wrote each message we sent under one
+//! - message_search: FTS5 table (text, message_id UNINDEXED, channel_id UNINDEXED) over the displayed text of
+//!   kept plaintexts, so it exists exactly when `message_plaintexts` does (see `message_index`)
+//! - friends(user_id BLOB PRIMARY KEY, ed25519_public BLOB, nickname TEXT, notes TEXT, tags TEXT,
+//!   custom_display_name TEXT, x25519_public BLOB, verified_at INTEGER): the friend list (tags as a JSON array;
+//!   see `friends`, which imports friends.json of older versions once)
+//! - announced_channels(channel_id BLOB PRIMARY KEY, geohash TEXT, topic TEXT, name TEXT, last_sent_at INTEGER):
+//!   our public channels we announce, and when we last did (see `channel_directory`)
+//! - channel_directory(channel_id BLOB, signer BLOB, geohash TEXT, topic TEXT, name TEXT, activity INTEGER,
+//!   announced_at INTEGER, received_at INTEGER, spam_score INTEGER, PRIMARY KEY(channel_id, signer)): public
+//!   channels other nodes announced, one row per announcing key
+//! - watched_contacts(ed25519_public BLOB PRIMARY KEY, label TEXT, added_at INTEGER): keys whose broadcast
+//!   channel we follow without being friends (see `broadcasts`)
+//! - backup_changes(seq INTEGER PRIMARY KEY, table_name TEXT, row_id INTEGER) and backup_manifest(id INTEGER
+//!   PRIMARY KEY, manifest TEXT): rows changed since the last backup, logged by triggers once a first backup
+//!   is made (see `backup`)
+//!
+//! Tables and columns from before schema versions are created and added in
+//! `setup`; every change since is a numbered step in `MIGRATIONS`, run once,
+//! in order, with the database's version kept in `PRAGMA user_version`.
+//!
+//! With the `sqlcipher` feature the whole file, metadata included, can be
+//! encrypted at rest (`Storage::init_with_key`).
 
-use rusqlite::{params, Connection};
+use crate::codec;
+use crate::error::MeshError;
+use crate::notifications::NotificationSettings;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 pub struct Storage {
     conn: Connection,
+    /// Opened with a key (`init_with_key`)
+    encrypted: bool,
+}
+
+/// A transaction that nests: a savepoint, released by `commit` and rolled
+/// back when dropped without it. Inside `Storage::with_transaction` it joins
+/// the outer transaction instead of committing on its own.
+struct Tx<'a> {
+    conn: &'a Connection,
+    done: bool,
+}
+
+impl Tx<'_> {
+    fn commit(mut self) -> rusqlite::Result<()> {
+        self.done = true;
+        self.conn.execute_batch("RELEASE storage_tx")
+    }
+}
+
+impl std::ops::Deref for Tx<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn
+    }
+}
+
+impl Drop for Tx<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.conn.execute_batch("ROLLBACK TO storage_tx; RELEASE storage_tx");
+        }
+    }
 }
 
+/// messages.kind: a message written by a user (encrypted)
+pub const MESSAGE_KIND_USER: u8 = 0;
+/// messages.kind: a status event generated by the core (local-only)
+pub const MESSAGE_KIND_SYSTEM: u8 = 1;
+/// messages.kind: a message known to exist that has not arrived yet (no
+/// ciphertext; see `message_futures`)
+pub const MESSAGE_KIND_PENDING: u8 = 2;
+
+/// (channel_id, type if registered) of a channel holding messages
+pub type MessageChannel = ([u8; 32], Option<String>);
+
+/// (channel_id, message_id) of a stored message
+pub type ChannelMessage = ([u8; 32], [u8; 32]);
+
+/// (timestamp, message_id): a message's place in its channel's history
+pub type MessagePosition = (i64, [u8; 32]);
+
 #[derive(Debug)]
 pub struct MessageRow {
     pub message_id: [u8; 32],
@@ -19,6 +168,21 @@ pub struct MessageRow {
     pub ciphertext: Vec<u8>,
     pub timestamp: i64,
     pub ttl: u8,
+    pub kind: u8,
+    /// `priority::Priority` the sender marked it with
+    pub priority: u8,
+}
+
+/// A message we are sending, with the channel it registers.
+#[derive(Debug)]
+pub struct OutgoingMessage {
+    pub message_id: [u8; 32],
+    pub channel_id: [u8; 32],
+    pub channel_type: &'static str,
+    pub ciphertext: Vec<u8>,
+    pub timestamp: i64,
+    pub ttl: u8,
+    pub priority: u8,
 }
 
 #[derive(Debug)]
@@ -27,21 +191,397 @@ pub struct ChannelRow {
     pub channel_type: String,
 }
 
+/// A nearby node and when we last heard from it.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerRow {
+    pub peer_id: [u8; 32],
+    pub last_seen: i64,
+}
+
+/// A blocked user and, if known, their Ed25519 key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockedUserRow {
+    pub user_id: [u8; 32],
+    pub ed25519_public: Option<[u8; 32]>,
+    pub blocked_at: i64,
+}
+
+/// A message time another node claimed, replaced when stored (see `clock`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuarantinedTimestampRow {
+    pub message_id: [u8; 32],
+    pub channel_id: [u8; 32],
+    pub claimed: i64,
+    pub stored: i64,
+    pub quarantined_at: i64,
+}
+
+/// Relay traffic of one day (see `relay_budget`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RelayUsageRow {
+    /// Days since UNIX_EPOCH (UTC)
+    pub day: i64,
+    pub bytes: u64,
+    pub packets: u64,
+    /// Packets not relayed because the budget was used up
+    pub throttled: u64,
+}
+
+/// One of our pseudonyms in a public channel (see `pseudonyms`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PseudonymRow {
+    pub channel_id: [u8; 32],
+    pub generation: u32,
+    pub ed25519_public: [u8; 32],
+    pub created_at: i64,
+    /// When `regenerate` replaced it (None for the channel's current pseudonym)
+    pub retired_at: Option<i64>,
+}
+
+/// A public channel we announce (see `channel_directory`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnouncedChannelRow {
+    pub channel_id: [u8; 32],
+    pub geohash: String,
+    pub topic: String,
+    pub name: Option<String>,
+    pub last_sent_at: Option<i64>,
+}
+
+/// A channel announcement another node made (see `channel_directory`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryRow {
+    pub channel_id: [u8; 32],
+    /// The announcing key (a pseudonym of the channel)
+    pub signer: [u8; 32],
+    pub geohash: String,
+    pub topic: String,
+    pub name: Option<String>,
+    pub activity: u8,
+    pub announced_at: i64,
+    pub received_at: i64,
+    pub spam_score: u8,
+}
+
+/// A key we follow without friending it (see `broadcasts`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchedContactRow {
+    pub ed25519_public: [u8; 32],
+    pub label: Option<String>,
+    pub added_at: i64,
+}
+
+/// Capabilities negotiated with a peer (see `peer_capabilities`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerCapabilitiesRow {
+    pub peer_id: [u8; 32],
+    pub protocol_version: u32,
+    /// JSON array of cipher names
+    pub ciphers: String,
+    pub max_mtu: u32,
+    pub negotiated_at: i64,
+    pub expires_at: i64,
+}
+
+/// A peer recently heard on a channel.
+#[derive(Debug, Clone, Copy)]
+pub struct RoutingHintRow {
+    pub channel_id: [u8; 32],
+    pub peer_id: [u8; 32],
+    pub last_seen: i64,
+}
+
+/// One key a protected channel has had (see `key_escrow`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelKeyEpochRow {
+    pub channel_id: [u8; 32],
+    pub key: [u8; 32],
+    pub added_at: i64,
+}
+
+/// A group message of ours awaiting custody receipts (see `group_delivery`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupDeliveryRow {
+    pub message_id: [u8; 32],
+    pub channel_id: [u8; 32],
+    pub sent_at: i64,
+    /// Retransmissions so far
+    pub attempts: u32,
+    pub next_attempt: i64,
+}
+
+/// A packet waiting in the outbox.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboxRow {
+    pub packet_id: [u8; 32],
+    pub channel_id: [u8; 32],
+    pub kind: u8,
+    pub ttl: u8,
+    pub payload: Vec<u8>,
+    pub priority: u8,
+    pub created_at: i64,
+    /// Failed retries so far
+    pub attempts: u32,
+    pub next_attempt: i64,
+}
+
+/// A send remembered under its client token (see `client_tokens`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientTokenRow {
+    pub fingerprint: [u8; 32],
+    pub result: String,
+    pub created_at: i64,
+}
+
+/// An established DM session (see `dm_crypto::DmSessionManager`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmSessionRow {
+    /// SHA256 of the handshake message
+    pub session_id: [u8; 32],
+    pub channel_id: [u8; 32],
+    /// True for sessions we initiated (and send with)
+    pub outgoing: bool,
+    pub remote_static: [u8; 32],
+    /// The Noise IK first message that set the session up
+    pub handshake: Vec<u8>,
+    /// Initiator-to-responder key from the Noise split
+    pub key: [u8; 32],
+    pub next_counter: u64,
+    pub created_at: i64,
+}
+
+/// A DM Double Ratchet (see `ratchet`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmRatchetRow {
+    /// SHA256 of the Noise handshake message that seeded it
+    pub ratchet_id: [u8; 32],
+    pub channel_id: [u8; 32],
+    /// True for ratchets we seeded by sending first
+    pub initiator: bool,
+    pub remote_static: [u8; 32],
+    pub handshake: Vec<u8>,
+    pub root_key: [u8; 32],
+    /// Secret of our current ratchet key pair
+    pub dh_secret: [u8; 32],
+    /// The friend's current ratchet public key
+    pub dh_remote: Option<[u8; 32]>,
+    pub send_chain: Option<[u8; 32]>,
+    pub recv_chain: Option<[u8; 32]>,
+    pub send_count: u32,
+    pub recv_count: u32,
+    /// Messages sent on the previous sending chain
+    pub prev_send_count: u32,
+    pub send_header_key: Option<[u8; 32]>,
+    pub recv_header_key: Option<[u8; 32]>,
+    pub next_send_header_key: [u8; 32],
+    pub next_recv_header_key: [u8; 32],
+    /// When a message last arrived on it
+    pub received_at: Option<i64>,
+    pub created_at: i64,
+}
+
+/// A reader's delivery/read status for a message (see `receipts`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageReceiptRow {
+    pub user_id: [u8; 32],
+    /// `receipts::ReceiptStatus` value
+    pub status: u8,
+    pub updated_at: i64,
+}
+
+/// How far a message we sent got (see `send_status`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendStateRow {
+    pub message_id: [u8; 32],
+    pub channel_id: [u8; 32],
+    /// `send_status::SendState` value
+    pub state: u8,
+    pub attempts: u32,
+    pub updated_at: i64,
+}
+
+/// A decrypted DM message (see `message_index`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessagePlaintextRow {
+    pub outgoing: bool,
+    pub plaintext: String,
+}
+
+/// (message_id, channel_id, plaintext) of a kept plaintext
+pub type KeptPlaintext = ([u8; 32], [u8; 32], String);
+
+/// A full-text search match among kept plaintexts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageSearchRow {
+    pub message_id: [u8; 32],
+    pub channel_id: [u8; 32],
+    pub text: String,
+    pub timestamp: i64,
+}
+
+/// A starred message copy (see `starred`): ciphertext and key material, or a plaintext snapshot.
+#[derive(Debug, Clone)]
+pub struct StarredRow {
+    pub message_id: [u8; 32],
+    pub channel_id: [u8; 32],
+    pub timestamp: i64,
+    pub author: Option<[u8; 32]>,
+    pub starred_at: i64,
+    pub mode: String,
+    pub ciphertext: Option<Vec<u8>>,
+    pub key_kind: Option<String>,
+    pub key: Option<[u8; 32]>,
+    pub plaintext: Option<String>,
+}
+
+/// A channel with its latest activity, for the conversation list.
+#[derive(Debug)]
+pub struct ConversationRow {
+    pub channel_id: [u8; 32],
+    pub channel_type: String,
+    pub name: Option<String>,
+    pub pinned: bool,
+    pub sort_order: Option<i64>,
+    pub ui_metadata: Option<String>,
+    pub observe_only: bool,
+    pub message_count: u64,
+    pub last_message_at: Option<i64>,
+    /// User messages after the channel's read marker (see `read_state`)
+    pub unread_count: u64,
+}
+
+/// Messages of a channel on one UTC day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DayActivity {
+    /// UNIX seconds at 00:00 UTC
+    pub day_start: i64,
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+/// A table of the database: its columns and how many rows it holds.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct TableSummary {
+    pub name: String,
+    pub columns: Vec<String>,
+    pub rows: u64,
+}
+
+/// Rows of one table as a backup holds them (see `backup`): each row's rowid
+/// and column values, and the rowids of rows deleted since the last backup.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableRows {
+    pub table: String,
+    pub columns: Vec<String>,
+    pub rows: Vec<(i64, Vec<rusqlite::types::Value>)>,
+    pub deleted: Vec<i64>,
+}
+
+/// Activity and storage use of one channel.
+#[derive(Debug, Default)]
+pub struct ChannelStatsRow {
+    /// Days with messages, oldest first
+    pub days: Vec<DayActivity>,
+    pub message_bytes: u64,
+    pub attachment_bytes: u64,
+    /// Peers heard on the channel (routing_hints)
+    pub peers_heard: u64,
+}
+
+/// Attachment metadata; `parent_id` is set for thumbnails.
+#[derive(Debug, Clone)]
+pub struct AttachmentRow {
+    pub attachment_id: [u8; 32],
+    pub message_id: [u8; 32],
+    pub channel_id: [u8; 32],
+    pub parent_id: Option<[u8; 32]>,
+    pub mime: String,
+    pub size: u64,
+    pub chunk_count: u32,
+    pub complete: bool,
+    pub created_at: i64,
+    /// Data is wanted (requested by us or pushed by the sender); used to resume
+    pub requested: bool,
+    /// Key the chunks travel encrypted under (None: sent in the clear)
+    pub chunk_key: Option<[u8; 32]>,
+}
+
+const ATTACHMENT_COLUMNS: &str =
+    "attachment_id, message_id, channel_id, parent_id, mime, size, chunk_count, complete, created_at, requested, chunk_key";
+
 impl Storage {
     /// Initialize storage and create tables if they don't exist.
     pub fn init(db_path: &PathBuf) -> Result<Self, String> {
+        Self::open(db_path, None)
+    }
+
+    /// Like `init`, with the database encrypted at rest under `key` (feature
+    /// `sqlcipher`). An existing unencrypted database is encrypted first;
+    /// opening an encrypted one with another key fails.
+    pub fn init_with_key(db_path: &PathBuf, key: &[u8; 32]) -> Result<Self, String> {
+        if !cfg!(feature = "sqlcipher") {
+            return Err(MeshError::Rejected.raise("Database encryption needs the sqlcipher feature"));
+        }
+        Self::open(db_path, Some(key))
+    }
+
+    fn open(db_path: &PathBuf, key: Option<&[u8; 32]>) -> Result<Self, String> {
         if let Some(parent) = db_path.parent() {
             std::fs::create_dir_all(parent)
                 .map_err(|e| format!("Failed to create storage directory: {}", e))?;
         }
+        if let Some(key) = key {
+            if is_plain_database(db_path) {
+                encrypt_database(db_path, key)?;
+            }
+        }
 
         let conn = Connection::open(db_path)
-            .map_err(|e| format!("Failed to open database: {}", e))?;
+            .map_err(db_error("Failed to open database"))?;
+        if let Some(key) = key {
+            conn.execute_batch(&format!("PRAGMA key = \"{}\";", sqlcipher_key(key)))
+                .map_err(db_error("Failed to set database key"))?;
+            conn.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))
+                .map_err(|_| "Database does not open with this key".to_string())?;
+        }
 
         // Enable WAL for better concurrency on mobile/desktop
         conn.pragma_update(None, "journal_mode", "WAL")
-            .map_err(|e| format!("Failed to set WAL mode: {}", e))?;
+            .map_err(db_error("Failed to set WAL mode"))?;
+
+        Self::setup(conn, key.is_some())
+    }
+
+    /// Open an existing database read-only, for a process attached next to
+    /// the one that holds the data directory (see `instance_lock`). Nothing
+    /// is created or migrated; writes fail.
+    pub fn open_read_only(db_path: &PathBuf, key: Option<&[u8; 32]>) -> Result<Self, String> {
+        if !db_path.exists() {
+            return Err("No database in the data directory".to_string());
+        }
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI;
+        let conn = Connection::open_with_flags(db_path, flags).map_err(db_error("Failed to open database"))?;
+        if let Some(key) = key {
+            conn.execute_batch(&format!("PRAGMA key = \"{}\";", sqlcipher_key(key)))
+                .map_err(db_error("Failed to set database key"))?;
+        }
+        conn.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))
+            .map_err(|_| "Database does not open with this key".to_string())?;
+        if schema_version(&conn)? != SCHEMA_VERSION {
+            return Err(MeshError::Rejected.raise("Database schema version differs from this app's; attach read-only with the same version"));
+        }
+        Ok(Self { conn, encrypted: key.is_some() })
+    }
 
+    /// A database in memory only, gone when dropped (see `init_in_memory`).
+    pub fn in_memory() -> Result<Self, String> {
+        let conn = Connection::open_in_memory()
+            .map_err(db_error("Failed to open database"))?;
+        Self::setup(conn, false)
+    }
+
+    /// Create the tables, and migrate those of older databases.
+    fn setup(conn: Connection, encrypted: bool) -> Result<Self, String> {
         conn.execute_batch(
             "
             CREATE TABLE IF NOT EXISTS messages (
@@ -49,20 +589,368 @@ impl Storage {
                 channel_id BLOB NOT NULL,
                 ciphertext BLOB NOT NULL,
                 timestamp INTEGER NOT NULL,
-                ttl INTEGER NOT NULL
+                ttl INTEGER NOT NULL,
+                kind INTEGER NOT NULL DEFAULT 0,
+                expires_at INTEGER,
+                priority INTEGER NOT NULL DEFAULT 1,
+                seq INTEGER
             );
             CREATE TABLE IF NOT EXISTS channels (
                 channel_id BLOB PRIMARY KEY,
-                type TEXT NOT NULL
+                type TEXT NOT NULL,
+                muted INTEGER NOT NULL DEFAULT 0,
+                mention_only INTEGER NOT NULL DEFAULT 0,
+                sound_profile TEXT,
+                name TEXT,
+                pinned INTEGER NOT NULL DEFAULT 0,
+                sort_order INTEGER,
+                ui_metadata TEXT
+            );
+            CREATE TABLE IF NOT EXISTS channel_keys (
+                channel_id BLOB PRIMARY KEY,
+                key BLOB NOT NULL,
+                added_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS attachments (
+                attachment_id BLOB PRIMARY KEY,
+                message_id BLOB NOT NULL,
+                channel_id BLOB NOT NULL,
+                parent_id BLOB,
+                mime TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                chunk_count INTEGER NOT NULL,
+                complete INTEGER NOT NULL,
+                created_at INTEGER NOT NULL,
+                requested INTEGER NOT NULL DEFAULT 0,
+                chunk_key BLOB
+            );
+            CREATE INDEX IF NOT EXISTS idx_attachments_message ON attachments(message_id);
+            CREATE TABLE IF NOT EXISTS attachment_chunks (
+                attachment_id BLOB NOT NULL,
+                chunk_index INTEGER NOT NULL,
+                data BLOB NOT NULL,
+                PRIMARY KEY (attachment_id, chunk_index)
+            );
+            CREATE TABLE IF NOT EXISTS attachment_refs (
+                attachment_id BLOB NOT NULL,
+                message_id BLOB NOT NULL,
+                PRIMARY KEY (attachment_id, message_id)
+            );
+            CREATE INDEX IF NOT EXISTS idx_attachment_refs_message ON attachment_refs(message_id);
+            CREATE TABLE IF NOT EXISTS settings (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS peers (
+                peer_id BLOB PRIMARY KEY,
+                last_seen INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS routing_hints (
+                channel_id BLOB NOT NULL,
+                peer_id BLOB NOT NULL,
+                last_seen INTEGER NOT NULL,
+                PRIMARY KEY (channel_id, peer_id)
+            );
+            CREATE TABLE IF NOT EXISTS starred_messages (
+                message_id BLOB PRIMARY KEY,
+                channel_id BLOB NOT NULL,
+                timestamp INTEGER NOT NULL,
+                author BLOB,
+                starred_at INTEGER NOT NULL,
+                mode TEXT NOT NULL,
+                ciphertext BLOB,
+                key_kind TEXT,
+                key BLOB,
+                plaintext TEXT
+            );
+            CREATE TABLE IF NOT EXISTS linked_primaries (
+                primary_ed25519 BLOB PRIMARY KEY,
+                linked_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS key_histories (
+                user_id BLOB PRIMARY KEY,
+                history TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS channel_key_epochs (
+                channel_id BLOB NOT NULL,
+                key BLOB NOT NULL,
+                added_at INTEGER NOT NULL,
+                PRIMARY KEY (channel_id, key)
+            );
+            CREATE TABLE IF NOT EXISTS group_members (
+                channel_id BLOB NOT NULL,
+                user_id BLOB NOT NULL,
+                added_at INTEGER NOT NULL,
+                PRIMARY KEY (channel_id, user_id)
+            );
+            CREATE TABLE IF NOT EXISTS group_admins (
+                channel_id BLOB NOT NULL,
+                user_id BLOB NOT NULL,
+                added_at INTEGER NOT NULL,
+                PRIMARY KEY (channel_id, user_id)
+            );
+            CREATE TABLE IF NOT EXISTS group_mutes (
+                channel_id BLOB NOT NULL,
+                user_id BLOB NOT NULL,
+                muted_at INTEGER NOT NULL,
+                PRIMARY KEY (channel_id, user_id)
+            );
+            CREATE TABLE IF NOT EXISTS group_deliveries (
+                message_id BLOB PRIMARY KEY,
+                channel_id BLOB NOT NULL,
+                sent_at INTEGER NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt INTEGER NOT NULL,
+                priority INTEGER NOT NULL DEFAULT 1
+            );
+            CREATE TABLE IF NOT EXISTS delivery_receipts (
+                message_id BLOB NOT NULL,
+                user_id BLOB NOT NULL,
+                received_at INTEGER NOT NULL,
+                PRIMARY KEY (message_id, user_id)
+            );
+            CREATE TABLE IF NOT EXISTS dm_peer_keys (
+                channel_id BLOB PRIMARY KEY,
+                x25519_public BLOB NOT NULL,
+                updated_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS dm_sessions (
+                session_id BLOB PRIMARY KEY,
+                channel_id BLOB NOT NULL,
+                outgoing INTEGER NOT NULL,
+                remote_static BLOB NOT NULL,
+                handshake BLOB NOT NULL,
+                key BLOB NOT NULL,
+                next_counter INTEGER NOT NULL DEFAULT 0,
+                created_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_dm_sessions_channel ON dm_sessions(channel_id, outgoing);
+            CREATE TABLE IF NOT EXISTS dm_ratchets (
+                ratchet_id BLOB PRIMARY KEY,
+                channel_id BLOB NOT NULL,
+                initiator INTEGER NOT NULL,
+                remote_static BLOB NOT NULL,
+                handshake BLOB NOT NULL,
+                root_key BLOB NOT NULL,
+                dh_secret BLOB NOT NULL,
+                dh_remote BLOB,
+                send_chain BLOB,
+                recv_chain BLOB,
+                send_count INTEGER NOT NULL,
+                recv_count INTEGER NOT NULL,
+                prev_send_count INTEGER NOT NULL,
+                send_header_key BLOB,
+                recv_header_key BLOB,
+                next_send_header_key BLOB NOT NULL,
+                next_recv_header_key BLOB NOT NULL,
+                received_at INTEGER,
+                created_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_dm_ratchets_channel ON dm_ratchets(channel_id);
+            CREATE TABLE IF NOT EXISTS dm_skipped_keys (
+                ratchet_id BLOB NOT NULL,
+                header_key BLOB NOT NULL,
+                counter INTEGER NOT NULL,
+                message_key BLOB NOT NULL,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (ratchet_id, header_key, counter)
+            );
+            CREATE TABLE IF NOT EXISTS dm_message_keys (
+                message_id BLOB PRIMARY KEY,
+                channel_id BLOB NOT NULL,
+                outgoing INTEGER NOT NULL,
+                message_key BLOB NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS peer_capabilities (
+                peer_id BLOB PRIMARY KEY,
+                protocol_version INTEGER NOT NULL,
+                ciphers TEXT NOT NULL,
+                max_mtu INTEGER NOT NULL,
+                negotiated_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS message_receipts (
+                message_id BLOB NOT NULL,
+                user_id BLOB NOT NULL,
+                channel_id BLOB NOT NULL,
+                status INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL,
+                PRIMARY KEY (message_id, user_id)
+            );
+            CREATE INDEX IF NOT EXISTS idx_message_receipts_channel ON message_receipts(channel_id);
+            CREATE TABLE IF NOT EXISTS send_states (
+                message_id BLOB PRIMARY KEY,
+                channel_id BLOB NOT NULL,
+                state INTEGER NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                updated_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_send_states_channel ON send_states(channel_id);
+            CREATE TABLE IF NOT EXISTS message_plaintexts (
+                message_id BLOB PRIMARY KEY,
+                channel_id BLOB NOT NULL,
+                outgoing INTEGER NOT NULL,
+                plaintext TEXT NOT NULL,
+                indexed_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_message_plaintexts_channel ON message_plaintexts(channel_id);
+            CREATE VIRTUAL TABLE IF NOT EXISTS message_search USING fts5(
+                text,
+                message_id UNINDEXED,
+                channel_id UNINDEXED,
+                tokenize = 'unicode61 remove_diacritics 2'
+            );
+            CREATE TABLE IF NOT EXISTS client_tokens (
+                token TEXT PRIMARY KEY,
+                fingerprint BLOB NOT NULL,
+                result TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS seen_packets (
+                packet_id BLOB PRIMARY KEY,
+                seen_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_seen_packets_seen_at ON seen_packets(seen_at);
+            CREATE TABLE IF NOT EXISTS outbox (
+                packet_id BLOB PRIMARY KEY,
+                channel_id BLOB NOT NULL,
+                kind INTEGER NOT NULL,
+                ttl INTEGER NOT NULL,
+                payload BLOB NOT NULL,
+                created_at INTEGER NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt INTEGER NOT NULL,
+                priority INTEGER NOT NULL DEFAULT 1
+            );
+            CREATE TABLE IF NOT EXISTS read_markers (
+                channel_id BLOB PRIMARY KEY,
+                timestamp INTEGER NOT NULL,
+                message_id BLOB NOT NULL,
+                updated_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS blocked_users (
+                user_id BLOB PRIMARY KEY,
+                ed25519_public BLOB,
+                blocked_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS quarantined_timestamps (
+                message_id BLOB PRIMARY KEY,
+                channel_id BLOB NOT NULL,
+                claimed INTEGER NOT NULL,
+                stored INTEGER NOT NULL,
+                quarantined_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS relay_usage (
+                day INTEGER PRIMARY KEY,
+                bytes INTEGER NOT NULL DEFAULT 0,
+                packets INTEGER NOT NULL DEFAULT 0,
+                throttled INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS pseudonyms (
+                channel_id BLOB NOT NULL,
+                generation INTEGER NOT NULL,
+                ed25519_public BLOB NOT NULL UNIQUE,
+                created_at INTEGER NOT NULL,
+                retired_at INTEGER,
+                PRIMARY KEY (channel_id, generation)
+            );
+            CREATE TABLE IF NOT EXISTS pseudonym_messages (
+                message_id BLOB PRIMARY KEY,
+                channel_id BLOB NOT NULL,
+                generation INTEGER NOT NULL
             );
             ",
         )
-        .map_err(|e| format!("Failed to create tables: {}", e))?;
+        .map_err(db_error("Failed to create tables"))?;
+
+        // Columns added after a table was first shipped
+        ensure_column(&conn, "messages", "kind", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(&conn, "channels", "muted", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(&conn, "channels", "mention_only", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(&conn, "channels", "sound_profile", "TEXT")?;
+        ensure_column(&conn, "channels", "name", "TEXT")?;
+        ensure_column(&conn, "channels", "pinned", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(&conn, "channels", "sort_order", "INTEGER")?;
+        ensure_column(&conn, "channels", "ui_metadata", "TEXT")?;
+        ensure_column(&conn, "channels", "observe_only", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(&conn, "attachments", "requested", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(&conn, "attachments", "chunk_key", "BLOB")?;
+        ensure_column(&conn, "messages", "expires_at", "INTEGER")?;
+        ensure_column(&conn, "messages", "priority", "INTEGER NOT NULL DEFAULT 1")?;
+        ensure_column(&conn, "outbox", "priority", "INTEGER NOT NULL DEFAULT 1")?;
+        ensure_column(&conn, "channels", "topic", "TEXT")?;
+        ensure_column(&conn, "channels", "metadata_updated_at", "INTEGER")?;
+        ensure_column(&conn, "channels", "metadata_updated_by", "BLOB")?;
+        ensure_column(&conn, "channels", "retention_secs", "INTEGER")?;
+        ensure_column(&conn, "messages", "seq", "INTEGER")?;
+        conn.execute("CREATE INDEX IF NOT EXISTS idx_messages_expires_at ON messages(expires_at)", [])
+            .map_err(db_error("Failed to create expiry index"))?;
+        // Messages stored before seq existed come first, in insertion order
+        conn.execute("UPDATE messages SET seq = rowid WHERE seq IS NULL", [])
+            .map_err(db_error("Failed to backfill message seq"))?;
+        conn.execute("CREATE INDEX IF NOT EXISTS idx_messages_seq ON messages(seq)", [])
+            .map_err(db_error("Failed to create seq index"))?;
+
+        // Attachments stored before reference counting reference their own message
+        conn.execute(
+            "INSERT OR IGNORE INTO attachment_refs (attachment_id, message_id)
+             SELECT attachment_id, message_id FROM attachments",
+            [],
+        )
+        .map_err(db_error("Failed to backfill attachment refs"))?;
+
+        // Keys stored before key epochs were kept are their channel's first epoch
+        conn.execute(
+            "INSERT OR IGNORE INTO channel_key_epochs (channel_id, key, added_at)
+             SELECT channel_id, key, added_at FROM channel_keys",
+            [],
+        )
+        .map_err(db_error("Failed to backfill channel key epochs"))?;
+
+        migrate(&conn)?;
+        Ok(Self { conn, encrypted })
+    }
+
+    /// Schema version of the database (see `MIGRATIONS`).
+    pub fn schema_version(&self) -> Result<u32, String> {
+        schema_version(&self.conn)
+    }
+
+    /// Whether the database is encrypted at rest.
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    /// Re-encrypt the database under another key (feature `sqlcipher`).
+    pub fn rekey(&self, key: &[u8; 32]) -> Result<(), String> {
+        if !self.encrypted {
+            return Err(MeshError::Rejected.raise("The database is not encrypted"));
+        }
+        self.conn
+            .execute_batch(&format!("PRAGMA rekey = \"{}\";", sqlcipher_key(key)))
+            .map_err(db_error("Failed to rekey database"))
+    }
+
+    /// Whether the encrypted database at `db_path` opens with `key`.
+    pub fn opens_with(db_path: &PathBuf, key: &[u8; 32]) -> bool {
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        Connection::open_with_flags(db_path, flags).is_ok_and(|conn| {
+            conn.execute_batch(&format!("PRAGMA key = \"{}\";", sqlcipher_key(key))).is_ok()
+                && conn.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(())).is_ok()
+        })
+    }
 
-        Ok(Self { conn })
+    /// Move everything in the WAL into the database file and empty the WAL,
+    /// so a process killed right after leaves nothing to replay.
+    pub fn checkpoint(&self) -> Result<(), String> {
+        self.conn
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+            .map_err(db_error("Failed to checkpoint database"))
     }
 
-    /// Store a message (idempotent on message_id).
+    /// Store a message (idempotent on message_id). A placeholder for it in
+    /// the same channel is filled in place, keeping its expected position.
     pub fn store_message(
         &self,
         message_id: [u8; 32],
@@ -73,11 +961,109 @@ impl Storage {
     ) -> Result<(), String> {
         self.conn
             .execute(
-                "INSERT OR IGNORE INTO messages (message_id, channel_id, ciphertext, timestamp, ttl)
-                 VALUES (?1, ?2, ?3, ?4, ?5)",
-                params![&message_id, &channel_id, &ciphertext, timestamp, ttl as i64],
+                "INSERT INTO messages (message_id, channel_id, ciphertext, timestamp, ttl, seq)
+                 VALUES (?1, ?2, ?3, ?4, ?5, (SELECT COALESCE(MAX(seq), 0) + 1 FROM messages))
+                 ON CONFLICT(message_id) DO UPDATE SET ciphertext = excluded.ciphertext, ttl = excluded.ttl, kind = ?6,
+                   seq = excluded.seq
+                 WHERE messages.kind = ?7 AND messages.channel_id = excluded.channel_id",
+                params![
+                    &message_id,
+                    &channel_id,
+                    &ciphertext,
+                    timestamp,
+                    ttl as i64,
+                    MESSAGE_KIND_USER as i64,
+                    MESSAGE_KIND_PENDING as i64
+                ],
+            )
+            .map_err(db_error("Failed to insert message"))?;
+        Ok(())
+    }
+
+    /// Store a placeholder for a message that has not arrived yet. Returns
+    /// false if the message (or a placeholder for it) is already stored.
+    pub fn store_message_placeholder(&self, message_id: [u8; 32], channel_id: [u8; 32], expected_at: i64) -> Result<bool, String> {
+        let n = self
+            .conn
+            .execute(
+                "INSERT OR IGNORE INTO messages (message_id, channel_id, ciphertext, timestamp, ttl, kind, seq)
+                 VALUES (?1, ?2, X'', ?3, 0, ?4, (SELECT COALESCE(MAX(seq), 0) + 1 FROM messages))",
+                params![&message_id, &channel_id, expected_at, MESSAGE_KIND_PENDING as i64],
+            )
+            .map_err(db_error("Failed to insert message placeholder"))?;
+        Ok(n > 0)
+    }
+
+    /// Record the priority a received message was marked with.
+    pub fn set_message_priority(&self, message_id: [u8; 32], priority: u8) -> Result<(), String> {
+        self.conn
+            .execute(
+                "UPDATE messages SET priority = ?2 WHERE message_id = ?1",
+                params![&message_id, priority as i64],
+            )
+            .map_err(db_error("Failed to set message priority"))?;
+        Ok(())
+    }
+
+    fn transaction(&self) -> rusqlite::Result<Tx<'_>> {
+        self.conn.execute_batch("SAVEPOINT storage_tx")?;
+        Ok(Tx { conn: &self.conn, done: false })
+    }
+
+    /// Run `f` as one transaction: everything it stores commits together, or
+    /// nothing does if it fails. Transactions of the methods it calls join this one.
+    pub fn with_transaction<T>(&self, f: impl FnOnce(&Storage) -> Result<T, String>) -> Result<T, String> {
+        let tx = self.transaction().map_err(db_error("Failed to begin transaction"))?;
+        let value = f(self)?;
+        tx.commit().map_err(db_error("Failed to commit transaction"))?;
+        Ok(value)
+    }
+
+    /// Store outgoing messages and register their channels in one transaction.
+    pub fn store_outgoing_batch(&self, messages: &[OutgoingMessage]) -> Result<(), String> {
+        let tx = self
+            .transaction()
+            .map_err(db_error("Failed to begin transaction"))?;
+        for m in messages {
+            tx.execute(
+                "INSERT OR IGNORE INTO channels (channel_id, type) VALUES (?1, ?2)",
+                params![&m.channel_id, m.channel_type],
+            )
+            .map_err(db_error("Failed to upsert channel"))?;
+            tx.execute(
+                "INSERT OR IGNORE INTO messages (message_id, channel_id, ciphertext, timestamp, ttl, priority, seq)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, (SELECT COALESCE(MAX(seq), 0) + 1 FROM messages))",
+                params![&m.message_id, &m.channel_id, &m.ciphertext, m.timestamp, m.ttl as i64, m.priority as i64],
+            )
+            .map_err(db_error("Failed to insert message"))?;
+            tx.execute(
+                "INSERT OR IGNORE INTO send_states (message_id, channel_id, state, attempts, updated_at)
+                 VALUES (?1, ?2, 0, 0, ?3)",
+                params![&m.message_id, &m.channel_id, m.timestamp],
+            )
+            .map_err(db_error("Failed to insert send state"))?;
+            // What we send we have read up to
+            advance_read_marker(&tx, m.channel_id, m.timestamp, m.message_id, m.timestamp)?;
+        }
+        tx.commit()
+            .map_err(db_error("Failed to commit messages"))
+    }
+
+    /// Store a core-generated system message (never routed).
+    pub fn store_system_message(
+        &self,
+        message_id: [u8; 32],
+        channel_id: [u8; 32],
+        body: &[u8],
+        timestamp: i64,
+    ) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR IGNORE INTO messages (message_id, channel_id, ciphertext, timestamp, ttl, kind, seq)
+                 VALUES (?1, ?2, ?3, ?4, 0, ?5, (SELECT COALESCE(MAX(seq), 0) + 1 FROM messages))",
+                params![&message_id, &channel_id, body, timestamp, MESSAGE_KIND_SYSTEM as i64],
             )
-            .map_err(|e| format!("Failed to insert message: {}", e))?;
+            .map_err(db_error("Failed to insert system message"))?;
         Ok(())
     }
 
@@ -87,50 +1073,281 @@ impl Storage {
         channel_id: [u8; 32],
         limit: u32,
         offset: u32,
+    ) -> Result<Vec<MessageRow>, String> {
+        self.fetch_messages_at(channel_id, limit, offset, None)
+    }
+
+    /// `fetch_messages` without the messages that disappeared by `now` under
+    /// the channel's retention_secs (whether or not they were purged yet).
+    pub fn fetch_live_messages(
+        &self,
+        channel_id: [u8; 32],
+        limit: u32,
+        offset: u32,
+        now: i64,
+    ) -> Result<Vec<MessageRow>, String> {
+        self.fetch_messages_at(channel_id, limit, offset, Some(now))
+    }
+
+    fn fetch_messages_at(
+        &self,
+        channel_id: [u8; 32],
+        limit: u32,
+        offset: u32,
+        now: Option<i64>,
     ) -> Result<Vec<MessageRow>, String> {
         let mut stmt = self
             .conn
             .prepare(
-                "SELECT message_id, channel_id, ciphertext, timestamp, ttl
+                "SELECT message_id, channel_id, ciphertext, timestamp, ttl, kind, priority
                  FROM messages
                  WHERE channel_id = ?1
-                 ORDER BY timestamp ASC
+                   AND (?4 IS NULL OR NOT EXISTS (
+                       SELECT 1 FROM channels c
+                       WHERE c.channel_id = ?1 AND c.retention_secs IS NOT NULL AND timestamp + c.retention_secs <= ?4))
+                 ORDER BY timestamp ASC, message_id ASC
                  LIMIT ?2 OFFSET ?3",
             )
-            .map_err(|e| format!("Failed to prepare fetch: {}", e))?;
+            .map_err(db_error("Failed to prepare fetch"))?;
 
         let rows = stmt
-            .query_map(params![&channel_id, limit as i64, offset as i64], |row| {
+            .query_map(params![&channel_id, limit as i64, offset as i64, now], |row| {
                 Ok(MessageRow {
-                    message_id: {
-                        let blob: Vec<u8> = row.get(0)?;
-                        let mut arr = [0u8; 32];
-                        arr.copy_from_slice(&blob);
-                        arr
-                    },
-                    channel_id: {
-                        let blob: Vec<u8> = row.get(1)?;
-                        let mut arr = [0u8; 32];
-                        arr.copy_from_slice(&blob);
-                        arr
-                    },
+                    message_id: id_column(row, 0)?,
+                    channel_id: id_column(row, 1)?,
                     ciphertext: row.get(2)?,
                     timestamp: row.get(3)?,
                     ttl: {
                         let v: i64 = row.get(4)?;
                         v as u8
                     },
+                    kind: row.get::<_, i64>(5)? as u8,
+                    priority: row.get::<_, i64>(6)? as u8,
                 })
             })
-            .map_err(|e| format!("Failed to query messages: {}", e))?;
+            .map_err(db_error("Failed to query messages"))?;
 
         let mut results = Vec::new();
         for r in rows {
-            results.push(r.map_err(|e| format!("Row error: {}", e))?);
+            results.push(r.map_err(db_error("Row error"))?);
         }
         Ok(results)
     }
 
+    /// Get one message by id.
+    pub fn get_message(&self, message_id: [u8; 32]) -> Result<Option<MessageRow>, String> {
+        self.conn
+            .query_row(
+                "SELECT message_id, channel_id, ciphertext, timestamp, ttl, kind, priority
+                 FROM messages
+                 WHERE message_id = ?1",
+                params![&message_id],
+                |row| {
+                    Ok(MessageRow {
+                        message_id: id_column(row, 0)?,
+                        channel_id: id_column(row, 1)?,
+                        ciphertext: row.get(2)?,
+                        timestamp: row.get(3)?,
+                        ttl: row.get::<_, i64>(4)? as u8,
+                        kind: row.get::<_, i64>(5)? as u8,
+                        priority: row.get::<_, i64>(6)? as u8,
+                    })
+                },
+            )
+            .optional()
+            .map_err(db_error("Failed to read message"))
+    }
+
+    /// The newest user message of a channel that has not disappeared by `now`
+    /// under its retention_secs.
+    pub fn last_user_message(&self, channel_id: [u8; 32], now: i64) -> Result<Option<MessageRow>, String> {
+        self.conn
+            .query_row(
+                "SELECT message_id, channel_id, ciphertext, timestamp, ttl, kind, priority
+                 FROM messages
+                 WHERE channel_id = ?1 AND kind = ?2
+                   AND NOT EXISTS (
+                       SELECT 1 FROM channels c
+                       WHERE c.channel_id = ?1 AND c.retention_secs IS NOT NULL AND timestamp + c.retention_secs <= ?3)
+                 ORDER BY timestamp DESC, message_id DESC
+                 LIMIT 1",
+                params![&channel_id, MESSAGE_KIND_USER as i64, now],
+                |row| {
+                    Ok(MessageRow {
+                        message_id: id_column(row, 0)?,
+                        channel_id: id_column(row, 1)?,
+                        ciphertext: row.get(2)?,
+                        timestamp: row.get(3)?,
+                        ttl: row.get::<_, i64>(4)? as u8,
+                        kind: row.get::<_, i64>(5)? as u8,
+                        priority: row.get::<_, i64>(6)? as u8,
+                    })
+                },
+            )
+            .optional()
+            .map_err(db_error("Failed to read last message"))
+    }
+
+    /// Fetch a channel's messages stored (or placeholders filled) after the
+    /// cursor `after_seq`, in that order, each with its `seq`; pass the last
+    /// one back to get only what came since. Unlike timestamps, the cursor
+    /// also catches messages that arrive late with an old timestamp. Messages
+    /// that disappeared by `now` under the channel's retention are left out.
+    pub fn fetch_messages_since_seq(
+        &self,
+        channel_id: [u8; 32],
+        after_seq: i64,
+        limit: u32,
+        now: i64,
+    ) -> Result<Vec<(i64, MessageRow)>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT message_id, channel_id, ciphertext, timestamp, ttl, kind, priority, seq
+                 FROM messages
+                 WHERE channel_id = ?1 AND seq > ?2
+                   AND NOT EXISTS (
+                       SELECT 1 FROM channels c
+                       WHERE c.channel_id = ?1 AND c.retention_secs IS NOT NULL AND timestamp + c.retention_secs <= ?4)
+                 ORDER BY seq ASC
+                 LIMIT ?3",
+            )
+            .map_err(db_error("Failed to prepare fetch"))?;
+        let rows = stmt
+            .query_map(params![&channel_id, after_seq, limit as i64, now], |row| {
+                Ok((
+                    row.get(7)?,
+                    MessageRow {
+                        message_id: id_column(row, 0)?,
+                        channel_id: id_column(row, 1)?,
+                        ciphertext: row.get(2)?,
+                        timestamp: row.get(3)?,
+                        ttl: row.get::<_, i64>(4)? as u8,
+                        kind: row.get::<_, i64>(5)? as u8,
+                        priority: row.get::<_, i64>(6)? as u8,
+                    },
+                ))
+            })
+            .map_err(db_error("Failed to query messages"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Row error"))
+    }
+
+    /// Fetch user messages in a channel newer than `since`, newest first.
+    pub fn fetch_messages_since(&self, channel_id: [u8; 32], since: i64, limit: u32) -> Result<Vec<MessageRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT message_id, channel_id, ciphertext, timestamp, ttl, kind, priority
+                 FROM messages
+                 WHERE channel_id = ?1 AND timestamp > ?2 AND kind = ?3
+                 ORDER BY timestamp DESC
+                 LIMIT ?4",
+            )
+            .map_err(db_error("Failed to prepare fetch"))?;
+        let rows = stmt
+            .query_map(
+                params![&channel_id, since, MESSAGE_KIND_USER as i64, limit as i64],
+                |row| {
+                    Ok(MessageRow {
+                        message_id: id_column(row, 0)?,
+                        channel_id: id_column(row, 1)?,
+                        ciphertext: row.get(2)?,
+                        timestamp: row.get(3)?,
+                        ttl: row.get::<_, i64>(4)? as u8,
+                        kind: row.get::<_, i64>(5)? as u8,
+                        priority: row.get::<_, i64>(6)? as u8,
+                    })
+                },
+            )
+            .map_err(db_error("Failed to query messages"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Row error"))
+    }
+
+    /// Fetch user messages ordered by (timestamp, message_id) after the cursor
+    /// `(since, after)`, oldest first (history paging).
+    pub fn fetch_messages_after(
+        &self,
+        channel_id: [u8; 32],
+        since: i64,
+        after: [u8; 32],
+        limit: u32,
+    ) -> Result<Vec<MessageRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT message_id, channel_id, ciphertext, timestamp, ttl, kind, priority
+                 FROM messages
+                 WHERE channel_id = ?1 AND kind = ?2
+                   AND (timestamp > ?3 OR (timestamp = ?3 AND message_id > ?4))
+                 ORDER BY timestamp ASC, message_id ASC
+                 LIMIT ?5",
+            )
+            .map_err(db_error("Failed to prepare fetch"))?;
+        let rows = stmt
+            .query_map(
+                params![&channel_id, MESSAGE_KIND_USER as i64, since, &after, limit as i64],
+                |row| {
+                    Ok(MessageRow {
+                        message_id: id_column(row, 0)?,
+                        channel_id: id_column(row, 1)?,
+                        ciphertext: row.get(2)?,
+                        timestamp: row.get(3)?,
+                        ttl: row.get::<_, i64>(4)? as u8,
+                        kind: row.get::<_, i64>(5)? as u8,
+                        priority: row.get::<_, i64>(6)? as u8,
+                    })
+                },
+            )
+            .map_err(db_error("Failed to query messages"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Row error"))
+    }
+
+    /// Get the type of a registered channel.
+    pub fn get_channel_type(&self, channel_id: [u8; 32]) -> Result<Option<String>, String> {
+        self.conn
+            .query_row(
+                "SELECT type FROM channels WHERE channel_id = ?1",
+                params![&channel_id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(db_error("Failed to read channel type"))
+    }
+
+    /// Make a channel's messages disappear `retention_secs` after they were
+    /// sent (None: its type's policy). Returns false for unknown channels.
+    pub fn set_channel_retention(&self, channel_id: [u8; 32], retention_secs: Option<u64>) -> Result<bool, String> {
+        let updated = self
+            .conn
+            .execute(
+                "UPDATE channels SET retention_secs = ?2 WHERE channel_id = ?1",
+                params![&channel_id, retention_secs.map(|s| s.min(i64::MAX as u64) as i64)],
+            )
+            .map_err(db_error("Failed to set channel retention"))?;
+        if updated > 0 {
+            self.conn
+                .execute("UPDATE messages SET expires_at = NULL WHERE channel_id = ?1", params![&channel_id])
+                .map_err(db_error("Failed to clear message expiry"))?;
+        }
+        Ok(updated > 0)
+    }
+
+    /// A channel's retention_secs (None if unset or the channel is unknown).
+    pub fn get_channel_retention(&self, channel_id: [u8; 32]) -> Result<Option<u64>, String> {
+        self.conn
+            .query_row(
+                "SELECT retention_secs FROM channels WHERE channel_id = ?1",
+                params![&channel_id],
+                |row| row.get::<_, Option<i64>>(0),
+            )
+            .optional()
+            .map(|secs| secs.flatten().map(|s| s as u64))
+            .map_err(db_error("Failed to read channel retention"))
+    }
+
     /// Upsert a channel (idempotent on channel_id).
     pub fn upsert_channel(&self, channel_id: [u8; 32], channel_type: &str) -> Result<(), String> {
         self.conn
@@ -139,57 +1356,2999 @@ impl Storage {
                  VALUES (?1, ?2)",
                 params![&channel_id, &channel_type],
             )
-            .map_err(|e| format!("Failed to upsert channel: {}", e))?;
+            .map_err(db_error("Failed to upsert channel"))?;
         Ok(())
     }
 
-    /// Delete all messages for a channel
-    pub fn delete_channel_messages(&self, channel_id: [u8; 32]) -> Result<usize, String> {
-        let count = self.conn
+    /// Change the type of a registered channel.
+    pub fn set_channel_type(&self, channel_id: [u8; 32], channel_type: &str) -> Result<(), String> {
+        self.conn
             .execute(
-                "DELETE FROM messages WHERE channel_id = ?1",
-                params![&channel_id],
+                "UPDATE channels SET type = ?2 WHERE channel_id = ?1",
+                params![&channel_id, channel_type],
             )
-            .map_err(|e| format!("Failed to delete messages: {}", e))?;
-        Ok(count)
+            .map_err(db_error("Failed to set channel type"))?;
+        Ok(())
     }
 
-    /// List channels by type.
-    pub fn list_channels_by_type(&self, channel_type: &str) -> Result<Vec<ChannelRow>, String> {
+    /// Pin or unpin a channel. Returns false if the channel is unknown.
+    pub fn set_channel_pinned(&self, channel_id: [u8; 32], pinned: bool) -> Result<bool, String> {
+        let n = self
+            .conn
+            .execute(
+                "UPDATE channels SET pinned = ?2 WHERE channel_id = ?1",
+                params![&channel_id, pinned],
+            )
+            .map_err(db_error("Failed to pin channel"))?;
+        Ok(n > 0)
+    }
+
+    /// Turn observer (read-only) mode of a geo channel on or off.
+    /// Returns false if there is no such geo channel.
+    pub fn set_channel_observe_only(&self, channel_id: [u8; 32], observe_only: bool) -> Result<bool, String> {
+        let n = self
+            .conn
+            .execute(
+                "UPDATE channels SET observe_only = ?2 WHERE channel_id = ?1 AND type = 'geo'",
+                params![&channel_id, observe_only],
+            )
+            .map_err(db_error("Failed to set observer mode"))?;
+        Ok(n > 0)
+    }
+
+    /// Channels in observer mode.
+    pub fn list_observed_channels(&self) -> Result<Vec<[u8; 32]>, String> {
         let mut stmt = self
             .conn
-            .prepare(
-                "SELECT channel_id, type
-                 FROM channels
-                 WHERE type = ?1",
+            .prepare("SELECT channel_id FROM channels WHERE observe_only = 1")
+            .map_err(db_error("Failed to prepare observed channel query"))?;
+        let rows = stmt
+            .query_map([], |row| id_column(row, 0))
+            .map_err(db_error("Failed to list observed channels"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Observed channel row error"))
+    }
+
+    /// Give the listed channels sort positions 0..n in list order (others keep theirs).
+    pub fn set_channel_order(&self, channel_ids: &[[u8; 32]]) -> Result<(), String> {
+        let tx = self
+            .transaction()
+            .map_err(db_error("Failed to begin transaction"))?;
+        for (i, channel_id) in channel_ids.iter().enumerate() {
+            tx.execute(
+                "UPDATE channels SET sort_order = ?2 WHERE channel_id = ?1",
+                params![channel_id, i as i64],
+            )
+            .map_err(db_error("Failed to set channel order"))?;
+        }
+        tx.commit()
+            .map_err(db_error("Failed to commit channel order"))
+    }
+
+    /// Set a channel's display name (no-op for unknown channels).
+    pub fn set_channel_name(&self, channel_id: [u8; 32], name: &str) -> Result<(), String> {
+        self.conn
+            .execute(
+                "UPDATE channels SET name = ?2 WHERE channel_id = ?1",
+                params![&channel_id, name],
+            )
+            .map_err(db_error("Failed to set channel name"))?;
+        Ok(())
+    }
+
+    /// A channel's display name (None if unset or the channel is unknown).
+    pub fn get_channel_name(&self, channel_id: [u8; 32]) -> Result<Option<String>, String> {
+        self.conn
+            .query_row("SELECT name FROM channels WHERE channel_id = ?1", params![&channel_id], |row| row.get(0))
+            .optional()
+            .map(Option::flatten)
+            .map_err(db_error("Failed to read channel name"))
+    }
+
+    /// Set a channel's name and topic unless a newer change is kept already:
+    /// changes are ordered by (updated_at, updated_by). Returns whether it was set.
+    pub fn set_channel_metadata_if_newer(
+        &self,
+        channel_id: [u8; 32],
+        name: Option<&str>,
+        topic: Option<&str>,
+        updated_at: i64,
+        updated_by: [u8; 32],
+    ) -> Result<bool, String> {
+        let n = self
+            .conn
+            .execute(
+                "UPDATE channels SET name = ?2, topic = ?3, metadata_updated_at = ?4, metadata_updated_by = ?5
+                 WHERE channel_id = ?1
+                 AND (metadata_updated_at IS NULL OR (metadata_updated_at, metadata_updated_by) < (?4, ?5))",
+                params![&channel_id, name, topic, updated_at, &updated_by],
+            )
+            .map_err(db_error("Failed to set channel metadata"))?;
+        Ok(n > 0)
+    }
+
+    /// A channel's topic (None if unset or the channel is unknown).
+    pub fn get_channel_topic(&self, channel_id: [u8; 32]) -> Result<Option<String>, String> {
+        self.conn
+            .query_row("SELECT topic FROM channels WHERE channel_id = ?1", params![&channel_id], |row| row.get(0))
+            .optional()
+            .map(Option::flatten)
+            .map_err(db_error("Failed to read channel topic"))
+    }
+
+    /// Set (or clear, with None) a channel's UI metadata. Returns false for unknown channels.
+    pub fn set_channel_ui_metadata(&self, channel_id: [u8; 32], metadata: Option<&str>) -> Result<bool, String> {
+        let n = self
+            .conn
+            .execute(
+                "UPDATE channels SET ui_metadata = ?2 WHERE channel_id = ?1",
+                params![&channel_id, metadata],
+            )
+            .map_err(db_error("Failed to set channel UI metadata"))?;
+        Ok(n > 0)
+    }
+
+    /// Get a channel's UI metadata (None for unknown channels or when unset).
+    pub fn get_channel_ui_metadata(&self, channel_id: [u8; 32]) -> Result<Option<String>, String> {
+        self.conn
+            .query_row(
+                "SELECT ui_metadata FROM channels WHERE channel_id = ?1",
+                params![&channel_id],
+                |row| row.get(0),
             )
-            .map_err(|e| format!("Failed to prepare channel query: {}", e))?;
+            .optional()
+            .map(Option::flatten)
+            .map_err(db_error("Failed to read channel UI metadata"))
+    }
+
+    /// Store the key of a protected channel (replaces the current key; earlier
+    /// keys stay in `channel_key_epochs`). Returns true if the key is new.
+    pub fn set_channel_key(&self, channel_id: [u8; 32], key: [u8; 32], added_at: i64) -> Result<bool, String> {
+        let tx = self
+            .transaction()
+            .map_err(db_error("Failed to begin transaction"))?;
+        tx.execute(
+            "INSERT OR REPLACE INTO channel_keys (channel_id, key, added_at) VALUES (?1, ?2, ?3)",
+            params![&channel_id, &key, added_at],
+        )
+        .map_err(db_error("Failed to store channel key"))?;
+        let new_epoch = tx
+            .execute(
+                "INSERT OR IGNORE INTO channel_key_epochs (channel_id, key, added_at) VALUES (?1, ?2, ?3)",
+                params![&channel_id, &key, added_at],
+            )
+            .map_err(db_error("Failed to store channel key epoch"))?
+            > 0;
+        tx.commit().map_err(db_error("Failed to commit channel key"))?;
+        Ok(new_epoch)
+    }
+
+    /// Every key a protected channel has had, newest first.
+    pub fn list_channel_key_epochs(&self, channel_id: [u8; 32]) -> Result<Vec<ChannelKeyEpochRow>, String> {
+        self.query_channel_key_epochs(
+            "SELECT channel_id, key, added_at FROM channel_key_epochs
+             WHERE channel_id = ?1 ORDER BY added_at DESC",
+            params![&channel_id],
+        )
+    }
+
+    /// Key epochs of every protected channel, oldest first.
+    pub fn list_all_channel_key_epochs(&self) -> Result<Vec<ChannelKeyEpochRow>, String> {
+        self.query_channel_key_epochs(
+            "SELECT channel_id, key, added_at FROM channel_key_epochs ORDER BY channel_id, added_at ASC",
+            [],
+        )
+    }
 
+    fn query_channel_key_epochs(
+        &self,
+        sql: &str,
+        args: impl rusqlite::Params,
+    ) -> Result<Vec<ChannelKeyEpochRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(sql)
+            .map_err(db_error("Failed to prepare key epoch query"))?;
         let rows = stmt
-            .query_map(params![channel_type], |row| {
-                Ok(ChannelRow {
-                    channel_id: {
-                        let blob: Vec<u8> = row.get(0)?;
-                        let mut arr = [0u8; 32];
-                        arr.copy_from_slice(&blob);
-                        arr
-                    },
-                    channel_type: row.get(1)?,
+            .query_map(args, |row| {
+                Ok(ChannelKeyEpochRow {
+                    channel_id: id_column(row, 0)?,
+                    key: id_column(row, 1)?,
+                    added_at: row.get(2)?,
                 })
             })
-            .map_err(|e| format!("Failed to query channels: {}", e))?;
+            .map_err(db_error("Failed to query key epochs"))?;
 
         let mut out = Vec::new();
         for r in rows {
-            out.push(r.map_err(|e| format!("Channel row error: {}", e))?);
+            out.push(r.map_err(db_error("Key epoch row error"))?);
         }
         Ok(out)
     }
-}
 
-/// Get the storage path for the SQLite database.
-pub fn db_path() -> Result<PathBuf, String> {
-    let data_dir = dirs::data_local_dir().ok_or("Failed to get data directory")?;
-    Ok(data_dir.join("meshapp").join("mesh.db"))
-}
+    /// Restore a key epoch from escrow. The key becomes the channel's current
+    /// key if the channel has none or only an older one. Returns true if the
+    /// epoch was new.
+    pub fn restore_channel_key_epoch(&self, row: &ChannelKeyEpochRow) -> Result<bool, String> {
+        let tx = self
+            .transaction()
+            .map_err(db_error("Failed to begin transaction"))?;
+        let new_epoch = tx
+            .execute(
+                "INSERT OR IGNORE INTO channel_key_epochs (channel_id, key, added_at) VALUES (?1, ?2, ?3)",
+                params![&row.channel_id, &row.key, row.added_at],
+            )
+            .map_err(db_error("Failed to restore channel key epoch"))?
+            > 0;
+        tx.execute(
+            "INSERT OR IGNORE INTO channels (channel_id, type) VALUES (?1, 'group')",
+            params![&row.channel_id],
+        )
+        .map_err(db_error("Failed to upsert channel"))?;
+        tx.execute(
+            "INSERT INTO channel_keys (channel_id, key, added_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(channel_id) DO UPDATE SET key = excluded.key, added_at = excluded.added_at
+             WHERE excluded.added_at > channel_keys.added_at",
+            params![&row.channel_id, &row.key, row.added_at],
+        )
+        .map_err(db_error("Failed to restore channel key"))?;
+        tx.commit().map_err(db_error("Failed to commit key epoch"))?;
+        Ok(new_epoch)
+    }
+
+    /// Get the key of a protected channel (None for open channels).
+    pub fn get_channel_key(&self, channel_id: [u8; 32]) -> Result<Option<[u8; 32]>, String> {
+        self.conn
+            .query_row(
+                "SELECT key FROM channel_keys WHERE channel_id = ?1",
+                params![&channel_id],
+                |row| id_column(row, 0),
+            )
+            .optional()
+            .map_err(db_error("Failed to read channel key"))
+    }
+
+    /// Get a raw setting value.
+    pub fn get_setting(&self, key: &str) -> Result<Option<String>, String> {
+        self.conn
+            .query_row(
+                "SELECT value FROM settings WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(db_error(&format!("Failed to read setting {}", key)))
+    }
+
+    /// Set a raw setting value.
+    pub fn set_setting(&self, key: &str, value: &str) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT INTO settings (key, value) VALUES (?1, ?2)
+                 ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+                params![key, value],
+            )
+            .map_err(db_error(&format!("Failed to write setting {}", key)))?;
+        Ok(())
+    }
+
+    /// Replace the member list of a group.
+    pub fn set_group_members(&self, channel_id: [u8; 32], user_ids: &[[u8; 32]], now: i64) -> Result<(), String> {
+        let tx = self
+            .transaction()
+            .map_err(db_error("Failed to begin transaction"))?;
+        tx.execute("DELETE FROM group_members WHERE channel_id = ?1", params![&channel_id])
+            .map_err(db_error("Failed to clear group members"))?;
+        for user_id in user_ids {
+            tx.execute(
+                "INSERT OR IGNORE INTO group_members (channel_id, user_id, added_at) VALUES (?1, ?2, ?3)",
+                params![&channel_id, user_id, now],
+            )
+            .map_err(db_error("Failed to add group member"))?;
+        }
+        tx.commit()
+            .map_err(db_error("Failed to commit group members"))?;
+        Ok(())
+    }
+
+    /// Members of a group, in the order they were added.
+    pub fn list_group_members(&self, channel_id: [u8; 32]) -> Result<Vec<[u8; 32]>, String> {
+        self.query_ids(
+            "SELECT user_id FROM group_members WHERE channel_id = ?1 ORDER BY added_at, user_id",
+            params![&channel_id],
+        )
+    }
+
+    /// Add admins to a group's roster.
+    pub fn add_group_admins(&self, channel_id: [u8; 32], user_ids: &[[u8; 32]], now: i64) -> Result<(), String> {
+        for user_id in user_ids {
+            self.conn
+                .execute(
+                    "INSERT OR IGNORE INTO group_admins (channel_id, user_id, added_at) VALUES (?1, ?2, ?3)",
+                    params![&channel_id, user_id, now],
+                )
+                .map_err(db_error("Failed to add group admin"))?;
+        }
+        Ok(())
+    }
+
+    /// A group's admins, in the order they were added.
+    pub fn list_group_admins(&self, channel_id: [u8; 32]) -> Result<Vec<[u8; 32]>, String> {
+        self.query_ids(
+            "SELECT user_id FROM group_admins WHERE channel_id = ?1 ORDER BY added_at, user_id",
+            params![&channel_id],
+        )
+    }
+
+    /// Mute or unmute a group member.
+    pub fn set_group_member_muted(&self, channel_id: [u8; 32], user_id: [u8; 32], muted: bool, now: i64) -> Result<(), String> {
+        let result = if muted {
+            self.conn.execute(
+                "INSERT OR IGNORE INTO group_mutes (channel_id, user_id, muted_at) VALUES (?1, ?2, ?3)",
+                params![&channel_id, &user_id, now],
+            )
+        } else {
+            self.conn.execute(
+                "DELETE FROM group_mutes WHERE channel_id = ?1 AND user_id = ?2",
+                params![&channel_id, &user_id],
+            )
+        };
+        result.map_err(db_error("Failed to set group mute"))?;
+        Ok(())
+    }
+
+    /// Muted members of a group.
+    pub fn list_group_mutes(&self, channel_id: [u8; 32]) -> Result<Vec<[u8; 32]>, String> {
+        self.query_ids(
+            "SELECT user_id FROM group_mutes WHERE channel_id = ?1 ORDER BY muted_at, user_id",
+            params![&channel_id],
+        )
+    }
+
+    /// Block a user. A known key replaces a missing one; returns whether the
+    /// user was not blocked before.
+    pub fn block_user(&self, user_id: [u8; 32], ed25519_public: Option<[u8; 32]>, now: i64) -> Result<bool, String> {
+        let was_blocked = self.is_user_blocked(user_id)?;
+        self.conn
+            .execute(
+                "INSERT INTO blocked_users (user_id, ed25519_public, blocked_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(user_id) DO UPDATE SET
+                   ed25519_public = COALESCE(excluded.ed25519_public, blocked_users.ed25519_public)",
+                params![&user_id, ed25519_public.as_ref().map(|k| &k[..]), now],
+            )
+            .map_err(db_error("Failed to block user"))?;
+        Ok(!was_blocked)
+    }
 
+    /// Unblock a user. Returns whether they were blocked.
+    pub fn unblock_user(&self, user_id: [u8; 32]) -> Result<bool, String> {
+        let n = self
+            .conn
+            .execute("DELETE FROM blocked_users WHERE user_id = ?1", params![&user_id])
+            .map_err(db_error("Failed to unblock user"))?;
+        Ok(n > 0)
+    }
+
+    /// Whether a user is blocked.
+    pub fn is_user_blocked(&self, user_id: [u8; 32]) -> Result<bool, String> {
+        self.conn
+            .query_row("SELECT 1 FROM blocked_users WHERE user_id = ?1", params![&user_id], |_| Ok(()))
+            .optional()
+            .map(|found| found.is_some())
+            .map_err(db_error("Failed to read blocklist"))
+    }
+
+    /// Blocked users, in the order they were blocked.
+    pub fn list_blocked_users(&self) -> Result<Vec<BlockedUserRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT user_id, ed25519_public, blocked_at FROM blocked_users ORDER BY blocked_at, user_id")
+            .map_err(db_error("Failed to prepare blocklist query"))?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok(BlockedUserRow {
+                    user_id: id_column(row, 0)?,
+                    ed25519_public: optional_id_column(row, 1)?,
+                    blocked_at: row.get(2)?,
+                })
+            })
+            .map_err(db_error("Failed to query blocklist"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Blocklist row error"))
+    }
+
+    /// Start tracking custody receipts for one of our group messages.
+    pub fn track_group_delivery(&self, row: &GroupDeliveryRow) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR IGNORE INTO group_deliveries (message_id, channel_id, sent_at, attempts, next_attempt)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![&row.message_id, &row.channel_id, row.sent_at, row.attempts, row.next_attempt],
+            )
+            .map_err(db_error("Failed to track group delivery"))?;
+        Ok(())
+    }
+
+    pub fn get_group_delivery(&self, message_id: [u8; 32]) -> Result<Option<GroupDeliveryRow>, String> {
+        self.conn
+            .query_row(
+                "SELECT message_id, channel_id, sent_at, attempts, next_attempt FROM group_deliveries WHERE message_id = ?1",
+                params![&message_id],
+                group_delivery_row,
+            )
+            .optional()
+            .map_err(db_error("Failed to read group delivery"))
+    }
+
+    /// Tracked deliveries whose next retransmission is due.
+    pub fn list_due_group_deliveries(&self, now: i64) -> Result<Vec<GroupDeliveryRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT message_id, channel_id, sent_at, attempts, next_attempt FROM group_deliveries
+                 WHERE next_attempt <= ?1 ORDER BY next_attempt",
+            )
+            .map_err(db_error("Failed to prepare group delivery query"))?;
+        let rows = stmt
+            .query_map(params![now], group_delivery_row)
+            .map_err(db_error("Failed to list group deliveries"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Group delivery row error"))
+    }
+
+    /// Record a retransmission of a tracked delivery.
+    pub fn set_group_delivery_attempt(&self, message_id: [u8; 32], attempts: u32, next_attempt: i64) -> Result<(), String> {
+        self.conn
+            .execute(
+                "UPDATE group_deliveries SET attempts = ?2, next_attempt = ?3 WHERE message_id = ?1",
+                params![&message_id, attempts, next_attempt],
+            )
+            .map_err(db_error("Failed to update group delivery"))?;
+        Ok(())
+    }
+
+    /// Stop tracking a delivery (forgets its receipts).
+    pub fn delete_group_delivery(&self, message_id: [u8; 32]) -> Result<(), String> {
+        let tx = self
+            .transaction()
+            .map_err(db_error("Failed to begin transaction"))?;
+        tx.execute("DELETE FROM delivery_receipts WHERE message_id = ?1", params![&message_id])
+            .map_err(db_error("Failed to delete delivery receipts"))?;
+        tx.execute("DELETE FROM group_deliveries WHERE message_id = ?1", params![&message_id])
+            .map_err(db_error("Failed to delete group delivery"))?;
+        tx.commit()
+            .map_err(db_error("Failed to commit delete"))?;
+        Ok(())
+    }
+
+    /// Record a member's custody receipt. Returns false if it was already known.
+    pub fn add_delivery_receipt(&self, message_id: [u8; 32], user_id: [u8; 32], now: i64) -> Result<bool, String> {
+        let n = self
+            .conn
+            .execute(
+                "INSERT OR IGNORE INTO delivery_receipts (message_id, user_id, received_at) VALUES (?1, ?2, ?3)",
+                params![&message_id, &user_id, now],
+            )
+            .map_err(db_error("Failed to store delivery receipt"))?;
+        Ok(n > 0)
+    }
+
+    /// Members that confirmed custody of a message.
+    pub fn list_delivery_receipts(&self, message_id: [u8; 32]) -> Result<Vec<[u8; 32]>, String> {
+        self.query_ids(
+            "SELECT user_id FROM delivery_receipts WHERE message_id = ?1 ORDER BY received_at",
+            params![&message_id],
+        )
+    }
+
+    fn query_ids(&self, sql: &str, args: impl rusqlite::Params) -> Result<Vec<[u8; 32]>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(sql)
+            .map_err(db_error("Failed to prepare id query"))?;
+        let rows = stmt
+            .query_map(args, |row| id_column(row, 0))
+            .map_err(db_error("Failed to query ids"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Id row error"))
+    }
+
+    /// Set the friend's Noise static key for a DM channel. A changed key
+    /// drops our outgoing sessions and ratchets so the next send handshakes again.
+    pub fn set_dm_peer_key(&self, channel_id: [u8; 32], x25519_public: [u8; 32], now: i64) -> Result<(), String> {
+        let tx = self
+            .transaction()
+            .map_err(db_error("Failed to begin transaction"))?;
+        tx.execute(
+            "DELETE FROM dm_sessions WHERE channel_id = ?1 AND outgoing = 1 AND remote_static != ?2",
+            params![&channel_id, &x25519_public],
+        )
+        .map_err(db_error("Failed to drop DM sessions"))?;
+        tx.execute(
+            "DELETE FROM dm_ratchets WHERE channel_id = ?1 AND initiator = 1 AND remote_static != ?2",
+            params![&channel_id, &x25519_public],
+        )
+        .map_err(db_error("Failed to drop DM ratchets"))?;
+        tx.execute(
+            "INSERT OR REPLACE INTO dm_peer_keys (channel_id, x25519_public, updated_at) VALUES (?1, ?2, ?3)",
+            params![&channel_id, &x25519_public, now],
+        )
+        .map_err(db_error("Failed to store DM peer key"))?;
+        tx.commit().map_err(db_error("Failed to commit DM peer key"))
+    }
+
+    pub fn get_dm_peer_key(&self, channel_id: [u8; 32]) -> Result<Option<[u8; 32]>, String> {
+        self.conn
+            .query_row(
+                "SELECT x25519_public FROM dm_peer_keys WHERE channel_id = ?1",
+                params![&channel_id],
+                |row| id_column(row, 0),
+            )
+            .optional()
+            .map_err(db_error("Failed to read DM peer key"))
+    }
+
+    pub fn put_dm_session(&self, session: &DmSessionRow) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR IGNORE INTO dm_sessions
+                 (session_id, channel_id, outgoing, remote_static, handshake, key, next_counter, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    &session.session_id,
+                    &session.channel_id,
+                    session.outgoing,
+                    &session.remote_static,
+                    &session.handshake,
+                    &session.key,
+                    session.next_counter as i64,
+                    session.created_at
+                ],
+            )
+            .map_err(db_error("Failed to store DM session"))?;
+        Ok(())
+    }
+
+    pub fn get_dm_session(&self, session_id: [u8; 32]) -> Result<Option<DmSessionRow>, String> {
+        self.conn
+            .query_row(
+                "SELECT session_id, channel_id, outgoing, remote_static, handshake, key, next_counter, created_at
+                 FROM dm_sessions WHERE session_id = ?1",
+                params![&session_id],
+                dm_session_row,
+            )
+            .optional()
+            .map_err(db_error("Failed to read DM session"))
+    }
+
+    /// Every session of a DM channel, oldest first (for `audit` exports).
+    #[cfg(feature = "audit")]
+    pub fn list_dm_sessions(&self, channel_id: [u8; 32]) -> Result<Vec<DmSessionRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT session_id, channel_id, outgoing, remote_static, handshake, key, next_counter, created_at
+                 FROM dm_sessions WHERE channel_id = ?1 ORDER BY created_at, session_id",
+            )
+            .map_err(db_error("Failed to prepare DM session query"))?;
+        let rows = stmt
+            .query_map(params![&channel_id], dm_session_row)
+            .map_err(db_error("Failed to query DM sessions"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("DM session row error"))
+    }
+
+    pub fn put_dm_ratchet(&self, ratchet: &DmRatchetRow) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO dm_ratchets
+                 (ratchet_id, channel_id, initiator, remote_static, handshake, root_key, dh_secret, dh_remote,
+                  send_chain, recv_chain, send_count, recv_count, prev_send_count, send_header_key,
+                  recv_header_key, next_send_header_key, next_recv_header_key, received_at, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19)",
+                params![
+                    &ratchet.ratchet_id,
+                    &ratchet.channel_id,
+                    ratchet.initiator,
+                    &ratchet.remote_static,
+                    &ratchet.handshake,
+                    &ratchet.root_key,
+                    &ratchet.dh_secret,
+                    ratchet.dh_remote.as_ref(),
+                    ratchet.send_chain.as_ref(),
+                    ratchet.recv_chain.as_ref(),
+                    ratchet.send_count,
+                    ratchet.recv_count,
+                    ratchet.prev_send_count,
+                    ratchet.send_header_key.as_ref(),
+                    ratchet.recv_header_key.as_ref(),
+                    &ratchet.next_send_header_key,
+                    &ratchet.next_recv_header_key,
+                    ratchet.received_at,
+                    ratchet.created_at
+                ],
+            )
+            .map_err(db_error("Failed to store DM ratchet"))?;
+        Ok(())
+    }
+
+    pub fn get_dm_ratchet(&self, ratchet_id: [u8; 32]) -> Result<Option<DmRatchetRow>, String> {
+        self.query_dm_ratchets("WHERE ratchet_id = ?1", params![&ratchet_id])
+            .map(|rows| rows.into_iter().next())
+    }
+
+    /// The ratchet to send on in a DM channel to the given static key: the
+    /// one a message last arrived on, else our newest.
+    pub fn sending_dm_ratchet(&self, channel_id: [u8; 32], remote_static: [u8; 32]) -> Result<Option<DmRatchetRow>, String> {
+        self.query_dm_ratchets(
+            "WHERE channel_id = ?1 AND remote_static = ?2 AND send_chain IS NOT NULL
+             ORDER BY COALESCE(received_at, 0) DESC, created_at DESC LIMIT 1",
+            params![&channel_id, &remote_static],
+        )
+        .map(|rows| rows.into_iter().next())
+    }
+
+    /// Every ratchet of a DM channel, oldest first (for `audit` exports).
+    #[cfg(feature = "audit")]
+    pub fn list_dm_ratchets(&self, channel_id: [u8; 32]) -> Result<Vec<DmRatchetRow>, String> {
+        self.query_dm_ratchets("WHERE channel_id = ?1 ORDER BY created_at, ratchet_id", params![&channel_id])
+    }
+
+    fn query_dm_ratchets(&self, filter: &str, args: impl rusqlite::Params) -> Result<Vec<DmRatchetRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT ratchet_id, channel_id, initiator, remote_static, handshake, root_key, dh_secret, dh_remote,
+                        send_chain, recv_chain, send_count, recv_count, prev_send_count, send_header_key,
+                        recv_header_key, next_send_header_key, next_recv_header_key, received_at, created_at
+                 FROM dm_ratchets {}",
+                filter
+            ))
+            .map_err(db_error("Failed to prepare DM ratchet query"))?;
+        let rows = stmt
+            .query_map(args, dm_ratchet_row)
+            .map_err(db_error("Failed to query DM ratchets"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("DM ratchet row error"))
+    }
+
+    /// Keep the key of a ratchet message that has not arrived yet, keeping
+    /// at most `keep` keys per ratchet (the oldest go first).
+    pub fn add_skipped_dm_key(
+        &self,
+        ratchet_id: [u8; 32],
+        header_key: [u8; 32],
+        counter: u32,
+        message_key: [u8; 32],
+        now: i64,
+        keep: u32,
+    ) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO dm_skipped_keys (ratchet_id, header_key, counter, message_key, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![&ratchet_id, &header_key, counter, &message_key, now],
+            )
+            .map_err(db_error("Failed to store skipped DM key"))?;
+        self.conn
+            .execute(
+                "DELETE FROM dm_skipped_keys WHERE ratchet_id = ?1 AND rowid NOT IN
+                 (SELECT rowid FROM dm_skipped_keys WHERE ratchet_id = ?1 ORDER BY created_at DESC, rowid DESC LIMIT ?2)",
+                params![&ratchet_id, keep],
+            )
+            .map_err(db_error("Failed to trim skipped DM keys"))?;
+        Ok(())
+    }
+
+    /// Header keys a ratchet has skipped message keys under.
+    pub fn skipped_dm_header_keys(&self, ratchet_id: [u8; 32]) -> Result<Vec<[u8; 32]>, String> {
+        self.query_ids(
+            "SELECT DISTINCT header_key FROM dm_skipped_keys WHERE ratchet_id = ?1",
+            params![&ratchet_id],
+        )
+    }
+
+    /// Take (and delete) a skipped message key.
+    pub fn take_skipped_dm_key(&self, ratchet_id: [u8; 32], header_key: [u8; 32], counter: u32) -> Result<Option<[u8; 32]>, String> {
+        self.conn
+            .query_row(
+                "DELETE FROM dm_skipped_keys WHERE ratchet_id = ?1 AND header_key = ?2 AND counter = ?3
+                 RETURNING message_key",
+                params![&ratchet_id, &header_key, counter],
+                |row| id_column(row, 0),
+            )
+            .optional()
+            .map_err(db_error("Failed to take skipped DM key"))
+    }
+
+    /// Keep the key of a stored ratchet message.
+    pub fn put_dm_message_key(&self, message_id: [u8; 32], channel_id: [u8; 32], outgoing: bool, message_key: [u8; 32]) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO dm_message_keys (message_id, channel_id, outgoing, message_key)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![&message_id, &channel_id, outgoing, &message_key],
+            )
+            .map_err(db_error("Failed to store DM message key"))?;
+        Ok(())
+    }
+
+    /// The key of a stored ratchet message, and whether we sent it.
+    pub fn get_dm_message_key(&self, message_id: [u8; 32]) -> Result<Option<([u8; 32], bool)>, String> {
+        self.conn
+            .query_row(
+                "SELECT message_key, outgoing FROM dm_message_keys WHERE message_id = ?1",
+                params![&message_id],
+                |row| Ok((id_column(row, 0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(db_error("Failed to read DM message key"))
+    }
+
+    /// Raise a reader's status for a message. Returns false if they were
+    /// already at `status` or beyond.
+    pub fn set_message_receipt(
+        &self,
+        message_id: [u8; 32],
+        channel_id: [u8; 32],
+        user_id: [u8; 32],
+        status: u8,
+        now: i64,
+    ) -> Result<bool, String> {
+        let changed = self
+            .conn
+            .execute(
+                "INSERT INTO message_receipts (message_id, user_id, channel_id, status, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT(message_id, user_id) DO UPDATE SET status = excluded.status, updated_at = excluded.updated_at
+                 WHERE excluded.status > message_receipts.status",
+                params![&message_id, &user_id, &channel_id, status, now],
+            )
+            .map_err(db_error("Failed to store message receipt"))?;
+        Ok(changed > 0)
+    }
+
+    /// Keep a decrypted message's plaintext (idempotent on message_id).
+    pub fn store_message_plaintext(
+        &self,
+        message_id: [u8; 32],
+        channel_id: [u8; 32],
+        outgoing: bool,
+        plaintext: &str,
+        now: i64,
+    ) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR IGNORE INTO message_plaintexts (message_id, channel_id, outgoing, plaintext, indexed_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![&message_id, &channel_id, outgoing, plaintext, now],
+            )
+            .map_err(db_error("Failed to store message plaintext"))?;
+        Ok(())
+    }
+
+    pub fn get_send_state(&self, message_id: [u8; 32]) -> Result<Option<SendStateRow>, String> {
+        self.conn
+            .query_row(
+                "SELECT message_id, channel_id, state, attempts, updated_at FROM send_states WHERE message_id = ?1",
+                params![&message_id],
+                |row| {
+                    Ok(SendStateRow {
+                        message_id: id_column(row, 0)?,
+                        channel_id: id_column(row, 1)?,
+                        state: row.get::<_, i64>(2)? as u8,
+                        attempts: row.get::<_, i64>(3)? as u32,
+                        updated_at: row.get(4)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(db_error("Failed to query send state"))
+    }
+
+    /// Store a message's send state (the row must exist; see `store_outgoing_batch`).
+    pub fn put_send_state(&self, row: &SendStateRow) -> Result<(), String> {
+        self.conn
+            .execute(
+                "UPDATE send_states SET state = ?2, attempts = ?3, updated_at = ?4 WHERE message_id = ?1",
+                params![&row.message_id, row.state as i64, row.attempts as i64, row.updated_at],
+            )
+            .map_err(db_error("Failed to store send state"))?;
+        Ok(())
+    }
+
+    /// Our messages in send state `state` (a `send_status::SendState` value).
+    pub fn list_send_state_ids(&self, state: u8) -> Result<Vec<[u8; 32]>, String> {
+        self.query_ids("SELECT message_id FROM send_states WHERE state = ?1 ORDER BY updated_at", params![state as i64])
+    }
+
+    pub fn get_message_plaintext(&self, message_id: [u8; 32]) -> Result<Option<MessagePlaintextRow>, String> {
+        self.conn
+            .query_row(
+                "SELECT outgoing, plaintext FROM message_plaintexts WHERE message_id = ?1",
+                params![&message_id],
+                |row| Ok(MessagePlaintextRow { outgoing: row.get(0)?, plaintext: row.get(1)? }),
+            )
+            .optional()
+            .map_err(db_error("Failed to query message plaintext"))
+    }
+
+    /// Drop every kept plaintext and its search entry. Returns how many were dropped.
+    pub fn clear_message_plaintexts(&self) -> Result<usize, String> {
+        self.conn
+            .execute("DELETE FROM message_search", [])
+            .map_err(db_error("Failed to clear message search"))?;
+        self.conn
+            .execute("DELETE FROM message_plaintexts", [])
+            .map_err(db_error("Failed to clear message plaintexts"))
+    }
+
+    /// Add a message's displayed text to the search index.
+    pub fn index_message_text(&self, message_id: [u8; 32], channel_id: [u8; 32], text: &str) -> Result<(), String> {
+        self.conn
+            .execute("DELETE FROM message_search WHERE message_id = ?1", params![&message_id])
+            .and_then(|_| {
+                self.conn.execute(
+                    "INSERT INTO message_search (text, message_id, channel_id) VALUES (?1, ?2, ?3)",
+                    params![text, &message_id, &channel_id],
+                )
+            })
+            .map_err(db_error("Failed to index message text"))?;
+        Ok(())
+    }
+
+    /// Kept plaintexts missing from the search index.
+    pub fn list_unindexed_plaintexts(&self, limit: u32) -> Result<Vec<KeptPlaintext>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT message_id, channel_id, plaintext FROM message_plaintexts
+                 WHERE message_id NOT IN (SELECT message_id FROM message_search) LIMIT ?1",
+            )
+            .map_err(db_error("Failed to prepare unindexed plaintext query"))?;
+        let rows = stmt
+            .query_map(params![limit], |row| Ok((id_column(row, 0)?, id_column(row, 1)?, row.get(2)?)))
+            .map_err(db_error("Failed to query unindexed plaintexts"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Unindexed plaintext row error"))
+    }
+
+    /// Messages whose indexed text matches an FTS5 query, best match first.
+    pub fn search_message_text(&self, fts_query: &str, limit: u32) -> Result<Vec<MessageSearchRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT message_search.message_id, message_search.channel_id, message_search.text, m.timestamp
+                 FROM message_search JOIN messages m ON m.message_id = message_search.message_id
+                 WHERE message_search MATCH ?1 ORDER BY message_search.rank LIMIT ?2",
+            )
+            .map_err(db_error("Failed to prepare message search"))?;
+        let rows = stmt
+            .query_map(params![fts_query, limit], |row| {
+                Ok(MessageSearchRow {
+                    message_id: id_column(row, 0)?,
+                    channel_id: id_column(row, 1)?,
+                    text: row.get(2)?,
+                    timestamp: row.get(3)?,
+                })
+            })
+            .map_err(db_error("Failed to search messages"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Message search row error"))
+    }
+
+    pub fn list_message_receipts(&self, message_id: [u8; 32]) -> Result<Vec<MessageReceiptRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT user_id, status, updated_at FROM message_receipts WHERE message_id = ?1 ORDER BY updated_at")
+            .map_err(db_error("Failed to prepare receipt query"))?;
+        let rows = stmt
+            .query_map(params![&message_id], |row| {
+                Ok(MessageReceiptRow {
+                    user_id: id_column(row, 0)?,
+                    status: row.get(1)?,
+                    updated_at: row.get(2)?,
+                })
+            })
+            .map_err(db_error("Failed to query message receipts"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Message receipt row error"))
+    }
+
+    pub fn get_client_token(&self, token: &str) -> Result<Option<ClientTokenRow>, String> {
+        self.conn
+            .query_row(
+                "SELECT fingerprint, result, created_at FROM client_tokens WHERE token = ?1",
+                params![token],
+                |row| {
+                    Ok(ClientTokenRow {
+                        fingerprint: id_column(row, 0)?,
+                        result: row.get(1)?,
+                        created_at: row.get(2)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(db_error("Failed to read client token"))
+    }
+
+    pub fn put_client_token(&self, token: &str, fingerprint: [u8; 32], result: &str, now: i64) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO client_tokens (token, fingerprint, result, created_at) VALUES (?1, ?2, ?3, ?4)",
+                params![token, &fingerprint, result, now],
+            )
+            .map_err(db_error("Failed to store client token"))?;
+        Ok(())
+    }
+
+    /// Forget client tokens created before `cutoff`.
+    pub fn prune_client_tokens(&self, cutoff: i64) -> Result<usize, String> {
+        self.conn
+            .execute("DELETE FROM client_tokens WHERE created_at < ?1", params![cutoff])
+            .map_err(db_error("Failed to prune client tokens"))
+    }
+
+    /// Record packet ids the router has handled (already recorded ones keep their time).
+    pub fn record_seen_packets(&self, packet_ids: &[[u8; 32]], seen_at: i64) -> Result<(), String> {
+        let tx = self
+            .transaction()
+            .map_err(db_error("Failed to begin transaction"))?;
+        {
+            let mut stmt = tx
+                .prepare("INSERT OR IGNORE INTO seen_packets (packet_id, seen_at) VALUES (?1, ?2)")
+                .map_err(db_error("Failed to prepare statement"))?;
+            for packet_id in packet_ids {
+                stmt.execute(params![packet_id, seen_at])
+                    .map_err(db_error("Failed to record seen packet"))?;
+            }
+        }
+        tx.commit()
+            .map_err(db_error("Failed to commit seen packets"))
+    }
+
+    /// The newest `limit` packet ids seen at or after `since`, oldest first.
+    pub fn list_seen_packets(&self, since: i64, limit: usize) -> Result<Vec<[u8; 32]>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT packet_id FROM
+                 (SELECT packet_id, seen_at, rowid AS seq FROM seen_packets WHERE seen_at >= ?1
+                  ORDER BY seen_at DESC, seq DESC LIMIT ?2)
+                 ORDER BY seen_at, seq",
+            )
+            .map_err(db_error("Failed to prepare statement"))?;
+        let rows = stmt
+            .query_map(params![since, limit.min(i64::MAX as usize) as i64], |row| id_column(row, 0))
+            .map_err(db_error("Failed to query seen packets"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Failed to read seen packets"))
+    }
+
+    /// Forget packet ids seen before `cutoff`.
+    pub fn prune_seen_packets(&self, cutoff: i64) -> Result<usize, String> {
+        self.conn
+            .execute("DELETE FROM seen_packets WHERE seen_at < ?1", params![cutoff])
+            .map_err(db_error("Failed to prune seen packets"))
+    }
+
+    /// Queue a packet in the outbox, first tried at `next_attempt` (a packet
+    /// queued already keeps its place).
+    pub fn enqueue_outbox(&self, row: &OutboxRow) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR IGNORE INTO outbox (packet_id, channel_id, kind, ttl, payload, created_at, attempts, next_attempt, priority)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    &row.packet_id,
+                    &row.channel_id,
+                    row.kind,
+                    row.ttl,
+                    &row.payload,
+                    row.created_at,
+                    row.attempts,
+                    row.next_attempt,
+                    row.priority
+                ],
+            )
+            .map_err(db_error("Failed to queue outbox packet"))?;
+        Ok(())
+    }
+
+    /// Outbox packets due at `now`, most urgent first, then oldest first.
+    pub fn list_due_outbox(&self, now: i64) -> Result<Vec<OutboxRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT packet_id, channel_id, kind, ttl, payload, created_at, attempts, next_attempt, priority
+                 FROM outbox WHERE next_attempt <= ?1 ORDER BY priority DESC, created_at, rowid",
+            )
+            .map_err(db_error("Failed to prepare statement"))?;
+        let rows = stmt
+            .query_map(params![now], |row| {
+                Ok(OutboxRow {
+                    packet_id: id_column(row, 0)?,
+                    channel_id: id_column(row, 1)?,
+                    kind: row.get(2)?,
+                    ttl: row.get(3)?,
+                    payload: row.get(4)?,
+                    created_at: row.get(5)?,
+                    attempts: row.get(6)?,
+                    next_attempt: row.get(7)?,
+                    priority: row.get(8)?,
+                })
+            })
+            .map_err(db_error("Failed to query outbox"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Failed to read outbox"))
+    }
+
+    /// Record a failed retry of an outbox packet.
+    pub fn reschedule_outbox(&self, packet_id: [u8; 32], attempts: u32, next_attempt: i64) -> Result<(), String> {
+        self.conn
+            .execute(
+                "UPDATE outbox SET attempts = ?2, next_attempt = ?3 WHERE packet_id = ?1",
+                params![&packet_id, attempts, next_attempt],
+            )
+            .map_err(db_error("Failed to reschedule outbox packet"))?;
+        Ok(())
+    }
+
+    /// Remove a packet from the outbox (sent or given up).
+    pub fn delete_outbox(&self, packet_id: [u8; 32]) -> Result<(), String> {
+        self.conn
+            .execute("DELETE FROM outbox WHERE packet_id = ?1", params![&packet_id])
+            .map_err(db_error("Failed to delete outbox packet"))?;
+        Ok(())
+    }
+
+    /// Outbox size: (packets, earliest next_attempt, earliest created_at).
+    pub fn outbox_summary(&self) -> Result<(u64, Option<i64>, Option<i64>), String> {
+        self.conn
+            .query_row(
+                "SELECT COUNT(*), MIN(next_attempt), MIN(created_at) FROM outbox",
+                [],
+                |row| Ok((row.get::<_, i64>(0)? as u64, row.get(1)?, row.get(2)?)),
+            )
+            .map_err(db_error("Failed to read outbox summary"))
+    }
+
+    /// Queued packets of `kind` whose message is no longer stored.
+    pub fn list_outbox_without_message(&self, kind: u8) -> Result<Vec<[u8; 32]>, String> {
+        self.query_ids(
+            "SELECT packet_id FROM outbox WHERE kind = ?1 AND packet_id NOT IN (SELECT message_id FROM messages)",
+            params![kind as i64],
+        )
+    }
+
+    /// Record that a peer was seen (keeps the latest last_seen).
+    pub fn upsert_peer(&self, peer_id: [u8; 32], last_seen: i64) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT INTO peers (peer_id, last_seen) VALUES (?1, ?2)
+                 ON CONFLICT(peer_id) DO UPDATE SET last_seen = MAX(last_seen, excluded.last_seen)",
+                params![&peer_id, last_seen],
+            )
+            .map_err(db_error("Failed to upsert peer"))?;
+        Ok(())
+    }
+
+    /// When a peer was last seen, if it is known.
+    pub fn get_peer_last_seen(&self, peer_id: [u8; 32]) -> Result<Option<i64>, String> {
+        self.conn
+            .query_row("SELECT last_seen FROM peers WHERE peer_id = ?1", params![&peer_id], |row| row.get(0))
+            .optional()
+            .map_err(db_error("Failed to read peer"))
+    }
+
+    /// Record that a peer was heard on a channel (keeps the latest last_seen).
+    pub fn upsert_routing_hint(&self, channel_id: [u8; 32], peer_id: [u8; 32], last_seen: i64) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT INTO routing_hints (channel_id, peer_id, last_seen) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(channel_id, peer_id) DO UPDATE SET last_seen = MAX(last_seen, excluded.last_seen)",
+                params![&channel_id, &peer_id, last_seen],
+            )
+            .map_err(db_error("Failed to upsert routing hint"))?;
+        Ok(())
+    }
+
+    /// List known peers, most recently seen first.
+    pub fn list_peers(&self) -> Result<Vec<PeerRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT peer_id, last_seen FROM peers ORDER BY last_seen DESC")
+            .map_err(db_error("Failed to prepare peer query"))?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok(PeerRow {
+                    peer_id: id_column(row, 0)?,
+                    last_seen: row.get(1)?,
+                })
+            })
+            .map_err(db_error("Failed to list peers"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Peer row error"))
+    }
+
+    pub fn put_peer_capabilities(&self, row: &PeerCapabilitiesRow) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO peer_capabilities
+                 (peer_id, protocol_version, ciphers, max_mtu, negotiated_at, expires_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![&row.peer_id, row.protocol_version, row.ciphers, row.max_mtu, row.negotiated_at, row.expires_at],
+            )
+            .map_err(db_error("Failed to store peer capabilities"))?;
+        Ok(())
+    }
+
+    pub fn get_peer_capabilities(&self, peer_id: [u8; 32]) -> Result<Option<PeerCapabilitiesRow>, String> {
+        self.conn
+            .query_row(
+                "SELECT peer_id, protocol_version, ciphers, max_mtu, negotiated_at, expires_at
+                 FROM peer_capabilities WHERE peer_id = ?1",
+                params![&peer_id],
+                |row| {
+                    Ok(PeerCapabilitiesRow {
+                        peer_id: id_column(row, 0)?,
+                        protocol_version: row.get(1)?,
+                        ciphers: row.get(2)?,
+                        max_mtu: row.get(3)?,
+                        negotiated_at: row.get(4)?,
+                        expires_at: row.get(5)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(db_error("Failed to read peer capabilities"))
+    }
+
+    pub fn delete_peer_capabilities(&self, peer_id: [u8; 32]) -> Result<(), String> {
+        self.conn
+            .execute("DELETE FROM peer_capabilities WHERE peer_id = ?1", params![&peer_id])
+            .map_err(db_error("Failed to delete peer capabilities"))?;
+        Ok(())
+    }
+
+    /// List all routing hints.
+    pub fn list_routing_hints(&self) -> Result<Vec<RoutingHintRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT channel_id, peer_id, last_seen FROM routing_hints")
+            .map_err(db_error("Failed to prepare routing hint query"))?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok(RoutingHintRow {
+                    channel_id: id_column(row, 0)?,
+                    peer_id: id_column(row, 1)?,
+                    last_seen: row.get(2)?,
+                })
+            })
+            .map_err(db_error("Failed to list routing hints"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Routing hint row error"))
+    }
+
+    /// List all stored settings as (key, raw value), ordered by key.
+    pub fn list_settings(&self) -> Result<Vec<(String, String)>, String> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT key, value FROM settings ORDER BY key")
+            .map_err(db_error("Failed to prepare settings query"))?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(db_error("Failed to list settings"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Failed to read setting row"))
+    }
+
+    /// Get a channel's notification settings (None if the channel is unknown).
+    pub fn get_notification_settings(&self, channel_id: [u8; 32]) -> Result<Option<NotificationSettings>, String> {
+        self.conn
+            .query_row(
+                "SELECT muted, mention_only, sound_profile FROM channels WHERE channel_id = ?1",
+                params![&channel_id],
+                |row| {
+                    Ok(NotificationSettings {
+                        muted: row.get(0)?,
+                        mention_only: row.get(1)?,
+                        sound_profile: row.get(2)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(db_error("Failed to query notification settings"))
+    }
+
+    /// Update a channel's notification settings.
+    /// Returns false if the channel is unknown.
+    pub fn set_notification_settings(
+        &self,
+        channel_id: [u8; 32],
+        settings: &NotificationSettings,
+    ) -> Result<bool, String> {
+        let updated = self.conn
+            .execute(
+                "UPDATE channels SET muted = ?2, mention_only = ?3, sound_profile = ?4
+                 WHERE channel_id = ?1",
+                params![&channel_id, settings.muted, settings.mention_only, &settings.sound_profile],
+            )
+            .map_err(db_error("Failed to update notification settings"))?;
+        Ok(updated > 0)
+    }
+
+    /// Delete all messages for a channel
+    /// Also drops the messages' attachment references (blobs are reclaimed by GC).
+    pub fn delete_channel_messages(&self, channel_id: [u8; 32]) -> Result<usize, String> {
+        let tx = self
+            .transaction()
+            .map_err(db_error("Failed to begin transaction"))?;
+        tx.execute(
+            "DELETE FROM attachment_refs
+             WHERE message_id IN (SELECT message_id FROM messages WHERE channel_id = ?1)",
+            params![&channel_id],
+        )
+        .map_err(db_error("Failed to delete attachment refs"))?;
+        tx.execute("DELETE FROM message_receipts WHERE channel_id = ?1", params![&channel_id])
+            .map_err(db_error("Failed to delete message receipts"))?;
+        tx.execute("DELETE FROM send_states WHERE channel_id = ?1", params![&channel_id])
+            .map_err(db_error("Failed to delete send states"))?;
+        tx.execute("DELETE FROM message_search WHERE channel_id = ?1", params![&channel_id])
+            .map_err(db_error("Failed to delete message search entries"))?;
+        tx.execute("DELETE FROM message_plaintexts WHERE channel_id = ?1", params![&channel_id])
+            .map_err(db_error("Failed to delete message plaintexts"))?;
+        tx.execute("DELETE FROM dm_message_keys WHERE channel_id = ?1", params![&channel_id])
+            .map_err(db_error("Failed to delete DM message keys"))?;
+        tx.execute(
+            "DELETE FROM outbox WHERE packet_id IN (SELECT message_id FROM messages WHERE channel_id = ?1)",
+            params![&channel_id],
+        )
+        .map_err(db_error("Failed to delete outbox packets"))?;
+        let count = tx
+            .execute(
+                "DELETE FROM messages WHERE channel_id = ?1",
+                params![&channel_id],
+            )
+            .map_err(db_error("Failed to delete messages"))?;
+        tx.commit()
+            .map_err(db_error("Failed to commit delete"))?;
+        Ok(count)
+    }
+
+    /// Delete one message with its attachment refs, receipts and plaintext.
+    /// Returns false if it was not stored.
+    pub fn delete_message(&self, message_id: [u8; 32]) -> Result<bool, String> {
+        if self.get_message(message_id)?.is_none() {
+            return Ok(false);
+        }
+        let tx = self
+            .transaction()
+            .map_err(db_error("Failed to begin transaction"))?;
+        delete_message_rows(&tx, &message_id)?;
+        tx.commit()
+            .map_err(db_error("Failed to commit delete"))?;
+        Ok(true)
+    }
+
+    /// Move a channel's read marker to (timestamp, message_id) if that is
+    /// further on. Returns whether it moved.
+    pub fn advance_read_marker(&self, channel_id: [u8; 32], timestamp: i64, message_id: [u8; 32], now: i64) -> Result<bool, String> {
+        advance_read_marker(&self.conn, channel_id, timestamp, message_id, now)
+    }
+
+    /// A channel's read marker.
+    pub fn get_read_marker(&self, channel_id: [u8; 32]) -> Result<Option<MessagePosition>, String> {
+        self.conn
+            .query_row(
+                "SELECT timestamp, message_id FROM read_markers WHERE channel_id = ?1",
+                params![&channel_id],
+                |row| Ok((row.get(0)?, id_column(row, 1)?)),
+            )
+            .optional()
+            .map_err(db_error("Failed to read read marker"))
+    }
+
+    /// Newest time this node itself wrote: the newest of our sent messages
+    /// and of the times quarantined messages were stored at. Times other
+    /// nodes claimed are left out, so no peer can move our clock.
+    pub fn newest_local_timestamp(&self) -> Result<Option<i64>, String> {
+        self.conn
+            .query_row(
+                "SELECT MAX(t) FROM (
+                     SELECT MAX(m.timestamp) AS t FROM messages m JOIN send_states s ON s.message_id = m.message_id
+                     UNION ALL SELECT MAX(stored) FROM quarantined_timestamps
+                 )",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(db_error("Failed to read newest local time"))
+    }
+
+    /// Record a quarantined message time (the first record of a message wins).
+    pub fn quarantine_timestamp(&self, row: &QuarantinedTimestampRow) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR IGNORE INTO quarantined_timestamps (message_id, channel_id, claimed, stored, quarantined_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![&row.message_id, &row.channel_id, row.claimed, row.stored, row.quarantined_at],
+            )
+            .map_err(db_error("Failed to quarantine timestamp"))?;
+        Ok(())
+    }
+
+    /// Quarantined message times, newest first.
+    pub fn list_quarantined_timestamps(&self, limit: u32) -> Result<Vec<QuarantinedTimestampRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT message_id, channel_id, claimed, stored, quarantined_at FROM quarantined_timestamps
+                 ORDER BY quarantined_at DESC, message_id LIMIT ?1",
+            )
+            .map_err(db_error("Failed to prepare quarantine query"))?;
+        let rows = stmt
+            .query_map(params![limit], |row| {
+                Ok(QuarantinedTimestampRow {
+                    message_id: id_column(row, 0)?,
+                    channel_id: id_column(row, 1)?,
+                    claimed: row.get(2)?,
+                    stored: row.get(3)?,
+                    quarantined_at: row.get(4)?,
+                })
+            })
+            .map_err(db_error("Failed to query quarantined timestamps"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Quarantine row error"))
+    }
+
+    /// Record a new pseudonym.
+    pub fn insert_pseudonym(&self, row: &PseudonymRow) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT INTO pseudonyms (channel_id, generation, ed25519_public, created_at, retired_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![&row.channel_id, row.generation, &row.ed25519_public, row.created_at, row.retired_at],
+            )
+            .map_err(db_error("Failed to store pseudonym"))?;
+        Ok(())
+    }
+
+    /// Mark a channel's current pseudonym retired.
+    pub fn retire_pseudonym(&self, channel_id: [u8; 32], now: i64) -> Result<(), String> {
+        self.conn
+            .execute(
+                "UPDATE pseudonyms SET retired_at = ?2 WHERE channel_id = ?1 AND retired_at IS NULL",
+                params![&channel_id, now],
+            )
+            .map_err(db_error("Failed to retire pseudonym"))?;
+        Ok(())
+    }
+
+    /// A channel's newest pseudonym (retired or not).
+    pub fn latest_pseudonym(&self, channel_id: [u8; 32]) -> Result<Option<PseudonymRow>, String> {
+        self.query_pseudonyms("WHERE channel_id = ?1 ORDER BY generation DESC LIMIT 1", params![&channel_id])
+            .map(|rows| rows.into_iter().next())
+    }
+
+    /// One generation of a channel's pseudonym.
+    pub fn get_pseudonym(&self, channel_id: [u8; 32], generation: u32) -> Result<Option<PseudonymRow>, String> {
+        self.query_pseudonyms("WHERE channel_id = ?1 AND generation = ?2", params![&channel_id, generation])
+            .map(|rows| rows.into_iter().next())
+    }
+
+    /// The pseudonym with this public key, if it is ours.
+    pub fn find_pseudonym(&self, ed25519_public: [u8; 32]) -> Result<Option<PseudonymRow>, String> {
+        self.query_pseudonyms("WHERE ed25519_public = ?1", params![&ed25519_public])
+            .map(|rows| rows.into_iter().next())
+    }
+
+    /// Every pseudonym, by channel, current generation first.
+    pub fn list_pseudonyms(&self) -> Result<Vec<PseudonymRow>, String> {
+        self.query_pseudonyms("ORDER BY channel_id, generation DESC", [])
+    }
+
+    fn query_pseudonyms(&self, filter: &str, args: impl rusqlite::Params) -> Result<Vec<PseudonymRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT channel_id, generation, ed25519_public, created_at, retired_at FROM pseudonyms {}",
+                filter
+            ))
+            .map_err(db_error("Failed to prepare pseudonym query"))?;
+        let rows = stmt
+            .query_map(args, |row| {
+                Ok(PseudonymRow {
+                    channel_id: id_column(row, 0)?,
+                    generation: row.get(1)?,
+                    ed25519_public: id_column(row, 2)?,
+                    created_at: row.get(3)?,
+                    retired_at: row.get(4)?,
+                })
+            })
+            .map_err(db_error("Failed to query pseudonyms"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Pseudonym row error"))
+    }
+
+    /// Remember which pseudonym wrote a message we sent.
+    pub fn record_pseudonym_message(&self, message_id: [u8; 32], channel_id: [u8; 32], generation: u32) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO pseudonym_messages (message_id, channel_id, generation) VALUES (?1, ?2, ?3)",
+                params![&message_id, &channel_id, generation],
+            )
+            .map_err(db_error("Failed to record pseudonym message"))?;
+        Ok(())
+    }
+
+    /// The pseudonym generation that wrote a message of ours in a channel.
+    pub fn pseudonym_of_message(&self, message_id: [u8; 32], channel_id: [u8; 32]) -> Result<Option<u32>, String> {
+        self.conn
+            .query_row(
+                "SELECT generation FROM pseudonym_messages WHERE message_id = ?1 AND channel_id = ?2",
+                params![&message_id, &channel_id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(db_error("Failed to read pseudonym message"))
+    }
+
+    /// Relay traffic of a day (zero if nothing was relayed).
+    pub fn get_relay_usage(&self, day: i64) -> Result<RelayUsageRow, String> {
+        self.conn
+            .query_row(
+                "SELECT bytes, packets, throttled FROM relay_usage WHERE day = ?1",
+                params![day],
+                |row| {
+                    Ok(RelayUsageRow {
+                        day,
+                        bytes: row.get::<_, i64>(0)? as u64,
+                        packets: row.get::<_, i64>(1)? as u64,
+                        throttled: row.get::<_, i64>(2)? as u64,
+                    })
+                },
+            )
+            .optional()
+            .map(|row| row.unwrap_or(RelayUsageRow { day, ..Default::default() }))
+            .map_err(db_error("Failed to read relay usage"))
+    }
+
+    /// Add one packet to a day's relay traffic: `bytes` relayed, or throttled.
+    pub fn add_relay_usage(&self, day: i64, bytes: u64, throttled: bool) -> Result<(), String> {
+        let (bytes, packets, throttled) = if throttled { (0, 0, 1) } else { (bytes as i64, 1, 0) };
+        self.conn
+            .execute(
+                "INSERT INTO relay_usage (day, bytes, packets, throttled) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT(day) DO UPDATE SET bytes = bytes + ?2, packets = packets + ?3, throttled = throttled + ?4",
+                params![day, bytes, packets, throttled],
+            )
+            .map_err(db_error("Failed to record relay usage"))?;
+        Ok(())
+    }
+
+    /// Forget relay traffic of days before `day`.
+    pub fn prune_relay_usage(&self, day: i64) -> Result<(), String> {
+        self.conn
+            .execute("DELETE FROM relay_usage WHERE day < ?1", params![day])
+            .map_err(db_error("Failed to prune relay usage"))?;
+        Ok(())
+    }
+
+    /// The position of the newest message in a channel.
+    pub fn last_message(&self, channel_id: [u8; 32]) -> Result<Option<MessagePosition>, String> {
+        self.conn
+            .query_row(
+                "SELECT timestamp, message_id FROM messages WHERE channel_id = ?1
+                 ORDER BY timestamp DESC, message_id DESC LIMIT 1",
+                params![&channel_id],
+                |row| Ok((row.get(0)?, id_column(row, 1)?)),
+            )
+            .optional()
+            .map_err(db_error("Failed to read last message"))
+    }
+
+    /// The position of the first user message after a position (`None`: from
+    /// the start), with how many user messages follow that position.
+    pub fn first_user_message_after(
+        &self,
+        channel_id: [u8; 32],
+        position: Option<MessagePosition>,
+    ) -> Result<Option<(MessagePosition, u64)>, String> {
+        let (since, after) = position.unwrap_or((i64::MIN, [0u8; 32]));
+        let first = self
+            .conn
+            .query_row(
+                "SELECT timestamp, message_id FROM messages
+                 WHERE channel_id = ?1 AND kind = ?2
+                   AND (timestamp > ?3 OR (timestamp = ?3 AND message_id > ?4))
+                 ORDER BY timestamp ASC, message_id ASC LIMIT 1",
+                params![&channel_id, MESSAGE_KIND_USER as i64, since, &after],
+                |row| Ok((row.get(0)?, id_column(row, 1)?)),
+            )
+            .optional()
+            .map_err(db_error("Failed to read first unread message"))?;
+        let Some(first) = first else {
+            return Ok(None);
+        };
+        let count: i64 = self
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM messages
+                 WHERE channel_id = ?1 AND kind = ?2
+                   AND (timestamp > ?3 OR (timestamp = ?3 AND message_id > ?4))",
+                params![&channel_id, MESSAGE_KIND_USER as i64, since, &after],
+                |row| row.get(0),
+            )
+            .map_err(db_error("Failed to count unread messages"))?;
+        Ok(Some((first, count as u64)))
+    }
+
+    /// How many messages of a channel come before (timestamp, message_id) in
+    /// `fetch_messages` order: the offset that fetches it first.
+    pub fn message_offset(&self, channel_id: [u8; 32], timestamp: i64, message_id: [u8; 32]) -> Result<u64, String> {
+        self.conn
+            .query_row(
+                "SELECT COUNT(*) FROM messages
+                 WHERE channel_id = ?1 AND (timestamp < ?2 OR (timestamp = ?2 AND message_id < ?3))",
+                params![&channel_id, timestamp, &message_id],
+                |row| row.get::<_, i64>(0),
+            )
+            .map(|n| n as u64)
+            .map_err(db_error("Failed to count messages"))
+    }
+
+    /// Channels that hold messages, with their type (None if never registered).
+    pub fn list_message_channels(&self) -> Result<Vec<MessageChannel>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT m.channel_id, c.type FROM (SELECT DISTINCT channel_id FROM messages) m
+                 LEFT JOIN channels c ON c.channel_id = m.channel_id",
+            )
+            .map_err(db_error("Failed to prepare statement"))?;
+        let rows = stmt
+            .query_map([], |row| Ok((id_column(row, 0)?, row.get(1)?)))
+            .map_err(db_error("Failed to query message channels"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Failed to read message channels"))
+    }
+
+    /// Stamp a channel's messages that have no expiry yet with
+    /// `timestamp + lifetime_secs`. Returns how many were stamped.
+    pub fn stamp_message_expiry(&self, channel_id: [u8; 32], lifetime_secs: i64) -> Result<usize, String> {
+        self.conn
+            .execute(
+                "UPDATE messages SET expires_at = timestamp + ?2 WHERE channel_id = ?1 AND expires_at IS NULL",
+                params![&channel_id, lifetime_secs],
+            )
+            .map_err(db_error("Failed to stamp message expiry"))
+    }
+
+    /// Forget every stamped expiry (the retention policy changed).
+    pub fn clear_message_expiry(&self) -> Result<usize, String> {
+        self.conn
+            .execute("UPDATE messages SET expires_at = NULL WHERE expires_at IS NOT NULL", [])
+            .map_err(db_error("Failed to clear message expiry"))
+    }
+
+    /// Delete messages whose expiry is at or before `now`, with their
+    /// attachment refs, receipts and plaintexts. Returns (channel_id,
+    /// message_id) of each, oldest first.
+    pub fn purge_expired_messages(&self, now: i64) -> Result<Vec<ChannelMessage>, String> {
+        let tx = self
+            .transaction()
+            .map_err(db_error("Failed to begin transaction"))?;
+        let deleted = {
+            let mut stmt = tx
+                .prepare("SELECT channel_id, message_id FROM messages WHERE expires_at <= ?1 ORDER BY timestamp")
+                .map_err(db_error("Failed to prepare statement"))?;
+            let rows = stmt
+                .query_map(params![now], |row| Ok((id_column(row, 0)?, id_column(row, 1)?)))
+                .map_err(db_error("Failed to query expired messages"))?;
+            rows.collect::<Result<Vec<_>, _>>()
+                .map_err(db_error("Failed to read expired messages"))?
+        };
+        for (_, message_id) in &deleted {
+            delete_message_rows(&tx, message_id)?;
+        }
+        tx.commit()
+            .map_err(db_error("Failed to commit delete"))?;
+        Ok(deleted)
+    }
+
+    /// Delete a channel's messages beyond its newest `keep_newest`, with their
+    /// attachment refs, receipts and plaintexts. Returns the deleted message
+    /// ids, oldest first.
+    pub fn prune_channel_messages(&self, channel_id: [u8; 32], keep_newest: u64) -> Result<Vec<[u8; 32]>, String> {
+        let tx = self
+            .transaction()
+            .map_err(db_error("Failed to begin transaction"))?;
+        let deleted = {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT message_id FROM messages WHERE channel_id = ?1 AND message_id NOT IN
+                     (SELECT message_id FROM messages WHERE channel_id = ?1 ORDER BY timestamp DESC, message_id DESC LIMIT ?2)
+                     ORDER BY timestamp",
+                )
+                .map_err(db_error("Failed to prepare statement"))?;
+            let rows = stmt
+                .query_map(params![&channel_id, keep_newest.min(i64::MAX as u64) as i64], |row| id_column(row, 0))
+                .map_err(db_error("Failed to query expired messages"))?;
+            rows.collect::<Result<Vec<_>, _>>()
+                .map_err(db_error("Failed to read expired messages"))?
+        };
+        for message_id in &deleted {
+            delete_message_rows(&tx, message_id)?;
+        }
+        tx.commit()
+            .map_err(db_error("Failed to commit delete"))?;
+        Ok(deleted)
+    }
+
+    /// Delete the oldest messages until the ciphertext of those left fits in
+    /// `max_bytes`, with their attachment refs, receipts and plaintexts.
+    /// Returns (channel_id, message_id) of each, oldest first.
+    pub fn prune_messages_to_size(&self, max_bytes: u64) -> Result<Vec<ChannelMessage>, String> {
+        let tx = self
+            .transaction()
+            .map_err(db_error("Failed to begin transaction"))?;
+        let deleted = {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT channel_id, message_id FROM
+                     (SELECT channel_id, message_id, timestamp,
+                      SUM(COALESCE(LENGTH(ciphertext), 0)) OVER (ORDER BY timestamp DESC, message_id DESC) AS kept
+                      FROM messages)
+                     WHERE kept > ?1 ORDER BY timestamp",
+                )
+                .map_err(db_error("Failed to prepare statement"))?;
+            let rows = stmt
+                .query_map(params![max_bytes.min(i64::MAX as u64) as i64], |row| Ok((id_column(row, 0)?, id_column(row, 1)?)))
+                .map_err(db_error("Failed to query messages over quota"))?;
+            rows.collect::<Result<Vec<_>, _>>()
+                .map_err(db_error("Failed to read messages over quota"))?
+        };
+        for (_, message_id) in &deleted {
+            delete_message_rows(&tx, message_id)?;
+        }
+        tx.commit()
+            .map_err(db_error("Failed to commit delete"))?;
+        Ok(deleted)
+    }
+
+    /// Star a message (replaces an earlier star of the same message).
+    pub fn star_message(&self, star: &StarredRow) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO starred_messages
+                 (message_id, channel_id, timestamp, author, starred_at, mode, ciphertext, key_kind, key, plaintext)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    &star.message_id,
+                    &star.channel_id,
+                    star.timestamp,
+                    star.author.as_ref(),
+                    star.starred_at,
+                    &star.mode,
+                    star.ciphertext.as_ref(),
+                    star.key_kind.as_ref(),
+                    star.key.as_ref(),
+                    star.plaintext.as_ref(),
+                ],
+            )
+            .map_err(db_error("Failed to star message"))?;
+        Ok(())
+    }
+
+    /// Remove a star. Returns false if the message was not starred.
+    pub fn unstar_message(&self, message_id: [u8; 32]) -> Result<bool, String> {
+        let removed = self
+            .conn
+            .execute("DELETE FROM starred_messages WHERE message_id = ?1", params![&message_id])
+            .map_err(db_error("Failed to unstar message"))?;
+        Ok(removed > 0)
+    }
+
+    /// List starred messages, most recently starred first.
+    pub fn list_starred(&self, limit: u32, offset: u32) -> Result<Vec<StarredRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT message_id, channel_id, timestamp, author, starred_at, mode, ciphertext, key_kind, key, plaintext
+                 FROM starred_messages
+                 ORDER BY starred_at DESC, message_id
+                 LIMIT ?1 OFFSET ?2",
+            )
+            .map_err(db_error("Failed to prepare starred query"))?;
+        let rows = stmt
+            .query_map(params![limit as i64, offset as i64], |row| {
+                Ok(StarredRow {
+                    message_id: id_column(row, 0)?,
+                    channel_id: id_column(row, 1)?,
+                    timestamp: row.get(2)?,
+                    author: optional_id_column(row, 3)?,
+                    starred_at: row.get(4)?,
+                    mode: row.get(5)?,
+                    ciphertext: row.get(6)?,
+                    key_kind: row.get(7)?,
+                    key: optional_id_column(row, 8)?,
+                    plaintext: row.get(9)?,
+                })
+            })
+            .map_err(db_error("Failed to query starred messages"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Row error"))
+    }
+
+    /// Link this device to a primary identity (replaces an earlier link to it).
+    pub fn link_primary(&self, primary_ed25519: [u8; 32], linked_at: i64) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO linked_primaries (primary_ed25519, linked_at) VALUES (?1, ?2)",
+                params![&primary_ed25519, linked_at],
+            )
+            .map_err(db_error("Failed to link primary"))?;
+        Ok(())
+    }
+
+    /// Remove a primary link. Returns false if it was not linked.
+    pub fn unlink_primary(&self, primary_ed25519: [u8; 32]) -> Result<bool, String> {
+        let n = self
+            .conn
+            .execute("DELETE FROM linked_primaries WHERE primary_ed25519 = ?1", params![&primary_ed25519])
+            .map_err(db_error("Failed to unlink primary"))?;
+        Ok(n > 0)
+    }
+
+    /// When a primary identity was linked (None if it is not).
+    pub fn get_primary_linked_at(&self, primary_ed25519: [u8; 32]) -> Result<Option<i64>, String> {
+        self.conn
+            .query_row(
+                "SELECT linked_at FROM linked_primaries WHERE primary_ed25519 = ?1",
+                params![&primary_ed25519],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(db_error("Failed to read primary link"))
+    }
+
+    /// Get the stored key history (JSON) of a user.
+    pub fn get_key_history(&self, user_id: [u8; 32]) -> Result<Option<String>, String> {
+        self.conn
+            .query_row(
+                "SELECT history FROM key_histories WHERE user_id = ?1",
+                params![&user_id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(db_error("Failed to read key history"))
+    }
+
+    /// Store a user's key history (JSON), replacing the previous one.
+    pub fn set_key_history(&self, user_id: [u8; 32], history: &str, updated_at: i64) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO key_histories (user_id, history, updated_at) VALUES (?1, ?2, ?3)",
+                params![&user_id, history, updated_at],
+            )
+            .map_err(db_error("Failed to store key history"))?;
+        Ok(())
+    }
+
+    /// List all channels of the given types with message and unread counts:
+    /// pinned first, then by custom sort order, then most recently active.
+    pub fn list_conversations(&self, channel_types: &[&str]) -> Result<Vec<ConversationRow>, String> {
+        let placeholders = vec!["?"; channel_types.len()].join(", ");
+        let sql = format!(
+            "SELECT c.channel_id, c.type, c.name, c.pinned, c.sort_order, c.ui_metadata, COUNT(m.message_id), MAX(m.timestamp),
+                    c.observe_only,
+                    (SELECT COUNT(*) FROM messages u
+                     LEFT JOIN read_markers r ON r.channel_id = u.channel_id
+                     WHERE u.channel_id = c.channel_id AND u.kind = {}
+                       AND (r.channel_id IS NULL OR u.timestamp > r.timestamp
+                            OR (u.timestamp = r.timestamp AND u.message_id > r.message_id)))
+             FROM channels c
+             LEFT JOIN messages m ON m.channel_id = c.channel_id
+             WHERE c.type IN ({})
+             GROUP BY c.channel_id
+             ORDER BY c.pinned DESC, c.sort_order IS NULL, c.sort_order,
+                      MAX(m.timestamp) IS NULL, MAX(m.timestamp) DESC",
+            MESSAGE_KIND_USER, placeholders
+        );
+        let mut stmt = self
+            .conn
+            .prepare(&sql)
+            .map_err(db_error("Failed to prepare conversation query"))?;
+
+        let rows = stmt
+            .query_map(rusqlite::params_from_iter(channel_types), |row| {
+                Ok(ConversationRow {
+                    channel_id: id_column(row, 0)?,
+                    channel_type: row.get(1)?,
+                    name: row.get(2)?,
+                    pinned: row.get(3)?,
+                    sort_order: row.get(4)?,
+                    ui_metadata: row.get(5)?,
+                    message_count: row.get::<_, i64>(6)? as u64,
+                    last_message_at: row.get(7)?,
+                    observe_only: row.get(8)?,
+                    unread_count: row.get::<_, i64>(9)? as u64,
+                })
+            })
+            .map_err(db_error("Failed to query conversations"))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Conversation row error"))
+    }
+
+    /// Every table with its columns and row count (the layout a database was
+    /// migrated to, without its contents).
+    pub fn table_summaries(&self) -> Result<Vec<TableSummary>, String> {
+        let map_err = |e: rusqlite::Error| format!("Failed to read database layout: {}", e);
+        let names: Vec<String> = self
+            .conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name")
+            .and_then(|mut stmt| stmt.query_map([], |row| row.get(0))?.collect())
+            .map_err(map_err)?;
+        names
+            .into_iter()
+            .map(|name| {
+                let columns = self
+                    .conn
+                    .prepare("SELECT name FROM pragma_table_info(?1) ORDER BY cid")
+                    .and_then(|mut stmt| stmt.query_map(params![&name], |row| row.get(0))?.collect())
+                    .map_err(map_err)?;
+                let rows: i64 = self
+                    .conn
+                    .query_row(&format!("SELECT COUNT(*) FROM \"{}\"", name.replace('"', "\"\"")), [], |row| row.get(0))
+                    .map_err(map_err)?;
+                Ok(TableSummary { name, columns, rows: rows as u64 })
+            })
+            .collect()
+    }
+
+    /// Every friend.
+    pub fn list_friends(&self) -> Result<Vec<crate::friends::Friend>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT user_id, ed25519_public, nickname, notes, tags, custom_display_name, x25519_public, verified_at
+                 FROM friends ORDER BY nickname",
+            )
+            .map_err(db_error("Failed to prepare friends query"))?;
+        let rows = stmt
+            .query_map([], |row| {
+                let tags: String = row.get(4)?;
+                let x25519_public: Option<Vec<u8>> = row.get(6)?;
+                Ok(crate::friends::Friend {
+                    user_id: id_column(row, 0)?,
+                    ed25519_public: id_column(row, 1)?,
+                    nickname: row.get(2)?,
+                    notes: row.get(3)?,
+                    tags: serde_json::from_str(&tags).unwrap_or_default(),
+                    custom_display_name: row.get(5)?,
+                    x25519_public: x25519_public.and_then(|k| k.try_into().ok()),
+                    verified_at: row.get(7)?,
+                })
+            })
+            .map_err(db_error("Failed to list friends"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Failed to read friend row"))
+    }
+
+    /// Insert or replace a friend.
+    pub fn put_friend(&self, friend: &crate::friends::Friend) -> Result<(), String> {
+        let tags = serde_json::to_string(&friend.tags).map_err(|e| format!("Failed to serialize tags: {}", e))?;
+        self.conn
+            .execute(
+                "INSERT INTO friends (user_id, ed25519_public, nickname, notes, tags, custom_display_name, x25519_public, verified_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                 ON CONFLICT(user_id) DO UPDATE SET ed25519_public = excluded.ed25519_public, nickname = excluded.nickname,
+                   notes = excluded.notes, tags = excluded.tags, custom_display_name = excluded.custom_display_name,
+                   x25519_public = excluded.x25519_public, verified_at = excluded.verified_at",
+                params![
+                    &friend.user_id,
+                    &friend.ed25519_public,
+                    &friend.nickname,
+                    &friend.notes,
+                    tags,
+                    &friend.custom_display_name,
+                    friend.x25519_public.as_ref(),
+                    friend.verified_at
+                ],
+            )
+            .map_err(db_error("Failed to write friend"))?;
+        Ok(())
+    }
+
+    /// Delete a friend. Returns false if there was none.
+    pub fn delete_friend(&self, user_id: [u8; 32]) -> Result<bool, String> {
+        let n = self
+            .conn
+            .execute("DELETE FROM friends WHERE user_id = ?1", params![&user_id])
+            .map_err(db_error("Failed to delete friend"))?;
+        Ok(n > 0)
+    }
+
+    /// Announce a public channel (replacing its geohash, topic and name).
+    pub fn set_announced_channel(&self, row: &AnnouncedChannelRow) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT INTO announced_channels (channel_id, geohash, topic, name, last_sent_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT(channel_id) DO UPDATE SET geohash = excluded.geohash, topic = excluded.topic,
+                   name = excluded.name",
+                params![&row.channel_id, &row.geohash, &row.topic, &row.name, row.last_sent_at],
+            )
+            .map_err(db_error("Failed to announce channel"))?;
+        Ok(())
+    }
+
+    /// Stop announcing a channel. Returns false if it was not announced.
+    pub fn remove_announced_channel(&self, channel_id: [u8; 32]) -> Result<bool, String> {
+        let n = self
+            .conn
+            .execute("DELETE FROM announced_channels WHERE channel_id = ?1", params![&channel_id])
+            .map_err(db_error("Failed to stop announcing channel"))?;
+        Ok(n > 0)
+    }
+
+    /// Announced channels we are still registered in and not observing.
+    pub fn list_announced_channels(&self) -> Result<Vec<AnnouncedChannelRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT a.channel_id, a.geohash, a.topic, a.name, a.last_sent_at
+                 FROM announced_channels a JOIN channels c ON c.channel_id = a.channel_id
+                 WHERE COALESCE(c.observe_only, 0) = 0
+                 ORDER BY a.channel_id",
+            )
+            .map_err(db_error("Failed to prepare announced channel query"))?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok(AnnouncedChannelRow {
+                    channel_id: id_column(row, 0)?,
+                    geohash: row.get(1)?,
+                    topic: row.get(2)?,
+                    name: row.get(3)?,
+                    last_sent_at: row.get(4)?,
+                })
+            })
+            .map_err(db_error("Failed to list announced channels"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Announced channel row error"))
+    }
+
+    /// Record that a channel was just announced.
+    pub fn mark_channel_announced(&self, channel_id: [u8; 32], now: i64) -> Result<(), String> {
+        self.conn
+            .execute(
+                "UPDATE announced_channels SET last_sent_at = ?2 WHERE channel_id = ?1",
+                params![&channel_id, now],
+            )
+            .map_err(db_error("Failed to record channel announcement"))?;
+        Ok(())
+    }
+
+    /// Insert or replace a key's announcement of a channel.
+    pub fn put_directory_entry(&self, row: &DirectoryRow) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO channel_directory
+                 (channel_id, signer, geohash, topic, name, activity, announced_at, received_at, spam_score)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    &row.channel_id,
+                    &row.signer,
+                    &row.geohash,
+                    &row.topic,
+                    &row.name,
+                    row.activity,
+                    row.announced_at,
+                    row.received_at,
+                    row.spam_score
+                ],
+            )
+            .map_err(db_error("Failed to store channel announcement"))?;
+        Ok(())
+    }
+
+    /// When a key's announcement of a channel last arrived.
+    pub fn directory_received_at(&self, channel_id: [u8; 32], signer: [u8; 32]) -> Result<Option<i64>, String> {
+        self.conn
+            .query_row(
+                "SELECT received_at FROM channel_directory WHERE channel_id = ?1 AND signer = ?2",
+                params![&channel_id, &signer],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(db_error("Failed to read channel announcement"))
+    }
+
+    /// Other channels a key has announced since `since`.
+    pub fn count_signer_channels(&self, signer: [u8; 32], except: [u8; 32], since: i64) -> Result<u32, String> {
+        self.conn
+            .query_row(
+                "SELECT COUNT(*) FROM channel_directory WHERE signer = ?1 AND channel_id != ?2 AND received_at >= ?3",
+                params![&signer, &except, since],
+                |row| row.get(0),
+            )
+            .map_err(db_error("Failed to count announced channels"))
+    }
+
+    /// Announcements received since `since` of channels whose geohash starts with `prefix`.
+    pub fn list_directory(&self, prefix: &str, since: i64) -> Result<Vec<DirectoryRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT channel_id, signer, geohash, topic, name, activity, announced_at, received_at, spam_score
+                 FROM channel_directory
+                 WHERE substr(geohash, 1, length(?1)) = ?1 AND received_at >= ?2
+                 ORDER BY channel_id, announced_at DESC",
+            )
+            .map_err(db_error("Failed to prepare channel directory query"))?;
+        let rows = stmt
+            .query_map(params![prefix, since], |row| {
+                Ok(DirectoryRow {
+                    channel_id: id_column(row, 0)?,
+                    signer: id_column(row, 1)?,
+                    geohash: row.get(2)?,
+                    topic: row.get(3)?,
+                    name: row.get(4)?,
+                    activity: row.get(5)?,
+                    announced_at: row.get(6)?,
+                    received_at: row.get(7)?,
+                    spam_score: row.get(8)?,
+                })
+            })
+            .map_err(db_error("Failed to query channel directory"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Channel directory row error"))
+    }
+
+    /// Forget announcements received before `before`, then the oldest beyond
+    /// `max_entries`. Returns how many were deleted.
+    pub fn prune_directory(&self, before: i64, max_entries: u32) -> Result<usize, String> {
+        let expired = self
+            .conn
+            .execute("DELETE FROM channel_directory WHERE received_at < ?1", params![before])
+            .map_err(db_error("Failed to prune channel directory"))?;
+        let excess = self
+            .conn
+            .execute(
+                "DELETE FROM channel_directory WHERE rowid IN (
+                    SELECT rowid FROM channel_directory ORDER BY received_at DESC LIMIT -1 OFFSET ?1
+                 )",
+                params![max_entries],
+            )
+            .map_err(db_error("Failed to prune channel directory"))?;
+        Ok(expired + excess)
+    }
+
+    /// Follow a key (replacing its label).
+    pub fn put_watched_contact(&self, row: &WatchedContactRow) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT INTO watched_contacts (ed25519_public, label, added_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(ed25519_public) DO UPDATE SET label = excluded.label",
+                params![&row.ed25519_public, &row.label, row.added_at],
+            )
+            .map_err(db_error("Failed to watch contact"))?;
+        Ok(())
+    }
+
+    /// Stop following a key. Returns false if it was not followed.
+    pub fn delete_watched_contact(&self, ed25519_public: [u8; 32]) -> Result<bool, String> {
+        let n = self
+            .conn
+            .execute("DELETE FROM watched_contacts WHERE ed25519_public = ?1", params![&ed25519_public])
+            .map_err(db_error("Failed to unwatch contact"))?;
+        Ok(n > 0)
+    }
+
+    /// A followed key.
+    pub fn get_watched_contact(&self, ed25519_public: [u8; 32]) -> Result<Option<WatchedContactRow>, String> {
+        self.query_watched_contacts("WHERE ed25519_public = ?1", params![&ed25519_public])
+            .map(|rows| rows.into_iter().next())
+    }
+
+    /// Every followed key, oldest first.
+    pub fn list_watched_contacts(&self) -> Result<Vec<WatchedContactRow>, String> {
+        self.query_watched_contacts("ORDER BY added_at, ed25519_public", [])
+    }
+
+    fn query_watched_contacts(&self, filter: &str, args: impl rusqlite::Params) -> Result<Vec<WatchedContactRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!("SELECT ed25519_public, label, added_at FROM watched_contacts {}", filter))
+            .map_err(db_error("Failed to prepare watched contact query"))?;
+        let rows = stmt
+            .query_map(args, |row| {
+                Ok(WatchedContactRow { ed25519_public: id_column(row, 0)?, label: row.get(1)?, added_at: row.get(2)? })
+            })
+            .map_err(db_error("Failed to query watched contacts"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Watched contact row error"))
+    }
+
+    /// Tables backups hold: all but SQLite's own, the search index (rebuilt
+    /// from message_plaintexts), the channel directory (refilled by
+    /// announcements) and the backup bookkeeping.
+    fn backup_tables(&self) -> Result<Vec<String>, String> {
+        self.conn
+            .prepare(
+                "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'
+                 AND name NOT LIKE 'message_search%' AND name != 'channel_directory'
+                 AND name NOT LIKE 'backup_%' ORDER BY name",
+            )
+            .and_then(|mut stmt| stmt.query_map([], |row| row.get(0))?.collect())
+            .map_err(db_error("Failed to read database layout"))
+    }
+
+    /// Log the rowid of every row inserted, updated or deleted from now on,
+    /// for incremental backups. Idempotent; covers tables added since.
+    pub fn track_changes(&self) -> Result<(), String> {
+        self.conn
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS backup_changes (
+                    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                    table_name TEXT NOT NULL,
+                    row_id INTEGER NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS idx_backup_changes_table ON backup_changes(table_name, seq);
+                CREATE TABLE IF NOT EXISTS backup_manifest (
+                    id INTEGER PRIMARY KEY CHECK (id = 0),
+                    manifest TEXT NOT NULL
+                );",
+            )
+            .map_err(db_error("Failed to create backup tables"))?;
+        for table in self.backup_tables()? {
+            let quoted = table.replace('"', "\"\"");
+            let literal = table.replace('\'', "''");
+            let log = |row: &str| format!("INSERT INTO backup_changes (table_name, row_id) VALUES ('{}', {});", literal, row);
+            self.conn
+                .execute_batch(&format!(
+                    "CREATE TRIGGER IF NOT EXISTS \"backup_{q}_insert\" AFTER INSERT ON \"{q}\" BEGIN {new} END;
+                     CREATE TRIGGER IF NOT EXISTS \"backup_{q}_update\" AFTER UPDATE ON \"{q}\" BEGIN {old} {new} END;
+                     CREATE TRIGGER IF NOT EXISTS \"backup_{q}_delete\" AFTER DELETE ON \"{q}\" BEGIN {old} END;",
+                    q = quoted,
+                    new = log("NEW.rowid"),
+                    old = log("OLD.rowid"),
+                ))
+                .map_err(db_error(&format!("Failed to track changes of {}", table)))?;
+        }
+        Ok(())
+    }
+
+    /// Seq of the last change logged (0 if none).
+    pub fn change_seq(&self) -> Result<i64, String> {
+        self.conn
+            .query_row("SELECT COALESCE(MAX(seq), 0) FROM backup_changes", [], |row| row.get(0))
+            .map_err(db_error("Failed to read change log"))
+    }
+
+    /// Every row of the backed-up tables, or with `since`, only the rows
+    /// changed after that seq (and the rowids of those deleted).
+    pub fn dump_rows(&self, since: Option<i64>) -> Result<Vec<TableRows>, String> {
+        let map_err = |e: rusqlite::Error| format!("Failed to read rows for backup: {}", e);
+        let mut tables = Vec::new();
+        for table in self.backup_tables()? {
+            let quoted = table.replace('"', "\"\"");
+            let columns: Vec<String> = self
+                .conn
+                .prepare("SELECT name FROM pragma_table_info(?1) ORDER BY cid")
+                .and_then(|mut stmt| stmt.query_map(params![&table], |row| row.get(0))?.collect())
+                .map_err(map_err)?;
+            let changed = "SELECT row_id FROM backup_changes WHERE table_name = ?1 AND seq > ?2";
+            let sql = match since {
+                None => format!("SELECT rowid, * FROM \"{}\" ORDER BY rowid", quoted),
+                Some(_) => format!("SELECT rowid, * FROM \"{}\" WHERE rowid IN ({}) ORDER BY rowid", quoted, changed),
+            };
+            let mut stmt = self.conn.prepare(&sql).map_err(map_err)?;
+            let read = |row: &rusqlite::Row| -> rusqlite::Result<(i64, Vec<rusqlite::types::Value>)> {
+                let values = (0..columns.len()).map(|i| row.get(i + 1)).collect::<rusqlite::Result<_>>()?;
+                Ok((row.get(0)?, values))
+            };
+            let rows = match since {
+                None => stmt.query_map([], read),
+                Some(seq) => stmt.query_map(params![&table, seq], read),
+            }
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+            .map_err(map_err)?;
+            let deleted = match since {
+                None => Vec::new(),
+                Some(seq) => self
+                    .conn
+                    .prepare(&format!(
+                        "SELECT DISTINCT row_id FROM backup_changes WHERE table_name = ?1 AND seq > ?2
+                         AND row_id NOT IN (SELECT rowid FROM \"{}\") ORDER BY row_id",
+                        quoted
+                    ))
+                    .and_then(|mut stmt| stmt.query_map(params![&table, seq], |row| row.get(0))?.collect())
+                    .map_err(map_err)?,
+            };
+            if since.is_none() || !rows.is_empty() || !deleted.is_empty() {
+                tables.push(TableRows { table, columns, rows, deleted });
+            }
+        }
+        Ok(tables)
+    }
+
+    /// Apply rows from a backup: a full one replaces the contents of every
+    /// table it holds, an incremental one upserts and deletes by rowid.
+    pub fn apply_rows(&self, tables: &[TableRows], full: bool) -> Result<u64, String> {
+        let known = self.backup_tables()?;
+        let mut applied = 0u64;
+        self.with_transaction(|s| {
+            let map_err = |e: rusqlite::Error| format!("Failed to restore rows: {}", e);
+            for t in tables {
+                if !known.contains(&t.table) {
+                    return Err(format!("Backup holds unknown table {}", t.table));
+                }
+                let quoted = t.table.replace('"', "\"\"");
+                if full {
+                    s.conn.execute(&format!("DELETE FROM \"{}\"", quoted), []).map_err(map_err)?;
+                }
+                let columns: Vec<String> = t.columns.iter().map(|c| format!("\"{}\"", c.replace('"', "\"\""))).collect();
+                let placeholders: Vec<String> = (1..=t.columns.len() + 1).map(|i| format!("?{}", i)).collect();
+                let mut insert = s
+                    .conn
+                    .prepare(&format!(
+                        "INSERT OR REPLACE INTO \"{}\" (rowid, {}) VALUES ({})",
+                        quoted,
+                        columns.join(", "),
+                        placeholders.join(", ")
+                    ))
+                    .map_err(map_err)?;
+                for (rowid, values) in &t.rows {
+                    if values.len() != t.columns.len() {
+                        return Err(format!("Backup row of {} has {} values for {} columns", t.table, values.len(), t.columns.len()));
+                    }
+                    let mut params: Vec<&dyn rusqlite::ToSql> = vec![rowid];
+                    params.extend(values.iter().map(|v| v as &dyn rusqlite::ToSql));
+                    insert.execute(params.as_slice()).map_err(map_err)?;
+                    applied += 1;
+                }
+                for rowid in &t.deleted {
+                    s.conn
+                        .execute(&format!("DELETE FROM \"{}\" WHERE rowid = ?1", quoted), params![rowid])
+                        .map_err(map_err)?;
+                    applied += 1;
+                }
+            }
+            Ok(())
+        })?;
+        Ok(applied)
+    }
+
+    /// Forget logged changes up to `seq` (covered by a backup).
+    pub fn prune_changes(&self, seq: i64) -> Result<(), String> {
+        self.conn
+            .execute("DELETE FROM backup_changes WHERE seq <= ?1", params![seq])
+            .map_err(db_error("Failed to prune change log"))?;
+        Ok(())
+    }
+
+    /// Manifest of the last backup made (JSON, see `backup`).
+    pub fn get_backup_manifest(&self) -> Result<Option<String>, String> {
+        self.conn
+            .query_row("SELECT manifest FROM backup_manifest WHERE id = 0", [], |row| row.get(0))
+            .optional()
+            .map_err(db_error("Failed to read backup manifest"))
+    }
+
+    /// Record the manifest of the last backup made (None: start a new chain).
+    pub fn set_backup_manifest(&self, manifest: Option<&str>) -> Result<(), String> {
+        let result = match manifest {
+            Some(m) => self.conn.execute(
+                "INSERT INTO backup_manifest (id, manifest) VALUES (0, ?1)
+                 ON CONFLICT(id) DO UPDATE SET manifest = excluded.manifest",
+                params![m],
+            ),
+            None => self.conn.execute("DELETE FROM backup_manifest", []),
+        };
+        result.map_err(db_error("Failed to write backup manifest"))?;
+        Ok(())
+    }
+
+    /// Per-day message counts, bytes stored and peers heard for a channel.
+    pub fn channel_stats(&self, channel_id: [u8; 32]) -> Result<ChannelStatsRow, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT timestamp / 86400, COUNT(*), SUM(LENGTH(ciphertext))
+                 FROM messages
+                 WHERE channel_id = ?1
+                 GROUP BY timestamp / 86400
+                 ORDER BY timestamp / 86400",
+            )
+            .map_err(db_error("Failed to prepare channel stats query"))?;
+        let days = stmt
+            .query_map(params![&channel_id], |row| {
+                Ok(DayActivity {
+                    day_start: row.get::<_, i64>(0)? * 86400,
+                    messages: row.get::<_, i64>(1)? as u64,
+                    bytes: row.get::<_, i64>(2)? as u64,
+                })
+            })
+            .map_err(db_error("Failed to query channel stats"))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(db_error("Channel stats row error"))?;
+
+        let (attachment_bytes, peers_heard) = self
+            .conn
+            .query_row(
+                "SELECT (SELECT COALESCE(SUM(size), 0) FROM attachments WHERE channel_id = ?1 AND complete = 1),
+                        (SELECT COUNT(*) FROM routing_hints WHERE channel_id = ?1)",
+                params![&channel_id],
+                |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)),
+            )
+            .map_err(db_error("Failed to query channel stats"))?;
+
+        Ok(ChannelStatsRow {
+            message_bytes: days.iter().map(|d| d.bytes).sum(),
+            days,
+            attachment_bytes,
+            peers_heard,
+        })
+    }
+
+    /// List channels by type.
+    pub fn list_channels_by_type(&self, channel_type: &str) -> Result<Vec<ChannelRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT channel_id, type
+                 FROM channels
+                 WHERE type = ?1",
+            )
+            .map_err(db_error("Failed to prepare channel query"))?;
+
+        let rows = stmt
+            .query_map(params![channel_type], |row| {
+                Ok(ChannelRow {
+                    channel_id: id_column(row, 0)?,
+                    channel_type: row.get(1)?,
+                })
+            })
+            .map_err(db_error("Failed to query channels"))?;
+
+        let mut out = Vec::new();
+        for r in rows {
+            out.push(r.map_err(db_error("Channel row error"))?);
+        }
+        Ok(out)
+    }
+
+    /// Insert attachment metadata (idempotent on attachment_id) and reference
+    /// it from `row.message_id`.
+    pub fn insert_attachment(&self, row: &AttachmentRow) -> Result<(), String> {
+        self.add_attachment_ref(row.attachment_id, row.message_id)?;
+        self.conn
+            .execute(
+                &format!(
+                    "INSERT OR IGNORE INTO attachments ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                    ATTACHMENT_COLUMNS
+                ),
+                params![
+                    &row.attachment_id,
+                    &row.message_id,
+                    &row.channel_id,
+                    row.parent_id.as_ref(),
+                    &row.mime,
+                    row.size as i64,
+                    row.chunk_count as i64,
+                    row.complete,
+                    row.created_at,
+                    row.requested,
+                    row.chunk_key.as_ref(),
+                ],
+            )
+            .map_err(db_error("Failed to insert attachment"))?;
+        Ok(())
+    }
+
+    /// Get attachment metadata by id.
+    pub fn get_attachment(&self, attachment_id: [u8; 32]) -> Result<Option<AttachmentRow>, String> {
+        self.query_attachments("WHERE attachment_id = ?1", &attachment_id)
+            .map(|mut rows| rows.pop())
+    }
+
+    /// Record that a message references an attachment blob.
+    pub fn add_attachment_ref(&self, attachment_id: [u8; 32], message_id: [u8; 32]) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR IGNORE INTO attachment_refs (attachment_id, message_id) VALUES (?1, ?2)",
+                params![&attachment_id, &message_id],
+            )
+            .map_err(db_error("Failed to add attachment ref"))?;
+        Ok(())
+    }
+
+    /// Drop references from messages that never arrived (or were deleted),
+    /// for attachments created before `cutoff`.
+    pub fn prune_dangling_attachment_refs(&self, cutoff: i64) -> Result<usize, String> {
+        self.conn
+            .execute(
+                "DELETE FROM attachment_refs
+                 WHERE message_id NOT IN (SELECT message_id FROM messages)
+                   AND attachment_id IN (SELECT attachment_id FROM attachments WHERE created_at < ?1)",
+                params![cutoff],
+            )
+            .map_err(db_error("Failed to prune attachment refs"))
+    }
+
+    /// List attachments no message references any more.
+    pub fn list_unreferenced_attachments(&self) -> Result<Vec<AttachmentRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT {} FROM attachments
+                 WHERE attachment_id NOT IN (SELECT attachment_id FROM attachment_refs)",
+                ATTACHMENT_COLUMNS
+            ))
+            .map_err(db_error("Failed to prepare attachment query"))?;
+
+        let rows = stmt
+            .query_map([], attachment_from_row)
+            .map_err(db_error("Failed to query attachments"))?;
+
+        let mut out = Vec::new();
+        for r in rows {
+            out.push(r.map_err(db_error("Attachment row error"))?);
+        }
+        Ok(out)
+    }
+
+    /// Delete attachment metadata and any partially received chunks.
+    pub fn delete_attachment(&self, attachment_id: [u8; 32]) -> Result<(), String> {
+        self.delete_attachment_chunks(attachment_id)?;
+        self.conn
+            .execute(
+                "DELETE FROM attachments WHERE attachment_id = ?1",
+                params![&attachment_id],
+            )
+            .map_err(db_error("Failed to delete attachment"))?;
+        Ok(())
+    }
+
+    /// List the (non-thumbnail) attachments referenced by a message.
+    pub fn list_message_attachments(&self, message_id: [u8; 32]) -> Result<Vec<AttachmentRow>, String> {
+        self.query_attachments(
+            "WHERE attachment_id IN (SELECT attachment_id FROM attachment_refs WHERE message_id = ?1)
+               AND parent_id IS NULL
+             ORDER BY created_at ASC",
+            &message_id,
+        )
+    }
+
+    /// Get the thumbnail of an attachment, if one is known.
+    pub fn get_thumbnail(&self, parent_id: [u8; 32]) -> Result<Option<AttachmentRow>, String> {
+        self.query_attachments("WHERE parent_id = ?1", &parent_id)
+            .map(|mut rows| rows.pop())
+    }
+
+    /// Mark an attachment blob as fully present (or not) on disk.
+    pub fn set_attachment_complete(&self, attachment_id: [u8; 32], complete: bool) -> Result<(), String> {
+        self.conn
+            .execute(
+                "UPDATE attachments SET complete = ?2 WHERE attachment_id = ?1",
+                params![&attachment_id, complete],
+            )
+            .map_err(db_error("Failed to update attachment"))?;
+        Ok(())
+    }
+
+    /// Mark an attachment's data as wanted (or no longer wanted).
+    pub fn set_attachment_requested(&self, attachment_id: [u8; 32], requested: bool) -> Result<(), String> {
+        self.conn
+            .execute(
+                "UPDATE attachments SET requested = ?2 WHERE attachment_id = ?1",
+                params![&attachment_id, requested],
+            )
+            .map_err(db_error("Failed to update attachment"))?;
+        Ok(())
+    }
+
+    /// List wanted attachments whose data has not fully arrived.
+    pub fn list_pending_attachments(&self) -> Result<Vec<AttachmentRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT {} FROM attachments WHERE requested = 1 AND complete = 0",
+                ATTACHMENT_COLUMNS
+            ))
+            .map_err(db_error("Failed to prepare attachment query"))?;
+
+        let rows = stmt
+            .query_map([], attachment_from_row)
+            .map_err(db_error("Failed to query attachments"))?;
+
+        let mut out = Vec::new();
+        for r in rows {
+            out.push(r.map_err(db_error("Attachment row error"))?);
+        }
+        Ok(out)
+    }
+
+    /// Store a received attachment chunk (idempotent on attachment_id + index).
+    pub fn store_attachment_chunk(&self, attachment_id: [u8; 32], index: u32, data: &[u8]) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT OR IGNORE INTO attachment_chunks (attachment_id, chunk_index, data)
+                 VALUES (?1, ?2, ?3)",
+                params![&attachment_id, index as i64, data],
+            )
+            .map_err(db_error("Failed to store attachment chunk"))?;
+        Ok(())
+    }
+
+    /// Count received chunks of an attachment.
+    pub fn count_attachment_chunks(&self, attachment_id: [u8; 32]) -> Result<u32, String> {
+        self.conn
+            .query_row(
+                "SELECT COUNT(*) FROM attachment_chunks WHERE attachment_id = ?1",
+                params![&attachment_id],
+                |row| row.get::<_, i64>(0),
+            )
+            .map(|n| n as u32)
+            .map_err(db_error("Failed to count attachment chunks"))
+    }
+
+    /// List indices of received chunks of an attachment.
+    pub fn list_attachment_chunk_indices(&self, attachment_id: [u8; 32]) -> Result<Vec<u32>, String> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT chunk_index FROM attachment_chunks WHERE attachment_id = ?1")
+            .map_err(db_error("Failed to prepare chunk query"))?;
+
+        let rows = stmt
+            .query_map(params![&attachment_id], |row| row.get::<_, i64>(0))
+            .map_err(db_error("Failed to query chunks"))?;
+
+        let mut out = Vec::new();
+        for r in rows {
+            out.push(r.map_err(db_error("Chunk row error"))? as u32);
+        }
+        Ok(out)
+    }
+
+    /// Visit received chunks of an attachment ordered by index, one row at
+    /// a time (the chunks of a large attachment never sit in memory together).
+    pub fn for_each_attachment_chunk(
+        &self,
+        attachment_id: [u8; 32],
+        mut f: impl FnMut(&[u8]) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT data FROM attachment_chunks
+                 WHERE attachment_id = ?1
+                 ORDER BY chunk_index ASC",
+            )
+            .map_err(db_error("Failed to prepare chunk query"))?;
+
+        let mut rows = stmt
+            .query(params![&attachment_id])
+            .map_err(db_error("Failed to query chunks"))?;
+
+        while let Some(row) = rows.next().map_err(db_error("Chunk row error"))? {
+            let data = row
+                .get_ref(0)
+                .and_then(|v| v.as_blob().map_err(Into::into))
+                .map_err(db_error("Chunk row error"))?;
+            f(data)?;
+        }
+        Ok(())
+    }
+
+    /// Delete received chunks of an attachment (after assembly or on failure).
+    pub fn delete_attachment_chunks(&self, attachment_id: [u8; 32]) -> Result<usize, String> {
+        self.conn
+            .execute(
+                "DELETE FROM attachment_chunks WHERE attachment_id = ?1",
+                params![&attachment_id],
+            )
+            .map_err(db_error("Failed to delete attachment chunks"))
+    }
+
+    fn query_attachments(&self, clause: &str, id: &[u8; 32]) -> Result<Vec<AttachmentRow>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!("SELECT {} FROM attachments {}", ATTACHMENT_COLUMNS, clause))
+            .map_err(db_error("Failed to prepare attachment query"))?;
+
+        let rows = stmt
+            .query_map(params![id], attachment_from_row)
+            .map_err(db_error("Failed to query attachments"))?;
+
+        let mut out = Vec::new();
+        for r in rows {
+            out.push(r.map_err(db_error("Attachment row error"))?);
+        }
+        Ok(out)
+    }
+}
+
+fn attachment_from_row(row: &rusqlite::Row) -> rusqlite::Result<AttachmentRow> {
+    Ok(AttachmentRow {
+        attachment_id: id_column(row, 0)?,
+        message_id: id_column(row, 1)?,
+        channel_id: id_column(row, 2)?,
+        parent_id: optional_id_column(row, 3)?,
+        mime: row.get(4)?,
+        size: row.get::<_, i64>(5)? as u64,
+        chunk_count: row.get::<_, i64>(6)? as u32,
+        complete: row.get(7)?,
+        created_at: row.get(8)?,
+        requested: row.get(9)?,
+        chunk_key: optional_id_column(row, 10)?,
+    })
+}
+
+/// Delete a message with its attachment refs, receipts, plaintext and key.
+fn delete_message_rows(conn: &Connection, message_id: &[u8; 32]) -> Result<(), String> {
+    conn.execute("DELETE FROM attachment_refs WHERE message_id = ?1", params![message_id])
+        .map_err(db_error("Failed to delete attachment refs"))?;
+    conn.execute("DELETE FROM message_receipts WHERE message_id = ?1", params![message_id])
+        .map_err(db_error("Failed to delete message receipts"))?;
+    conn.execute("DELETE FROM send_states WHERE message_id = ?1", params![message_id])
+        .map_err(db_error("Failed to delete send states"))?;
+    conn.execute("DELETE FROM message_search WHERE message_id = ?1", params![message_id])
+        .map_err(db_error("Failed to delete message search entry"))?;
+    conn.execute("DELETE FROM message_plaintexts WHERE message_id = ?1", params![message_id])
+        .map_err(db_error("Failed to delete message plaintexts"))?;
+    conn.execute("DELETE FROM dm_message_keys WHERE message_id = ?1", params![message_id])
+        .map_err(db_error("Failed to delete DM message keys"))?;
+    conn.execute("DELETE FROM outbox WHERE packet_id = ?1", params![message_id])
+        .map_err(db_error("Failed to delete outbox packet"))?;
+    conn.execute("DELETE FROM messages WHERE message_id = ?1", params![message_id])
+        .map_err(db_error("Failed to delete messages"))?;
+    Ok(())
+}
+
+fn advance_read_marker(conn: &Connection, channel_id: [u8; 32], timestamp: i64, message_id: [u8; 32], now: i64) -> Result<bool, String> {
+    conn.execute(
+        "INSERT INTO read_markers (channel_id, timestamp, message_id, updated_at) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(channel_id) DO UPDATE SET
+             timestamp = excluded.timestamp, message_id = excluded.message_id, updated_at = excluded.updated_at
+         WHERE excluded.timestamp > read_markers.timestamp
+            OR (excluded.timestamp = read_markers.timestamp AND excluded.message_id > read_markers.message_id)",
+        params![&channel_id, timestamp, &message_id, now],
+    )
+    .map(|n| n > 0)
+    .map_err(db_error("Failed to advance read marker"))
+}
+
+/// Schema changes, in order: step i brings a database from version i to
+/// version i + 1. Append new steps; never edit or reorder shipped ones.
+const MIGRATIONS: &[fn(&Connection) -> rusqlite::Result<()>] = &[
+    // 1: friends move from friends.json into the database
+    |conn| {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS friends (
+                user_id BLOB PRIMARY KEY,
+                ed25519_public BLOB NOT NULL,
+                nickname TEXT NOT NULL,
+                notes TEXT NOT NULL DEFAULT '',
+                tags TEXT NOT NULL DEFAULT '[]',
+                custom_display_name TEXT,
+                x25519_public BLOB,
+                verified_at INTEGER
+            );",
+        )
+    },
+    // 2: channel announcements (see `channel_directory`)
+    |conn| {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS announced_channels (
+                channel_id BLOB PRIMARY KEY,
+                geohash TEXT NOT NULL,
+                topic TEXT NOT NULL,
+                name TEXT,
+                last_sent_at INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS channel_directory (
+                channel_id BLOB NOT NULL,
+                signer BLOB NOT NULL,
+                geohash TEXT NOT NULL,
+                topic TEXT NOT NULL,
+                name TEXT,
+                activity INTEGER NOT NULL,
+                announced_at INTEGER NOT NULL,
+                received_at INTEGER NOT NULL,
+                spam_score INTEGER NOT NULL,
+                PRIMARY KEY (channel_id, signer)
+            );
+            CREATE INDEX IF NOT EXISTS idx_channel_directory_geohash ON channel_directory(geohash);
+            CREATE INDEX IF NOT EXISTS idx_channel_directory_signer ON channel_directory(signer, received_at);",
+        )
+    },
+    // 3: watch-only contacts (see `broadcasts`)
+    |conn| {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS watched_contacts (
+                ed25519_public BLOB PRIMARY KEY,
+                label TEXT,
+                added_at INTEGER NOT NULL
+            );",
+        )
+    },
+];
+
+/// Version the current code migrates databases to
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+fn schema_version(conn: &Connection) -> Result<u32, String> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(db_error("Failed to read schema version"))
+}
+
+/// Run the migrations a database has not had yet, each in its own
+/// transaction with the version it reaches. A database from a newer version
+/// of the app is refused rather than guessed at.
+fn migrate(conn: &Connection) -> Result<(), String> {
+    let version = schema_version(conn)?;
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "Database schema version {} is newer than this app supports ({})",
+            version, SCHEMA_VERSION
+        ));
+    }
+    for (i, step) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let to = i as u32 + 1;
+        conn.execute_batch("BEGIN IMMEDIATE")
+            .map_err(db_error(&format!("Failed to begin migration {}", to)))?;
+        let applied = step(conn).and_then(|_| conn.execute_batch(&format!("PRAGMA user_version = {}", to)));
+        match applied {
+            Ok(()) => conn.execute_batch("COMMIT").map_err(db_error(&format!("Failed to commit migration {}", to)))?,
+            Err(e) => {
+                let _ = conn.execute_batch("ROLLBACK");
+                return Err(format!("Failed to migrate database to schema version {}: {}", to, e));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Add a column to an existing table if it is missing (databases created by older versions).
+fn ensure_column(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<(), String> {
+    let mut stmt = conn
+        .prepare(&format!("PRAGMA table_info({})", table))
+        .map_err(db_error(&format!("Failed to inspect table {}", table)))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))
+        .map_err(db_error(&format!("Failed to inspect table {}", table)))?
+        .filter_map(Result::ok)
+        .any(|name| name == column);
+
+    if !exists {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl), [])
+            .map_err(db_error(&format!("Failed to add column {}.{}", table, column)))?;
+    }
+    Ok(())
+}
+
+/// Read a 32-byte id column, failing (instead of panicking) on bad lengths.
+fn id_column(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<[u8; 32]> {
+    let blob: Vec<u8> = row.get(idx)?;
+    codec::id_from_slice(&blob)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, rusqlite::types::Type::Blob, e.into()))
+}
+
+fn dm_session_row(row: &rusqlite::Row) -> rusqlite::Result<DmSessionRow> {
+    Ok(DmSessionRow {
+        session_id: id_column(row, 0)?,
+        channel_id: id_column(row, 1)?,
+        outgoing: row.get(2)?,
+        remote_static: id_column(row, 3)?,
+        handshake: row.get(4)?,
+        key: id_column(row, 5)?,
+        next_counter: row.get::<_, i64>(6)? as u64,
+        created_at: row.get(7)?,
+    })
+}
+
+fn dm_ratchet_row(row: &rusqlite::Row) -> rusqlite::Result<DmRatchetRow> {
+    Ok(DmRatchetRow {
+        ratchet_id: id_column(row, 0)?,
+        channel_id: id_column(row, 1)?,
+        initiator: row.get(2)?,
+        remote_static: id_column(row, 3)?,
+        handshake: row.get(4)?,
+        root_key: id_column(row, 5)?,
+        dh_secret: id_column(row, 6)?,
+        dh_remote: optional_id_column(row, 7)?,
+        send_chain: optional_id_column(row, 8)?,
+        recv_chain: optional_id_column(row, 9)?,
+        send_count: row.get(10)?,
+        recv_count: row.get(11)?,
+        prev_send_count: row.get(12)?,
+        send_header_key: optional_id_column(row, 13)?,
+        recv_header_key: optional_id_column(row, 14)?,
+        next_send_header_key: id_column(row, 15)?,
+        next_recv_header_key: id_column(row, 16)?,
+        received_at: row.get(17)?,
+        created_at: row.get(18)?,
+    })
+}
+
+fn group_delivery_row(row: &rusqlite::Row) -> rusqlite::Result<GroupDeliveryRow> {
+    Ok(GroupDeliveryRow {
+        message_id: id_column(row, 0)?,
+        channel_id: id_column(row, 1)?,
+        sent_at: row.get(2)?,
+        attempts: row.get(3)?,
+        next_attempt: row.get(4)?,
+    })
+}
+
+/// Read a nullable 32-byte id column.
+fn optional_id_column(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<Option<[u8; 32]>> {
+    match row.get::<_, Option<Vec<u8>>>(idx)? {
+        Some(_) => id_column(row, idx).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Set once the core runs in memory: nothing may be written to disk
+static IN_MEMORY: AtomicBool = AtomicBool::new(false);
+
+/// Switch this process to in-memory mode (see `init_in_memory`): from then
+/// on there is no data directory, so nothing is read from or written to it.
+pub fn enter_in_memory_mode() {
+    IN_MEMORY.store(true, Ordering::SeqCst);
+}
+
+/// Whether the process runs in memory only.
+pub fn is_in_memory_mode() -> bool {
+    IN_MEMORY.load(Ordering::SeqCst)
+}
+
+/// Set once the process attaches read-only to a data directory another holds
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Switch this process to read-only mode (see `attach_read_only`): no data
+/// directory lock is taken, the database is opened read-only and no file is written.
+pub fn enter_read_only_mode() {
+    READ_ONLY.store(true, Ordering::SeqCst);
+}
+
+/// Whether the process is attached read-only.
+pub fn is_read_only_mode() -> bool {
+    READ_ONLY.load(Ordering::SeqCst)
+}
+
+/// Err if files in the data directory must not be written (attached read-only).
+pub fn ensure_writable() -> Result<(), String> {
+    if is_read_only_mode() {
+        return Err(MeshError::InUse.raise("Attached read-only: the data directory is not written"));
+    }
+    Ok(())
+}
+
+/// Raise a database error as "<context>: <error>" with the code SQLite
+/// failed with: busy or locked is StorageBusy (retry), a read-only database
+/// InUse (attached read-only), anything else Storage.
+pub(crate) fn db_error(context: &str) -> impl Fn(rusqlite::Error) -> String + Copy + '_ {
+    move |e| {
+        let code = match e.sqlite_error_code() {
+            Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked) => MeshError::StorageBusy,
+            Some(rusqlite::ErrorCode::ReadOnly) => MeshError::InUse,
+            _ => MeshError::Storage,
+        };
+        code.raise(format!("{}: {}", context, e))
+    }
+}
+
+/// Data directory chosen by the host app (see `set_root_dir`)
+static ROOT_DIR: std::sync::Mutex<Option<PathBuf>> = std::sync::Mutex::new(None);
+
+/// Keep all files under `dir` instead of the platform default (mobile apps
+/// must use their sandbox's documents directory).
+pub fn set_root_dir(dir: PathBuf) -> Result<(), String> {
+    if !dir.is_absolute() {
+        return Err(MeshError::InvalidArgument.raise("Data directory must be an absolute path"));
+    }
+    std::fs::create_dir_all(&dir).map_err(|e| format!("Failed to create data directory: {}", e))?;
+    *ROOT_DIR.lock().unwrap() = Some(dir);
+    Ok(())
+}
+
+/// Get the root data directory, holding the identities index (see `identities`).
+pub fn root_dir() -> Result<PathBuf, String> {
+    if is_in_memory_mode() {
+        return Err("Running in memory: there is no data directory".to_string());
+    }
+    if let Some(dir) = ROOT_DIR.lock().unwrap().as_ref() {
+        return Ok(dir.clone());
+    }
+    let data_dir = dirs::data_local_dir().ok_or("Failed to get data directory")?;
+    Ok(data_dir.join("meshapp"))
+}
+
+/// Get the active identity's data directory, shared by identity, friends and storage.
+pub fn data_dir() -> Result<PathBuf, String> {
+    crate::identities::active_dir(&root_dir()?)
+}
+
+/// Get the storage path for the SQLite database.
+pub fn db_path() -> Result<PathBuf, String> {
+    Ok(data_dir()?.join("mesh.db"))
+}
+
+/// Raw key literal for SQLCipher (no passphrase derivation)
+fn sqlcipher_key(key: &[u8; 32]) -> String {
+    format!("x'{}'", hex::encode(key))
+}
+
+/// Whether `path` is an unencrypted SQLite database (SQLCipher files have no
+/// readable header).
+fn is_plain_database(path: &PathBuf) -> bool {
+    let mut header = [0u8; 16];
+    std::fs::File::open(path)
+        .and_then(|mut f| std::io::Read::read_exact(&mut f, &mut header))
+        .is_ok_and(|_| &header == b"SQLite format 3\0")
+}
+
+/// Encrypt an unencrypted database in place: export it into an encrypted
+/// copy, then rename the copy over it, so an interruption leaves the plain
+/// database intact and the migration runs again on the next open.
+fn encrypt_database(db_path: &PathBuf, key: &[u8; 32]) -> Result<(), String> {
+    let encrypted_path = db_path.with_extension("db.encrypting");
+    let _ = std::fs::remove_file(&encrypted_path);
+    let map_err = db_error("Failed to encrypt database");
+
+    let conn = Connection::open(db_path).map_err(map_err)?;
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(())).map_err(map_err)?;
+    let user_version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0)).map_err(map_err)?;
+    conn.execute(
+        "ATTACH DATABASE ?1 AS encrypted KEY ?2",
+        params![encrypted_path.to_string_lossy(), sqlcipher_key(key)],
+    )
+    .map_err(map_err)?;
+    conn.query_row("SELECT sqlcipher_export('encrypted')", [], |_| Ok(())).map_err(map_err)?;
+    conn.execute_batch(&format!("PRAGMA encrypted.user_version = {}; DETACH DATABASE encrypted;", user_version))
+        .map_err(map_err)?;
+    drop(conn);
+
+    for suffix in ["-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", db_path.display(), suffix));
+    }
+    std::fs::rename(&encrypted_path, db_path).map_err(|e| format!("Failed to replace database: {}", e))
+}
+
+
+#[cfg(all(test, feature = "sqlcipher"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_database_is_encrypted_in_place() {
+        let path = std::env::temp_dir().join(format!("meshapp-sqlcipher-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let storage = Storage::init(&path).unwrap();
+        storage.upsert_channel([1u8; 32], "geo").unwrap();
+        drop(storage);
+        assert!(is_plain_database(&path));
+
+        let storage = Storage::init_with_key(&path, &[7u8; 32]).unwrap();
+        assert_eq!(storage.get_channel_type([1u8; 32]).unwrap().as_deref(), Some("geo"));
+        drop(storage);
+        assert!(!is_plain_database(&path));
+        assert!(Storage::init_with_key(&path, &[8u8; 32]).is_err());
+        assert!(Storage::init_with_key(&path, &[7u8; 32]).is_ok());
+
+        let storage = Storage::init_with_key(&path, &[7u8; 32]).unwrap();
+        storage.rekey(&[9u8; 32]).unwrap();
+        drop(storage);
+        assert!(!Storage::opens_with(&path, &[7u8; 32]));
+        assert!(Storage::opens_with(&path, &[9u8; 32]));
+        let storage = Storage::init_with_key(&path, &[9u8; 32]).unwrap();
+        assert_eq!(storage.get_channel_type([1u8; 32]).unwrap().as_deref(), Some("geo"));
+        drop(storage);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,220 @@
+//! Channel sync (message inventory exchange)
+//!
+//! Gossip only reaches peers that are around when a message is sent. When two
+//! peers meet, each can tell the other what it already holds of a channel
+//! with a `SyncInventory` packet: a Bloom filter of the ids of its messages
+//! since `since`. The receiver re-sends, as-is and with TTL 0, every message
+//! of its own from that window the filter does not contain, and answers with
+//! its own inventory if asked (`reply`), so one exchange fills both sides.
+//!
+//! Inventory payloads are JSON { since, salt, bits, hashes, filter, reply }
+//! with hex fields. Each inventory hashes ids with a fresh salt, so a message
+//! hidden by a false positive once gets through on the next exchange.
+//!
+//! Only channels whose history may be replayed (see `replay`) are synced, and
+//! within the replay budget; a channel answers at most once every
+//! `MIN_ANSWER_INTERVAL_SECS`.
+
+use crate::error::MeshError;
+use crate::priority::Priority;
+use crate::replay::{self, ReplayBudget};
+use crate::storage::Storage;
+use crate::transport::{Packet, PacketKind, Router};
+use once_cell::sync::Lazy;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Most message ids in one inventory (the newest win)
+pub const MAX_INVENTORY: u32 = 1024;
+
+/// Filter bits per id (about 1% false positives with `HASHES`)
+const BITS_PER_ID: usize = 10;
+
+/// Bit positions per id
+const HASHES: u8 = 7;
+
+/// Largest filter accepted from a peer (bits)
+const MAX_FILTER_BITS: usize = MAX_INVENTORY as usize * BITS_PER_ID;
+
+/// Shortest time between two answers for one channel
+pub const MIN_ANSWER_INTERVAL_SECS: i64 = 10;
+
+/// Last answer time per channel
+static LAST_ANSWER: Lazy<Mutex<HashMap<[u8; 32], i64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Inventory {
+    /// Oldest message timestamp the filter covers
+    pub since: i64,
+    pub salt: String,
+    pub bits: u32,
+    pub hashes: u8,
+    pub filter: String,
+    /// Whether the receiver should answer with its own inventory
+    pub reply: bool,
+}
+
+/// A Bloom filter over message ids
+struct IdFilter {
+    salt: [u8; 16],
+    bits: usize,
+    hashes: u8,
+    filter: Vec<u8>,
+}
+
+impl IdFilter {
+    fn new(count: usize) -> Self {
+        let bits = (count * BITS_PER_ID).max(64);
+        let mut salt = [0u8; 16];
+        crate::rng::rng().fill_bytes(&mut salt);
+        Self { salt, bits, hashes: HASHES, filter: vec![0; bits.div_ceil(8)] }
+    }
+
+    fn positions(&self, message_id: &[u8; 32]) -> impl Iterator<Item = usize> {
+        let digest = Sha256::new().chain_update(self.salt).chain_update(message_id).finalize();
+        let h1 = u64::from_be_bytes(digest[..8].try_into().unwrap());
+        let h2 = u64::from_be_bytes(digest[8..16].try_into().unwrap());
+        let bits = self.bits as u64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+
+    fn insert(&mut self, message_id: &[u8; 32]) {
+        for bit in self.positions(message_id).collect::<Vec<_>>() {
+            self.filter[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    fn contains(&self, message_id: &[u8; 32]) -> bool {
+        self.positions(message_id).all(|bit| self.filter[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    fn from_inventory(inventory: &Inventory) -> Result<Self, String> {
+        let bits = inventory.bits as usize;
+        if bits == 0 || bits > MAX_FILTER_BITS || inventory.hashes == 0 || inventory.hashes > 2 * HASHES {
+            return Err(MeshError::InvalidArgument.raise("Inventory filter out of range"));
+        }
+        let filter = hex::decode(&inventory.filter).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid inventory filter: {}", e)))?;
+        if filter.len() != bits.div_ceil(8) {
+            return Err("Inventory filter length does not match its bits".to_string());
+        }
+        let salt = hex::decode(&inventory.salt)
+            .ok()
+            .and_then(|s| <[u8; 16]>::try_from(s).ok())
+            .ok_or_else(|| MeshError::InvalidArgument.raise("Invalid inventory salt"))?;
+        Ok(Self { salt, bits, hashes: inventory.hashes, filter })
+    }
+}
+
+/// Our inventory of a channel's messages within the replay budget.
+pub fn inventory(storage: &Storage, channel_id: [u8; 32], reply: bool, now: i64) -> Result<Inventory, String> {
+    let budget = ReplayBudget::load(storage)?;
+    let since = now.saturating_sub(budget.max_age_secs.min(i64::MAX as u64) as i64);
+    let rows = storage.fetch_messages_since(channel_id, since, MAX_INVENTORY)?;
+    let mut filter = IdFilter::new(rows.len());
+    for row in &rows {
+        filter.insert(&row.message_id);
+    }
+    Ok(Inventory {
+        since,
+        salt: hex::encode(filter.salt),
+        bits: filter.bits as u32,
+        hashes: filter.hashes,
+        filter: hex::encode(&filter.filter),
+        reply,
+    })
+}
+
+/// A `SyncInventory` packet for a channel (TTL 0: neighbours only).
+pub fn inventory_packet(storage: &Storage, channel_id: [u8; 32], reply: bool, now: i64) -> Result<Option<Packet>, String> {
+    if !replay::is_replayable(storage, channel_id)? {
+        return Ok(None);
+    }
+    let payload = serde_json::to_vec(&inventory(storage, channel_id, reply, now)?)
+        .map_err(|e| format!("Failed to serialize inventory: {}", e))?;
+    Ok(Some(Packet {
+        packet_id: Router::generate_packet_id(),
+        channel_id,
+        kind: PacketKind::SyncInventory,
+        ttl: 0,
+        payload,
+        priority: Priority::Normal,
+    }))
+}
+
+/// What answering an inventory sends
+#[derive(Debug, Default)]
+pub struct SyncAnswer {
+    /// Stored messages the peer lacks, to send as-is (they bypass dedup)
+    pub messages: Vec<Packet>,
+    /// Our own inventory, if the peer asked for it
+    pub inventory: Option<Packet>,
+}
+
+/// Answer a peer's inventory. Nothing is sent for channels we do not
+/// replay, and at most once per `MIN_ANSWER_INTERVAL_SECS` per channel.
+pub fn handle_inventory(storage: &Storage, packet: &Packet, now: i64) -> Result<SyncAnswer, String> {
+    let inventory: Inventory =
+        serde_json::from_slice(&packet.payload).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid inventory: {}", e)))?;
+    let filter = IdFilter::from_inventory(&inventory)?;
+    if !replay::is_replayable(storage, packet.channel_id)? {
+        return Ok(SyncAnswer::default());
+    }
+    {
+        let mut last = LAST_ANSWER.lock().unwrap();
+        if last.get(&packet.channel_id).is_some_and(|&at| now.saturating_sub(at) < MIN_ANSWER_INTERVAL_SECS) {
+            return Ok(SyncAnswer::default());
+        }
+        last.insert(packet.channel_id, now);
+    }
+
+    // The peer's window, within our replay budget
+    let mut budget = ReplayBudget::load(storage)?;
+    let window = now.saturating_sub(inventory.since).max(0) as u64;
+    budget.max_age_secs = budget.max_age_secs.min(window);
+    let missing = replay::history_packets(storage, packet.channel_id, budget, now)?
+        .into_iter()
+        .filter(|p| !filter.contains(&p.packet_id))
+        .collect();
+    let inventory = match inventory.reply {
+        true => inventory_packet(storage, packet.channel_id, false, now)?,
+        false => None,
+    };
+    Ok(SyncAnswer { messages: missing, inventory })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_exchange_fills_both_sides() {
+        let (a, b) = (Storage::in_memory().unwrap(), Storage::in_memory().unwrap());
+        let channel = [6u8; 32];
+        for storage in [&a, &b] {
+            storage.upsert_channel(channel, "geo").unwrap();
+        }
+        for i in 1..=3u8 {
+            a.store_message([i; 32], channel, vec![i], 1_000 + i as i64, 4).unwrap();
+            b.store_message([i + 1; 32], channel, vec![i + 1], 1_001 + i as i64, 4).unwrap();
+        }
+
+        let offer = inventory_packet(&a, channel, true, 1_100).unwrap().unwrap();
+        let answer = handle_inventory(&b, &offer, 1_100).unwrap();
+        assert_eq!(answer.messages.iter().map(|p| p.packet_id).collect::<Vec<_>>(), vec![[4u8; 32]]);
+        assert!(answer.messages.iter().all(|p| p.ttl == 0 && p.kind == PacketKind::Message));
+
+        let reply = answer.inventory.unwrap();
+        let back = handle_inventory(&a, &reply, 1_200).unwrap();
+        assert_eq!(back.messages.iter().map(|p| p.packet_id).collect::<Vec<_>>(), vec![[1u8; 32]]);
+        assert!(back.inventory.is_none());
+        // Answers for a channel are rate limited
+        assert!(handle_inventory(&a, &reply, 1_201).unwrap().messages.is_empty());
+
+        // Private channels are not synced
+        a.upsert_channel([7u8; 32], "dm").unwrap();
+        assert!(inventory_packet(&a, [7u8; 32], true, 1_300).unwrap().is_none());
+    }
+}
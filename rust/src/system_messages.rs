@@ -0,0 +1,70 @@
+//! System messages
+//!
+//! Status events (friend added, key changed, member joined, expiry changed)
+//! are stored in the channel they concern as messages of kind
+//! `MESSAGE_KIND_SYSTEM`, so the UI shows them in order with the
+//! conversation. They are generated locally and never routed; the body is
+//! the event as JSON, e.g. `{"event":"friend_added","user_id":"..."}`.
+
+use crate::error::MeshError;
+use crate::events;
+use crate::storage::Storage;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// A status event shown in conversation history
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+#[allow(dead_code)] // KeyChanged / ExpiryChanged are recorded once friend key updates and channel expiry exist
+pub enum SystemEvent {
+    /// A friend was added (recorded in their DM channel)
+    FriendAdded { user_id: String },
+    /// A friend's key changed
+    KeyChanged { user_id: String },
+    /// Someone joined the channel (`user_id` is ours when we joined via invite)
+    MemberJoined { user_id: String },
+    /// The channel's message expiry changed (seconds; None = off)
+    ExpiryChanged { expiry_secs: Option<u64> },
+    /// A member changed the group's name and topic (see `group_metadata`)
+    GroupMetadataChanged { user_id: String, name: Option<String>, topic: Option<String> },
+    /// A group admin (`user_id`) moderated a member or message (see `moderation`)
+    Moderation { user_id: String, action: String, target: String },
+}
+
+/// Store a system event in a channel. Returns the message id.
+/// Also emits a `system_message` event for live UIs.
+pub fn record(storage: &Storage, channel_id: [u8; 32], event: &SystemEvent, now: i64) -> Result<[u8; 32], String> {
+    let body = serde_json::to_vec(event).map_err(|e| format!("Failed to serialize system event: {}", e))?;
+    let message_id = message_id(channel_id, &body, now);
+
+    storage.store_system_message(message_id, channel_id, &body, now)?;
+    events::emit(
+        "system_message",
+        serde_json::json!({
+            "channel_id": hex::encode(channel_id),
+            "message_id": hex::encode(message_id),
+            "system": event,
+        }),
+    );
+    Ok(message_id)
+}
+
+/// Whether an event was recorded in a channel at `timestamp` already.
+pub fn is_recorded(storage: &Storage, channel_id: [u8; 32], event: &SystemEvent, timestamp: i64) -> Result<bool, String> {
+    let body = serde_json::to_vec(event).map_err(|e| format!("Failed to serialize system event: {}", e))?;
+    Ok(storage.get_message(message_id(channel_id, &body, timestamp))?.is_some())
+}
+
+fn message_id(channel_id: [u8; 32], body: &[u8], timestamp: i64) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"system");
+    hasher.update(channel_id);
+    hasher.update(timestamp.to_be_bytes());
+    hasher.update(body);
+    hasher.finalize().into()
+}
+
+/// Parse the body of a stored system message.
+pub fn parse(body: &[u8]) -> Result<SystemEvent, String> {
+    serde_json::from_slice(body).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid system message: {}", e)))
+}
@@ -2,26 +2,108 @@
 //!
 //! Transport-agnostic packet routing with TTL and deduplication.
 //! Implements:
-//! - `Packet` struct
+//! - `Packet` struct and `PacketKind`
 //! - `Transport` trait
 //! - `LoopbackTransport` for local testing
 //! - `Router` with TTL + dedup logic
 //!
+//! The dedup cache is charged to the memory budget and forgets its oldest
+//! ids once it exceeds its share (see `memory_budget`). Ids the router
+//! routes are also kept for the caller to persist (`take_unsaved`, into the
+//! `seen_packets` table) and loaded back with `mark_seen` on startup, so a
+//! restart does not store and forward the same packets again. Persisted ids
+//! are forgotten after `SEEN_PERSIST_SECS`.
+//!
+//! A packet no transport takes (none available, or every send failed) is
+//! held rather than dropped (when too many are held, the lowest-ranked one
+//! goes, see `memory_budget`); the caller moves held packets to the outbox
+//! with `take_held` and retries them later (see `outbox`). Held and dropped
+//! packets are reported as `packet_held` / `packet_dropped` events.
+//!
 //! BLE and other real transports will plug into this trait in later phases.
 
 #![allow(dead_code)] // Many items will be fully used in later phases
 
+use crate::events;
+use crate::memory_budget::{self, Pool, BUDGET, DEDUP_ENTRY_BYTES};
+use crate::priority::Priority;
 use rand::RngCore;
-use std::collections::HashSet;
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
 use std::sync::{Arc, Mutex};
 
+/// How long persisted packet ids are kept and reloaded (seconds)
+pub const SEEN_PERSIST_SECS: i64 = 3 * 24 * 60 * 60;
+
+/// Routed ids held for `take_unsaved` at most; older ones are not persisted
+const MAX_UNSAVED: usize = 4096;
+
+/// Packets held for `take_held` at most; older ones are dropped
+const MAX_HELD: usize = 256;
+
+/// What a packet's payload carries; decides how `on_new` handles it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PacketKind {
+    /// Encrypted message ciphertext (stored as a message)
+    Message = 0,
+    /// Attachment metadata announcement (JSON)
+    AttachmentManifest = 1,
+    /// One chunk of an attachment blob
+    AttachmentChunk = 2,
+    /// Ask peers holding an attachment to send its chunks
+    AttachmentRequest = 3,
+    /// Ask for a page of a channel's stored history (JSON)
+    HistoryRequest = 4,
+    /// A page of history answering a request (JSON)
+    HistoryResponse = 5,
+    /// Signed command for a linked device, e.g. remote wipe (JSON)
+    DeviceControl = 6,
+    /// A group member confirming custody of a message (see `group_delivery`)
+    DeliveryReceipt = 7,
+    /// A late joiner asking for a protected geo channel's recent keys (see `key_share`)
+    KeyShareRequest = 8,
+    /// Recent channel keys wrapped to a late joiner
+    KeyShare = 9,
+    /// A reader's delivery or read receipt for a message (see `receipts`)
+    MessageReceipt = 10,
+    /// A Bloom filter of the messages a node holds of a channel (see `sync`)
+    SyncInventory = 11,
+    /// A signed announcement of a public channel (see `channel_directory`)
+    ChannelAnnouncement = 12,
+}
+
+impl PacketKind {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(PacketKind::Message),
+            1 => Some(PacketKind::AttachmentManifest),
+            2 => Some(PacketKind::AttachmentChunk),
+            3 => Some(PacketKind::AttachmentRequest),
+            4 => Some(PacketKind::HistoryRequest),
+            5 => Some(PacketKind::HistoryResponse),
+            6 => Some(PacketKind::DeviceControl),
+            7 => Some(PacketKind::DeliveryReceipt),
+            8 => Some(PacketKind::KeyShareRequest),
+            9 => Some(PacketKind::KeyShare),
+            10 => Some(PacketKind::MessageReceipt),
+            11 => Some(PacketKind::SyncInventory),
+            12 => Some(PacketKind::ChannelAnnouncement),
+            _ => None,
+        }
+    }
+}
+
 /// Mesh packet as seen by transports and router.
 #[derive(Clone, Debug)]
 pub struct Packet {
     pub packet_id: [u8; 32],
     pub channel_id: [u8; 32],
+    pub kind: PacketKind,
     pub ttl: u8,
     pub payload: Vec<u8>, // encrypted bytes
+    /// Sender's priority mark, carried beside the payload (see `priority`)
+    pub priority: Priority,
 }
 
 /// Abstract transport (BLE, Wi‑Fi Direct, Loopback, etc.).
@@ -71,31 +153,222 @@ impl Transport for LoopbackTransport {
     }
 }
 
+/// Packet ids seen so far, with their insertion order for eviction
+#[derive(Default)]
+struct SeenCache {
+    ids: HashSet<[u8; 32]>,
+    order: VecDeque<[u8; 32]>,
+}
+
+impl SeenCache {
+    /// Remember an id. Returns false if it was already known.
+    fn insert(&mut self, id: [u8; 32]) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        BUDGET.charge(Pool::DedupCache, DEDUP_ENTRY_BYTES);
+        while BUDGET.pool_bytes(Pool::DedupCache) > BUDGET.dedup_limit() {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.ids.remove(&oldest);
+            BUDGET.release_shed(Pool::DedupCache, DEDUP_ENTRY_BYTES);
+        }
+        true
+    }
+}
+
+impl Drop for SeenCache {
+    fn drop(&mut self) {
+        BUDGET.release(Pool::DedupCache, self.order.len() * DEDUP_ENTRY_BYTES);
+    }
+}
+
+fn packet_event(kind: &str, packet: &Packet) {
+    events::emit(
+        kind,
+        serde_json::json!({
+            "packet_id": hex::encode(packet.packet_id),
+            "channel_id": hex::encode(packet.channel_id),
+            "kind": packet.kind as u8,
+        }),
+    );
+}
+
+/// A transport as reported in router stats
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TransportStatus {
+    pub name: &'static str,
+    pub available: bool,
+}
+
+/// Router state for diagnostics
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RouterStats {
+    pub transports: Vec<TransportStatus>,
+    /// Packet ids in the dedup cache
+    pub seen_ids: usize,
+    pub observed_channels: usize,
+    pub blocked_channels: usize,
+    /// Routed ids not persisted yet
+    pub unsaved_ids: usize,
+    /// Packets no transport took, not queued in the outbox yet
+    pub held_packets: usize,
+}
+
 /// Router implementing TTL and deduplication across transports.
 pub struct Router {
     transports: Vec<Arc<dyn Transport>>,
-    seen: Mutex<HashSet<[u8; 32]>>,
+    seen: Mutex<SeenCache>,
+    /// Channels in observer mode: handled locally, never sent on
+    observed: Mutex<HashSet<[u8; 32]>>,
+    /// DM channels of blocked users: dropped, neither handled nor sent on
+    blocked: Mutex<HashSet<[u8; 32]>>,
+    /// Ids routed since the last `take_unsaved`
+    unsaved: Mutex<VecDeque<[u8; 32]>>,
+    /// Packets no transport took since the last `take_held`
+    held: Mutex<VecDeque<Packet>>,
 }
 
 impl Router {
     pub fn new(transports: Vec<Arc<dyn Transport>>) -> Self {
         Self {
             transports,
-            seen: Mutex::new(HashSet::new()),
+            seen: Mutex::new(SeenCache::default()),
+            observed: Mutex::new(HashSet::new()),
+            blocked: Mutex::new(HashSet::new()),
+            unsaved: Mutex::new(VecDeque::new()),
+            held: Mutex::new(VecDeque::new()),
         }
     }
 
+    /// Replace the set of channels in observer mode.
+    pub fn set_observed_channels(&self, channel_ids: &[[u8; 32]]) {
+        *self.observed.lock().unwrap() = channel_ids.iter().copied().collect();
+    }
+
+    fn is_observed(&self, channel_id: &[u8; 32]) -> bool {
+        self.observed.lock().unwrap().contains(channel_id)
+    }
+
+    /// Replace the set of blocked DM channels.
+    pub fn set_blocked_channels(&self, channel_ids: &[[u8; 32]]) {
+        *self.blocked.lock().unwrap() = channel_ids.iter().copied().collect();
+    }
+
+    fn is_blocked(&self, channel_id: &[u8; 32]) -> bool {
+        self.blocked.lock().unwrap().contains(channel_id)
+    }
+
+    /// Whether routing this packet now would forward it: it is new, has TTL
+    /// left and its channel is neither blocked nor observed.
+    pub fn will_forward(&self, packet: &Packet) -> bool {
+        packet.ttl > 0 && !self.has_seen(&packet.packet_id) && !self.is_blocked(&packet.channel_id) && !self.is_observed(&packet.channel_id)
+    }
+
+    /// Packet ids seen so far (for relay handover snapshots).
+    pub fn seen_ids(&self) -> Vec<[u8; 32]> {
+        self.seen.lock().unwrap().order.iter().copied().collect()
+    }
+
+    /// Whether a packet id has been routed already.
+    pub fn has_seen(&self, id: &[u8; 32]) -> bool {
+        self.seen.lock().unwrap().ids.contains(id)
+    }
+
+    /// Ids routed since the last call, for the caller to persist.
+    pub fn take_unsaved(&self) -> Vec<[u8; 32]> {
+        self.unsaved.lock().unwrap().drain(..).collect()
+    }
+
+    /// Treat these packet ids as already seen (they are not persisted).
+    /// Returns how many were new.
+    pub fn mark_seen(&self, ids: &[[u8; 32]]) -> usize {
+        let mut seen = self.seen.lock().unwrap();
+        ids.iter().filter(|id| seen.insert(**id)).count()
+    }
+
+    /// Transports and cache sizes, for diagnostics.
+    pub fn stats(&self) -> RouterStats {
+        RouterStats {
+            transports: self
+                .transports
+                .iter()
+                .map(|t| TransportStatus { name: t.name(), available: t.is_available() })
+                .collect(),
+            seen_ids: self.seen.lock().unwrap().ids.len(),
+            observed_channels: self.observed.lock().unwrap().len(),
+            blocked_channels: self.blocked.lock().unwrap().len(),
+            unsaved_ids: self.unsaved.lock().unwrap().len(),
+            held_packets: self.held.lock().unwrap().len(),
+        }
+    }
+
+    /// Packets no transport took since the last call, for the caller to queue.
+    pub fn take_held(&self) -> Vec<Packet> {
+        self.held.lock().unwrap().drain(..).collect()
+    }
+
+    /// Send a packet to every available transport as-is. Returns true if
+    /// any took it (or it belongs to an observed channel and is not sent).
+    pub fn try_send(&self, packet: &Packet) -> bool {
+        if self.is_observed(&packet.channel_id) {
+            return true;
+        }
+        let mut sent = false;
+        for transport in &self.transports {
+            if transport.is_available() && transport.send(packet).is_ok() {
+                sent = true;
+            }
+        }
+        sent
+    }
+
+    /// Send a packet, holding it if no transport takes it. Emits
+    /// `packet_held`, and `packet_dropped` for a packet no room was left for.
+    fn send_or_hold(&self, packet: Packet) {
+        if self.try_send(&packet) {
+            return;
+        }
+        let mut held = self.held.lock().unwrap();
+        if held.len() >= MAX_HELD {
+            // Make room by dropping the lowest-ranked packet (this one if it ranks lowest)
+            let Some(victim) = memory_budget::lowest_ranked(&held) else {
+                return;
+            };
+            if memory_budget::packet_rank(&held[victim]) > memory_budget::packet_rank(&packet) {
+                packet_event("packet_dropped", &packet);
+                return;
+            }
+            if let Some(dropped) = held.remove(victim) {
+                packet_event("packet_dropped", &dropped);
+            }
+        }
+        packet_event("packet_held", &packet);
+        held.push_back(packet);
+    }
+
+    /// Send a packet to every available transport as-is, bypassing dedup
+    /// (replaying packets this router has already seen).
+    /// Packets of observed channels are not sent.
+    pub fn send_direct(&self, packet: &Packet) {
+        self.send_or_hold(packet.clone());
+    }
+
     /// Generate a random packet_id.
     pub fn generate_packet_id() -> [u8; 32] {
         let mut id = [0u8; 32];
-        rand::thread_rng().fill_bytes(&mut id);
+        crate::rng::rng().fill_bytes(&mut id);
         id
     }
 
     /// Route a packet:
     /// - Drops if already seen (dedup).
     /// - Calls `on_new` callback exactly once for new packets (for storage, UI, etc.).
-    /// - Forwards to all available transports while `ttl > 0`, decrementing TTL.
+    /// - Forwards to all available transports while `ttl > 0`, decrementing TTL,
+    ///   unless the packet's channel is in observer mode. Held if none takes it.
+    /// - Drops packets of blocked channels once marked seen, without `on_new`.
     pub fn route<F>(&self, mut packet: Packet, on_new: F)
     where
         F: Fn(&Packet),
@@ -107,22 +380,58 @@ impl Router {
                 return;
             }
         }
+        {
+            let mut unsaved = self.unsaved.lock().unwrap();
+            if unsaved.len() >= MAX_UNSAVED {
+                unsaved.pop_front();
+            }
+            unsaved.push_back(packet.packet_id);
+        }
+        if self.is_blocked(&packet.channel_id) {
+            return;
+        }
 
         // New packet: inform caller (e.g., store in DB).
         on_new(&packet);
 
-        if packet.ttl == 0 {
+        if packet.ttl == 0 || self.is_observed(&packet.channel_id) {
             return;
         }
 
         packet.ttl -= 1;
-        for transport in &self.transports {
-            if transport.is_available() {
-                let _ = transport.send(&packet);
-            }
-        }
+        self.send_or_hold(packet);
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+
+    #[test]
+    fn test_persisted_ids_survive_a_restart() {
+        let storage = Storage::in_memory().unwrap();
+        let packet = Packet {
+            packet_id: [1u8; 32],
+            channel_id: [2u8; 32],
+            kind: PacketKind::Message,
+            ttl: 3,
+            payload: vec![7],
+            priority: Priority::Normal,
+        };
 
+        let router = Router::new(Vec::new());
+        router.route(packet.clone(), |_| {});
+        storage.record_seen_packets(&router.take_unsaved(), 1_000).unwrap();
+        storage.record_seen_packets(&[[3u8; 32]], 10).unwrap();
+        assert!(router.take_unsaved().is_empty());
 
+        let restarted = Router::new(Vec::new());
+        assert_eq!(storage.prune_seen_packets(100).unwrap(), 1);
+        restarted.mark_seen(&storage.list_seen_packets(100, 10).unwrap());
+        let handled = std::cell::Cell::new(false);
+        restarted.route(packet, |_| handled.set(true));
+        assert!(!handled.get());
+        assert!(restarted.take_unsaved().is_empty());
+    }
+}
@@ -0,0 +1,164 @@
+//! mesh:// links
+//!
+//! QR codes and shared links use one URI format so both are validated by
+//! the same code:
+//! - `mesh://add-friend/<payload>`: a friend export (see `export_own_identity`)
+//! - `mesh://join/<payload>`: a signed channel invite (see `invites`)
+//!
+//! `<payload>` is the JSON payload encoded as unpadded base64url, which keeps
+//! links short enough for a QR code and free of characters that need escaping.
+
+use crate::error::MeshError;
+use crate::friends;
+use crate::invites::{self, Invite};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+pub const MESH_SCHEME: &str = "mesh://";
+
+/// Longest URI we accept (well above any real payload)
+const MAX_URI_LEN: usize = 4096;
+
+/// Kinds of mesh:// link
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UriKind {
+    AddFriend,
+    Join,
+}
+
+impl UriKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "add-friend" => Some(UriKind::AddFriend),
+            "join" => Some(UriKind::Join),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            UriKind::AddFriend => "add-friend",
+            UriKind::Join => "join",
+        }
+    }
+}
+
+/// Check that a payload is valid for its kind.
+/// Friend payloads must carry a user_id matching their key; invites must be
+/// correctly signed and unexpired.
+pub fn validate_payload(kind: UriKind, payload: &str, now: i64) -> Result<(), String> {
+    match kind {
+        UriKind::AddFriend => {
+            let friend = friends::parse_friend_from_json(payload)?;
+            if hex::encode(Sha256::digest(friend.ed25519_public)) != friend.user_id_hex.to_lowercase() {
+                return Err("user_id does not match Ed25519 public key".to_string());
+            }
+        }
+        UriKind::Join => {
+            let invite: Invite =
+                serde_json::from_str(payload).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid invite payload: {}", e)))?;
+            invites::verify(&invite, now)?;
+        }
+    }
+    Ok(())
+}
+
+/// Build a mesh:// URI from a JSON payload, after validating it.
+pub fn build(kind: UriKind, payload: &str, now: i64) -> Result<String, String> {
+    validate_payload(kind, payload, now)?;
+    // Re-serialize so equal payloads always produce the same link
+    let value: Value = serde_json::from_str(payload).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid payload: {}", e)))?;
+    Ok(format!(
+        "{}{}/{}",
+        MESH_SCHEME,
+        kind.name(),
+        base64url_encode(value.to_string().as_bytes())
+    ))
+}
+
+/// Parse and validate a mesh:// URI, returning its kind and JSON payload.
+pub fn parse(uri: &str, now: i64) -> Result<(UriKind, Value), String> {
+    let uri = uri.trim();
+    if uri.len() > MAX_URI_LEN {
+        return Err(MeshError::InvalidArgument.raise("URI too long"));
+    }
+    let rest = uri
+        .get(..MESH_SCHEME.len())
+        .filter(|s| s.eq_ignore_ascii_case(MESH_SCHEME))
+        .map(|_| &uri[MESH_SCHEME.len()..])
+        .ok_or_else(|| MeshError::Rejected.raise("Not a mesh:// URI"))?;
+
+    let (kind_name, encoded) = rest.split_once('/').ok_or("Missing URI payload")?;
+    let kind = UriKind::from_name(kind_name).ok_or_else(|| MeshError::InvalidArgument.raise(format!("Unknown URI kind: {}", kind_name)))?;
+    let encoded = encoded.trim_end_matches('/');
+
+    let bytes = base64url_decode(encoded)?;
+    let payload = String::from_utf8(bytes).map_err(|_| MeshError::InvalidArgument.raise("URI payload is not UTF-8"))?;
+    validate_payload(kind, &payload, now)?;
+
+    let value = serde_json::from_str(&payload).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid payload: {}", e)))?;
+    Ok((kind, value))
+}
+
+const BASE64URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+pub fn base64url_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |acc, (i, b)| acc | ((*b as u32) << (16 - 8 * i)));
+        for i in 0..=chunk.len() {
+            out.push(BASE64URL[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+        }
+    }
+    out
+}
+
+pub fn base64url_decode(text: &str) -> Result<Vec<u8>, String> {
+    let text = text.trim_end_matches('=');
+    if text.len() % 4 == 1 {
+        return Err(MeshError::InvalidArgument.raise("Invalid base64url length"));
+    }
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.as_bytes().chunks(4) {
+        let mut n = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let v = BASE64URL
+                .iter()
+                .position(|b| b == c)
+                .ok_or_else(|| MeshError::InvalidArgument.raise("Invalid base64url character"))?;
+            n |= (v as u32) << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            out.push((n >> (16 - 8 * i)) as u8);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_friend_uri_roundtrip() {
+        for data in [&b""[..], b"a", b"ab", b"abc", b"abcd"] {
+            assert_eq!(base64url_decode(&base64url_encode(data)).unwrap(), data);
+        }
+
+        let identity = crate::identity::Identity::generate();
+        let payload = serde_json::json!({
+            "user_id": hex::encode(identity.public().user_id),
+            "ed25519_public": hex::encode(identity.public().ed25519_public.as_bytes()),
+        })
+        .to_string();
+
+        let uri = build(UriKind::AddFriend, &payload, 0).unwrap();
+        assert!(uri.starts_with("mesh://add-friend/"));
+        let (kind, value) = parse(&uri, 0).unwrap();
+        assert_eq!(kind, UriKind::AddFriend);
+        assert_eq!(value["user_id"], hex::encode(identity.public().user_id));
+
+        assert!(parse("mesh://join/e30", 0).is_err());
+        assert!(parse("https://example.com/x", 0).is_err());
+    }
+}
@@ -0,0 +1,347 @@
+//! Local webhooks for relay and gateway daemons
+//!
+//! A headless node can hand a few events to local monitoring and alerting:
+//! each hook in the `daemon.webhooks` setting, [{ url, events?, secret? }],
+//! gets an HTTP POST of { event, timestamp, ... } for the events it lists
+//! (all of them if `events` is left out):
+//! - `packet_relayed` { packet, channel, kind, ttl }: we forwarded another node's packet
+//! - `channel_activity` { channel, messages }: messages arrived on a channel;
+//!   at most one post per channel per minute, counting the ones in between
+//! - `peer_joined` { peer }: a transport heard a peer that was new or had been away
+//!
+//! Events are sanitized on the way out: ids become tags (the first 16 hex
+//! digits of SHA256("meshapp-webhook" || id), stable across restarts so
+//! dashboards can group by them) and no payload, key or message id leaves
+//! the node. URLs are plain `http://`, meant for a collector on localhost or
+//! the LAN. With a `secret`, X-Meshapp-Signature is "sha256=" and the hex
+//! HMAC-SHA256 of the body under it.
+//!
+//! Delivery (feature `async`) is best effort: events wait in a bounded queue,
+//! dropped when it is full, and each hook gets one attempt of at most 5 s.
+
+use crate::error::MeshError;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Events a hook can subscribe to
+pub const EVENTS: &[&str] = &["packet_relayed", "channel_activity", "peer_joined"];
+
+/// A configured hook
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Webhook {
+    pub url: String,
+    /// Events posted to it (empty: all of them)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+/// Host, port and path of an `http://` URL.
+fn endpoint(url: &str) -> Result<(String, u16, String), String> {
+    let rest = url.strip_prefix("http://").ok_or_else(|| MeshError::InvalidArgument.raise(format!("Webhook URL must start with http://: {}", url)))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !authority.ends_with(']') => {
+            (host, port.parse::<u16>().map_err(|_| MeshError::InvalidArgument.raise(format!("Invalid webhook port: {}", port)))?)
+        }
+        _ => (authority, 80),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() || host.contains('@') || path.contains(|c: char| c.is_whitespace() || c.is_control()) {
+        return Err(MeshError::InvalidArgument.raise(format!("Invalid webhook URL: {}", url)));
+    }
+    Ok((host.to_string(), port, path.to_string()))
+}
+
+/// Parse and check the `daemon.webhooks` setting.
+pub fn parse_hooks(value: &Value) -> Result<Vec<Webhook>, String> {
+    let hooks: Vec<Webhook> = serde_json::from_value(value.clone()).map_err(|e| e.to_string())?;
+    for hook in &hooks {
+        endpoint(&hook.url)?;
+        if let Some(event) = hook.events.iter().find(|e| !EVENTS.contains(&e.as_str())) {
+            return Err(MeshError::InvalidArgument.raise(format!("Unknown webhook event: {}", event)));
+        }
+    }
+    Ok(hooks)
+}
+
+#[cfg(feature = "async")]
+pub use delivery::{offer, start, start_from_settings, stats, stop, WebhookStats};
+
+#[cfg(feature = "async")]
+mod delivery {
+    use super::{endpoint, parse_hooks, Webhook};
+    use crate::async_api::AsyncStorage;
+    use crate::events::Event;
+    use crate::settings;
+    use hmac::{Hmac, Mac};
+    use once_cell::sync::Lazy;
+    use serde::Serialize;
+    use serde_json::{json, Value};
+    use sha2::{Digest, Sha256};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::mpsc;
+    use tokio::task::JoinHandle;
+
+    /// Events waiting for delivery before new ones are dropped
+    const QUEUE_CAPACITY: usize = 256;
+    /// Longest a hook may take to connect, read the request and answer
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    /// At most one `channel_activity` post per channel in this many seconds
+    const ACTIVITY_WINDOW_SECS: i64 = 60;
+    /// Channels whose activity is tracked before the counts start over
+    const MAX_TRACKED_CHANNELS: usize = 4096;
+    /// Most response bytes read looking for the status line
+    const MAX_STATUS_LINE: usize = 1024;
+
+    /// A sanitized event ready to post
+    struct Post {
+        event: &'static str,
+        body: String,
+    }
+
+    static QUEUE: Mutex<Option<mpsc::Sender<Post>>> = Mutex::new(None);
+    /// Per channel tag: when activity was last posted, messages since
+    static ACTIVITY: Lazy<Mutex<HashMap<String, (i64, u64)>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+    static DELIVERED: AtomicU64 = AtomicU64::new(0);
+    static FAILED: AtomicU64 = AtomicU64::new(0);
+    static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+    /// Delivery counters since the process started
+    #[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct WebhookStats {
+        pub running: bool,
+        /// Posts answered with a 2xx status
+        pub delivered: u64,
+        /// Posts that failed, timed out or got another status
+        pub failed: u64,
+        /// Events dropped because the queue was full
+        pub dropped: u64,
+    }
+
+    /// Tag of a hex id from an event payload.
+    fn tag(payload: &Value, field: &str) -> Option<String> {
+        let id = hex::decode(payload[field].as_str()?).ok()?;
+        let digest = Sha256::new().chain_update(b"meshapp-webhook").chain_update(id).finalize();
+        Some(hex::encode(&digest[..8]))
+    }
+
+    /// The webhook form of a core event, if it has one.
+    fn sanitize(event: &Event) -> Option<Post> {
+        let payload = &event.payload;
+        let (name, fields) = match event.kind.as_str() {
+            "packet_relayed" => (
+                "packet_relayed",
+                json!({
+                    "packet": tag(payload, "packet_id")?,
+                    "channel": tag(payload, "channel_id")?,
+                    "kind": payload["kind"],
+                    "ttl": payload["ttl"],
+                }),
+            ),
+            "peer_joined" => ("peer_joined", json!({ "peer": tag(payload, "peer_id")? })),
+            "message_received" => {
+                let channel = tag(payload, "channel_id")?;
+                let mut activity = ACTIVITY.lock().unwrap();
+                if activity.len() >= MAX_TRACKED_CHANNELS && !activity.contains_key(&channel) {
+                    activity.clear();
+                }
+                let (last_posted, count) = activity.entry(channel.clone()).or_insert((i64::MIN, 0));
+                *count += 1;
+                if event.timestamp.saturating_sub(*last_posted) < ACTIVITY_WINDOW_SECS {
+                    return None;
+                }
+                let messages = std::mem::take(count);
+                *last_posted = event.timestamp;
+                ("channel_activity", json!({ "channel": channel, "messages": messages }))
+            }
+            _ => return None,
+        };
+        let mut body = json!({ "event": name, "timestamp": event.timestamp });
+        if let (Some(body), Value::Object(fields)) = (body.as_object_mut(), fields) {
+            body.extend(fields);
+        }
+        Some(Post { event: name, body: body.to_string() })
+    }
+
+    /// Queue an event for the hooks (called by `events::emit`). Does nothing
+    /// unless the webhooks are running.
+    pub fn offer(event: &Event) {
+        let queue = QUEUE.lock().unwrap();
+        let Some(tx) = queue.as_ref() else { return };
+        if let Some(post) = sanitize(event) {
+            if tx.try_send(post).is_err() {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Start posting events to `hooks`, replacing any hooks started before.
+    /// Must be called within the daemon's Tokio runtime; the returned task
+    /// ends after `stop`.
+    pub fn start(hooks: Vec<Webhook>) -> Result<JoinHandle<()>, String> {
+        parse_hooks(&serde_json::to_value(&hooks).map_err(|e| e.to_string())?)?;
+        let (tx, mut rx) = mpsc::channel::<Post>(QUEUE_CAPACITY);
+        let worker = tokio::spawn(async move {
+            while let Some(post) = rx.recv().await {
+                for hook in hooks.iter().filter(|h| h.events.is_empty() || h.events.iter().any(|e| e == post.event)) {
+                    match tokio::time::timeout(TIMEOUT, send(hook, &post)).await {
+                        Ok(Ok(())) => {
+                            DELIVERED.fetch_add(1, Ordering::Relaxed);
+                            continue;
+                        }
+                        Ok(Err(e)) => eprintln!("Webhook {} failed: {}", hook.url, e),
+                        Err(_) => eprintln!("Webhook {} timed out", hook.url),
+                    }
+                    FAILED.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+        *QUEUE.lock().unwrap() = Some(tx);
+        Ok(worker)
+    }
+
+    /// Start the hooks configured in the `daemon.webhooks` setting.
+    pub async fn start_from_settings(storage: &AsyncStorage) -> Result<JoinHandle<()>, String> {
+        let value = storage.call(|s| settings::get_value(s, settings::DAEMON_WEBHOOKS)).await?;
+        start(parse_hooks(&value)?)
+    }
+
+    /// Stop queueing events; the worker ends once the queued ones are posted.
+    pub fn stop() {
+        QUEUE.lock().unwrap().take();
+    }
+
+    /// Delivery counters.
+    pub fn stats() -> WebhookStats {
+        WebhookStats {
+            running: QUEUE.lock().unwrap().is_some(),
+            delivered: DELIVERED.load(Ordering::Relaxed),
+            failed: FAILED.load(Ordering::Relaxed),
+            dropped: DROPPED.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Hex HMAC-SHA256 of a post body under the hook's secret.
+    fn sign(secret: &str, body: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+        mac.update(body.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// POST one event to a hook and check for a 2xx answer.
+    async fn send(hook: &Webhook, post: &Post) -> Result<(), String> {
+        let (host, port, path) = endpoint(&hook.url)?;
+        let mut stream = TcpStream::connect((host.as_str(), port)).await.map_err(|e| format!("Failed to connect: {}", e))?;
+        let host_header = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nX-Meshapp-Event: {}\r\nConnection: close\r\n",
+            path,
+            host_header,
+            post.body.len(),
+            post.event
+        );
+        if let Some(secret) = &hook.secret {
+            request.push_str(&format!("X-Meshapp-Signature: sha256={}\r\n", sign(secret, &post.body)));
+        }
+        request.push_str("\r\n");
+        request.push_str(&post.body);
+        stream.write_all(request.as_bytes()).await.map_err(|e| format!("Failed to send: {}", e))?;
+
+        let mut response = Vec::new();
+        let mut buf = [0u8; 256];
+        while !response.windows(2).any(|w| w == b"\r\n") && response.len() < MAX_STATUS_LINE {
+            let n = stream.read(&mut buf).await.map_err(|e| format!("Failed to read response: {}", e))?;
+            if n == 0 {
+                break;
+            }
+            response.extend_from_slice(&buf[..n]);
+        }
+        let status = String::from_utf8_lossy(&response)
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or("Malformed HTTP response")?;
+        if (200..300).contains(&status) {
+            Ok(())
+        } else {
+            Err(format!("HTTP status {}", status))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_hook_settings_are_checked() {
+        let hooks = parse_hooks(&json!([{ "url": "http://127.0.0.1:9100/meshapp", "events": ["peer_joined"] }])).unwrap();
+        assert_eq!(hooks[0].events, vec!["peer_joined"]);
+        assert_eq!(endpoint("http://collector.lan").unwrap(), ("collector.lan".to_string(), 80, "/".to_string()));
+        assert_eq!(endpoint("http://[::1]:8080/x").unwrap(), ("::1".to_string(), 8080, "/x".to_string()));
+        assert!(parse_hooks(&json!([{ "url": "https://example.com/" }])).is_err());
+        assert!(parse_hooks(&json!([{ "url": "http://host:99999/" }])).is_err());
+        assert!(parse_hooks(&json!([{ "url": "http://host/", "events": ["message_received"] }])).is_err());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_sanitized_events_are_posted() {
+        use crate::events;
+        use hmac::{Hmac, Mac};
+        use sha2::{Digest, Sha256};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/hook", listener.local_addr().unwrap());
+            let hook = Webhook { url, events: vec!["peer_joined".to_string()], secret: Some("s3cret".to_string()) };
+            let worker = start(vec![hook]).unwrap();
+
+            // Other tests emit events too: wait for our peer's post
+            let peer = hex::encode([0x5au8; 32]);
+            events::emit("peer_joined", json!({ "peer_id": peer }));
+            events::emit("message_received", json!({ "message_id": hex::encode([1u8; 32]), "channel_id": hex::encode([2u8; 32]) }));
+            let tag = hex::encode(&Sha256::digest([b"meshapp-webhook".as_slice(), &[0x5au8; 32]].concat())[..8]);
+            let request = loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !String::from_utf8_lossy(&request).contains("}") {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                socket.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+                let request = String::from_utf8(request).unwrap();
+                if request.contains(&tag) {
+                    break request;
+                }
+            };
+            let (head, body) = request.split_once("\r\n\r\n").unwrap();
+            assert!(head.starts_with("POST /hook HTTP/1.1") && head.contains("X-Meshapp-Event: peer_joined"));
+            let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cret").unwrap();
+            mac.update(body.as_bytes());
+            let signature = hex::encode(mac.finalize().into_bytes());
+            assert!(head.contains(&format!("X-Meshapp-Signature: sha256={}", signature)));
+            let body: Value = serde_json::from_str(body).unwrap();
+            assert_eq!((body["event"].as_str(), body["peer"].as_str()), (Some("peer_joined"), Some(tag.as_str())));
+            assert!(!body.to_string().contains(&peer));
+
+            stop();
+            worker.await.unwrap();
+            assert!(!stats().running);
+        });
+    }
+}
@@ -0,0 +1,211 @@
+//! Secure wipe and remote wipe
+//!
+//! `wipe_dir` overwrites every file of a directory with zeros before removing
+//! it (best effort: flash storage may keep old blocks, so this complements,
+//! not replaces, OS-level encryption). A device wipe runs it on the root data
+//! directory: every identity goes, whichever is active.
+//!
+//! A device can be linked to a primary identity (`link_primary`). The
+//! primary can then send it a signed wipe command as a `DeviceControl`
+//! packet on the device's control channel, SHA256("meshapp-device-control"
+//! || device user_id). The command is accepted when:
+//! - it names this device (our user_id)
+//! - it is signed by a linked primary and was issued after the link
+//! - it is no older than `COMMAND_MAX_AGE_SECS`
+//!
+//! Accepting a command only marks a wipe as pending; the FFI layer runs it
+//! once every core lock has been released.
+
+use crate::codec;
+use crate::error::MeshError;
+use crate::identity::Identity;
+use crate::priority::Priority;
+use crate::storage::Storage;
+use crate::transport::{Packet, PacketKind, Router};
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Current wipe command format version
+pub const WIPE_COMMAND_VERSION: u8 = 1;
+
+/// Oldest wipe command accepted (devices may be offline for a while)
+const COMMAND_MAX_AGE_SECS: i64 = 30 * 86_400;
+
+/// Set when a verified wipe command arrived
+static WIPE_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Signed "wipe device X" command (hex-encoded binary fields)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WipeCommand {
+    pub version: u8,
+    pub device_user_id: String,
+    pub issued_at: i64,
+    pub signer_ed25519_public: String,
+    pub signature: String,
+}
+
+/// What `wipe_dir` removed
+#[derive(Serialize, Default, Debug)]
+pub struct WipeSummary {
+    pub files_wiped: usize,
+    /// Files that could not be wiped (name: error)
+    pub errors: Vec<String>,
+}
+
+/// Channel a device listens on for control packets.
+pub fn control_channel_id(device_user_id: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"meshapp-device-control");
+    hasher.update(device_user_id);
+    hasher.finalize().into()
+}
+
+fn signing_bytes(device_user_id: &[u8; 32], issued_at: i64) -> Vec<u8> {
+    let mut out = b"meshapp-remote-wipe".to_vec();
+    out.push(WIPE_COMMAND_VERSION);
+    out.extend_from_slice(device_user_id);
+    out.extend_from_slice(&issued_at.to_be_bytes());
+    out
+}
+
+/// Build a signed wipe command packet for a linked device.
+pub fn build_command(identity: &Identity, device_user_id: [u8; 32], ttl: u8, now: i64) -> Result<Packet, String> {
+    let signature = identity.ed25519_signing_key().sign(&signing_bytes(&device_user_id, now));
+    let command = WipeCommand {
+        version: WIPE_COMMAND_VERSION,
+        device_user_id: hex::encode(device_user_id),
+        issued_at: now,
+        signer_ed25519_public: hex::encode(identity.public().ed25519_public.as_bytes()),
+        signature: hex::encode(signature.to_bytes()),
+    };
+    let payload = serde_json::to_vec(&command).map_err(|e| format!("Failed to serialize wipe command: {}", e))?;
+    Ok(Packet {
+        packet_id: Router::generate_packet_id(),
+        channel_id: control_channel_id(&device_user_id),
+        kind: PacketKind::DeviceControl,
+        ttl,
+        payload,
+        priority: Priority::Normal,
+    })
+}
+
+/// Check a received control packet. Returns Ok(true) for a valid wipe
+/// command addressed to us, Ok(false) for commands meant for other devices.
+pub fn verify_command(storage: &Storage, own_user_id: [u8; 32], packet: &Packet, now: i64) -> Result<bool, String> {
+    if packet.channel_id != control_channel_id(&own_user_id) {
+        return Ok(false);
+    }
+    let command: WipeCommand =
+        serde_json::from_slice(&packet.payload).map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid wipe command: {}", e)))?;
+    if command.version != WIPE_COMMAND_VERSION {
+        return Err(MeshError::InvalidArgument.raise(format!("Unsupported wipe command version {}", command.version)));
+    }
+    if codec::parse_id_hex(&command.device_user_id, "device user id")? != own_user_id {
+        return Ok(false);
+    }
+
+    let signer = codec::parse_id_hex(&command.signer_ed25519_public, "signer key")?;
+    let linked_at = storage
+        .get_primary_linked_at(signer)?
+        .ok_or_else(|| MeshError::Rejected.raise("Wipe command is not from a linked primary"))?;
+    if command.issued_at < linked_at || now.saturating_sub(command.issued_at) > COMMAND_MAX_AGE_SECS {
+        return Err("Wipe command is stale".to_string());
+    }
+
+    let signature: [u8; 64] = codec::parse_hex_array(&command.signature, "wipe signature")?;
+    VerifyingKey::from_bytes(&signer)
+        .map_err(|e| MeshError::InvalidArgument.raise(format!("Invalid signer key: {}", e)))?
+        .verify(&signing_bytes(&own_user_id, command.issued_at), &Signature::from_bytes(&signature))
+        .map_err(|_| MeshError::Crypto.raise("Wipe command signature does not verify"))?;
+    Ok(true)
+}
+
+/// Mark a wipe as pending (run by the FFI layer once locks are released).
+pub fn request() {
+    WIPE_PENDING.store(true, Ordering::SeqCst);
+}
+
+/// Whether a wipe is pending; clears the flag.
+pub fn take_pending() -> bool {
+    WIPE_PENDING.swap(false, Ordering::SeqCst)
+}
+
+/// Overwrite a file with zeros, sync, then remove it.
+fn wipe_file(path: &Path) -> Result<(), String> {
+    let len = fs::metadata(path).map_err(|e| e.to_string())?.len();
+    let mut file = fs::OpenOptions::new().write(true).open(path).map_err(|e| e.to_string())?;
+    let zeros = [0u8; 8192];
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = remaining.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..n]).map_err(|e| e.to_string())?;
+        remaining -= n as u64;
+    }
+    file.sync_all().map_err(|e| e.to_string())?;
+    drop(file);
+    fs::remove_file(path).map_err(|e| e.to_string())
+}
+
+/// Securely delete everything under `dir`, then the directory itself.
+pub fn wipe_dir(dir: &Path) -> WipeSummary {
+    let mut summary = WipeSummary::default();
+    wipe_tree(dir, &mut summary);
+    if dir.exists() {
+        if let Err(e) = fs::remove_dir_all(dir) {
+            summary.errors.push(format!("{}: {}", dir.display(), e));
+        }
+    }
+    summary
+}
+
+fn wipe_tree(dir: &Path, summary: &mut WipeSummary) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            wipe_tree(&path, summary);
+        } else {
+            match wipe_file(&path) {
+                Ok(()) => summary.files_wiped += 1,
+                Err(e) => summary.errors.push(format!("{}: {}", path.display(), e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wipe_command_needs_linked_primary() {
+        let dir = std::env::temp_dir().join(format!("meshapp-wipe-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let storage = Storage::init(&dir.join("mesh.db")).unwrap();
+
+        let (primary, device, stranger) = (Identity::generate(), Identity::generate(), Identity::generate());
+        let device_id = device.public().user_id;
+
+        let forged = build_command(&stranger, device_id, 3, 1_000).unwrap();
+        assert!(verify_command(&storage, device_id, &forged, 1_000).is_err());
+
+        storage.link_primary(*primary.public().ed25519_public.as_bytes(), 500).unwrap();
+        let command = build_command(&primary, device_id, 3, 1_000).unwrap();
+        assert!(verify_command(&storage, device_id, &command, 1_000).unwrap());
+        assert!(!verify_command(&storage, [9u8; 32], &command, 1_000).unwrap());
+        assert!(verify_command(&storage, device_id, &command, 1_000 + COMMAND_MAX_AGE_SECS + 1).is_err());
+
+        drop(storage);
+        fs::write(dir.join("identity.json"), b"secret").unwrap();
+        let summary = wipe_dir(&dir);
+        assert!(summary.files_wiped >= 2 && summary.errors.is_empty());
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,40 @@
+//! A far-future message stored through the FFI does not move the clock,
+//! then or after the database is opened again.
+//!
+//! FFI tests drive the process-wide core state, so each runs in its own
+//! test binary (and process).
+
+use meshapp_core::*;
+use std::ffi::{CStr, CString};
+
+fn json(ptr: *mut std::os::raw::c_char) -> serde_json::Value {
+    assert!(!ptr.is_null());
+    let value = serde_json::from_str(unsafe { CStr::from_ptr(ptr) }.to_str().unwrap()).unwrap();
+    free_string(ptr);
+    value
+}
+
+#[test]
+fn test_far_future_message_does_not_poison_the_clock() {
+    let root = std::env::temp_dir().join(format!("meshapp-ffi-clock-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let path = CString::new(root.to_str().unwrap()).unwrap();
+    assert_eq!(set_data_directory(path.as_ptr()), 0);
+    assert_eq!(init_storage(), 0);
+
+    let far_future: i64 = 4_102_444_800;
+    let (message_id, channel_id) = (CString::new("11".repeat(32)).unwrap(), CString::new("22".repeat(32)).unwrap());
+    let ciphertext = CString::new("aabb").unwrap();
+    assert_eq!(store_message(message_id.as_ptr(), channel_id.as_ptr(), ciphertext.as_ptr(), far_future, 3), 0);
+    let quarantined = json(get_quarantined_timestamps(10));
+    assert_eq!(quarantined[0]["claimed"], far_future);
+
+    // Close and reopen the database: the mark is loaded again
+    assert_eq!(suspend(), 0);
+    json(resume());
+    let status = json(get_clock_status());
+    assert!(status["trusted_time"].as_i64().unwrap() < far_future);
+    assert_eq!(status["state"], "ok");
+
+    let _ = std::fs::remove_dir_all(&root);
+}
@@ -0,0 +1,48 @@
+//! With SQLCipher, setting a passphrase moves the database to the sealed
+//! database key and changing it rekeys the database; both reopen on resume.
+//!
+//! FFI tests drive the process-wide core state, so each runs in its own
+//! test binary (and process).
+#![cfg(feature = "sqlcipher")]
+
+use meshapp_core::*;
+use std::ffi::{CStr, CString};
+
+fn json(ptr: *mut std::os::raw::c_char) -> serde_json::Value {
+    assert!(!ptr.is_null());
+    let value = serde_json::from_str(unsafe { CStr::from_ptr(ptr) }.to_str().unwrap()).unwrap();
+    free_string(ptr);
+    value
+}
+
+fn reopen_and_count(channel_id: &CString) -> usize {
+    assert_eq!(suspend(), 0);
+    assert_eq!(json(resume())["reopened"], true);
+    json(get_messages(channel_id.as_ptr(), 10, 0)).as_array().unwrap().len()
+}
+
+#[test]
+fn test_passphrase_keys_the_database() {
+    let root = std::env::temp_dir().join(format!("meshapp-ffi-passphrase-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let path = CString::new(root.to_str().unwrap()).unwrap();
+    assert_eq!(set_data_directory(path.as_ptr()), 0);
+    assert_eq!(init_identity(), 0);
+    assert_eq!(init_storage(), 0);
+    let (message_id, channel_id) = (CString::new("11".repeat(32)).unwrap(), CString::new("22".repeat(32)).unwrap());
+    let ciphertext = CString::new("aabb").unwrap();
+    assert_eq!(store_message(message_id.as_ptr(), channel_id.as_ptr(), ciphertext.as_ptr(), 1_000, 3), 0);
+
+    let (old, new) = (CString::new("correct horse").unwrap(), CString::new("battery staple").unwrap());
+    assert_eq!(set_passphrase(old.as_ptr()), 0);
+    assert_eq!(reopen_and_count(&channel_id), 1);
+
+    assert_eq!(change_passphrase(old.as_ptr(), new.as_ptr()), 0);
+    while json(get_passphrase_status())["reencryption_running"] == true {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    assert_eq!(json(get_passphrase_status())["reencryption_pending"], false);
+    assert_eq!(reopen_and_count(&channel_id), 1);
+
+    let _ = std::fs::remove_dir_all(&root);
+}
@@ -0,0 +1,44 @@
+//! A process attached read-only is refused every call that writes files in
+//! the data directory, and writes none.
+//!
+//! FFI tests drive the process-wide core state, so each runs in its own
+//! test binary (and process).
+
+use meshapp_core::*;
+use std::ffi::CString;
+
+const IN_USE: i32 = -10;
+
+#[test]
+fn test_read_only_attach_refuses_writes() {
+    let root = std::env::temp_dir().join(format!("meshapp-ffi-read-only-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+    let path = CString::new(root.to_str().unwrap()).unwrap();
+    assert_eq!(set_data_directory(path.as_ptr()), 0);
+    assert_eq!(attach_read_only(), 0);
+
+    let text = |s: &str| CString::new(s).unwrap();
+    let (passphrase, other, name, file) = (text("correct horse"), text("battery staple"), text("work"), text("identity"));
+    let (id, mime, data) = (text(&"11".repeat(32)), text("text/plain"), text("aabb"));
+    assert_eq!(set_passphrase(passphrase.as_ptr()), IN_USE);
+    assert_eq!(change_passphrase(passphrase.as_ptr(), other.as_ptr()), IN_USE);
+    assert_eq!(switch_identity(name.as_ptr()), IN_USE);
+    assert_eq!(accept_protected_file(file.as_ptr()), IN_USE);
+    assert_eq!(secure_wipe_all(), IN_USE);
+    let refused = [
+        create_identity(name.as_ptr()),
+        set_aside_protected_file(file.as_ptr()),
+        create_diagnostics_bundle(),
+        store_attachment(id.as_ptr(), id.as_ptr(), mime.as_ptr(), data.as_ptr()),
+        set_attachment_thumbnail(id.as_ptr(), mime.as_ptr(), data.as_ptr()),
+        gc_attachments(),
+    ];
+    for result in refused {
+        assert!(result.is_null());
+        assert_eq!(last_error_code(), IN_USE);
+    }
+
+    assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+    let _ = std::fs::remove_dir_all(&root);
+}
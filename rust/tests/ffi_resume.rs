@@ -0,0 +1,57 @@
+//! Resuming reloads what another process changed while this one was
+//! suspended, and refuses an identity that is no longer the one on disk.
+//!
+//! FFI tests drive the process-wide core state, so each runs in its own
+//! test binary (and process). This one writes the database as another
+//! process would, which needs it unencrypted.
+#![cfg(not(feature = "sqlcipher"))]
+
+use meshapp_core::*;
+use sha2::{Digest, Sha256};
+use std::ffi::{CStr, CString};
+
+fn json(ptr: *mut std::os::raw::c_char) -> serde_json::Value {
+    assert!(!ptr.is_null());
+    let value = serde_json::from_str(unsafe { CStr::from_ptr(ptr) }.to_str().unwrap()).unwrap();
+    free_string(ptr);
+    value
+}
+
+#[test]
+fn test_resume_reloads_friends_and_checks_identity() {
+    let root = std::env::temp_dir().join(format!("meshapp-ffi-resume-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let path = CString::new(root.to_str().unwrap()).unwrap();
+    assert_eq!(set_data_directory(path.as_ptr()), 0);
+    assert_eq!(init_identity(), 0);
+    assert_eq!(init_storage(), 0);
+    assert_eq!(init_friends(), 0);
+    assert_eq!(json(get_all_friends()).as_array().unwrap().len(), 0);
+
+    // Another process adds a friend while this one is suspended
+    assert_eq!(suspend(), 0);
+    let ed25519_public = [9u8; 32];
+    let user_id: [u8; 32] = Sha256::digest(ed25519_public).into();
+    let conn = rusqlite::Connection::open(root.join("mesh.db")).unwrap();
+    conn.execute(
+        "INSERT INTO friends (user_id, ed25519_public, nickname) VALUES (?1, ?2, 'bob')",
+        rusqlite::params![user_id.to_vec(), ed25519_public.to_vec()],
+    )
+    .unwrap();
+    drop(conn);
+    let report = json(resume());
+    assert_eq!(report["reopened"], true);
+    let friends = json(get_all_friends());
+    assert_eq!(friends.as_array().unwrap().len(), 1);
+    assert_eq!(friends[0]["nickname"], "bob");
+
+    // The identity went away meanwhile: resume fails until it is back
+    assert_eq!(suspend(), 0);
+    let (identity, moved) = (root.join("identity.json"), root.join("identity.json.moved"));
+    std::fs::rename(&identity, &moved).unwrap();
+    assert!(resume().is_null());
+    std::fs::rename(&moved, &identity).unwrap();
+    assert_eq!(json(resume())["reopened"], true);
+
+    let _ = std::fs::remove_dir_all(&root);
+}
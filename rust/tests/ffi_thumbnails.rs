@@ -0,0 +1,67 @@
+//! An image attachment gets a small thumbnail derived in the core, and the
+//! thumbnail is what goes out first when it is sent.
+//!
+//! FFI tests drive the process-wide core state, so each runs in its own
+//! test binary (and process).
+#![cfg(feature = "thumbnails")]
+
+use meshapp_core::*;
+use std::ffi::{CStr, CString};
+
+const MAX_THUMBNAIL_BYTES: usize = 32 * 1024;
+const THUMBNAIL_MAX_DIMENSION: u32 = 160;
+const ATTACHMENT_MANIFEST: u8 = 1;
+const ATTACHMENT_CHUNK: u8 = 2;
+
+fn text(ptr: *mut std::os::raw::c_char) -> String {
+    assert!(!ptr.is_null());
+    let value = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_string();
+    free_string(ptr);
+    value
+}
+
+#[test]
+fn test_png_attachment_pushes_its_thumbnail_first() {
+    let root = std::env::temp_dir().join(format!("meshapp-ffi-thumbnails-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let path = CString::new(root.to_str().unwrap()).unwrap();
+    assert_eq!(set_data_directory(path.as_ptr()), 0);
+    assert_eq!(init_storage(), 0);
+    assert_eq!(init_router_with_loopback(), 0);
+
+    let image = image::RgbImage::from_fn(1024, 768, |x, y| image::Rgb([(x % 256) as u8, (y % 256) as u8, ((x * y) % 256) as u8]));
+    let mut png = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut png, image::ImageFormat::Png).unwrap();
+    let png = png.into_inner();
+    assert!(png.len() > MAX_THUMBNAIL_BYTES);
+
+    let (message_id, channel_id) = (CString::new("11".repeat(32)).unwrap(), CString::new("22".repeat(32)).unwrap());
+    let (mime, data) = (CString::new("image/png").unwrap(), CString::new(hex::encode(&png)).unwrap());
+    let attachment_id = text(store_attachment(message_id.as_ptr(), channel_id.as_ptr(), mime.as_ptr(), data.as_ptr()));
+    let attachment = CString::new(attachment_id.clone()).unwrap();
+    let thumbnail_id = text(derive_attachment_thumbnail(attachment.as_ptr()));
+
+    let listed: serde_json::Value = serde_json::from_str(&text(get_message_attachments(message_id.as_ptr()))).unwrap();
+    let full = listed.as_array().unwrap().iter().find(|a| a["attachment_id"] == attachment_id.as_str()).unwrap();
+    assert_eq!(full["thumbnail_id"], thumbnail_id.as_str());
+    let thumbnail = std::fs::read(root.join("attachments").join(format!("{}.blob", thumbnail_id))).unwrap();
+    assert!(thumbnail.len() <= MAX_THUMBNAIL_BYTES);
+    let decoded = image::load_from_memory(&thumbnail).unwrap();
+    assert!(decoded.width() <= THUMBNAIL_MAX_DIMENSION && decoded.height() <= THUMBNAIL_MAX_DIMENSION);
+
+    // Only the thumbnail's chunks are pushed; the full image waits for a request
+    assert_eq!(send_attachment(attachment.as_ptr(), 3, std::ptr::null()), 0);
+    let packets: serde_json::Value = serde_json::from_str(&text(drain_loopback_packets())).unwrap();
+    let of_kind = |kind: u8| packets.as_array().unwrap().iter().filter(move |p| p["kind"] == kind);
+    let manifests: Vec<serde_json::Value> = of_kind(ATTACHMENT_MANIFEST)
+        .map(|p| serde_json::from_slice(&hex::decode(p["payload"].as_str().unwrap()).unwrap()).unwrap())
+        .collect();
+    let pushed: Vec<&serde_json::Value> = manifests.iter().filter(|m| m["pushed"] == true).collect();
+    assert_eq!((manifests.len(), pushed.len()), (2, 1));
+    assert_eq!(pushed[0]["attachment_id"], thumbnail_id.as_str());
+    assert_eq!(pushed[0]["parent_id"], attachment_id.as_str());
+    assert!(of_kind(ATTACHMENT_CHUNK).count() > 0);
+    assert!(of_kind(ATTACHMENT_CHUNK).all(|c| c["payload"].as_str().unwrap().starts_with(&thumbnail_id)));
+
+    let _ = std::fs::remove_dir_all(&root);
+}
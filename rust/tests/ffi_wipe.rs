@@ -0,0 +1,46 @@
+//! A device wipe erases every identity and the identities index, whichever
+//! identity is active.
+//!
+//! FFI tests drive the process-wide core state, so each runs in its own
+//! test binary (and process).
+
+use meshapp_core::*;
+use std::ffi::{CStr, CString};
+
+fn json(ptr: *mut std::os::raw::c_char) -> serde_json::Value {
+    assert!(!ptr.is_null());
+    let value = serde_json::from_str(unsafe { CStr::from_ptr(ptr) }.to_str().unwrap()).unwrap();
+    free_string(ptr);
+    value
+}
+
+#[test]
+fn test_wipe_erases_every_identity() {
+    let root = std::env::temp_dir().join(format!("meshapp-ffi-wipe-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let path = CString::new(root.to_str().unwrap()).unwrap();
+    assert_eq!(set_data_directory(path.as_ptr()), 0);
+    let work = CString::new("work").unwrap();
+
+    // The default identity is active: the other identities go with it
+    assert_eq!(init_identity(), 0);
+    assert_eq!(init_storage(), 0);
+    json(create_identity(work.as_ptr()));
+    assert!(root.join("identities").exists());
+    assert_eq!(secure_wipe_all(), 0);
+    assert!(!root.exists());
+
+    // Another identity is active: the default one and the index go too
+    assert_eq!(init_identity(), 0);
+    json(create_identity(work.as_ptr()));
+    assert_eq!(switch_identity(work.as_ptr()), 0);
+    assert_eq!(init_identity(), 0);
+    assert_eq!(init_storage(), 0);
+    assert_eq!(secure_wipe_all(), 0);
+    assert!(!root.exists());
+    let index = json(list_identities());
+    assert_eq!(index["active"], "default");
+    assert_eq!(index["identities"].as_array().unwrap().len(), 1);
+
+    let _ = std::fs::remove_dir_all(&root);
+}